@@ -0,0 +1,117 @@
+//! Runs a function over and over under the evaluator and measures it, for seeing how changes to the evaluator affect
+//! its performance
+use bytecode::BytecodeEngine;
+use error::Result;
+use eval::EvalEngine;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long a benchmark runs for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BenchLength {
+    /// Runs the function this many times
+    Iterations(u64),
+    /// Runs the function until this much time has passed, and at least once
+    Duration(Duration),
+}
+
+/// What was measured while a function was benchmarked.  The counts are totals over every iteration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Benchmark {
+    pub function: String,
+    pub iterations: u64,
+    pub wall_time: Duration,
+    /// The instructions evaluated
+    pub instructions: u64,
+    /// The objects allocated on the heap
+    pub allocations: u64,
+}
+
+impl Benchmark {
+    pub fn time_per_iteration(&self) -> Duration {
+        Duration::from_nanos((self.wall_time.as_nanos() / u128::from(self.iterations)) as u64)
+    }
+
+    pub fn instructions_per_iteration(&self) -> u64 {
+        self.instructions / self.iterations
+    }
+
+    pub fn allocations_per_iteration(&self) -> u64 {
+        self.allocations / self.iterations
+    }
+
+    /// How many instructions were evaluated each second, which can be compared across functions
+    pub fn instructions_per_second(&self) -> f64 {
+        self.instructions as f64 / self.wall_time.as_secs_f64()
+    }
+}
+
+/// Eg)
+/// ```text
+/// bench fib: 120 iterations in 1.004s
+///   per iteration: 8.367ms, 177115 instructions, 0 allocations
+///   21169312 instructions/s
+/// ```
+impl fmt::Display for Benchmark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "bench {}: {} iterations in {:.3?}",
+            self.function, self.iterations, self.wall_time
+        )?;
+        writeln!(
+            f,
+            "  per iteration: {:.3?}, {} instructions, {} allocations",
+            self.time_per_iteration(),
+            self.instructions_per_iteration(),
+            self.allocations_per_iteration()
+        )?;
+        writeln!(f, "  {:.0} instructions/s", self.instructions_per_second())
+    }
+}
+
+impl EvalEngine {
+    /// Evaluates the function, which has to be processed and take no arguments, over and over for the given length,
+    /// measuring the runs.  Each run is evaluated like `eval_program`, so the settings of the engine, like the fuel and
+    /// where output goes, apply to them.  If a run stops with an error, benchmarking stops with it too.
+    pub fn bench_fn(
+        &mut self,
+        bc: &BytecodeEngine,
+        fn_name: &str,
+        length: BenchLength,
+    ) -> Result<Benchmark> {
+        if !bc.get_fn(fn_name, 0)?.params.is_empty() {
+            bail!(
+                "Function {} takes arguments, so it can't be benchmarked",
+                fn_name
+            );
+        }
+        if length == BenchLength::Iterations(0) {
+            bail!("A benchmark needs at least one iteration");
+        }
+
+        let instructions = self.instructions_evaluated();
+        let allocations = self.heap_allocations();
+        let start = Instant::now();
+        let mut iterations = 0;
+        loop {
+            let done = match length {
+                BenchLength::Iterations(count) => iterations == count,
+                BenchLength::Duration(duration) => iterations > 0 && start.elapsed() >= duration,
+            };
+            if done {
+                break;
+            }
+            self.eval_program(bc, fn_name)?;
+            iterations += 1;
+        }
+
+        Ok(Benchmark {
+            function: fn_name.to_string(),
+            iterations,
+            wall_time: start.elapsed(),
+            instructions: self.instructions_evaluated() - instructions,
+            allocations: self.heap_allocations() - allocations,
+        })
+    }
+}
@@ -0,0 +1,159 @@
+//! Builds programs into native executables, by translating them to C and running the system's C compiler on the result
+use bytecode::{BytecodeEngine, Prelude};
+use compile::emit_c;
+#[cfg(feature = "llvm")]
+use compile::RUNTIME;
+use error::{PeachError, Result};
+#[cfg(feature = "llvm")]
+use llvm::emit_llvm;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The C compiler used when `CC` isn't set
+#[cfg(not(windows))]
+const DEFAULT_COMPILER: &str = "cc";
+#[cfg(windows)]
+const DEFAULT_COMPILER: &str = "cl.exe";
+
+/// The compiler used for LLVM IR when `CLANG` isn't set
+#[cfg(feature = "llvm")]
+const DEFAULT_LLVM_COMPILER: &str = "clang";
+
+/// Keeps apart the temporary directories of builds running at the same time in one process
+static NEXT_BUILD_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Loads the program at `source`, which is a file or a project directory laid out like a Cargo package, and builds
+/// it into an executable at `output` that starts from its `main`.  Programs are loaded with the standard prelude.
+/// Returns the path of the executable.
+pub fn build(source: &str, output: &str) -> Result<PathBuf> {
+    let path = match Path::new(source).canonicalize() {
+        Ok(path) => path,
+        Err(e) => return Err(PeachError::Io(format!("{}: {}", source, e))),
+    };
+
+    let mut bc = BytecodeEngine::with_prelude(&Prelude::standard())?;
+    match (path.parent(), path.file_name()) {
+        (Some(root), Some(fname)) if !path.is_dir() => {
+            bc.set_project_root(&root.to_string_lossy())?;
+            bc.load_file(&fname.to_string_lossy())?;
+        }
+        _ => bc.load_project(&path.to_string_lossy())?,
+    }
+    bc.process_fn("main", 0)?;
+
+    build_executable(&bc, "main", Path::new(output))
+}
+
+/// Builds the program's bytecode into an executable at `output`, which starts from `entry_fn` as it does for
+/// `emit_c`.  The compiler is the one the `CC` environment variable names, or else the platform's usual one.  The
+/// C it's given is written to a temporary directory, which is removed again whether or not the build succeeds.
+pub fn build_executable(bc: &BytecodeEngine, entry_fn: &str, output: &Path) -> Result<PathBuf> {
+    let c = emit_c(bc, entry_fn)?;
+    let compiler = env::var("CC").unwrap_or_else(|_| DEFAULT_COMPILER.to_string());
+
+    in_build_dir(|dir| {
+        let c_path = dir.join("program.c");
+        write_file(&c_path, &c)?;
+
+        let mut command = Command::new(&compiler);
+        compiler_args(&mut command, dir, &c_path, output);
+        run_compiler(command, &compiler, output)
+    })
+}
+
+/// Like `build_executable`, but lowers the bytecode to LLVM IR and optimizes it with `-O2`.  The compiler is the one
+/// the `CLANG` environment variable names, or else `clang`, which builds the IR together with the C runtime.  Only
+/// available with the `llvm` feature.
+#[cfg(feature = "llvm")]
+pub fn build_llvm_executable(
+    bc: &BytecodeEngine,
+    entry_fn: &str,
+    output: &Path,
+) -> Result<PathBuf> {
+    let ir = emit_llvm(bc, entry_fn)?;
+    let compiler = env::var("CLANG").unwrap_or_else(|_| DEFAULT_LLVM_COMPILER.to_string());
+
+    in_build_dir(|dir| {
+        let ir_path = dir.join("program.ll");
+        write_file(&ir_path, &ir)?;
+        let runtime_path = dir.join("runtime.c");
+        write_file(&runtime_path, RUNTIME)?;
+
+        let mut command = Command::new(&compiler);
+        command
+            .arg("-O2")
+            .arg("-w")
+            .arg(&ir_path)
+            .arg(&runtime_path)
+            .arg("-o")
+            .arg(output);
+        run_compiler(command, &compiler, output)
+    })
+}
+
+/// Runs `build` in a new temporary directory, which is removed again whether or not it succeeds
+fn in_build_dir<F: FnOnce(&Path) -> Result<PathBuf>>(build: F) -> Result<PathBuf> {
+    let dir = env::temp_dir().join(format!(
+        "peach-build-{}-{}",
+        process::id(),
+        NEXT_BUILD_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    let result = match fs::create_dir_all(&dir) {
+        Ok(()) => build(&dir),
+        Err(e) => Err(PeachError::Io(format!("{}: {}", dir.display(), e))),
+    };
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<()> {
+    match fs::write(path, contents) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(PeachError::Io(format!("{}: {}", path.display(), e))),
+    }
+}
+
+/// Runs the compiler, which should build the executable at `output`.  What it reports is kept if it fails.
+fn run_compiler(mut command: Command, compiler: &str, output: &Path) -> Result<PathBuf> {
+    let result = match command.output() {
+        Ok(result) => result,
+        Err(e) => {
+            return Err(PeachError::Io(format!(
+                "Could not run the C compiler {}: {}",
+                compiler, e
+            )))
+        }
+    };
+
+    if result.status.success() {
+        Ok(output.to_path_buf())
+    } else {
+        let mut message = String::from_utf8_lossy(&result.stdout).into_owned();
+        message += &String::from_utf8_lossy(&result.stderr);
+        Err(PeachError::Build(format!(
+            "{} failed ({}): {}",
+            compiler,
+            result.status,
+            message.trim()
+        )))
+    }
+}
+
+#[cfg(not(windows))]
+fn compiler_args(command: &mut Command, _dir: &Path, c_path: &Path, output: &Path) {
+    command.arg("-w").arg(c_path).arg("-o").arg(output);
+}
+
+#[cfg(windows)]
+fn compiler_args(command: &mut Command, dir: &Path, c_path: &Path, output: &Path) {
+    // cl writes its object files to the current directory unless it's told otherwise
+    command
+        .arg("/nologo")
+        .arg("/w")
+        .arg(format!("/Fe{}", output.display()))
+        .arg(format!("/Fo{}\\", dir.display()))
+        .arg(c_path);
+}
@@ -1,103 +1,231 @@
-use bytecode::engine::{Bytecode, BytecodeEngine, Definition, DefinitionId, Fun, Param, Scope,
-                       ScopeId, VarStack};
+use bytecode::attrs::stmt_attrs;
+use bytecode::engine::{
+    Bytecode, BytecodeEngine, Definition, DefinitionId, Fun, LazyFn, Param, Scope, ScopeId,
+    VarStack,
+};
+use bytecode::macros::{debug_call, macro_name, quote_block};
+use bytecode::symbols::SymbolTarget;
 use bytecode::typecheck::builtin_type;
-use proc_macro2::TokenStream;
+use error::{PeachError, Result};
+use proc_macro2::Span;
+use std::convert::TryFrom;
+use std::mem;
 use std::ptr;
-use syn::{self, BinOp, Block, Expr, FnArg, GenericParam, IntSuffix, Item, Lit, Member, Pat,
-          ReturnType, Stmt, Type, UnOp};
+use syn::spanned::Spanned;
+use syn::{
+    self, BinOp, Block, Expr, ExprMethodCall, ExprStruct, FnArg, GenericArgument, GenericParam,
+    IntSuffix, Item, Lit, Member, Pat, PathArguments, ReturnType, Stmt, Type, TypeParamBound, UnOp,
+    WherePredicate,
+};
 
 impl BytecodeEngine {
     pub(crate) fn convert_fn_to_bytecode(
         &mut self,
         definition_id: DefinitionId,
         scope_id: ScopeId,
-    ) -> Fun {
-        let defn_state = self.definitions[definition_id].clone();
-
-        match defn_state {
-            Definition::Fun(fun) => fun,
+    ) -> Result<Fun> {
+        match self.definitions[definition_id].clone() {
+            Definition::Fun(fun) => Ok(fun),
             Definition::LazyFn(item_fn) => {
-                let mut bytecode = Vec::new();
-
-                let mut ty_params = vec![];
-                for generic_param in item_fn.decl.generics.params {
-                    match generic_param {
-                        GenericParam::Type(type_param) => {
-                            self.definitions.push(Definition::TypeVariable);
-                            self.scopes[scope_id]
-                                .definitions
-                                .insert(type_param.ident.to_string(), self.definitions.len() - 1);
-                            ty_params.push(self.definitions.len() - 1);
-                        }
-                        _ => {}
-                    }
+                // Functions this one uses are converted partway through it, so the locations of its statements are
+                // kept apart from theirs
+                let outer_locations = mem::take(&mut self.stmt_locations);
+                let outer_op_locations = mem::take(&mut self.op_locations);
+                let errors_before = self.errors.len();
+                let symbols_before = self.symbols.len();
+                let result = self.convert_lazy_fn_to_bytecode(definition_id, &item_fn, scope_id);
+                self.bind_var_symbols(symbols_before, definition_id);
+                // The function stood in for itself while its body was converted.  Its caller stores the result.
+                self.definitions[definition_id] = Definition::LazyFn(item_fn.clone());
+                let stmt_locations = mem::replace(&mut self.stmt_locations, outer_locations);
+                self.op_locations = outer_op_locations;
+
+                let mut fun = result.map_err(|e| e.at(self.location(item_fn.decl.fn_token.0)))?;
+                fun.stmt_locations = stmt_locations;
+                // Uses in statements with errors are left out, so variables may only look unused
+                if self.errors.len() == errors_before {
+                    self.check_unused_vars(&fun);
+                    self.check_unreachable(&fun);
                 }
+                Ok(fun)
+            }
+            Definition::Omitted(kind) => bail!(
+                "The {} {} was left out of the image it was loaded from",
+                kind,
+                self.qualified_name(definition_id)
+                    .unwrap_or_else(|| format!("{{{} {}}}", kind, definition_id))
+            ),
+            _ => bail!("Could not find function"),
+        }
+    }
 
-                let return_ty = match &item_fn.decl.output {
-                    ReturnType::Default => builtin_type::VOID,
-                    ReturnType::Type(_, ref box_ty) => self.resolve_type(box_ty, scope_id),
-                };
-
-                let mut var_stack = VarStack::new();
-                let mut params = vec![];
-
-                // process function params
-                for input in &item_fn.decl.inputs {
-                    match input {
-                        FnArg::Captured(ref capture) => {
-                            match capture.pat {
-                                Pat::Ident(ref pi) => {
-                                    let ident = pi.ident.to_string();
-                                    let definition_id = self.resolve_type(&capture.ty, scope_id);
-                                    let var_id = var_stack.add_var(ident.clone(), definition_id);
-                                    params.push(Param::new(ident, var_id, definition_id));
-                                }
-                                _ => {
-                                    unimplemented!("Unsupported pattern type in function parameter")
-                                }
-                            };
-                        }
-                        _ => unimplemented!("Function argument of {:?} is not supported", input),
-                    }
+    fn convert_lazy_fn_to_bytecode(
+        &mut self,
+        definition_id: DefinitionId,
+        item_fn: &LazyFn,
+        scope_id: ScopeId,
+    ) -> Result<Fun> {
+        let mut bytecode = Vec::new();
+
+        let mut ty_params = vec![];
+        for generic_param in &item_fn.decl.generics.params {
+            match generic_param {
+                GenericParam::Type(type_param) => {
+                    self.definitions.push(Definition::TypeVariable);
+                    let type_var = self.definitions.len() - 1;
+                    self.scopes[scope_id]
+                        .definitions
+                        .insert(type_param.ident.to_string(), type_var);
+                    self.add_type_var_bounds(type_var, type_param.bounds.iter());
+                    ty_params.push(type_var);
                 }
+                // Lifetimes are erased: they're accepted, but the borrow checker doesn't use them
+                GenericParam::Lifetime(_) => {}
+                GenericParam::Const(_) => {
+                    bail!("Const generics are not supported")
+                }
+            }
+        }
 
-                let block_ty = self.convert_block_to_bytecode(
-                    &item_fn.block,
-                    return_ty,
-                    &mut bytecode,
-                    Some(scope_id),
-                    &mut var_stack,
-                );
-
-                match block_ty {
-                    builtin_type::VOID => bytecode.push(Bytecode::ReturnVoid),
-                    _ => bytecode.push(Bytecode::ReturnLastStackValue),
+        if let Some(ref where_clause) = item_fn.decl.generics.where_clause {
+            for predicate in &where_clause.predicates {
+                match predicate {
+                    WherePredicate::Type(predicate_type) => {
+                        let bounded_ty = self.resolve_type(&predicate_type.bounded_ty, scope_id)?;
+                        self.add_type_var_bounds(bounded_ty, predicate_type.bounds.iter());
+                    }
+                    WherePredicate::Lifetime(_) => {}
+                    WherePredicate::Eq(_) => {
+                        bail!("Equality constraints in where clauses are not supported")
+                    }
                 }
+            }
+        }
 
-                match bytecode.last() {
-                    Some(Bytecode::ReturnVoid) | Some(Bytecode::ReturnLastStackValue) => {}
-                    _ => {
-                        if !self.assignment_compatible(return_ty, block_ty) {
-                            unimplemented!(
-                                "Mismatched return types: {} and {}",
-                                self.printable_name(block_ty),
-                                self.printable_name(return_ty),
+        let return_ty = match &item_fn.decl.output {
+            ReturnType::Default => builtin_type::VOID,
+            ReturnType::Type(_, ref box_ty) => self.resolve_type(box_ty, scope_id)?,
+        };
+
+        let mut var_stack = VarStack::new();
+        let mut params = vec![];
+
+        // process function params
+        for input in &item_fn.decl.inputs {
+            match input {
+                FnArg::Captured(ref capture) => {
+                    match capture.pat {
+                        Pat::Ident(ref pi) => {
+                            let ident = pi.ident.to_string();
+                            let definition_id = self.resolve_type(&capture.ty, scope_id)?;
+                            let var_id = self.declare_var(
+                                &mut var_stack,
+                                ident.clone(),
+                                pi.ident.span(),
+                                definition_id,
                             );
+                            params.push(Param::new(ident, var_id, definition_id));
                         }
-                    }
+                        _ => {
+                            bail!("Unsupported pattern type in function parameter")
+                        }
+                    };
+                }
+                FnArg::SelfRef(ref self_ref) => {
+                    let self_ty = self.self_ty(scope_id)?;
+                    let ty = self.reference_ty(self_ty, self_ref.mutability.is_some());
+                    let span = self_ref.self_token.0;
+                    let var_id = self.declare_var(&mut var_stack, "self".to_string(), span, ty);
+                    params.push(Param::new("self".to_string(), var_id, ty));
                 }
+                FnArg::SelfValue(ref self_value) => {
+                    let ty = self.self_ty(scope_id)?;
+                    let span = self_value.self_token.0;
+                    let var_id = self.declare_var(&mut var_stack, "self".to_string(), span, ty);
+                    params.push(Param::new("self".to_string(), var_id, ty));
+                }
+                _ => bail!("Function argument of {:?} is not supported", input),
+            }
+        }
 
-                Fun {
-                    ty_params,
-                    params,
-                    return_ty,
-                    vars: var_stack.vars,
-                    bytecode,
-                    extern_name: None,
+        // Recursive calls are typechecked against the signature, as the body isn't ready to be called yet
+        if ty_params.is_empty() {
+            self.definitions[definition_id] = Definition::Fun(Fun {
+                ty_params: vec![],
+                params: params.clone(),
+                return_ty,
+                vars: vec![],
+                bytecode: vec![],
+                extern_name: None,
+                native_id: None,
+                location: None,
+                stmt_locations: vec![],
+            });
+        }
+
+        let errors = self.errors.len();
+        let block_ty = self.convert_block_to_bytecode(
+            &item_fn.block,
+            return_ty,
+            &mut bytecode,
+            Some(scope_id),
+            &mut var_stack,
+        )?;
+
+        // Parameters are owned by the function, so they're dropped as it returns
+        let param_var_ids = var_stack.var_stack.clone();
+        self.convert_scope_exit_to_bytecode(
+            &param_var_ids,
+            block_ty,
+            &mut bytecode,
+            &mut var_stack,
+        )?;
+
+        match block_ty {
+            builtin_type::VOID => bytecode.push(Bytecode::ReturnVoid),
+            _ => bytecode.push(Bytecode::ReturnLastStackValue),
+        }
+
+        let mut fun = Fun {
+            ty_params,
+            params,
+            return_ty,
+            vars: var_stack.vars,
+            bytecode,
+            extern_name: None,
+            native_id: None,
+            location: self.location(item_fn.decl.fn_token.0),
+            stmt_locations: vec![],
+        };
+
+        match (block_ty, &item_fn.decl.output) {
+            // Without a tail expression, every way through the body has to end in a return.  Statements with
+            // errors have been left out of the bytecode, so it can't tell whether they would have returned.
+            (builtin_type::VOID, ReturnType::Type(_, ref box_ty)) => {
+                if return_ty != builtin_type::ERROR
+                    && self.errors.len() == errors
+                    && fun.reachable()[fun.bytecode.len() - 1]
+                {
+                    bail_at!(
+                        self.location(box_ty.span()),
+                        "Function may finish without returning a value of type {}",
+                        self.ty(return_ty)
+                    );
+                }
+            }
+            (builtin_type::VOID, ReturnType::Default) => {}
+            _ if self.widens(return_ty, block_ty) => {
+                let end = fun.bytecode.len() - 1;
+                fun.bytecode.insert(end, Bytecode::As(return_ty));
+            }
+            _ => {
+                if let Some(tail) = item_fn.block.stmts.last() {
+                    self.expect_type(return_ty, block_ty, tail.span())?;
                 }
             }
-            _ => unimplemented!("Could not find function"),
         }
+
+        Ok(fun)
     }
 
     pub(crate) fn convert_block_to_bytecode(
@@ -107,7 +235,7 @@ impl BytecodeEngine {
         bytecode: &mut Vec<Bytecode>,
         parent: Option<ScopeId>,
         var_stack: &mut VarStack,
-    ) -> DefinitionId {
+    ) -> Result<DefinitionId> {
         //TODO: there may be more efficient ways to do this, but this will do for now
         let mut block_var_stack = var_stack.clone();
         let mut return_definition_id = builtin_type::VOID;
@@ -115,39 +243,151 @@ impl BytecodeEngine {
         let current_scope_id = self.scopes.len() - 1;
 
         let mut processed_block: Vec<Stmt> = vec![];
-
-        for stmt in &block.stmts {
-            let stmt = stmt.clone();
-            //TODO: FIXME: proper macro processing should probably be done higher
-            if let Stmt::Item(Item::Macro(im)) = stmt {
-                if im.mac.path.segments[0].ident.as_ref() == "println" {
-                    let token_stream: TokenStream = im.mac.tts.into_iter().skip(2).collect();
-                    let call = String::new() + "__debug__(" + &token_stream.to_string() + ");";
-                    let result: Stmt = syn::parse_str(&call).unwrap();
-                    processed_block.push(result);
-                }
-            } else if let Stmt::Item(ref item) = stmt {
-                self.prepare_item(item.clone(), current_scope_id);
-            } else {
-                processed_block.push(stmt.clone());
-            }
-        }
+        self.expand_stmts(&block.stmts, current_scope_id, &mut processed_block)?;
 
         for stmt in &processed_block {
-            return_definition_id = self.convert_stmt_to_bytecode(
+            let start = bytecode.len();
+            match self.convert_stmt_to_bytecode(
                 stmt,
                 expected_return_type,
                 bytecode,
                 current_scope_id,
                 &mut block_var_stack,
-            );
+            ) {
+                Ok(ty) => return_definition_id = ty,
+                Err(e) => {
+                    // Record the error and carry on with the next statement, so that every problem in the function
+                    // is found.  Anything declared by the statement gets the error type, which is compatible with
+                    // everything, so that its uses don't report errors of their own.
+                    self.errors.push(e);
+                    bytecode.truncate(start);
+                    if let Stmt::Local(ref l) = stmt {
+                        if let Pat::Ident(ref pi) = l.pats[0] {
+                            block_var_stack.add_var(pi.ident.to_string(), builtin_type::ERROR);
+                        }
+                    }
+                    return_definition_id = builtin_type::ERROR;
+                }
+            }
         }
 
+        let block_var_ids = block_var_stack.var_stack[var_stack.var_stack.len()..].to_vec();
+        self.convert_scope_exit_to_bytecode(
+            &block_var_ids,
+            return_definition_id,
+            bytecode,
+            &mut block_var_stack,
+        )?;
+
         var_stack.vars = block_var_stack.vars;
 
-        return_definition_id
+        Ok(return_definition_id)
+    }
+
+    /// Prepares the items among the given statements, and expands the macros invoked as statements, leaving the
+    /// statements to convert in `processed_block`
+    fn expand_stmts(
+        &mut self,
+        stmts: &[Stmt],
+        current_scope_id: ScopeId,
+        processed_block: &mut Vec<Stmt>,
+    ) -> Result<()> {
+        for stmt in stmts {
+            if !self.filter_attrs(stmt_attrs(stmt))? {
+                continue;
+            }
+            let stmt = stmt.clone();
+            //TODO: FIXME: proper macro processing should probably be done higher
+            match stmt {
+                Stmt::Item(Item::Macro(ref im)) if im.ident.is_none() => {
+                    if im.mac.path.segments[0].ident.as_ref() == "println" {
+                        let result: Stmt = syn::parse2(debug_call(&im.mac, true)).unwrap();
+                        processed_block.push(result);
+                    } else {
+                        // The expansion is parsed as the inside of a block, so that it can be any number of
+                        // statements
+                        let tokens = self.expand_macro(&im.mac, current_scope_id)?;
+                        let expanded: Block = match syn::parse2(quote_block(tokens)) {
+                            Ok(expanded) => expanded,
+                            Err(_) => bail!(
+                                "Expansion of {}! is not a valid list of statements",
+                                macro_name(&im.mac)
+                            ),
+                        };
+                        self.macro_depth += 1;
+                        let result =
+                            self.expand_stmts(&expanded.stmts, current_scope_id, processed_block);
+                        self.macro_depth -= 1;
+                        result?;
+                    }
+                }
+                Stmt::Item(ref item) => {
+                    self.prepare_item(item.clone(), current_scope_id)?;
+                }
+                _ => processed_block.push(stmt),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the given variables, which are going out of scope, in reverse order of declaration.  If there is a
+    /// result value on top of the stack, it's kept there.
+    fn convert_scope_exit_to_bytecode(
+        &mut self,
+        var_ids: &[usize],
+        result_ty: DefinitionId,
+        bytecode: &mut Vec<Bytecode>,
+        var_stack: &mut VarStack,
+    ) -> Result<()> {
+        let mut dropped = vec![];
+        for var_id in var_ids.iter().rev() {
+            let ty = var_stack.vars[*var_id].ty;
+            if self.process_drop_glue(ty)? {
+                dropped.push((*var_id, ty));
+            }
+        }
+
+        if dropped.is_empty() {
+            return Ok(());
+        }
+
+        if result_ty == builtin_type::VOID {
+            bytecode.push(Bytecode::DropVars(dropped));
+        } else {
+            // Stash the result so that it is computed before the drops run
+            let result_var_id = var_stack.add_var("{result}".to_string(), result_ty);
+            var_stack.var_stack.pop();
+            bytecode.push(Bytecode::VarDecl(result_var_id));
+            bytecode.push(Bytecode::DropVars(dropped));
+            bytecode.push(Bytecode::Var(result_var_id));
+        }
+
+        Ok(())
+    }
+
+    /// Finds the type `Self` refers to in the given scope
+    fn self_ty(&self, scope_id: ScopeId) -> Result<DefinitionId> {
+        match self.get_defn("Self", scope_id) {
+            Some((definition_id, _)) => Ok(definition_id),
+            None => bail!("Use of self outside of an impl"),
+        }
     }
 
+    /// Adds a variable declared by the name at the span, recording the name as its declaration
+    fn declare_var(
+        &mut self,
+        var_stack: &mut VarStack,
+        name: String,
+        span: Span,
+        ty: DefinitionId,
+    ) -> usize {
+        self.record_symbol(span, &name, SymbolTarget::Var(None, var_stack.vars.len()));
+        var_stack.add_var(name, ty)
+    }
+
+    /// Converts a statement, recording where it is so that errors in it, and in the instructions it's converted to,
+    /// can point at it
     pub fn convert_stmt_to_bytecode(
         &mut self,
         stmt: &Stmt,
@@ -155,16 +395,87 @@ impl BytecodeEngine {
         bytecode: &mut Vec<Bytecode>,
         current_scope_id: ScopeId,
         var_stack: &mut VarStack,
-    ) -> DefinitionId {
-        match stmt {
+    ) -> Result<DefinitionId> {
+        let location = self.location(stmt.span());
+        let start = bytecode.len();
+        let locations_len = self.stmt_locations.len();
+        let op_locations_len = self.op_locations.len();
+
+        let result = self.convert_stmt_contents_to_bytecode(
+            stmt,
+            expected_return_type,
+            bytecode,
+            current_scope_id,
+            var_stack,
+        );
+        let ty = match result {
+            Ok(ty) => ty,
+            Err(e) => {
+                // The statements inside it are left out along with it
+                self.stmt_locations.truncate(locations_len);
+                self.op_locations.truncate(op_locations_len);
+                return Err(e.at(location));
+            }
+        };
+
+        // The statements in blocks inside this one were checked on their own, so only the rest of it is reported.
+        // Problems with an operator are reported where it is, and the rest at the start of the statement.
+        let nested = &self.stmt_locations[locations_len..];
+        let op_locations = &self.op_locations[op_locations_len..];
+        let mut errors: Vec<PeachError> = self
+            .check_types(&bytecode[start..], &var_stack.vars)
+            .into_iter()
+            .filter(|(idx, _)| {
+                !nested
+                    .iter()
+                    .any(|(range, _)| range.contains(&(start + idx)))
+            })
+            .map(|(idx, message)| {
+                let op_location = op_locations
+                    .iter()
+                    .find(|(op_idx, _)| *op_idx == start + idx)
+                    .map(|(_, op_location)| op_location.clone());
+                PeachError::Conversion(message, op_location.or_else(|| location.clone()))
+            })
+            .collect();
+        self.op_locations.truncate(op_locations_len);
+        if let Some(last) = errors.pop() {
+            self.stmt_locations.truncate(locations_len);
+            self.errors.append(&mut errors);
+            return Err(last);
+        }
+
+        if let Some(location) = location {
+            self.stmt_locations.push((start..bytecode.len(), location));
+        }
+        Ok(ty)
+    }
+
+    fn convert_stmt_contents_to_bytecode(
+        &mut self,
+        stmt: &Stmt,
+        expected_return_type: DefinitionId,
+        bytecode: &mut Vec<Bytecode>,
+        current_scope_id: ScopeId,
+        var_stack: &mut VarStack,
+    ) -> Result<DefinitionId> {
+        let ty = match stmt {
             Stmt::Semi(ref e, _) => {
-                self.convert_expr_to_bytecode(
+                let ty = self.convert_expr_to_bytecode(
                     e,
                     expected_return_type,
                     bytecode,
                     current_scope_id,
                     var_stack,
-                );
+                )?;
+
+                // A value the statement throws away is dropped at the end of it
+                if ty != builtin_type::VOID && self.process_drop_glue(ty)? {
+                    let temporary_var_id = var_stack.add_var("{temporary}".to_string(), ty);
+                    var_stack.var_stack.pop();
+                    bytecode.push(Bytecode::VarDecl(temporary_var_id));
+                    bytecode.push(Bytecode::DropVars(vec![(temporary_var_id, ty)]));
+                }
                 builtin_type::VOID
             }
             Stmt::Expr(ref e) => self.convert_expr_to_bytecode(
@@ -173,38 +484,55 @@ impl BytecodeEngine {
                 bytecode,
                 current_scope_id,
                 var_stack,
-            ),
+            )?,
             Stmt::Local(ref l) => {
-                let ident = match l.pats[0] {
-                    Pat::Ident(ref pi) => pi.ident.to_string(),
-                    _ => unimplemented!("Unsupported pattern in variable declaration"),
+                let pat_ident = match l.pats[0] {
+                    Pat::Ident(ref pi) => pi.ident,
+                    _ => bail!("Unsupported pattern in variable declaration"),
                 };
+                let ident = pat_ident.to_string();
                 match l.init {
                     Some(ref foo) => {
-                        let rhs_ty = self.convert_expr_to_bytecode(
-                            &*foo.1,
-                            expected_return_type,
-                            bytecode,
-                            current_scope_id,
-                            var_stack,
-                        );
+                        let rhs_ty = match (&*foo.1, &l.ty) {
+                            // The explicit type can give the type arguments of a generic struct
+                            (Expr::Struct(es), Some(explicit_ty)) => {
+                                let ty_hint =
+                                    self.resolve_type(&explicit_ty.1, current_scope_id)?;
+                                self.convert_struct_expr_to_bytecode(
+                                    es,
+                                    Some(ty_hint),
+                                    expected_return_type,
+                                    bytecode,
+                                    current_scope_id,
+                                    var_stack,
+                                )?
+                            }
+                            _ => self.convert_expr_to_bytecode(
+                                &foo.1,
+                                expected_return_type,
+                                bytecode,
+                                current_scope_id,
+                                var_stack,
+                            )?,
+                        };
 
                         match l.ty {
                             None => {
-                                let var_id = var_stack.add_var(ident, rhs_ty);
+                                let var_id =
+                                    self.declare_var(var_stack, ident, pat_ident.span(), rhs_ty);
                                 bytecode.push(Bytecode::VarDecl(var_id));
                                 builtin_type::VOID
                             }
                             Some(ref explicit_ty) => {
-                                let var_ty = self.resolve_type(&*explicit_ty.1, current_scope_id);
+                                let var_ty = self.resolve_type(&explicit_ty.1, current_scope_id)?;
 
-                                if !self.assignment_compatible(var_ty, rhs_ty) {
-                                    unimplemented!(
-                                        "Explicit variable type '{}' does not match expression type '{}'", self.printable_name(var_ty), self.printable_name(rhs_ty)
-                                    )
+                                // The value is converted to the declared type either way
+                                if !self.widens(var_ty, rhs_ty) {
+                                    self.expect_type(var_ty, rhs_ty, foo.1.span())?;
                                 }
 
-                                let var_id = var_stack.add_var(ident, var_ty);
+                                let var_id =
+                                    self.declare_var(var_stack, ident, pat_ident.span(), var_ty);
                                 bytecode.push(Bytecode::As(var_ty));
                                 bytecode.push(Bytecode::VarDecl(var_id));
 
@@ -215,13 +543,19 @@ impl BytecodeEngine {
                     None => {
                         match l.ty {
                             None => {
-                                let var_id = var_stack.add_var(ident, builtin_type::UNKNOWN);
+                                let var_id = self.declare_var(
+                                    var_stack,
+                                    ident,
+                                    pat_ident.span(),
+                                    builtin_type::UNKNOWN,
+                                );
                                 bytecode.push(Bytecode::VarDeclUninit(var_id));
                             }
                             Some(ref explicit_ty) => {
-                                let var_ty = self.resolve_type(&*explicit_ty.1, current_scope_id);
+                                let var_ty = self.resolve_type(&explicit_ty.1, current_scope_id)?;
 
-                                let var_id = var_stack.add_var(ident, var_ty);
+                                let var_id =
+                                    self.declare_var(var_stack, ident, pat_ident.span(), var_ty);
                                 bytecode.push(Bytecode::VarDeclUninit(var_id));
                             }
                         }
@@ -231,7 +565,9 @@ impl BytecodeEngine {
                 }
             }
             _ => builtin_type::VOID, // ignore Item(s) as we've already processed them
-        }
+        };
+
+        Ok(ty)
     }
 
     fn convert_lhs_expr_to_bytecode(
@@ -241,29 +577,40 @@ impl BytecodeEngine {
         bytecode: &mut Vec<Bytecode>,
         current_scope_id: ScopeId,
         var_stack: &mut VarStack,
-    ) -> DefinitionId {
-        match expr {
+    ) -> Result<DefinitionId> {
+        let ty = match expr {
             Expr::Path(ep) => {
                 let ident = ep.path.segments[0].ident.to_string();
 
                 let var_id = var_stack.find_var(&ident);
                 if var_id.is_none() {
-                    unimplemented!("Could not find variable: {}", ident);
+                    bail!("Could not find variable: {}", ident);
                 }
                 let var_id = var_id.unwrap();
+                let span = ep.path.segments[0].ident.span();
+                self.record_symbol(span, &ident, SymbolTarget::Var(None, var_id));
                 let var = &mut var_stack.vars[var_id];
                 bytecode.push(Bytecode::LValueVar(var_id));
 
                 var.ty
             }
             Expr::Field(ef) => {
-                let ty = self.convert_lhs_expr_to_bytecode(
+                let mut ty = self.convert_lhs_expr_to_bytecode(
                     &*ef.base,
                     expected_return_type,
                     bytecode,
                     current_scope_id,
                     var_stack,
-                );
+                )?;
+
+                // Field access automatically looks through references
+                while let Definition::Reference(referenced, _) = self.definitions[ty] {
+                    bytecode.push(Bytecode::LValueDeref);
+                    ty = referenced;
+                }
+                if ty == builtin_type::ERROR {
+                    return Ok(builtin_type::ERROR);
+                }
 
                 if let Definition::Struct(ref st) = self.definitions[ty] {
                     match ef.member {
@@ -271,19 +618,48 @@ impl BytecodeEngine {
                             bytecode.push(Bytecode::LValueDot(ident.to_string()));
                             for field in &st.fields {
                                 if field.0 == ident.as_ref() {
-                                    return field.1;
+                                    return Ok(field.1);
                                 }
                             }
-                            unimplemented!("Field access of {} not found", ident);
+                            bail!("Field access of {} not found", ident);
                         }
-                        _ => unimplemented!("Unsupported member access"),
+                        _ => bail!("Unsupported member access"),
                     }
                 } else {
-                    unimplemented!("Member access on non-struct types");
+                    bail!("Member access on non-struct types");
                 }
             }
-            _ => unimplemented!("Unsupport lvalue type"),
-        }
+            Expr::Unary(eu) => match eu.op {
+                UnOp::Deref(_) => {
+                    let ty = self.convert_lhs_expr_to_bytecode(
+                        &eu.expr,
+                        expected_return_type,
+                        bytecode,
+                        current_scope_id,
+                        var_stack,
+                    )?;
+                    if ty == builtin_type::ERROR {
+                        return Ok(builtin_type::ERROR);
+                    }
+
+                    match self.definitions[ty] {
+                        Definition::Reference(referenced, true) => {
+                            bytecode.push(Bytecode::LValueDeref);
+                            referenced
+                        }
+                        Definition::Reference(_, false) => bail!(
+                            "Can not assign through a shared reference of type {}",
+                            self.ty(ty)
+                        ),
+                        _ => bail!("Can not dereference value of type {}", self.ty(ty)),
+                    }
+                }
+                _ => bail!("Unsupport lvalue type"),
+            },
+            _ => bail!("Unsupport lvalue type"),
+        };
+
+        Ok(ty)
     }
     pub fn convert_expr_to_bytecode(
         &mut self,
@@ -292,32 +668,45 @@ impl BytecodeEngine {
         bytecode: &mut Vec<Bytecode>,
         current_scope_id: ScopeId,
         var_stack: &mut VarStack,
-    ) -> DefinitionId {
-        match expr {
+    ) -> Result<DefinitionId> {
+        let ty = match expr {
             Expr::Return(er) => {
-                let actual_return_type = match er.expr {
+                let mut actual_return_type = match er.expr {
                     Some(ref inner) => self.convert_expr_to_bytecode(
                         inner,
                         expected_return_type,
                         bytecode,
                         current_scope_id,
                         var_stack,
-                    ),
+                    )?,
                     None => builtin_type::VOID,
                 };
 
+                if self.widens(expected_return_type, actual_return_type) {
+                    bytecode.push(Bytecode::As(expected_return_type));
+                    actual_return_type = expected_return_type;
+                }
                 if self.assignment_compatible(expected_return_type, actual_return_type) {
+                    // Returning leaves every scope in the function
+                    let var_ids = var_stack.var_stack.clone();
+                    self.convert_scope_exit_to_bytecode(
+                        &var_ids,
+                        actual_return_type,
+                        bytecode,
+                        var_stack,
+                    )?;
+
                     match actual_return_type {
                         builtin_type::VOID => bytecode.push(Bytecode::ReturnVoid),
                         _ => bytecode.push(Bytecode::ReturnLastStackValue),
                     }
                     builtin_type::VOID
                 } else {
-                    unimplemented!(
-                        "Mismatched return types: {} and {}",
-                        self.printable_name(actual_return_type),
-                        self.printable_name(expected_return_type)
-                    );
+                    let span = match er.expr {
+                        Some(ref inner) => inner.span(),
+                        None => er.span(),
+                    };
+                    return Err(self.mismatch(expected_return_type, actual_return_type, span));
                 }
             }
             Expr::Lit(el) => match el.lit {
@@ -326,20 +715,36 @@ impl BytecodeEngine {
                         bytecode.push(Bytecode::PushU64(li.value()));
                         builtin_type::U64
                     }
-                    IntSuffix::U32 => {
-                        bytecode.push(Bytecode::PushU32(li.value() as u32));
-                        builtin_type::U32
-                    }
-                    IntSuffix::I64 => {
-                        bytecode.push(Bytecode::PushI64(li.value() as i64));
-                        builtin_type::I64
-                    }
-                    IntSuffix::I32 => {
-                        bytecode.push(Bytecode::PushI32(li.value() as i32));
-                        builtin_type::I32
-                    }
+                    IntSuffix::U32 => match u32::try_from(li.value()) {
+                        Ok(value) => {
+                            bytecode.push(Bytecode::PushU32(value));
+                            builtin_type::U32
+                        }
+                        Err(_) => {
+                            bail_at!(self.location(li.span()), "Literal out of range for u32")
+                        }
+                    },
+                    IntSuffix::I64 => match i64::try_from(li.value()) {
+                        Ok(value) => {
+                            bytecode.push(Bytecode::PushI64(value));
+                            builtin_type::I64
+                        }
+                        Err(_) => {
+                            bail_at!(self.location(li.span()), "Literal out of range for i64")
+                        }
+                    },
+                    IntSuffix::I32 => match i32::try_from(li.value()) {
+                        Ok(value) => {
+                            bytecode.push(Bytecode::PushI32(value));
+                            builtin_type::I32
+                        }
+                        Err(_) => {
+                            bail_at!(self.location(li.span()), "Literal out of range for i32")
+                        }
+                    },
                     _ => {
-                        bytecode.push(Bytecode::PushUnknownInt(li.value() as i32));
+                        // The whole value is kept until the literal's type is inferred
+                        bytecode.push(Bytecode::PushUnknownInt(i128::from(li.value())));
                         builtin_type::UNKNOWN_INT
                     }
                 },
@@ -347,7 +752,7 @@ impl BytecodeEngine {
                     bytecode.push(Bytecode::PushBool(lb.value));
                     builtin_type::BOOL
                 }
-                _ => unimplemented!("unknown literal: {:?}", el),
+                _ => bail!("unknown literal: {:?}", el),
             },
             Expr::Paren(ep) => self.convert_expr_to_bytecode(
                 &*ep.expr,
@@ -355,22 +760,23 @@ impl BytecodeEngine {
                 bytecode,
                 current_scope_id,
                 var_stack,
-            ),
+            )?,
             Expr::Block(eb) => self.convert_block_to_bytecode(
                 &eb.block,
                 expected_return_type,
                 bytecode,
                 Some(current_scope_id),
                 var_stack,
-            ),
+            )?,
             Expr::Assign(ea) => {
-                let rhs_type = self.convert_expr_to_bytecode(
+                let mut rhs_type = self.convert_expr_to_bytecode(
                     &*ea.right,
                     expected_return_type,
                     bytecode,
                     current_scope_id,
                     var_stack,
-                );
+                )?;
+                let rhs_end = bytecode.len();
 
                 let lhs_type = self.convert_lhs_expr_to_bytecode(
                     &*ea.left,
@@ -378,18 +784,39 @@ impl BytecodeEngine {
                     bytecode,
                     current_scope_id,
                     var_stack,
-                );
+                )?;
+
+                // The lvalue is pushed after the value, so the value is widened before it
+                if self.widens(lhs_type, rhs_type) {
+                    bytecode.insert(rhs_end, Bytecode::As(lhs_type));
+                    rhs_type = lhs_type;
+                }
 
                 if self.assignment_compatible(lhs_type, rhs_type) {
-                    let tighter_type = self.tighter_of_types(lhs_type, rhs_type);
+                    // A variable declared without a type or value gets one from what it's assigned
+                    let tighter_type = self.unify(lhs_type, rhs_type).unwrap_or(lhs_type);
                     match bytecode.last() {
                         Some(Bytecode::LValueVar(var_id)) => {
                             var_stack.vars[*var_id].ty = tighter_type;
                         }
                         _ => {}
                     }
+
+                    // Overwriting a whole variable drops its old value (once the new value is computed)
+                    if let Some(Bytecode::LValueVar(var_id)) = bytecode.last().cloned() {
+                        if self.process_drop_glue(lhs_type)? {
+                            bytecode.pop();
+                            let new_value_var_id =
+                                var_stack.add_var("{new value}".to_string(), rhs_type);
+                            var_stack.var_stack.pop();
+                            bytecode.push(Bytecode::VarDecl(new_value_var_id));
+                            bytecode.push(Bytecode::DropVars(vec![(var_id, lhs_type)]));
+                            bytecode.push(Bytecode::Var(new_value_var_id));
+                            bytecode.push(Bytecode::LValueVar(var_id));
+                        }
+                    }
                 } else {
-                    unimplemented!("Assignment between {:?} and {:?}", lhs_type, rhs_type);
+                    return Err(self.mismatch(lhs_type, rhs_type, ea.right.span()));
                 }
 
                 bytecode.push(Bytecode::Assign);
@@ -397,18 +824,13 @@ impl BytecodeEngine {
                 builtin_type::VOID
             }
             Expr::If(ei) => {
-                let cond_type = self.convert_expr_to_bytecode(
+                self.convert_expr_to_bytecode(
                     &*ei.cond,
                     expected_return_type,
                     bytecode,
                     current_scope_id,
                     var_stack,
-                );
-
-                match cond_type {
-                    builtin_type::BOOL => {}
-                    _ => unimplemented!("If condition needs to be boolean"),
-                }
+                )?;
 
                 bytecode.push(Bytecode::If(0, builtin_type::VOID));
                 let before_then_block_len = bytecode.len();
@@ -419,9 +841,10 @@ impl BytecodeEngine {
                     bytecode,
                     Some(current_scope_id),
                     var_stack,
-                );
+                )?;
                 let after_then_block_len = bytecode.len();
 
+                let mut if_ty = then_ty;
                 if let Some(ref else_branch) = ei.else_branch {
                     bytecode.push(Bytecode::Else(0, builtin_type::VOID));
                     match *else_branch.1 {
@@ -432,44 +855,50 @@ impl BytecodeEngine {
                                 bytecode,
                                 Some(current_scope_id),
                                 var_stack,
-                            );
-
-                            if then_ty != else_ty {
-                                unimplemented!("If then/else blocks have mismatching types");
-                            }
-                            bytecode[after_then_block_len] = Bytecode::Else(
-                                bytecode.len() - after_then_block_len,
-                                else_ty.clone(),
-                            );
+                            )?;
+
+                            // The value of the if is what both branches can be, eg) a literal in one branch takes
+                            // the type of the other
+                            if_ty = match self.unify(then_ty, else_ty) {
+                                Some(ty) => ty,
+                                None => {
+                                    let span = match eb.block.stmts.last() {
+                                        Some(stmt) => stmt.span(),
+                                        None => eb.span(),
+                                    };
+                                    bail_at!(
+                                        self.location(span),
+                                        "If and else have incompatible types: expected {}, found {}",
+                                        self.ty(then_ty),
+                                        self.ty(else_ty)
+                                    );
+                                }
+                            };
+                            bytecode[after_then_block_len] =
+                                Bytecode::Else(bytecode.len() - after_then_block_len, if_ty);
                         }
-                        _ => unimplemented!("Unsupported else block"),
+                        _ => bail!("Unsupported else block"),
                     }
                 }
-                bytecode.push(Bytecode::EndIf(then_ty));
+                bytecode.push(Bytecode::EndIf(if_ty));
 
                 // Patch the original offset to the correct offset
-                bytecode[before_then_block_len - 1] = Bytecode::If(
-                    after_then_block_len - before_then_block_len + 2,
-                    then_ty.clone(),
-                );
+                bytecode[before_then_block_len - 1] =
+                    Bytecode::If(after_then_block_len - before_then_block_len + 2, if_ty);
 
-                then_ty
+                if_ty
             }
             Expr::While(ew) => {
                 let before_cond_len = bytecode.len();
                 bytecode.push(Bytecode::BeginWhile);
 
-                let cond_type = self.convert_expr_to_bytecode(
+                self.convert_expr_to_bytecode(
                     &*ew.cond,
                     expected_return_type,
                     bytecode,
                     current_scope_id,
                     var_stack,
-                );
-
-                if cond_type != builtin_type::BOOL {
-                    unimplemented!("While condition needs to be boolean");
-                }
+                )?;
 
                 bytecode.push(Bytecode::WhileCond(0));
                 let before_block_len = bytecode.len();
@@ -480,7 +909,7 @@ impl BytecodeEngine {
                     bytecode,
                     Some(current_scope_id),
                     var_stack,
-                );
+                )?;
 
                 let after_block_len = bytecode.len();
                 bytecode.push(Bytecode::EndWhile(after_block_len - before_cond_len));
@@ -493,205 +922,118 @@ impl BytecodeEngine {
             }
             Expr::Unary(eu) => match eu.op {
                 UnOp::Neg(_a) => {
+                    // The minimum of a signed type is one past its maximum until it's negated, so a suffixed
+                    // literal for it is only in range as the operand of a negation
+                    if let Expr::Lit(syn::ExprLit {
+                        lit: Lit::Int(ref li),
+                        ..
+                    }) = *eu.expr
+                    {
+                        match li.suffix() {
+                            IntSuffix::I64 if li.value() == 1 << 63 => {
+                                bytecode.push(Bytecode::PushI64(i64::MIN));
+                                return Ok(builtin_type::I64);
+                            }
+                            IntSuffix::I32 if li.value() == 1 << 31 => {
+                                bytecode.push(Bytecode::PushI32(i32::MIN));
+                                return Ok(builtin_type::I32);
+                            }
+                            _ => {}
+                        }
+                    }
+
                     let expr_type = self.convert_expr_to_bytecode(
                         &*eu.expr,
                         expected_return_type,
                         bytecode,
                         current_scope_id,
                         var_stack,
-                    );
+                    )?;
 
-                    match expr_type {
-                        builtin_type::U32 => {
-                            unimplemented!("Can't negate value of type u32");
-                        }
-                        builtin_type::U64 => {
-                            unimplemented!("Can't negate value of type u64");
-                        }
-                        builtin_type::UNKNOWN_INT => {
-                            bytecode.push(Bytecode::Neg);
-                            builtin_type::UNKNOWN_INT
-                        }
-                        builtin_type::I32 => {
-                            bytecode.push(Bytecode::Neg);
-                            builtin_type::I32
-                        }
-                        builtin_type::I64 => {
-                            bytecode.push(Bytecode::Neg);
-                            builtin_type::I64
-                        }
-                        _ => unimplemented!("Negate of non-numeric type"),
-                    }
-                }
-                _ => unimplemented!("Unsupport unary operator"),
-            },
-            Expr::Binary(eb) => match eb.op {
-                BinOp::Add(_a) => {
-                    let lhs_type = self.convert_expr_to_bytecode(
-                        &*eb.left,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                    let rhs_type = self.convert_expr_to_bytecode(
-                        &*eb.right,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                    if self.operator_compatible(lhs_type, rhs_type) {
-                        bytecode.push(Bytecode::Add);
-                        self.tighter_of_types(lhs_type, rhs_type)
-                    } else {
-                        unimplemented!(
-                            "Can't add values of {:?} and {:?}",
-                            self.printable_name(lhs_type),
-                            self.printable_name(rhs_type)
-                        );
-                    }
-                }
-                BinOp::Sub(_a) => {
-                    let lhs_type = self.convert_expr_to_bytecode(
-                        &*eb.left,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                    let rhs_type = self.convert_expr_to_bytecode(
-                        &*eb.right,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                    if self.operator_compatible(lhs_type, rhs_type) {
-                        bytecode.push(Bytecode::Sub);
-                        self.tighter_of_types(lhs_type, rhs_type)
-                    } else {
-                        unimplemented!(
-                            "Can't subtract values of {:?} and {:?}",
-                            lhs_type,
-                            rhs_type
-                        );
-                    }
+                    bytecode.push(Bytecode::Neg);
+                    self.neg_type(expr_type).unwrap_or(builtin_type::ERROR)
                 }
-                BinOp::Mul(_a) => {
-                    let lhs_type = self.convert_expr_to_bytecode(
-                        &*eb.left,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                    let rhs_type = self.convert_expr_to_bytecode(
-                        &*eb.right,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                    if self.operator_compatible(lhs_type, rhs_type) {
-                        bytecode.push(Bytecode::Mul);
-                        self.tighter_of_types(lhs_type, rhs_type)
-                    } else {
-                        unimplemented!(
-                            "Can't multiply values of {:?} and {:?}",
-                            lhs_type,
-                            rhs_type
-                        );
-                    }
-                }
-                BinOp::Div(_a) => {
-                    let lhs_type = self.convert_expr_to_bytecode(
-                        &*eb.left,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                    let rhs_type = self.convert_expr_to_bytecode(
-                        &*eb.right,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                    if self.operator_compatible(lhs_type, rhs_type) {
-                        bytecode.push(Bytecode::Div);
-                        self.tighter_of_types(lhs_type, rhs_type)
-                    } else {
-                        unimplemented!("Can't divide values of {:?} and {:?}", lhs_type, rhs_type);
-                    }
-                }
-                BinOp::Lt(_a) => {
-                    let lhs_type = self.convert_expr_to_bytecode(
-                        &*eb.left,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                    let rhs_type = self.convert_expr_to_bytecode(
-                        &*eb.right,
+                UnOp::Deref(_) => {
+                    let expr_type = self.convert_expr_to_bytecode(
+                        &eu.expr,
                         expected_return_type,
                         bytecode,
                         current_scope_id,
                         var_stack,
-                    );
+                    )?;
 
-                    if self.operator_compatible(lhs_type, rhs_type) {
-                        bytecode.push(Bytecode::Lt);
-                        builtin_type::BOOL
+                    if let Definition::Reference(referenced, _) = self.definitions[expr_type] {
+                        bytecode.push(Bytecode::Deref);
+                        referenced
+                    } else if expr_type == builtin_type::ERROR {
+                        builtin_type::ERROR
                     } else {
-                        unimplemented!("Can't compare values of {:?} and {:?}", lhs_type, rhs_type);
+                        bail!("Can not dereference value of type {}", self.ty(expr_type));
                     }
                 }
-                _ => unimplemented!("Unknown operator: {:?}", eb.op),
+                _ => bail!("Unsupport unary operator"),
             },
-            Expr::Struct(es) => {
-                let mut fields = vec![];
-                for field in &es.fields {
-                    match field.member {
-                        Member::Named(name) => {
-                            fields.push((name.to_string(), &field.expr));
-                        }
-                        _ => unimplemented!("Unnamed struct members not yet supported"),
-                    }
-                }
-
-                //TODO: FIXME: would be great to not have to clone here
-                fields.sort_by_key(|x| x.0.clone());
+            Expr::Reference(er) => {
+                let mutable = er.mutability.is_some();
+                let ty = self.convert_lhs_expr_to_bytecode(
+                    &er.expr,
+                    expected_return_type,
+                    bytecode,
+                    current_scope_id,
+                    var_stack,
+                )?;
+                bytecode.push(Bytecode::Borrow(mutable));
 
-                for field in fields {
-                    self.convert_expr_to_bytecode(
-                        field.1,
-                        expected_return_type,
-                        bytecode,
-                        current_scope_id,
-                        var_stack,
-                    );
-                }
-                if let Some(definition_id) = self.process_path(&es.path, current_scope_id) {
-                    if let Definition::Struct(_) = self.definitions[definition_id] {
-                        bytecode.push(Bytecode::Call(definition_id));
-                        definition_id
-                    } else {
-                        unimplemented!("Unsupport definition type in struct call");
-                    }
-                } else {
-                    unimplemented!("Can't process struct");
+                self.reference_ty(ty, mutable)
+            }
+            Expr::Binary(eb) => {
+                let op = match eb.op {
+                    BinOp::Add(_) => Bytecode::Add,
+                    BinOp::Sub(_) => Bytecode::Sub,
+                    BinOp::Mul(_) => Bytecode::Mul,
+                    BinOp::Div(_) => Bytecode::Div,
+                    BinOp::Lt(_) => Bytecode::Lt,
+                    _ => bail!("Unknown operator: {:?}", eb.op),
+                };
+                let lhs_type = self.convert_expr_to_bytecode(
+                    &eb.left,
+                    expected_return_type,
+                    bytecode,
+                    current_scope_id,
+                    var_stack,
+                )?;
+                let rhs_type = self.convert_expr_to_bytecode(
+                    &eb.right,
+                    expected_return_type,
+                    bytecode,
+                    current_scope_id,
+                    var_stack,
+                )?;
+                // Operands that can't be used together are reported by `check_types`, at the operator
+                let ty = self
+                    .binary_op_type(&op, lhs_type, rhs_type)
+                    .unwrap_or(builtin_type::ERROR);
+                if let Some(location) = self.location(eb.op.span()) {
+                    self.op_locations.push((bytecode.len(), location));
                 }
+                bytecode.push(op);
+                ty
             }
+            Expr::Struct(es) => self.convert_struct_expr_to_bytecode(
+                es,
+                None,
+                expected_return_type,
+                bytecode,
+                current_scope_id,
+                var_stack,
+            )?,
             Expr::Path(ep) => {
-                if let Some(definition_id) = self.process_path(&ep.path, current_scope_id) {
+                if let Some(definition_id) = self.process_path(&ep.path, current_scope_id)? {
                     if let Definition::Struct(_) = self.definitions[definition_id] {
                         bytecode.push(Bytecode::Call(definition_id));
                         definition_id
                     } else {
-                        unimplemented!("Unsupport definition type in struct call");
+                        bail!("Unsupport definition type in struct call");
                     }
                 } else {
                     let ident = ep.path.segments[0].ident.to_string();
@@ -701,14 +1043,16 @@ impl BytecodeEngine {
                             bytecode.push(Bytecode::PushRawPtr(ptr::null()));
                             builtin_type::VOID_PTR
                         } else {
-                            unimplemented!("Could not find {}", ident);
+                            bail!("Could not find {}", ident);
                         }
                     } else {
                         let var_id = var_id.unwrap();
+                        let span = ep.path.segments[0].ident.span();
+                        self.record_symbol(span, &ident, SymbolTarget::Var(None, var_id));
                         let var = &var_stack.vars[var_id];
 
                         if var.ty == builtin_type::UNKNOWN {
-                            unimplemented!("{} used before being given a value", ident);
+                            bail!("{} used before being given a value", ident);
                         }
 
                         bytecode.push(Bytecode::Var(var_id));
@@ -720,15 +1064,37 @@ impl BytecodeEngine {
             Expr::Call(ec) => match *ec.func {
                 Expr::Path(ref ep) => {
                     if ep.path.segments.len() == 1 && ep.path.segments[0].ident == "__debug__" {
-                        let definition_id = self.convert_expr_to_bytecode(
+                        if !self.config.allow_print {
+                            bail!("println! is not allowed by this engine's configuration");
+                        }
+                        let mut definition_id = self.convert_expr_to_bytecode(
                             &ec.args[0],
                             expected_return_type,
                             bytecode,
                             current_scope_id,
                             var_stack,
-                        );
+                        )?;
+
+                        // Like println!, print the value behind any references
+                        while let Definition::Reference(referenced, _) =
+                            self.definitions[definition_id]
+                        {
+                            bytecode.push(Bytecode::Deref);
+                            definition_id = referenced;
+                        }
                         bytecode.push(Bytecode::DebugPrint(definition_id));
                         builtin_type::VOID
+                    } else if ep.path.segments.len() == 1
+                        && self.is_builtin_fn(ep.path.segments[0].ident.as_ref(), current_scope_id)
+                    {
+                        self.convert_builtin_call(
+                            ep.path.segments[0].ident.as_ref(),
+                            ec,
+                            expected_return_type,
+                            bytecode,
+                            current_scope_id,
+                            var_stack,
+                        )?
                     } else {
                         // If we're in a single ident path, check values in scope
                         if ep.path.segments.len() == 1 && ep.path.leading_colon.is_none() {
@@ -736,17 +1102,17 @@ impl BytecodeEngine {
                             let var_result = var_stack.find_var(ident.as_ref());
                             if let Some(var_id) = var_result {
                                 //TODO: FIXME: in the future check this for lambda
-                                unimplemented!(
+                                bail!(
                                     "Can not call function on type {:?}",
                                     var_stack.vars[var_id].ty
                                 );
                             }
                         }
 
-                        let definition_id = self.process_path(&ep.path, current_scope_id);
+                        let definition_id = self.process_path(&ep.path, current_scope_id)?;
 
                         if definition_id.is_none() {
-                            unimplemented!("Could not find call for {:?}", ep.path);
+                            bail!("Could not find call for {:?}", ep.path);
                         }
 
                         //TODO: FIXME: please don't do this
@@ -763,55 +1129,95 @@ impl BytecodeEngine {
                                         bytecode,
                                         current_scope_id,
                                         var_stack,
-                                    );
+                                    )?;
                                     arg_tys.push(arg_ty);
                                 }
 
-                                //TODO: FIXME: probably not the best scope id for this
-                                let (instance_definition_id, return_ty) = self.instantiate_generic_fn(
-                                    definition_id,
-                                    current_scope_id,
-                                    &arg_tys,
-                                );
+                                let (instance_definition_id, return_ty) =
+                                    self.instantiate_generic_fn(definition_id, &arg_tys)?;
 
                                 bytecode.push(Bytecode::Call(instance_definition_id));
 
                                 return_ty
-                            } else {
+                            } else if let Some(native_id) = target_fn.native_id {
+                                // Natives can't be checked by converting a body, so their arguments are checked
+                                // against the signature they were registered with
+                                let param_tys: Vec<DefinitionId> =
+                                    target_fn.params.iter().map(|x| x.ty).collect();
                                 let return_ty = target_fn.return_ty;
 
-                                for arg in &ec.args {
-                                    self.convert_expr_to_bytecode(
+                                if ec.args.len() != param_tys.len() {
+                                    bail!(
+                                        "{} takes {} arguments but {} were given",
+                                        ep.path.segments[ep.path.segments.len() - 1].ident,
+                                        param_tys.len(),
+                                        ec.args.len()
+                                    );
+                                }
+                                for (arg, param_ty) in ec.args.iter().zip(param_tys) {
+                                    let arg_ty = self.convert_expr_to_bytecode(
                                         arg,
                                         expected_return_type,
                                         bytecode,
                                         current_scope_id,
                                         var_stack,
+                                    )?;
+                                    self.coerce(param_ty, arg_ty, arg.span(), bytecode)?;
+                                }
+
+                                bytecode.push(Bytecode::CallNative(native_id));
+
+                                return_ty
+                            } else {
+                                if target_fn.extern_name.is_some() && !self.config.allow_extern_fns
+                                {
+                                    bail!(
+                                        "Calling extern functions is not allowed by this engine's configuration"
                                     );
                                 }
+                                let param_tys: Vec<DefinitionId> =
+                                    target_fn.params.iter().map(|x| x.ty).collect();
+                                let return_ty = target_fn.return_ty;
+
+                                for (arg, param_ty) in ec.args.iter().zip(param_tys) {
+                                    let arg_ty = self.convert_expr_to_bytecode(
+                                        arg,
+                                        expected_return_type,
+                                        bytecode,
+                                        current_scope_id,
+                                        var_stack,
+                                    )?;
+                                    self.coerce(param_ty, arg_ty, arg.span(), bytecode)?;
+                                }
 
                                 bytecode.push(Bytecode::Call(definition_id));
 
                                 return_ty
                             }
                         } else {
-                            unimplemented!(
-                                "Processed function {:?} did not process correctly",
-                                ep.path
-                            );
+                            bail!("Processed function {:?} did not process correctly", ep.path);
                         }
                     }
                 }
-                _ => unimplemented!("unknown function call type: {:#?}", ec.func),
+                _ => bail!("unknown function call type: {:#?}", ec.func),
             },
             Expr::Field(ef) => {
-                let definition_id = self.convert_expr_to_bytecode(
+                let mut definition_id = self.convert_expr_to_bytecode(
                     &*ef.base,
                     expected_return_type,
                     bytecode,
                     current_scope_id,
                     var_stack,
-                );
+                )?;
+
+                // Field access automatically looks through references
+                while let Definition::Reference(referenced, _) = self.definitions[definition_id] {
+                    bytecode.push(Bytecode::Deref);
+                    definition_id = referenced;
+                }
+                if definition_id == builtin_type::ERROR {
+                    return Ok(builtin_type::ERROR);
+                }
 
                 if let Definition::Struct(ref st) = self.definitions[definition_id] {
                     match ef.member {
@@ -819,33 +1225,47 @@ impl BytecodeEngine {
                             bytecode.push(Bytecode::Dot(ident.to_string()));
                             for field in &st.fields {
                                 if field.0 == ident.as_ref() {
-                                    return field.1;
+                                    return Ok(field.1);
                                 }
                             }
-                            unimplemented!("Field access of {} not found", ident);
+                            bail!("Field access of {} not found", ident);
                         }
-                        _ => unimplemented!("Unsupported member access"),
+                        _ => bail!("Unsupported member access"),
                     }
                 } else {
-                    unimplemented!("Member access on non-struct types");
+                    bail!("Member access on non-struct types");
                 }
             }
             Expr::Macro(em) => {
                 if em.mac.path.segments[0].ident.as_ref() == "println" {
-                    let em = em.clone();
-                    let token_stream: TokenStream = em.mac.tts.into_iter().skip(2).collect();
-                    let call = String::new() + "__debug__(" + &token_stream.to_string() + ")";
-                    let result: Expr = syn::parse_str(&call).unwrap();
+                    let result: Expr = syn::parse2(debug_call(&em.mac, false)).unwrap();
                     let definition_id = self.convert_expr_to_bytecode(
                         &result,
                         expected_return_type,
                         bytecode,
                         current_scope_id,
                         var_stack,
-                    );
+                    )?;
                     definition_id
                 } else {
-                    unimplemented!("Can not resolve macro type");
+                    let tokens = self.expand_macro(&em.mac, current_scope_id)?;
+                    let expanded: Expr = match syn::parse2(tokens) {
+                        Ok(expanded) => expanded,
+                        Err(_) => bail!(
+                            "Expansion of {}! is not a valid expression",
+                            macro_name(&em.mac)
+                        ),
+                    };
+                    self.macro_depth += 1;
+                    let definition_id = self.convert_expr_to_bytecode(
+                        &expanded,
+                        expected_return_type,
+                        bytecode,
+                        current_scope_id,
+                        var_stack,
+                    );
+                    self.macro_depth -= 1;
+                    definition_id?
                 }
             }
             Expr::Unsafe(eu) => self.convert_block_to_bytecode(
@@ -854,13 +1274,133 @@ impl BytecodeEngine {
                 bytecode,
                 Some(current_scope_id),
                 var_stack,
+            )?,
+            Expr::MethodCall(emc) => self.convert_method_call_to_bytecode(
+                emc,
+                expected_return_type,
+                bytecode,
+                current_scope_id,
+                var_stack,
+            )?,
+            _ => bail!("Unknown expr type: {:#?}", expr),
+        };
+
+        Ok(ty)
+    }
+
+    /// Converts `receiver.method(args)` into a call of the method from the receiver type's impls, borrowing or
+    /// dereferencing the receiver to match how the method takes `self`
+    fn convert_method_call_to_bytecode(
+        &mut self,
+        emc: &ExprMethodCall,
+        expected_return_type: DefinitionId,
+        bytecode: &mut Vec<Bytecode>,
+        current_scope_id: ScopeId,
+        var_stack: &mut VarStack,
+    ) -> Result<DefinitionId> {
+        // Find the receiver's type without keeping its bytecode, as how it's passed depends on the method
+        let op_locations_len = self.op_locations.len();
+        let receiver_ty = self.convert_expr_to_bytecode(
+            &emc.receiver,
+            expected_return_type,
+            &mut vec![],
+            current_scope_id,
+            &mut var_stack.clone(),
+        )?;
+        self.op_locations.truncate(op_locations_len);
+        if receiver_ty == builtin_type::ERROR {
+            return Ok(builtin_type::ERROR);
+        }
+        let (receiver_is_reference, self_ty) = match self.definitions[receiver_ty] {
+            Definition::Reference(referenced, _) => (true, referenced),
+            _ => (false, receiver_ty),
+        };
+
+        let method_name = emc.method.to_string();
+        let definition_id = match self.process_associated_fn(self_ty, &method_name)? {
+            Some(definition_id) => definition_id,
+            None => bail!(
+                "No method named {} found for {}",
+                method_name,
+                self.ty(self_ty)
             ),
-            _ => unimplemented!("Unknown expr type: {:#?}", expr),
+        };
+
+        let (self_param_ty, param_tys, return_ty) = match self.definitions[definition_id] {
+            Definition::Fun(ref fun) => {
+                if fun.is_generic() {
+                    bail!("Generic methods are not supported");
+                }
+                let param_tys: Vec<DefinitionId> =
+                    fun.params.iter().skip(1).map(|x| x.ty).collect();
+                match fun.params.first() {
+                    Some(param) if param.name == "self" => (param.ty, param_tys, fun.return_ty),
+                    _ => bail!("{} is an associated function, not a method", method_name),
+                }
+            }
+            _ => bail!("Method {} did not process correctly", method_name),
+        };
+
+        match (
+            self.definitions[self_param_ty].clone(),
+            receiver_is_reference,
+        ) {
+            // `&self` and `&mut self` take a reference, borrowing the receiver if it isn't one already
+            (Definition::Reference(_, mutable), false) => {
+                self.convert_lhs_expr_to_bytecode(
+                    &emc.receiver,
+                    expected_return_type,
+                    bytecode,
+                    current_scope_id,
+                    var_stack,
+                )?;
+                bytecode.push(Bytecode::Borrow(mutable));
+            }
+            (Definition::Reference(_, _), true) => {
+                self.convert_expr_to_bytecode(
+                    &emc.receiver,
+                    expected_return_type,
+                    bytecode,
+                    current_scope_id,
+                    var_stack,
+                )?;
+            }
+            // `self` takes the value, copying it out of a reference if needed
+            (_, is_reference) => {
+                self.convert_expr_to_bytecode(
+                    &emc.receiver,
+                    expected_return_type,
+                    bytecode,
+                    current_scope_id,
+                    var_stack,
+                )?;
+                if is_reference {
+                    bytecode.push(Bytecode::Deref);
+                }
+            }
+        }
+
+        for (arg, param_ty) in emc.args.iter().zip(param_tys) {
+            let arg_ty = self.convert_expr_to_bytecode(
+                arg,
+                expected_return_type,
+                bytecode,
+                current_scope_id,
+                var_stack,
+            )?;
+            self.coerce(param_ty, arg_ty, arg.span(), bytecode)?;
         }
+
+        bytecode.push(Bytecode::Call(definition_id));
+        Ok(return_ty)
     }
 
-    pub(crate) fn resolve_type(&mut self, tp: &Type, current_scope_id: ScopeId) -> DefinitionId {
-        match *tp {
+    pub(crate) fn resolve_type(
+        &mut self,
+        tp: &Type,
+        current_scope_id: ScopeId,
+    ) -> Result<DefinitionId> {
+        let ty = match *tp {
             Type::Path(ref tp) => match tp.path.segments[0].ident.as_ref() {
                 "u64" => builtin_type::U64,
                 "u32" => builtin_type::U32,
@@ -868,24 +1408,206 @@ impl BytecodeEngine {
                 "i32" => builtin_type::I32,
                 "bool" => builtin_type::BOOL,
                 _ => {
-                    if let Some(definition_id) = self.process_path(&tp.path, current_scope_id) {
-                        if let Definition::Struct(_) = self.definitions[definition_id] {
-                            definition_id
-                        } else if let Definition::TypeVariable = self.definitions[definition_id] {
-                            definition_id
-                        } else {
-                            unimplemented!("Could not find processed struct for type");
+                    if let Some(definition_id) = self.process_path(&tp.path, current_scope_id)? {
+                        match self.definitions[definition_id] {
+                            // Type parameters of generic structs may name any type
+                            Definition::Struct(_)
+                            | Definition::TypeVariable
+                            | Definition::Builtin
+                            | Definition::Reference(_, _) => definition_id,
+                            Definition::GenericStruct(_, _) => {
+                                let ty_args = self.resolve_type_args(&tp.path, current_scope_id)?;
+                                self.instantiate_generic_struct(definition_id, ty_args)?
+                            }
+                            _ => bail!("Could not find processed struct for type"),
                         }
                     } else {
-                        unimplemented!("Could not find processed struct for type");
+                        bail!("Could not find processed struct for type");
                     }
                 }
             },
+            Type::Reference(ref tr) => {
+                let referenced = self.resolve_type(&tr.elem, current_scope_id)?;
+                self.reference_ty(referenced, tr.mutability.is_some())
+            }
             Type::Ptr(_) => {
                 //TODO: FIXME: Currently we only support void pointers, so we assume that's what it is
                 builtin_type::VOID_PTR
             }
-            _ => unimplemented!("Unsupported type"),
+            _ => bail!("Unsupported type"),
+        };
+
+        Ok(ty)
+    }
+
+    /// Resolves the type arguments given to the last segment of a path, eg) the `u64` in `Pair<u64>`.  Lifetimes are
+    /// erased.
+    fn resolve_type_args(
+        &mut self,
+        path: &syn::Path,
+        current_scope_id: ScopeId,
+    ) -> Result<Vec<DefinitionId>> {
+        let mut ty_args = vec![];
+        if let PathArguments::AngleBracketed(ref args) =
+            path.segments[path.segments.len() - 1].arguments
+        {
+            for arg in &args.args {
+                match arg {
+                    GenericArgument::Type(ty) => {
+                        ty_args.push(self.resolve_type(ty, current_scope_id)?)
+                    }
+                    GenericArgument::Lifetime(_) => {}
+                    _ => bail!("Unsupported generic argument"),
+                }
+            }
+        }
+        Ok(ty_args)
+    }
+
+    /// Records the trait bounds of a type variable, eg) the `Copy` in `T: Copy`
+    fn add_type_var_bounds<'a, I>(&mut self, ty: DefinitionId, bounds: I)
+    where
+        I: Iterator<Item = &'a TypeParamBound>,
+    {
+        for bound in bounds {
+            if let TypeParamBound::Trait(trait_bound) = bound {
+                let path = &trait_bound.path;
+                let trait_name = path.segments[path.segments.len() - 1].ident.to_string();
+                if let Definition::TypeVariable = self.definitions[ty] {
+                    self.type_var_bounds.entry(ty).or_default().push(trait_name);
+                }
+            }
+        }
+    }
+
+    /// Converts a struct literal.  For generic structs, the type arguments come from the path (eg
+    /// `Pair::<u64> { .. }`), from `ty_hint` if it's an instance of the same struct, or else from the types of the fields.
+    fn convert_struct_expr_to_bytecode(
+        &mut self,
+        es: &ExprStruct,
+        ty_hint: Option<DefinitionId>,
+        expected_return_type: DefinitionId,
+        bytecode: &mut Vec<Bytecode>,
+        current_scope_id: ScopeId,
+        var_stack: &mut VarStack,
+    ) -> Result<DefinitionId> {
+        let mut fields = vec![];
+        for field in &es.fields {
+            match field.member {
+                Member::Named(name) => {
+                    fields.push((name.to_string(), &field.expr));
+                }
+                _ => bail!("Unnamed struct members not yet supported"),
+            }
+        }
+
+        //TODO: FIXME: would be great to not have to clone here
+        fields.sort_by_key(|x| x.0.clone());
+
+        let mut field_tys = vec![];
+        for field in &fields {
+            let field_ty = self.convert_expr_to_bytecode(
+                field.1,
+                expected_return_type,
+                bytecode,
+                current_scope_id,
+                var_stack,
+            )?;
+            field_tys.push((field.0.clone(), field_ty));
+        }
+
+        let definition_id = match self.process_path(&es.path, current_scope_id)? {
+            Some(definition_id) => definition_id,
+            None => bail!("Can't process struct"),
+        };
+
+        let definition_id = match self.definitions[definition_id].clone() {
+            Definition::Struct(_) => definition_id,
+            Definition::GenericStruct(item_struct, _) => {
+                let ty_args = self.resolve_type_args(&es.path, current_scope_id)?;
+                if !ty_args.is_empty() {
+                    self.instantiate_generic_struct(definition_id, ty_args)?
+                } else {
+                    let hinted = ty_hint.and_then(|hint| match self.definitions[hint] {
+                        Definition::Struct(ref st) => match st.instance_of {
+                            Some((generic_id, _)) if generic_id == definition_id => Some(hint),
+                            _ => None,
+                        },
+                        _ => None,
+                    });
+                    match hinted {
+                        Some(hint) => hint,
+                        None => {
+                            let ty_args = self.infer_struct_ty_args(
+                                &item_struct.generics,
+                                &item_struct.fields,
+                                &field_tys,
+                            )?;
+                            self.instantiate_generic_struct(definition_id, ty_args)?
+                        }
+                    }
+                }
+            }
+            _ => bail!("Unsupport definition type in struct call"),
+        };
+
+        if let Definition::Struct(ref st) = self.definitions[definition_id] {
+            for (((_, expected_ty), (_, field_ty)), (_, expr)) in
+                st.fields.iter().zip(&field_tys).zip(&fields)
+            {
+                self.expect_type(*expected_ty, *field_ty, expr.span())?;
+            }
+        }
+
+        bytecode.push(Bytecode::Call(definition_id));
+        Ok(definition_id)
+    }
+
+    /// Infers the type arguments of a generic struct from the types of the fields given to it.  Fields whose type is
+    /// just a type parameter decide that parameter, with integer literals defaulting to i32.
+    fn infer_struct_ty_args(
+        &self,
+        generics: &syn::Generics,
+        fields: &syn::Fields,
+        field_tys: &[(String, DefinitionId)],
+    ) -> Result<Vec<DefinitionId>> {
+        let mut ty_args = vec![];
+        for param in &generics.params {
+            if let GenericParam::Type(type_param) = param {
+                let mut ty_arg = builtin_type::UNKNOWN;
+                for field in fields {
+                    let is_param = match field.ty {
+                        Type::Path(ref tp) => {
+                            tp.path.segments.len() == 1
+                                && tp.path.segments[0].ident == type_param.ident
+                        }
+                        _ => false,
+                    };
+                    if !is_param {
+                        continue;
+                    }
+                    let field_name = match field.ident {
+                        Some(ref ident) => ident.to_string(),
+                        None => bail_at!(
+                            self.location(field.span()),
+                            "Tuple structs aren't supported"
+                        ),
+                    };
+                    for (name, field_ty) in field_tys {
+                        if *name == field_name {
+                            ty_arg = self.tighter_of_types(ty_arg, *field_ty);
+                        }
+                    }
+                }
+                match ty_arg {
+                    builtin_type::UNKNOWN => {
+                        bail!("Can not infer type parameter {}", type_param.ident)
+                    }
+                    builtin_type::UNKNOWN_INT => ty_args.push(builtin_type::I32),
+                    _ => ty_args.push(ty_arg),
+                }
+            }
         }
+        Ok(ty_args)
     }
 }
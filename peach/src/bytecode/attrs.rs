@@ -0,0 +1,156 @@
+//! Handling for the attributes on items and statements.  Attributes that only matter to rustc's lints, optimizer, or
+//! documentation are skipped, and items that are only compiled for tests are left out, unless the engine is
+//! compiling for tests.
+use bytecode::engine::BytecodeEngine;
+use error::Result;
+use syn::{Attribute, Item, Lit, Meta, NestedMeta, Stmt};
+
+/// The traits that `#[derive]` can implement
+const SUPPORTED_DERIVES: &[&str] = &["Copy"];
+
+impl BytecodeEngine {
+    /// Checks the attributes on an item or statement, recording any that are skipped.  Returns false if what they're
+    /// attached to should be left out because it's only compiled for tests, and the engine isn't compiling for them.
+    /// Benchmarks are always left out.
+    pub(crate) fn filter_attrs(&mut self, attrs: &[Attribute]) -> Result<bool> {
+        let mut include = true;
+
+        for attr in attrs {
+            let meta = match attr.interpret_meta() {
+                Some(meta) => meta,
+                None => {
+                    // Attributes with paths, like #[rustfmt::skip], are only meaningful to other tools
+                    let segments: Vec<String> = attr
+                        .path
+                        .segments
+                        .iter()
+                        .map(|segment| segment.ident.to_string())
+                        .collect();
+                    self.skip_attr(segments.join("::"));
+                    continue;
+                }
+            };
+
+            match meta.name().as_ref() {
+                // #[path] is read when the module it's on is loaded
+                "doc" | "path" => {}
+                "test" => include = include && self.config.tests,
+                "bench" => include = false,
+                "cfg" => match meta {
+                    Meta::List(ref list) if list.nested.len() == 1 => {
+                        include = include && cfg_enabled(&list.nested[0], self.config.tests)?;
+                    }
+                    _ => bail!("Malformed cfg attribute"),
+                },
+                "derive" => {
+                    if let Meta::List(ref list) = meta {
+                        for nested in &list.nested {
+                            if let NestedMeta::Meta(Meta::Word(ident)) = nested {
+                                if !SUPPORTED_DERIVES.contains(&ident.as_ref()) {
+                                    self.skip_attr(format!("derive({})", ident));
+                                }
+                            }
+                        }
+                    }
+                }
+                name => self.skip_attr(name.to_string()),
+            }
+        }
+
+        Ok(include)
+    }
+
+    fn skip_attr(&mut self, name: String) {
+        if !self.skipped_attributes.contains(&name) {
+            self.skipped_attributes.push(name);
+        }
+    }
+
+    /// The attributes that were accepted but have no effect, like `inline`, `allow`, and unsupported derives
+    pub fn skipped_attributes(&self) -> &[String] {
+        &self.skipped_attributes
+    }
+}
+
+/// Evaluates a cfg predicate.  `test` is only set when compiling for tests, and other configuration options aren't
+/// known.
+fn cfg_enabled(predicate: &NestedMeta, tests: bool) -> Result<bool> {
+    match predicate {
+        NestedMeta::Meta(Meta::Word(ident)) if ident == "test" => Ok(tests),
+        NestedMeta::Meta(Meta::List(list)) if list.ident == "not" && list.nested.len() == 1 => {
+            Ok(!cfg_enabled(&list.nested[0], tests)?)
+        }
+        NestedMeta::Meta(Meta::List(list)) if list.ident == "all" => {
+            for nested in &list.nested {
+                if !cfg_enabled(nested, tests)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        NestedMeta::Meta(Meta::List(list)) if list.ident == "any" => {
+            for nested in &list.nested {
+                if cfg_enabled(nested, tests)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        _ => bail!("Unsupported cfg predicate: {:?}", predicate),
+    }
+}
+
+/// Whether the attributes mark a function as a test
+pub(crate) fn is_test(attrs: &[Attribute]) -> bool {
+    attrs
+        .iter()
+        .any(|attr| matches!(attr.interpret_meta(), Some(Meta::Word(ref ident)) if ident == "test"))
+}
+
+/// The file given by a `#[path = "..."]` attribute, if there is one
+pub(crate) fn path_attr(attrs: &[Attribute]) -> Result<Option<String>> {
+    for attr in attrs {
+        match attr.interpret_meta() {
+            Some(Meta::NameValue(ref name_value)) if name_value.ident == "path" => {
+                match name_value.lit {
+                    Lit::Str(ref path) => return Ok(Some(path.value())),
+                    _ => bail!("The path attribute needs to be a string"),
+                }
+            }
+            Some(ref meta) if meta.name() == "path" => bail!("Malformed path attribute"),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// The attributes on an item
+pub(crate) fn item_attrs(item: &Item) -> &[Attribute] {
+    match item {
+        Item::ExternCrate(item) => &item.attrs,
+        Item::Use(item) => &item.attrs,
+        Item::Static(item) => &item.attrs,
+        Item::Const(item) => &item.attrs,
+        Item::Fn(item) => &item.attrs,
+        Item::Mod(item) => &item.attrs,
+        Item::ForeignMod(item) => &item.attrs,
+        Item::Type(item) => &item.attrs,
+        Item::Struct(item) => &item.attrs,
+        Item::Enum(item) => &item.attrs,
+        Item::Union(item) => &item.attrs,
+        Item::Trait(item) => &item.attrs,
+        Item::Impl(item) => &item.attrs,
+        Item::Macro(item) => &item.attrs,
+        Item::Macro2(item) => &item.attrs,
+        Item::Verbatim(_) => &[],
+    }
+}
+
+/// The attributes on a statement.  Attributes on expressions aren't looked at.
+pub(crate) fn stmt_attrs(stmt: &Stmt) -> &[Attribute] {
+    match stmt {
+        Stmt::Local(local) => &local.attrs,
+        Stmt::Item(item) => item_attrs(item),
+        Stmt::Expr(_) | Stmt::Semi(_, _) => &[],
+    }
+}
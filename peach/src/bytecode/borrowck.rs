@@ -0,0 +1,292 @@
+use bytecode::engine::{Bytecode, BytecodeEngine, Definition, Fun};
+use error::Result;
+use std::collections::{HashMap, HashSet};
+
+/// A borrow of a variable, identified by the index of the `Borrow` instruction that created it
+#[derive(Clone, Copy)]
+struct Loan {
+    var_id: usize,
+    mutable: bool,
+}
+
+/// The loans that may be alive on entry to an instruction
+#[derive(Clone, Default, PartialEq)]
+struct LoanState {
+    /// Loans stored in variables, as (loan, holding variable)
+    held: HashSet<(usize, usize)>,
+    /// Loans in temporaries that haven't been stored or consumed yet
+    pending: HashSet<usize>,
+}
+
+impl BytecodeEngine {
+    /// A basic, intraprocedural borrow checker.  It rejects:
+    ///   * mutable borrows of a variable while another borrow of it is alive
+    ///   * shared borrows of a variable while a mutable borrow of it is alive
+    ///   * assigning to a variable while it's borrowed
+    ///   * using a variable while it's mutably borrowed
+    ///
+    /// A borrow stays alive for as long as a variable holding it may still be used, so borrows end at their last use
+    /// rather than at the end of the block.  Borrows held only in temporaries (eg function arguments) live until the
+    /// value is consumed.  References returned from calls are assumed to hold the borrows passed into the call.
+    pub(crate) fn check_borrows(&self, fun: &Fun) -> Result<()> {
+        let bytecode = &fun.bytecode;
+        if bytecode.is_empty() {
+            return Ok(());
+        }
+
+        let mut loans = HashMap::new();
+        for (idx, code) in bytecode.iter().enumerate() {
+            if let Bytecode::Borrow(mutable) = code {
+                if let Some(var_id) = borrowed_var(bytecode, idx) {
+                    loans.insert(
+                        idx,
+                        Loan {
+                            var_id,
+                            mutable: *mutable,
+                        },
+                    );
+                }
+            }
+        }
+        if loans.is_empty() {
+            return Ok(());
+        }
+
+        let live_out = self.live_vars(fun);
+
+        let mut state_at: Vec<Option<LoanState>> = vec![None; bytecode.len()];
+        state_at[0] = Some(LoanState::default());
+        let mut worklist = vec![0];
+
+        while let Some(idx) = worklist.pop() {
+            let mut state = state_at[idx].clone().unwrap();
+
+            // Loans that are still needed after this instruction
+            let alive: Vec<(usize, Loan)> = state
+                .pending
+                .iter()
+                .cloned()
+                .chain(
+                    state
+                        .held
+                        .iter()
+                        .filter(|(_, holder)| live_out[idx].contains(holder))
+                        .map(|(loan_id, _)| *loan_id),
+                )
+                .map(|loan_id| (loan_id, loans[&loan_id]))
+                .collect();
+
+            match bytecode[idx] {
+                Bytecode::Borrow(mutable) => {
+                    if let Some(var_id) = borrowed_var(bytecode, idx) {
+                        let name = &fun.vars[var_id].ident;
+                        for (_, loan) in alive.iter().filter(|(_, loan)| loan.var_id == var_id) {
+                            match (mutable, loan.mutable) {
+                                (true, true) => bail_at!(
+                                    fun.location_of(idx),
+                                    "Can not borrow {} as mutable more than once at a time",
+                                    name
+                                ),
+                                (true, false) => bail_at!(
+                                    fun.location_of(idx),
+                                    "Can not borrow {} as mutable because it is also borrowed as immutable",
+                                    name
+                                ),
+                                (false, true) => bail_at!(
+                                    fun.location_of(idx),
+                                    "Can not borrow {} as immutable because it is also borrowed as mutable",
+                                    name
+                                ),
+                                (false, false) => {}
+                            }
+                        }
+                        state.pending.insert(idx);
+                    }
+                }
+                Bytecode::LValueVar(var_id) => {
+                    if let LValueUse::Write = lvalue_use(bytecode, idx) {
+                        if alive.iter().any(|(_, loan)| loan.var_id == var_id) {
+                            bail_at!(
+                                fun.location_of(idx),
+                                "Can not assign to {} because it is borrowed",
+                                fun.vars[var_id].ident
+                            );
+                        }
+                    }
+                }
+                Bytecode::Var(var_id) | Bytecode::Move(var_id) => {
+                    if let Bytecode::Move(_) = bytecode[idx] {
+                        if alive.iter().any(|(_, loan)| loan.var_id == var_id) {
+                            bail_at!(
+                                fun.location_of(idx),
+                                "Can not move out of {} because it is borrowed",
+                                fun.vars[var_id].ident
+                            );
+                        }
+                    }
+                    if alive
+                        .iter()
+                        .any(|(_, loan)| loan.var_id == var_id && loan.mutable)
+                    {
+                        bail_at!(
+                            fun.location_of(idx),
+                            "Can not use {} because it was mutably borrowed",
+                            fun.vars[var_id].ident
+                        );
+                    }
+
+                    // Copying a reference copies the loans it holds
+                    let copied: Vec<usize> = state
+                        .held
+                        .iter()
+                        .filter(|(_, holder)| *holder == var_id)
+                        .map(|(loan_id, _)| *loan_id)
+                        .collect();
+                    state.pending.extend(copied);
+                }
+                Bytecode::VarDecl(var_id) => {
+                    for loan_id in state.pending.drain() {
+                        state.held.insert((loan_id, var_id));
+                    }
+                }
+                Bytecode::Assign => {
+                    if let Some(Bytecode::LValueVar(var_id)) = bytecode.get(idx - 1) {
+                        state.held.retain(|(_, holder)| holder != var_id);
+                        for loan_id in state.pending.drain() {
+                            state.held.insert((loan_id, *var_id));
+                        }
+                    }
+                    state.pending.clear();
+                }
+                Bytecode::Call(definition_id) => {
+                    let returns_reference = match self.definitions[definition_id] {
+                        Definition::Fun(ref target_fn) => matches!(
+                            self.definitions[target_fn.return_ty],
+                            Definition::Reference(_, _)
+                        ),
+                        _ => false,
+                    };
+                    if !returns_reference {
+                        state.pending.clear();
+                    }
+                }
+                Bytecode::Deref
+                | Bytecode::CallNative(_)
+                | Bytecode::DebugPrint(_)
+                | Bytecode::If(_, _)
+                | Bytecode::WhileCond(_)
+                | Bytecode::ReturnVoid
+                | Bytecode::ReturnLastStackValue => {
+                    state.pending.clear();
+                }
+                _ => {}
+            }
+
+            for successor in fun.successors(idx) {
+                if successor >= bytecode.len() {
+                    continue;
+                }
+                let changed = match state_at[successor] {
+                    None => {
+                        state_at[successor] = Some(state.clone());
+                        true
+                    }
+                    Some(ref mut existing) => {
+                        let before = (existing.held.len(), existing.pending.len());
+                        existing.held.extend(state.held.iter().cloned());
+                        existing.pending.extend(state.pending.iter().cloned());
+                        before != (existing.held.len(), existing.pending.len())
+                    }
+                };
+                if changed {
+                    worklist.push(successor);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Computes, for each instruction, the variables whose current value may be used after it executes
+    fn live_vars(&self, fun: &Fun) -> Vec<HashSet<usize>> {
+        let bytecode = &fun.bytecode;
+        let mut live_in: Vec<HashSet<usize>> = vec![HashSet::new(); bytecode.len()];
+        let mut live_out: Vec<HashSet<usize>> = vec![HashSet::new(); bytecode.len()];
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for idx in (0..bytecode.len()).rev() {
+                let mut out = HashSet::new();
+                for successor in fun.successors(idx) {
+                    if let Some(successor_live) = live_in.get(successor) {
+                        out.extend(successor_live.iter().cloned());
+                    }
+                }
+
+                let mut live = out.clone();
+                match bytecode[idx] {
+                    Bytecode::Var(var_id) | Bytecode::Move(var_id) => {
+                        live.insert(var_id);
+                    }
+                    Bytecode::LValueVar(var_id) => match lvalue_use(bytecode, idx) {
+                        LValueUse::Write => {
+                            live.remove(&var_id);
+                        }
+                        _ => {
+                            live.insert(var_id);
+                        }
+                    },
+                    Bytecode::VarDecl(var_id) | Bytecode::VarDeclUninit(var_id) => {
+                        live.remove(&var_id);
+                    }
+                    _ => {}
+                }
+
+                if live != live_in[idx] || out != live_out[idx] {
+                    live_in[idx] = live;
+                    live_out[idx] = out;
+                    changed = true;
+                }
+            }
+        }
+
+        live_out
+    }
+}
+
+pub(crate) enum LValueUse {
+    /// The variable itself is overwritten (possibly just one of its fields)
+    Write,
+    /// The variable is borrowed
+    Borrow,
+    /// The variable holds a reference which is written or borrowed through
+    Through,
+}
+
+/// Works out what the lvalue path starting with the `LValueVar` at `idx` is used for
+pub(crate) fn lvalue_use(bytecode: &[Bytecode], idx: usize) -> LValueUse {
+    let mut through_reference = false;
+    for code in &bytecode[idx + 1..] {
+        match code {
+            Bytecode::LValueDot(_) => {}
+            Bytecode::LValueDeref => through_reference = true,
+            Bytecode::Borrow(_) if !through_reference => return LValueUse::Borrow,
+            _ if !through_reference => return LValueUse::Write,
+            _ => return LValueUse::Through,
+        }
+    }
+    LValueUse::Write
+}
+
+/// Finds the variable borrowed by the `Borrow` at `idx`, unless the borrow is through a reference
+fn borrowed_var(bytecode: &[Bytecode], idx: usize) -> Option<usize> {
+    for code in bytecode[..idx].iter().rev() {
+        match code {
+            Bytecode::LValueDot(_) => {}
+            Bytecode::LValueVar(var_id) => return Some(*var_id),
+            _ => return None,
+        }
+    }
+    None
+}
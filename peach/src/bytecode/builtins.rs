@@ -0,0 +1,149 @@
+//! The functions built into the language, as opposed to those defined by programs or registered by the host.  Each is
+//! converted straight to an instruction, and a program can define a function with the same name to use instead.
+use bytecode::engine::{Bytecode, BytecodeEngine, DefinitionId, ScopeId, VarStack};
+use bytecode::typecheck::builtin_type;
+use error::Result;
+use std::path::{Component, Path};
+use syn::{Expr, ExprCall, Lit};
+
+/// The names of the builtin functions
+const BUILTIN_FNS: &[&str] = &[
+    "read_u64",
+    "now_millis",
+    "elapsed_millis",
+    "random_below",
+    "arg_count",
+    "arg",
+    "env",
+    "read_file_u64",
+    "write_file_u64",
+    "exit",
+    // What `panic!` and the assertions expand to
+    "__panic__",
+];
+
+impl BytecodeEngine {
+    /// Whether a call of the given name is of a builtin, rather than of a function the program defines
+    pub(crate) fn is_builtin_fn(&self, name: &str, scope_id: ScopeId) -> bool {
+        BUILTIN_FNS.contains(&name) && self.get_defn(name, scope_id).is_none()
+    }
+
+    /// Converts a call of a builtin function, checking that the engine's configuration allows it
+    pub(crate) fn convert_builtin_call(
+        &mut self,
+        name: &str,
+        ec: &ExprCall,
+        expected_return_type: DefinitionId,
+        bytecode: &mut Vec<Bytecode>,
+        current_scope_id: ScopeId,
+        var_stack: &mut VarStack,
+    ) -> Result<DefinitionId> {
+        // Some builtins start with a name, which is kept in the instruction as there are no strings at runtime
+        let (allowed, takes_name, param_tys): (bool, bool, &[DefinitionId]) = match name {
+            "read_u64" => (self.config.allow_input, false, &[]),
+            "now_millis" => (self.config.allow_clock, false, &[]),
+            "elapsed_millis" => (self.config.allow_clock, false, &[builtin_type::U64]),
+            // The numbers only depend on the seed, so they're always allowed
+            "random_below" => (true, false, &[builtin_type::U64]),
+            "arg_count" => (self.config.allow_args, false, &[]),
+            "arg" => (self.config.allow_args, false, &[builtin_type::U64]),
+            "env" => (self.config.allow_env, true, &[]),
+            "read_file_u64" => (self.config.file_root.is_some(), true, &[]),
+            "write_file_u64" => (self.config.file_root.is_some(), true, &[builtin_type::U64]),
+            "exit" => (true, false, &[builtin_type::I32]),
+            "__panic__" => (true, true, &[]),
+            _ => bail!("{} is not a builtin function", name),
+        };
+
+        if !allowed {
+            bail!("{} is not allowed by this engine's configuration", name);
+        }
+        let num_names = if takes_name { 1 } else { 0 };
+        if ec.args.len() != num_names + param_tys.len() {
+            bail!(
+                "{} takes {} arguments but {} were given",
+                name,
+                num_names + param_tys.len(),
+                ec.args.len()
+            );
+        }
+        let literal = if takes_name {
+            string_literal(name, &ec.args[0])?
+        } else {
+            String::new()
+        };
+
+        if name == "elapsed_millis" {
+            // elapsed_millis(start) is now_millis() - start
+            bytecode.push(Bytecode::ReadClock);
+        }
+        for (arg, param_ty) in ec.args.iter().skip(num_names).zip(param_tys) {
+            let arg_ty = self.convert_expr_to_bytecode(
+                arg,
+                expected_return_type,
+                bytecode,
+                current_scope_id,
+                var_stack,
+            )?;
+            if !self.assignment_compatible(*param_ty, arg_ty) {
+                bail!(
+                    "Argument of type {} does not match parameter of type {}",
+                    self.ty(arg_ty),
+                    self.ty(*param_ty)
+                );
+            }
+        }
+        bytecode.push(match name {
+            "read_u64" => Bytecode::ReadInput,
+            "now_millis" => Bytecode::ReadClock,
+            "elapsed_millis" => Bytecode::Sub,
+            "random_below" => Bytecode::RandomBelow,
+            "arg_count" => Bytecode::ArgCount,
+            "arg" => Bytecode::Arg,
+            "env" => Bytecode::Env(env_var_name(literal)?),
+            "read_file_u64" => Bytecode::ReadFile(file_path(literal)?),
+            "write_file_u64" => Bytecode::WriteFile(file_path(literal)?),
+            "exit" => Bytecode::Exit,
+            _ => Bytecode::Panic(literal),
+        });
+
+        match name {
+            "write_file_u64" | "exit" | "__panic__" => Ok(builtin_type::VOID),
+            _ => Ok(builtin_type::U64),
+        }
+    }
+}
+
+/// The name a builtin starts with, which has to be written as a string literal
+fn string_literal(name: &str, arg: &Expr) -> Result<String> {
+    if let Expr::Lit(ref lit) = arg {
+        if let Lit::Str(ref literal) = lit.lit {
+            return Ok(literal.value());
+        }
+    }
+    bail!("The first argument of {} has to be a string literal", name)
+}
+
+/// Checks the name given to `env()`.  It's kept to the characters that are safe to put in the C backend's output.
+pub(crate) fn env_var_name(name: String) -> Result<String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!(
+            "Environment variable names can only contain letters, digits, and underscores, not {:?}",
+            name
+        );
+    }
+    Ok(name)
+}
+
+/// Checks a path given to the file builtins.  They can only reach files under the engine's file root, so the path
+/// has to be relative and can't go up with `..`.
+pub(crate) fn file_path(path: String) -> Result<String> {
+    let inside_root = !path.is_empty()
+        && Path::new(&path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !inside_root {
+        bail!("{:?} is not a path inside the file root", path);
+    }
+    Ok(path)
+}
@@ -0,0 +1,198 @@
+//! A control-flow graph of a function's bytecode, split into basic blocks.  Bytecode jumps by relative offsets, which
+//! every pass that adds or removes instructions would otherwise have to patch.  Blocks instead refer to each other by
+//! id, and the offsets are recomputed when the graph is turned back into bytecode.
+use bytecode::engine::{Bytecode, Fun};
+use bytecode::labels::Labeled;
+
+/// The index of a block in `Cfg::blocks`
+pub type BlockId = usize;
+
+/// A run of instructions which is only entered at its start and only left at its end
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    /// The instructions of the block, ending with its jump or return if it has one.  The offsets of jumps are left as
+    /// they were, and only recomputed by `Cfg::linearize`.
+    pub code: Vec<Bytecode>,
+    /// The blocks which may run next.  The block a conditional jump falls through to comes first.
+    pub successors: Vec<BlockId>,
+    /// Where the block started in the bytecode the graph was built from
+    pub start: usize,
+    /// Where each instruction came from in the bytecode the graph was built from.  An instruction that replaces
+    /// several others takes the place of the last of them, so the places stay in order.
+    pub origins: Vec<usize>,
+}
+
+impl BasicBlock {
+    /// The jump or return that ends the block, if it has one
+    pub fn terminator(&self) -> Option<&Bytecode> {
+        self.code.last().filter(|code| is_terminator(code))
+    }
+}
+
+/// The basic blocks of a function, in the order of its bytecode.  The first block is the entry, which nothing jumps
+/// to: if the bytecode starts with a loop, the entry is an empty block before it.  If evaluation can run past the
+/// last instruction, the last block is empty and has no successors.
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+/// Returns true if control may not continue to the next instruction after this one
+fn is_terminator(code: &Bytecode) -> bool {
+    matches!(
+        code,
+        Bytecode::ReturnVoid
+            | Bytecode::ReturnLastStackValue
+            | Bytecode::If(..)
+            | Bytecode::Else(..)
+            | Bytecode::WhileCond(_)
+            | Bytecode::EndWhile(_)
+    )
+}
+
+impl Cfg {
+    /// Builds the graph of the function's bytecode
+    pub fn new(fun: &Fun) -> Cfg {
+        let len = fun.bytecode.len();
+
+        // Blocks start at the entry, at every instruction which is jumped to, and after every jump or return
+        let mut is_start = vec![false; len + 1];
+        is_start[0] = true;
+        for (idx, code) in fun.bytecode.iter().enumerate() {
+            if is_terminator(code) {
+                for successor in fun.successors(idx) {
+                    is_start[successor] = true;
+                }
+                is_start[idx + 1] = true;
+            }
+        }
+
+        // Running past the end gets a block of its own, so that every successor is a block
+        let mut starts: Vec<usize> = (0..len).filter(|idx| is_start[*idx]).collect();
+        if len == 0 || (0..len).any(|idx| fun.successors(idx).contains(&len)) {
+            starts.push(len);
+        }
+
+        // Jumps land in the blocks after the empty entry, if there is one
+        let entry_is_target = (0..len)
+            .any(|idx| is_terminator(&fun.bytecode[idx]) && fun.successors(idx).contains(&0));
+        let shift = entry_is_target as usize;
+        let block_of = |idx: usize| starts.binary_search(&idx).unwrap() + shift;
+        let mut blocks: Vec<BasicBlock> = starts
+            .iter()
+            .enumerate()
+            .map(|(block_id, &start)| {
+                let end = starts.get(block_id + 1).cloned().unwrap_or(len);
+                let successors = if start == end {
+                    vec![]
+                } else {
+                    fun.successors(end - 1).into_iter().map(block_of).collect()
+                };
+                BasicBlock {
+                    code: fun.bytecode[start..end].to_vec(),
+                    successors,
+                    start,
+                    origins: (start..end).collect(),
+                }
+            })
+            .collect();
+        if entry_is_target {
+            blocks.insert(
+                0,
+                BasicBlock {
+                    code: vec![],
+                    successors: vec![1],
+                    start: 0,
+                    origins: vec![],
+                },
+            );
+        }
+
+        Cfg { blocks }
+    }
+
+    /// The blocks which may run just before each block
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![vec![]; self.blocks.len()];
+        for (block_id, block) in self.blocks.iter().enumerate() {
+            for &successor in &block.successors {
+                if !predecessors[successor].contains(&block_id) {
+                    predecessors[successor].push(block_id);
+                }
+            }
+        }
+        predecessors
+    }
+
+    /// The blocks which can be reached from the entry, in reverse postorder.  Each block comes before its successors,
+    /// except where a successor starts a loop, which makes it the order forward dataflow analyses visit blocks in.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut postorder = vec![];
+        // Each block on the stack, with how many of its successors have been visited
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((block_id, next)) = stack.pop() {
+            match self.blocks[block_id].successors.get(next) {
+                Some(&successor) => {
+                    stack.push((block_id, next + 1));
+                    if !visited[successor] {
+                        visited[successor] = true;
+                        stack.push((successor, 0));
+                    }
+                }
+                None => postorder.push(block_id),
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+
+    /// Lays the blocks back out as bytecode, in order, with the offsets of jumps recomputed.  A block which falls
+    /// through, or ends with a conditional jump, has to be followed by the block it falls through to.
+    pub fn linearize(&self) -> Vec<Bytecode> {
+        // Each block starts with a label, which its predecessors jump to
+        let mut labeled = Labeled::new();
+        let labels: Vec<_> = self.blocks.iter().map(|_| labeled.new_label()).collect();
+        for (block, label) in self.blocks.iter().zip(&labels) {
+            labeled.place(*label);
+            let jump_to = match block.terminator() {
+                Some(Bytecode::If(..)) | Some(Bytecode::WhileCond(_)) => Some(block.successors[1]),
+                Some(Bytecode::Else(..)) | Some(Bytecode::EndWhile(_)) => Some(block.successors[0]),
+                _ => None,
+            };
+            for (idx, code) in block.code.iter().enumerate() {
+                match jump_to {
+                    Some(successor) if idx == block.code.len() - 1 => {
+                        labeled.jump(code.clone(), labels[successor])
+                    }
+                    _ => labeled.push(code.clone()),
+                }
+            }
+        }
+        labeled.finish().0
+    }
+
+    /// Replaces the function's bytecode with the blocks laid out again.  The graph has to have been built from the
+    /// function's current bytecode, and the origins of each block's instructions kept up to date.  Statements cover
+    /// the instructions that came from them.
+    pub fn apply(&self, fun: &mut Fun) {
+        let bytecode = self.linearize();
+        let origins: Vec<usize> = self
+            .blocks
+            .iter()
+            .flat_map(|block| block.origins.iter().cloned())
+            .collect();
+        debug_assert_eq!(origins.len(), bytecode.len());
+
+        // Where each instruction of the old bytecode ends up, followed by where the end of it does
+        let new_idx: Vec<usize> = (0..=fun.bytecode.len())
+            .map(|idx| origins.partition_point(|origin| *origin < idx))
+            .collect();
+
+        for (range, _) in &mut fun.stmt_locations {
+            *range = new_idx[range.start]..new_idx[range.end];
+        }
+        fun.bytecode = bytecode;
+    }
+}
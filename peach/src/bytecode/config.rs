@@ -0,0 +1,100 @@
+//! The policy for which builtins programs may use.  Embedders running untrusted code turn off the builtins that reach
+//! outside the engine, and programs using them fail to convert rather than running.  Builtins that do IO, like
+//! reading files or the clock, get a flag here when they're added.  It also holds the settings that loosen the
+//! language, for embedders that want a more forgiving subset of it.
+use bytecode::engine::{Bytecode, BytecodeEngine};
+use std::path::PathBuf;
+
+/// Which builtins programs are allowed to use.  Everything is allowed by default, except for file access, which needs
+/// a root directory to be given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineConfig {
+    /// Whether functions in `extern` blocks can be called.  They're native code, so they can do anything the host can.
+    pub allow_extern_fns: bool,
+    /// Whether `println!` can be used
+    pub allow_print: bool,
+    /// Whether the `read_u64()` builtin can be used to read input from the host
+    pub allow_input: bool,
+    /// Whether the clock can be read with the `now_millis()` and `elapsed_millis()` builtins
+    pub allow_clock: bool,
+    /// Whether the program's arguments can be read with the `arg_count()` and `arg()` builtins
+    pub allow_args: bool,
+    /// Whether environment variables can be read with the `env()` builtin
+    pub allow_env: bool,
+    /// The directory that `read_file_u64()` and `write_file_u64()` work in.  They're only allowed when it's set, and
+    /// can't reach files outside it.
+    pub file_root: Option<PathBuf>,
+    /// The seed for the numbers given by `random_below()`, so runs can be reproduced.  When it's `None`, each run is
+    /// seeded from the clock.
+    pub random_seed: Option<u64>,
+    /// Whether a `u32` can be used where a `u64` is expected, and an `i32` where an `i64` is, without an `as`.  The
+    /// value is widened where it's used.
+    pub widening_coercions: bool,
+    /// Whether programs are compiled for testing, like `cargo test` does.  Items behind `#[cfg(test)]` are included,
+    /// and `#[test]` functions are collected for `BytecodeEngine::run_tests`.  It applies to files loaded after it's
+    /// set.
+    pub tests: bool,
+}
+
+impl Default for EngineConfig {
+    fn default() -> EngineConfig {
+        EngineConfig {
+            allow_extern_fns: true,
+            allow_print: true,
+            allow_input: true,
+            allow_clock: true,
+            allow_args: true,
+            allow_env: true,
+            file_root: None,
+            random_seed: None,
+            widening_coercions: false,
+            tests: false,
+        }
+    }
+}
+
+impl EngineConfig {
+    /// A policy that allows none of the builtins that reach outside the engine.  Natives registered by the host are
+    /// still allowed, as the host chose them.
+    pub fn sandboxed() -> EngineConfig {
+        EngineConfig {
+            allow_extern_fns: false,
+            allow_print: false,
+            allow_input: false,
+            allow_clock: false,
+            allow_args: false,
+            allow_env: false,
+            file_root: None,
+            random_seed: None,
+            widening_coercions: false,
+            tests: false,
+        }
+    }
+
+    /// The builtin an instruction runs, if this policy doesn't allow it.  Conversion already refuses those builtins,
+    /// but bytecode loaded from an image wasn't converted under this policy, so evaluation checks it again.  The
+    /// file builtins check for the file root when they run.
+    pub(crate) fn disallowed_builtin(&self, code: &Bytecode) -> Option<&'static str> {
+        match code {
+            Bytecode::DebugPrint(_) if !self.allow_print => Some("println!"),
+            Bytecode::ReadInput if !self.allow_input => Some("read_u64"),
+            Bytecode::ReadClock if !self.allow_clock => Some("now_millis"),
+            Bytecode::ArgCount if !self.allow_args => Some("arg_count"),
+            Bytecode::Arg if !self.allow_args => Some("arg"),
+            Bytecode::Env(_) if !self.allow_env => Some("env"),
+            _ => None,
+        }
+    }
+}
+
+impl BytecodeEngine {
+    /// Sets which builtins programs may use.  It applies to functions processed after it's set.
+    pub fn set_config(&mut self, config: EngineConfig) {
+        self.config = config;
+    }
+
+    /// Which builtins programs may use
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+}
@@ -1,9 +1,26 @@
-use std::collections::HashMap;
-
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use bytecode::attrs::{is_test, item_attrs, path_attr};
+use bytecode::config::EngineConfig;
+use bytecode::macros::macro_name;
+use bytecode::natives::{Native, NativeId};
+use bytecode::passes::Pipeline;
+use bytecode::symbols::{Symbol, SymbolTarget};
 use bytecode::typecheck::builtin_type;
+use cache::StableHasher;
+use error::{Location, PeachError, Result, Warning};
+use proc_macro2::{Span, TokenStream};
+use std::mem;
+use std::ops::Range;
 use std::os::raw::c_void;
-use syn::{self, Block, FnArg, FnDecl, ForeignItem, ImplItem, Item, ItemImpl, ItemMod, ItemStruct,
-          Pat, ReturnType};
+use syn::spanned::Spanned;
+use syn::{
+    self, Attribute, Block, FnArg, FnDecl, ForeignItem, GenericParam, ImplItem, Item, ItemImpl,
+    ItemMod, ItemStruct, ItemTrait, Meta, NestedMeta, Pat, ReturnType, TraitItem, TraitItemMethod,
+    Type, Visibility,
+};
 
 pub(crate) type ScopeId = usize;
 
@@ -21,7 +38,7 @@ pub enum Bytecode {
     PushU32(u32),
     PushI64(i64),
     PushI32(i32),
-    PushUnknownInt(i32),
+    PushUnknownInt(i128),
     PushBool(bool),
     PushRawPtr(*const c_void),
     As(DefinitionId),
@@ -31,12 +48,17 @@ pub enum Bytecode {
     Div,
     Lt,
     Neg,
+    Borrow(bool), // Takes a reference to the lvalue on the stack.  True if the reference is mutable
+    Deref,
     Dot(String),
     VarDecl(VarId),
     VarDeclUninit(VarId),
     Var(VarId),
+    Move(VarId), // Reads the variable, moving its value out.  The variable is no longer dropped
+    DropVars(Vec<(VarId, DefinitionId)>), // The given variables (with their types) go out of scope and are dropped, in order
     Assign,
     Call(DefinitionId),
+    CallNative(NativeId), // Calls a function registered with `register_native`
     If(Offset, DefinitionId), // Offset is number of bytecodes to jump forward if false.  Also includes the type of the result, if this is an expression
     Else(Offset, DefinitionId), // Offset is number of bytecodes to skip (aka jump forward). Also includes the type of the result, if this is an expression
     EndIf(DefinitionId),        //includes the type of the result, if this is an expression
@@ -44,10 +66,141 @@ pub enum Bytecode {
     WhileCond(Offset), // Offset is number of bytecodes to jump forward if false
     EndWhile(Offset),  // Offset is number of bytecodes to jump backward to return to start of while
     DebugPrint(DefinitionId),
+    ReadInput, // Reads a line of input and parses it as a u64
+    ReadClock, // Pushes the current time, in milliseconds since the Unix epoch, as a u64
+    RandomBelow, // Pops a u64 bound and pushes a random u64 below it
+    ArgCount,    // Pushes the number of arguments the program was given, as a u64
+    Arg,         // Pops a u64 index and pushes that argument, parsed as a u64
+    Env(String), // Pushes the named environment variable, parsed as a u64
+    ReadFile(String), // Reads the file at the path, relative to the file root, and pushes its contents parsed as a u64
+    WriteFile(String), // Pops a u64 and replaces the file at the path, relative to the file root, with it
+    Panic(String),     // Stops the program with the message, like Rust's panic!
+    Exit,              // Pops an i32 and stops the program, which exits with it as its status
 
     //lvalue
     LValueVar(VarId),
     LValueDot(String),
+    LValueDeref, // The place pointed to by the reference stored in the lvalue
+
+    // Superinstructions, which `BytecodeEngine::fuse_instructions` puts in place of common sequences
+    AddVarConst(VarId, i32), // Var, PushUnknownInt, Add
+    SubVarConst(VarId, i32), // Var, PushUnknownInt, Sub
+    LtVarConst(VarId, i32),  // Var, PushUnknownInt, Lt
+    AssignVar(VarId),        // LValueVar, Assign
+}
+
+/// The names of every bytecode instruction, as given by `Bytecode::name`
+pub(crate) const OPCODE_NAMES: &[&str] = &[
+    "ReturnLastStackValue",
+    "ReturnVoid",
+    "PushU64",
+    "PushU32",
+    "PushI64",
+    "PushI32",
+    "PushUnknownInt",
+    "PushBool",
+    "PushRawPtr",
+    "As",
+    "Add",
+    "Sub",
+    "Mul",
+    "Div",
+    "Lt",
+    "Neg",
+    "Borrow",
+    "Deref",
+    "Dot",
+    "VarDecl",
+    "VarDeclUninit",
+    "Var",
+    "Move",
+    "DropVars",
+    "Assign",
+    "Call",
+    "CallNative",
+    "If",
+    "Else",
+    "EndIf",
+    "BeginWhile",
+    "WhileCond",
+    "EndWhile",
+    "DebugPrint",
+    "ReadInput",
+    "ReadClock",
+    "RandomBelow",
+    "ArgCount",
+    "Arg",
+    "Env",
+    "ReadFile",
+    "WriteFile",
+    "Panic",
+    "Exit",
+    "LValueVar",
+    "LValueDot",
+    "LValueDeref",
+    "AddVarConst",
+    "SubVarConst",
+    "LtVarConst",
+    "AssignVar",
+];
+
+impl Bytecode {
+    /// The name of the instruction, without its operands
+    pub fn name(&self) -> &'static str {
+        match self {
+            Bytecode::ReturnLastStackValue => "ReturnLastStackValue",
+            Bytecode::ReturnVoid => "ReturnVoid",
+            Bytecode::PushU64(_) => "PushU64",
+            Bytecode::PushU32(_) => "PushU32",
+            Bytecode::PushI64(_) => "PushI64",
+            Bytecode::PushI32(_) => "PushI32",
+            Bytecode::PushUnknownInt(_) => "PushUnknownInt",
+            Bytecode::PushBool(_) => "PushBool",
+            Bytecode::PushRawPtr(_) => "PushRawPtr",
+            Bytecode::As(_) => "As",
+            Bytecode::Add => "Add",
+            Bytecode::Sub => "Sub",
+            Bytecode::Mul => "Mul",
+            Bytecode::Div => "Div",
+            Bytecode::Lt => "Lt",
+            Bytecode::Neg => "Neg",
+            Bytecode::Borrow(_) => "Borrow",
+            Bytecode::Deref => "Deref",
+            Bytecode::Dot(_) => "Dot",
+            Bytecode::VarDecl(_) => "VarDecl",
+            Bytecode::VarDeclUninit(_) => "VarDeclUninit",
+            Bytecode::Var(_) => "Var",
+            Bytecode::Move(_) => "Move",
+            Bytecode::DropVars(_) => "DropVars",
+            Bytecode::Assign => "Assign",
+            Bytecode::Call(_) => "Call",
+            Bytecode::CallNative(_) => "CallNative",
+            Bytecode::If(_, _) => "If",
+            Bytecode::Else(_, _) => "Else",
+            Bytecode::EndIf(_) => "EndIf",
+            Bytecode::BeginWhile => "BeginWhile",
+            Bytecode::WhileCond(_) => "WhileCond",
+            Bytecode::EndWhile(_) => "EndWhile",
+            Bytecode::DebugPrint(_) => "DebugPrint",
+            Bytecode::ReadInput => "ReadInput",
+            Bytecode::ReadClock => "ReadClock",
+            Bytecode::RandomBelow => "RandomBelow",
+            Bytecode::ArgCount => "ArgCount",
+            Bytecode::Arg => "Arg",
+            Bytecode::Env(_) => "Env",
+            Bytecode::ReadFile(_) => "ReadFile",
+            Bytecode::WriteFile(_) => "WriteFile",
+            Bytecode::Panic(_) => "Panic",
+            Bytecode::Exit => "Exit",
+            Bytecode::LValueVar(_) => "LValueVar",
+            Bytecode::LValueDot(_) => "LValueDot",
+            Bytecode::LValueDeref => "LValueDeref",
+            Bytecode::AddVarConst(_, _) => "AddVarConst",
+            Bytecode::SubVarConst(_, _) => "SubVarConst",
+            Bytecode::LtVarConst(_, _) => "LtVarConst",
+            Bytecode::AssignVar(_) => "AssignVar",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -76,7 +229,7 @@ impl VarDecl {
 
 #[derive(Clone)]
 pub struct VarStack {
-    var_stack: Vec<usize>,
+    pub(crate) var_stack: Vec<usize>,
     pub(crate) vars: Vec<VarDecl>,
 }
 
@@ -104,6 +257,16 @@ impl VarStack {
         }
         None
     }
+
+    /// The variables that can be named, in the order they were declared.  Variables shadowed by a later one with the
+    /// same name are left out.
+    pub fn visible_vars(&self) -> Vec<(usize, &VarDecl)> {
+        self.var_stack
+            .iter()
+            .filter(|var| self.find_var(&self.vars[**var].ident) == Some(**var))
+            .map(|var| (*var, &self.vars[*var]))
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -114,12 +277,79 @@ pub struct Fun {
     pub vars: Vec<VarDecl>,
     pub bytecode: Vec<Bytecode>,
     pub extern_name: Option<String>,
+    /// The Rust function this calls, for functions registered with `register_native`
+    pub native_id: Option<NativeId>,
+    /// Where the function is declared
+    pub location: Option<Location>,
+    /// Where each statement is, with the range of instructions it was converted to.  Nested statements come before
+    /// the statements they're part of.
+    pub stmt_locations: Vec<(Range<usize>, Location)>,
 }
 
 impl Fun {
     pub fn is_generic(&self) -> bool {
         self.ty_params.len() > 0
     }
+
+    /// Finds where in the source the instruction at `idx` came from: the innermost statement it's part of, or else
+    /// the function itself
+    pub(crate) fn location_of(&self, idx: usize) -> Option<Location> {
+        self.stmt_locations
+            .iter()
+            .find(|(range, _)| range.contains(&idx))
+            .map(|(_, location)| location.clone())
+            .or_else(|| self.location.clone())
+    }
+
+    /// The indices of the instructions that may execute after the instruction at `idx`
+    pub(crate) fn successors(&self, idx: usize) -> Vec<usize> {
+        match self.bytecode[idx] {
+            Bytecode::ReturnVoid | Bytecode::ReturnLastStackValue => vec![],
+            Bytecode::If(offset, _) => vec![idx + 1, idx + offset],
+            Bytecode::Else(offset, _) => vec![idx + offset],
+            // Eval skips past the EndWhile when the condition is false
+            Bytecode::WhileCond(offset) => vec![idx + 1, idx + offset + 1],
+            Bytecode::EndWhile(offset) => vec![idx - offset],
+            _ => vec![idx + 1],
+        }
+    }
+
+    /// Which of the instructions evaluation may get to from the start of the function
+    pub(crate) fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.bytecode.len()];
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            if idx < self.bytecode.len() && !reachable[idx] {
+                reachable[idx] = true;
+                stack.extend(self.successors(idx));
+            }
+        }
+        reachable
+    }
+
+    /// The statements evaluation never gets to, by their index in `stmt_locations`: those with instructions, none of
+    /// which can be reached.  Statements inside one of them are left out.
+    pub(crate) fn unreachable_stmts(&self) -> Vec<usize> {
+        let reachable = self.reachable();
+        let dead: Vec<usize> = (0..self.stmt_locations.len())
+            .filter(|stmt| {
+                let range = &self.stmt_locations[*stmt].0;
+                !range.is_empty() && range.clone().all(|idx| !reachable[idx])
+            })
+            .collect();
+        dead.iter()
+            .cloned()
+            .filter(|stmt| {
+                let inner = &self.stmt_locations[*stmt].0;
+                !dead.iter().any(|other| {
+                    let outer = &self.stmt_locations[*other].0;
+                    outer.start <= inner.start
+                        && inner.end <= outer.end
+                        && (outer.len() > inner.len() || other < stmt)
+                })
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -135,13 +365,51 @@ impl Mod {
 #[derive(Debug, Clone)]
 pub struct Struct {
     pub fields: Vec<(String, DefinitionId)>,
+    /// Set by `#[derive(Copy)]` or `impl Copy for ...`
+    pub copy: bool,
+    /// The generic struct and type arguments this struct is an instance of, if any
+    pub instance_of: Option<(DefinitionId, Vec<DefinitionId>)>,
+    /// Where the struct is declared
+    pub location: Option<Location>,
 }
 impl Struct {
-    fn new(fields: Vec<(String, DefinitionId)>) -> Struct {
-        Struct { fields }
+    fn new(fields: Vec<(String, DefinitionId)>, copy: bool, location: Option<Location>) -> Struct {
+        Struct {
+            fields,
+            copy,
+            instance_of: None,
+            location,
+        }
     }
 }
 
+/// A `macro_rules!` macro, as its rules in the order they're tried
+#[derive(Debug, Clone)]
+pub struct MacroRules {
+    pub rules: Vec<MacroRule>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MacroRule {
+    /// The tokens the invocation is matched against, which may contain `$name:fragment` captures
+    pub pattern: TokenStream,
+    /// The tokens the invocation expands to
+    pub body: TokenStream,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trait {
+    /// The methods the trait declares, some of which may have default bodies
+    pub methods: Vec<TraitItemMethod>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Impl {
+    pub self_ty: DefinitionId,
+    pub trait_name: Option<String>,
+    pub scope_id: ScopeId,
+}
+
 #[derive(Debug, Clone)]
 pub struct LazyFn {
     pub decl: FnDecl,
@@ -161,20 +429,63 @@ pub enum Definition {
     LazyMod(ItemMod),
     LazyStruct(ItemStruct),
     LazyImpl(ItemImpl),
+    LazyTrait(ItemTrait),
 
     //Processed definitions
     Fun(Fun),
     Mod(Mod),
     Struct(Struct),
+    GenericStruct(ItemStruct, ScopeId), // Instantiated into a separate struct for each set of type arguments
+    Impl(Impl),
+    Trait(Trait),
+    Macro(MacroRules),
     InstantiatedFun(DefinitionId, Vec<(DefinitionId, DefinitionId)>),
     Builtin,
     TypeVariable,
+    Reference(DefinitionId, bool), // The type being referenced, and whether the reference is mutable
+
+    //Loaded definitions
+    Omitted(&'static str), // Left out of the image it was loaded from, as it was still syntax.  Has its kind.
+}
+
+impl Definition {
+    /// The keyword the definition is declared with, like `fn` or `struct`.  Builtin types, type variables and
+    /// references are a `type`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Definition::LazyFn(_) | Definition::Fun(_) | Definition::InstantiatedFun(..) => "fn",
+            Definition::LazyMod(_) | Definition::Mod(_) => "mod",
+            Definition::LazyStruct(_) | Definition::Struct(_) | Definition::GenericStruct(..) => {
+                "struct"
+            }
+            Definition::LazyImpl(_) | Definition::Impl(_) => "impl",
+            Definition::LazyTrait(_) | Definition::Trait(_) => "trait",
+            Definition::Macro(_) => "macro",
+            Definition::Builtin | Definition::TypeVariable | Definition::Reference(..) => "type",
+            Definition::Omitted(kind) => kind,
+        }
+    }
 }
 
 pub struct Scope {
-    parent: Option<ScopeId>,
-    is_mod: bool,
+    pub(crate) parent: Option<ScopeId>,
+    pub(crate) is_mod: bool,
     pub(crate) definitions: HashMap<String, DefinitionId>,
+    /// The names of the definitions that were declared without `pub`, so they can only be used inside the module
+    pub(crate) private: HashSet<String>,
+    /// The names brought into the scope by `use`, rather than declared in it
+    pub(crate) imported: HashMap<String, Import>,
+}
+
+/// A name brought into a scope by `use`
+#[derive(Debug, Clone)]
+pub(crate) struct Import {
+    /// Where the name is given in the `use`
+    pub(crate) location: Option<Location>,
+    /// Set for names brought in by a glob, which only name what they import as a group
+    pub(crate) glob: bool,
+    /// Set once the name has been resolved through the scope
+    pub(crate) used: bool,
 }
 
 impl Scope {
@@ -183,8 +494,25 @@ impl Scope {
             parent,
             is_mod,
             definitions: HashMap::new(),
+            private: HashSet::new(),
+            imported: HashMap::new(),
+        }
+    }
+
+    /// Records whether the definition with the given name was declared `pub`.  Restricted visibility like
+    /// `pub(crate)` is treated like `pub`.
+    pub(crate) fn set_pub(&mut self, name: String, is_pub: bool) {
+        if is_pub {
+            self.private.remove(&name);
+        } else {
+            self.private.insert(name);
         }
     }
+
+    /// Returns true if the definition with the given name was declared without `pub`
+    pub(crate) fn is_private(&self, name: &str) -> bool {
+        self.private.contains(name)
+    }
 }
 
 /// BytecodeEngine is the root of Peach's work.  Here code is converted from source files to an intermediate bytecode format
@@ -207,6 +535,63 @@ pub struct BytecodeEngine {
     pub(crate) scopes: Vec<Scope>,
     pub(crate) definitions: Vec<Definition>,
     pub(crate) project_root: Option<::std::path::PathBuf>,
+    /// The directory that `mod` declarations without a body are loaded from
+    src_dir: Option<::std::path::PathBuf>,
+    /// The directories the files of nested modules are loaded from, for each module's scope.  The crate root's are in
+    /// `src_dir`.
+    mod_dirs: HashMap<ScopeId, ::std::path::PathBuf>,
+    /// The directory of the file each module was loaded from, which `#[path]` attributes in it are relative to
+    file_dirs: HashMap<ScopeId, ::std::path::PathBuf>,
+    /// The crates loaded by `add_crate`, as the module of each one's root, by name
+    pub(crate) crates: HashMap<String, DefinitionId>,
+    /// The definitions loaded from the prelude, which are found by lookups that don't find a name in their module
+    pub(crate) prelude: HashMap<String, DefinitionId>,
+    pub(crate) reference_tys: HashMap<(DefinitionId, bool), DefinitionId>,
+    /// Every impl block that has been seen, with the scope it appeared in
+    pub(crate) impls: Vec<(DefinitionId, ScopeId)>,
+    /// The `Drop::drop` implementation for each type that has one
+    pub(crate) drop_fns: HashMap<DefinitionId, DefinitionId>,
+    /// The instances of generic structs, by the generic struct and the type arguments
+    pub(crate) struct_instances: HashMap<(DefinitionId, Vec<DefinitionId>), DefinitionId>,
+    /// The instances of generic functions, by the generic function and the type arguments
+    pub(crate) fn_instances: HashMap<(DefinitionId, Vec<DefinitionId>), DefinitionId>,
+    /// The names of the traits each type variable is bound by
+    pub(crate) type_var_bounds: HashMap<DefinitionId, Vec<String>>,
+    /// Whether each type implements each trait, as far as `implements` has been asked
+    pub(crate) trait_solutions: RefCell<HashMap<(DefinitionId, String), bool>>,
+    /// How many macro expansions deep the conversion currently is
+    pub(crate) macro_depth: usize,
+    /// The attributes that were accepted but ignored
+    pub(crate) skipped_attributes: Vec<String>,
+    /// The `#[test]` functions, in the order they were loaded, when compiling for tests
+    pub(crate) tests: Vec<DefinitionId>,
+    /// The names of the files that have been parsed, by the name proc-macro2 knows them by
+    source_names: HashMap<String, String>,
+    /// The text of the files that have been parsed, by the name they're reported by
+    pub(crate) sources: HashMap<String, String>,
+    /// The syntax of the functions that have been processed, which `dump_ast` and `dump_lowering` show
+    pub(crate) fn_syntax: HashMap<DefinitionId, LazyFn>,
+    /// The locations of the statements converted so far in the function being converted
+    pub(crate) stmt_locations: Vec<(Range<usize>, Location)>,
+    /// The locations of the operators in the statement being converted, by the index of their instruction, so that
+    /// operands that can't be used together are reported where the operator is
+    pub(crate) op_locations: Vec<(usize, Location)>,
+    /// The errors that conversion has recovered from so far, which are reported together when it finishes
+    pub(crate) errors: Vec<PeachError>,
+    /// The warnings found while converting functions
+    pub(crate) warnings: Vec<Warning>,
+    /// The names used in the functions converted so far, with what they name
+    pub(crate) symbols: Vec<Symbol>,
+    /// The Rust functions registered with `register_native`, by `NativeId`
+    pub(crate) natives: Vec<Native>,
+    /// Which builtins programs may use
+    pub(crate) config: EngineConfig,
+    /// The passes run on each function after it's converted and checked, if it's been set
+    pub(crate) pipeline: Option<Pipeline>,
+    /// The directory processed functions are cached in, if it's been set
+    pub(crate) cache_dir: Option<::std::path::PathBuf>,
+    /// A hash of the names and text of every file parsed so far, in order, which cache entries are keyed by
+    pub(crate) sources_hash: u64,
 }
 
 impl BytecodeEngine {
@@ -218,18 +603,42 @@ impl BytecodeEngine {
         }
 
         BytecodeEngine {
-            scopes: vec![Scope {
-                parent: None,
-                is_mod: true,
-                definitions: HashMap::new(),
-            }],
+            scopes: vec![Scope::new(None, true)],
             definitions,
             project_root: None,
+            src_dir: None,
+            mod_dirs: HashMap::new(),
+            file_dirs: HashMap::new(),
+            crates: HashMap::new(),
+            prelude: HashMap::new(),
+            reference_tys: HashMap::new(),
+            impls: vec![],
+            drop_fns: HashMap::new(),
+            struct_instances: HashMap::new(),
+            fn_instances: HashMap::new(),
+            type_var_bounds: HashMap::new(),
+            trait_solutions: RefCell::new(HashMap::new()),
+            macro_depth: 0,
+            skipped_attributes: vec![],
+            tests: vec![],
+            source_names: HashMap::new(),
+            sources: HashMap::new(),
+            fn_syntax: HashMap::new(),
+            stmt_locations: vec![],
+            op_locations: vec![],
+            errors: vec![],
+            warnings: vec![],
+            symbols: vec![],
+            natives: vec![],
+            config: EngineConfig::default(),
+            pipeline: None,
+            cache_dir: None,
+            sources_hash: 0,
         }
     }
 
     /// Will find the definition id for the given name, by starting at the scope given and working up through the scopes
-    /// until the matching definition is found.
+    /// until the matching definition is found.  Names that aren't found in the module are looked for in the prelude.
     /// Returns the corresponding definition id with the scope it was found in
     pub(crate) fn get_defn(
         &self,
@@ -243,12 +652,18 @@ impl BytecodeEngine {
             .contains_key(defn_name)
         {
             if self.scopes[current_scope_id].is_mod {
-                return None;
+                return self
+                    .prelude
+                    .get(defn_name)
+                    .map(|definition_id| (*definition_id, 0));
             }
             if let Some(parent_id) = self.scopes[current_scope_id].parent {
                 current_scope_id = parent_id;
             } else {
-                return None;
+                return self
+                    .prelude
+                    .get(defn_name)
+                    .map(|definition_id| (*definition_id, 0));
             }
         }
 
@@ -258,34 +673,354 @@ impl BytecodeEngine {
         ))
     }
 
+    /// Finds a name the given definition is known by.  This is a reverse lookup through the scopes, so it's best
+    /// used for diagnostics and tooling rather than during processing.
+    pub fn definition_name(&self, definition_id: DefinitionId) -> Option<String> {
+        for scope in &self.scopes {
+            for (name, id) in &scope.definitions {
+                if *id == definition_id {
+                    return Some(name.clone());
+                }
+            }
+        }
+        None
+    }
+
+    /// The names in the scope, both declared and brought in by `use`, sorted, with what they name
+    pub fn scope_names(&self, scope_id: ScopeId) -> Vec<(String, DefinitionId)> {
+        let mut names: Vec<(String, DefinitionId)> = self.scopes[scope_id]
+            .definitions
+            .iter()
+            .filter(|(name, _)| *name != "Self")
+            .map(|(name, definition_id)| (name.clone(), *definition_id))
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The names that can finish a partly written path used in the scope, sorted.  Without a `::` they're the names
+    /// that can be used there: its definitions, those of the scopes around it, and the prelude's.  After a module
+    /// they're the module's items the scope can use, and after a type they're the type's associated functions.  The
+    /// modules and types named along the way are processed, so that their items are known.
+    ///
+    /// Eg) `shapes::Po` gives `["Point"]`, and `Point::` gives `Point`'s associated functions
+    pub fn complete_path(&mut self, partial: &str, scope_id: ScopeId) -> Vec<String> {
+        let mut segments: Vec<&str> = partial.split("::").collect();
+        let prefix = segments.pop().unwrap_or("");
+
+        let mut names: Vec<String> = vec![];
+        if segments.is_empty() {
+            let mut current_scope_id = Some(scope_id);
+            while let Some(current_id) = current_scope_id {
+                names.extend(self.scopes[current_id].definitions.keys().cloned());
+                if self.scopes[current_id].is_mod {
+                    break;
+                }
+                current_scope_id = self.scopes[current_id].parent;
+            }
+            names.extend(self.prelude.keys().cloned());
+        } else {
+            let mut mod_scope_id = scope_id;
+            let mut ty_name = None;
+            for (idx, segment) in segments.iter().enumerate() {
+                if idx == 0 && segment.is_empty() {
+                    mod_scope_id = self.crate_root(scope_id);
+                    continue;
+                }
+                if let Ok(Some(prefix_scope_id)) =
+                    self.path_prefix_scope(segment, scope_id, mod_scope_id)
+                {
+                    mod_scope_id = prefix_scope_id;
+                    continue;
+                }
+
+                // A type ends the path, as only its associated functions can follow it
+                let found = self
+                    .get_defn(segment, mod_scope_id)
+                    .map(|(definition_id, _)| &self.definitions[definition_id]);
+                if let Some(Definition::LazyStruct(_)) | Some(Definition::Struct(_)) = found {
+                    if idx < segments.len() - 1 {
+                        return vec![];
+                    }
+                    ty_name = Some(segment);
+                    break;
+                }
+
+                match self.process_mod(segment, mod_scope_id) {
+                    Ok(definition_id) => match self.definitions[definition_id] {
+                        Definition::Mod(ref module) => mod_scope_id = module.scope_id,
+                        _ => return vec![],
+                    },
+                    Err(_) => return vec![],
+                }
+            }
+
+            names = match ty_name {
+                Some(ty_name) => self.associated_fn_names(ty_name, mod_scope_id),
+                None => self.scopes[mod_scope_id]
+                    .definitions
+                    .keys()
+                    .filter(|name| self.is_visible(name, mod_scope_id, scope_id))
+                    .cloned()
+                    .collect(),
+            };
+        }
+
+        names.retain(|name| name.starts_with(prefix) && name != "Self");
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The names of the functions in the impls for the type with the given name
+    fn associated_fn_names(&mut self, ty_name: &str, scope_id: ScopeId) -> Vec<String> {
+        let ty = match self.process_defn(ty_name, scope_id) {
+            Ok(Some(ty)) => ty,
+            _ => return vec![],
+        };
+        if self.process_impls().is_err() {
+            return vec![];
+        }
+
+        let mut names = vec![];
+        for (definition_id, _) in &self.impls {
+            if let Definition::Impl(ref imp) = self.definitions[*definition_id] {
+                if imp.self_ty == ty {
+                    names.extend(self.scopes[imp.scope_id].definitions.keys().cloned());
+                }
+            }
+        }
+        names
+    }
+
+    /// Finds the path to the given definition from the root of its crate, like `shapes::area`, or `Point::new` for a
+    /// method.  Names brought in by `use` aren't followed, and definitions in crates loaded by `add_crate` start with
+    /// the crate's name.
+    pub fn qualified_name(&self, definition_id: DefinitionId) -> Option<String> {
+        let (mut scope_id, name) = self.declared_in(definition_id)?;
+        let mut path = vec![name];
+        loop {
+            let scope = &self.scopes[scope_id];
+            if let Some(&self_ty) = scope.definitions.get("Self") {
+                path.push(
+                    self.qualified_name(self_ty)
+                        .unwrap_or_else(|| self.ty(self_ty).to_string()),
+                );
+            } else if scope.is_mod {
+                // A module is named where it's declared, in the scope this one continues to.  A crate's root is named
+                // by the crate.
+                let module = self.definitions.iter().position(
+                    |x| matches!(x, Definition::Mod(module) if module.scope_id == scope_id),
+                );
+                match module {
+                    Some(module) if scope.parent.is_some() => {
+                        path.push(self.declared_in(module)?.1);
+                    }
+                    Some(module) => {
+                        if let Some((crate_name, _)) =
+                            self.crates.iter().find(|(_, id)| **id == module)
+                        {
+                            path.push(crate_name.clone());
+                        }
+                    }
+                    None => {}
+                }
+            }
+            match scope.parent {
+                Some(parent_id) => scope_id = parent_id,
+                None => break,
+            }
+        }
+        path.reverse();
+        Some(path.join("::"))
+    }
+
+    /// The scope the given definition was declared in, with its name there
+    pub(crate) fn declared_in(&self, definition_id: DefinitionId) -> Option<(ScopeId, String)> {
+        self.scopes.iter().enumerate().find_map(|(scope_id, scope)| {
+            scope
+                .definitions
+                .iter()
+                .find(|(name, id)| {
+                    **id == definition_id && *name != "Self" && !scope.imported.contains_key(*name)
+                })
+                .map(|(name, _)| (scope_id, name.clone()))
+        })
+    }
+
+    /// Gets the definition id for the type of a reference to the given type, creating it the first time it's needed
+    pub(crate) fn reference_ty(&mut self, ty: DefinitionId, mutable: bool) -> DefinitionId {
+        if let Some(definition_id) = self.reference_tys.get(&(ty, mutable)) {
+            return *definition_id;
+        }
+
+        self.definitions.push(Definition::Reference(ty, mutable));
+        let definition_id = self.definitions.len() - 1;
+        self.reference_tys.insert((ty, mutable), definition_id);
+
+        definition_id
+    }
+
+    /// Gets the definition with the given id
+    pub fn definition(&self, definition_id: DefinitionId) -> &Definition {
+        &self.definitions[definition_id]
+    }
+
     /// Gets the bytecoded function for the given name
-    pub fn get_fn(&self, defn_name: &str, scope_id: ScopeId) -> &Fun {
+    pub fn get_fn(&self, defn_name: &str, scope_id: ScopeId) -> Result<&Fun> {
         if let Some((defn_id, _)) = self.get_defn(defn_name, scope_id) {
             let defn = &self.definitions[defn_id];
 
             if let Definition::Fun(ref p) = defn {
-                p
+                Ok(p)
             } else {
-                unimplemented!("Function {:?} needs to be precomputed", defn)
+                bail!("Function {:?} needs to be precomputed", defn)
             }
         } else {
-            unimplemented!("Function {} could not be found", defn_name);
+            bail!("Function {} could not be found", defn_name);
         }
     }
 
+    /// Finds where the span is in the files that have been loaded
+    #[cfg(procmacro2_semver_exempt)]
+    pub(crate) fn location(&self, span: Span) -> Option<Location> {
+        let file = self
+            .source_names
+            .get(&span.source_file().path().to_string())?;
+        let start = span.start();
+        Some(Location {
+            file: file.clone(),
+            line: start.line,
+            column: start.column + 1,
+        })
+    }
+
+    /// Without proc-macro2's `procmacro2_semver_exempt` cfg, spans don't know where they are
+    #[cfg(not(procmacro2_semver_exempt))]
+    pub(crate) fn location(&self, _span: Span) -> Option<Location> {
+        None
+    }
+
+    /// The name a file is reported by: its path, relative to the project root if it's inside it
+    fn source_name(&self, path: &::std::path::Path) -> String {
+        let relative = match self.project_root {
+            Some(ref project_root) => path.strip_prefix(project_root).unwrap_or(path),
+            None => path,
+        };
+        relative.display().to_string()
+    }
+
+    /// Parses the contents of a source file, remembering the file's name so errors in it can be located, and its text
+    /// so the code at a location can be shown
+    fn parse_source(&mut self, name: &str, src: &str) -> Result<syn::File> {
+        let syntax_file =
+            syn::parse_file(src).map_err(|e| PeachError::Parse(format!("{}: {}", name, e)))?;
+
+        // Every token in the file has a span in the same source file, so any of them will give its name
+        #[cfg(procmacro2_semver_exempt)]
+        if let Some(item) = syntax_file.items.first() {
+            self.source_names.insert(
+                item.span().source_file().path().to_string(),
+                name.to_string(),
+            );
+        }
+        self.sources.insert(name.to_string(), src.to_string());
+
+        let mut hasher = StableHasher::default();
+        (self.sources_hash, name, src).hash(&mut hasher);
+        self.sources_hash = hasher.finish();
+
+        Ok(syntax_file)
+    }
+
     /// Sets the project root that will be used when modules are loaded
-    pub fn set_project_root(&mut self, path: &str) {
+    pub fn set_project_root(&mut self, path: &str) -> Result<()> {
         use std::fs;
 
-        let path = fs::canonicalize(path).unwrap();
+        let path =
+            fs::canonicalize(path).map_err(|e| PeachError::Io(format!("{}: {}", path, e)))?;
 
+        self.src_dir = Some(path.clone());
         self.project_root = Some(path);
+        Ok(())
+    }
+
+    /// Loads a project laid out like a Cargo package, whose crate root is `src/main.rs`, or `src/lib.rs` for a
+    /// library.  Modules are loaded from `src`, and files are reported by their path in the project.
+    pub fn load_project(&mut self, path: &str) -> Result<()> {
+        self.set_project_root(path)?;
+        let src_dir = self.project_root.as_ref().unwrap().join("src");
+
+        let crate_root = match ["main.rs", "lib.rs"]
+            .iter()
+            .map(|fname| src_dir.join(fname))
+            .find(|crate_root| crate_root.is_file())
+        {
+            Some(crate_root) => crate_root,
+            None => {
+                return Err(PeachError::Io(format!(
+                    "{}: no src/main.rs or src/lib.rs",
+                    path
+                )))
+            }
+        };
+        self.src_dir = Some(src_dir);
+
+        let src = read_source(&crate_root)?;
+        let name = self.source_name(&crate_root);
+        self.load_str(&name, &src)
+    }
+
+    /// Loads a file, or a project directory laid out like a Cargo package.  A file's directory becomes the project
+    /// root, so the files of its modules are loaded from beside it, and it's reported by its name.
+    pub fn load_path(&mut self, path: &str) -> Result<()> {
+        let path = ::std::path::Path::new(path)
+            .canonicalize()
+            .map_err(|e| PeachError::Io(format!("{}: {}", path, e)))?;
+        if path.is_dir() {
+            self.load_project(&path.to_string_lossy())
+        } else {
+            let parent = path.parent().unwrap_or_else(|| ::std::path::Path::new("/"));
+            self.set_project_root(&parent.to_string_lossy())?;
+            let fname = path.file_name().unwrap_or_default().to_string_lossy();
+            self.load_file(&fname)
+        }
+    }
+
+    /// The directory that the files of modules declared in the given scope are loaded from
+    fn mod_dir(&self, scope_id: ScopeId) -> ::std::path::PathBuf {
+        let mut scope_id = scope_id;
+        loop {
+            if let Some(mod_dir) = self.mod_dirs.get(&scope_id) {
+                return mod_dir.clone();
+            }
+            match self.scopes[scope_id].parent {
+                Some(parent) => scope_id = parent,
+                None => return self.src_dir.clone().unwrap_or_default(),
+            }
+        }
+    }
+
+    /// The directory that `#[path]` attributes on modules declared in the given scope are relative to.  That's the
+    /// directory of the file they're in, unless they're inside an inline module.
+    fn path_attr_dir(&self, scope_id: ScopeId) -> ::std::path::PathBuf {
+        let mut scope_id = scope_id;
+        loop {
+            if let Some(file_dir) = self.file_dirs.get(&scope_id) {
+                return file_dir.clone();
+            }
+            if let Some(mod_dir) = self.mod_dirs.get(&scope_id) {
+                return mod_dir.clone();
+            }
+            match self.scopes[scope_id].parent {
+                Some(parent) => scope_id = parent,
+                None => return self.src_dir.clone().unwrap_or_default(),
+            }
+        }
     }
 
     /// Loads the file with the given name
-    pub fn load_file(&mut self, fname: &str) {
-        use std::fs::File;
-        use std::io::Read;
+    pub fn load_file(&mut self, fname: &str) -> Result<()> {
         let path = if let Some(ref project_path) = self.project_root {
             let mut temp_path = project_path.clone();
             temp_path.push(fname);
@@ -296,27 +1031,83 @@ impl BytecodeEngine {
             temp_path
         };
 
-        let mut file = File::open(path).expect("Unable to open file");
+        let src = read_source(&path)?;
+        let name = self.source_name(&path);
+        self.load_str(&name, &src)
+    }
+
+    /// Loads the items from the given source text, as if it were the contents of the top-level file.  Errors in it
+    /// are reported as being in the file `name`, which doesn't need to exist.
+    pub fn load_str(&mut self, name: &str, src: &str) -> Result<()> {
+        self.load_source(name, src, 0)
+    }
+
+    /// Loads another crate, so its items can be used from paths starting with its name, or through `extern crate`.
+    /// The path is either the crate's root file, or a project directory with a `src/lib.rs`.
+    pub fn add_crate(&mut self, name: &str, root_path: &str) -> Result<()> {
+        use std::fs;
 
-        let mut src = String::new();
-        file.read_to_string(&mut src).expect("Unable to read file");
+        let path = ::std::path::Path::new(root_path);
+        let root_file = if path.is_dir() {
+            path.join("src").join("lib.rs")
+        } else {
+            path.to_path_buf()
+        };
+        let root_file = fs::canonicalize(&root_file)
+            .map_err(|e| PeachError::Io(format!("{}: {}", root_file.display(), e)))?;
+
+        // Each crate has its own root scope, which paths starting with crate:: lead to
+        self.scopes.push(Scope::new(None, true));
+        let crate_scope_id = self.scopes.len() - 1;
+        let crate_dir = root_file.parent().unwrap().to_path_buf();
+        self.mod_dirs.insert(crate_scope_id, crate_dir.clone());
+        self.file_dirs.insert(crate_scope_id, crate_dir);
+
+        self.definitions
+            .push(Definition::Mod(Mod::new(crate_scope_id)));
+        self.crates
+            .insert(name.to_string(), self.definitions.len() - 1);
+
+        let src = read_source(&root_file)?;
+        let source_name = self.source_name(&root_file);
+        self.load_source(&source_name, &src, crate_scope_id)
+    }
 
-        let syntax_file = syn::parse_file(&src).expect("Unable to parse file");
+    /// Loads the items from the given source text into the root scope of a crate
+    fn load_source(&mut self, name: &str, src: &str, crate_scope_id: ScopeId) -> Result<()> {
+        let syntax_file = self.parse_source(name, src)?;
+        if !self.filter_attrs(&syntax_file.attrs)? {
+            return Ok(());
+        }
 
         for item in syntax_file.items {
-            self.prepare_item(item, 0);
+            self.prepare_item(item, crate_scope_id)?;
         }
+        Ok(())
     }
 
     /// Prepares the given item to be processed lazily
-    pub fn prepare_item(&mut self, item: Item, current_scope_id: ScopeId) {
-        use std::fs::File;
-        use std::io::Read;
+    pub fn prepare_item(&mut self, item: Item, current_scope_id: ScopeId) -> Result<()> {
+        if !self.filter_attrs(item_attrs(&item))? {
+            return Ok(());
+        }
+
+        let visibility = match item {
+            Item::Fn(ref item_fn) => Some((item_fn.ident.to_string(), &item_fn.vis)),
+            Item::Struct(ref item_struct) => {
+                Some((item_struct.ident.to_string(), &item_struct.vis))
+            }
+            Item::Trait(ref item_trait) => Some((item_trait.ident.to_string(), &item_trait.vis)),
+            Item::Mod(ref item_mod) => Some((item_mod.ident.to_string(), &item_mod.vis)),
+            _ => None,
+        }
+        .map(|(name, vis)| (name, is_pub(vis)));
 
         match item {
             Item::Fn(item_fn) => {
                 // Adds a function to be processed lazily
                 let fn_name = item_fn.ident.to_string();
+                let test = is_test(&item_fn.attrs);
                 self.definitions.push(Definition::LazyFn(LazyFn::new(
                     *item_fn.decl,
                     *item_fn.block,
@@ -324,16 +1115,21 @@ impl BytecodeEngine {
                 self.scopes[current_scope_id]
                     .definitions
                     .insert(fn_name, self.definitions.len() - 1);
+                // Tests are only loaded when compiling for them
+                if test {
+                    self.tests.push(self.definitions.len() - 1);
+                }
             }
             Item::ForeignMod(item_fm) => for f in item_fm.items {
                 match f {
+                    ForeignItem::Fn(ref fun) if !self.filter_attrs(&fun.attrs)? => {}
                     ForeignItem::Fn(fun) => {
                         let fn_name = fun.ident.to_string();
 
                         let return_ty = match &fun.decl.output {
                             ReturnType::Default => builtin_type::VOID,
                             ReturnType::Type(_, ref box_ty) => {
-                                self.resolve_type(box_ty, current_scope_id)
+                                self.resolve_type(box_ty, current_scope_id)?
                             }
                         };
 
@@ -348,16 +1144,16 @@ impl BytecodeEngine {
                                         Pat::Ident(ref pi) => {
                                             let ident = pi.ident.to_string();
                                             let ty =
-                                                self.resolve_type(&capture.ty, current_scope_id);
+                                                self.resolve_type(&capture.ty, current_scope_id)?;
                                             let var_id = var_stack.add_var(ident.clone(), ty);
                                             params.push(Param::new(ident, var_id, ty));
                                         }
-                                        _ => unimplemented!(
+                                        _ => bail!(
                                             "Unsupported pattern type in function parameter"
                                         ),
                                     };
                                 }
-                                _ => unimplemented!(
+                                _ => bail!(
                                     "Function argument of {:?} is not supported",
                                     input
                                 ),
@@ -371,42 +1167,92 @@ impl BytecodeEngine {
                             return_ty,
                             vars: vec![],
                             extern_name: Some(fn_name.clone()),
+                            native_id: None,
+                            location: self.location(fun.ident.span()),
+                            stmt_locations: vec![],
                         }));
                         self.scopes[current_scope_id]
                             .definitions
-                            .insert(fn_name, self.definitions.len() - 1);
+                            .insert(fn_name.clone(), self.definitions.len() - 1);
+                        self.scopes[current_scope_id].set_pub(fn_name, is_pub(&fun.vis));
                     }
-                    _ => unimplemented!("Unsupported foreign item"),
+                    _ => bail!("Unsupported foreign item"),
                 }
             },
+            Item::ExternCrate(item_extern_crate) => {
+                let crate_name = item_extern_crate.ident.to_string();
+                let definition_id = match self.crates.get(&crate_name) {
+                    Some(definition_id) => *definition_id,
+                    None => bail_at!(
+                        self.location(item_extern_crate.ident.span()),
+                        "Can not find crate {}",
+                        crate_name
+                    ),
+                };
+                let name = match item_extern_crate.rename {
+                    Some((_, rename)) => rename.to_string(),
+                    None => crate_name,
+                };
+
+                self.scopes[current_scope_id]
+                    .definitions
+                    .insert(name.clone(), definition_id);
+                self.scopes[current_scope_id].set_pub(name, is_pub(&item_extern_crate.vis));
+            }
             Item::Impl(item_impl) => {
-                //let impl_name = item_impl.sig.ident.to_string();
-                println!("{:#?}", item_impl);
+                // Impls are anonymous, so rather than adding them to the scope, we remember them to be processed
+                // once their methods are needed
+                self.definitions.push(Definition::LazyImpl(item_impl));
+                self.impls
+                    .push((self.definitions.len() - 1, current_scope_id));
+            }
+            Item::Trait(item_trait) => {
+                let ident = item_trait.ident.to_string();
+
+                self.definitions.push(Definition::LazyTrait(item_trait));
+                self.scopes[current_scope_id]
+                    .definitions
+                    .insert(ident, self.definitions.len() - 1);
             }
             Item::Mod(item_mod) => {
                 if item_mod.content.is_none() {
-                    //Load the file as a module
+                    //Load the file as a module, which is either foo.rs or foo/mod.rs, unless #[path] says where it is
                     let fname = item_mod.ident.as_ref();
-                    let path = if let Some(ref project_path) = self.project_root {
-                        let mut temp_path = project_path.clone();
-                        temp_path.push(fname);
-                        temp_path.set_extension("rs");
-                        temp_path
-                    } else {
-                        let mut temp_path = ::std::path::PathBuf::new();
-                        temp_path.push(fname);
-                        temp_path.set_extension("rs");
-                        temp_path
+                    let (path, mod_dir) = match path_attr(&item_mod.attrs)? {
+                        Some(path_attr) => {
+                            // Modules in a file given by #[path] are found next to it, like with a mod.rs file
+                            let path = self.path_attr_dir(current_scope_id).join(path_attr);
+                            let mod_dir = path.parent().unwrap().to_path_buf();
+                            (path, mod_dir)
+                        }
+                        None => {
+                            let mod_dir = self.mod_dir(current_scope_id).join(fname);
+                            let file_path = mod_dir.with_extension("rs");
+                            let dir_path = mod_dir.join("mod.rs");
+                            let path = match (file_path.is_file(), dir_path.is_file()) {
+                                (true, true) => bail_at!(
+                                    self.location(item_mod.ident.span()),
+                                    "File for module {} found at both {} and {}",
+                                    fname,
+                                    self.source_name(&file_path),
+                                    self.source_name(&dir_path)
+                                ),
+                                (false, true) => dir_path,
+                                _ => file_path,
+                            };
+                            (path, mod_dir)
+                        }
                     };
 
-                    let mut file = File::open(path).expect("Unable to open file");
-
-                    let mut src = String::new();
-                    file.read_to_string(&mut src).expect("Unable to read file");
-
-                    let syntax_file = syn::parse_file(&src).expect("Unable to parse file");
-                    self.scopes.push(Scope::new(None, true));
+                    let src = read_source(&path)?;
+                    let name = self.source_name(&path);
+                    let syntax_file = self.parse_source(&name, &src)?;
+                    // Names aren't looked up past a module, so the parent is only used by paths like super::
+                    self.scopes.push(Scope::new(Some(current_scope_id), true));
                     let mod_scope_id = self.scopes.len() - 1;
+                    self.mod_dirs.insert(mod_scope_id, mod_dir);
+                    self.file_dirs
+                        .insert(mod_scope_id, path.parent().unwrap().to_path_buf());
 
                     // Eagerly process the top-most bit of the file as a module
                     // This allows us to make its contents lazily available
@@ -418,8 +1264,10 @@ impl BytecodeEngine {
                         .definitions
                         .insert(item_mod.ident.to_string(), self.definitions.len() - 1);
 
-                    for item in syntax_file.items {
-                        self.prepare_item(item, mod_scope_id);
+                    if self.filter_attrs(&syntax_file.attrs)? {
+                        for item in syntax_file.items {
+                            self.prepare_item(item, mod_scope_id)?;
+                        }
                     }
                 } else {
                     // Add module to be processed lazily
@@ -446,7 +1294,44 @@ impl BytecodeEngine {
                     }
                 }
 
-                self.process_use_tree(&item_use.tree, current_scope_id, temp_scope_id);
+                self.process_use_tree(
+                    &item_use.tree,
+                    current_scope_id,
+                    temp_scope_id,
+                    is_pub(&item_use.vis),
+                )
+                .map_err(|e| e.at(self.location(item_use.span())))?;
+            }
+            Item::Macro(ref item_macro) if macro_name(&item_macro.mac) == "macro_rules" => {
+                let ident = match item_macro.ident {
+                    Some(ident) => ident.to_string(),
+                    None => bail!("macro_rules! is missing the macro name"),
+                };
+                let macro_rules = self.parse_macro_rules(&ident, &item_macro.mac.tts)?;
+
+                // Macros live in their own namespace, so their names are kept apart from other definitions
+                self.definitions.push(Definition::Macro(macro_rules));
+                self.scopes[current_scope_id]
+                    .definitions
+                    .insert(ident + "!", self.definitions.len() - 1);
+            }
+            Item::Macro(ref item_macro) if item_macro.ident.is_none() => {
+                // A macro invoked where an item is expected expands to items
+                let tokens = self.expand_macro(&item_macro.mac, current_scope_id)?;
+                let syntax_file: syn::File = match syn::parse2(tokens) {
+                    Ok(syntax_file) => syntax_file,
+                    Err(_) => bail!(
+                        "Expansion of {}! is not a valid list of items",
+                        macro_name(&item_macro.mac)
+                    ),
+                };
+                self.macro_depth += 1;
+                let result = syntax_file
+                    .items
+                    .into_iter()
+                    .try_for_each(|item| self.prepare_item(item, current_scope_id));
+                self.macro_depth -= 1;
+                result?;
             }
             Item::Struct(item_struct) => {
                 let ident = item_struct.ident.to_string();
@@ -457,122 +1342,450 @@ impl BytecodeEngine {
                     .insert(ident, self.definitions.len() - 1);
             }
             _ => {
-                unimplemented!("Unknown item type: {:#?}", item);
+                bail_at!(self.location(item.span()), "Unknown item type: {:#?}", item);
             }
         }
+
+        if let Some((name, is_pub)) = visibility {
+            self.scopes[current_scope_id].set_pub(name, is_pub);
+        }
+
+        Ok(())
     }
 
     /// Begin processing the lazy definitions starting at the given function.
     /// This will continue processing until all necessary definitions have been processed.
-    pub fn process_fn(&mut self, fn_name: &str, scope_id: ScopeId) -> DefinitionId {
-        if let Some((definition_id, found_scope_id)) = self.get_defn(fn_name, scope_id) {
-            let fun = self.convert_fn_to_bytecode(definition_id, found_scope_id);
-            self.definitions[definition_id] = Definition::Fun(fun);
+    /// If there are errors, all of the ones found are returned together.
+    /// If a cache directory has been set, the function is loaded from the cache when it was processed before from the
+    /// same source.
+    pub fn process_fn(&mut self, fn_name: &str, scope_id: ScopeId) -> Result<DefinitionId> {
+        if self.cache_dir.is_some() {
+            return self.process_fn_cached(fn_name, scope_id);
+        }
+        let result = self.process_fn_recovering(fn_name, scope_id);
+        self.take_errors(result)
+    }
+
+    /// Typechecks a function and reports every error in it, without keeping the bytecode it's converted to.  The
+    /// function is left as it was, so it's only processed when something calls it.  The functions it calls are
+    /// processed as usual.
+    pub fn check_fn(&mut self, fn_name: &str, scope_id: ScopeId) -> Result<()> {
+        let result = match self.use_defn(fn_name, scope_id) {
+            Some((definition_id, found_scope_id)) => self
+                .convert_fn_to_bytecode(definition_id, found_scope_id)
+                .map(|_| ()),
+            None => Err(PeachError::Conversion(
+                format!("Can not find function {}", fn_name),
+                None,
+            )),
+        };
+        self.take_errors(result)
+    }
 
-            definition_id
+    /// Fails with the errors that have been recovered from, if there are any, along with the error `result` stopped
+    /// at
+    pub(crate) fn take_errors<T>(&mut self, result: Result<T>) -> Result<T> {
+        let mut errors = mem::take(&mut self.errors);
+        match result {
+            Ok(_) if errors.is_empty() => result,
+            Ok(_) => Err(PeachError::from_errors(errors)),
+            Err(e) => {
+                errors.push(e);
+                Err(PeachError::from_errors(errors))
+            }
+        }
+    }
+
+    /// Processes a function, adding the errors in its statements to `errors` rather than stopping at the first
+    pub(crate) fn process_fn_recovering(
+        &mut self,
+        fn_name: &str,
+        scope_id: ScopeId,
+    ) -> Result<DefinitionId> {
+        if let Some((definition_id, found_scope_id)) = self.use_defn(fn_name, scope_id) {
+            let errors_before = self.errors.len();
+            let mut fun = self.convert_fn_to_bytecode(definition_id, found_scope_id)?;
+            // Impls may make the types used by the function Copy
+            self.process_impls()?;
+            // The bytecode of statements with errors is left out, so it isn't worth checking
+            if self.errors.len() == errors_before {
+                self.infer_literals(definition_id, &mut fun)?;
+                self.check_init(&fun)?;
+                self.check_moves(&mut fun)?;
+                self.check_borrows(&fun)?;
+                if let Some(ref pipeline) = self.pipeline {
+                    pipeline.run(&mut fun);
+                }
+                self.verify_fn(definition_id, &fun)?;
+            }
+            let syntax = mem::replace(&mut self.definitions[definition_id], Definition::Fun(fun));
+            if let Definition::LazyFn(syntax) = syntax {
+                self.fn_syntax.insert(definition_id, syntax);
+            }
+
+            Ok(definition_id)
         } else {
-            unimplemented!("Can not find function {}", fn_name);
+            bail!("Can not find function {}", fn_name);
         }
     }
 
-    fn process_struct(&mut self, struct_name: &str, scope_id: ScopeId) -> DefinitionId {
-        if let Some((definition_id, _found_scope_id)) = self.get_defn(struct_name, scope_id) {
-            let fields_in =
+    fn process_struct(&mut self, struct_name: &str, scope_id: ScopeId) -> Result<DefinitionId> {
+        if let Some((definition_id, found_scope_id)) = self.use_defn(struct_name, scope_id) {
+            if let Definition::LazyStruct(ref item_struct) = self.definitions[definition_id] {
+                let is_generic = item_struct
+                    .generics
+                    .params
+                    .iter()
+                    .any(|param| matches!(param, GenericParam::Type(_)));
+                if is_generic {
+                    // Generic structs are laid out when they're instantiated with type arguments
+                    self.definitions[definition_id] =
+                        Definition::GenericStruct(item_struct.clone(), found_scope_id);
+                    return Ok(definition_id);
+                }
+            }
+
+            let (fields_in, copy, location) =
                 if let Definition::LazyStruct(ref item_struct) = self.definitions[definition_id] {
-                    item_struct.fields.clone()
+                    (
+                        item_struct.fields.clone(),
+                        derives(&item_struct.attrs, "Copy"),
+                        self.location(item_struct.ident.span()),
+                    )
                 } else {
-                    unimplemented!("Could not process struct fields");
+                    bail!("Could not process struct fields");
                 };
 
             let mut fields: Vec<(String, DefinitionId)> = vec![];
             for iter in &fields_in {
-                let field_ty = self.resolve_type(&iter.ty, scope_id);
-                fields.push((iter.ident.unwrap().to_string(), field_ty));
+                if !self.filter_attrs(&iter.attrs)? {
+                    continue;
+                }
+                let field_ty = self
+                    .resolve_type(&iter.ty, scope_id)
+                    .map_err(|e| e.at(location.clone()))?;
+                let field_name = match iter.ident {
+                    Some(ref ident) => ident.to_string(),
+                    None => bail_at!(self.location(iter.span()), "Tuple structs aren't supported"),
+                };
+                fields.push((field_name, field_ty));
             }
 
             fields.sort();
 
-            let s = Struct::new(fields);
+            let s = Struct::new(fields, copy, location);
             self.definitions[definition_id] = Definition::Struct(s);
 
-            definition_id
+            Ok(definition_id)
         } else {
-            unimplemented!("Can not find struct {}", struct_name);
+            bail!("Can not find struct {}", struct_name);
         }
     }
 
-    fn process_mod(&mut self, mod_name: &str, scope_id: ScopeId) -> DefinitionId {
-        if let Some((definition_id, current_scope_id)) = self.get_defn(mod_name, scope_id) {
+    pub(crate) fn process_mod(&mut self, mod_name: &str, scope_id: ScopeId) -> Result<DefinitionId> {
+        if let Some((definition_id, current_scope_id)) = self.use_defn(mod_name, scope_id) {
             if let Definition::LazyMod(ref item_mod) = self.definitions[definition_id] {
                 self.scopes.push(Scope::new(Some(current_scope_id), true));
                 let mod_scope_id = self.scopes.len() - 1;
+                // Files for modules declared inside are found in a directory named after this one
+                let mod_dir = self.mod_dir(current_scope_id).join(item_mod.ident.as_ref());
+                self.mod_dirs.insert(mod_scope_id, mod_dir);
+
+                //TODO: would be great if we didn't clone here and just reused what we had
+                let items = match item_mod.content {
+                    Some(ref content) => content.1.clone(),
+                    None => vec![],
+                };
 
-                match item_mod.content {
-                    //TODO: would be great if we didn't clone here and just reused what we had
-                    Some(ref content) => for item in content.1.clone() {
-                        self.prepare_item(item, mod_scope_id);
-                    },
-                    None => {}
-                }
-
+                // The module is replaced before its items are prepared, so a `use super::*` inside it doesn't load
+                // it again
                 self.definitions[definition_id] = Definition::Mod(Mod::new(mod_scope_id));
+                for item in items {
+                    self.prepare_item(item, mod_scope_id)?;
+                }
             } else if let Definition::Mod(_) = self.definitions[definition_id] {
 
             } else {
-                unimplemented!("Processing definition that is not a lazy module");
+                bail!("Processing definition that is not a lazy module");
             }
-            definition_id
+            Ok(definition_id)
         } else {
-            unimplemented!("Can not find mod {}", mod_name);
+            // Crates can be used without `extern crate`
+            match self.crates.get(mod_name) {
+                Some(definition_id) => Ok(*definition_id),
+                None => bail!("Can not find mod {}", mod_name),
+            }
         }
     }
 
-    fn process_impl(&mut self, impl_name: &str, scope_id: ScopeId) -> DefinitionId {
-        if let Some((definition_id, current_scope_id)) = self.get_defn(impl_name, scope_id) {
-            if let Definition::LazyImpl(item_impl) = self.definitions[definition_id].clone() {
-                self.scopes.push(Scope::new(Some(current_scope_id), true));
-                let impl_scope_id = self.scopes.len() - 1;
-                for item in item_impl.items {
+    fn process_impl(&mut self, definition_id: DefinitionId, scope_id: ScopeId) -> Result<()> {
+        if let Definition::LazyImpl(item_impl) = self.definitions[definition_id].clone() {
+            let self_ty = self.resolve_type(&item_impl.self_ty, scope_id)?;
+
+            self.scopes.push(Scope::new(Some(scope_id), false));
+            let impl_scope_id = self.scopes.len() - 1;
+            self.scopes[impl_scope_id]
+                .definitions
+                .insert("Self".to_string(), self_ty);
+
+            for item in item_impl.items {
+                match item {
+                    ImplItem::Method(ref impl_item_method)
+                        if !self.filter_attrs(&impl_item_method.attrs)? => {}
+                    ImplItem::Method(impl_item_method) => {
+                        // Adds a function to be processed lazily
+                        let fn_name = impl_item_method.sig.ident.to_string();
+                        self.definitions.push(Definition::LazyFn(LazyFn::new(
+                            impl_item_method.sig.decl,
+                            impl_item_method.block,
+                        )));
+                        self.scopes[impl_scope_id]
+                            .definitions
+                            .insert(fn_name, self.definitions.len() - 1);
+                    }
+                    _ => bail!("Unsupport item type when processing impl"),
+                }
+            }
+
+            let trait_name = match item_impl.trait_ {
+                Some((_, ref path, _)) => {
+                    Some(path.segments[path.segments.len() - 1].ident.to_string())
+                }
+                None => None,
+            };
+
+            // Drop and Copy are built in, other traits must be declared
+            if let Some((_, ref path, _)) = item_impl.trait_ {
+                let trait_name = trait_name.clone().unwrap();
+                if trait_name != "Drop" && trait_name != "Copy" {
+                    let self_name = match *item_impl.self_ty {
+                        Type::Path(ref tp) => tp.path.segments[tp.path.segments.len() - 1]
+                            .ident
+                            .to_string(),
+                        _ => self.ty(self_ty).to_string(),
+                    };
+                    self.add_trait_methods(path, &trait_name, &self_name, impl_scope_id, scope_id)?;
+                }
+            }
+
+            // Mark the impl as processed before processing any methods, as they may need the impls themselves
+            self.definitions[definition_id] = Definition::Impl(Impl {
+                self_ty,
+                trait_name: trait_name.clone(),
+                scope_id: impl_scope_id,
+            });
+
+            if trait_name == Some("Copy".to_string()) {
+                if let Definition::Struct(ref mut st) = self.definitions[self_ty] {
+                    st.copy = true;
+                } else {
+                    bail!("Copy can not be implemented for {}", self.ty(self_ty));
+                }
+            }
+
+            // The impl can make the type implement more traits
+            self.trait_solutions.get_mut().clear();
+
+            if trait_name == Some("Drop".to_string()) {
+                if !self.scopes[impl_scope_id].definitions.contains_key("drop") {
+                    bail!(
+                        "Drop impl for {} is missing a drop method",
+                        self.ty(self_ty)
+                    );
+                }
+                let drop_fn = self.process_fn_recovering("drop", impl_scope_id)?;
+                self.drop_fns.insert(self_ty, drop_fn);
+            }
+        }
+        Ok(())
+    }
+
+    fn process_trait(&mut self, trait_name: &str, scope_id: ScopeId) -> Result<DefinitionId> {
+        if let Some((definition_id, _)) = self.use_defn(trait_name, scope_id) {
+            if let Definition::LazyTrait(item_trait) = self.definitions[definition_id].clone() {
+                let mut methods = vec![];
+                for item in item_trait.items {
                     match item {
-                        ImplItem::Method(impl_item_method) => {
-                            // Adds a function to be processed lazily
-                            let fn_name = impl_item_method.sig.ident.to_string();
-                            self.definitions.push(Definition::LazyFn(LazyFn::new(
-                                impl_item_method.sig.decl,
-                                impl_item_method.block,
-                            )));
-                            self.scopes[impl_scope_id]
-                                .definitions
-                                .insert(fn_name, self.definitions.len() - 1);
-                        }
-                        _ => unimplemented!("Unsupport item type when processing impl"),
+                        TraitItem::Method(ref trait_item_method)
+                            if !self.filter_attrs(&trait_item_method.attrs)? => {}
+                        TraitItem::Method(trait_item_method) => methods.push(trait_item_method),
+                        _ => bail!("Unsupported item type when processing trait"),
                     }
                 }
-            } else {
-                unimplemented!("Processing definition that is not a lazy impl");
+                self.definitions[definition_id] = Definition::Trait(Trait { methods });
             }
-            definition_id
+            Ok(definition_id)
         } else {
-            unimplemented!("Could not find impl {}", impl_name);
+            bail!("Can not find trait {}", trait_name);
+        }
+    }
+
+    /// Checks the methods of an impl against those of the trait it implements, and adds the trait's default methods
+    /// that the impl doesn't override.  Each impl gets its own copy of the default methods, so that `Self` refers to
+    /// the impl's type.
+    fn add_trait_methods(
+        &mut self,
+        trait_path: &syn::Path,
+        trait_name: &str,
+        self_name: &str,
+        impl_scope_id: ScopeId,
+        scope_id: ScopeId,
+    ) -> Result<()> {
+        let methods = match self.process_path(trait_path, scope_id)? {
+            Some(trait_id) => match self.definitions[trait_id] {
+                Definition::Trait(ref tr) => tr.methods.clone(),
+                _ => bail!("{} is not a trait", trait_name),
+            },
+            None => bail!("Can not find trait {}", trait_name),
+        };
+
+        for fn_name in self.scopes[impl_scope_id].definitions.keys() {
+            if fn_name != "Self" && !methods.iter().any(|method| method.sig.ident == fn_name) {
+                bail!("Method {} is not a member of trait {}", fn_name, trait_name);
+            }
         }
+
+        for method in methods {
+            let fn_name = method.sig.ident.to_string();
+            if self.scopes[impl_scope_id]
+                .definitions
+                .contains_key(&fn_name)
+            {
+                continue;
+            }
+            match method.default {
+                Some(block) => {
+                    self.definitions
+                        .push(Definition::LazyFn(LazyFn::new(method.sig.decl, block)));
+                    self.scopes[impl_scope_id]
+                        .definitions
+                        .insert(fn_name, self.definitions.len() - 1);
+                }
+                None => bail!(
+                    "Missing method {} in impl of {} for {}",
+                    fn_name,
+                    trait_name,
+                    self_name
+                ),
+            }
+        }
+        Ok(())
     }
 
-    fn process_defn(&mut self, name: &str, scope_id: ScopeId) -> Option<DefinitionId> {
-        if let Some((definition_id, scope_id)) = self.get_defn(name, scope_id) {
-            match self.definitions[definition_id] {
-                Definition::LazyFn(_) => Some(self.process_fn(name, scope_id)),
-                Definition::LazyMod(_) => Some(self.process_mod(name, scope_id)),
-                Definition::LazyStruct(_) => Some(self.process_struct(name, scope_id)),
-                Definition::LazyImpl(_) => Some(self.process_impl(name, scope_id)),
-                Definition::Fun(_) => Some(definition_id),
-                Definition::Struct(_) => Some(definition_id),
-                Definition::Mod(_) => Some(definition_id),
-                Definition::Builtin => Some(definition_id),
-                Definition::TypeVariable => Some(definition_id),
-                Definition::InstantiatedFun(_, _) => Some(definition_id),
+    /// Finds the function with the given name in the impls for the given type, preferring inherent impls over
+    /// trait impls, and processes it.  It's an error if more than one of the traits the type implements has it.
+    pub(crate) fn process_associated_fn(
+        &mut self,
+        ty: DefinitionId,
+        fn_name: &str,
+    ) -> Result<Option<DefinitionId>> {
+        self.process_impls()?;
+
+        let mut traits: Vec<String> = vec![];
+        for (definition_id, _) in &self.impls {
+            if let Definition::Impl(ref imp) = self.definitions[*definition_id] {
+                if imp.self_ty == ty && self.scopes[imp.scope_id].definitions.contains_key(fn_name)
+                {
+                    match imp.trait_name {
+                        None => return self.process_defn(fn_name, imp.scope_id),
+                        Some(ref trait_name) => traits.push(trait_name.clone()),
+                    }
+                }
             }
+        }
+
+        if traits.len() > 1 {
+            bail!(
+                "Multiple traits implemented by {} have a method named {}: {}",
+                self.ty(ty),
+                fn_name,
+                traits.join(", ")
+            );
+        }
+        let impl_scope_id = match traits
+            .first()
+            .and_then(|trait_name| self.find_impl(ty, trait_name))
+            .map(|impl_id| &self.definitions[impl_id])
+        {
+            Some(Definition::Impl(imp)) => imp.scope_id,
+            _ => return Ok(None),
+        };
+        self.process_defn(fn_name, impl_scope_id)
+    }
+
+    /// Processes any impls which haven't been processed yet
+    pub(crate) fn process_impls(&mut self) -> Result<()> {
+        let mut idx = 0;
+        while idx < self.impls.len() {
+            let (definition_id, scope_id) = self.impls[idx];
+            self.process_impl(definition_id, scope_id)?;
+            idx += 1;
+        }
+
+        self.check_copy_types()
+    }
+
+    /// Processes what's needed to drop a value of the given type (its Drop impl, and those of its fields).
+    /// Returns true if dropping the value runs any code.
+    pub(crate) fn process_drop_glue(&mut self, ty: DefinitionId) -> Result<bool> {
+        self.process_impls()?;
+
+        let fields = match self.definitions[ty] {
+            Definition::Struct(ref st) => st.fields.clone(),
+            _ => return Ok(false),
+        };
+
+        let mut needs_drop = self.drop_fns.contains_key(&ty);
+        for (_, field_ty) in fields {
+            if self.process_drop_glue(field_ty)? {
+                needs_drop = true;
+            }
+        }
+        Ok(needs_drop)
+    }
+
+    /// Returns true if dropping a value of the given type runs any code.  The drop glue must already be processed.
+    pub(crate) fn needs_drop(&self, ty: DefinitionId) -> bool {
+        match self.definitions[ty] {
+            Definition::Struct(ref st) => {
+                self.drop_fns.contains_key(&ty)
+                    || st
+                        .fields
+                        .iter()
+                        .any(|(_, field_ty)| self.needs_drop(*field_ty))
+            }
+            _ => false,
+        }
+    }
+
+    fn process_defn(&mut self, name: &str, scope_id: ScopeId) -> Result<Option<DefinitionId>> {
+        if let Some((definition_id, scope_id)) = self.use_defn(name, scope_id) {
+            let processed_id = match self.definitions[definition_id] {
+                Definition::LazyFn(_) => self.process_fn_recovering(name, scope_id)?,
+                Definition::LazyMod(_) => self.process_mod(name, scope_id)?,
+                Definition::LazyStruct(_) => self.process_struct(name, scope_id)?,
+                Definition::LazyImpl(_) => definition_id,
+                Definition::LazyTrait(_) => self.process_trait(name, scope_id)?,
+                Definition::Trait(_) => definition_id,
+                Definition::Macro(_) => definition_id,
+                Definition::Impl(_) => definition_id,
+                Definition::Fun(_) => definition_id,
+                Definition::Struct(_) => definition_id,
+                Definition::GenericStruct(_, _) => definition_id,
+                Definition::Mod(_) => definition_id,
+                Definition::Builtin => definition_id,
+                Definition::TypeVariable => definition_id,
+                Definition::InstantiatedFun(_, _) => definition_id,
+                Definition::Reference(_, _) => definition_id,
+                Definition::Omitted(kind) => bail!(
+                    "The {} {} was left out of the image it was loaded from",
+                    kind,
+                    name
+                ),
+            };
+            Ok(Some(processed_id))
         } else {
-            None
+            Ok(None)
         }
     }
 
@@ -582,27 +1795,63 @@ impl BytecodeEngine {
         &mut self,
         path: &syn::Path,
         current_scope_id: ScopeId,
-    ) -> Option<DefinitionId> {
+    ) -> Result<Option<DefinitionId>> {
         let mut mod_scope_id = current_scope_id;
         if path.leading_colon.is_some() {
-            loop {
-                if let Some(parent_id) = self.scopes[mod_scope_id].parent {
-                    mod_scope_id = parent_id;
-                } else {
-                    break;
-                }
-            }
+            mod_scope_id = self.crate_root(current_scope_id);
         }
 
         let num_segments = path.segments.len();
 
-        for current_segment in 0..(num_segments - 1) {
+        // Paths can start with crate::, self::, and any number of super::
+        let mut first_segment = 0;
+        while first_segment < num_segments - 1 {
+            match self.path_prefix_scope(
+                path.segments[first_segment].ident.as_ref(),
+                current_scope_id,
+                mod_scope_id,
+            )? {
+                Some(prefix_scope_id) => mod_scope_id = prefix_scope_id,
+                None => break,
+            }
+            first_segment += 1;
+        }
+
+        for current_segment in first_segment..(num_segments - 1) {
             let ident = path.segments[current_segment].ident.as_ref();
-            let definition_id = self.process_mod(ident, mod_scope_id);
+
+            self.check_visible(ident, mod_scope_id, current_scope_id)?;
+
+            // A type followed by a name is a function from one of the type's impls
+            if current_segment == num_segments - 2 {
+                if let Some((definition_id, _)) = self.use_defn(ident, mod_scope_id) {
+                    match self.definitions[definition_id] {
+                        Definition::LazyStruct(_) | Definition::Struct(_) => {
+                            let ty = self.process_defn(ident, mod_scope_id)?.unwrap();
+                            let span = path.segments[current_segment].ident.span();
+                            self.record_symbol(span, ident, SymbolTarget::Definition(ty));
+
+                            let fn_segment = &path.segments[num_segments - 1];
+                            let fn_name = fn_segment.ident.to_string();
+                            let definition_id = self.process_associated_fn(ty, &fn_name)?;
+                            if let Some(definition_id) = definition_id {
+                                let target = SymbolTarget::Definition(definition_id);
+                                self.record_symbol(fn_segment.ident.span(), &fn_name, target);
+                            }
+                            return Ok(definition_id);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            let definition_id = self.process_mod(ident, mod_scope_id)?;
+            let span = path.segments[current_segment].ident.span();
+            self.record_symbol(span, ident, SymbolTarget::Definition(definition_id));
             if let Definition::Mod(ref module) = self.definitions[definition_id] {
                 mod_scope_id = module.scope_id;
             } else {
-                unimplemented!("Failure to process module");
+                bail!("Failure to process module");
             }
         }
 
@@ -611,7 +1860,118 @@ impl BytecodeEngine {
         let ident = path.segments[num_segments - 1].ident.to_string();
 
         // lastly, make sure we've processed the definition before we return
-        self.process_defn(&ident, mod_scope_id)
+        self.check_visible(&ident, mod_scope_id, current_scope_id)?;
+        let definition_id = self.process_defn(&ident, mod_scope_id)?;
+        if let Some(definition_id) = definition_id {
+            let span = path.segments[num_segments - 1].ident.span();
+            self.record_symbol(span, &ident, SymbolTarget::Definition(definition_id));
+        }
+        Ok(definition_id)
+    }
+
+    /// Returns true if the definition with the given name, found in the scope `found_scope_id`, can be used from
+    /// `from_scope_id`.  Private definitions can be used anywhere inside the module they're declared in.
+    fn is_visible(&self, name: &str, found_scope_id: ScopeId, from_scope_id: ScopeId) -> bool {
+        if !self.scopes[found_scope_id].private.contains(name) {
+            return true;
+        }
+
+        let module_id = self.module_of(found_scope_id);
+        let mut scope_id = from_scope_id;
+        loop {
+            if scope_id == module_id {
+                return true;
+            }
+            match self.scopes[scope_id].parent {
+                Some(parent_id) => scope_id = parent_id,
+                None => return false,
+            }
+        }
+    }
+
+    /// Fails if the name, as looked up from `scope_id`, is a private definition that can't be used from
+    /// `from_scope_id`
+    fn check_visible(&self, name: &str, scope_id: ScopeId, from_scope_id: ScopeId) -> Result<()> {
+        if let Some((_, found_scope_id)) = self.get_defn(name, scope_id) {
+            if !self.is_visible(name, found_scope_id, from_scope_id) {
+                bail!(
+                    "{} is private, so it can only be used inside its module",
+                    name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a definition brought into scope by `use`
+    fn import(
+        &mut self,
+        scope_id: ScopeId,
+        name: String,
+        definition_id: DefinitionId,
+        is_pub: bool,
+        import: Import,
+    ) {
+        self.scopes[scope_id]
+            .definitions
+            .insert(name.clone(), definition_id);
+        self.scopes[scope_id].imported.insert(name.clone(), import);
+        self.scopes[scope_id].set_pub(name, is_pub);
+    }
+
+    /// Looks up a name like `get_defn`, for a use of it.  If it's found through an import, the import is marked used.
+    pub(crate) fn use_defn(
+        &mut self,
+        name: &str,
+        scope_id: ScopeId,
+    ) -> Option<(DefinitionId, ScopeId)> {
+        let found = self.get_defn(name, scope_id);
+        if let Some((_, found_scope_id)) = found {
+            if let Some(import) = self.scopes[found_scope_id].imported.get_mut(name) {
+                import.used = true;
+            }
+        }
+        found
+    }
+
+    /// The scope of the crate's root module, which the given scope is part of
+    pub(crate) fn crate_root(&self, scope_id: ScopeId) -> ScopeId {
+        let mut scope_id = scope_id;
+        while let Some(parent_id) = self.scopes[scope_id].parent {
+            scope_id = parent_id;
+        }
+        scope_id
+    }
+
+    /// The scope of the module the given scope is part of
+    fn module_of(&self, scope_id: ScopeId) -> ScopeId {
+        let mut scope_id = scope_id;
+        while !self.scopes[scope_id].is_mod {
+            match self.scopes[scope_id].parent {
+                Some(parent_id) => scope_id = parent_id,
+                None => break,
+            }
+        }
+        scope_id
+    }
+
+    /// Resolves a `crate`, `self`, or `super` segment at the start of a path, given the scope the path is used in
+    /// and the module the segments before it led to.  Returns None for any other segment.
+    fn path_prefix_scope(
+        &self,
+        segment: &str,
+        current_scope_id: ScopeId,
+        mod_scope_id: ScopeId,
+    ) -> Result<Option<ScopeId>> {
+        match segment {
+            "crate" => Ok(Some(self.crate_root(current_scope_id))),
+            "self" => Ok(Some(self.module_of(mod_scope_id))),
+            "super" => match self.scopes[self.module_of(mod_scope_id)].parent {
+                Some(parent_id) => Ok(Some(self.module_of(parent_id))),
+                None => bail!("There are too many leading super keywords"),
+            },
+            _ => Ok(None),
+        }
     }
 
     fn process_use_tree(
@@ -619,68 +1979,131 @@ impl BytecodeEngine {
         use_tree: &syn::UseTree,
         original_scope_id: ScopeId,
         current_scope_id: ScopeId,
-    ) {
+        is_pub: bool,
+    ) -> Result<()> {
         match use_tree {
             syn::UseTree::Name(ref use_name) => {
-                let definition_id = self.process_defn(use_name.ident.as_ref(), current_scope_id);
+                self.check_visible(use_name.ident.as_ref(), current_scope_id, original_scope_id)?;
+                let definition_id = self.process_defn(use_name.ident.as_ref(), current_scope_id)?;
 
                 if definition_id.is_none() {
-                    unimplemented!(
+                    bail!(
                         "Could not process the definition for {}",
                         use_name.ident.as_ref()
                     );
                 }
 
-                self.scopes[original_scope_id]
-                    .definitions
-                    .insert(use_name.ident.to_string(), definition_id.unwrap());
+                let import = Import {
+                    location: self.location(use_name.ident.span()),
+                    glob: false,
+                    used: false,
+                };
+                self.import(
+                    original_scope_id,
+                    use_name.ident.to_string(),
+                    definition_id.unwrap(),
+                    is_pub,
+                    import,
+                );
             }
             syn::UseTree::Path(ref use_path) => {
-                let definition_id = self.process_mod(use_path.ident.as_ref(), current_scope_id);
+                if let Some(prefix_scope_id) = self.path_prefix_scope(
+                    use_path.ident.as_ref(),
+                    original_scope_id,
+                    current_scope_id,
+                )? {
+                    return self.process_use_tree(
+                        &use_path.tree,
+                        original_scope_id,
+                        prefix_scope_id,
+                        is_pub,
+                    );
+                }
+
+                self.check_visible(use_path.ident.as_ref(), current_scope_id, original_scope_id)?;
+                let definition_id = self.process_mod(use_path.ident.as_ref(), current_scope_id)?;
                 if let Definition::Mod(ref module) = self.definitions[definition_id] {
-                    self.process_use_tree(&*use_path.tree, original_scope_id, module.scope_id);
+                    self.process_use_tree(
+                        &use_path.tree,
+                        original_scope_id,
+                        module.scope_id,
+                        is_pub,
+                    )?;
                 } else {
-                    unimplemented!("Expected module in use path");
+                    bail!("Expected module in use path");
                 }
             }
             syn::UseTree::Group(ref use_group) => {
                 for tree in &use_group.items {
-                    self.process_use_tree(tree, original_scope_id, current_scope_id);
+                    self.process_use_tree(tree, original_scope_id, current_scope_id, is_pub)?;
                 }
             }
             syn::UseTree::Glob(_) => {
+                // Only the definitions that are visible from where the use is are imported.  Names that are
+                // imported by name aren't replaced, so the import keeps track of whether it's used.
                 let mut defn_names = vec![];
                 for defn_name in self.scopes[current_scope_id].definitions.keys() {
-                    defn_names.push(defn_name.clone());
+                    let named = match self.scopes[original_scope_id].imported.get(defn_name) {
+                        Some(import) => !import.glob,
+                        None => false,
+                    };
+                    if !named && self.is_visible(defn_name, current_scope_id, original_scope_id) {
+                        defn_names.push(defn_name.clone());
+                    }
                 }
 
                 for defn_name in defn_names {
-                    let definition_id = self.process_defn(&defn_name, current_scope_id);
+                    let definition_id = self.process_defn(&defn_name, current_scope_id)?;
 
                     if definition_id.is_none() {
-                        unimplemented!("Could not process the definition for {}", defn_name);
+                        bail!("Could not process the definition for {}", defn_name);
                     }
 
-                    self.scopes[original_scope_id]
-                        .definitions
-                        .insert(defn_name, definition_id.unwrap());
+                    let import = Import {
+                        location: None,
+                        glob: true,
+                        used: false,
+                    };
+                    self.import(
+                        original_scope_id,
+                        defn_name,
+                        definition_id.unwrap(),
+                        is_pub,
+                        import,
+                    );
                 }
             }
             syn::UseTree::Rename(ref use_rename) => {
-                let definition_id = self.process_defn(use_rename.ident.as_ref(), current_scope_id);
+                self.check_visible(
+                    use_rename.ident.as_ref(),
+                    current_scope_id,
+                    original_scope_id,
+                )?;
+                let definition_id =
+                    self.process_defn(use_rename.ident.as_ref(), current_scope_id)?;
 
                 if definition_id.is_none() {
-                    unimplemented!(
+                    bail!(
                         "Could not process the definition for {}",
                         use_rename.ident.as_ref()
                     );
                 }
 
-                self.scopes[original_scope_id]
-                    .definitions
-                    .insert(use_rename.rename.to_string(), definition_id.unwrap());
+                let import = Import {
+                    location: self.location(use_rename.rename.span()),
+                    glob: false,
+                    used: false,
+                };
+                self.import(
+                    original_scope_id,
+                    use_rename.rename.to_string(),
+                    definition_id.unwrap(),
+                    is_pub,
+                    import,
+                );
             }
         }
+        Ok(())
     }
 
     /// immediately process a string into bytecode, treating it as an expression
@@ -690,18 +2113,19 @@ impl BytecodeEngine {
         expr_str: &str,
         bytecode: &mut Vec<Bytecode>,
         var_stack: &mut VarStack,
-    ) -> Result<DefinitionId, String> {
+    ) -> Result<DefinitionId> {
         match syn::parse_str::<syn::Expr>(expr_str) {
             Ok(expr) => {
-                Ok(self.convert_expr_to_bytecode(
+                let result = self.convert_expr_to_bytecode(
                     &expr,
                     builtin_type::UNKNOWN,
                     bytecode,
                     0, // hardwire repl scope to 0
                     var_stack,
-                ))
+                );
+                self.take_errors(result)
             }
-            Err(e) => Err(e.to_string()),
+            Err(e) => Err(PeachError::Parse(e.to_string())),
         }
     }
 
@@ -713,82 +2137,326 @@ impl BytecodeEngine {
         expr_str: &str,
         bytecode: &mut Vec<Bytecode>,
         var_stack: &mut VarStack,
-    ) -> Result<(), String> {
+    ) -> Result<()> {
         match syn::parse_str::<syn::Stmt>(expr_str) {
             Ok(stmt) => {
                 match stmt {
-                    syn::Stmt::Item(item) => {
-                        self.prepare_item(item, 0);
-                        Ok(())
-                    }
+                    syn::Stmt::Item(item) => self.prepare_item(item, 0),
                     _ => {
-                        self.convert_stmt_to_bytecode(
+                        let result = self.convert_stmt_to_bytecode(
                             &stmt,
                             builtin_type::UNKNOWN,
                             bytecode,
                             0, // hardwire repl scope to 0
                             var_stack,
                         );
+                        self.take_errors(result)?;
                         Ok(())
                     }
                 }
             }
-            Err(e) => Err(e.to_string()),
+            Err(e) => Err(PeachError::Parse(e.to_string())),
         }
     }
 
+    /// Finds the instance of the generic function for arguments of the given types, making it if it's the first call
+    /// with them.  Returns the instance, with its return type.
     pub fn instantiate_generic_fn(
         &mut self,
         target_fn_id: DefinitionId,
-        scope_id: ScopeId,
         arg_tys: &Vec<DefinitionId>,
-    ) -> (DefinitionId, DefinitionId) {
+    ) -> Result<(DefinitionId, DefinitionId)> {
         // Simple unification
+        let (ty_params, param_tys, return_ty, var_tys) =
+            if let Definition::Fun(ref fun) = self.definitions[target_fn_id] {
+                (
+                    fun.ty_params.clone(),
+                    fun.params.iter().map(|param| param.ty).collect::<Vec<_>>(),
+                    fun.return_ty,
+                    fun.vars.iter().map(|var| var.ty).collect::<Vec<_>>(),
+                )
+            } else {
+                bail!("Instantiation of non-function")
+            };
 
-        if let Definition::Fun(ref fun) = self.definitions[target_fn_id] {
-            //let mut instantiated_param_tys = vec![];
-            let mut unification = vec![];
-            let mut return_ty = fun.return_ty;
-            let mut instance_name = "inst".to_string();
-
-            let mut arg_iter = arg_tys.iter();
-            for param in &fun.params {
-                let arg = arg_iter.next();
-                match self.definitions[param.ty] {
-                    Definition::TypeVariable => match arg {
-                        Some(arg) => {
-                            unification.push((param.ty, *arg));
-                            instance_name += &format!("${}", arg);
-                            if return_ty == param.ty {
-                                return_ty = *arg;
-                            }
-                        }
-                        None => {
-                            unimplemented!("Mismatched params and args");
-                        }
-                    },
-                    _ => {}
+        if param_tys.len() != arg_tys.len() {
+            bail!("Mismatched params and args");
+        }
+
+        let mut unification = vec![];
+        for (param_ty, arg_ty) in param_tys.iter().zip(arg_tys.iter()) {
+            self.unify_types(*param_ty, *arg_ty, &mut unification);
+        }
+
+        // Type parameters the arguments don't pin down are left as they are
+        let ty_args: Vec<DefinitionId> = ty_params
+            .iter()
+            .map(|ty_param| self.find_substituted_type(*ty_param, &unification))
+            .collect();
+        if let Some(definition_id) = self.fn_instances.get(&(target_fn_id, ty_args.clone())) {
+            let return_ty = self.find_substituted_type(return_ty, &unification);
+            return Ok((*definition_id, return_ty));
+        }
+
+        for (type_var, ty) in &unification {
+            if let Some(bounds) = self.type_var_bounds.get(type_var) {
+                for bound in bounds {
+                    if !self.implements(*ty, bound) {
+                        bail!(
+                            "The trait bound {}: {} is not satisfied",
+                            self.ty(*ty),
+                            bound
+                        );
+                    }
                 }
             }
-            instance_name += &format!("%{}", return_ty);
+        }
 
-            match self.scopes[scope_id].definitions.get(&instance_name) {
-                Some(def_id) => (*def_id, return_ty),
-                None => {
-                    self.definitions
-                        .push(Definition::InstantiatedFun(target_fn_id, unification));
+        let return_ty = self.substitute_type(return_ty, &unification)?;
 
-                    let instance_definition_id = self.definitions.len() - 1;
+        // Lay out the struct instances the function's body will use, so that backends can find them
+        for var_ty in var_tys {
+            self.substitute_type(var_ty, &unification)?;
+        }
 
-                    self.scopes[scope_id]
-                        .definitions
-                        .insert(instance_name, instance_definition_id);
+        self.definitions
+            .push(Definition::InstantiatedFun(target_fn_id, unification));
+        let instance_definition_id = self.definitions.len() - 1;
+        self.fn_instances
+            .insert((target_fn_id, ty_args), instance_definition_id);
+
+        Ok((instance_definition_id, return_ty))
+    }
 
-                    (instance_definition_id, return_ty)
+    /// The instances of generic functions made so far, as the generic function, the type arguments in the order of
+    /// its type parameters, and the instance.  Each is made once, the first time it's called with those types, and
+    /// found again by later calls.
+    pub fn fn_instances(&self) -> Vec<(DefinitionId, Vec<DefinitionId>, DefinitionId)> {
+        let mut instances: Vec<_> = self
+            .fn_instances
+            .iter()
+            .map(|((generic_id, ty_args), instance_id)| {
+                (*generic_id, ty_args.clone(), *instance_id)
+            })
+            .collect();
+        instances.sort_by_key(|(_, _, instance_id)| *instance_id);
+        instances
+    }
+
+    /// Finds the types the type variables in `param_ty` stand for, by matching it against `arg_ty`
+    fn unify_types(
+        &self,
+        param_ty: DefinitionId,
+        arg_ty: DefinitionId,
+        unification: &mut Vec<(DefinitionId, DefinitionId)>,
+    ) {
+        match (&self.definitions[param_ty], &self.definitions[arg_ty]) {
+            (Definition::TypeVariable, _)
+                if !unification
+                    .iter()
+                    .any(|(type_var, _)| *type_var == param_ty) =>
+            {
+                unification.push((param_ty, arg_ty));
+            }
+            (
+                Definition::Reference(param_referenced, _),
+                Definition::Reference(arg_referenced, _),
+            ) => self.unify_types(*param_referenced, *arg_referenced, unification),
+            (Definition::Struct(param_st), Definition::Struct(arg_st)) => {
+                if let (Some((param_generic, param_args)), Some((arg_generic, arg_args))) =
+                    (&param_st.instance_of, &arg_st.instance_of)
+                {
+                    if param_generic == arg_generic {
+                        for (param_arg, arg_arg) in param_args.iter().zip(arg_args.iter()) {
+                            self.unify_types(*param_arg, *arg_arg, unification);
+                        }
+                    }
                 }
             }
-        } else {
-            unimplemented!("Instantiation of non-function")
+            _ => {}
+        }
+    }
+
+    /// Replaces the type variables in `ty` using the given substitutions, instantiating any generic structs needed
+    pub(crate) fn substitute_type(
+        &mut self,
+        ty: DefinitionId,
+        substitutions: &[(DefinitionId, DefinitionId)],
+    ) -> Result<DefinitionId> {
+        match self.definitions[ty].clone() {
+            Definition::TypeVariable => match substitutions.iter().find(|x| x.0 == ty) {
+                Some(substitution) => Ok(substitution.1),
+                None => Ok(ty),
+            },
+            Definition::Reference(referenced, mutable) => {
+                let referenced = self.substitute_type(referenced, substitutions)?;
+                Ok(self.reference_ty(referenced, mutable))
+            }
+            Definition::Struct(Struct {
+                instance_of: Some((generic_id, ty_args)),
+                ..
+            }) => {
+                let ty_args = ty_args
+                    .iter()
+                    .map(|ty_arg| self.substitute_type(*ty_arg, substitutions))
+                    .collect::<Result<_>>()?;
+                self.instantiate_generic_struct(generic_id, ty_args)
+            }
+            _ => Ok(ty),
+        }
+    }
+
+    /// Like `substitute_type`, but only finds types which already exist.  Used by backends once processing is done.
+    pub(crate) fn find_substituted_type(
+        &self,
+        ty: DefinitionId,
+        substitutions: &[(DefinitionId, DefinitionId)],
+    ) -> DefinitionId {
+        match self.definitions[ty] {
+            Definition::TypeVariable => match substitutions.iter().find(|x| x.0 == ty) {
+                Some(substitution) => substitution.1,
+                None => ty,
+            },
+            Definition::Reference(referenced, mutable) => {
+                let referenced = self.find_substituted_type(referenced, substitutions);
+                self.reference_tys[&(referenced, mutable)]
+            }
+            Definition::Struct(Struct {
+                instance_of: Some((generic_id, ref ty_args)),
+                ..
+            }) => {
+                let ty_args: Vec<DefinitionId> = ty_args
+                    .iter()
+                    .map(|ty_arg| self.find_substituted_type(*ty_arg, substitutions))
+                    .collect();
+                self.struct_instances[&(generic_id, ty_args)]
+            }
+            _ => ty,
         }
     }
+
+    /// Returns true if the type mentions a type variable, and so can't be laid out until it's instantiated
+    pub(crate) fn contains_type_variable(&self, ty: DefinitionId) -> bool {
+        match self.definitions[ty] {
+            Definition::TypeVariable => true,
+            Definition::Reference(referenced, _) => self.contains_type_variable(referenced),
+            Definition::Struct(Struct {
+                instance_of: Some((_, ref ty_args)),
+                ..
+            }) => ty_args
+                .iter()
+                .any(|ty_arg| self.contains_type_variable(*ty_arg)),
+            _ => false,
+        }
+    }
+
+    /// Lays out the generic struct for the given type arguments, or finds the existing instance
+    pub(crate) fn instantiate_generic_struct(
+        &mut self,
+        generic_id: DefinitionId,
+        ty_args: Vec<DefinitionId>,
+    ) -> Result<DefinitionId> {
+        if let Some(definition_id) = self.struct_instances.get(&(generic_id, ty_args.clone())) {
+            return Ok(*definition_id);
+        }
+
+        let (item_struct, scope_id) = match self.definitions[generic_id] {
+            Definition::GenericStruct(ref item_struct, scope_id) => (item_struct.clone(), scope_id),
+            _ => bail!("Instantiation of non-generic struct"),
+        };
+
+        let ty_params: Vec<String> = item_struct
+            .generics
+            .params
+            .iter()
+            .filter_map(|param| match param {
+                GenericParam::Type(type_param) => Some(type_param.ident.to_string()),
+                _ => None,
+            })
+            .collect();
+        if ty_params.len() != ty_args.len() {
+            bail!(
+                "Wrong number of type arguments for {}: expected {}, found {}",
+                item_struct.ident,
+                ty_params.len(),
+                ty_args.len()
+            );
+        }
+
+        // The field types are resolved in a scope where the type parameters name the type arguments
+        self.scopes.push(Scope::new(Some(scope_id), false));
+        let instance_scope_id = self.scopes.len() - 1;
+        for (ty_param, ty_arg) in ty_params.into_iter().zip(ty_args.iter()) {
+            self.scopes[instance_scope_id]
+                .definitions
+                .insert(ty_param, *ty_arg);
+        }
+
+        let location = self.location(item_struct.ident.span());
+        let mut fields: Vec<(String, DefinitionId)> = vec![];
+        for field in &item_struct.fields {
+            if !self.filter_attrs(&field.attrs)? {
+                continue;
+            }
+            let field_ty = self
+                .resolve_type(&field.ty, instance_scope_id)
+                .map_err(|e| e.at(location.clone()))?;
+            let field_name = match field.ident {
+                Some(ref ident) => ident.to_string(),
+                None => bail_at!(
+                    self.location(field.span()),
+                    "Tuple structs aren't supported"
+                ),
+            };
+            fields.push((field_name, field_ty));
+        }
+        fields.sort();
+
+        // A derived Copy only applies to instances whose fields are all Copy
+        let copy = derives(&item_struct.attrs, "Copy")
+            && fields.iter().all(|(_, field_ty)| self.is_copy(*field_ty));
+
+        self.definitions.push(Definition::Struct(Struct {
+            fields,
+            copy,
+            instance_of: Some((generic_id, ty_args.clone())),
+            location,
+        }));
+        let definition_id = self.definitions.len() - 1;
+        self.struct_instances
+            .insert((generic_id, ty_args), definition_id);
+
+        Ok(definition_id)
+    }
+}
+
+/// Reads a source file
+fn read_source(path: &::std::path::Path) -> Result<String> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut src = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut src))
+        .map_err(|e| PeachError::Io(format!("{}: {}", path.display(), e)))?;
+    Ok(src)
+}
+
+
+/// Returns true if the visibility is some form of `pub`
+fn is_pub(vis: &Visibility) -> bool {
+    !matches!(vis, Visibility::Inherited)
+}
+
+/// Returns true if the attributes include a `#[derive(...)]` of the given trait
+fn derives(attrs: &[Attribute], trait_name: &str) -> bool {
+    attrs.iter().any(|attr| match attr.interpret_meta() {
+        Some(Meta::List(ref list)) if list.ident == "derive" => {
+            list.nested.iter().any(|nested| match nested {
+                NestedMeta::Meta(Meta::Word(ident)) => ident == trait_name,
+                _ => false,
+            })
+        }
+        _ => false,
+    })
 }
@@ -0,0 +1,81 @@
+//! An optional pass which fuses common sequences of instructions into superinstructions.  A superinstruction
+//! evaluates the same way as the sequence it replaces, but is only dispatched once, which matters most in hot loops.
+//! The pass runs after functions have been converted and checked, as the checks only understand the instructions
+//! conversion produces.
+use bytecode::engine::{Bytecode, BytecodeEngine, Definition, Fun};
+use bytecode::labels::{Labeled, LabeledCode};
+use std::convert::TryFrom;
+use std::mem;
+
+impl BytecodeEngine {
+    /// Fuses common instruction sequences in each function converted so far.  Functions converted afterwards are
+    /// left as they are until this is called again.  Fuel, traces, profiles, and offset breakpoints count a
+    /// superinstruction as one instruction, at its fused offset.
+    pub fn fuse_instructions(&mut self) {
+        for definition in &mut self.definitions {
+            if let Definition::Fun(ref mut fun) = definition {
+                fuse_fun(fun);
+            }
+        }
+    }
+}
+
+/// The superinstruction for the sequence at the start of `codes`, if there is one, with the number of instructions it
+/// replaces
+fn superinstruction(codes: &[Bytecode]) -> Option<(Bytecode, usize)> {
+    match codes {
+        // Literals whose type was never pinned down are i32s
+        [Bytecode::Var(var_id), Bytecode::PushUnknownInt(val), Bytecode::Add, ..] => {
+            i32::try_from(*val)
+                .ok()
+                .map(|val| (Bytecode::AddVarConst(*var_id, val), 3))
+        }
+        [Bytecode::Var(var_id), Bytecode::PushUnknownInt(val), Bytecode::Sub, ..] => {
+            i32::try_from(*val)
+                .ok()
+                .map(|val| (Bytecode::SubVarConst(*var_id, val), 3))
+        }
+        [Bytecode::Var(var_id), Bytecode::PushUnknownInt(val), Bytecode::Lt, ..] => {
+            i32::try_from(*val)
+                .ok()
+                .map(|val| (Bytecode::LtVarConst(*var_id, val), 3))
+        }
+        [Bytecode::LValueVar(var_id), Bytecode::Assign, ..] => {
+            Some((Bytecode::AssignVar(*var_id), 2))
+        }
+        _ => None,
+    }
+}
+
+fn fuse_fun(fun: &mut Fun) {
+    let mut labeled = Labeled::from_fun(fun);
+    let code = mem::take(&mut labeled.code);
+
+    // Instructions which are jumped to, or which start or end a statement, have a label before them, so they're never
+    // fused into the instructions before them
+    let mut idx = 0;
+    while idx < code.len() {
+        let run: Vec<Bytecode> = code[idx..]
+            .iter()
+            .map_while(|x| match x {
+                LabeledCode::Code(code) => Some(code.clone()),
+                _ => None,
+            })
+            .take(3)
+            .collect();
+        match superinstruction(&run) {
+            Some((code, count)) => {
+                labeled.push(code);
+                idx += count;
+            }
+            None => {
+                labeled.code.push(code[idx].clone());
+                idx += 1;
+            }
+        }
+    }
+
+    if labeled.code.len() < code.len() {
+        labeled.apply(fun);
+    }
+}
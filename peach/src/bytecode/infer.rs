@@ -0,0 +1,507 @@
+//! Inference of the types of integer literals.  An unsuffixed literal is converted before what it's used with is
+//! known, so it's pushed as an `{unknown int}`.  Once a function is converted, each of its literals, and each variable
+//! that only ever held literals, gets a type variable.  These are unified with the types of the values they meet: the
+//! other operand of an operator, the parameter or field they're passed to, the variable they're stored in, and what
+//! the function returns.  Literals whose type variable ends up with an integer type are then pushed as that type.
+//! Those that meet no integer type stay `{unknown int}`.
+use bytecode::engine::{Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use bytecode::typecheck::{builtin_type, is_integer};
+use error::Result;
+use std::convert::TryFrom;
+
+/// The type of a value on the stack while literals are inferred
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Known(DefinitionId),
+    /// An integer whose type is the type variable
+    Var(usize),
+    /// A reference to an integer whose type is the type variable
+    Ref(usize),
+}
+
+/// The type variables, as a union-find of the ones that have been unified with each other
+#[derive(Default)]
+struct TypeVars {
+    parents: Vec<usize>,
+    /// The integer type each class has been unified with, kept at its root
+    types: Vec<Option<DefinitionId>>,
+    /// The index of a `Neg` of each class, kept at its root.  Negated integers can't be unsigned.
+    negated: Vec<Option<usize>>,
+}
+
+impl TypeVars {
+    fn new_var(&mut self) -> usize {
+        self.parents.push(self.parents.len());
+        self.types.push(None);
+        self.negated.push(None);
+        self.parents.len() - 1
+    }
+
+    fn find(&mut self, var: usize) -> usize {
+        let parent = self.parents[var];
+        if parent == var {
+            return var;
+        }
+        let root = self.find(parent);
+        self.parents[var] = root;
+        root
+    }
+
+    fn ty(&mut self, var: usize) -> Option<DefinitionId> {
+        let root = self.find(var);
+        self.types[root]
+    }
+}
+
+/// The bookkeeping of an if or while which hasn't ended yet
+struct Frame {
+    /// The height of the stack at its start
+    height: usize,
+    /// The value of the then branch of an if, once its else is reached
+    then_value: Option<Slot>,
+    /// The indices of the instructions which carry the type of the if's value
+    type_sites: Vec<usize>,
+}
+
+/// The body of a function, while its literals are inferred
+struct Inference<'a> {
+    engine: &'a BytecodeEngine,
+    definition_id: DefinitionId,
+    fun: &'a Fun,
+    vars: TypeVars,
+    stack: Vec<Slot>,
+}
+
+impl<'a> Inference<'a> {
+    /// A slot for a value of the given type, with a new type variable if the type is `{unknown int}`
+    fn known(&mut self, ty: DefinitionId) -> Slot {
+        match ty {
+            builtin_type::UNKNOWN_INT => Slot::Var(self.vars.new_var()),
+            _ => Slot::Known(ty),
+        }
+    }
+
+    fn pop(&mut self) -> Slot {
+        self.stack.pop().unwrap_or(Slot::Known(builtin_type::ERROR))
+    }
+
+    /// Unifies the types of two values, which meet at the instruction at `idx`
+    fn unify(&mut self, lhs: Slot, rhs: Slot, idx: usize) -> Result<()> {
+        match (lhs, rhs) {
+            (Slot::Var(lhs), Slot::Var(rhs)) | (Slot::Ref(lhs), Slot::Ref(rhs)) => {
+                let lhs = self.vars.find(lhs);
+                let rhs = self.vars.find(rhs);
+                if lhs != rhs {
+                    if let Some(ty) = self.vars.types[rhs] {
+                        self.bind(lhs, ty, idx)?;
+                    }
+                    self.vars.negated[lhs] = self.vars.negated[lhs].or(self.vars.negated[rhs]);
+                    self.vars.parents[rhs] = lhs;
+                }
+                Ok(())
+            }
+            (Slot::Var(var), Slot::Known(ty)) | (Slot::Known(ty), Slot::Var(var)) => {
+                self.bind(var, ty, idx)
+            }
+            (Slot::Ref(var), Slot::Known(ty)) | (Slot::Known(ty), Slot::Ref(var)) => {
+                match self.engine.definitions[ty] {
+                    Definition::Reference(referenced, _) => self.bind(var, referenced, idx),
+                    _ => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Gives the class of the type variable an integer type.  Other types are left to the typechecker to report.
+    fn bind(&mut self, var: usize, ty: DefinitionId, idx: usize) -> Result<()> {
+        if !is_integer(ty) || ty == builtin_type::UNKNOWN_INT {
+            return Ok(());
+        }
+        let root = self.vars.find(var);
+        match self.vars.types[root] {
+            Some(bound) if bound != ty => bail_at!(
+                self.fun.location_of(idx),
+                "Mismatched types: expected {}, found {}",
+                self.engine.ty(bound),
+                self.engine.ty(ty)
+            ),
+            _ => {
+                self.vars.types[root] = Some(ty);
+                Ok(())
+            }
+        }
+    }
+
+    /// The parameter types and return type of what the definition is called with
+    fn signature(&self, definition_id: DefinitionId) -> Option<(Vec<DefinitionId>, DefinitionId)> {
+        let params = |fun: &Fun| fun.params.iter().map(|param| param.ty).collect::<Vec<_>>();
+        match self.engine.definitions[definition_id] {
+            Definition::Fun(ref fun) => Some((params(fun), fun.return_ty)),
+            Definition::Struct(ref st) => {
+                Some((st.fields.iter().map(|(_, ty)| *ty).collect(), definition_id))
+            }
+            // A recursive call, as the function being inferred isn't stored yet
+            Definition::LazyFn(_) if definition_id == self.definition_id => {
+                Some((params(self.fun), self.fun.return_ty))
+            }
+            Definition::InstantiatedFun(generic_id, ref unification) => {
+                match self.engine.definitions[generic_id] {
+                    Definition::Fun(ref fun) => {
+                        // Only types which are a type argument themselves are substituted
+                        let substitute = |ty: DefinitionId| match unification
+                            .iter()
+                            .find(|(type_var, _)| *type_var == ty)
+                        {
+                            Some((_, ty)) => *ty,
+                            None if self.engine.contains_type_variable(ty) => builtin_type::ERROR,
+                            None => ty,
+                        };
+                        Some((
+                            fun.params
+                                .iter()
+                                .map(|param| substitute(param.ty))
+                                .collect(),
+                            substitute(fun.return_ty),
+                        ))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Passes the arguments on top of the stack to parameters of the given types
+    fn pass_args(&mut self, param_tys: &[DefinitionId], idx: usize) -> Result<()> {
+        let args = self
+            .stack
+            .split_off(self.stack.len().saturating_sub(param_tys.len()));
+        for (arg, param_ty) in args.into_iter().zip(param_tys) {
+            let param = self.known(*param_ty);
+            self.unify(param, arg, idx)?;
+        }
+        Ok(())
+    }
+}
+
+impl BytecodeEngine {
+    /// Infers the types of the function's integer literals, and of the variables that hold them, and rewrites the
+    /// literals to push the types they were given.  Fails if a literal is used as two different integer types, or is
+    /// negated and used as an unsigned one.
+    pub(crate) fn infer_literals(&self, definition_id: DefinitionId, fun: &mut Fun) -> Result<()> {
+        let mut inference = Inference {
+            engine: self,
+            definition_id,
+            fun,
+            vars: TypeVars::default(),
+            stack: vec![],
+        };
+        // Extern functions have parameters without variables
+        let var_count = fun
+            .params
+            .iter()
+            .map(|param| param.var_id + 1)
+            .fold(fun.vars.len(), usize::max);
+        let var_slots: Vec<Slot> = (0..var_count)
+            .map(|var_id| match fun.vars.get(var_id) {
+                Some(var) => inference.known(var.ty),
+                None => Slot::Known(builtin_type::ERROR),
+            })
+            .collect();
+
+        // The literals, and the instructions which carry the type of a value that may be a literal
+        let mut literals = vec![];
+        let mut type_sites = vec![];
+        let mut frames: Vec<Frame> = vec![];
+
+        for (idx, code) in fun.bytecode.iter().enumerate() {
+            match code {
+                Bytecode::PushUnknownInt(_) => {
+                    let var = inference.vars.new_var();
+                    literals.push((idx, var));
+                    inference.stack.push(Slot::Var(var));
+                }
+                Bytecode::PushU64(_)
+                | Bytecode::ReadInput
+                | Bytecode::ReadClock
+                | Bytecode::ArgCount
+                | Bytecode::Env(_)
+                | Bytecode::ReadFile(_) => inference.stack.push(Slot::Known(builtin_type::U64)),
+                Bytecode::PushU32(_) => inference.stack.push(Slot::Known(builtin_type::U32)),
+                Bytecode::PushI64(_) => inference.stack.push(Slot::Known(builtin_type::I64)),
+                Bytecode::PushI32(_) => inference.stack.push(Slot::Known(builtin_type::I32)),
+                Bytecode::PushBool(_) => inference.stack.push(Slot::Known(builtin_type::BOOL)),
+                Bytecode::PushRawPtr(_) => {
+                    inference.stack.push(Slot::Known(builtin_type::VOID_PTR))
+                }
+                Bytecode::RandomBelow | Bytecode::Arg => {
+                    let value = inference.pop();
+                    inference.unify(Slot::Known(builtin_type::U64), value, idx)?;
+                    inference.stack.push(Slot::Known(builtin_type::U64));
+                }
+                Bytecode::WriteFile(_) => {
+                    let value = inference.pop();
+                    inference.unify(Slot::Known(builtin_type::U64), value, idx)?;
+                }
+                Bytecode::Exit => {
+                    let value = inference.pop();
+                    inference.unify(Slot::Known(builtin_type::I32), value, idx)?;
+                }
+                Bytecode::As(ty) => {
+                    let value = inference.pop();
+                    inference.unify(Slot::Known(*ty), value, idx)?;
+                    let slot = inference.known(*ty);
+                    inference.stack.push(slot);
+                }
+                Bytecode::Add | Bytecode::Sub | Bytecode::Mul | Bytecode::Div => {
+                    let rhs = inference.pop();
+                    let lhs = inference.pop();
+                    inference.unify(lhs, rhs, idx)?;
+                    inference.stack.push(match lhs {
+                        Slot::Known(_) => rhs,
+                        _ => lhs,
+                    });
+                }
+                Bytecode::Lt => {
+                    let rhs = inference.pop();
+                    let lhs = inference.pop();
+                    inference.unify(lhs, rhs, idx)?;
+                    inference.stack.push(Slot::Known(builtin_type::BOOL));
+                }
+                Bytecode::Neg => {
+                    let value = inference.pop();
+                    if let Slot::Var(var) = value {
+                        let root = inference.vars.find(var);
+                        inference.vars.negated[root].get_or_insert(idx);
+                    }
+                    inference.stack.push(value);
+                }
+                Bytecode::AddVarConst(var_id, _) | Bytecode::SubVarConst(var_id, _) => {
+                    inference.stack.push(var_slots[*var_id])
+                }
+                Bytecode::LtVarConst(_, _) => inference.stack.push(Slot::Known(builtin_type::BOOL)),
+                Bytecode::Var(var_id) | Bytecode::Move(var_id) | Bytecode::LValueVar(var_id) => {
+                    inference.stack.push(var_slots[*var_id])
+                }
+                Bytecode::VarDecl(var_id) | Bytecode::AssignVar(var_id) => {
+                    let value = inference.pop();
+                    inference.unify(var_slots[*var_id], value, idx)?;
+                }
+                Bytecode::Assign => {
+                    let place = inference.pop();
+                    let value = inference.pop();
+                    inference.unify(place, value, idx)?;
+                }
+                Bytecode::Dot(field) | Bytecode::LValueDot(field) => {
+                    let field_ty = match inference.pop() {
+                        Slot::Known(ty) => match self.definitions[ty] {
+                            Definition::Struct(ref st) => st
+                                .fields
+                                .iter()
+                                .find(|(name, _)| name == field)
+                                .map(|(_, ty)| *ty),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let slot = inference.known(field_ty.unwrap_or(builtin_type::ERROR));
+                    inference.stack.push(slot);
+                }
+                Bytecode::Deref | Bytecode::LValueDeref => {
+                    let slot = match inference.pop() {
+                        Slot::Ref(var) => Slot::Var(var),
+                        Slot::Known(ty) => match self.definitions[ty] {
+                            Definition::Reference(referenced, _) => inference.known(referenced),
+                            _ => Slot::Known(builtin_type::ERROR),
+                        },
+                        Slot::Var(_) => Slot::Known(builtin_type::ERROR),
+                    };
+                    inference.stack.push(slot);
+                }
+                Bytecode::Borrow(mutable) => {
+                    let slot = match inference.pop() {
+                        Slot::Var(var) => Slot::Ref(var),
+                        Slot::Known(ty) => match self.reference_tys.get(&(ty, *mutable)) {
+                            Some(reference) => Slot::Known(*reference),
+                            None => Slot::Known(builtin_type::ERROR),
+                        },
+                        Slot::Ref(_) => Slot::Known(builtin_type::ERROR),
+                    };
+                    inference.stack.push(slot);
+                }
+                Bytecode::DebugPrint(ty) => {
+                    if let Slot::Var(var) = inference.pop() {
+                        if *ty == builtin_type::UNKNOWN_INT {
+                            type_sites.push((vec![idx], var));
+                        }
+                    }
+                }
+                Bytecode::ReturnLastStackValue => {
+                    let value = inference.pop();
+                    let return_slot = inference.known(fun.return_ty);
+                    inference.unify(return_slot, value, idx)?;
+                }
+                Bytecode::Call(target) => match inference.signature(*target) {
+                    Some((param_tys, return_ty)) => {
+                        inference.pass_args(&param_tys, idx)?;
+                        let slot = inference.known(return_ty);
+                        inference.stack.push(slot);
+                    }
+                    // Without knowing how many arguments there are, nothing under them can be followed
+                    None => {
+                        inference.stack.clear();
+                        inference.stack.push(Slot::Known(builtin_type::ERROR));
+                    }
+                },
+                Bytecode::CallNative(native_id) => {
+                    inference.pass_args(&self.natives[*native_id].param_tys, idx)?;
+                    let return_ty = self.definitions.iter().find_map(|x| match x {
+                        Definition::Fun(fun) if fun.native_id == Some(*native_id) => {
+                            Some(fun.return_ty)
+                        }
+                        _ => None,
+                    });
+                    let slot = inference.known(return_ty.unwrap_or(builtin_type::ERROR));
+                    inference.stack.push(slot);
+                }
+                Bytecode::If(..) => {
+                    inference.pop();
+                    frames.push(Frame {
+                        height: inference.stack.len(),
+                        then_value: None,
+                        type_sites: vec![idx],
+                    });
+                }
+                Bytecode::Else(..) => {
+                    if let Some(frame) = frames.last_mut() {
+                        if inference.stack.len() > frame.height {
+                            frame.then_value = inference.stack.last().cloned();
+                        }
+                        frame.type_sites.push(idx);
+                        inference.stack.truncate(frame.height);
+                    }
+                }
+                Bytecode::EndIf(ty) => {
+                    let frame = match frames.pop() {
+                        Some(frame) => frame,
+                        None => continue,
+                    };
+                    let else_value = if inference.stack.len() > frame.height {
+                        inference.stack.last().cloned()
+                    } else {
+                        None
+                    };
+                    inference.stack.truncate(frame.height);
+                    if *ty == builtin_type::VOID {
+                        continue;
+                    }
+                    let value = match (frame.then_value, else_value) {
+                        (Some(then_value), Some(else_value)) => {
+                            inference.unify(then_value, else_value, idx)?;
+                            then_value
+                        }
+                        (Some(value), None) | (None, Some(value)) => value,
+                        (None, None) => inference.known(*ty),
+                    };
+                    if let (Slot::Var(var), builtin_type::UNKNOWN_INT) = (value, *ty) {
+                        let mut sites = frame.type_sites;
+                        sites.push(idx);
+                        type_sites.push((sites, var));
+                    }
+                    inference.stack.push(value);
+                }
+                Bytecode::BeginWhile => frames.push(Frame {
+                    height: inference.stack.len(),
+                    then_value: None,
+                    type_sites: vec![],
+                }),
+                Bytecode::WhileCond(_) => {
+                    inference.pop();
+                }
+                Bytecode::EndWhile(_) => {
+                    if let Some(frame) = frames.pop() {
+                        inference.stack.truncate(frame.height);
+                    }
+                }
+                Bytecode::VarDeclUninit(_)
+                | Bytecode::DropVars(_)
+                | Bytecode::ReturnVoid
+                | Bytecode::Panic(_) => {}
+            }
+        }
+
+        let mut vars = inference.vars;
+        for root in 0..vars.parents.len() {
+            if vars.parents[root] != root {
+                continue;
+            }
+            if let (Some(neg_idx), Some(ty)) = (vars.negated[root], vars.types[root]) {
+                if ty == builtin_type::U64 || ty == builtin_type::U32 {
+                    bail_at!(
+                        fun.location_of(neg_idx),
+                        "Can't negate value of type {}",
+                        self.ty(ty)
+                    );
+                }
+            }
+        }
+
+        let literal_of = |val: i128, ty: Option<DefinitionId>| match ty {
+            Some(builtin_type::U64) => u64::try_from(val).ok().map(Bytecode::PushU64),
+            Some(builtin_type::U32) => u32::try_from(val).ok().map(Bytecode::PushU32),
+            Some(builtin_type::I64) => i64::try_from(val).ok().map(Bytecode::PushI64),
+            Some(_) => i32::try_from(val).ok().map(Bytecode::PushI32),
+            // Literals that meet no integer type are i32s, as they are in Rust
+            None => i32::try_from(val)
+                .ok()
+                .map(|_| Bytecode::PushUnknownInt(val)),
+        };
+        for (idx, var) in literals {
+            if let Bytecode::PushUnknownInt(val) = fun.bytecode[idx] {
+                let ty = vars.ty(var);
+                // The minimum of a signed type is one past its maximum until it's negated, so a literal that's
+                // negated right away is also in range if its negation is
+                let negated = match fun.bytecode.get(idx + 1) {
+                    Some(Bytecode::Neg) => literal_of(-val, ty),
+                    _ => None,
+                };
+                match (literal_of(val, ty), negated) {
+                    (Some(literal), _) => fun.bytecode[idx] = literal,
+                    // The literal is pushed already negated, and the negation becomes a conversion to the type it
+                    // already has, so the instructions keep their offsets
+                    (None, Some(literal)) => {
+                        fun.bytecode[idx] = literal;
+                        fun.bytecode[idx + 1] = Bytecode::As(ty.unwrap_or(builtin_type::I32));
+                    }
+                    (None, None) => bail_at!(
+                        fun.location_of(idx),
+                        "Literal out of range for {}",
+                        self.ty(ty.unwrap_or(builtin_type::I32))
+                    ),
+                }
+            }
+        }
+        for (sites, var) in type_sites {
+            if let Some(ty) = vars.ty(var) {
+                for idx in sites {
+                    fun.bytecode[idx] = match fun.bytecode[idx] {
+                        Bytecode::DebugPrint(_) => Bytecode::DebugPrint(ty),
+                        Bytecode::If(offset, _) => Bytecode::If(offset, ty),
+                        Bytecode::Else(offset, _) => Bytecode::Else(offset, ty),
+                        Bytecode::EndIf(_) => Bytecode::EndIf(ty),
+                        ref code => code.clone(),
+                    };
+                }
+            }
+        }
+        for (var_id, slot) in var_slots.iter().enumerate() {
+            if let Slot::Var(var) = slot {
+                if let Some(ty) = vars.ty(*var) {
+                    fun.vars[var_id].ty = ty;
+                }
+            }
+        }
+        Ok(())
+    }
+}
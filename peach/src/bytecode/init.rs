@@ -0,0 +1,85 @@
+use bytecode::engine::{Bytecode, BytecodeEngine, Fun};
+use error::Result;
+use std::collections::HashSet;
+
+impl BytecodeEngine {
+    /// Checks that variables declared without a value are assigned one before they're used.
+    ///
+    /// Like `check_moves`, this is conservative and flow-sensitive: a variable that is left unassigned along any path
+    /// into an instruction is considered possibly uninitialized there, so an if that only assigns it in one arm, or a
+    /// while that assigns it in its body, doesn't count.
+    pub(crate) fn check_init(&self, fun: &Fun) -> Result<()> {
+        if fun.bytecode.is_empty() {
+            return Ok(());
+        }
+
+        let bytecode = &fun.bytecode;
+
+        // The set of variables that may be uninitialized on entry to each instruction
+        let mut uninit_at: Vec<Option<HashSet<usize>>> = vec![None; bytecode.len()];
+        uninit_at[0] = Some(HashSet::new());
+        let mut worklist = vec![0];
+
+        while let Some(idx) = worklist.pop() {
+            let mut uninit = uninit_at[idx].clone().unwrap();
+
+            match bytecode[idx] {
+                Bytecode::Var(var_id)
+                | Bytecode::Move(var_id)
+                | Bytecode::AddVarConst(var_id, _)
+                    if uninit.contains(&var_id) =>
+                {
+                    bail_at!(
+                        fun.location_of(idx),
+                        "Use of possibly-uninitialized variable: {}",
+                        fun.vars[var_id].ident
+                    );
+                }
+                Bytecode::LValueVar(var_id) => match bytecode.get(idx + 1) {
+                    Some(Bytecode::Assign) => {
+                        uninit.remove(&var_id);
+                    }
+                    _ => {
+                        // Assigning to a field, or borrowing, needs the rest of the value to be there
+                        if uninit.contains(&var_id) {
+                            bail_at!(
+                                fun.location_of(idx),
+                                "Use of possibly-uninitialized variable: {}",
+                                fun.vars[var_id].ident
+                            );
+                        }
+                    }
+                },
+                Bytecode::VarDecl(var_id) => {
+                    uninit.remove(&var_id);
+                }
+                Bytecode::VarDeclUninit(var_id) => {
+                    uninit.insert(var_id);
+                }
+                _ => {}
+            }
+
+            for successor in fun.successors(idx) {
+                if successor >= bytecode.len() {
+                    continue;
+                }
+                let changed = match uninit_at[successor] {
+                    None => {
+                        uninit_at[successor] = Some(uninit.clone());
+                        true
+                    }
+                    Some(ref mut existing) => {
+                        let before = existing.len();
+                        existing.extend(uninit.iter().cloned());
+                        existing.len() != before
+                    }
+                };
+                if changed {
+                    worklist.push(successor);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! Bytecode whose jumps go to labels rather than relative offsets.  An offset counts the instructions between a jump
+//! and where it lands, so adding or removing one in between breaks it.  A pass that changes the instructions works
+//! on the labeled form instead, and the offsets are only computed when it's finished.
+use bytecode::engine::{Bytecode, Fun};
+
+/// A place in labeled bytecode that jumps and statements refer to
+pub type Label = usize;
+
+/// One entry of labeled bytecode
+#[derive(Debug, Clone)]
+pub enum LabeledCode {
+    /// An instruction which doesn't jump
+    Code(Bytecode),
+    /// A jump to the label.  The offset the instruction has is ignored, and recomputed by `Labeled::finish`.
+    Jump(Bytecode, Label),
+    /// Places the label before the instruction which follows it
+    Place(Label),
+}
+
+/// Bytecode with labels in place of jump offsets
+#[derive(Debug, Clone, Default)]
+pub struct Labeled {
+    pub code: Vec<LabeledCode>,
+    /// The labels placed at the start and end of each of the function's statements, in the order of its
+    /// `stmt_locations`, when it was built with `Labeled::from_fun`
+    pub stmts: Vec<(Label, Label)>,
+    label_count: usize,
+}
+
+/// Where the instruction at `idx` lands when it jumps, if it's a jump
+pub(crate) fn jump_target(idx: usize, code: &Bytecode) -> Option<usize> {
+    match code {
+        Bytecode::If(offset, _) | Bytecode::Else(offset, _) => Some(idx + offset),
+        // Eval skips past the EndWhile when the condition is false
+        Bytecode::WhileCond(offset) => Some(idx + offset + 1),
+        Bytecode::EndWhile(offset) => Some(idx - offset),
+        _ => None,
+    }
+}
+
+/// The jump at `from` with its offset set so that it lands at `to`, or `None` if it can't jump in that direction
+fn with_target(code: &Bytecode, from: usize, to: usize) -> Option<Bytecode> {
+    match code {
+        Bytecode::If(_, ty) if to > from => Some(Bytecode::If(to - from, *ty)),
+        Bytecode::Else(_, ty) if to > from => Some(Bytecode::Else(to - from, *ty)),
+        Bytecode::WhileCond(_) if to > from => Some(Bytecode::WhileCond(to - from - 1)),
+        Bytecode::EndWhile(_) if to <= from => Some(Bytecode::EndWhile(from - to)),
+        _ => None,
+    }
+}
+
+impl Labeled {
+    pub fn new() -> Labeled {
+        Labeled::default()
+    }
+
+    /// Labels the function's bytecode.  Every instruction that's jumped to, and the start and end of every statement,
+    /// gets a label.
+    pub fn from_fun(fun: &Fun) -> Labeled {
+        let len = fun.bytecode.len();
+        let mut labeled = Labeled::new();
+        let mut label_at: Vec<Option<Label>> = vec![None; len + 1];
+        let mut label = |labeled: &mut Labeled, idx: usize| {
+            *label_at[idx].get_or_insert_with(|| labeled.new_label())
+        };
+
+        let targets: Vec<Option<Label>> = fun
+            .bytecode
+            .iter()
+            .enumerate()
+            .map(|(idx, code)| jump_target(idx, code).map(|target| label(&mut labeled, target)))
+            .collect();
+        labeled.stmts = fun
+            .stmt_locations
+            .iter()
+            .map(|(range, _)| {
+                (
+                    label(&mut labeled, range.start),
+                    label(&mut labeled, range.end),
+                )
+            })
+            .collect();
+
+        for (idx, code) in fun.bytecode.iter().enumerate() {
+            if let Some(label) = label_at[idx] {
+                labeled.place(label);
+            }
+            match targets[idx] {
+                Some(target) => labeled.jump(code.clone(), target),
+                None => labeled.push(code.clone()),
+            }
+        }
+        if let Some(label) = label_at[len] {
+            labeled.place(label);
+        }
+        labeled
+    }
+
+    /// Makes a label which isn't placed yet
+    pub fn new_label(&mut self) -> Label {
+        self.label_count += 1;
+        self.label_count - 1
+    }
+
+    pub fn push(&mut self, code: Bytecode) {
+        self.code.push(LabeledCode::Code(code));
+    }
+
+    /// Adds a jump to the label, which can be placed before or after it
+    pub fn jump(&mut self, code: Bytecode, label: Label) {
+        self.code.push(LabeledCode::Jump(code, label));
+    }
+
+    /// Places the label before the next instruction added
+    pub fn place(&mut self, label: Label) {
+        self.code.push(LabeledCode::Place(label));
+    }
+
+    /// Computes the offsets of the jumps.  Returns the bytecode, with the index each label was placed at.
+    ///
+    /// Panics if a label is jumped to but never placed, or is on the wrong side of a jump for its instruction: ifs,
+    /// elses, and while conditions only jump forward, and the ends of whiles only jump back.  Either is a bug in the
+    /// pass that built the labeled bytecode.
+    pub fn finish(&self) -> (Vec<Bytecode>, Vec<Option<usize>>) {
+        let mut positions = vec![None; self.label_count];
+        let mut bytecode = vec![];
+        for code in &self.code {
+            match code {
+                LabeledCode::Code(code) | LabeledCode::Jump(code, _) => bytecode.push(code.clone()),
+                LabeledCode::Place(label) => positions[*label] = Some(bytecode.len()),
+            }
+        }
+
+        // Where each jump ends up is counted again, as labels take no space
+        let mut from = 0;
+        for code in &self.code {
+            if let LabeledCode::Jump(code, label) = code {
+                let to = positions[*label].unwrap_or_else(|| {
+                    panic!("{:?} jumps to label {}, which isn't placed", code, label)
+                });
+                bytecode[from] = with_target(code, from, to)
+                    .unwrap_or_else(|| panic!("{:?} at {} can't jump to {}", code, from, to));
+            }
+            if !matches!(code, LabeledCode::Place(_)) {
+                from += 1;
+            }
+        }
+        (bytecode, positions)
+    }
+
+    /// Replaces the function's bytecode with the labeled bytecode, finished.  Statements start and end where their
+    /// labels were placed, so this has to have been built from the function with `Labeled::from_fun`.
+    pub fn apply(&self, fun: &mut Fun) {
+        let (bytecode, positions) = self.finish();
+        for ((range, _), (start, end)) in fun.stmt_locations.iter_mut().zip(&self.stmts) {
+            *range = positions[*start].unwrap()..positions[*end].unwrap();
+        }
+        fun.bytecode = bytecode;
+    }
+}
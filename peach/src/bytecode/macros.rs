@@ -0,0 +1,479 @@
+//! Support for `macro_rules!` macros.  An invocation is expanded by matching its tokens against the pattern of each
+//! rule in turn, then substituting what was captured into the body of the first rule that matches.
+//!
+//! Only simple macros are supported: repetitions (`$(...)*`) are rejected, and macros aren't hygienic.
+//!
+//! `panic!` and the assertions from the standard library are built in, and are expanded the same way.
+use bytecode::engine::{BytecodeEngine, Definition, MacroRule, MacroRules, ScopeId};
+use error::Result;
+use proc_macro2::{Delimiter, Group, Literal, Op, Spacing, Span, Term, TokenStream, TokenTree};
+use std::collections::HashMap;
+use syn::{self, Expr, Macro, Pat, Type};
+
+/// How deeply macro expansions may nest before giving up, which catches macros that expand to themselves.  It's kept
+/// low because each level of expansion recurses through the bytecode conversion.
+const MACRO_RECURSION_LIMIT: usize = 16;
+
+/// The fragments captured by a pattern, as (fragment specifier, tokens) for each name
+type Captures = HashMap<String, (String, Vec<TokenTree>)>;
+
+/// The macros from the standard library that are built in, used when a program doesn't define its own
+const BUILTIN_MACROS: &[&str] = &["panic", "assert", "assert_eq", "assert_ne"];
+
+/// The name of the macro being invoked, without the `!`
+pub(crate) fn macro_name(mac: &Macro) -> String {
+    let segments: Vec<String> = mac
+        .path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    segments.join("::")
+}
+
+/// Wraps the tokens in braces, so they can be parsed as a block
+pub(crate) fn quote_block(tokens: TokenStream) -> TokenStream {
+    let block = TokenTree::Group(Group::new(Delimiter::Brace, tokens));
+    vec![block].into_iter().collect()
+}
+
+/// Rewrites `println!("{}", x)` as a call of the `__debug__` builtin, `__debug__(x)`.  The arguments keep their
+/// spans, so that errors in them can be located.
+pub(crate) fn debug_call(mac: &Macro, is_stmt: bool) -> TokenStream {
+    let span = mac.path.segments[0].ident.span();
+    let args: TokenStream = mac.tts.clone().into_iter().skip(2).collect();
+    let mut group = Group::new(Delimiter::Parenthesis, args);
+    group.set_span(span);
+
+    let mut call = vec![
+        TokenTree::Term(Term::new("__debug__", span)),
+        TokenTree::Group(group),
+    ];
+    if is_stmt {
+        call.push(TokenTree::Op(Op::new(';', Spacing::Alone)));
+    }
+    call.into_iter().collect()
+}
+
+impl BytecodeEngine {
+    /// Reads the rules from the body of a `macro_rules!` definition
+    pub(crate) fn parse_macro_rules(&self, name: &str, tts: &TokenStream) -> Result<MacroRules> {
+        let tokens: Vec<TokenTree> = tts.clone().into_iter().collect();
+        let mut rules = vec![];
+
+        let mut idx = 0;
+        while idx < tokens.len() {
+            match (
+                &tokens[idx],
+                tokens.get(idx + 1),
+                tokens.get(idx + 2),
+                tokens.get(idx + 3),
+            ) {
+                (
+                    TokenTree::Group(pattern),
+                    Some(TokenTree::Op(eq)),
+                    Some(TokenTree::Op(gt)),
+                    Some(TokenTree::Group(body)),
+                ) if eq.op() == '=' && gt.op() == '>' => {
+                    rules.push(MacroRule {
+                        pattern: pattern.stream(),
+                        body: body.stream(),
+                    });
+                }
+                _ => bail!("Unsupported macro_rules! syntax in {}", name),
+            }
+            idx += 4;
+
+            if let Some(TokenTree::Op(semi)) = tokens.get(idx) {
+                if semi.op() == ';' {
+                    idx += 1;
+                }
+            }
+        }
+
+        if rules.is_empty() {
+            bail!("macro_rules! {} has no rules", name);
+        }
+
+        Ok(MacroRules { rules })
+    }
+
+    /// Expands a macro invocation into the tokens it stands for.  Callers parse the result as whatever syntax is
+    /// expected where the macro was invoked, and count the expansion in `macro_depth` while converting it.
+    pub(crate) fn expand_macro(&mut self, mac: &Macro, scope_id: ScopeId) -> Result<TokenStream> {
+        let name = macro_name(mac);
+        if self.macro_depth >= MACRO_RECURSION_LIMIT {
+            bail!("Recursion limit reached while expanding {}!", name);
+        }
+
+        // Macros defined at the top of the crate are visible in every module
+        let key = name.clone() + "!";
+        let definition_id = match self
+            .use_defn(&key, scope_id)
+            .or_else(|| self.use_defn(&key, 0))
+        {
+            Some((definition_id, _)) => definition_id,
+            None if BUILTIN_MACROS.contains(&name.as_str()) => {
+                return expand_builtin_macro(&name, mac)
+            }
+            None => bail!("Can not find macro {}!", name),
+        };
+        let macro_rules = match self.definitions[definition_id] {
+            Definition::Macro(ref macro_rules) => macro_rules.clone(),
+            _ => bail!("{} is not a macro", name),
+        };
+
+        let input: Vec<TokenTree> = mac.tts.clone().into_iter().collect();
+        for rule in &macro_rules.rules {
+            let pattern: Vec<TokenTree> = rule.pattern.clone().into_iter().collect();
+            let mut captures = HashMap::new();
+            if match_tokens(&name, &pattern, &input, &mut captures)? {
+                return transcribe(&name, rule.body.clone(), &captures);
+            }
+        }
+
+        bail!("No rules of {}! match this invocation", name)
+    }
+}
+
+/// Matches the whole of `input` against `pattern`, adding the fragments it captures to `captures`
+fn match_tokens(
+    name: &str,
+    pattern: &[TokenTree],
+    input: &[TokenTree],
+    captures: &mut Captures,
+) -> Result<bool> {
+    let mut p = 0;
+    let mut i = 0;
+
+    while p < pattern.len() {
+        if let Some((var, fragment)) = fragment_at(name, pattern, p)? {
+            p += 4;
+            let len = match fragment.as_str() {
+                "ident" => match input.get(i) {
+                    Some(TokenTree::Term(_)) => 1,
+                    _ => return Ok(false),
+                },
+                "literal" => match input.get(i) {
+                    Some(TokenTree::Literal(_)) => 1,
+                    Some(TokenTree::Term(term))
+                        if term.as_str() == "true" || term.as_str() == "false" =>
+                    {
+                        1
+                    }
+                    _ => return Ok(false),
+                },
+                "block" => match input.get(i) {
+                    Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Brace => 1,
+                    _ => return Ok(false),
+                },
+                "tt" => {
+                    if i < input.len() {
+                        1
+                    } else {
+                        return Ok(false);
+                    }
+                }
+                "expr" | "ty" | "pat" | "path" => {
+                    // These fragments run up to the next token the pattern expects
+                    match pattern.get(p) {
+                        None => input.len() - i,
+                        Some(TokenTree::Op(next)) if next.op() != '$' => {
+                            match input[i..].iter().position(|x| same_token(x, &pattern[p])) {
+                                Some(len) => len,
+                                None => return Ok(false),
+                            }
+                        }
+                        Some(_) => bail!(
+                            "${}:{} must be followed by a separator like `,` or `;` in {}!",
+                            var,
+                            fragment,
+                            name
+                        ),
+                    }
+                }
+                _ => bail!(
+                    "Unsupported fragment specifier ${}:{} in {}!",
+                    var,
+                    fragment,
+                    name
+                ),
+            };
+
+            let captured = input[i..i + len].to_vec();
+            if !is_fragment(&fragment, &captured) {
+                return Ok(false);
+            }
+            captures.insert(var, (fragment, captured));
+            i += len;
+        } else {
+            let actual = match input.get(i) {
+                Some(actual) => actual,
+                None => return Ok(false),
+            };
+            match (&pattern[p], actual) {
+                (TokenTree::Group(expected_group), TokenTree::Group(actual_group)) => {
+                    if expected_group.delimiter() != actual_group.delimiter() {
+                        return Ok(false);
+                    }
+                    let expected_tokens: Vec<TokenTree> =
+                        expected_group.stream().into_iter().collect();
+                    let actual_tokens: Vec<TokenTree> = actual_group.stream().into_iter().collect();
+                    if !match_tokens(name, &expected_tokens, &actual_tokens, captures)? {
+                        return Ok(false);
+                    }
+                }
+                (expected, actual) => {
+                    if !same_token(expected, actual) {
+                        return Ok(false);
+                    }
+                }
+            }
+            p += 1;
+            i += 1;
+        }
+    }
+
+    Ok(i == input.len())
+}
+
+/// Reads the `$name:fragment` capture starting at `idx` in the pattern, if there is one
+fn fragment_at(name: &str, pattern: &[TokenTree], idx: usize) -> Result<Option<(String, String)>> {
+    match pattern[idx] {
+        TokenTree::Op(ref dollar) if dollar.op() == '$' => {}
+        _ => return Ok(None),
+    }
+
+    match (
+        pattern.get(idx + 1),
+        pattern.get(idx + 2),
+        pattern.get(idx + 3),
+    ) {
+        (
+            Some(TokenTree::Term(var)),
+            Some(TokenTree::Op(colon)),
+            Some(TokenTree::Term(fragment)),
+        ) if colon.op() == ':' => Ok(Some((
+            var.as_str().to_string(),
+            fragment.as_str().to_string(),
+        ))),
+        (Some(TokenTree::Group(_)), _, _) => {
+            bail!("Macro repetitions are not supported in {}!", name)
+        }
+        _ => bail!("Malformed macro pattern in {}!", name),
+    }
+}
+
+/// Checks that the captured tokens parse as the given kind of fragment
+fn is_fragment(fragment: &str, tokens: &[TokenTree]) -> bool {
+    let stream: TokenStream = tokens.iter().cloned().collect();
+    match fragment {
+        "expr" => syn::parse2::<Expr>(stream).is_ok(),
+        "ty" => syn::parse2::<Type>(stream).is_ok(),
+        "pat" => syn::parse2::<Pat>(stream).is_ok(),
+        "path" => syn::parse2::<syn::Path>(stream).is_ok(),
+        _ => true,
+    }
+}
+
+fn same_token(lhs: &TokenTree, rhs: &TokenTree) -> bool {
+    match (lhs, rhs) {
+        (TokenTree::Term(lhs), TokenTree::Term(rhs)) => lhs.as_str() == rhs.as_str(),
+        (TokenTree::Op(lhs), TokenTree::Op(rhs)) => lhs.op() == rhs.op(),
+        (TokenTree::Literal(lhs), TokenTree::Literal(rhs)) => lhs.to_string() == rhs.to_string(),
+        _ => false,
+    }
+}
+
+/// Substitutes the captured fragments into the body of a rule
+/// Expands one of the `BUILTIN_MACROS`.  A panic is a call of the `__panic__` builtin, which stops the program with the
+/// message, and a failed assertion panics.  Messages have to be string literals without format arguments, as there are
+/// no strings at runtime.  There's no `==` either, so `assert_eq!` and `assert_ne!` compare their operands with `<`.
+fn expand_builtin_macro(name: &str, mac: &Macro) -> Result<TokenStream> {
+    // The arguments are split at the commas between them
+    let mut args: Vec<Vec<TokenTree>> = vec![vec![]];
+    for token in mac.tts.clone() {
+        match token {
+            TokenTree::Op(ref op) if op.op() == ',' => args.push(vec![]),
+            token => args.last_mut().unwrap().push(token),
+        }
+    }
+    if args.last().is_some_and(|arg| arg.is_empty()) {
+        args.pop();
+    }
+
+    let (operands, body) = match name {
+        "panic" => (&[][..], "__panic__($message)"),
+        "assert" => (&["cond"][..], "if $cond {} else { __panic__($message) }"),
+        "assert_eq" => (
+            &["left", "right"][..],
+            "{
+                let __left = $left;
+                let __right = $right;
+                if __left < __right {
+                    __panic__($message)
+                } else {
+                    if __right < __left {
+                        __panic__($message)
+                    }
+                }
+            }",
+        ),
+        _ => (
+            &["left", "right"][..],
+            "{
+                let __left = $left;
+                let __right = $right;
+                if __left < __right {
+                } else {
+                    if __right < __left {
+                    } else {
+                        __panic__($message)
+                    }
+                }
+            }",
+        ),
+    };
+    if args.len() != operands.len() && args.len() != operands.len() + 1 {
+        bail!(
+            "{}! takes {} arguments and an optional message, but {} were given",
+            name,
+            operands.len(),
+            args.len()
+        );
+    }
+
+    let text = |tokens: &[TokenTree]| source_text(tokens.iter().cloned());
+    let mut captures = Captures::new();
+    for (operand, arg) in operands.iter().zip(&args) {
+        captures.insert(operand.to_string(), ("expr".to_string(), arg.clone()));
+    }
+    let message = match args.get(operands.len()) {
+        Some(message) => match message[..] {
+            [TokenTree::Literal(ref literal)] if literal.to_string().starts_with('"') => {
+                message.clone()
+            }
+            _ => bail!("The message of {}! has to be a string literal", name),
+        },
+        None => {
+            let message = match name {
+                "panic" => "explicit panic".to_string(),
+                "assert" => format!("assertion failed: {}", text(&args[0])),
+                "assert_eq" => {
+                    format!("assertion failed: {} == {}", text(&args[0]), text(&args[1]))
+                }
+                _ => format!("assertion failed: {} != {}", text(&args[0]), text(&args[1])),
+            };
+            vec![TokenTree::Literal(Literal::string(&message))]
+        }
+    };
+    captures.insert("message".to_string(), ("literal".to_string(), message));
+
+    // The expansion is located where the macro is invoked, apart from the arguments, which keep their own spans
+    let body = respan(body.parse().unwrap(), mac.path.segments[0].ident.span());
+    transcribe(name, body, &captures)
+}
+
+/// Writes the tokens out the way they'd usually be written in source, like `double(3) == x.y`, for the messages of
+/// the assertions
+fn source_text<I: IntoIterator<Item = TokenTree>>(tokens: I) -> String {
+    let mut text = String::new();
+    // Whether the next token follows the previous one without a space, like after a `.` or the `!` of `!=`
+    let mut glued = true;
+    for token in tokens {
+        let (token_text, glue_before, glue_after) = match token {
+            TokenTree::Group(ref group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::Brace => ("{ ", " }"),
+                    Delimiter::None => ("", ""),
+                };
+                // Calls and indexing follow what they apply to
+                let applied = group.delimiter() != Delimiter::Brace
+                    && text.ends_with(|c: char| {
+                        c.is_alphanumeric() || c == '_' || c == ')' || c == ']'
+                    });
+                let inner = source_text(group.stream());
+                (format!("{}{}{}", open, inner, close), applied, false)
+            }
+            TokenTree::Op(ref op) => {
+                let joint = op.spacing() == Spacing::Joint;
+                match op.op() {
+                    '.' => (".".to_string(), true, true),
+                    ',' | ';' => (op.op().to_string(), true, false),
+                    c => (c.to_string(), false, joint),
+                }
+            }
+            token => (token.to_string(), false, false),
+        };
+        if !glued && !glue_before {
+            text.push(' ');
+        }
+        text += &token_text;
+        glued = glue_after;
+    }
+    text
+}
+
+/// Gives every token the span
+fn respan(stream: TokenStream, span: Span) -> TokenStream {
+    stream
+        .into_iter()
+        .map(|token| {
+            let mut token = match token {
+                TokenTree::Group(group) => {
+                    TokenTree::Group(Group::new(group.delimiter(), respan(group.stream(), span)))
+                }
+                token => token,
+            };
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
+
+fn transcribe(name: &str, body: TokenStream, captures: &Captures) -> Result<TokenStream> {
+    let tokens: Vec<TokenTree> = body.into_iter().collect();
+    let mut output = vec![];
+
+    let mut idx = 0;
+    while idx < tokens.len() {
+        match (&tokens[idx], tokens.get(idx + 1)) {
+            (TokenTree::Op(dollar), Some(TokenTree::Term(var))) if dollar.op() == '$' => {
+                match captures.get(var.as_str()) {
+                    Some((fragment, captured)) => {
+                        if fragment == "expr" {
+                            // An expression is substituted as a unit, keeping the precedence it was written with
+                            let stream = captured.iter().cloned().collect();
+                            output
+                                .push(TokenTree::Group(Group::new(Delimiter::Parenthesis, stream)));
+                        } else {
+                            output.extend(captured.iter().cloned());
+                        }
+                    }
+                    None => bail!("Unknown macro variable ${} in {}!", var.as_str(), name),
+                }
+                idx += 2;
+            }
+            (TokenTree::Op(dollar), Some(TokenTree::Group(_))) if dollar.op() == '$' => {
+                bail!("Macro repetitions are not supported in {}!", name)
+            }
+            (TokenTree::Group(group), _) => {
+                let mut transcribed = Group::new(
+                    group.delimiter(),
+                    transcribe(name, group.stream(), captures)?,
+                );
+                transcribed.set_span(group.span());
+                output.push(TokenTree::Group(transcribed));
+                idx += 1;
+            }
+            (token, _) => {
+                output.push(token.clone());
+                idx += 1;
+            }
+        }
+    }
+
+    Ok(output.into_iter().collect())
+}
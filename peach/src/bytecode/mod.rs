@@ -1,6 +1,37 @@
 mod ast;
+mod attrs;
+mod builtins;
+mod borrowck;
+mod cfg;
+mod config;
 mod engine;
+mod fuse;
+mod infer;
+mod init;
+mod labels;
+mod macros;
+mod moves;
+mod natives;
+mod passes;
+mod prelude;
+mod ssa;
+mod symbols;
+mod traits;
 mod typecheck;
+mod verify;
+mod warnings;
 
+pub use self::cfg::{BasicBlock, BlockId, Cfg};
+pub use self::config::EngineConfig;
+pub(crate) use self::engine::{
+    Impl, Import, LazyFn, Mod, Param, Scope, ScopeId, Struct, VarDecl, OPCODE_NAMES,
+};
 pub use self::engine::{Bytecode, BytecodeEngine, Definition, DefinitionId, Fun, VarStack};
-pub use self::typecheck::builtin_type;
+pub use self::labels::{Label, Labeled, LabeledCode};
+pub use self::natives::NativeId;
+pub use self::passes::{ConstantFolding, ConstantPropagation, Pass, Pipeline, UnreachableCode};
+pub use self::prelude::Prelude;
+pub use self::ssa::{Ssa, SsaValue, ValueDef, ValueId};
+pub(crate) use self::typecheck::is_integer;
+pub use self::typecheck::{builtin_type, Ty};
+pub use self::verify::{VerifyError, VerifyProblem};
@@ -0,0 +1,166 @@
+use bytecode::engine::{Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use error::Result;
+use std::collections::HashSet;
+
+/// What happens to a variable's value when it's read by a `Var` at a given point
+enum VarUse {
+    /// Only a Copy part of the value is read (or it's only read for printing), so the variable stays usable
+    Read,
+    /// The value is moved out of the variable
+    Move,
+}
+
+impl BytecodeEngine {
+    /// Checks that values which aren't Copy are not used after they have been moved.
+    ///
+    /// This is a conservative, flow-sensitive check: a variable that is moved along any path into an instruction is
+    /// considered moved at that instruction.  Assigning a whole new value to a moved variable makes it usable again.
+    ///
+    /// Reads that move the value out of their variable are then rewritten to `Move`, so that the variable is no
+    /// longer dropped.
+    pub(crate) fn check_moves(&self, fun: &mut Fun) -> Result<()> {
+        if fun.bytecode.is_empty() {
+            return Ok(());
+        }
+
+        let mut move_sites = HashSet::new();
+        let bytecode = &fun.bytecode;
+
+        // The set of variables that may have been moved on entry to each instruction
+        let mut moved_at: Vec<Option<HashSet<usize>>> = vec![None; bytecode.len()];
+        moved_at[0] = Some(HashSet::new());
+        let mut worklist = vec![0];
+
+        while let Some(idx) = worklist.pop() {
+            let mut moved = moved_at[idx].clone().unwrap();
+
+            match bytecode[idx] {
+                Bytecode::Var(var_id) | Bytecode::Move(var_id) => {
+                    if moved.contains(&var_id) {
+                        bail_at!(
+                            fun.location_of(idx),
+                            "Use of moved value: {}",
+                            fun.vars[var_id].ident
+                        );
+                    }
+                    if let VarUse::Move = self.var_use(fun, idx)? {
+                        moved.insert(var_id);
+                        move_sites.insert(idx);
+                    }
+                }
+                Bytecode::LValueVar(var_id) => match bytecode.get(idx + 1) {
+                    Some(Bytecode::Assign) => {
+                        moved.remove(&var_id);
+                    }
+                    _ => {
+                        if moved.contains(&var_id) {
+                            bail_at!(
+                                fun.location_of(idx),
+                                "Assignment to part of moved value: {}",
+                                fun.vars[var_id].ident
+                            );
+                        }
+                    }
+                },
+                Bytecode::VarDecl(var_id) | Bytecode::VarDeclUninit(var_id) => {
+                    moved.remove(&var_id);
+                }
+                _ => {}
+            }
+
+            for successor in fun.successors(idx) {
+                if successor >= bytecode.len() {
+                    continue;
+                }
+                let changed = match moved_at[successor] {
+                    None => {
+                        moved_at[successor] = Some(moved.clone());
+                        true
+                    }
+                    Some(ref mut existing) => {
+                        let before = existing.len();
+                        existing.extend(moved.iter().cloned());
+                        existing.len() != before
+                    }
+                };
+                if changed {
+                    worklist.push(successor);
+                }
+            }
+        }
+
+        for idx in move_sites {
+            if let Bytecode::Var(var_id) = fun.bytecode[idx] {
+                fun.bytecode[idx] = Bytecode::Move(var_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Works out whether the `Var` at `idx` moves the variable, by looking at how the value it pushes is consumed
+    fn var_use(&self, fun: &Fun, idx: usize) -> Result<VarUse> {
+        let (var_id, mut ty) = match fun.bytecode[idx] {
+            Bytecode::Var(var_id) | Bytecode::Move(var_id) => (var_id, fun.vars[var_id].ty),
+            _ => unreachable!(),
+        };
+        let mut moves_field = false;
+
+        // Field accesses that end in a Copy value only read the variable
+        let mut next = idx + 1;
+        loop {
+            match fun.bytecode.get(next) {
+                Some(Bytecode::Dot(ref field)) => {
+                    if self.drop_fns.contains_key(&ty) {
+                        moves_field = true;
+                    }
+                    ty = match self.field_ty(ty, field) {
+                        Some(field_ty) => field_ty,
+                        None => return Ok(VarUse::Move),
+                    };
+                }
+                Some(Bytecode::Deref) => {
+                    if let Definition::Reference(referenced, _) = self.definitions[ty] {
+                        ty = referenced;
+                    }
+                }
+                _ => break,
+            }
+            next += 1;
+        }
+
+        if self.is_copy(ty) {
+            return Ok(VarUse::Read);
+        }
+
+        // Mutable references are implicitly reborrowed rather than moved
+        if let Definition::Reference(_, true) = self.definitions[ty] {
+            return Ok(VarUse::Read);
+        }
+
+        match fun.bytecode.get(next) {
+            Some(Bytecode::DebugPrint(_)) => Ok(VarUse::Read),
+            _ => {
+                if moves_field {
+                    bail_at!(
+                        fun.location_of(idx),
+                        "Can not move out of {} because its type implements Drop",
+                        fun.vars[var_id].ident
+                    );
+                }
+                Ok(VarUse::Move)
+            }
+        }
+    }
+
+    fn field_ty(&self, ty: DefinitionId, field: &str) -> Option<DefinitionId> {
+        if let Definition::Struct(ref st) = self.definitions[ty] {
+            for (name, field_ty) in &st.fields {
+                if name == field {
+                    return Some(*field_ty);
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,87 @@
+//! Native functions: Rust functions that embedders expose to peach programs.  They're called with the `CallNative`
+//! instruction, and only the evaluator can run them.
+use bytecode::engine::{BytecodeEngine, Definition, DefinitionId, Fun, Param};
+use eval::Value;
+
+/// The index of a native function in the engine's list of natives
+pub type NativeId = usize;
+
+type NativeFn = Box<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+/// A Rust function registered with `BytecodeEngine::register_native`
+pub(crate) struct Native {
+    pub(crate) param_tys: Vec<DefinitionId>,
+    pub(crate) fun: NativeFn,
+}
+
+impl BytecodeEngine {
+    /// Makes a Rust function callable from peach programs under the given name, in every module.  Calls are
+    /// typechecked against the declared parameter and return types, and the function is given the values of the
+    /// arguments in order.  Integers whose type was never pinned down are passed as the declared parameter type.  A
+    /// function that fails returns a message, and evaluation halts with it as a runtime error.
+    ///
+    /// Eg)
+    /// ```
+    /// extern crate peach;
+    /// use peach::{builtin_type, BytecodeEngine, Value};
+    ///
+    /// let mut bc = BytecodeEngine::new();
+    /// bc.register_native("triple", &[builtin_type::U64], builtin_type::U64, |args: &[Value]| {
+    ///     match args[0] {
+    ///         Value::U64(x) => Ok(Value::U64(x * 3)),
+    ///         _ => Err("triple takes a u64".to_string()),
+    ///     }
+    /// });
+    /// ```
+    pub fn register_native<F>(
+        &mut self,
+        name: &str,
+        param_tys: &[DefinitionId],
+        return_ty: DefinitionId,
+        native: F,
+    ) where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        let native_id = self.natives.len();
+        self.natives.push(Native {
+            param_tys: param_tys.to_vec(),
+            fun: Box::new(native),
+        });
+
+        let params = param_tys
+            .iter()
+            .enumerate()
+            .map(|(var_id, ty)| Param::new(format!("arg{}", var_id), var_id, *ty))
+            .collect();
+        self.definitions.push(Definition::Fun(Fun {
+            ty_params: vec![],
+            params,
+            return_ty,
+            vars: vec![],
+            bytecode: vec![],
+            extern_name: None,
+            native_id: Some(native_id),
+            location: None,
+            stmt_locations: vec![],
+        }));
+
+        let definition_id = self.definitions.len() - 1;
+        self.scopes[0]
+            .definitions
+            .insert(name.to_string(), definition_id);
+        self.scopes[0].set_pub(name.to_string(), true);
+        self.prelude.insert(name.to_string(), definition_id);
+    }
+
+    /// The name the native function was registered under
+    pub(crate) fn native_name(&self, native_id: NativeId) -> String {
+        self.prelude
+            .iter()
+            .find(|(_, &definition_id)| {
+                let definition = &self.definitions[definition_id];
+                matches!(definition, Definition::Fun(fun) if fun.native_id == Some(native_id))
+            })
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| format!("{{native {}}}", native_id))
+    }
+}
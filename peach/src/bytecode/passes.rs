@@ -0,0 +1,382 @@
+//! Passes which transform converted functions, and the pipeline that runs them in order.  Each pass is given the
+//! function's control-flow graph and the SSA form of its variables, and changes the graph's blocks, which are then
+//! laid back out as the function's bytecode.
+use bytecode::cfg::{BlockId, Cfg};
+use bytecode::engine::{Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use bytecode::ssa::{Ssa, ValueDef};
+use bytecode::typecheck::builtin_type;
+use std::convert::TryFrom;
+use std::mem;
+use std::ops::Range;
+
+/// A transformation of a function
+pub trait Pass {
+    /// The name of the pass, as `Pipeline::run` reports it
+    fn name(&self) -> &'static str;
+
+    /// Changes the blocks of the function's graph.  Returns true if anything was changed.
+    fn run(&self, fun: &Fun, cfg: &mut Cfg, ssa: &Ssa) -> bool;
+}
+
+/// Passes to run on functions, in the order they're added
+#[derive(Default)]
+pub struct Pipeline {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline { passes: vec![] }
+    }
+
+    /// Adds a pass to run after those already added
+    pub fn add<P: Pass + 'static>(&mut self, pass: P) {
+        self.passes.push(Box::new(pass));
+    }
+
+    /// Runs each pass on a function that has been converted and checked, rebuilding its graph and SSA form between
+    /// passes.  Returns the names of the passes that changed it.
+    pub fn run(&self, fun: &mut Fun) -> Vec<&'static str> {
+        let mut changed_by = vec![];
+        if fun.bytecode.is_empty() {
+            return changed_by;
+        }
+        for pass in &self.passes {
+            let mut cfg = Cfg::new(fun);
+            let ssa = Ssa::new(fun, &cfg);
+            if pass.run(fun, &mut cfg, &ssa) {
+                cfg.apply(fun);
+                changed_by.push(pass.name());
+            }
+        }
+        changed_by
+    }
+
+    /// The names of the passes, in the order they run
+    pub(crate) fn pass_names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+}
+
+impl BytecodeEngine {
+    /// Sets the passes to run on each function after it's converted and checked.  It applies to functions processed
+    /// after it's set.
+    pub fn set_pipeline(&mut self, pipeline: Pipeline) {
+        self.pipeline = Some(pipeline);
+    }
+
+    /// Runs the pipeline on each function converted so far.  Functions converted afterwards are left as they are
+    /// until this is called again.
+    pub fn run_pipeline(&mut self, pipeline: &Pipeline) {
+        for definition in &mut self.definitions {
+            if let Definition::Fun(ref mut fun) = definition {
+                pipeline.run(fun);
+            }
+        }
+    }
+}
+
+/// Replaces reads of variables with the literal they hold, where the only value that can reach the read was stored
+/// straight from that literal
+pub struct ConstantPropagation;
+
+/// The literal pushed just before the store of a value, if it's a number or bool
+fn stored_literal(cfg: &Cfg, def: &ValueDef) -> Option<Bytecode> {
+    let (block_id, idx) = match def {
+        ValueDef::Store(block_id, idx) => (*block_id, *idx),
+        _ => return None,
+    };
+    let code = &cfg.blocks[block_id].code;
+    // An `Assign` takes the value from under the `LValueVar`
+    let pushed = match code[idx] {
+        Bytecode::Assign => idx.checked_sub(2)?,
+        _ => idx.checked_sub(1)?,
+    };
+    Some(code[pushed].clone()).filter(is_literal)
+}
+
+/// Returns true if the instruction pushes a number or bool
+fn is_literal(code: &Bytecode) -> bool {
+    matches!(
+        code,
+        Bytecode::PushU64(_)
+            | Bytecode::PushU32(_)
+            | Bytecode::PushI64(_)
+            | Bytecode::PushI32(_)
+            | Bytecode::PushUnknownInt(_)
+            | Bytecode::PushBool(_)
+    )
+}
+
+impl Pass for ConstantPropagation {
+    fn name(&self) -> &'static str {
+        "constant-propagation"
+    }
+
+    fn run(&self, _fun: &Fun, cfg: &mut Cfg, ssa: &Ssa) -> bool {
+        let mut replacements = vec![];
+        for (block_id, block) in cfg.blocks.iter().enumerate() {
+            for (idx, code) in block.code.iter().enumerate() {
+                if let Bytecode::Var(var_id) = code {
+                    let literal = ssa
+                        .read(block_id, idx, *var_id)
+                        .and_then(|value| stored_literal(cfg, &ssa.values[value].def));
+                    if let Some(literal) = literal {
+                        replacements.push((block_id, idx, literal));
+                    }
+                }
+            }
+        }
+
+        let changed = !replacements.is_empty();
+        for (block_id, idx, literal) in replacements {
+            cfg.blocks[block_id].code[idx] = literal;
+        }
+        changed
+    }
+}
+
+/// Removes the statements that can't be reached, like those after a `return`, along with the rest of the code that
+/// can't be.  The ends of ifs and whiles that can be reached stay, so that they keep their shape, and returns are kept
+/// as returns without a value, so that the backends still see the function end.
+pub struct UnreachableCode;
+
+impl Pass for UnreachableCode {
+    fn name(&self) -> &'static str {
+        "unreachable-code"
+    }
+
+    fn run(&self, fun: &Fun, cfg: &mut Cfg, _ssa: &Ssa) -> bool {
+        let dead_stmts: Vec<Range<usize>> = fun
+            .unreachable_stmts()
+            .into_iter()
+            .map(|stmt| fun.stmt_locations[stmt].0.clone())
+            .collect();
+        let mut reachable = vec![false; cfg.blocks.len()];
+        for block_id in cfg.reverse_postorder() {
+            reachable[block_id] = true;
+        }
+
+        let mut changed = false;
+        for (block_id, block) in cfg.blocks.iter_mut().enumerate() {
+            if reachable[block_id] {
+                continue;
+            }
+            let mut code = Vec::with_capacity(block.code.len());
+            let mut origins = Vec::with_capacity(block.code.len());
+            for (old, origin) in mem::take(&mut block.code).into_iter().zip(&block.origins) {
+                let kept = match old {
+                    _ if dead_stmts.iter().any(|range| range.contains(origin)) => None,
+                    Bytecode::Else(..)
+                    | Bytecode::EndIf(_)
+                    | Bytecode::EndWhile(_)
+                    | Bytecode::ReturnVoid => Some(old),
+                    Bytecode::ReturnLastStackValue => {
+                        changed = true;
+                        Some(Bytecode::ReturnVoid)
+                    }
+                    _ => None,
+                };
+                match kept {
+                    Some(code_kept) => {
+                        code.push(code_kept);
+                        origins.push(*origin);
+                    }
+                    None => changed = true,
+                }
+            }
+            block.code = code;
+            block.origins = origins;
+        }
+        changed
+    }
+}
+
+/// Evaluates arithmetic and comparisons of literals ahead of time, and drops the arms of ifs with literal conditions
+/// that can't run.  Operations that would fail or overflow are left for evaluation, so they still report the error.
+pub struct ConstantFolding;
+
+/// Folds an integer operation on two literals of the same type, unless it fails
+macro_rules! fold_int {
+    ($lhs:expr, $rhs:expr, $op:ident) => {
+        match ($lhs, $rhs) {
+            (Bytecode::PushU64(lhs), Bytecode::PushU64(rhs)) => lhs.$op(rhs).map(Bytecode::PushU64),
+            (Bytecode::PushU32(lhs), Bytecode::PushU32(rhs)) => lhs.$op(rhs).map(Bytecode::PushU32),
+            (Bytecode::PushI64(lhs), Bytecode::PushI64(rhs)) => lhs.$op(rhs).map(Bytecode::PushI64),
+            (Bytecode::PushI32(lhs), Bytecode::PushI32(rhs)) => lhs.$op(rhs).map(Bytecode::PushI32),
+            // Literals whose type was never pinned down are i32s, so those that overflow one are left too
+            (Bytecode::PushUnknownInt(lhs), Bytecode::PushUnknownInt(rhs)) => lhs
+                .$op(rhs)
+                .filter(|val| i32::try_from(*val).is_ok())
+                .map(Bytecode::PushUnknownInt),
+            _ => None,
+        }
+    };
+}
+
+/// Gives an integer literal whose type was never pinned down the type of the other operand, as evaluation does
+fn with_type_of(literal: Bytecode, other: &Bytecode) -> Bytecode {
+    match (literal, other) {
+        (Bytecode::PushUnknownInt(val), Bytecode::PushU64(_)) => Bytecode::PushU64(val as u64),
+        (Bytecode::PushUnknownInt(val), Bytecode::PushU32(_)) => Bytecode::PushU32(val as u32),
+        (Bytecode::PushUnknownInt(val), Bytecode::PushI64(_)) => Bytecode::PushI64(val as i64),
+        (Bytecode::PushUnknownInt(val), Bytecode::PushI32(_)) => Bytecode::PushI32(val as i32),
+        (literal, _) => literal,
+    }
+}
+
+/// The literal a binary operator gives for two literals
+fn fold_binary(lhs: &Bytecode, rhs: &Bytecode, op: &Bytecode) -> Option<Bytecode> {
+    let rhs = with_type_of(rhs.clone(), lhs);
+    let lhs = with_type_of(lhs.clone(), &rhs);
+    match op {
+        Bytecode::Add => fold_int!(lhs, rhs, checked_add),
+        Bytecode::Sub => fold_int!(lhs, rhs, checked_sub),
+        Bytecode::Mul => fold_int!(lhs, rhs, checked_mul),
+        // Dividing by zero fails too
+        Bytecode::Div => fold_int!(lhs, rhs, checked_div),
+        Bytecode::Lt => match (lhs, rhs) {
+            (Bytecode::PushU64(lhs), Bytecode::PushU64(rhs)) => Some(Bytecode::PushBool(lhs < rhs)),
+            (Bytecode::PushU32(lhs), Bytecode::PushU32(rhs)) => Some(Bytecode::PushBool(lhs < rhs)),
+            (Bytecode::PushI64(lhs), Bytecode::PushI64(rhs)) => Some(Bytecode::PushBool(lhs < rhs)),
+            (Bytecode::PushI32(lhs), Bytecode::PushI32(rhs)) => Some(Bytecode::PushBool(lhs < rhs)),
+            (Bytecode::PushUnknownInt(lhs), Bytecode::PushUnknownInt(rhs)) => {
+                Some(Bytecode::PushBool(lhs < rhs))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The literal a unary instruction gives for a literal
+fn fold_unary(operand: &Bytecode, op: &Bytecode) -> Option<Bytecode> {
+    match (operand, op) {
+        (Bytecode::PushI64(val), Bytecode::Neg) => val.checked_neg().map(Bytecode::PushI64),
+        (Bytecode::PushI32(val), Bytecode::Neg) => val.checked_neg().map(Bytecode::PushI32),
+        (Bytecode::PushUnknownInt(val), Bytecode::Neg) => val
+            .checked_neg()
+            .filter(|val| i32::try_from(*val).is_ok())
+            .map(Bytecode::PushUnknownInt),
+        (Bytecode::PushUnknownInt(val), Bytecode::As(ty)) => unknown_int_as(*val, *ty),
+        // Values whose type is known are left as they are
+        (operand, Bytecode::As(_)) if is_literal(operand) => Some(operand.clone()),
+        _ => None,
+    }
+}
+
+/// Gives an integer literal whose type was never pinned down the given integer type
+fn unknown_int_as(val: i128, ty: DefinitionId) -> Option<Bytecode> {
+    match ty {
+        builtin_type::U64 => Some(Bytecode::PushU64(val as u64)),
+        builtin_type::U32 => Some(Bytecode::PushU32(val as u32)),
+        builtin_type::I64 => Some(Bytecode::PushI64(val as i64)),
+        builtin_type::I32 => Some(Bytecode::PushI32(val as i32)),
+        _ => None,
+    }
+}
+
+/// The literal the instructions at the end of `code` can be folded into, with the number of instructions it replaces
+fn fold_end(code: &[Bytecode]) -> Option<(Bytecode, usize)> {
+    if let [.., lhs, rhs, op] = code {
+        if is_literal(lhs) && is_literal(rhs) {
+            if let Some(literal) = fold_binary(lhs, rhs, op) {
+                return Some((literal, 3));
+            }
+        }
+    }
+    match code {
+        [.., operand, op] => fold_unary(operand, op).map(|literal| (literal, 2)),
+        _ => None,
+    }
+}
+
+/// Drops the `If` at the end of the block, whose condition is the literal `cond`, along with the arm that can't run
+/// and the `Else` and `EndIf` that go with it.  The blocks of the dropped arm are left empty.  Returns false if the
+/// blocks aren't laid out the way an if converts to.
+fn drop_if(cfg: &mut Cfg, cond_block: BlockId, cond: bool) -> bool {
+    let false_block = cfg.blocks[cond_block].successors[1];
+    let last_then = false_block - 1;
+    // With an else arm, the then arm ends by jumping over it to the EndIf.  Without one, the then arm ends with it.
+    let end_if = match cfg.blocks[last_then].code.last() {
+        Some(Bytecode::Else(..)) => {
+            let end_if = cfg.blocks[last_then].successors[0];
+            if !matches!(cfg.blocks[end_if].code.first(), Some(Bytecode::EndIf(_))) {
+                return false;
+            }
+            Some(end_if)
+        }
+        Some(Bytecode::EndIf(_)) => None,
+        _ => return false,
+    };
+
+    let dead = match end_if {
+        Some(end_if) => {
+            cfg.blocks[end_if].code.remove(0);
+            cfg.blocks[end_if].origins.remove(0);
+            if cond {
+                cfg.blocks[last_then].code.pop();
+                cfg.blocks[last_then].origins.pop();
+                cfg.blocks[last_then].successors = vec![false_block];
+                false_block..end_if
+            } else {
+                cond_block + 1..false_block
+            }
+        }
+        None if cond => {
+            cfg.blocks[last_then].code.pop();
+            cfg.blocks[last_then].origins.pop();
+            false_block..false_block
+        }
+        None => cond_block + 1..false_block,
+    };
+    for block_id in dead {
+        cfg.blocks[block_id].code.clear();
+        cfg.blocks[block_id].origins.clear();
+        cfg.blocks[block_id].successors = vec![block_id + 1];
+    }
+
+    let block = &mut cfg.blocks[cond_block];
+    block.code.truncate(block.code.len() - 2);
+    block.origins.truncate(block.code.len());
+    block.successors = vec![cond_block + 1];
+    true
+}
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "constant-folding"
+    }
+
+    fn run(&self, _fun: &Fun, cfg: &mut Cfg, _ssa: &Ssa) -> bool {
+        let mut changed = false;
+        for block in &mut cfg.blocks {
+            // Folding the end of the code as it's built up folds nested expressions from the inside out
+            let mut folded = Vec::with_capacity(block.code.len());
+            let mut origins = Vec::with_capacity(block.code.len());
+            for (code, origin) in mem::take(&mut block.code).into_iter().zip(&block.origins) {
+                folded.push(code);
+                origins.push(*origin);
+                while let Some((literal, count)) = fold_end(&folded) {
+                    folded.truncate(folded.len() - count);
+                    folded.push(literal);
+                    // The literal takes the place of the operation, which comes last
+                    origins.drain(origins.len() - count..origins.len() - 1);
+                    changed = true;
+                }
+            }
+            block.code = folded;
+            block.origins = origins;
+        }
+
+        // Ifs inside an arm that's kept come after the if it's part of, so they're still found
+        for block_id in 0..cfg.blocks.len() {
+            let cond = match cfg.blocks[block_id].code[..] {
+                [.., Bytecode::PushBool(cond), Bytecode::If(..)] => cond,
+                _ => continue,
+            };
+            changed |= drop_if(cfg, block_id, cond);
+        }
+        changed
+    }
+}
@@ -0,0 +1,65 @@
+//! The prelude: definitions that are in scope everywhere in a program, loaded before any of its files.  Embedders
+//! choose what goes in it, which is how shims for the standard library are provided.
+use bytecode::engine::BytecodeEngine;
+use error::Result;
+
+/// Shims for the parts of the standard library that programs commonly use
+const STANDARD_PRELUDE: &str = "
+pub fn min<T: Copy>(a: T, b: T) -> T {
+    if b < a {
+        b
+    } else {
+        a
+    }
+}
+
+pub fn max<T: Copy>(a: T, b: T) -> T {
+    if a < b {
+        b
+    } else {
+        a
+    }
+}
+";
+
+/// The source of the definitions to load before a program
+#[derive(Debug, Clone, Default)]
+pub struct Prelude {
+    sources: Vec<(String, String)>,
+}
+
+impl Prelude {
+    /// A prelude with nothing in it
+    pub fn new() -> Prelude {
+        Prelude::default()
+    }
+
+    /// A prelude with peach's shims for `min` and `max`
+    pub fn standard() -> Prelude {
+        let mut prelude = Prelude::new();
+        prelude.add_source("<prelude>", STANDARD_PRELUDE);
+        prelude
+    }
+
+    /// Adds the items in the given source text.  Errors in them are reported as being in the file `name`.
+    pub fn add_source(&mut self, name: &str, src: &str) {
+        self.sources.push((name.to_string(), src.to_string()));
+    }
+}
+
+impl BytecodeEngine {
+    /// Makes an engine with the given prelude loaded into scope 0.  Its definitions can be used from every module
+    /// and crate, and are shadowed by definitions of the same name.
+    pub fn with_prelude(prelude: &Prelude) -> Result<BytecodeEngine> {
+        let mut bc = BytecodeEngine::new();
+        for (name, src) in &prelude.sources {
+            bc.load_str(name, src)?;
+        }
+
+        bc.prelude = bc.scopes[0].definitions.clone();
+        for name in bc.prelude.keys() {
+            bc.scopes[0].set_pub(name.clone(), true);
+        }
+        Ok(bc)
+    }
+}
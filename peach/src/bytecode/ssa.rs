@@ -0,0 +1,317 @@
+//! The static single assignment form of a function's local variables.  Each store to a variable makes a new value, and
+//! where control flow joins with different values of a variable, a phi chooses between them.  Every read of a variable
+//! then reads exactly one value, which is what passes like constant propagation need to know.
+//!
+//! The bytecode isn't rewritten.  `Ssa` describes the values on top of a `Cfg`, and passes change the graph's blocks.
+use bytecode::borrowck::{lvalue_use, LValueUse};
+use bytecode::cfg::{BlockId, Cfg};
+use bytecode::engine::{Bytecode, Fun};
+use std::collections::HashMap;
+
+/// The index of a value in `Ssa::values`
+pub type ValueId = usize;
+
+/// Where a value comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueDef {
+    /// The argument the parameter was called with
+    Param,
+    /// Stored by the instruction at the index in the block: a `VarDecl`, an `Assign` to the whole variable, or an
+    /// `AssignVar`
+    Store(BlockId, usize),
+    /// The variable is declared without a value, or is read along a path that never gives it one
+    Undefined,
+    /// Chosen at the start of the block from the values the variable has at the end of each of its predecessors, in
+    /// the order given by `Cfg::predecessors`
+    Phi(BlockId, Vec<ValueId>),
+}
+
+/// One value of a variable
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsaValue {
+    pub var_id: usize,
+    pub def: ValueDef,
+}
+
+/// The SSA form of a function's variables
+#[derive(Debug, Clone)]
+pub struct Ssa {
+    pub values: Vec<SsaValue>,
+    /// The phis at the start of each block
+    pub phis: Vec<Vec<ValueId>>,
+    /// The value each variable read reads, by block, index in the block, and variable
+    reads: HashMap<(BlockId, usize, usize), ValueId>,
+    /// Whether each variable is in SSA form.  Variables which are borrowed, or have only some of their fields
+    /// assigned, can change without a store to them, so they're left out.
+    promoted: Vec<bool>,
+}
+
+/// How an instruction uses a variable
+enum VarAccess {
+    Read(usize),
+    Store(usize),
+    /// Something that stops the variable from being promoted
+    Escape(usize),
+}
+
+/// The variables the instruction at `idx` uses, in the order it uses them
+fn accesses(code: &[Bytecode], idx: usize) -> Vec<VarAccess> {
+    match code[idx] {
+        Bytecode::Var(var_id)
+        | Bytecode::Move(var_id)
+        | Bytecode::AddVarConst(var_id, _)
+        | Bytecode::SubVarConst(var_id, _)
+        | Bytecode::LtVarConst(var_id, _) => vec![VarAccess::Read(var_id)],
+        Bytecode::DropVars(ref vars) => vars
+            .iter()
+            .map(|(var_id, _)| VarAccess::Read(*var_id))
+            .collect(),
+        Bytecode::VarDecl(var_id)
+        | Bytecode::VarDeclUninit(var_id)
+        | Bytecode::AssignVar(var_id) => {
+            vec![VarAccess::Store(var_id)]
+        }
+        Bytecode::LValueVar(var_id) => match lvalue_use(code, idx) {
+            // A whole assignment stores at the `Assign`, not here
+            LValueUse::Write if matches!(code.get(idx + 1), Some(Bytecode::Assign)) => vec![],
+            LValueUse::Write | LValueUse::Borrow => vec![VarAccess::Escape(var_id)],
+            LValueUse::Through => vec![VarAccess::Read(var_id)],
+        },
+        Bytecode::Assign => match code[..idx].last() {
+            Some(Bytecode::LValueVar(var_id)) => vec![VarAccess::Store(*var_id)],
+            _ => vec![],
+        },
+        _ => vec![],
+    }
+}
+
+/// The immediate dominator of each block reachable from the entry.  The entry is given itself.
+fn dominators(cfg: &Cfg, rpo: &[BlockId], predecessors: &[Vec<BlockId>]) -> Vec<Option<BlockId>> {
+    let mut order = vec![usize::MAX; cfg.blocks.len()];
+    for (position, block_id) in rpo.iter().enumerate() {
+        order[*block_id] = position;
+    }
+
+    let mut idom = vec![None; cfg.blocks.len()];
+    idom[0] = Some(0);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block_id in &rpo[1..] {
+            let mut new_idom = None;
+            for &pred in &predecessors[block_id] {
+                if idom[pred].is_none() {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    None => pred,
+                    Some(mut other) => {
+                        // Walk both up the tree until they meet
+                        let mut pred = pred;
+                        while pred != other {
+                            while order[pred] > order[other] {
+                                pred = idom[pred].unwrap();
+                            }
+                            while order[other] > order[pred] {
+                                other = idom[other].unwrap();
+                            }
+                        }
+                        pred
+                    }
+                });
+            }
+            if new_idom != idom[block_id] {
+                idom[block_id] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+impl Ssa {
+    /// Works out the SSA form of the function's variables, over the graph built from its bytecode
+    pub fn new(fun: &Fun, cfg: &Cfg) -> Ssa {
+        let rpo = cfg.reverse_postorder();
+        let predecessors = cfg.predecessors();
+        let idom = dominators(cfg, &rpo, &predecessors);
+
+        // Extern functions have parameters without variables
+        let var_count = fun
+            .params
+            .iter()
+            .map(|param| param.var_id + 1)
+            .fold(fun.vars.len(), usize::max);
+
+        let mut promoted = vec![true; var_count];
+        // The blocks that store to each variable
+        let mut store_blocks = vec![vec![]; var_count];
+        for param in &fun.params {
+            store_blocks[param.var_id].push(0);
+        }
+        for &block_id in &rpo {
+            let code = &cfg.blocks[block_id].code;
+            for idx in 0..code.len() {
+                for access in accesses(code, idx) {
+                    match access {
+                        VarAccess::Store(var_id) => store_blocks[var_id].push(block_id),
+                        VarAccess::Escape(var_id) => promoted[var_id] = false,
+                        VarAccess::Read(_) => {}
+                    }
+                }
+            }
+        }
+
+        // Phis go where the stores to a variable first meet, which is the dominance frontier of the blocks with the
+        // stores, and then that of the phis themselves
+        let mut frontiers = vec![vec![]; cfg.blocks.len()];
+        for &block_id in &rpo {
+            if predecessors[block_id].len() < 2 {
+                continue;
+            }
+            for &pred in &predecessors[block_id] {
+                if idom[pred].is_none() {
+                    continue;
+                }
+                let mut runner = pred;
+                while Some(runner) != idom[block_id] {
+                    if !frontiers[runner].contains(&block_id) {
+                        frontiers[runner].push(block_id);
+                    }
+                    runner = idom[runner].unwrap();
+                }
+            }
+        }
+
+        let mut ssa = Ssa {
+            values: vec![],
+            phis: vec![vec![]; cfg.blocks.len()],
+            reads: HashMap::new(),
+            promoted,
+        };
+
+        // Each variable starts undefined, apart from the parameters
+        let mut current: Vec<Vec<ValueId>> = vec![vec![]; var_count];
+        for (var_id, values) in current.iter_mut().enumerate() {
+            values.push(ssa.add_value(var_id, ValueDef::Undefined));
+        }
+        for param in &fun.params {
+            let value = ssa.add_value(param.var_id, ValueDef::Param);
+            current[param.var_id].push(value);
+        }
+
+        for (var_id, blocks) in store_blocks.iter().enumerate() {
+            if !ssa.promoted[var_id] {
+                continue;
+            }
+            let mut worklist = blocks.clone();
+            let mut has_phi = vec![false; cfg.blocks.len()];
+            while let Some(block_id) = worklist.pop() {
+                for &frontier in &frontiers[block_id] {
+                    if !has_phi[frontier] {
+                        has_phi[frontier] = true;
+                        // Predecessors which can't be reached keep the undefined value
+                        let args = vec![current[var_id][0]; predecessors[frontier].len()];
+                        let value = ssa.add_value(var_id, ValueDef::Phi(frontier, args));
+                        ssa.phis[frontier].push(value);
+                        worklist.push(frontier);
+                    }
+                }
+            }
+        }
+
+        let mut children = vec![vec![]; cfg.blocks.len()];
+        for &block_id in &rpo[1..] {
+            children[idom[block_id].unwrap()].push(block_id);
+        }
+        ssa.rename(cfg, &predecessors, &children, 0, &mut current);
+        ssa
+    }
+
+    fn add_value(&mut self, var_id: usize, def: ValueDef) -> ValueId {
+        self.values.push(SsaValue { var_id, def });
+        self.values.len() - 1
+    }
+
+    /// Gives each read in the block, and in the blocks it dominates, the value it reads.  `current` has the values
+    /// each variable was given on the way down the dominator tree, latest last.
+    fn rename(
+        &mut self,
+        cfg: &Cfg,
+        predecessors: &[Vec<BlockId>],
+        children: &[Vec<BlockId>],
+        block_id: BlockId,
+        current: &mut [Vec<ValueId>],
+    ) {
+        let mut pushed = vec![];
+        for &phi in &self.phis[block_id] {
+            current[self.values[phi].var_id].push(phi);
+            pushed.push(self.values[phi].var_id);
+        }
+
+        let code = &cfg.blocks[block_id].code;
+        for idx in 0..code.len() {
+            for access in accesses(code, idx) {
+                match access {
+                    VarAccess::Read(var_id) if self.promoted[var_id] => {
+                        let value = *current[var_id].last().unwrap();
+                        self.reads.insert((block_id, idx, var_id), value);
+                    }
+                    VarAccess::Store(var_id) if self.promoted[var_id] => {
+                        let def = match code[idx] {
+                            Bytecode::VarDeclUninit(_) => ValueDef::Undefined,
+                            _ => ValueDef::Store(block_id, idx),
+                        };
+                        let value = self.add_value(var_id, def);
+                        current[var_id].push(value);
+                        pushed.push(var_id);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for &successor in &cfg.blocks[block_id].successors {
+            let position = predecessors[successor]
+                .iter()
+                .position(|x| *x == block_id)
+                .unwrap();
+            for &phi in &self.phis[successor] {
+                let incoming = *current[self.values[phi].var_id].last().unwrap();
+                if let ValueDef::Phi(_, ref mut args) = self.values[phi].def {
+                    args[position] = incoming;
+                }
+            }
+        }
+
+        for &child in &children[block_id] {
+            self.rename(cfg, predecessors, children, child, current);
+        }
+        for var_id in pushed {
+            current[var_id].pop();
+        }
+    }
+
+    /// Returns true if the variable is in SSA form, so its reads are given values
+    pub fn is_promoted(&self, var_id: usize) -> bool {
+        self.promoted[var_id]
+    }
+
+    /// The value of the variable read by the instruction at `idx` in the block, if it's promoted and the block can be
+    /// reached
+    pub fn read(&self, block_id: BlockId, idx: usize, var_id: usize) -> Option<ValueId> {
+        self.reads.get(&(block_id, idx, var_id)).cloned()
+    }
+
+    /// The instructions which read the value, as blocks and indices in them
+    pub fn reads_of(&self, value: ValueId) -> Vec<(BlockId, usize)> {
+        let mut reads: Vec<(BlockId, usize)> = self
+            .reads
+            .iter()
+            .filter(|(_, read)| **read == value)
+            .map(|((block_id, idx, _), _)| (*block_id, *idx))
+            .collect();
+        reads.sort();
+        reads
+    }
+}
@@ -0,0 +1,150 @@
+//! The names used in the source and what each of them names, recorded as functions are converted, so that tools like
+//! editors can find where a name is defined and what its type is.
+use bytecode::engine::{BytecodeEngine, Definition, DefinitionId, Fun};
+use bytecode::typecheck::builtin_type;
+use error::Location;
+use proc_macro2::Span;
+
+/// A name used in the source
+#[derive(Debug, Clone)]
+pub(crate) struct Symbol {
+    /// Where the name starts
+    pub(crate) location: Location,
+    /// How many characters long the name is
+    pub(crate) len: usize,
+    pub(crate) target: SymbolTarget,
+}
+
+/// What a name used in the source names
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SymbolTarget {
+    Definition(DefinitionId),
+    /// A variable, by its index in the `vars` of the function it's in.  The function is `None` until it's finished
+    /// being converted, and stays `None` for code that isn't part of a function.
+    Var(Option<DefinitionId>, usize),
+}
+
+impl Symbol {
+    fn contains(&self, location: &Location) -> bool {
+        self.location.file == location.file
+            && self.location.line == location.line
+            && self.location.column <= location.column
+            && location.column < self.location.column + self.len
+    }
+}
+
+impl BytecodeEngine {
+    /// Records the name at the span as naming the target.  Names that don't come from a loaded file, like those made
+    /// by macros, aren't recorded.
+    pub(crate) fn record_symbol(&mut self, span: Span, name: &str, target: SymbolTarget) {
+        if let Some(location) = self.location(span) {
+            self.symbols.push(Symbol {
+                location,
+                len: name.chars().count(),
+                target,
+            });
+        }
+    }
+
+    /// Gives the variables named while the function was converted to it
+    pub(crate) fn bind_var_symbols(&mut self, from: usize, definition_id: DefinitionId) {
+        for symbol in &mut self.symbols[from..] {
+            if let SymbolTarget::Var(ref mut fun @ None, _) = symbol.target {
+                *fun = Some(definition_id);
+            }
+        }
+    }
+
+    /// The name used at the location, if any
+    pub(crate) fn symbol_at(&self, location: &Location) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.contains(location))
+    }
+
+    /// Finds where the name used at the location is defined: the declaration of a variable, or the item a path names.
+    /// Only functions that have been processed know the names they use.
+    pub fn definition_at(&self, location: &Location) -> Option<Location> {
+        match self.symbol_at(location)?.target {
+            // A variable's declaration is the first place it's named
+            target @ SymbolTarget::Var(Some(_), _) => self
+                .symbols
+                .iter()
+                .find(|symbol| symbol.target == target)
+                .map(|symbol| symbol.location.clone()),
+            SymbolTarget::Var(None, _) => None,
+            SymbolTarget::Definition(definition_id) => self.definition_location(definition_id),
+        }
+    }
+
+    /// Describes what the name used at the location is, with its type.
+    ///
+    /// Eg)
+    /// ```text
+    /// total: u64
+    /// fn add(x: u64, y: u64) -> u64
+    /// struct Point { x: u64, y: u64 }
+    /// ```
+    pub fn describe_at(&self, location: &Location) -> Option<String> {
+        match self.symbol_at(location)?.target {
+            SymbolTarget::Var(Some(definition_id), var_id) => {
+                let var = match self.definitions[definition_id] {
+                    Definition::Fun(ref fun) => fun.vars.get(var_id)?,
+                    _ => return None,
+                };
+                Some(format!("{}: {}", var.ident, self.ty(var.ty)))
+            }
+            SymbolTarget::Var(None, _) => None,
+            SymbolTarget::Definition(definition_id) => Some(self.describe(definition_id)),
+        }
+    }
+
+    fn definition_location(&self, definition_id: DefinitionId) -> Option<Location> {
+        match self.definitions[definition_id] {
+            Definition::Fun(ref fun) => fun.location.clone(),
+            Definition::InstantiatedFun(generic_id, _) => self.definition_location(generic_id),
+            Definition::LazyFn(ref lazy_fn) => self.location(lazy_fn.decl.fn_token.0),
+            Definition::Struct(ref st) => match st.instance_of {
+                Some((generic_id, _)) => self.definition_location(generic_id),
+                None => st.location.clone(),
+            },
+            Definition::GenericStruct(ref item_struct, _)
+            | Definition::LazyStruct(ref item_struct) => self.location(item_struct.ident.span()),
+            _ => None,
+        }
+    }
+
+    /// The kind, name, and type of the definition, like `describe_at` gives
+    pub(crate) fn describe(&self, definition_id: DefinitionId) -> String {
+        let name = self
+            .qualified_name(definition_id)
+            .unwrap_or_else(|| self.ty(definition_id).to_string());
+        match self.definitions[definition_id] {
+            Definition::Fun(ref fun) => format!("fn {}{}", name, self.signature(fun)),
+            Definition::Struct(ref st) => {
+                let fields: Vec<String> = st
+                    .fields
+                    .iter()
+                    .map(|(field, ty)| format!("{}: {}", field, self.ty(*ty)))
+                    .collect();
+                format!(
+                    "struct {} {{ {} }}",
+                    self.ty(definition_id),
+                    fields.join(", ")
+                )
+            }
+            ref definition => format!("{} {}", definition.kind(), name),
+        }
+    }
+
+    /// The parameters and return type of the function, eg) `(x: u64, y: u64) -> u64`
+    fn signature(&self, fun: &Fun) -> String {
+        let params: Vec<String> = fun
+            .params
+            .iter()
+            .map(|param| format!("{}: {}", param.name, self.ty(param.ty)))
+            .collect();
+        match fun.return_ty {
+            builtin_type::VOID => format!("({})", params.join(", ")),
+            return_ty => format!("({}) -> {}", params.join(", "), self.ty(return_ty)),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Answers whether types implement traits, for the typechecker and method resolution
+use bytecode::engine::{BytecodeEngine, Definition, DefinitionId};
+
+impl BytecodeEngine {
+    /// Whether the type implements the trait.  Copy is built in: builtins and shared references are Copy, and a
+    /// struct is if it's marked Copy and all of its fields are.  Drop and the traits programs declare need an impl
+    /// of the trait for the type.  A type variable implements the traits it's bound by.
+    ///
+    /// Answers are cached until the next impl is processed, as that can change them.  An obligation that depends
+    /// on itself, like a struct that's Copy if its field is, whose type is Copy if the struct is, doesn't hold.
+    pub(crate) fn implements(&self, ty: DefinitionId, trait_name: &str) -> bool {
+        self.solve(ty, trait_name, &mut vec![])
+    }
+
+    fn solve(
+        &self,
+        ty: DefinitionId,
+        trait_name: &str,
+        solving: &mut Vec<(DefinitionId, String)>,
+    ) -> bool {
+        let obligation = (ty, trait_name.to_string());
+        if let Some(answer) = self.trait_solutions.borrow().get(&obligation) {
+            return *answer;
+        }
+        if solving.contains(&obligation) {
+            return false;
+        }
+
+        solving.push(obligation);
+        let answer = match (trait_name, &self.definitions[ty]) {
+            (_, Definition::TypeVariable) => match self.type_var_bounds.get(&ty) {
+                Some(bounds) => bounds.iter().any(|bound| bound == trait_name),
+                None => false,
+            },
+            ("Copy", Definition::Builtin) | ("Copy", Definition::Reference(_, false)) => true,
+            ("Copy", Definition::Struct(st)) => {
+                st.copy
+                    && st
+                        .fields
+                        .iter()
+                        .all(|(_, field_ty)| self.solve(*field_ty, "Copy", solving))
+            }
+            ("Copy", _) => false,
+            _ => self.find_impl(ty, trait_name).is_some(),
+        };
+        let obligation = solving.pop().unwrap();
+
+        // Structs that are still lazy aren't cached, as they won't be once they're processed
+        if !matches!(self.definitions[ty], Definition::LazyStruct(_)) {
+            self.trait_solutions.borrow_mut().insert(obligation, answer);
+        }
+        answer
+    }
+
+    /// The processed impl of the trait for the type, if there is one
+    pub(crate) fn find_impl(&self, ty: DefinitionId, trait_name: &str) -> Option<DefinitionId> {
+        self.impls
+            .iter()
+            .map(|(definition_id, _)| *definition_id)
+            .find(|definition_id| match self.definitions[*definition_id] {
+                Definition::Impl(ref imp) => {
+                    imp.self_ty == ty && imp.trait_name.as_deref() == Some(trait_name)
+                }
+                _ => false,
+            })
+    }
+}
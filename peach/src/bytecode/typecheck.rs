@@ -1,4 +1,7 @@
-use bytecode::engine::{BytecodeEngine, DefinitionId};
+use bytecode::engine::{Bytecode, BytecodeEngine, Definition, DefinitionId, ScopeId, VarDecl};
+use error::{PeachError, Result};
+use proc_macro2::Span;
+use std::fmt;
 
 pub mod builtin_type {
     use super::*;
@@ -14,24 +17,141 @@ pub mod builtin_type {
     pub const ERROR: DefinitionId = 9;
 }
 
-impl BytecodeEngine {
-    pub fn printable_name(&self, ty: DefinitionId) -> String {
-        match ty {
-            builtin_type::UNKNOWN => "{unknown}".into(),
-            builtin_type::UNKNOWN_INT => "{unknown int}".into(),
-            builtin_type::VOID => "void".into(),
-            builtin_type::U64 => "u64".into(),
-            builtin_type::U32 => "u32".into(),
-            builtin_type::I64 => "i64".into(),
-            builtin_type::I32 => "i32".into(),
-            builtin_type::BOOL => "bool".into(),
-            builtin_type::ERROR => "{error}".into(),
-            _ => format!("{{custom type: {}}}", ty),
+/// Returns true for the integer types, including that of integer literals whose type hasn't been pinned down
+pub(crate) fn is_integer(ty: DefinitionId) -> bool {
+    matches!(
+        ty,
+        builtin_type::U64
+            | builtin_type::U32
+            | builtin_type::I64
+            | builtin_type::I32
+            | builtin_type::UNKNOWN_INT
+    )
+}
+
+/// A type the engine knows about.  It displays as it's written in Rust, like `u64`, `&mut shapes::Point`, or
+/// `Pair<bool>`, and can be asked what kind of type it is.
+#[derive(Clone, Copy)]
+pub struct Ty<'a> {
+    engine: &'a BytecodeEngine,
+    id: DefinitionId,
+}
+
+impl<'a> Ty<'a> {
+    pub fn id(&self) -> DefinitionId {
+        self.id
+    }
+
+    /// True for the integer types, and the type of an integer literal that hasn't been pinned down
+    pub fn is_integer(&self) -> bool {
+        is_integer(self.id)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self.id, builtin_type::I64 | builtin_type::I32)
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.id == builtin_type::BOOL
+    }
+
+    pub fn is_void(&self) -> bool {
+        self.id == builtin_type::VOID
+    }
+
+    pub fn is_struct(&self) -> bool {
+        matches!(self.engine.definitions[self.id], Definition::Struct(_))
+    }
+
+    pub fn is_reference(&self) -> bool {
+        self.referenced().is_some()
+    }
+
+    pub fn is_mutable_reference(&self) -> bool {
+        matches!(
+            self.engine.definitions[self.id],
+            Definition::Reference(_, true)
+        )
+    }
+
+    /// The type a reference refers to, if this is a reference
+    pub fn referenced(&self) -> Option<Ty<'a>> {
+        match self.engine.definitions[self.id] {
+            Definition::Reference(referenced, _) => Some(self.engine.ty(referenced)),
+            _ => None,
         }
     }
 
+    /// Whether values of the type are copied rather than moved
+    pub fn is_copy(&self) -> bool {
+        self.engine.is_copy(self.id)
+    }
+}
+
+impl<'a> PartialEq for Ty<'a> {
+    fn eq(&self, other: &Ty<'a>) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<'a> fmt::Debug for Ty<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ty({}: {})", self.id, self)
+    }
+}
+
+impl<'a> fmt::Display for Ty<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let engine = self.engine;
+        // Types declared by the program are named by their path, and type variables by their name
+        let name = |definition_id: DefinitionId| {
+            match engine.definitions[definition_id] {
+                Definition::TypeVariable => engine.definition_name(definition_id),
+                _ => engine.qualified_name(definition_id),
+            }
+            .unwrap_or_else(|| format!("{{type {}}}", definition_id))
+        };
+
+        match self.id {
+            builtin_type::UNKNOWN => write!(f, "_"),
+            builtin_type::UNKNOWN_INT => write!(f, "{{integer}}"),
+            builtin_type::VOID => write!(f, "()"),
+            builtin_type::U64 => write!(f, "u64"),
+            builtin_type::U32 => write!(f, "u32"),
+            builtin_type::I64 => write!(f, "i64"),
+            builtin_type::I32 => write!(f, "i32"),
+            builtin_type::BOOL => write!(f, "bool"),
+            builtin_type::VOID_PTR => write!(f, "*mut c_void"),
+            builtin_type::ERROR => write!(f, "{{error}}"),
+            _ => match engine.definitions[self.id] {
+                Definition::Reference(referenced, false) => write!(f, "&{}", engine.ty(referenced)),
+                Definition::Reference(referenced, true) => {
+                    write!(f, "&mut {}", engine.ty(referenced))
+                }
+                Definition::Struct(ref st) => match st.instance_of {
+                    Some((generic_id, ref ty_args)) => {
+                        let ty_args: Vec<String> = ty_args
+                            .iter()
+                            .map(|ty_arg| engine.ty(*ty_arg).to_string())
+                            .collect();
+                        write!(f, "{}<{}>", name(generic_id), ty_args.join(", "))
+                    }
+                    None => write!(f, "{}", name(self.id)),
+                },
+                _ => write!(f, "{}", name(self.id)),
+            },
+        }
+    }
+}
+
+/// Takes the type of the value on top of the stack.  Values from before the code being checked have the error type.
+fn pop(stack: &mut Vec<DefinitionId>) -> DefinitionId {
+    stack.pop().unwrap_or(builtin_type::ERROR)
+}
+
+impl BytecodeEngine {
     pub(crate) fn operator_compatible(&self, lhs: DefinitionId, rhs: DefinitionId) -> bool {
-        if lhs == rhs {
+        if lhs == rhs || lhs == builtin_type::ERROR || rhs == builtin_type::ERROR {
             return true;
         }
         match (lhs, rhs) {
@@ -49,9 +169,15 @@ impl BytecodeEngine {
     }
 
     pub(crate) fn assignment_compatible(&self, lhs: DefinitionId, rhs: DefinitionId) -> bool {
-        if lhs == rhs {
+        if lhs == rhs || lhs == builtin_type::ERROR || rhs == builtin_type::ERROR {
             return true;
         }
+        // A reference can be given one to a compatible value, and a mutable reference can be used as a shared one
+        if let (Definition::Reference(lhs, lhs_mut), Definition::Reference(rhs, rhs_mut)) =
+            (&self.definitions[lhs], &self.definitions[rhs])
+        {
+            return (*rhs_mut || !*lhs_mut) && self.assignment_compatible(*lhs, *rhs);
+        }
         match (lhs, rhs) {
             (builtin_type::U64, builtin_type::UNKNOWN_INT)
             | (builtin_type::U32, builtin_type::UNKNOWN_INT)
@@ -66,6 +192,367 @@ impl BytecodeEngine {
         }
     }
 
+    /// The type values of both types can have, if there is one.  A placeholder for a type that isn't known yet is
+    /// resolved to the other type: `{unknown}` to any type, and `{unknown int}` to any integer type.  References unify
+    /// when the types they refer to do.
+    pub(crate) fn unify(&self, lhs: DefinitionId, rhs: DefinitionId) -> Option<DefinitionId> {
+        match (lhs, rhs) {
+            _ if lhs == rhs => Some(lhs),
+            (builtin_type::ERROR, ty) | (ty, builtin_type::ERROR) => Some(ty),
+            (builtin_type::UNKNOWN, ty) | (ty, builtin_type::UNKNOWN) => Some(ty),
+            (builtin_type::UNKNOWN_INT, ty) | (ty, builtin_type::UNKNOWN_INT) if is_integer(ty) => {
+                Some(ty)
+            }
+            _ => match (&self.definitions[lhs], &self.definitions[rhs]) {
+                (Definition::Reference(lhs, lhs_mut), Definition::Reference(rhs, rhs_mut))
+                    if lhs_mut == rhs_mut =>
+                {
+                    let referenced = self.unify(*lhs, *rhs)?;
+                    self.reference_tys.get(&(referenced, *lhs_mut)).cloned()
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// The error for a value of type `found` where one of type `expected` is needed, placed at `span`, the expression
+    /// that has the wrong type
+    pub(crate) fn mismatch(
+        &self,
+        expected: DefinitionId,
+        found: DefinitionId,
+        span: Span,
+    ) -> PeachError {
+        PeachError::Conversion(
+            format!(
+                "Mismatched types: expected {}, found {}",
+                self.ty(expected),
+                self.ty(found)
+            ),
+            self.location(span),
+        )
+    }
+
+    /// Fails with a mismatch at `span` unless a value of type `found` can be assigned to one of type `expected`
+    pub(crate) fn expect_type(
+        &self,
+        expected: DefinitionId,
+        found: DefinitionId,
+        span: Span,
+    ) -> Result<()> {
+        if self.assignment_compatible(expected, found) {
+            Ok(())
+        } else {
+            Err(self.mismatch(expected, found, span))
+        }
+    }
+
+    /// Returns true if a value of type `found` is widened to be used where `expected` is, which only happens when the
+    /// engine's config turns on widening coercions
+    pub(crate) fn widens(&self, expected: DefinitionId, found: DefinitionId) -> bool {
+        self.config.widening_coercions
+            && matches!(
+                (expected, found),
+                (builtin_type::U64, builtin_type::U32) | (builtin_type::I64, builtin_type::I32)
+            )
+    }
+
+    /// Like `expect_type`, for a value that has just been pushed.  If it's widened, the conversion is added after it.
+    pub(crate) fn coerce(
+        &self,
+        expected: DefinitionId,
+        found: DefinitionId,
+        span: Span,
+        bytecode: &mut Vec<Bytecode>,
+    ) -> Result<()> {
+        if self.widens(expected, found) {
+            bytecode.push(Bytecode::As(expected));
+            Ok(())
+        } else {
+            self.expect_type(expected, found, span)
+        }
+    }
+
+    /// Copy types can still be used after they're assigned or passed somewhere, rather than being moved
+    pub(crate) fn is_copy(&self, ty: DefinitionId) -> bool {
+        self.implements(ty, "Copy")
+    }
+
+    /// Checks that the structs marked as Copy can be: all of their fields must be Copy, and they can't also
+    /// implement Drop
+    pub(crate) fn check_copy_types(&self) -> Result<()> {
+        for (definition_id, definition) in self.definitions.iter().enumerate() {
+            if let Definition::Struct(ref st) = definition {
+                if !st.copy {
+                    continue;
+                }
+                for (field, field_ty) in &st.fields {
+                    if !self.is_copy(*field_ty) {
+                        bail_at!(
+                            st.location.clone(),
+                            "Copy can not be implemented for {}: field {} is not Copy",
+                            self.ty(definition_id),
+                            field
+                        );
+                    }
+                }
+                if self.drop_fns.contains_key(&definition_id) {
+                    bail_at!(
+                        st.location.clone(),
+                        "Copy can not be implemented for {} because it implements Drop",
+                        self.ty(definition_id)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The type an arithmetic or comparison instruction gives for its operands, or `None` if it can't be used on
+    /// them.  Arithmetic needs integers of the same type, and comparisons can also compare bools.  An integer literal
+    /// takes the type of the other operand.
+    pub(crate) fn binary_op_type(
+        &self,
+        op: &Bytecode,
+        lhs: DefinitionId,
+        rhs: DefinitionId,
+    ) -> Option<DefinitionId> {
+        let result = match op {
+            Bytecode::Lt => builtin_type::BOOL,
+            _ => self.tighter_of_types(lhs, rhs),
+        };
+        if lhs == builtin_type::ERROR || rhs == builtin_type::ERROR {
+            return Some(match op {
+                Bytecode::Lt => builtin_type::BOOL,
+                _ => builtin_type::ERROR,
+            });
+        }
+        if !self.operator_compatible(lhs, rhs) {
+            return None;
+        }
+        // Operators on type variables are checked for each set of type arguments
+        let is_type_var = matches!(self.definitions[lhs], Definition::TypeVariable);
+        match op {
+            Bytecode::Add | Bytecode::Sub | Bytecode::Mul | Bytecode::Div
+                if is_integer(lhs) || is_type_var =>
+            {
+                Some(result)
+            }
+            Bytecode::Lt if is_integer(lhs) || lhs == builtin_type::BOOL || is_type_var => {
+                Some(result)
+            }
+            _ => None,
+        }
+    }
+
+    /// The type negating a value of the given type gives, or `None` if it can't be negated.  Only signed integers can.
+    pub(crate) fn neg_type(&self, ty: DefinitionId) -> Option<DefinitionId> {
+        match ty {
+            builtin_type::I64
+            | builtin_type::I32
+            | builtin_type::UNKNOWN_INT
+            | builtin_type::ERROR => Some(ty),
+            _ => None,
+        }
+    }
+
+    /// Describes why an arithmetic or comparison instruction can't be used on its operands
+    pub(crate) fn binary_op_error(
+        &self,
+        op: &Bytecode,
+        lhs: DefinitionId,
+        rhs: DefinitionId,
+    ) -> String {
+        let verb = match op {
+            Bytecode::Add => "add",
+            Bytecode::Sub => "subtract",
+            Bytecode::Mul => "multiply",
+            Bytecode::Div => "divide",
+            _ => "compare",
+        };
+        format!(
+            "Can't {} values of {} and {}",
+            verb,
+            self.ty(lhs),
+            self.ty(rhs)
+        )
+    }
+
+    /// Checks the types of the operands of the instructions in `code`, which a statement or function was converted
+    /// to, with the given variables.  The types of the values are followed on a stack, the way evaluation follows the
+    /// values themselves.  Values from before the start of `code`, or whose types can't be followed, have the error
+    /// type, so they're never reported.  Returns the problems found, in order, with the index of the instruction each
+    /// was found at.
+    pub(crate) fn check_types(&self, code: &[Bytecode], vars: &[VarDecl]) -> Vec<(usize, String)> {
+        self.follow_types(code, vars, |_, _| {})
+    }
+
+    /// Follows the types on the stack through `code` like `check_types`, giving `visit` the index of each instruction
+    /// and the types on the stack after it, bottom first
+    pub(crate) fn follow_types<F>(
+        &self,
+        code: &[Bytecode],
+        vars: &[VarDecl],
+        mut visit: F,
+    ) -> Vec<(usize, String)>
+    where
+        F: FnMut(usize, &[DefinitionId]),
+    {
+        let mut stack = vec![];
+        // The height of the stack at the start of each if and while that hasn't ended yet
+        let mut heights = vec![];
+        let mut errors = vec![];
+        let var_ty = |var_id: usize| vars.get(var_id).map_or(builtin_type::ERROR, |x| x.ty);
+
+        for (idx, code) in code.iter().enumerate() {
+            match code {
+                Bytecode::PushU64(_)
+                | Bytecode::ReadInput
+                | Bytecode::ReadClock
+                | Bytecode::ArgCount
+                | Bytecode::Env(_)
+                | Bytecode::ReadFile(_) => stack.push(builtin_type::U64),
+                Bytecode::PushU32(_) => stack.push(builtin_type::U32),
+                Bytecode::PushI64(_) => stack.push(builtin_type::I64),
+                Bytecode::PushI32(_) => stack.push(builtin_type::I32),
+                Bytecode::PushUnknownInt(_) => stack.push(builtin_type::UNKNOWN_INT),
+                Bytecode::PushBool(_) => stack.push(builtin_type::BOOL),
+                Bytecode::PushRawPtr(_) => stack.push(builtin_type::VOID_PTR),
+                Bytecode::RandomBelow | Bytecode::Arg => {
+                    pop(&mut stack);
+                    stack.push(builtin_type::U64);
+                }
+                Bytecode::As(ty) => {
+                    pop(&mut stack);
+                    stack.push(*ty);
+                }
+                Bytecode::Add | Bytecode::Sub | Bytecode::Mul | Bytecode::Div | Bytecode::Lt => {
+                    let rhs = pop(&mut stack);
+                    let lhs = pop(&mut stack);
+                    stack.push(self.binary_op_type(code, lhs, rhs).unwrap_or_else(|| {
+                        errors.push((idx, self.binary_op_error(code, lhs, rhs)));
+                        builtin_type::ERROR
+                    }));
+                }
+                Bytecode::AddVarConst(var_id, _) | Bytecode::SubVarConst(var_id, _) => {
+                    stack.push(var_ty(*var_id));
+                }
+                Bytecode::LtVarConst(_, _) => stack.push(builtin_type::BOOL),
+                Bytecode::Neg => {
+                    let ty = pop(&mut stack);
+                    stack.push(self.neg_type(ty).unwrap_or_else(|| {
+                        let message = format!("Can't negate value of type {}", self.ty(ty));
+                        errors.push((idx, message));
+                        builtin_type::ERROR
+                    }));
+                }
+                Bytecode::Var(var_id) | Bytecode::Move(var_id) | Bytecode::LValueVar(var_id) => {
+                    stack.push(var_ty(*var_id))
+                }
+                Bytecode::Dot(field) | Bytecode::LValueDot(field) => {
+                    let ty = pop(&mut stack);
+                    let field_ty = match self.definitions[ty] {
+                        Definition::Struct(ref st) => st
+                            .fields
+                            .iter()
+                            .find(|(name, _)| name == field)
+                            .map(|(_, ty)| *ty),
+                        _ => None,
+                    };
+                    stack.push(field_ty.unwrap_or(builtin_type::ERROR));
+                }
+                Bytecode::Deref | Bytecode::LValueDeref => {
+                    let ty = pop(&mut stack);
+                    stack.push(match self.definitions[ty] {
+                        Definition::Reference(referenced, _) => referenced,
+                        _ => builtin_type::ERROR,
+                    });
+                }
+                Bytecode::Borrow(mutable) => {
+                    let ty = pop(&mut stack);
+                    let reference = self.reference_tys.get(&(ty, *mutable));
+                    stack.push(reference.cloned().unwrap_or(builtin_type::ERROR));
+                }
+                Bytecode::VarDecl(_)
+                | Bytecode::AssignVar(_)
+                | Bytecode::DebugPrint(_)
+                | Bytecode::WriteFile(_)
+                | Bytecode::Exit
+                | Bytecode::ReturnLastStackValue => {
+                    pop(&mut stack);
+                }
+                Bytecode::Assign => {
+                    pop(&mut stack);
+                    pop(&mut stack);
+                }
+                Bytecode::Call(definition_id) => {
+                    let (param_count, return_ty) = match self.definitions[*definition_id] {
+                        Definition::Fun(ref fun) => (Some(fun.params.len()), fun.return_ty),
+                        Definition::Struct(ref st) => (Some(st.fields.len()), *definition_id),
+                        Definition::InstantiatedFun(generic_id, _) => {
+                            match self.definitions[generic_id] {
+                                Definition::Fun(ref fun)
+                                    if self.contains_type_variable(fun.return_ty) =>
+                                {
+                                    (Some(fun.params.len()), builtin_type::ERROR)
+                                }
+                                Definition::Fun(ref fun) => (Some(fun.params.len()), fun.return_ty),
+                                _ => (None, builtin_type::ERROR),
+                            }
+                        }
+                        _ => (None, builtin_type::ERROR),
+                    };
+                    match param_count {
+                        Some(count) => stack.truncate(stack.len().saturating_sub(count)),
+                        // Without knowing how many arguments there are, nothing under them can be followed
+                        None => stack.clear(),
+                    }
+                    stack.push(return_ty);
+                }
+                Bytecode::CallNative(native_id) => {
+                    let count = self.natives[*native_id].param_tys.len();
+                    stack.truncate(stack.len().saturating_sub(count));
+                    let return_ty = self.definitions.iter().find_map(|x| match x {
+                        Definition::Fun(fun) if fun.native_id == Some(*native_id) => {
+                            Some(fun.return_ty)
+                        }
+                        _ => None,
+                    });
+                    stack.push(return_ty.unwrap_or(builtin_type::ERROR));
+                }
+                Bytecode::If(..) | Bytecode::WhileCond(_) => {
+                    let cond = pop(&mut stack);
+                    if cond != builtin_type::BOOL && cond != builtin_type::ERROR {
+                        let message = match code {
+                            Bytecode::If(..) => "If condition needs to be boolean",
+                            _ => "While condition needs to be boolean",
+                        };
+                        errors.push((idx, message.to_string()));
+                    }
+                    if let Bytecode::If(..) = code {
+                        heights.push(stack.len());
+                    }
+                }
+                // Each arm starts from the stack the if started with, and only the value of the if is left after it
+                Bytecode::Else(..) => stack.truncate(heights.last().cloned().unwrap_or(0)),
+                Bytecode::EndIf(ty) => {
+                    stack.truncate(heights.pop().unwrap_or(0));
+                    if *ty != builtin_type::VOID {
+                        stack.push(*ty);
+                    }
+                }
+                Bytecode::BeginWhile => heights.push(stack.len()),
+                Bytecode::EndWhile(_) => stack.truncate(heights.pop().unwrap_or(0)),
+                Bytecode::VarDeclUninit(_)
+                | Bytecode::DropVars(_)
+                | Bytecode::ReturnVoid
+                | Bytecode::Panic(_) => {}
+            }
+            visit(idx, &stack);
+        }
+        errors
+    }
+
     pub(crate) fn tighter_of_types(&self, lhs: DefinitionId, rhs: DefinitionId) -> DefinitionId {
         match (lhs, rhs) {
             (builtin_type::U64, _) => builtin_type::U64,
@@ -83,4 +570,27 @@ impl BytecodeEngine {
             _ => lhs.clone(),
         }
     }
+
+    /// The type with the given definition id
+    pub fn ty(&self, ty: DefinitionId) -> Ty<'_> {
+        Ty {
+            engine: self,
+            id: ty,
+        }
+    }
+
+    /// The type of the variable with the given name in a processed function, as it was inferred or declared.  If
+    /// the function declares more than one variable with the name, shadowing the others, it's the last one's type.
+    pub fn var_ty(&self, fn_name: &str, var_name: &str, scope_id: ScopeId) -> Result<Ty<'_>> {
+        let fun = self.get_fn(fn_name, scope_id)?;
+        match fun.vars.iter().rev().find(|var| var.ident == var_name) {
+            Some(var) => Ok(self.ty(var.ty)),
+            None => bail!("Function {} has no variable named {}", fn_name, var_name),
+        }
+    }
+
+    /// The return type of a processed function, which is `()` if it doesn't return a value
+    pub fn return_ty(&self, fn_name: &str, scope_id: ScopeId) -> Result<Ty<'_>> {
+        Ok(self.ty(self.get_fn(fn_name, scope_id)?.return_ty))
+    }
 }
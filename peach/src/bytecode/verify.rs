@@ -0,0 +1,512 @@
+//! Checks that bytecode is well-formed before it's run: its jumps land inside the function and where the if or
+//! while they belong to ends, every instruction has the values it takes on the stack, and variables are declared
+//! before they're used, and the names given to builtins are ones conversion would allow.  Bytecode converted from a program always is, unless there's a bug in the conversion or in a
+//! pass, so finding a problem here means the bytecode can't be trusted to run.
+use bytecode::builtins::{env_var_name, file_path};
+use bytecode::engine::{Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use bytecode::natives::NativeId;
+use bytecode::typecheck::builtin_type;
+use error::{PeachError, Result};
+use std::collections::HashSet;
+use std::fmt;
+
+/// What the verifier found wrong with an instruction
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyProblem {
+    /// A jump lands before the start or past the end of the function
+    JumpOutOfBounds,
+    /// An if, else, or while jumps somewhere other than where the construct it's part of ends
+    MisplacedJump,
+    /// An else, end of if, while condition, or end of while isn't part of an if or while, or an if or while never
+    /// ends
+    Unbalanced,
+    /// An instruction takes more values than there are on the stack
+    StackUnderflow { needed: usize, depth: usize },
+    /// An arm of an if, or a while, leaves fewer values on the stack than the code after it relies on
+    StackMismatch { expected: usize, found: usize },
+    /// The types an if, its else, and its end are marked with don't agree
+    MismatchedTypes {
+        expected: DefinitionId,
+        found: DefinitionId,
+    },
+    /// An if without an else is marked as having a value, which it doesn't have when the condition is false
+    MissingElse,
+    /// The variable isn't one of the function's
+    UnknownVar(usize),
+    /// The variable is used where it may not have been declared yet
+    UndeclaredVar(usize),
+    /// A call is to something that isn't a function or struct
+    BadCallTarget(DefinitionId),
+    /// The native function isn't registered with the engine
+    UnknownNative(NativeId),
+    /// The path given to a file builtin isn't inside the file root
+    PathOutsideRoot(String),
+    /// The name given to `env()` has characters it can't have
+    BadEnvName(String),
+}
+
+impl fmt::Display for VerifyProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyProblem::JumpOutOfBounds => write!(f, "jump lands outside the function"),
+            VerifyProblem::MisplacedJump => {
+                write!(f, "jump doesn't land where its if or while ends")
+            }
+            VerifyProblem::Unbalanced => write!(f, "if or while isn't started or ended"),
+            VerifyProblem::StackUnderflow { needed, depth } => write!(
+                f,
+                "instruction takes {} values, but the stack has {}",
+                needed, depth
+            ),
+            VerifyProblem::StackMismatch { expected, found } => write!(
+                f,
+                "stack has {} values where {} are expected",
+                found, expected
+            ),
+            VerifyProblem::MismatchedTypes { expected, found } => write!(
+                f,
+                "control flow is marked with type {} where {} is expected",
+                found, expected
+            ),
+            VerifyProblem::MissingElse => write!(f, "if with a value has no else"),
+            VerifyProblem::UnknownVar(var_id) => write!(f, "variable {} doesn't exist", var_id),
+            VerifyProblem::UndeclaredVar(var_id) => {
+                write!(f, "variable {} may be used before it's declared", var_id)
+            }
+            VerifyProblem::BadCallTarget(definition_id) => write!(
+                f,
+                "call to definition {}, which isn't a function or struct",
+                definition_id
+            ),
+            VerifyProblem::UnknownNative(native_id) => {
+                write!(f, "native function {} isn't registered", native_id)
+            }
+            VerifyProblem::PathOutsideRoot(path) => {
+                write!(f, "path {:?} isn't inside the file root", path)
+            }
+            VerifyProblem::BadEnvName(name) => {
+                write!(f, "environment variable name {:?} isn't allowed", name)
+            }
+        }
+    }
+}
+
+/// Bytecode the verifier rejected, with where the problem is
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    /// The function, by its qualified name when it has one
+    pub function: String,
+    /// The index of the instruction the problem was found at
+    pub offset: usize,
+    pub problem: VerifyProblem,
+    /// Describes the problem, for `PeachError::message`
+    pub message: String,
+}
+
+impl VerifyError {
+    pub fn new(function: String, offset: usize, problem: VerifyProblem) -> VerifyError {
+        let message = problem.to_string();
+        VerifyError {
+            function,
+            offset,
+            problem,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid bytecode in {} at {}: {}",
+            self.function, self.offset, self.message
+        )
+    }
+}
+
+/// An if or while whose end hasn't been reached yet, with the height of the stack where it started
+enum Construct {
+    If {
+        at: usize,
+        height: usize,
+        ty: DefinitionId,
+        else_at: Option<usize>,
+    },
+    While {
+        at: usize,
+        height: usize,
+        cond_at: Option<usize>,
+    },
+}
+
+impl BytecodeEngine {
+    /// Verifies the bytecode of every function that has been processed, for bytecode that didn't come straight from
+    /// conversion.  The first problem found is returned as a `PeachError::Verify`.
+    pub fn verify(&self) -> Result<()> {
+        for (definition_id, definition) in self.definitions.iter().enumerate() {
+            if let Definition::Fun(ref fun) = definition {
+                self.verify_fn(definition_id, fun)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifies the bytecode of a function, which is the given definition
+    pub(crate) fn verify_fn(&self, definition_id: DefinitionId, fun: &Fun) -> Result<()> {
+        self.verify_bytecode(fun).map_err(|(offset, problem)| {
+            let function = self
+                .qualified_name(definition_id)
+                .unwrap_or_else(|| format!("{{fn {}}}", definition_id));
+            PeachError::Verify(VerifyError::new(function, offset, problem))
+        })
+    }
+
+    /// Finds the first problem with the function's bytecode, with the index of the instruction it's at
+    fn verify_bytecode(&self, fun: &Fun) -> ::std::result::Result<(), (usize, VerifyProblem)> {
+        let bytecode = &fun.bytecode;
+
+        // The other checks follow the jumps, so they have to land somewhere first
+        for (idx, code) in bytecode.iter().enumerate() {
+            let target = match code {
+                Bytecode::If(offset, _) | Bytecode::Else(offset, _) => idx.checked_add(*offset),
+                Bytecode::WhileCond(offset) => idx.checked_add(*offset + 1),
+                Bytecode::EndWhile(offset) => idx.checked_sub(*offset),
+                _ => Some(idx),
+            };
+            match target {
+                Some(target) if target <= bytecode.len() => {}
+                _ => return Err((idx, VerifyProblem::JumpOutOfBounds)),
+            }
+
+            // An image could name any file or variable, so the names are checked like conversion checks them
+            match code {
+                Bytecode::ReadFile(path) | Bytecode::WriteFile(path)
+                    if file_path(path.clone()).is_err() =>
+                {
+                    return Err((idx, VerifyProblem::PathOutsideRoot(path.clone())))
+                }
+                Bytecode::Env(name) if env_var_name(name.clone()).is_err() => {
+                    return Err((idx, VerifyProblem::BadEnvName(name.clone())))
+                }
+                _ => {}
+            }
+        }
+
+        self.verify_stack(fun)?;
+        self.verify_vars(fun)
+    }
+
+    /// Follows the height of the stack through the function, the way `check_types` follows the types on it.  Values
+    /// a statement leaves behind, like the result of a call used as a statement, stay there until the function
+    /// returns, so an arm of an if or the body of a while can leave more values than it started with, but never
+    /// fewer.  Instructions that can't be reached aren't checked.
+    fn verify_stack(&self, fun: &Fun) -> ::std::result::Result<(), (usize, VerifyProblem)> {
+        let bytecode = &fun.bytecode;
+        let reachable = fun.reachable();
+        let mut constructs: Vec<Construct> = vec![];
+        let mut depth = 0;
+
+        // An arm that can't fall through to where it ends doesn't leave anything there
+        let check_arm = |idx: usize, depth: usize, expected: usize| {
+            if reachable[idx] && depth < expected {
+                Err((
+                    idx,
+                    VerifyProblem::StackMismatch {
+                        expected,
+                        found: depth,
+                    },
+                ))
+            } else {
+                Ok(())
+            }
+        };
+        let check_ty = |idx: usize, expected: DefinitionId, found: DefinitionId| {
+            if expected == found {
+                Ok(())
+            } else {
+                Err((idx, VerifyProblem::MismatchedTypes { expected, found }))
+            }
+        };
+        let lands_at = |from: usize, to: usize| match bytecode[from] {
+            Bytecode::If(offset, _) | Bytecode::Else(offset, _) if from + offset == to => Ok(()),
+            Bytecode::WhileCond(offset) if from + offset == to => Ok(()),
+            _ => Err((from, VerifyProblem::MisplacedJump)),
+        };
+
+        for (idx, code) in bytecode.iter().enumerate() {
+            match code {
+                Bytecode::Else(_, ty) => match constructs.last_mut() {
+                    Some(Construct::If {
+                        at,
+                        height,
+                        ty: if_ty,
+                        else_at: else_at @ None,
+                    }) => {
+                        check_ty(idx, *if_ty, *ty)?;
+                        lands_at(*at, idx + 1)?;
+                        check_arm(idx, depth, *height + valued(*ty))?;
+                        *else_at = Some(idx);
+                        depth = *height;
+                        continue;
+                    }
+                    _ => return Err((idx, VerifyProblem::Unbalanced)),
+                },
+                Bytecode::EndIf(ty) => match constructs.pop() {
+                    Some(Construct::If {
+                        at,
+                        height,
+                        ty: if_ty,
+                        else_at,
+                    }) => {
+                        check_ty(idx, if_ty, *ty)?;
+                        match else_at {
+                            Some(else_at) => lands_at(else_at, idx)?,
+                            None if valued(*ty) == 1 => {
+                                return Err((idx, VerifyProblem::MissingElse))
+                            }
+                            None => lands_at(at, idx + 1)?,
+                        }
+                        check_arm(idx, depth, height + valued(*ty))?;
+                        depth = height + valued(*ty);
+                        continue;
+                    }
+                    _ => return Err((idx, VerifyProblem::Unbalanced)),
+                },
+                Bytecode::BeginWhile => {
+                    constructs.push(Construct::While {
+                        at: idx,
+                        height: depth,
+                        cond_at: None,
+                    });
+                    continue;
+                }
+                Bytecode::WhileCond(offset) => match constructs.last_mut() {
+                    Some(Construct::While {
+                        height,
+                        cond_at: cond_at @ None,
+                        ..
+                    }) => {
+                        if !matches!(bytecode[idx + offset], Bytecode::EndWhile(_)) {
+                            return Err((idx, VerifyProblem::MisplacedJump));
+                        }
+                        check_arm(idx, depth, *height + 1)?;
+                        *cond_at = Some(idx);
+                    }
+                    _ => return Err((idx, VerifyProblem::Unbalanced)),
+                },
+                Bytecode::EndWhile(offset) => match constructs.pop() {
+                    Some(Construct::While {
+                        at,
+                        height,
+                        cond_at: Some(cond_at),
+                    }) => {
+                        if idx - offset != at {
+                            return Err((idx, VerifyProblem::MisplacedJump));
+                        }
+                        lands_at(cond_at, idx)?;
+                        check_arm(idx, depth, height)?;
+                        depth = height;
+                        continue;
+                    }
+                    _ => return Err((idx, VerifyProblem::Unbalanced)),
+                },
+                _ => {}
+            }
+
+            let (pops, pushes) = self.stack_effect(code).map_err(|problem| (idx, problem))?;
+            if reachable[idx] && depth < pops {
+                return Err((
+                    idx,
+                    VerifyProblem::StackUnderflow {
+                        needed: pops,
+                        depth,
+                    },
+                ));
+            }
+            depth = depth.saturating_sub(pops) + pushes;
+
+            if let Bytecode::If(_, ty) = code {
+                constructs.push(Construct::If {
+                    at: idx,
+                    height: depth,
+                    ty: *ty,
+                    else_at: None,
+                });
+            }
+        }
+
+        match constructs.last() {
+            Some(Construct::If { at, .. }) | Some(Construct::While { at, .. }) => {
+                Err((*at, VerifyProblem::Unbalanced))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// How many values the instruction takes off the stack, and how many it leaves there.  The value a variable is
+    /// declared with is counted as taken, as it's no longer an operand.
+    fn stack_effect(
+        &self,
+        code: &Bytecode,
+    ) -> ::std::result::Result<(usize, usize), VerifyProblem> {
+        Ok(match code {
+            Bytecode::ReturnVoid
+            | Bytecode::VarDeclUninit(_)
+            | Bytecode::DropVars(_)
+            | Bytecode::BeginWhile
+            | Bytecode::EndWhile(_)
+            | Bytecode::Else(..)
+            | Bytecode::EndIf(_)
+            | Bytecode::Panic(_) => (0, 0),
+            Bytecode::PushU64(_)
+            | Bytecode::PushU32(_)
+            | Bytecode::PushI64(_)
+            | Bytecode::PushI32(_)
+            | Bytecode::PushUnknownInt(_)
+            | Bytecode::PushBool(_)
+            | Bytecode::PushRawPtr(_)
+            | Bytecode::ReadInput
+            | Bytecode::ReadClock
+            | Bytecode::ArgCount
+            | Bytecode::Env(_)
+            | Bytecode::ReadFile(_)
+            | Bytecode::Var(_)
+            | Bytecode::Move(_)
+            | Bytecode::LValueVar(_)
+            | Bytecode::AddVarConst(_, _)
+            | Bytecode::SubVarConst(_, _)
+            | Bytecode::LtVarConst(_, _) => (0, 1),
+            Bytecode::As(_)
+            | Bytecode::Neg
+            | Bytecode::Borrow(_)
+            | Bytecode::Deref
+            | Bytecode::Dot(_)
+            | Bytecode::LValueDot(_)
+            | Bytecode::LValueDeref
+            | Bytecode::RandomBelow
+            | Bytecode::Arg => (1, 1),
+            Bytecode::Add | Bytecode::Sub | Bytecode::Mul | Bytecode::Div | Bytecode::Lt => (2, 1),
+            Bytecode::ReturnLastStackValue
+            | Bytecode::VarDecl(_)
+            | Bytecode::AssignVar(_)
+            | Bytecode::DebugPrint(_)
+            | Bytecode::WriteFile(_)
+            | Bytecode::Exit
+            | Bytecode::If(..)
+            | Bytecode::WhileCond(_) => (1, 0),
+            Bytecode::Assign => (2, 0),
+            // A call leaves its result, even if it's void
+            Bytecode::Call(definition_id) => {
+                let param_count = match self.definitions.get(*definition_id) {
+                    Some(Definition::Fun(fun)) => fun.params.len(),
+                    // A function that calls itself, or one that calls it, is still being processed
+                    Some(Definition::LazyFn(lazy_fn)) => lazy_fn.decl.inputs.len(),
+                    Some(Definition::Struct(st)) => st.fields.len(),
+                    Some(Definition::InstantiatedFun(generic_id, _)) => {
+                        match self.definitions[*generic_id] {
+                            Definition::Fun(ref fun) => fun.params.len(),
+                            _ => return Err(VerifyProblem::BadCallTarget(*definition_id)),
+                        }
+                    }
+                    _ => return Err(VerifyProblem::BadCallTarget(*definition_id)),
+                };
+                (param_count, 1)
+            }
+            Bytecode::CallNative(native_id) => match self.natives.get(*native_id) {
+                Some(native) => (native.param_tys.len(), 1),
+                None => return Err(VerifyProblem::UnknownNative(*native_id)),
+            },
+        })
+    }
+
+    /// Checks that the variables instructions use are the function's, and are declared on every path to where
+    /// they're used.  Parameters are declared when the function starts.  Dropping a variable that hasn't been
+    /// declared yet does nothing, so drops are only checked for being the function's.
+    fn verify_vars(&self, fun: &Fun) -> ::std::result::Result<(), (usize, VerifyProblem)> {
+        let bytecode = &fun.bytecode;
+        for (idx, code) in bytecode.iter().enumerate() {
+            let unknown = match code {
+                Bytecode::DropVars(vars) => vars
+                    .iter()
+                    .map(|(var_id, _)| *var_id)
+                    .find(|var_id| *var_id >= fun.vars.len()),
+                code => var_of(code).filter(|var_id| *var_id >= fun.vars.len()),
+            };
+            if let Some(var_id) = unknown {
+                return Err((idx, VerifyProblem::UnknownVar(var_id)));
+            }
+        }
+        if bytecode.is_empty() {
+            return Ok(());
+        }
+
+        // The variables declared on every path into each instruction
+        let mut declared_at: Vec<Option<HashSet<usize>>> = vec![None; bytecode.len()];
+        declared_at[0] = Some(fun.params.iter().map(|param| param.var_id).collect());
+        let mut worklist = vec![0];
+
+        while let Some(idx) = worklist.pop() {
+            let mut declared = declared_at[idx].clone().unwrap();
+            match bytecode[idx] {
+                Bytecode::VarDecl(var_id) | Bytecode::VarDeclUninit(var_id) => {
+                    declared.insert(var_id);
+                }
+                ref code => {
+                    if let Some(var_id) = var_of(code) {
+                        if !declared.contains(&var_id) {
+                            return Err((idx, VerifyProblem::UndeclaredVar(var_id)));
+                        }
+                    }
+                }
+            }
+
+            for successor in fun.successors(idx) {
+                if successor >= bytecode.len() {
+                    continue;
+                }
+                let changed = match declared_at[successor] {
+                    None => {
+                        declared_at[successor] = Some(declared.clone());
+                        true
+                    }
+                    Some(ref mut existing) => {
+                        let before = existing.len();
+                        existing.retain(|var_id| declared.contains(var_id));
+                        existing.len() != before
+                    }
+                };
+                if changed {
+                    worklist.push(successor);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// 1 if an if of the given type leaves a value, otherwise 0
+fn valued(ty: DefinitionId) -> usize {
+    if ty == builtin_type::VOID {
+        0
+    } else {
+        1
+    }
+}
+
+/// The variable the instruction uses or declares, if any
+fn var_of(code: &Bytecode) -> Option<usize> {
+    match code {
+        Bytecode::Var(var_id)
+        | Bytecode::Move(var_id)
+        | Bytecode::LValueVar(var_id)
+        | Bytecode::VarDecl(var_id)
+        | Bytecode::VarDeclUninit(var_id)
+        | Bytecode::AddVarConst(var_id, _)
+        | Bytecode::SubVarConst(var_id, _)
+        | Bytecode::LtVarConst(var_id, _)
+        | Bytecode::AssignVar(var_id) => Some(*var_id),
+        _ => None,
+    }
+}
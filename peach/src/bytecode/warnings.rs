@@ -0,0 +1,123 @@
+//! Warnings about programs that convert successfully but probably don't do what was meant: variables that are
+//! declared but never used, and functions that are never called.
+use bytecode::engine::{Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use error::Warning;
+use std::collections::HashSet;
+
+impl BytecodeEngine {
+    /// Warns about the variables declared in the function that are never read, assigned, or borrowed.  Like rustc,
+    /// variables whose names start with an underscore are left alone.
+    pub(crate) fn check_unused_vars(&mut self, fun: &Fun) {
+        let used: HashSet<usize> = fun
+            .bytecode
+            .iter()
+            .filter_map(|bytecode| match bytecode {
+                Bytecode::Var(var_id) | Bytecode::Move(var_id) | Bytecode::LValueVar(var_id) => {
+                    Some(*var_id)
+                }
+                _ => None,
+            })
+            .collect();
+
+        for (idx, bytecode) in fun.bytecode.iter().enumerate() {
+            match bytecode {
+                Bytecode::VarDecl(var_id) | Bytecode::VarDeclUninit(var_id) => {
+                    let ident = &fun.vars[*var_id].ident;
+                    // Temporaries made during conversion have names like {result}
+                    if used.contains(var_id) || ident.starts_with('_') || ident.starts_with('{') {
+                        continue;
+                    }
+                    self.warnings.push(Warning {
+                        message: format!("unused variable: {}", ident),
+                        location: fun.location_of(idx),
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Warns about statements that can't be reached, like those after a `return`.  Only the first of a run of them
+    /// is reported.
+    pub(crate) fn check_unreachable(&mut self, fun: &Fun) {
+        let dead = fun.unreachable_stmts();
+        for stmt in &dead {
+            let (ref range, ref location) = fun.stmt_locations[*stmt];
+            let follows_dead = dead
+                .iter()
+                .any(|other| fun.stmt_locations[*other].0.end == range.start);
+            if !follows_dead {
+                self.warnings.push(Warning {
+                    message: "unreachable statement".to_string(),
+                    location: Some(location.clone()),
+                });
+            }
+        }
+    }
+
+    /// The warnings about the program so far.  Functions that haven't been processed are reported as never used, so
+    /// this is best called once the program has been processed from its starting function.
+    pub fn warnings(&self) -> Vec<Warning> {
+        let mut warnings = self.warnings.clone();
+
+        // Trait impls have to provide their methods, whether they're called or not, and other crates are libraries
+        // whose functions are there to be used by the program
+        let trait_impl_scopes: HashSet<usize> = self
+            .definitions
+            .iter()
+            .filter_map(|definition| match definition {
+                Definition::Impl(imp) if imp.trait_name.is_some() => Some(imp.scope_id),
+                _ => None,
+            })
+            .collect();
+
+        // A function can be in more than one scope when it's imported with `use`
+        let mut unused_fns: Vec<(DefinitionId, &str)> = vec![];
+        for (scope_id, scope) in self.scopes.iter().enumerate() {
+            if trait_impl_scopes.contains(&scope_id) || self.crate_root(scope_id) != 0 {
+                continue;
+            }
+            for (name, definition_id) in &scope.definitions {
+                if let Definition::LazyFn(_) = self.definitions[*definition_id] {
+                    let is_prelude = self.prelude.values().any(|id| id == definition_id);
+                    if !is_prelude && !unused_fns.iter().any(|(id, _)| id == definition_id) {
+                        unused_fns.push((*definition_id, name));
+                    }
+                }
+            }
+        }
+        unused_fns.sort();
+
+        for (definition_id, name) in unused_fns {
+            if let Definition::LazyFn(ref lazy_fn) = self.definitions[definition_id] {
+                warnings.push(Warning {
+                    message: format!("function is never used: {}", name),
+                    location: self.location(lazy_fn.decl.fn_token.0),
+                });
+            }
+        }
+
+        // `pub use` is there for other modules, and a glob is only unused if none of the names it brings in are used
+        let mut unused_imports = vec![];
+        for (scope_id, scope) in self.scopes.iter().enumerate() {
+            if self.crate_root(scope_id) != 0 {
+                continue;
+            }
+            for (name, import) in &scope.imported {
+                if !import.used && !import.glob && scope.is_private(name) {
+                    unused_imports.push((scope_id, name, import.location.clone()));
+                }
+            }
+        }
+        unused_imports.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+        for (_, name, location) in unused_imports {
+            warnings.push(Warning {
+                message: format!("unused import: {}", name),
+                location,
+            });
+        }
+
+        warnings
+    }
+}
@@ -0,0 +1,302 @@
+//! The bytecode cache: processed functions saved on disk, so a program whose source hasn't changed isn't parsed into
+//! bytecode again on every run.
+//!
+//! Each entry is the image of the engine after a function was processed, saved under a key made from the source of
+//! every file loaded, the configuration, and the state of the engine before processing.  Any change to those gives a
+//! different key, so a stale entry is never used.  Entries are named by the project and function as well as the key,
+//! so saving an entry removes those of the same function in the same project that it supersedes, and the directory
+//! only grows with the functions cached.  An entry starts with the warnings and skipped attributes processing found, written like the rest of an
+//! image, followed by the image itself.
+use bytecode::{BytecodeEngine, Definition, DefinitionId, ScopeId};
+use capabilities::BYTECODE_VERSION;
+use error::{PeachError, Result, Warning};
+use image::{omitted_from_images, ImageReader, ImageWriter};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+/// What processing a function found besides its definitions, which a cache entry replays
+struct CacheEntry {
+    warnings: Vec<Warning>,
+    skipped_attributes: Vec<String>,
+}
+
+impl BytecodeEngine {
+    /// Caches processed functions in the given directory, which is created if it doesn't exist.  After it's set,
+    /// `process_fn` loads a function from the cache if it was processed before from the same source, with the same
+    /// configuration, and in an engine in the same state.  Otherwise the function is processed as usual, and saved
+    /// to the cache.  Functions that call natives, or whose processing loads the files of modules, aren't cached.
+    /// Problems reading or writing the cache are ignored, as the function can always be processed again.
+    ///
+    /// Eg)
+    /// ```text
+    /// let mut bc = BytecodeEngine::new();
+    /// bc.set_cache_dir("target/peach-cache");
+    /// bc.load_file("bin.rs")?;
+    /// bc.process_fn("main", 0)?;
+    /// ```
+    pub fn set_cache_dir(&mut self, path: &str) {
+        self.cache_dir = Some(PathBuf::from(path));
+    }
+
+    /// Processes a function like `process_fn`, loading it from the cache directory when there's an entry for it
+    pub(crate) fn process_fn_cached(
+        &mut self,
+        fn_name: &str,
+        scope_id: ScopeId,
+    ) -> Result<DefinitionId> {
+        let cache_dir = self.cache_dir.clone().unwrap_or_default();
+        let already_processed = match self.get_defn(fn_name, scope_id) {
+            Some((definition_id, _)) => {
+                matches!(self.definitions[definition_id], Definition::Fun(_))
+            }
+            None => false,
+        };
+        if already_processed {
+            let result = self.process_fn_recovering(fn_name, scope_id);
+            return self.take_errors(result);
+        }
+
+        // Processing impls makes definitions for their methods, which an entry can't hold as they're still syntax, so
+        // they're made before the entry is looked for
+        if let Err(e) = self.process_impls() {
+            return self.take_errors(Err(e));
+        }
+
+        let prefix = format!("{:016x}-", self.entry_prefix(fn_name, scope_id));
+        let entry_path = cache_dir.join(format!(
+            "{}{:016x}.cache",
+            prefix,
+            self.cache_key(fn_name, scope_id)
+        ));
+        if let Ok(entry) = fs::read(&entry_path) {
+            if let Some(definition_id) = self.load_cache_entry(&entry, fn_name, scope_id) {
+                return Ok(definition_id);
+            }
+        }
+
+        let definitions_before = self.definitions.len();
+        let warnings_before = self.warnings.len();
+        let skipped_before = self.skipped_attributes.len();
+        let sources_hash = self.sources_hash;
+        let result = self.process_fn_recovering(fn_name, scope_id);
+        let definition_id = self.take_errors(result)?;
+
+        // An entry can only stand in for processing if everything processing made is in it, so nothing is cached if
+        // another file was loaded, or if a definition was made that an image leaves out
+        let complete = self.sources_hash == sources_hash
+            && !self.definitions[definitions_before..]
+                .iter()
+                .any(omitted_from_images);
+        if complete {
+            let entry = CacheEntry {
+                warnings: self.warnings[warnings_before..].to_vec(),
+                skipped_attributes: self.skipped_attributes[skipped_before..].to_vec(),
+            };
+            if self
+                .save_cache_entry(&cache_dir, &entry_path, &entry)
+                .is_ok()
+            {
+                prune_superseded(&cache_dir, &prefix, &entry_path);
+            }
+        }
+
+        Ok(definition_id)
+    }
+
+    /// The start of the names of the entries for the function, which differ only in their keys.  Projects sharing a
+    /// cache directory are told apart by their roots and the names of their files, so they keep their own entries.
+    fn entry_prefix(&self, fn_name: &str, scope_id: ScopeId) -> u64 {
+        let mut hasher = StableHasher::default();
+        if let Some(ref project_root) = self.project_root {
+            project_root.to_string_lossy().hash(&mut hasher);
+        }
+        let mut names: Vec<&String> = self.sources.keys().collect();
+        names.sort();
+        names.hash(&mut hasher);
+        fn_name.hash(&mut hasher);
+        scope_id.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The key of the cache entry for processing the function in the engine as it is now
+    fn cache_key(&self, fn_name: &str, scope_id: ScopeId) -> u64 {
+        let mut hasher = StableHasher::default();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        BYTECODE_VERSION.hash(&mut hasher);
+        self.sources_hash.hash(&mut hasher);
+        format!("{:?}", self.config).hash(&mut hasher);
+        if let Some(ref pipeline) = self.pipeline {
+            pipeline.pass_names().hash(&mut hasher);
+        }
+        for native in &self.natives {
+            native.param_tys.hash(&mut hasher);
+        }
+        for definition in &self.definitions {
+            definition.kind().hash(&mut hasher);
+            omitted_from_images(definition).hash(&mut hasher);
+            if let Definition::Fun(ref fun) = definition {
+                fun.return_ty.hash(&mut hasher);
+            }
+        }
+        for scope in &self.scopes {
+            let mut names: Vec<(&String, &DefinitionId)> = scope.definitions.iter().collect();
+            names.sort();
+            names.hash(&mut hasher);
+        }
+        fn_name.hash(&mut hasher);
+        scope_id.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn save_cache_entry(
+        &self,
+        cache_dir: &Path,
+        entry_path: &Path,
+        entry: &CacheEntry,
+    ) -> Result<()> {
+        let image = self.to_image()?;
+
+        let mut w = ImageWriter::default();
+        w.usize(entry.warnings.len());
+        for warning in &entry.warnings {
+            w.str(&warning.message);
+            w.option(warning.location.as_ref(), ImageWriter::location);
+        }
+        w.usize(entry.skipped_attributes.len());
+        for name in &entry.skipped_attributes {
+            w.str(name);
+        }
+        w.bytes.extend_from_slice(&image);
+
+        // Written to the side first, so an entry is never read half written
+        fs::create_dir_all(cache_dir)
+            .and_then(|_| fs::write(entry_path.with_extension("tmp"), &w.bytes))
+            .and_then(|_| fs::rename(entry_path.with_extension("tmp"), entry_path))
+            .map_err(|e| PeachError::Io(format!("{}: {}", entry_path.display(), e)))
+    }
+
+    /// Loads the definitions and scopes of a cache entry into the engine, if the entry can stand in for processing
+    /// the function.  Returns the id of the function.
+    fn load_cache_entry(
+        &mut self,
+        entry: &[u8],
+        fn_name: &str,
+        scope_id: ScopeId,
+    ) -> Option<DefinitionId> {
+        let mut r = ImageReader::new(entry);
+        let warnings = r
+            .list(|r| {
+                Ok(Warning {
+                    message: r.str()?,
+                    location: r.option(ImageReader::location)?,
+                })
+            })
+            .ok()?;
+        let skipped_attributes = r.list(ImageReader::str).ok()?;
+        let cached = BytecodeEngine::from_image(&entry[r.pos..]).ok()?;
+
+        // The entry has to be this engine after processing: it only adds definitions and scopes, and what it left
+        // out is what this engine still has as syntax
+        if cached.definitions.len() < self.definitions.len()
+            || cached.scopes.len() < self.scopes.len()
+        {
+            return None;
+        }
+        let consistent = cached.definitions.iter().enumerate().all(
+            |(definition_id, definition)| match definition {
+                Definition::Omitted(kind) => {
+                    matches!(self.definitions.get(definition_id), Some(ours)
+                        if omitted_from_images(ours) && ours.kind() == *kind)
+                }
+                _ => true,
+            },
+        );
+        let definition_id = match cached.get_defn(fn_name, scope_id) {
+            Some((definition_id, _)) if consistent => definition_id,
+            _ => return None,
+        };
+        if !matches!(cached.definitions[definition_id], Definition::Fun(_)) {
+            return None;
+        }
+
+        let definition_count = self.definitions.len();
+        for (id, definition) in cached.definitions.into_iter().enumerate() {
+            if id >= definition_count {
+                self.definitions.push(definition);
+            } else if !matches!(definition, Definition::Omitted(_)) {
+                let replaced = mem::replace(&mut self.definitions[id], definition);
+                if let Definition::LazyFn(syntax) = replaced {
+                    self.fn_syntax.insert(id, syntax);
+                }
+            }
+        }
+        self.scopes = cached.scopes;
+        self.crates = cached.crates;
+        self.reference_tys = cached.reference_tys;
+        self.impls = cached.impls;
+        self.drop_fns = cached.drop_fns;
+        self.struct_instances = cached.struct_instances;
+        self.fn_instances = cached.fn_instances;
+        self.type_var_bounds = cached.type_var_bounds;
+        self.tests = cached.tests;
+        self.trait_solutions.get_mut().clear();
+
+        self.warnings.extend(warnings);
+        for name in skipped_attributes {
+            if !self.skipped_attributes.contains(&name) {
+                self.skipped_attributes.push(name);
+            }
+        }
+
+        Some(definition_id)
+    }
+}
+
+/// A 64-bit FNV-1a hasher.  Cache keys outlive the process that made them, so they can't come from `DefaultHasher`,
+/// whose algorithm may change between Rust releases.
+pub(crate) struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> StableHasher {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Removes the entries for the same function in the same project as the one just saved, which it supersedes
+fn prune_superseded(cache_dir: &Path, prefix: &str, entry_path: &Path) {
+    let entries = match fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        let superseded = path != entry_path
+            && path
+                .extension()
+                .is_some_and(|extension| extension == "cache")
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(prefix));
+        if superseded {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
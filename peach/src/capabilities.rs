@@ -0,0 +1,216 @@
+//! A description of what this build of peach supports, so tooling can adapt to the language subset rather than
+//! discovering unsupported constructs when they fail at runtime
+use bytecode::{BytecodeEngine, OPCODE_NAMES};
+use eval::TRACE_FORMAT_VERSION;
+use image::IMAGE_FORMAT_VERSION;
+use json::{self, ENGINE_JSON_FORMAT_VERSION};
+use replay::RECORDING_FORMAT_VERSION;
+
+/// The version of the JSON produced by `Capabilities::to_json`
+pub const CAPABILITIES_FORMAT_VERSION: u32 = 1;
+
+/// The version of the bytecode instruction set.  It's bumped whenever instructions are added, removed, or change
+/// meaning.
+pub const BYTECODE_VERSION: u32 = 11;
+
+/// The parts of the language that can be converted to bytecode and run by every backend
+const LANGUAGE_FEATURES: &[&str] = &[
+    "functions",
+    "recursion",
+    "generic-functions",
+    "extern-functions",
+    "native-functions",
+    "modules",
+    "multiple-crates",
+    "use",
+    "visibility",
+    "structs",
+    "generic-structs",
+    "where-clauses",
+    "impl-blocks",
+    "associated-functions",
+    "traits",
+    "default-methods",
+    "method-calls",
+    "drop",
+    "copy-types",
+    "references",
+    "lifetimes",
+    "borrow-checking",
+    "move-checking",
+    "let",
+    "assignment",
+    "if",
+    "while",
+    "return",
+    "arithmetic",
+    "comparison-lt",
+    "println",
+    "read-input",
+    "clock",
+    "random",
+    "args",
+    "env",
+    "file-io",
+    "panic",
+    "assertions",
+    "exit",
+    "macro-rules",
+    "attributes",
+    "tests",
+];
+
+/// The types that are built into the language
+const BUILTIN_TYPES: &[&str] = &["u64", "u32", "i64", "i32", "bool", "void-pointer"];
+
+/// A way of running peach bytecode
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backend {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// A versioned format that peach reads or writes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Format {
+    pub name: &'static str,
+    pub version: u32,
+}
+
+/// A structured description of the language features, instructions, backends, and formats supported by this build
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// The version of the peach library
+    pub version: &'static str,
+    pub language_features: Vec<&'static str>,
+    pub builtin_types: Vec<&'static str>,
+    pub opcodes: Vec<&'static str>,
+    pub backends: Vec<Backend>,
+    pub formats: Vec<Format>,
+}
+
+impl Capabilities {
+    /// Returns true if the given language feature is supported
+    pub fn supports(&self, feature: &str) -> bool {
+        self.language_features.contains(&feature)
+    }
+
+    /// Returns true if a backend with the given name is available
+    pub fn has_backend(&self, name: &str) -> bool {
+        self.backends.iter().any(|x| x.name == name)
+    }
+
+    /// Returns the version of the given format, if it's supported
+    pub fn format_version(&self, name: &str) -> Option<u32> {
+        self.formats
+            .iter()
+            .find(|x| x.name == name)
+            .map(|x| x.version)
+    }
+
+    pub fn to_json(&self) -> String {
+        fn quote_all(items: &[&str]) -> String {
+            let quoted: Vec<String> = items.iter().map(|x| json::quote(x)).collect();
+            format!("[{}]", quoted.join(","))
+        }
+
+        let backends: Vec<String> = self
+            .backends
+            .iter()
+            .map(|x| {
+                format!(
+                    "{{\"name\":{},\"description\":{}}}",
+                    json::quote(x.name),
+                    json::quote(x.description)
+                )
+            })
+            .collect();
+        let formats: Vec<String> = self
+            .formats
+            .iter()
+            .map(|x| {
+                format!(
+                    "{{\"name\":{},\"version\":{}}}",
+                    json::quote(x.name),
+                    x.version
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"format_version\":{},\"version\":{},\"language_features\":{},\"builtin_types\":{},\"opcodes\":{},\"backends\":[{}],\"formats\":[{}]}}",
+            CAPABILITIES_FORMAT_VERSION,
+            json::quote(self.version),
+            quote_all(&self.language_features),
+            quote_all(&self.builtin_types),
+            quote_all(&self.opcodes),
+            backends.join(","),
+            formats.join(",")
+        )
+    }
+}
+
+impl BytecodeEngine {
+    /// Describes what this build of peach supports
+    pub fn capabilities() -> Capabilities {
+        #[allow(unused_mut)]
+        let mut backends = vec![
+            Backend {
+                name: "eval",
+                description: "Evaluates bytecode directly",
+            },
+            Backend {
+                name: "c",
+                description: "Compiles bytecode to C, then to a binary using the system C compiler",
+            },
+            Backend {
+                name: "rust",
+                description: "Translates bytecode back to Rust source, which rustc can build",
+            },
+        ];
+        #[cfg(feature = "llvm")]
+        backends.push(Backend {
+            name: "llvm",
+            description: "Lowers bytecode to LLVM IR, then to an optimized binary using clang",
+        });
+        #[cfg(feature = "jit")]
+        backends.push(Backend {
+            name: "jit",
+            description: "Evaluates bytecode, running the functions cranelift can compile as native code",
+        });
+
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            language_features: LANGUAGE_FEATURES.to_vec(),
+            builtin_types: BUILTIN_TYPES.to_vec(),
+            opcodes: OPCODE_NAMES.to_vec(),
+            backends,
+            formats: vec![
+                Format {
+                    name: "bytecode",
+                    version: BYTECODE_VERSION,
+                },
+                Format {
+                    name: "trace-json",
+                    version: TRACE_FORMAT_VERSION,
+                },
+                Format {
+                    name: "capabilities-json",
+                    version: CAPABILITIES_FORMAT_VERSION,
+                },
+                Format {
+                    name: "recording",
+                    version: RECORDING_FORMAT_VERSION,
+                },
+                Format {
+                    name: "image",
+                    version: IMAGE_FORMAT_VERSION,
+                },
+                Format {
+                    name: "engine-json",
+                    version: ENGINE_JSON_FORMAT_VERSION,
+                },
+            ],
+        }
+    }
+}
@@ -1,6 +1,11 @@
-use bytecode::{builtin_type, Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use bytecode::{builtin_type, is_integer, Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use error::{PeachError, Result};
+use rng::Rng;
 use time::PreciseTime;
 
+/// The helpers that builtins are compiled to calls of, which go at the top of each C program
+pub(crate) const RUNTIME: &str = include_str!("runtime.c");
+
 struct CFile {
     output_src: String,
     expression_stack: Vec<String>,
@@ -32,6 +37,23 @@ impl CFile {
     }
 }
 
+/// Quotes the text as a C string literal
+fn c_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(byte as char);
+            }
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => quoted += &format!("\\{:03o}", byte),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 fn codegen_type(
     bc: &BytecodeEngine,
     definition_id: DefinitionId,
@@ -47,9 +69,18 @@ fn codegen_type(
         builtin_type::VOID => "void".into(),
         builtin_type::BOOL => "bool".into(),
         ty => {
-            if let Definition::Struct(_) = bc.definitions[definition_id] {
+            if let Definition::Struct(ref st) = bc.definitions[definition_id] {
                 //For now, let's assume all custom types are structs
-                format!("struct struct_{}", ty)
+                match (&st.instance_of, instantiations) {
+                    // Generic functions use the instance for their instantiation's type arguments
+                    (Some(_), Some(instantiations)) if bc.contains_type_variable(ty) => format!(
+                        "struct struct_{}",
+                        bc.find_substituted_type(ty, instantiations)
+                    ),
+                    _ => format!("struct struct_{}", ty),
+                }
+            } else if let Definition::Reference(referenced, _) = bc.definitions[definition_id] {
+                format!("{}*", codegen_type(bc, referenced, instantiations))
             } else if let Definition::TypeVariable = bc.definitions[definition_id] {
                 let sub = instantiations
                     .unwrap()
@@ -69,23 +100,44 @@ fn codegen_type(
     codegen_ty
 }
 
+/// Generates the statements that drop the value of type `ty` at `place`: first its Drop impl, then that of its fields
+fn codegen_drop_glue(bc: &BytecodeEngine, ty: DefinitionId, place: &str) -> String {
+    let mut output = String::new();
+
+    if let Some(drop_fn) = bc.drop_fns.get(&ty) {
+        output += &format!("fun_{}(&{});\n", drop_fn, place);
+    }
+
+    if let Definition::Struct(ref st) = bc.definitions[ty] {
+        for (field, field_ty) in &st.fields {
+            if bc.needs_drop(*field_ty) {
+                output += &codegen_drop_glue(bc, *field_ty, &format!("{}.{}", place, field));
+            }
+        }
+    }
+
+    output
+}
+
 fn codegen_fn(
     cfile: &mut CFile,
     bc: &BytecodeEngine,
     fn_name: &str,
     fun: &Fun,
     instantiations: Option<&Vec<(DefinitionId, DefinitionId)>>,
-) {
+) -> Result<()> {
     let mut next_temp_id = 0;
 
     //TODO: This isn't the best solution, but it's an experiment
     let mut temp_id_stack = vec![];
 
-    cfile.codegen_raw(&format!(
-        "{} {}(",
-        &codegen_type(bc, fun.return_ty, instantiations),
-        fn_name
-    ));
+    // main returns the program's exit status, which is the value the entry function returns, if any
+    let return_ty = if fn_name == "main" {
+        "int".to_string()
+    } else {
+        codegen_type(bc, fun.return_ty, instantiations)
+    };
+    cfile.codegen_raw(&format!("{} {}(", return_ty, fn_name));
 
     let mut first = true;
     for param in &fun.params {
@@ -98,7 +150,14 @@ fn codegen_fn(
         first = false;
     }
 
-    cfile.codegen_raw(") {\n");
+    if fn_name == "main" {
+        // main takes no parameters in peach, but the program's arguments are kept for arg_count() and arg()
+        cfile.codegen_raw("int argc, char **argv) {\n");
+        cfile.codegen_raw("peach_argc = argc;\n");
+        cfile.codegen_raw("peach_argv = argv;\n");
+    } else {
+        cfile.codegen_raw(") {\n");
+    }
 
     for param in &fun.params {
         cfile.codegen_raw(&format!(
@@ -107,26 +166,43 @@ fn codegen_fn(
             param.var_id,
             param.name
         ));
+        if bc.needs_drop(param.ty) {
+            cfile.codegen_raw(&format!("bool d{} = true;\n", param.var_id));
+        }
     }
 
-    for code in &fun.bytecode {
+    // Variables whose values need dropping get a drop flag, which is cleared when their value is moved out
+    for (idx, code) in fun.bytecode.iter().enumerate() {
         match code {
+            // Returns may be inside ifs and whiles, so the rest of the function is still generated after one.  A
+            // function with a return type can't finish without a value, so a return without one there is never reached.
             Bytecode::ReturnVoid => {
-                cfile.codegen_stmt("return;\n");
-                break;
+                if fn_name == "main" {
+                    cfile.codegen_stmt("return 0;\n");
+                } else if fun.return_ty == builtin_type::VOID {
+                    cfile.codegen_stmt("return;\n");
+                }
             }
             Bytecode::ReturnLastStackValue => {
                 let retval = cfile.expression_stack.pop().unwrap();
-                cfile.codegen_stmt(&format!("return {};\n", retval));
-                break;
+                if fn_name == "main" {
+                    cfile.codegen_stmt(&format!("return (int)({});\n", retval));
+                } else {
+                    cfile.codegen_stmt(&format!("return {};\n", retval));
+                }
             }
             Bytecode::As(ty) => {
                 let val = cfile.expression_stack.pop().unwrap();
-                cfile.delay_expr(format!(
-                    "(({})({}))",
-                    codegen_type(bc, *ty, instantiations),
-                    val
-                ));
+                if let Definition::Struct(_) = bc.definitions[*ty] {
+                    // C can't cast structs, and they're already the right type
+                    cfile.delay_expr(val);
+                } else {
+                    cfile.delay_expr(format!(
+                        "(({})({}))",
+                        codegen_type(bc, *ty, instantiations),
+                        val
+                    ));
+                }
             }
             Bytecode::PushU64(val) => {
                 cfile.delay_expr(val.to_string());
@@ -135,7 +211,12 @@ fn codegen_fn(
                 cfile.delay_expr(val.to_string());
             }
             Bytecode::PushI64(val) => {
-                cfile.delay_expr(val.to_string());
+                if *val == i64::MIN {
+                    // C has no literal for the minimum, as its negation is out of range
+                    cfile.delay_expr("(-9223372036854775807LL - 1)".to_string());
+                } else {
+                    cfile.delay_expr(val.to_string());
+                }
             }
             Bytecode::PushI32(val) => {
                 cfile.delay_expr(val.to_string());
@@ -190,6 +271,16 @@ fn codegen_fn(
 
                 cfile.delay_expr(format!("({} < {})", lhs, rhs));
             }
+            Bytecode::Borrow(_) => {
+                let lhs = cfile.expression_stack.pop().unwrap();
+
+                cfile.delay_expr(format!("(&{})", lhs));
+            }
+            Bytecode::Deref | Bytecode::LValueDeref => {
+                let val = cfile.expression_stack.pop().unwrap();
+
+                cfile.delay_expr(format!("(*{})", val));
+            }
             Bytecode::Dot(field) => {
                 let lhs = cfile.expression_stack.pop().unwrap();
 
@@ -211,6 +302,9 @@ fn codegen_fn(
                     *var_id,
                     rhs
                 ));
+                if bc.needs_drop(var.ty) {
+                    cfile.codegen_stmt(&format!("bool d{} = true;\n", var_id));
+                }
             }
             Bytecode::VarDeclUninit(var_id) => {
                 let var = &fun.vars[*var_id];
@@ -219,10 +313,30 @@ fn codegen_fn(
                     codegen_type(bc, var.ty, instantiations),
                     *var_id
                 ));
+                if bc.needs_drop(var.ty) {
+                    cfile.codegen_stmt(&format!("bool d{} = false;\n", var_id));
+                }
             }
             Bytecode::Var(var_id) => {
                 cfile.delay_expr(format!("v{}", var_id));
             }
+            Bytecode::Move(var_id) => {
+                if bc.needs_drop(fun.vars[*var_id].ty) {
+                    cfile.delay_expr(format!("(d{} = false, v{})", var_id, var_id));
+                } else {
+                    cfile.delay_expr(format!("v{}", var_id));
+                }
+            }
+            Bytecode::DropVars(vars) => {
+                for (var_id, ty) in vars {
+                    cfile.codegen_stmt(&format!(
+                        "if (d{}) {{\n{}d{} = false;\n}}\n",
+                        var_id,
+                        codegen_drop_glue(bc, *ty, &format!("v{}", var_id)),
+                        var_id
+                    ));
+                }
+            }
             Bytecode::LValueVar(var_id) => {
                 cfile.delay_expr(format!("v{}", var_id));
             }
@@ -231,6 +345,30 @@ fn codegen_fn(
                 let rhs = cfile.expression_stack.pop().unwrap();
 
                 cfile.codegen_stmt(&format!("{} = {};\n", lhs, rhs));
+
+                // Assigning to a whole variable gives it a value to drop
+                if let Some(Bytecode::LValueVar(var_id)) = fun.bytecode.get(idx - 1) {
+                    if bc.needs_drop(fun.vars[*var_id].ty) {
+                        cfile.codegen_stmt(&format!("d{} = true;\n", var_id));
+                    }
+                }
+            }
+            Bytecode::AddVarConst(var_id, val) => {
+                cfile.delay_expr(format!("(v{}+{})", var_id, val));
+            }
+            Bytecode::SubVarConst(var_id, val) => {
+                cfile.delay_expr(format!("(v{}-{})", var_id, val));
+            }
+            Bytecode::LtVarConst(var_id, val) => {
+                cfile.delay_expr(format!("(v{} < {})", var_id, val));
+            }
+            Bytecode::AssignVar(var_id) => {
+                let rhs = cfile.expression_stack.pop().unwrap();
+
+                cfile.codegen_stmt(&format!("v{} = {};\n", var_id, rhs));
+                if bc.needs_drop(fun.vars[*var_id].ty) {
+                    cfile.codegen_stmt(&format!("d{} = true;\n", var_id));
+                }
             }
             Bytecode::Call(definition_id) => {
                 if let Definition::Fun(ref fun) = bc.definitions[*definition_id] {
@@ -260,7 +398,13 @@ fn codegen_fn(
                     }
                     cfile.delay_expr(expr_string);
                 } else if let Definition::Struct(ref st) = bc.definitions[*definition_id] {
-                    let mut expr_string = format!("init_struct_{}(", definition_id);
+                    let struct_id = match instantiations {
+                        Some(instantiations) => {
+                            bc.find_substituted_type(*definition_id, instantiations)
+                        }
+                        None => *definition_id,
+                    };
+                    let mut expr_string = format!("init_struct_{}(", struct_id);
                     let expression_stack_len = cfile.expression_stack.len();
 
                     let mut offset = st.fields.len();
@@ -305,25 +449,23 @@ fn codegen_fn(
                     unimplemented!("Attempt to call unprocessed function");
                 }
             }
+            Bytecode::CallNative(native_id) => {
+                bail!(
+                    "Native function {} can't be compiled to C",
+                    bc.native_name(*native_id)
+                );
+            }
             Bytecode::If(_, ty) => {
                 let cond = cfile.expression_stack.pop().unwrap();
 
-                match *ty {
-                    builtin_type::U64
-                    | builtin_type::BOOL
-                    | builtin_type::U32
-                    | builtin_type::I64
-                    | builtin_type::I32
-                    | builtin_type::UNKNOWN_INT => {
-                        cfile.codegen_stmt(&format!(
-                            "{} t{};\n",
-                            codegen_type(bc, *ty, instantiations),
-                            next_temp_id
-                        ));
-                        temp_id_stack.push(next_temp_id);
-                        next_temp_id += 1;
-                    }
-                    _ => {}
+                if *ty != builtin_type::VOID {
+                    cfile.codegen_stmt(&format!(
+                        "{} t{};\n",
+                        codegen_type(bc, *ty, instantiations),
+                        next_temp_id
+                    ));
+                    temp_id_stack.push(next_temp_id);
+                    next_temp_id += 1;
                 }
 
                 cfile.codegen_stmt(&format!("if ({}) {{\n", cond));
@@ -364,11 +506,11 @@ fn codegen_fn(
                 let result = match *ty {
                     builtin_type::VOID => "DEBUG: <void>".into(),
                     builtin_type::UNKNOWN => "DEBUG: <unknown>".into(),
+                    builtin_type::U64 => format!("printf(\"DEBUG: %llu\\n\", ({}));\n", val),
+                    builtin_type::I64 => format!("printf(\"DEBUG: %lld\\n\", ({}));\n", val),
                     builtin_type::BOOL
                     | builtin_type::U32
-                    | builtin_type::U64
                     | builtin_type::I32
-                    | builtin_type::I64
                     | builtin_type::UNKNOWN_INT => {
                         format!("printf(\"DEBUG: %d\\n\", ({}));\n", val)
                     }
@@ -376,10 +518,50 @@ fn codegen_fn(
                 };
                 cfile.codegen_stmt(&result);
             }
+            Bytecode::ReadInput => {
+                cfile.delay_expr("peach_read_u64()".into());
+            }
+            Bytecode::ReadClock => {
+                cfile.delay_expr("peach_now_millis()".into());
+            }
+            Bytecode::RandomBelow => {
+                let bound = cfile.expression_stack.pop().unwrap();
+                cfile.delay_expr(format!("peach_random_below({})", bound));
+            }
+            Bytecode::ArgCount => {
+                cfile.delay_expr("peach_arg_count()".into());
+            }
+            Bytecode::Arg => {
+                let index = cfile.expression_stack.pop().unwrap();
+                cfile.delay_expr(format!("peach_arg({})", index));
+            }
+            Bytecode::Env(name) => {
+                // Names are checked to be plain identifiers when they're converted, so they can be quoted as they are
+                cfile.delay_expr(format!("peach_env(\"{}\")", name));
+            }
+            Bytecode::ReadFile(path) => {
+                cfile.delay_expr(format!("peach_read_file_u64({})", c_string(path)));
+            }
+            Bytecode::WriteFile(path) => {
+                let value = cfile.expression_stack.pop().unwrap();
+                cfile.codegen_stmt(&format!(
+                    "peach_write_file_u64({}, {});\n",
+                    c_string(path),
+                    value
+                ));
+            }
+            Bytecode::Panic(message) => {
+                cfile.codegen_stmt(&format!("peach_panic({});\n", c_string(message)));
+            }
+            Bytecode::Exit => {
+                let code = cfile.expression_stack.pop().unwrap();
+                cfile.codegen_stmt(&format!("exit({});\n", code));
+            }
         }
     }
 
     cfile.codegen_stmt("};\n");
+    Ok(())
 }
 
 fn codegen_c_prototype(
@@ -391,7 +573,7 @@ fn codegen_c_prototype(
 ) {
     if let Definition::Fun(ref fun) = bc.definitions[definition_id] {
         //Skip generic functions during codegen.  Instead, we'll codegen their instantiations
-        if definition_id != starting_fn_id {
+        if definition_id != starting_fn_id && fun.native_id.is_none() {
             match fun.extern_name {
                 Some(ref ex_name) => {
                     cfile.codegen_raw(&format!(
@@ -423,13 +605,21 @@ fn codegen_c_prototype(
 
             cfile.codegen_raw(");\n");
         }
-    } else if let Definition::Struct(_) = bc.definitions[definition_id] {
-        let struct_line = format!("struct struct_{};\n", definition_id);
-        cfile.codegen_raw(&struct_line);
+    } else if let Definition::Struct(ref st) = bc.definitions[definition_id] {
+        // Structs mentioning type variables are only laid out for their instances
+        if bc.contains_type_variable(definition_id) {
+            return;
+        }
+        let fields: Vec<String> = st
+            .fields
+            .iter()
+            .map(|field| format!("{} {}", codegen_type(bc, field.1, instantiations), field.0))
+            .collect();
         let struct_init_line = format!(
-            "{} init_struct_{}();\n",
+            "{} init_struct_{}({});\n",
             codegen_type(bc, definition_id, instantiations),
-            definition_id
+            definition_id,
+            fields.join(", ")
         );
         cfile.codegen_raw(&struct_init_line);
     } else if let Definition::InstantiatedFun(orig_id, ref instantiations) =
@@ -464,13 +654,13 @@ fn codegen_c_body(
     definition_id: DefinitionId,
     starting_fn_id: DefinitionId,
     instantiations: Option<&Vec<(DefinitionId, DefinitionId)>>,
-) {
+) -> Result<()> {
     // Only codegen definitions that we know, others may be types (and not have an associated codegen)
     if let Definition::Fun(ref fun) = bc.definitions[definition_id] {
         //Skip generic functions during codegen.  Instead, we'll codegen their instantiations
-        if fun.extern_name.is_none() {
+        if fun.extern_name.is_none() && fun.native_id.is_none() {
             if definition_id == starting_fn_id {
-                codegen_fn(cfile, bc, "main", fun, None);
+                codegen_fn(cfile, bc, "main", fun, None)?;
             } else {
                 codegen_fn(
                     cfile,
@@ -478,10 +668,13 @@ fn codegen_c_body(
                     &format!("fun_{}", definition_id),
                     fun,
                     instantiations,
-                );
+                )?;
             }
         }
     } else if let Definition::Struct(ref st) = bc.definitions[definition_id] {
+        if bc.contains_type_variable(definition_id) {
+            return Ok(());
+        }
         if st.fields.len() == 0 {
             cfile.codegen_raw(&format!(
                 "struct struct_{} {{int dummy;}};\n",
@@ -546,16 +739,55 @@ fn codegen_c_body(
                 &format!("fun_{}", definition_id),
                 fun,
                 Some(instantiations),
-            );
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Orders the structs so that each comes after the structs it contains by value
+fn struct_codegen_order(bc: &BytecodeEngine) -> Vec<DefinitionId> {
+    fn visit(bc: &BytecodeEngine, definition_id: DefinitionId, order: &mut Vec<DefinitionId>) {
+        if order.contains(&definition_id) {
+            return;
+        }
+        if let Definition::Struct(ref st) = bc.definitions[definition_id] {
+            for (_, field_ty) in &st.fields {
+                visit(bc, *field_ty, order);
+            }
+            order.push(definition_id);
         }
     }
+
+    let mut order = vec![];
+    for definition_id in 0..bc.definitions.len() {
+        visit(bc, definition_id, &mut order);
+    }
+    order
 }
 
-fn codegen_c_from_bytecode(bc: &BytecodeEngine) -> String {
+fn codegen_c_from_bytecode(bc: &BytecodeEngine, starting_fn_id: DefinitionId) -> Result<String> {
     let mut cfile = CFile::new();
 
-    cfile.codegen_raw("#include <stdio.h>\n");
-    cfile.codegen_raw("#include <stdbool.h>\n");
+    cfile.codegen_raw(RUNTIME);
+
+    // The runtime's generator starts from the configured seed, and its files are found under the configured root
+    let rng_state = match bc.config().random_seed {
+        Some(seed) => Rng::new(seed).state(),
+        None => 0,
+    };
+    cfile.codegen_raw(&format!(
+        "unsigned long long peach_rng_state = {}ULL;\n",
+        rng_state
+    ));
+    let file_root = match bc.config().file_root {
+        Some(ref root) => root.to_string_lossy().into_owned(),
+        None => String::new(),
+    };
+    cfile.codegen_raw(&format!(
+        "const char *peach_file_root = {};\n",
+        c_string(&file_root)
+    ));
     /* 
     // If we wanted checked add/sub we can use something like this:
     cfile.codegen_raw("#include <assert.h>\n");
@@ -573,7 +805,12 @@ fn codegen_c_from_bytecode(bc: &BytecodeEngine) -> String {
     cfile.codegen_raw("}\n");
     */
 
-    let starting_fn_id = bc.scopes[0].definitions["main"];
+    // Declare the structs before anything mentions them
+    for definition_id in struct_codegen_order(bc) {
+        if !bc.contains_type_variable(definition_id) {
+            cfile.codegen_raw(&format!("struct struct_{};\n", definition_id));
+        }
+    }
 
     //TODO: FIXME: just make two separate strings and concat them rather than iterating twice
     for definition_id in 0..bc.definitions.len() {
@@ -587,24 +824,61 @@ fn codegen_c_from_bytecode(bc: &BytecodeEngine) -> String {
         codegen_c_prototype(&mut cfile, bc, definition_id, starting_fn_id, None);
     }
 
+    // Structs come first, so that they're complete before anything uses them
+    for definition_id in struct_codegen_order(bc) {
+        codegen_c_body(&mut cfile, bc, definition_id, starting_fn_id, None)?;
+    }
+
     for definition_id in 0..bc.definitions.len() {
         // Skip generic functions during codegen, instead we'll output the instantitions when
         // we get to them
-        if let Definition::Fun(ref fun) = bc.definitions[definition_id] {
-            if fun.is_generic() {
-                continue;
-            }
+        match bc.definitions[definition_id] {
+            Definition::Fun(ref fun) if fun.is_generic() => continue,
+            Definition::Struct(_) => continue,
+            _ => {}
         }
-        codegen_c_body(&mut cfile, bc, definition_id, starting_fn_id, None)
+        codegen_c_body(&mut cfile, bc, definition_id, starting_fn_id, None)?;
     }
 
-    cfile.output_src
+    Ok(cfile.output_src)
+}
+
+/// Finds the function a translated program starts from.  The entry function is looked up at the top of the first
+/// file loaded, and it and everything it calls need to have been converted already.  It can't take parameters, as the
+/// translated `main` is given the program's arguments instead, and it returns nothing or an integer, which the program
+/// exits with.
+pub(crate) fn entry_fn_id(bc: &BytecodeEngine, entry_fn: &str) -> Result<DefinitionId> {
+    let fun = bc.get_fn(entry_fn, 0)?;
+    if fun.is_generic() {
+        bail!("The entry function {} can't be generic", entry_fn);
+    }
+    if !fun.params.is_empty() {
+        bail!("The entry function {} can't take parameters", entry_fn);
+    }
+    if fun.return_ty != builtin_type::VOID && !is_integer(fun.return_ty) {
+        bail!(
+            "The entry function {} has to return nothing or an integer, not {}",
+            entry_fn,
+            bc.ty(fun.return_ty)
+        );
+    }
+    match bc.get_defn(entry_fn, 0) {
+        Some((definition_id, _)) => Ok(definition_id),
+        None => bail!("Function {} could not be found", entry_fn),
+    }
+}
+
+/// Translates the program's bytecode to C, in which `entry_fn` becomes `main`.  The entry function is found as
+/// `entry_fn_id` describes.  The C only needs the C standard library, so any C compiler can build it.
+pub fn emit_c(bc: &BytecodeEngine, entry_fn: &str) -> Result<String> {
+    let starting_fn_id = entry_fn_id(bc, entry_fn)?;
+    codegen_c_from_bytecode(bc, starting_fn_id)
 }
 
 /// Compiles the project's bytecode to a give name.  
 /// Returns the location of the compiled binary.
-pub fn compile_bytecode(bc: &BytecodeEngine, output_fname: &str) -> ::std::io::Result<String> {
-    let output = codegen_c_from_bytecode(bc);
+pub fn compile_bytecode(bc: &BytecodeEngine, output_fname: &str) -> Result<String> {
+    let output = codegen_c_from_bytecode(bc, bc.scopes[0].definitions["main"])?;
 
     let path = {
         use std::fs::File;
@@ -624,7 +898,7 @@ pub fn compile_bytecode(bc: &BytecodeEngine, output_fname: &str) -> ::std::io::R
         path
     };
 
-    compile_file(path)
+    compile_file(path).map_err(|e| PeachError::Io(e.to_string()))
 }
 
 #[cfg(windows)]
@@ -0,0 +1,238 @@
+//! Coverage: which lines of a program ran during an evaluation, and how often, reported as annotated source or in
+//! the lcov format that coverage tools read
+use bytecode::{BytecodeEngine, Definition, DefinitionId};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+
+/// How often a function was called
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCoverage {
+    pub name: String,
+    /// The line the function is declared on
+    pub line: usize,
+    pub calls: u64,
+}
+
+/// What ran in one file of the program
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileCoverage {
+    /// The file, named as it is in `Location`s
+    pub file: String,
+    /// Where the file is on disk, for tools that read the report
+    pub path: PathBuf,
+    /// The lines that have instructions, in order, with how many times each ran.  A line ran as many times as its
+    /// instruction that ran the most.
+    pub lines: Vec<(usize, u64)>,
+    /// The functions declared in the file, in order
+    pub functions: Vec<FunctionCoverage>,
+    source: String,
+}
+
+impl FileCoverage {
+    /// How many times the line ran, or `None` if it has no instructions
+    pub fn hits(&self, line: usize) -> Option<u64> {
+        self.lines
+            .iter()
+            .find(|(number, _)| *number == line)
+            .map(|(_, hits)| *hits)
+    }
+
+    /// How many of the lines with instructions ran
+    pub fn lines_hit(&self) -> usize {
+        self.lines.iter().filter(|(_, hits)| *hits > 0).count()
+    }
+}
+
+/// Which lines ran during a covered evaluation, for each file of the program.  Functions that were converted are
+/// covered line by line, and those that were never converted, as nothing used them, are reported as never called.
+/// The prelude isn't covered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+    /// The files, sorted by name
+    pub files: Vec<FileCoverage>,
+}
+
+impl Coverage {
+    pub fn file(&self, file: &str) -> Option<&FileCoverage> {
+        self.files.iter().find(|coverage| coverage.file == file)
+    }
+
+    /// The source of each file, with how many times each line ran beside it.  Lines that never ran are marked with
+    /// `#####`, and lines without instructions are left blank.
+    ///
+    /// Eg)
+    /// ```text
+    /// debug01.rs: 6 of 6 lines ran
+    ///         1 | fn add(x: u64, y: u64) -> u64 {
+    ///         1 |     let sum = x + y;
+    ///         1 |     sum
+    ///           | }
+    /// ```
+    pub fn annotate(&self) -> String {
+        let mut output = String::new();
+        for file in &self.files {
+            output += &format!(
+                "{}: {} of {} lines ran\n",
+                file.file,
+                file.lines_hit(),
+                file.lines.len()
+            );
+            for (idx, text) in file.source.lines().enumerate() {
+                let count = match file.hits(idx + 1) {
+                    Some(0) => "#####".to_string(),
+                    Some(hits) => hits.to_string(),
+                    None => String::new(),
+                };
+                output += format!("{:>9} | {}", count, text).trim_end();
+                output.push('\n');
+            }
+            output.push('\n');
+        }
+        output
+    }
+
+    /// The coverage as an lcov tracefile, which tools like `genhtml` and editor extensions read
+    pub fn lcov(&self) -> String {
+        let mut output = String::new();
+        for file in &self.files {
+            output += &format!("TN:\nSF:{}\n", file.path.display());
+            for function in &file.functions {
+                output += &format!("FN:{},{}\n", function.line, function.name);
+            }
+            for function in &file.functions {
+                output += &format!("FNDA:{},{}\n", function.calls, function.name);
+            }
+            let functions_hit = file.functions.iter().filter(|f| f.calls > 0).count();
+            output += &format!("FNF:{}\nFNH:{}\n", file.functions.len(), functions_hit);
+            for (line, hits) in &file.lines {
+                output += &format!("DA:{},{}\n", line, hits);
+            }
+            output += &format!(
+                "LF:{}\nLH:{}\nend_of_record\n",
+                file.lines.len(),
+                file.lines_hit()
+            );
+        }
+        output
+    }
+}
+
+/// Counts what ran while a program is evaluated with coverage
+#[derive(Default)]
+pub(crate) struct CoverageRecorder {
+    /// How many times each instruction ran, by its function and offset
+    instructions: HashMap<(DefinitionId, usize), u64>,
+    calls: HashMap<DefinitionId, u64>,
+}
+
+impl CoverageRecorder {
+    pub(crate) fn count_instruction(&mut self, definition_id: Option<DefinitionId>, offset: usize) {
+        // Bytecode that isn't part of a function isn't part of any file
+        if let Some(definition_id) = definition_id {
+            *self
+                .instructions
+                .entry((definition_id, offset))
+                .or_insert(0) += 1;
+        }
+    }
+
+    pub(crate) fn count_call(&mut self, definition_id: DefinitionId) {
+        *self.calls.entry(definition_id).or_insert(0) += 1;
+    }
+
+    pub(crate) fn into_coverage(self, bc: &BytecodeEngine) -> Coverage {
+        // The prelude's functions, and the instances of its generic ones
+        let mut prelude: HashSet<DefinitionId> = bc.prelude.values().cloned().collect();
+        for (&(generic_id, _), &instance_id) in &bc.fn_instances {
+            if prelude.contains(&generic_id) {
+                prelude.insert(instance_id);
+            }
+        }
+
+        let mut lines: BTreeMap<String, BTreeMap<usize, u64>> = BTreeMap::new();
+        let mut functions: BTreeMap<String, Vec<FunctionCoverage>> = BTreeMap::new();
+        for (definition_id, definition) in bc.definitions.iter().enumerate() {
+            if prelude.contains(&definition_id) {
+                continue;
+            }
+            // Functions are named by their paths, so those of the same name in different modules can be told apart
+            let name = || {
+                bc.qualified_name(definition_id)
+                    .or_else(|| bc.definition_name(definition_id))
+                    .unwrap_or_else(|| format!("fun_{}", definition_id))
+            };
+            match definition {
+                Definition::Fun(fun) => {
+                    let location = match fun.location {
+                        Some(ref location) => location,
+                        None => continue,
+                    };
+                    for offset in 0..fun.bytecode.len() {
+                        if let Some(location) = fun.location_of(offset) {
+                            let hits = self
+                                .instructions
+                                .get(&(definition_id, offset))
+                                .cloned()
+                                .unwrap_or(0);
+                            let line = lines
+                                .entry(location.file)
+                                .or_default()
+                                .entry(location.line)
+                                .or_insert(0);
+                            *line = (*line).max(hits);
+                        }
+                    }
+                    functions
+                        .entry(location.file.clone())
+                        .or_default()
+                        .push(FunctionCoverage {
+                            name: name(),
+                            line: location.line,
+                            calls: self.calls.get(&definition_id).cloned().unwrap_or(0),
+                        });
+                }
+                // Generic functions are only converted for the types they're called with, which are covered instead
+                Definition::LazyFn(lazy_fn)
+                    if lazy_fn.decl.generics.type_params().next().is_none() =>
+                {
+                    if let Some(location) = bc.location(lazy_fn.decl.fn_token.0) {
+                        lines
+                            .entry(location.file.clone())
+                            .or_default()
+                            .entry(location.line)
+                            .or_insert(0);
+                        functions
+                            .entry(location.file)
+                            .or_default()
+                            .push(FunctionCoverage {
+                                name: name(),
+                                line: location.line,
+                                calls: 0,
+                            });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let files = lines
+            .into_iter()
+            .map(|(file, file_lines)| {
+                let mut file_functions = functions.remove(&file).unwrap_or_default();
+                file_functions
+                    .sort_by(|lhs, rhs| lhs.line.cmp(&rhs.line).then(lhs.name.cmp(&rhs.name)));
+                FileCoverage {
+                    path: match bc.project_root {
+                        Some(ref root) => root.join(&file),
+                        None => PathBuf::from(&file),
+                    },
+                    lines: file_lines.into_iter().collect(),
+                    functions: file_functions,
+                    source: bc.sources.get(&file).cloned().unwrap_or_default(),
+                    file,
+                }
+            })
+            .collect();
+        Coverage { files }
+    }
+}
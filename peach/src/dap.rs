@@ -0,0 +1,532 @@
+//! A debug adapter, which lets editors that speak the Debug Adapter Protocol run a program under the debugger: set
+//! breakpoints on its lines, step through it, and look at the variables of each call.
+//!
+//! The program is launched once the editor has sent both `launch` and `configurationDone`, and runs in the evaluator
+//! with a `Debugger` that hands the adapter every instruction.  The adapter stops when a line is reached that it was
+//! asked to stop at, and answers the editor's requests until it's told to go on.  What the program prints is sent
+//! to the editor as `output` events, and the program reads nothing, as the adapter's input is the editor's.
+use bytecode::{BytecodeEngine, Prelude};
+use debugger::{DebugCommand, Debugger, Pause};
+use error::{Location, PeachError, Result};
+use eval::{EvalEngine, Halt, Value};
+use json::{quote, JsonValue};
+use lsp::{read_message, write_message};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The adapter runs one program, on one thread
+const THREAD_ID: u32 = 1;
+
+/// What the program was launched with
+struct Launch {
+    /// The file or project directory to run
+    program: String,
+    args: Vec<String>,
+    stop_on_entry: bool,
+}
+
+/// Where the program should stop next, besides at breakpoints
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resume {
+    /// At the first line it reaches
+    Entry,
+    /// Only at breakpoints
+    Continue,
+    /// At the next line that's reached
+    StepIn,
+    /// At the next line of the call at this depth, or of one of its callers
+    Next(usize),
+    /// At the next line of a caller of the call at this depth
+    StepOut(usize),
+}
+
+/// A call the program is in the middle of, as it was when it last ran a line of it
+struct StackFrame {
+    function: String,
+    location: Option<Location>,
+    locals: Vec<(String, Value)>,
+}
+
+/// The state the adapter shares with the handler of its `Debugger`, which runs while the program does
+struct Session {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    /// The sequence number of the next message sent
+    seq: u64,
+    launch: Option<Launch>,
+    configured: bool,
+    /// Whether the program has been run, so it's only run once
+    launched: bool,
+    /// The lines to stop at, by the canonical path of their file
+    breakpoints: HashMap<PathBuf, Vec<usize>>,
+    /// The directory the program's locations are relative to, once it has loaded
+    root: Option<PathBuf>,
+    /// The calls the program is in, outermost first
+    frames: Vec<StackFrame>,
+    /// The call depth, file, and line of the last instruction, so the program only stops at the start of a line
+    last_line: Option<(usize, String, usize)>,
+    resume: Resume,
+    paused: bool,
+    disconnected: bool,
+}
+
+/// Serves one editor over a pair of streams, running the program it launches under the debugger
+///
+/// Eg)
+/// ```no_run
+/// extern crate peach;
+/// use peach::DebugAdapter;
+/// use std::io;
+///
+/// DebugAdapter::new(io::BufReader::new(io::stdin()), io::stdout())
+///     .serve()
+///     .unwrap();
+/// ```
+pub struct DebugAdapter {
+    session: Rc<RefCell<Session>>,
+}
+
+impl DebugAdapter {
+    pub fn new<R, W>(input: R, output: W) -> DebugAdapter
+    where
+        R: BufRead + 'static,
+        W: Write + 'static,
+    {
+        DebugAdapter {
+            session: Rc::new(RefCell::new(Session {
+                input: Box::new(input),
+                output: Box::new(output),
+                seq: 1,
+                launch: None,
+                configured: false,
+                launched: false,
+                breakpoints: HashMap::new(),
+                root: None,
+                frames: vec![],
+                last_line: None,
+                resume: Resume::Continue,
+                paused: false,
+                disconnected: false,
+            })),
+        }
+    }
+
+    /// Serves the editor until it disconnects, or its input ends
+    pub fn serve(&mut self) -> Result<()> {
+        loop {
+            let message = {
+                let mut session = self.session.borrow_mut();
+                if session.disconnected {
+                    break;
+                }
+                read_message(&mut session.input)
+                    .map_err(|e| PeachError::Io(format!("debug adapter: {}", e)))?
+            };
+            let message = match message {
+                Some(message) => message,
+                None => break,
+            };
+            self.session.borrow_mut().handle_message(&message);
+
+            let launch = {
+                let mut session = self.session.borrow_mut();
+                if session.configured && !session.launched && session.launch.is_some() {
+                    session.launched = true;
+                    session.launch.take()
+                } else {
+                    None
+                }
+            };
+            if let Some(launch) = launch {
+                self.run(launch);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the program under the debugger, then tells the editor how it exited
+    fn run(&mut self, launch: Launch) {
+        let bc = match load_program(&launch.program) {
+            Ok(bc) => bc,
+            Err(e) => {
+                let mut session = self.session.borrow_mut();
+                session.output("stderr", &format!("Error: {}\n", e));
+                session.exited(1);
+                return;
+            }
+        };
+        {
+            let mut session = self.session.borrow_mut();
+            for warning in bc.warnings() {
+                session.output("console", &format!("{}\n", warning));
+            }
+            session.root = bc.project_root.clone();
+            session.resume = if launch.stop_on_entry {
+                Resume::Entry
+            } else {
+                Resume::Continue
+            };
+        }
+
+        let mut ee = EvalEngine::new();
+        ee.output = Some(Box::new(OutputEvents(self.session.clone())));
+        ee.input = Some(Box::new(io::empty()));
+        ee.args = launch.args;
+        let session = self.session.clone();
+        // The adapter sees every instruction, and decides for itself where to stop
+        let mut debugger = Debugger::new(move |pause| session.borrow_mut().pause(pause));
+        debugger.step();
+        ee.debugger = Some(debugger);
+
+        let result = ee.eval_program_exit_code(&bc, "main");
+        let mut session = self.session.borrow_mut();
+        session.frames.clear();
+        let code = match result {
+            Ok(code) => code,
+            Err(_) if ee.halted == Some(Halt::Stopped) => 1,
+            Err(e) => {
+                session.output("stderr", &format!("Error: {}\n", e));
+                1
+            }
+        };
+        session.exited(code);
+    }
+}
+
+/// Loads the file or project and processes its main, like `peach run` does
+fn load_program(program: &str) -> Result<BytecodeEngine> {
+    let mut bc = BytecodeEngine::with_prelude(&Prelude::standard())?;
+    bc.load_path(program)?;
+    bc.process_fn("main", 0)?;
+    Ok(bc)
+}
+
+/// Sends what the program prints to the editor
+struct OutputEvents(Rc<RefCell<Session>>);
+
+impl Write for OutputEvents {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .borrow_mut()
+            .output("stdout", &String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Session {
+    /// Sends a message.  If it can't be sent, the editor has gone, and the session ends.
+    fn send(&mut self, message: String) {
+        if write_message(&mut self.output, &message).is_err() {
+            self.disconnected = true;
+        }
+    }
+
+    fn event(&mut self, event: &str, body: &str) {
+        let message = format!(
+            "{{\"seq\":{},\"type\":\"event\",\"event\":{},\"body\":{}}}",
+            self.seq,
+            quote(event),
+            body
+        );
+        self.seq += 1;
+        self.send(message);
+    }
+
+    fn respond(
+        &mut self,
+        request_seq: u64,
+        command: &str,
+        result: ::std::result::Result<String, String>,
+    ) {
+        let outcome = match result {
+            Ok(body) => format!("\"success\":true,\"body\":{}", body),
+            Err(message) => format!("\"success\":false,\"message\":{}", quote(&message)),
+        };
+        let message = format!(
+            "{{\"seq\":{},\"type\":\"response\",\"request_seq\":{},\"command\":{},{}}}",
+            self.seq,
+            request_seq,
+            quote(command),
+            outcome
+        );
+        self.seq += 1;
+        self.send(message);
+    }
+
+    fn output(&mut self, category: &str, output: &str) {
+        let body = format!(
+            "{{\"category\":{},\"output\":{}}}",
+            quote(category),
+            quote(output)
+        );
+        self.event("output", &body);
+    }
+
+    fn exited(&mut self, code: i32) {
+        self.event("exited", &format!("{{\"exitCode\":{}}}", code));
+        self.event("terminated", "{}");
+    }
+
+    /// Handles a message from the editor.  Only requests are answered, as the editor has nothing else to send.
+    fn handle_message(&mut self, message: &str) {
+        let message = match JsonValue::parse(message) {
+            Some(message) => message,
+            None => {
+                return self.output(
+                    "stderr",
+                    "The debug adapter was sent a message that isn't JSON\n",
+                )
+            }
+        };
+        if message.get("type").and_then(JsonValue::as_str) != Some("request") {
+            return;
+        }
+        let request_seq = message.get("seq").and_then(JsonValue::as_u64).unwrap_or(0);
+        let command = message
+            .get("command")
+            .and_then(JsonValue::as_str)
+            .unwrap_or("")
+            .to_string();
+        let args = message.get("arguments").unwrap_or(&JsonValue::Null);
+
+        let result = self.handle_request(&command, args);
+        self.respond(request_seq, &command, result);
+        if command == "initialize" {
+            self.event("initialized", "{}");
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        command: &str,
+        args: &JsonValue,
+    ) -> ::std::result::Result<String, String> {
+        match command {
+            "initialize" => Ok("{\"supportsConfigurationDoneRequest\":true}".to_string()),
+            "launch" => {
+                let program = match args.get("program").and_then(JsonValue::as_str) {
+                    Some(program) => program.to_string(),
+                    None => return Err("launch needs the program to run".to_string()),
+                };
+                let args_given = match args.get("args") {
+                    Some(JsonValue::Array(values)) => values
+                        .iter()
+                        .filter_map(|value| value.as_str().map(str::to_string))
+                        .collect(),
+                    _ => vec![],
+                };
+                self.launch = Some(Launch {
+                    program,
+                    args: args_given,
+                    stop_on_entry: args.get("stopOnEntry") == Some(&JsonValue::Bool(true)),
+                });
+                Ok("{}".to_string())
+            }
+            "configurationDone" => {
+                self.configured = true;
+                Ok("{}".to_string())
+            }
+            "setBreakpoints" => {
+                let path = match args
+                    .get("source")
+                    .and_then(|source| source.get("path"))
+                    .and_then(JsonValue::as_str)
+                {
+                    Some(path) => Path::new(path),
+                    None => return Err("setBreakpoints needs the path of the source".to_string()),
+                };
+                let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+                let lines: Vec<usize> = match args.get("breakpoints") {
+                    Some(JsonValue::Array(breakpoints)) => breakpoints
+                        .iter()
+                        .filter_map(|breakpoint| breakpoint.get("line")?.as_u64())
+                        .map(|line| line as usize)
+                        .collect(),
+                    _ => vec![],
+                };
+                let verified: Vec<String> = lines
+                    .iter()
+                    .map(|line| format!("{{\"verified\":true,\"line\":{}}}", line))
+                    .collect();
+                self.breakpoints.insert(path, lines);
+                Ok(format!("{{\"breakpoints\":[{}]}}", verified.join(",")))
+            }
+            "setExceptionBreakpoints" => Ok("{}".to_string()),
+            "threads" => Ok(format!(
+                "{{\"threads\":[{{\"id\":{},\"name\":\"main\"}}]}}",
+                THREAD_ID
+            )),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => {
+                let frame_id = args.get("frameId").and_then(JsonValue::as_u64).unwrap_or(0);
+                // Each frame has one scope, its locals, which is referred to by the frame's id plus one
+                Ok(format!(
+                    "{{\"scopes\":[{{\"name\":\"Locals\",\"variablesReference\":{},\"expensive\":false}}]}}",
+                    frame_id + 1
+                ))
+            }
+            "variables" => {
+                let reference = args
+                    .get("variablesReference")
+                    .and_then(JsonValue::as_u64)
+                    .unwrap_or(0) as usize;
+                let variables: Vec<String> = match self.frames.get(reference.wrapping_sub(1)) {
+                    Some(frame) => frame
+                        .locals
+                        .iter()
+                        .map(|(name, value)| {
+                            format!(
+                                "{{\"name\":{},\"value\":{},\"variablesReference\":0}}",
+                                quote(name),
+                                quote(&value.to_string())
+                            )
+                        })
+                        .collect(),
+                    None => vec![],
+                };
+                Ok(format!("{{\"variables\":[{}]}}", variables.join(",")))
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                if !self.paused {
+                    return Err("The program is not stopped".to_string());
+                }
+                let depth = self.frames.len().saturating_sub(1);
+                self.resume = match command {
+                    "continue" => Resume::Continue,
+                    "next" => Resume::Next(depth),
+                    "stepIn" => Resume::StepIn,
+                    _ => Resume::StepOut(depth),
+                };
+                self.paused = false;
+                if command == "continue" {
+                    Ok("{\"allThreadsContinued\":true}".to_string())
+                } else {
+                    Ok("{}".to_string())
+                }
+            }
+            "disconnect" | "terminate" => {
+                self.disconnected = true;
+                self.paused = false;
+                Ok("{}".to_string())
+            }
+            _ => Err(format!("Unknown command {}", command)),
+        }
+    }
+
+    /// The calls the program is in, innermost first, with the frame ids `scopes` is asked for
+    fn stack_trace(&self) -> String {
+        let frames: Vec<String> = self
+            .frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(frame_id, frame)| {
+                let position = match frame.location {
+                    Some(ref location) => {
+                        let path = match self.root {
+                            Some(ref root) => root.join(&location.file),
+                            None => PathBuf::from(&location.file),
+                        };
+                        format!(
+                            "\"line\":{},\"column\":{},\"source\":{{\"name\":{},\"path\":{}}}",
+                            location.line,
+                            location.column,
+                            quote(&location.file),
+                            quote(&path.to_string_lossy())
+                        )
+                    }
+                    None => "\"line\":0,\"column\":0".to_string(),
+                };
+                format!(
+                    "{{\"id\":{},\"name\":{},{}}}",
+                    frame_id,
+                    quote(&frame.function),
+                    position
+                )
+            })
+            .collect();
+        format!(
+            "{{\"stackFrames\":[{}],\"totalFrames\":{}}}",
+            frames.join(","),
+            frames.len()
+        )
+    }
+
+    /// Called before each instruction the program runs.  Stops the program if it's at the start of a line it should
+    /// stop at, and answers the editor until it's told to go on.
+    fn pause(&mut self, pause: &Pause) -> DebugCommand {
+        if self.disconnected {
+            return DebugCommand::Stop;
+        }
+
+        // Calls that returned are dropped, and calls that were made are added
+        self.frames.truncate(pause.depth + 1);
+        while self.frames.len() <= pause.depth {
+            self.frames.push(StackFrame {
+                function: String::new(),
+                location: None,
+                locals: vec![],
+            });
+        }
+        let frame = &mut self.frames[pause.depth];
+        frame.function = pause.function.clone();
+        frame.locals = pause.locals.clone();
+        if pause.location.is_some() {
+            frame.location = pause.location.clone();
+        }
+
+        let line = pause
+            .location
+            .as_ref()
+            .map(|location| (pause.depth, location.file.clone(), location.line));
+        let reached_line = line.is_some() && line != self.last_line;
+        self.last_line = line;
+        if !reached_line {
+            return DebugCommand::Step;
+        }
+
+        let at_breakpoint = match (&pause.location, &self.root) {
+            (Some(location), Some(root)) => self
+                .breakpoints
+                .get(&root.join(&location.file))
+                .is_some_and(|lines| lines.contains(&location.line)),
+            _ => false,
+        };
+        let reason = match self.resume {
+            _ if at_breakpoint => "breakpoint",
+            Resume::Entry => "entry",
+            Resume::StepIn => "step",
+            Resume::Next(depth) if pause.depth <= depth => "step",
+            Resume::StepOut(depth) if pause.depth < depth => "step",
+            _ => return DebugCommand::Step,
+        };
+
+        self.event(
+            "stopped",
+            &format!(
+                "{{\"reason\":{},\"threadId\":{},\"allThreadsStopped\":true}}",
+                quote(reason),
+                THREAD_ID
+            ),
+        );
+        self.paused = true;
+        while self.paused && !self.disconnected {
+            match read_message(&mut self.input) {
+                Ok(Some(message)) => self.handle_message(&message),
+                _ => self.disconnected = true,
+            }
+        }
+        if self.disconnected {
+            DebugCommand::Stop
+        } else {
+            DebugCommand::Step
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! A debugger for the evaluator.  Evaluation pauses before instructions at breakpoints, or before every instruction
+//! while stepping, and the host's handler inspects the paused state and decides how to go on.
+use bytecode::Bytecode;
+use error::{BacktraceFrame, Location};
+use eval::Value;
+
+/// Where evaluation pauses
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+    /// Before the instruction at `offset` in the function's bytecode
+    Offset { function: String, offset: usize },
+    /// Before the first instruction of the source line, each time the line is reached
+    Line { file: String, line: usize },
+}
+
+/// How evaluation goes on after a pause
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugCommand {
+    /// Runs one instruction, then pauses again
+    Step,
+    /// Runs until the next breakpoint
+    Continue,
+    /// Stops evaluating, which halts with `Halt::Stopped`
+    Stop,
+}
+
+/// The state of the evaluator where it paused, before the instruction runs
+#[derive(Debug)]
+pub struct Pause<'a> {
+    /// The function being run, or `{block}` for bytecode that isn't part of a function
+    pub function: String,
+    /// The index of the instruction in the function's bytecode
+    pub offset: usize,
+    pub instruction: &'a Bytecode,
+    /// Where in the source the instruction came from, when that's known
+    pub location: Option<Location>,
+    /// How many calls are waiting for the current one to return
+    pub depth: usize,
+    /// The variables of the current function that hold values, in declaration order
+    pub locals: Vec<(String, Value)>,
+    /// The full value stack, bottom first
+    pub stack: &'a [Value],
+}
+
+/// Breakpoints, and the handler that's called when evaluation pauses.  Set it as the `EvalEngine::debugger` to debug
+/// the programs the engine runs.
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    stepping: bool,
+    handler: Box<dyn FnMut(&Pause) -> DebugCommand>,
+    /// The call depth, file, and line of the last instruction, so that line breakpoints only pause when their line is
+    /// reached, rather than before each of its instructions
+    last_line: Option<(usize, String, usize)>,
+}
+
+impl Debugger {
+    pub fn new<F>(handler: F) -> Debugger
+    where
+        F: FnMut(&Pause) -> DebugCommand + 'static,
+    {
+        Debugger {
+            breakpoints: vec![],
+            stepping: false,
+            handler: Box::new(handler),
+            last_line: None,
+        }
+    }
+
+    /// Pauses before the instruction at `offset` in the named function
+    pub fn break_at_offset(&mut self, function: &str, offset: usize) {
+        self.add_breakpoint(Breakpoint::Offset {
+            function: function.to_string(),
+            offset,
+        });
+    }
+
+    /// Pauses each time the line of the file is reached.  The file is named as it is in `Location`s.
+    pub fn break_at_line(&mut self, file: &str, line: usize) {
+        self.add_breakpoint(Breakpoint::Line {
+            file: file.to_string(),
+            line,
+        });
+    }
+
+    fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// Removes the breakpoint.  Returns false if there wasn't one.
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|x| x != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Pauses before the next instruction that's evaluated, like the first instruction of the next program
+    pub fn step(&mut self) {
+        self.stepping = true;
+    }
+
+    /// Whether evaluation should pause before the instruction described by `frame`, which is `depth` calls deep
+    pub(crate) fn should_pause(&mut self, frame: &BacktraceFrame, depth: usize) -> bool {
+        let line = frame
+            .location
+            .as_ref()
+            .map(|location| (depth, location.file.clone(), location.line));
+        let reached_line = line.is_some() && line != self.last_line;
+        self.last_line = line;
+
+        self.stepping
+            || self.breakpoints.iter().any(|breakpoint| match breakpoint {
+                Breakpoint::Offset { function, offset } => {
+                    *function == frame.function && *offset == frame.offset
+                }
+                Breakpoint::Line { file, line } => {
+                    reached_line
+                        && frame.location.as_ref().is_some_and(|location| {
+                            location.file == *file && location.line == *line
+                        })
+                }
+            })
+    }
+
+    /// Hands the paused state to the handler, and returns how it wants evaluation to go on
+    pub(crate) fn pause(&mut self, pause: &Pause) -> DebugCommand {
+        let command = (self.handler)(pause);
+        self.stepping = command == DebugCommand::Step;
+        command
+    }
+}
@@ -0,0 +1,120 @@
+//! Graphviz DOT output, for looking at what the engine has converted
+use bytecode::{Bytecode, BytecodeEngine, Cfg, Definition, DefinitionId, Fun};
+
+/// Escapes a string and wraps it in quotes so it can be used as a DOT id or label.  Each line break ends a
+/// left-justified line, so a label ends with one if its last line is to be left-justified too.
+pub(crate) fn quote(s: &str) -> String {
+    let mut output = String::with_capacity(s.len() + 2);
+    output.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\l"),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+impl BytecodeEngine {
+    /// Draws the functions the engine knows about as a DOT graph, with an edge from each function that has been
+    /// processed to each function it calls.  Functions that are still lazy, because nothing processed has needed
+    /// them, are drawn dashed.  Functions in modules that haven't been processed yet aren't known, so they're left
+    /// out.  Calls to instances of generic functions are drawn to the generic function.
+    pub fn call_graph_dot(&self) -> String {
+        let name = |definition_id: DefinitionId| {
+            self.qualified_name(definition_id)
+                .unwrap_or_else(|| format!("{{fn {}}}", definition_id))
+        };
+        // Calls also construct structs, which aren't drawn
+        let callee = |definition_id: DefinitionId| match self.definitions[definition_id] {
+            Definition::Fun(_) => Some(definition_id),
+            Definition::InstantiatedFun(generic_id, _) => Some(generic_id),
+            _ => None,
+        };
+
+        let mut output = String::from("digraph calls {\n");
+        for (definition_id, definition) in self.definitions.iter().enumerate() {
+            match definition {
+                Definition::Fun(_) => {
+                    output += &format!(
+                        "    n{} [label={}];\n",
+                        definition_id,
+                        quote(&name(definition_id))
+                    );
+                }
+                Definition::LazyFn(_) => {
+                    output += &format!(
+                        "    n{} [label={}, style=dashed];\n",
+                        definition_id,
+                        quote(&name(definition_id))
+                    );
+                }
+                _ => {}
+            }
+        }
+        for (definition_id, definition) in self.definitions.iter().enumerate() {
+            if let Definition::Fun(fun) = definition {
+                let mut callees = vec![];
+                for code in &fun.bytecode {
+                    if let Some(target) = match code {
+                        Bytecode::Call(target) => callee(*target),
+                        _ => None,
+                    } {
+                        if !callees.contains(&target) {
+                            callees.push(target);
+                        }
+                    }
+                }
+                for target in callees {
+                    output += &format!("    n{} -> n{};\n", definition_id, target);
+                }
+            }
+        }
+        output.push_str("}\n");
+        output
+    }
+}
+
+impl Fun {
+    /// Draws the control-flow graph of the function's bytecode as a DOT graph.  Each basic block lists its
+    /// instructions with their indices, and the edges out of an if or a while condition are labeled with the value of
+    /// the condition that takes them.
+    pub fn to_dot(&self) -> String {
+        let cfg = Cfg::new(self);
+        let mut output = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+        for (block_id, block) in cfg.blocks.iter().enumerate() {
+            let label = if block.code.is_empty() {
+                match block.successors.len() {
+                    0 => "exit\n".to_string(),
+                    _ => "entry\n".to_string(),
+                }
+            } else {
+                block
+                    .code
+                    .iter()
+                    .enumerate()
+                    .map(|(offset, code)| format!("{}: {:?}\n", block.start + offset, code))
+                    .collect()
+            };
+            output += &format!("    b{} [label={}];\n", block_id, quote(&label));
+        }
+        for (block_id, block) in cfg.blocks.iter().enumerate() {
+            let conditional = matches!(
+                block.terminator(),
+                Some(Bytecode::If(..)) | Some(Bytecode::WhileCond(_))
+            );
+            for (idx, successor) in block.successors.iter().enumerate() {
+                output += &match (conditional, idx) {
+                    (true, 0) => format!("    b{} -> b{} [label=true];\n", block_id, successor),
+                    (true, _) => format!("    b{} -> b{} [label=false];\n", block_id, successor),
+                    _ => format!("    b{} -> b{};\n", block_id, successor),
+                };
+            }
+        }
+        output.push_str("}\n");
+        output
+    }
+}
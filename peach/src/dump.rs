@@ -0,0 +1,406 @@
+//! Text dumps of bytecode, for debugging conversion and for seeing how programs are lowered
+use bytecode::{builtin_type, Bytecode, BytecodeEngine, Definition, DefinitionId, Fun, LazyFn};
+use error::{Location, Result};
+use std::mem;
+
+impl BytecodeEngine {
+    /// Lists the function's instructions, each with the types on the stack before and after it, bottom first.  The
+    /// types are followed the way the typechecker follows them: variables aren't on the stack once they're declared,
+    /// and each arm of an if starts from the stack the if started with.  Values whose types can't be followed are
+    /// shown as `{error}`.
+    ///
+    /// Eg)
+    /// ```text
+    ///    0: PushU64(3)     [] -> [u64]
+    ///    1: VarDecl(0)     [u64] -> []
+    ///    2: Var(0)         [] -> [u64]
+    /// ```
+    pub fn typed_listing(&self, fun: &Fun) -> String {
+        let names = |tys: &[DefinitionId]| {
+            tys.iter()
+                .map(|ty| self.ty(*ty).to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut stacks = vec![];
+        self.follow_types(&fun.bytecode, &fun.vars, |_, stack| {
+            stacks.push(names(stack))
+        });
+
+        let codes: Vec<String> = fun
+            .bytecode
+            .iter()
+            .map(|code| format!("{:?}", code))
+            .collect();
+        let width = codes.iter().map(|code| code.len()).max().unwrap_or(0);
+
+        let mut output = String::new();
+        let mut before = String::new();
+        for (idx, (code, after)) in codes.iter().zip(stacks).enumerate() {
+            output += &format!(
+                "{:>4}: {:<width$}  [{}] -> [{}]\n",
+                idx,
+                code,
+                before,
+                after,
+                width = width
+            );
+            before = after;
+        }
+        output
+    }
+
+    /// The typed listing of every function that has been processed, each after a line with its name.  Extern and
+    /// native functions have no bytecode, so they're left out.
+    pub fn typed_listings(&self) -> String {
+        let mut listings = vec![];
+        for (definition_id, definition) in self.definitions.iter().enumerate() {
+            if let Definition::Fun(fun) = definition {
+                if fun.extern_name.is_some() || fun.native_id.is_some() {
+                    continue;
+                }
+                let name = self
+                    .qualified_name(definition_id)
+                    .unwrap_or_else(|| format!("{{fn {}}}", definition_id));
+                listings.push(format!("fn {}:\n{}", name, self.typed_listing(fun)));
+            }
+        }
+        listings.join("\n")
+    }
+
+    /// The syntax tree syn parsed the function in the root scope into, whether or not the function has been
+    /// processed
+    pub fn dump_ast(&self, fn_name: &str) -> Result<String> {
+        let (definition_id, _) = self.find_fn(fn_name)?;
+        Ok(format!("{:#?}\n", self.syntax_of(fn_name, definition_id)?))
+    }
+
+    /// The bytecode each statement of the function in the root scope is converted to, before it's checked and
+    /// optimized.  The function is converted again from its syntax, which is kept once it's been processed, so this
+    /// shows what conversion produces even when a later step goes wrong.  Each statement starts with its location
+    /// and the line of code it starts on, and the statements in it, like those in the body of a while, are indented
+    /// below it with their instructions.
+    ///
+    /// Eg)
+    /// ```text
+    /// // main.rs:2:5  let x = 3;
+    ///    0: PushUnknownInt(3)
+    ///    1: VarDecl(0)
+    /// // main.rs:3:5  if x < 5 {
+    ///    2: Var(0)
+    ///    3: PushUnknownInt(5)
+    ///    4: Lt
+    ///    5: If(2, 0)
+    ///    // main.rs:4:9  println!("{}", x);
+    ///       6: Var(0)
+    ///       7: DebugPrint
+    ///    8: ReturnVoid
+    /// ```
+    pub fn dump_lowering(&mut self, fn_name: &str) -> Result<String> {
+        let (definition_id, scope_id) = self.find_fn(fn_name)?;
+        let syntax = self.syntax_of(fn_name, definition_id)?.clone();
+
+        // The function stands in as its syntax while it's converted, then whatever it was is put back.  Its warnings
+        // were given when it was processed.
+        let definition = mem::replace(
+            &mut self.definitions[definition_id],
+            Definition::LazyFn(syntax),
+        );
+        let warnings = self.warnings.len();
+        let result = self.convert_fn_to_bytecode(definition_id, scope_id);
+        self.warnings.truncate(warnings);
+        self.definitions[definition_id] = definition;
+        let fun = self.take_errors(result)?;
+
+        // Statements are listed before those nested in them, which are recorded first
+        let mut stmts = fun.stmt_locations.clone();
+        stmts.sort_by(|lhs, rhs| {
+            lhs.0
+                .start
+                .cmp(&rhs.0.start)
+                .then(rhs.0.end.cmp(&lhs.0.end))
+        });
+        let depth_of = |idx: usize| {
+            stmts
+                .iter()
+                .filter(|(range, _)| range.contains(&idx))
+                .count()
+        };
+
+        let mut output = String::new();
+        for (idx, code) in fun.bytecode.iter().enumerate() {
+            for (range, location) in stmts.iter().filter(|(range, _)| range.start == idx) {
+                let depth = stmts
+                    .iter()
+                    .filter(|(outer, _)| outer.start <= range.start && outer.end >= range.end)
+                    .count();
+                output += &format!(
+                    "{}// {}  {}\n",
+                    "   ".repeat(depth - 1),
+                    location,
+                    self.source_line(location).unwrap_or("")
+                );
+            }
+            output += &format!(
+                "{}{:>4}: {:?}\n",
+                "   ".repeat(depth_of(idx).saturating_sub(1)),
+                idx,
+                code
+            );
+        }
+        Ok(output)
+    }
+
+    /// Lists the instructions of the processed function in the root scope, with the names of the functions, variables
+    /// and types they use in place of their ids.  Jumps are drawn as arrows to the left of the listing, and also give
+    /// the index they jump to.  Variables that share a name with another of the function's are told apart by their id,
+    /// like `x#2`.
+    ///
+    /// Eg)
+    /// ```text
+    ///         0: PushU64 2
+    ///         1: VarDecl x
+    /// +-->    2: BeginWhile
+    /// |       3: Var x
+    /// |       4: PushU64 10
+    /// |       5: Lt
+    /// | +-    6: WhileCond 12
+    /// | |     7: Var x
+    /// | |     8: Call double
+    /// | |     9: LValueVar x
+    /// | |    10: Assign
+    /// +-|-   11: EndWhile 2
+    ///   +>   12: ReturnVoid
+    /// ```
+    pub fn disassemble(&self, fn_name: &str) -> Result<String> {
+        let fun = self.get_fn(fn_name, 0)?;
+
+        let jumps: Vec<(usize, usize)> = fun
+            .bytecode
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, code)| jump_target(idx, code).map(|target| (idx, target)))
+            .collect();
+        let gutter = jump_gutter(&jumps, fun.bytecode.len());
+
+        let mut output = String::new();
+        for (idx, code) in fun.bytecode.iter().enumerate() {
+            let operands = self.operands(fun, idx, code);
+            let line = format!("{}{:>4}: {} {}", gutter[idx], idx, code.name(), operands);
+            output += line.trim_end();
+            output += "\n";
+        }
+        Ok(output)
+    }
+
+    /// Processes the function in the root scope, then gives its signature followed by its disassembly.  Neither
+    /// mentions ids, so the text only changes when the way the function is lowered does, which makes it suited to
+    /// golden files that lock in how code is lowered.
+    ///
+    /// Eg)
+    /// ```text
+    /// fn add(x: u64, y: u64) -> u64
+    ///    0: Var x
+    ///    1: Var y
+    ///    2: Add
+    ///    3: ReturnLastStackValue
+    /// ```
+    pub fn snapshot_fn(&mut self, fn_name: &str) -> Result<String> {
+        let definition_id = self.process_fn(fn_name, 0)?;
+        Ok(format!(
+            "{}\n{}",
+            self.describe(definition_id),
+            self.disassemble(fn_name)?
+        ))
+    }
+
+    /// The operands of the instruction, as `disassemble` shows them
+    fn operands(&self, fun: &Fun, idx: usize, code: &Bytecode) -> String {
+        let var = |var_id: usize| {
+            let ident = &fun.vars[var_id].ident;
+            if fun.vars.iter().filter(|var| var.ident == *ident).count() > 1 {
+                format!("{}#{}", ident, var_id)
+            } else {
+                ident.clone()
+            }
+        };
+        // The type an if gives, if it's an expression
+        let result_ty = |ty: DefinitionId| match ty {
+            builtin_type::VOID => String::new(),
+            ty => format!(": {}", self.ty(ty)),
+        };
+        let target = || jump_target(idx, code).unwrap_or(idx);
+
+        match code {
+            Bytecode::PushU64(value) => value.to_string(),
+            Bytecode::PushU32(value) => value.to_string(),
+            Bytecode::PushI64(value) => value.to_string(),
+            Bytecode::PushI32(value) => value.to_string(),
+            Bytecode::PushUnknownInt(value) => value.to_string(),
+            Bytecode::PushBool(value) => value.to_string(),
+            Bytecode::PushRawPtr(ptr) => format!("{:?}", ptr),
+            Bytecode::As(ty) | Bytecode::DebugPrint(ty) => self.ty(*ty).to_string(),
+            Bytecode::Borrow(true) => "mut".to_string(),
+            Bytecode::Dot(field) | Bytecode::LValueDot(field) => field.clone(),
+            Bytecode::VarDecl(var_id)
+            | Bytecode::VarDeclUninit(var_id)
+            | Bytecode::Var(var_id)
+            | Bytecode::Move(var_id)
+            | Bytecode::LValueVar(var_id)
+            | Bytecode::AssignVar(var_id) => var(*var_id),
+            Bytecode::AddVarConst(var_id, value)
+            | Bytecode::SubVarConst(var_id, value)
+            | Bytecode::LtVarConst(var_id, value) => format!("{} {}", var(*var_id), value),
+            Bytecode::DropVars(vars) => vars
+                .iter()
+                .map(|(var_id, ty)| format!("{}: {}", var(*var_id), self.ty(*ty)))
+                .collect::<Vec<_>>()
+                .join(", "),
+            Bytecode::Call(definition_id) => self.fn_name(*definition_id),
+            Bytecode::CallNative(native_id) => {
+                let native = self.definitions.iter().position(|definition| {
+                    matches!(definition, Definition::Fun(fun) if fun.native_id == Some(*native_id))
+                });
+                match native {
+                    Some(definition_id) => self.fn_name(definition_id),
+                    None => format!("{{native {}}}", native_id),
+                }
+            }
+            Bytecode::If(_, ty) | Bytecode::Else(_, ty) => {
+                format!("{}{}", target(), result_ty(*ty))
+            }
+            Bytecode::EndIf(ty) => result_ty(*ty).trim_start_matches(": ").to_string(),
+            Bytecode::WhileCond(_) | Bytecode::EndWhile(_) => target().to_string(),
+            Bytecode::Env(text)
+            | Bytecode::ReadFile(text)
+            | Bytecode::WriteFile(text)
+            | Bytecode::Panic(text) => format!("{:?}", text),
+            _ => String::new(),
+        }
+    }
+
+    /// The name of the function, with the type arguments of an instance of a generic function, like `max::<u64>`
+    fn fn_name(&self, definition_id: DefinitionId) -> String {
+        match self.definitions[definition_id] {
+            Definition::InstantiatedFun(generic_id, ref type_args) => format!(
+                "{}::<{}>",
+                self.fn_name(generic_id),
+                type_args
+                    .iter()
+                    .map(|(_, ty)| self.ty(*ty).to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            _ => self
+                .qualified_name(definition_id)
+                .unwrap_or_else(|| format!("{{fn {}}}", definition_id)),
+        }
+    }
+
+    fn find_fn(&self, fn_name: &str) -> Result<(DefinitionId, usize)> {
+        match self.get_defn(fn_name, 0) {
+            Some(found) => Ok(found),
+            None => bail!("Function {} could not be found", fn_name),
+        }
+    }
+
+    fn syntax_of(&self, fn_name: &str, definition_id: DefinitionId) -> Result<&LazyFn> {
+        match self.definitions[definition_id] {
+            Definition::LazyFn(ref syntax) => Ok(syntax),
+            Definition::Fun(_) => match self.fn_syntax.get(&definition_id) {
+                Some(syntax) => Ok(syntax),
+                None => bail!(
+                    "Function {} isn't written in Rust, so it has no syntax",
+                    fn_name
+                ),
+            },
+            _ => bail!("{} is not a function", fn_name),
+        }
+    }
+
+    /// The code on the line the location is in, from the location's column on
+    fn source_line(&self, location: &Location) -> Option<&str> {
+        let line = self
+            .sources
+            .get(&location.file)?
+            .lines()
+            .nth(location.line - 1)?;
+        // Columns count characters rather than bytes
+        let (start, _) = line.char_indices().nth(location.column - 1)?;
+        Some(line[start..].trim_end())
+    }
+}
+
+/// Where the instruction at `idx` jumps to, if it's a jump.  An if jumps when its condition is false, and a while's
+/// condition jumps past the end of the loop.
+fn jump_target(idx: usize, code: &Bytecode) -> Option<usize> {
+    match code {
+        Bytecode::If(offset, _) | Bytecode::Else(offset, _) => Some(idx + offset),
+        Bytecode::WhileCond(offset) => Some(idx + offset + 1),
+        Bytecode::EndWhile(offset) => Some(idx - offset),
+        _ => None,
+    }
+}
+
+/// Draws the jumps as arrows, giving the text to the left of each instruction.  Each jump has a lane of its own, with
+/// shorter jumps nearer the instructions, so the arrows of nested ifs and loops don't cross.
+fn jump_gutter(jumps: &[(usize, usize)], len: usize) -> Vec<String> {
+    let span = |&(from, to): &(usize, usize)| (from.min(to), from.max(to));
+
+    let mut by_length: Vec<&(usize, usize)> = jumps.iter().collect();
+    by_length.sort_by_key(|jump| {
+        let (start, end) = span(jump);
+        end - start
+    });
+    let mut lanes: Vec<Vec<(usize, usize)>> = vec![];
+    let mut laid_out = vec![];
+    for jump in by_length {
+        let (start, end) = span(jump);
+        let free = lanes.iter().position(|lane| {
+            lane.iter()
+                .all(|&(other_start, other_end)| end < other_start || start > other_end)
+        });
+        let lane = match free {
+            Some(lane) => lane,
+            None => {
+                lanes.push(vec![]);
+                lanes.len() - 1
+            }
+        };
+        lanes[lane].push((start, end));
+        laid_out.push((*jump, lane));
+    }
+
+    // Lanes take two columns each, with the innermost on the right
+    let width = lanes.len() * 2;
+    let mut rows = vec![vec![' '; width]; len];
+    for &(jump, lane) in &laid_out {
+        let (start, end) = span(&jump);
+        let column = width - 2 - lane * 2;
+        for row in &mut rows[start + 1..end] {
+            row[column] = '|';
+        }
+    }
+    for &((from, to), lane) in &laid_out {
+        let column = width - 2 - lane * 2;
+        for &row in &[from, to] {
+            rows[row][column] = '+';
+            for cell in &mut rows[row][column + 1..] {
+                if *cell == ' ' {
+                    *cell = '-';
+                }
+            }
+        }
+        rows[to][width - 1] = '>';
+    }
+
+    rows.into_iter()
+        .map(|row| {
+            let mut gutter: String = row.into_iter().collect();
+            if !gutter.is_empty() {
+                gutter.push(' ');
+            }
+            gutter
+        })
+        .collect()
+}
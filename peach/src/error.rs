@@ -0,0 +1,192 @@
+//! The errors reported when a program can't be loaded, converted to bytecode, or run, and the warnings about programs
+//! that can
+use bytecode::VerifyError;
+use std::error::Error;
+use std::fmt;
+use std::slice;
+
+/// A position in a source file.  Lines and columns both start at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Something in a program that is allowed, but is probably a mistake
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub message: String,
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some(ref location) => write!(f, "{}: warning: {}", location, self.message),
+            None => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+/// A call that was in progress when a program stopped running
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacktraceFrame {
+    /// The name of the function, or `{block}` for bytecode that isn't part of a function
+    pub function: String,
+    /// The index of the instruction that was running, in the function's bytecode
+    pub offset: usize,
+    /// Where in the source that instruction came from, when that's known
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some(ref location) => write!(f, "{} at {} ({})", self.function, self.offset, location),
+            None => write!(f, "{} at {}", self.function, self.offset),
+        }
+    }
+}
+
+/// Why a program stopped before it finished running
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The calls that were in progress, innermost first
+    pub peach_backtrace: Vec<BacktraceFrame>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "runtime error: {}", self.message)?;
+        for frame in &self.peach_backtrace {
+            write!(f, "\n    in {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why loading, converting, or running a program failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeachError {
+    /// A source file could not be read
+    Io(String),
+    /// The source is not valid Rust syntax
+    Parse(String),
+    /// The program fails to typecheck or borrow check, or uses something peach doesn't support.  Also has where in
+    /// the source the problem is, when that's known.
+    Conversion(String, Option<Location>),
+    /// Several independent problems were found in one run, in the order they were found
+    Multiple(Vec<PeachError>),
+    /// The program stopped while it was being evaluated
+    Runtime(RuntimeError),
+    /// The C compiler couldn't build the program into an executable.  Has what the compiler reported.
+    Build(String),
+    /// A function's bytecode isn't well-formed, so it can't be run
+    Verify(VerifyError),
+}
+
+impl PeachError {
+    /// Combines the errors found in a run into one.  A single error is returned as it is.  Errors that were found more
+    /// than once, like those in a function that's called from several statements, are only kept once.
+    pub(crate) fn from_errors(errors: Vec<PeachError>) -> PeachError {
+        let mut errors = errors.into_iter().fold(vec![], |mut unique, error| {
+            if !unique.contains(&error) {
+                unique.push(error);
+            }
+            unique
+        });
+        if errors.len() == 1 {
+            errors.remove(0)
+        } else {
+            PeachError::Multiple(errors)
+        }
+    }
+
+    /// The message of the error, or of the first error when there are several
+    pub fn message(&self) -> &str {
+        match self {
+            PeachError::Io(message)
+            | PeachError::Parse(message)
+            | PeachError::Conversion(message, _)
+            | PeachError::Build(message) => message,
+            PeachError::Multiple(errors) => errors[0].message(),
+            PeachError::Runtime(error) => &error.message,
+            PeachError::Verify(error) => &error.message,
+        }
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            PeachError::Conversion(_, location) => location.as_ref(),
+            PeachError::Multiple(errors) => errors[0].location(),
+            PeachError::Runtime(error) => error
+                .peach_backtrace
+                .first()
+                .and_then(|frame| frame.location.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// Each of the problems found, which is just this error unless there were several
+    pub fn errors(&self) -> &[PeachError] {
+        match self {
+            PeachError::Multiple(errors) => errors,
+            error => slice::from_ref(error),
+        }
+    }
+
+    /// Places the error at the given location, unless it already has one.  Errors are located as they pass out
+    /// through the syntax they occurred in, so the innermost location is kept.
+    pub(crate) fn at(self, location: Option<Location>) -> PeachError {
+        match self {
+            PeachError::Conversion(message, None) => PeachError::Conversion(message, location),
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for PeachError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeachError::Io(message) => write!(f, "io error: {}", message),
+            PeachError::Parse(message) => write!(f, "parse error: {}", message),
+            PeachError::Conversion(message, Some(location)) => {
+                write!(f, "{}: {}", location, message)
+            }
+            PeachError::Conversion(message, None) => write!(f, "{}", message),
+            PeachError::Multiple(errors) => {
+                let lines: Vec<String> = errors.iter().map(|x| x.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            PeachError::Runtime(error) => write!(f, "{}", error),
+            PeachError::Build(message) => write!(f, "build error: {}", message),
+            PeachError::Verify(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for PeachError {}
+
+pub type Result<T> = ::std::result::Result<T, PeachError>;
+
+/// Returns early with a `PeachError::Conversion` whose message is built from the format arguments
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(::error::PeachError::Conversion(format!($($arg)*), None))
+    };
+}
+
+/// Like `bail!`, but the first argument is the `Option<Location>` of the problem
+macro_rules! bail_at {
+    ($location:expr, $($arg:tt)*) => {
+        return Err(::error::PeachError::Conversion(format!($($arg)*), $location))
+    };
+}
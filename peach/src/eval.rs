@@ -1,9 +1,114 @@
-use bytecode::{builtin_type, Bytecode, BytecodeEngine, Definition, Fun};
+use bytecode::{
+    builtin_type, is_integer, Bytecode, BytecodeEngine, Definition, DefinitionId, Fun, VarStack,
+};
+use coverage::{Coverage, CoverageRecorder};
+use debugger::{DebugCommand, Debugger, Pause};
+use error::{BacktraceFrame, PeachError, Result, RuntimeError};
+use heap::{Handle, Heap, Place};
+use intern::{Interner, Symbol};
+#[cfg(feature = "jit")]
+use jit::{JitCall, JitProgram};
+use json;
+use profile::{Profile, Profiler};
+use replay::{self, RecordedInput, Recording, Replayer};
+use rng::Rng;
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::mem;
 use std::os::raw::c_void;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How many instructions are evaluated between checks of the deadline, as reading the clock is slow next to an
+/// instruction
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+/// How many variable maps are kept for reuse.  Calls deeper than this allocate their own, which are freed once they
+/// return.
+const LOOKUP_POOL_SIZE: usize = 64;
+
+/// Halts evaluation with a `Halt::Error` whose message is built from the format arguments
+macro_rules! fail {
+    ($engine:expr, $($arg:tt)*) => {{
+        $engine.halted = Some(Halt::Error(format!($($arg)*)));
+        break;
+    }};
+}
+
+/// Pushes the result of an integer operation, given as the checked result and the wrapped one.  If the operation
+/// overflowed, what happens depends on the engine's `OverflowPolicy`.
+macro_rules! push_int {
+    ($engine:expr, $variant:path, $checked:expr, $wrapped:expr, $op:expr) => {
+        match $checked {
+            Some(result) => $engine.value_stack.push($variant(result)),
+            None => match $engine.overflow {
+                OverflowPolicy::Error => fail!($engine, "attempt to {} with overflow", $op),
+                OverflowPolicy::Wrap => $engine.value_stack.push($variant($wrapped)),
+            },
+        }
+    };
+}
+
+/// Evaluates an arithmetic instruction on the top two values of the stack, which are integers of the same type, with
+/// the given checked and wrapping methods of the integer types
+macro_rules! int_binop {
+    ($engine:expr, $checked:ident, $wrapping:ident, $op:expr) => {
+        match $engine.pop_operands() {
+            (Some(Value::U64(rhs)), Some(Value::U64(lhs))) => {
+                push_int!(
+                    $engine,
+                    Value::U64,
+                    lhs.$checked(rhs),
+                    lhs.$wrapping(rhs),
+                    $op
+                )
+            }
+            (Some(Value::U32(rhs)), Some(Value::U32(lhs))) => {
+                push_int!(
+                    $engine,
+                    Value::U32,
+                    lhs.$checked(rhs),
+                    lhs.$wrapping(rhs),
+                    $op
+                )
+            }
+            (Some(Value::I64(rhs)), Some(Value::I64(lhs))) => {
+                push_int!(
+                    $engine,
+                    Value::I64,
+                    lhs.$checked(rhs),
+                    lhs.$wrapping(rhs),
+                    $op
+                )
+            }
+            (Some(Value::I32(rhs)), Some(Value::I32(lhs))) => {
+                push_int!(
+                    $engine,
+                    Value::I32,
+                    lhs.$checked(rhs),
+                    lhs.$wrapping(rhs),
+                    $op
+                )
+            }
+            (Some(Value::UnknownInt(rhs)), Some(Value::UnknownInt(lhs))) => {
+                push_int!(
+                    $engine,
+                    Value::UnknownInt,
+                    lhs.$checked(rhs),
+                    lhs.$wrapping(rhs),
+                    $op
+                )
+            }
+            (x, y) => fail!($engine, "Can't {} values of {:?} and {:?}", $op, x, y),
+        }
+    };
+}
 
 #[derive(Debug, Clone)]
 pub enum Value {
@@ -13,11 +118,10 @@ pub enum Value {
     I32(i32),
     UnknownInt(i32),
     Bool(bool),
-    Error,
     Void,
-    Object(HashMap<String, usize>),
+    Object(Handle), // a struct, whose fields are on the heap
     RawPtr(*const c_void),
-    Reference(usize), // reference into the value stack
+    Reference(Place),
 }
 
 impl fmt::Display for Value {
@@ -32,11 +136,10 @@ impl fmt::Display for Value {
                 Value::I32(x) => x.to_string(),
                 Value::UnknownInt(x) => x.to_string(),
                 Value::Bool(b) => b.to_string(),
-                Value::Error => "error".to_string(),
                 Value::Void => "void".to_string(),
-                Value::Object(dict) => format!("object: {:?}", dict),
+                Value::Object(handle) => format!("object: {:?}", handle),
                 Value::RawPtr(_p) => "{raw ptr}".to_string(),
-                Value::Reference(pos) => format!("reference: {}", pos),
+                Value::Reference(place) => format!("reference: {:?}", place),
             }
         )
     }
@@ -77,10 +180,292 @@ impl Value {
     }
 }
 
+impl Value {
+    fn to_json(&self) -> String {
+        match self {
+            Value::U64(x) => format!("{{\"type\":\"u64\",\"value\":{}}}", x),
+            Value::U32(x) => format!("{{\"type\":\"u32\",\"value\":{}}}", x),
+            Value::I64(x) => format!("{{\"type\":\"i64\",\"value\":{}}}", x),
+            Value::I32(x) => format!("{{\"type\":\"i32\",\"value\":{}}}", x),
+            Value::UnknownInt(x) => format!("{{\"type\":\"int\",\"value\":{}}}", x),
+            Value::Bool(b) => format!("{{\"type\":\"bool\",\"value\":{}}}", b),
+            Value::Void => "{\"type\":\"void\"}".to_string(),
+            Value::Object(handle) => format!("{{\"type\":\"object\",\"handle\":{}}}", handle.0),
+            Value::RawPtr(_p) => "{\"type\":\"raw_ptr\"}".to_string(),
+            Value::Reference(Place::Stack(slot)) => {
+                format!("{{\"type\":\"reference\",\"slot\":{}}}", slot)
+            }
+            Value::Reference(Place::Field(handle, field)) => format!(
+                "{{\"type\":\"reference\",\"handle\":{},\"field\":{}}}",
+                handle.0, field
+            ),
+        }
+    }
+}
+
+/// Limits on the size of values a program may build, and how deeply it may call.  Hosts that run untrusted programs
+/// can use these to keep a program from growing without bound.  A limit of `None` means unlimited.  Peach has no
+/// strings or Vecs, so there are no limits on their lengths.
+#[derive(Debug, Clone, Default)]
+pub struct ValueLimits {
+    /// The deepest that aggregates (eg structs) may nest inside of one another
+    pub max_aggregate_depth: Option<usize>,
+    /// The most values the value stack may hold, which bounds the locals and temporaries a program can use
+    pub max_stack_depth: Option<usize>,
+    /// The most calls that may be in progress at once
+    pub max_call_depth: Option<usize>,
+    /// The most objects the heap may hold, which bounds the structs a program can use.  Objects that can no longer
+    /// be reached are collected before this is checked, so they don't count towards it.
+    pub max_heap_objects: Option<usize>,
+}
+
+/// What happens when integer arithmetic overflows, like subtracting past zero with an unsigned type.  Dividing by zero
+/// is an error either way.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    /// Evaluation halts with a runtime error, as Rust's debug builds panic
+    #[default]
+    Error,
+    /// The result wraps around, as it does in Rust's release builds
+    Wrap,
+}
+
+/// A value limit that was exceeded during evaluation
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitError {
+    AggregateDepth { depth: usize, max: usize },
+    StackDepth { depth: usize, max: usize },
+    CallDepth { depth: usize, max: usize },
+    HeapObjects { objects: usize, max: usize },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitError::AggregateDepth { depth, max } => write!(
+                f,
+                "aggregate nesting depth of {} exceeds the limit of {}",
+                depth, max
+            ),
+            LimitError::StackDepth { depth, max } => write!(
+                f,
+                "value stack depth of {} exceeds the limit of {}",
+                depth, max
+            ),
+            LimitError::CallDepth { depth, max } => {
+                write!(f, "call depth of {} exceeds the limit of {}", depth, max)
+            }
+            LimitError::HeapObjects { objects, max } => {
+                write!(f, "{} heap objects exceeds the limit of {}", objects, max)
+            }
+        }
+    }
+}
+
+/// The reason evaluation stopped before the program finished
+#[derive(Debug, Clone, PartialEq)]
+pub enum Halt {
+    /// The step limit given when tracing was reached
+    StepLimit,
+    /// A value grew past one of the configured `ValueLimits`
+    LimitExceeded(LimitError),
+    /// The instructions allowed by `EvalEngine::fuel` were used up
+    OutOfFuel,
+    /// The host set the `EvalEngine::cancel` flag
+    Cancelled,
+    /// Evaluation was still running at the `EvalEngine::deadline`
+    TimedOut,
+    /// The program did something that can't be evaluated, like dividing by a value that isn't a number
+    Error(String),
+    /// The debugger's handler asked for evaluation to stop
+    Stopped,
+    /// The program called `exit` with this status
+    Exit(i32),
+}
+
+impl fmt::Display for Halt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Halt::StepLimit => write!(f, "the step limit was reached"),
+            Halt::LimitExceeded(err) => write!(f, "{}", err),
+            Halt::OutOfFuel => write!(f, "ran out of fuel"),
+            Halt::Cancelled => write!(f, "evaluation was cancelled"),
+            Halt::TimedOut => write!(f, "evaluation timed out"),
+            Halt::Error(message) => write!(f, "{}", message),
+            Halt::Stopped => write!(f, "evaluation was stopped by the debugger"),
+            Halt::Exit(code) => write!(f, "the program exited with status {}", code),
+        }
+    }
+}
+
+/// The machine state captured just before an instruction executes
+#[derive(Debug, Clone)]
+pub struct TraceStep {
+    /// The function the instruction belongs to
+    pub function: String,
+    /// The index of the instruction in its bytecode
+    pub offset: usize,
+    pub instruction: Bytecode,
+    /// The full value stack, bottom first
+    pub stack: Vec<Value>,
+    /// The variables visible in the current function as (name, stack slot), in declaration order
+    pub locals: Vec<(String, usize)>,
+}
+
+/// The version of the JSON produced by `Trace::to_json`.  It's bumped whenever the shape of the output changes.
+pub const TRACE_FORMAT_VERSION: u32 = 2;
+
+/// A structured record of how a program executed, one entry per instruction
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+    /// True if the step limit was reached before the program finished
+    pub truncated: bool,
+}
+
+impl Trace {
+    /// Serializes the trace to JSON, for front-ends that want to animate execution
+    ///
+    /// The format is `{"truncated": bool, "steps": [step]}` where each step is
+    /// `{"function": string, "offset": number, "instruction": string, "stack": [value], "locals": [{"name": string, "slot": number}]}`
+    /// and each value is an object with a `"type"` and, for scalars, a `"value"`.
+    pub fn to_json(&self) -> String {
+        let mut output = String::new();
+        output += &format!(
+            "{{\"version\":{},\"truncated\":{},\"steps\":[",
+            TRACE_FORMAT_VERSION, self.truncated
+        );
+        let mut first = true;
+        for step in &self.steps {
+            if !first {
+                output.push(',');
+            }
+            first = false;
+
+            let stack: Vec<String> = step.stack.iter().map(|v| v.to_json()).collect();
+            let locals: Vec<String> = step
+                .locals
+                .iter()
+                .map(|(name, slot)| format!("{{\"name\":{},\"slot\":{}}}", json::quote(name), slot))
+                .collect();
+
+            output += &format!(
+                "{{\"function\":{},\"offset\":{},\"instruction\":{},\"stack\":[{}],\"locals\":[{}]}}",
+                json::quote(&step.function),
+                step.offset,
+                json::quote(&format!("{:?}", step.instruction)),
+                stack.join(","),
+                locals.join(",")
+            );
+        }
+        output += "]}";
+        output
+    }
+}
+
+struct TraceFrame {
+    function: String,
+    var_names: Vec<String>,
+}
+
+struct Tracer {
+    max_steps: usize,
+    steps: Vec<TraceStep>,
+    frames: Vec<TraceFrame>,
+}
+
+/// Where an instruction is about to run, as an `EvalEngine::observer` sees it
+pub struct Frame<'a> {
+    /// The function the instruction belongs to, or `None` for a block that isn't part of a function.  Its name is
+    /// given by `BytecodeEngine::definition_name`.
+    pub definition_id: Option<DefinitionId>,
+    /// The index of the instruction in the function's bytecode
+    pub offset: usize,
+    /// How many calls are waiting for this one to return
+    pub depth: usize,
+    /// The stack slot of each of the function's variables that's in scope, keyed by its index in `Fun::vars`
+    pub locals: &'a HashMap<usize, usize>,
+    /// The full value stack, bottom first
+    pub stack: &'a [Value],
+}
+
+/// A function called before each instruction runs, with where it's running
+pub type Observer = Box<dyn FnMut(&Frame, &Bytecode)>;
+
+/// A function that's waiting for a function it called to return.  Calls are kept on this explicit stack rather than
+/// the host's, so how deeply programs can call is only bounded by `ValueLimits::max_call_depth`.
+struct Caller<'a> {
+    /// The function making the call, or `None` for a block that isn't part of a function
+    definition_id: Option<DefinitionId>,
+    bytecode: &'a [Bytecode],
+    /// The instruction to continue from once the call returns
+    idx: usize,
+    var_lookup: HashMap<usize, usize>,
+    /// Where the arguments of the call start on the value stack
+    base: usize,
+}
+
 pub struct EvalEngine {
     pub value_stack: Vec<Value>,
     extern_fns: HashMap<String, Box<Fn(&mut Vec<Value>) -> Value>>,
     pub debug_capture: Option<String>,
+    /// Where printed values are written, or `None` to write them to stdout.  `debug_capture` takes precedence when
+    /// it's set.
+    pub output: Option<Box<dyn Write>>,
+    /// Where `read_u64()` reads its input from, or `None` to read from stdin
+    pub input: Option<Box<dyn BufRead>>,
+    /// Gives the time in milliseconds for `now_millis()`, or `None` to use the system clock.  Hosts can set it to
+    /// keep runs reproducible.
+    pub clock: Option<Box<dyn FnMut() -> u64>>,
+    /// The arguments read by `arg_count()` and `arg()`, not including the program's name
+    pub args: Vec<String>,
+    /// The environment variables read by `env()`, or `None` to read the host's
+    pub env: Option<HashMap<String, String>>,
+    /// The generator for `random_below()`.  It's seeded from the `EngineConfig` when it's first used by a run.
+    rng: Option<Rng>,
+    /// Set when evaluation stopped early, with the reason it stopped
+    pub halted: Option<Halt>,
+    /// The calls that were in progress when evaluation halted, innermost first
+    backtrace: Vec<BacktraceFrame>,
+    pub limits: ValueLimits,
+    pub overflow: OverflowPolicy,
+    /// How many more instructions may be evaluated, or `None` for no limit.  Each instruction uses one, and evaluation
+    /// halts with `Halt::OutOfFuel` once there are none left, which keeps programs that don't terminate from hanging
+    /// the host.
+    pub fuel: Option<usize>,
+    /// Evaluation halts with `Halt::Cancelled` once this flag is set, so another thread (like a UI thread) can
+    /// interrupt it
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Evaluation halts with `Halt::TimedOut` if it's still running at this time
+    pub deadline: Option<Instant>,
+    /// Instructions evaluated since the deadline was last checked
+    steps_since_deadline_check: usize,
+    /// Instructions evaluated since the engine was created
+    instructions: u64,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    /// Counts the instructions and calls that ran, while covering a program or its tests
+    pub(crate) coverage: Option<CoverageRecorder>,
+    /// The values read from outside the engine, while a run is being recorded
+    recording: Option<Recording>,
+    /// Where the values read from outside the engine come from, while a run is being replayed
+    replayer: Option<Replayer>,
+    /// Pauses evaluation at breakpoints and while stepping, so the host can inspect it
+    pub debugger: Option<Debugger>,
+    /// Called before each instruction runs, for tools like coverage and visualizations that watch evaluation
+    pub observer: Option<Observer>,
+    /// Where struct values live.  Objects nothing on the value stack refers to are collected between instructions.
+    heap: Heap,
+    /// The names of the fields of objects on the heap
+    interner: Interner,
+    /// Places whose values have been moved out or dropped, and so must not be dropped again
+    moved_places: HashSet<Place>,
+    /// The cleared variable maps of calls which have returned, which later calls reuse rather than allocating their
+    /// own
+    lookup_pool: Vec<HashMap<usize, usize>>,
+    /// The functions compiled to native code by `eval_engine_jit`, which calls run instead of evaluating them
+    #[cfg(feature = "jit")]
+    pub(crate) jit: Option<JitProgram>,
 }
 
 impl EvalEngine {
@@ -89,133 +474,679 @@ impl EvalEngine {
             value_stack: vec![],
             extern_fns: HashMap::new(),
             debug_capture: None,
+            output: None,
+            input: None,
+            clock: None,
+            args: vec![],
+            env: None,
+            rng: None,
+            halted: None,
+            backtrace: vec![],
+            limits: ValueLimits::default(),
+            overflow: OverflowPolicy::default(),
+            fuel: None,
+            cancel: None,
+            deadline: None,
+            steps_since_deadline_check: 0,
+            instructions: 0,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            recording: None,
+            replayer: None,
+            debugger: None,
+            observer: None,
+            heap: Heap::new(),
+            interner: Interner::new(),
+            moved_places: HashSet::new(),
+            lookup_pool: vec![],
+            #[cfg(feature = "jit")]
+            jit: None,
+        }
+    }
+
+    /// Computes how deeply the given value nests aggregates.  Scalars have a depth of 0.
+    fn aggregate_depth(&self, value: &Value) -> usize {
+        match value {
+            Value::Object(handle) => {
+                let mut deepest = 0;
+                for (_, field) in self.heap.fields(*handle) {
+                    let depth = self.aggregate_depth(field);
+                    if depth > deepest {
+                        deepest = depth;
+                    }
+                }
+                deepest + 1
+            }
+            _ => 0,
+        }
+    }
+
+    /// Checks a newly built value against the configured limits, halting evaluation if one is exceeded.
+    /// Returns false if evaluation was halted.
+    fn check_limits(&mut self, value: &Value) -> bool {
+        if let Some(max) = self.limits.max_aggregate_depth {
+            let depth = self.aggregate_depth(value);
+            if depth > max {
+                self.halted = Some(Halt::LimitExceeded(LimitError::AggregateDepth {
+                    depth,
+                    max,
+                }));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Checks whether the host has cancelled evaluation or its deadline has passed, halting evaluation if so.
+    /// Returns false if evaluation was halted.
+    fn check_interrupts(&mut self) -> bool {
+        if let Some(ref cancel) = self.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                self.halted = Some(Halt::Cancelled);
+                return false;
+            }
+        }
+
+        if let Some(deadline) = self.deadline {
+            self.steps_since_deadline_check += 1;
+            if self.steps_since_deadline_check >= DEADLINE_CHECK_INTERVAL {
+                self.steps_since_deadline_check = 0;
+                if Instant::now() >= deadline {
+                    self.halted = Some(Halt::TimedOut);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Sets the deadline to the given time from now
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.deadline = Some(Instant::now() + timeout);
+        self.steps_since_deadline_check = 0;
+    }
+
+    /// Checks the size of the value stack against the configured limit, halting evaluation if it's exceeded.
+    /// Returns false if evaluation was halted.
+    fn check_stack_depth(&mut self) -> bool {
+        if let Some(max) = self.limits.max_stack_depth {
+            let depth = self.value_stack.len();
+            if depth > max {
+                self.halted = Some(Halt::LimitExceeded(LimitError::StackDepth { depth, max }));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Pushes the value in the given slot, then the integer constant, as the operands of a superinstruction
+    fn push_var_and_int(&mut self, slot: usize, val: i32) {
+        let value = self.value_stack[slot].clone();
+        self.value_stack.push(value);
+        self.value_stack.push(Value::UnknownInt(val));
+    }
+
+    /// Pops the operands of a binary operator, as (rhs, lhs).  An integer whose type was never pinned down takes
+    /// the type of the other operand, as it would have if the typechecker had inferred it.
+    fn pop_operands(&mut self) -> (Option<Value>, Option<Value>) {
+        let rhs = self.value_stack.pop();
+        let lhs = self.value_stack.pop();
+        match (rhs, lhs) {
+            (Some(Value::UnknownInt(rhs)), Some(lhs)) => {
+                let rhs = int_type(&lhs)
+                    .and_then(|ty| unknown_int_as(rhs, ty))
+                    .unwrap_or(Value::UnknownInt(rhs));
+                (Some(rhs), Some(lhs))
+            }
+            (Some(rhs), Some(Value::UnknownInt(lhs))) => {
+                let lhs = int_type(&rhs)
+                    .and_then(|ty| unknown_int_as(lhs, ty))
+                    .unwrap_or(Value::UnknownInt(lhs));
+                (Some(rhs), Some(lhs))
+            }
+            operands => operands,
+        }
+    }
+
+    /// Whether compiled code can run in place of evaluation, which can't be paused, stopped, or watched part way
+    /// through
+    #[cfg(feature = "jit")]
+    pub(crate) fn can_run_compiled(&self) -> bool {
+        self.fuel.is_none()
+            && self.cancel.is_none()
+            && self.deadline.is_none()
+            && self.tracer.is_none()
+            && self.profiler.is_none()
+            && self.coverage.is_none()
+            && self.recording.is_none()
+            && self.replayer.is_none()
+            && self.debugger.is_none()
+            && self.observer.is_none()
+    }
+
+    /// Checks the number of calls in progress against the configured limit, halting evaluation if it's exceeded.
+    /// Returns false if evaluation was halted.
+    fn check_call_depth(&mut self, depth: usize) -> bool {
+        if let Some(max) = self.limits.max_call_depth {
+            if depth > max {
+                self.halted = Some(Halt::LimitExceeded(LimitError::CallDepth { depth, max }));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Gives an object its own copy of its fields, so that it doesn't share them with the value it came from.  This
+    /// happens whenever an object is stored somewhere, as Copy structs are copied rather than moved.
+    fn copy_value(&mut self, value: Value) -> Value {
+        match value {
+            Value::Object(handle) => {
+                let fields = self.heap.fields(handle).to_vec();
+                let mut copy = vec![];
+                for (field, field_value) in fields {
+                    let field_value = self.copy_value(field_value);
+                    copy.push((field, field_value));
+                }
+                Value::Object(self.alloc_object(copy))
+            }
+            x => x,
+        }
+    }
+
+    /// Allocates an object on the heap.  If that takes the heap past its limit, evaluation halts before the next
+    /// instruction.
+    fn alloc_object(&mut self, fields: Vec<(Symbol, Value)>) -> Handle {
+        let handle = self.heap.alloc(fields);
+        if let Some(max) = self.limits.max_heap_objects {
+            let objects = self.heap.live();
+            if objects > max && self.halted.is_none() {
+                self.halted = Some(Halt::LimitExceeded(LimitError::HeapObjects {
+                    objects,
+                    max,
+                }));
+            }
+        }
+        handle
+    }
+
+    /// Formats the value like its `Debug` output, but with the fields of objects rather than their handles
+    fn debug_string(&self, value: &Value) -> String {
+        match value {
+            Value::Object(handle) => {
+                let fields: Vec<String> = self
+                    .heap
+                    .fields(*handle)
+                    .iter()
+                    .map(|(name, value)| {
+                        let name = self.interner.name(*name);
+                        format!("{:?}: {}", name, self.debug_string(value))
+                    })
+                    .collect();
+                format!("Object({{{}}})", fields.join(", "))
+            }
+            x => format!("{:?}", x),
+        }
+    }
+
+    /// The index of the named field in the object
+    fn field_index(&self, handle: Handle, field: &str) -> Option<usize> {
+        let symbol = self.interner.get(field)?;
+        self.heap.field_index(handle, symbol)
+    }
+
+    fn read_place(&self, place: Place) -> Option<&Value> {
+        match place {
+            Place::Stack(slot) => self.value_stack.get(slot),
+            Place::Field(handle, field) => self.heap.field(handle, field),
+        }
+    }
+
+    /// Stores the value at the place.  Returns false if the place no longer exists.
+    fn write_place(&mut self, place: Place, value: Value) -> bool {
+        let target = match place {
+            Place::Stack(slot) => self.value_stack.get_mut(slot),
+            Place::Field(handle, field) => self.heap.field_mut(handle, field),
+        };
+        match target {
+            Some(target) => {
+                *target = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the arguments and locals of a call which has returned, starting at `base`.  References may point into
+    /// the callee's part of the stack, so calls returning them leave it in place.
+    fn pop_frame(&mut self, base: usize, result: &Value) {
+        if let Value::Reference(_) = result {
+            return;
+        }
+        self.truncate_stack(base);
+    }
+
+    fn truncate_stack(&mut self, base: usize) {
+        self.value_stack.truncate(base);
+        self.moved_places.retain(|place| match place {
+            Place::Stack(slot) => *slot < base,
+            Place::Field(..) => true,
+        });
+    }
+
+    /// Frees the objects on the heap that nothing on the value stack refers to, directly or through other objects.
+    /// This happens between instructions as objects are allocated, but hosts can also collect when they like.
+    pub fn collect_garbage(&mut self) {
+        if self.heap.collect(&self.value_stack) > 0 {
+            let heap = &self.heap;
+            self.moved_places.retain(|place| match place {
+                Place::Field(handle, _) => heap.contains(*handle),
+                Place::Stack(_) => true,
+            });
+        }
+    }
+
+    /// The number of objects on the heap, including any that are unreachable but haven't been collected yet
+    pub fn heap_objects(&self) -> usize {
+        self.heap.live()
+    }
+
+    /// The number of instructions evaluated since the engine was created.  Functions run as native code by
+    /// `eval_engine_jit` aren't counted.
+    pub fn instructions_evaluated(&self) -> u64 {
+        self.instructions
+    }
+
+    /// The number of objects allocated on the heap since the engine was created, including those since collected
+    pub fn heap_allocations(&self) -> u64 {
+        self.heap.allocations()
+    }
+
+    /// Runs the drop glue for the value of type `ty` in the given place: first its Drop impl, then that of its
+    /// fields.  Places which have been moved out of (or were never initialized) are skipped.
+    fn drop_value(&mut self, bc: &BytecodeEngine, ty: DefinitionId, place: Place) -> Result<()> {
+        match self.read_place(place) {
+            None | Some(Value::Void) => return Ok(()),
+            Some(_) => {}
+        }
+        if self.moved_places.contains(&place) {
+            return Ok(());
+        }
+
+        if let Some(drop_fn) = bc.drop_fns.get(&ty) {
+            if let Definition::Fun(ref fun) = bc.definitions[*drop_fn] {
+                let base = self.value_stack.len();
+                self.value_stack.push(Value::Reference(place));
+                let result = self.eval_fn_bytecode(bc, *drop_fn, fun)?;
+                self.pop_frame(base, &result);
+            }
+        }
+
+        if let Definition::Struct(ref st) = bc.definitions[ty] {
+            if let Some(Value::Object(handle)) = self.read_place(place).cloned() {
+                for (field, (_, field_ty)) in st.fields.iter().enumerate() {
+                    if bc.needs_drop(*field_ty) {
+                        self.drop_value(bc, *field_ty, Place::Field(handle, field))?;
+                    }
+                }
+            }
+        }
+
+        self.moved_places.insert(place);
+        Ok(())
+    }
+
+    /// Records the current machine state before the instruction at `idx` runs.
+    /// Returns false if the step limit has been reached.
+    fn record_step(
+        &mut self,
+        idx: usize,
+        code: &Bytecode,
+        var_lookup: &HashMap<usize, usize>,
+    ) -> bool {
+        if let Some(ref mut tracer) = self.tracer {
+            if tracer.steps.len() >= tracer.max_steps {
+                return false;
+            }
+
+            let (function, locals) = match tracer.frames.last() {
+                Some(frame) => {
+                    let mut locals: Vec<(usize, usize)> = var_lookup
+                        .iter()
+                        .map(|(var_id, slot)| (*var_id, *slot))
+                        .collect();
+                    locals.sort();
+                    let locals = locals
+                        .into_iter()
+                        .map(|(var_id, slot)| {
+                            let name = match frame.var_names.get(var_id) {
+                                Some(name) => name.clone(),
+                                None => format!("v{}", var_id),
+                            };
+                            (name, slot)
+                        })
+                        .collect();
+                    (frame.function.clone(), locals)
+                }
+                None => ("{block}".to_string(), vec![]),
+            };
+
+            tracer.steps.push(TraceStep {
+                function,
+                offset: idx,
+                instruction: code.clone(),
+                stack: self.value_stack.clone(),
+                locals,
+            });
         }
+        true
+    }
+
+    /// Pauses in the debugger before the instruction at `idx` runs, if it's stepping or there's a breakpoint there.
+    /// Returns false if the debugger stopped evaluation.
+    fn debug_pause(
+        &mut self,
+        bc: &BytecodeEngine,
+        definition_id: Option<DefinitionId>,
+        idx: usize,
+        code: &Bytecode,
+        var_lookup: &HashMap<usize, usize>,
+        depth: usize,
+    ) -> bool {
+        let mut debugger = match self.debugger.take() {
+            Some(debugger) => debugger,
+            None => return true,
+        };
+
+        let frame = backtrace_frame(bc, definition_id, idx);
+        let command = if debugger.should_pause(&frame, depth) {
+            let mut locals: Vec<(usize, usize)> = var_lookup
+                .iter()
+                .filter(|(_, slot)| !self.moved_places.contains(&Place::Stack(**slot)))
+                .map(|(var_id, slot)| (*var_id, *slot))
+                .collect();
+            locals.sort();
+            let fun = definition_id.and_then(|definition_id| fun_of(bc, definition_id));
+            let var_names = match fun {
+                Some(fun) => fun.vars.iter().map(|v| v.ident.clone()).collect(),
+                None => vec![],
+            };
+            let locals = locals
+                .into_iter()
+                .filter_map(|(var_id, slot)| {
+                    let name = match var_names.get(var_id) {
+                        Some(name) => name.clone(),
+                        None => format!("v{}", var_id),
+                    };
+                    self.value_stack
+                        .get(slot)
+                        .map(|value| (name, value.clone()))
+                })
+                .collect();
+
+            debugger.pause(&Pause {
+                function: frame.function,
+                offset: idx,
+                instruction: code,
+                location: frame.location,
+                depth,
+                locals,
+                stack: &self.value_stack,
+            })
+        } else {
+            DebugCommand::Continue
+        };
+
+        self.debugger = Some(debugger);
+        command != DebugCommand::Stop
     }
 
+    /// Evaluates the bytecode of a block.  If evaluation halts, the error has the reason it halted and the calls that
+    /// were in progress.
     pub fn eval_block_bytecode(
         &mut self,
         bc: &BytecodeEngine,
         bytecode: &Vec<Bytecode>,
         var_lookup: &mut HashMap<usize, usize>,
-    ) -> Value {
-        let bytecode_len = bytecode.len();
+    ) -> Result<Value> {
+        self.eval_block_in(bc, None, bytecode, var_lookup)
+    }
+
+    /// Evaluates the bytecode of a block belonging to the given function, or to no function
+    fn eval_block_in(
+        &mut self,
+        bc: &BytecodeEngine,
+        definition_id: Option<DefinitionId>,
+        bytecode: &[Bytecode],
+        var_lookup: &mut HashMap<usize, usize>,
+    ) -> Result<Value> {
+        let mut calls = vec![];
+        let result = self.eval_calls(bc, definition_id, bytecode, var_lookup, &mut calls);
+
+        // If evaluation halted part way through calls, the block's variables are with the outermost caller, and the
+        // calls are still being traced
+        if let Some(ref mut tracer) = self.tracer {
+            let depth = tracer.frames.len() - calls.len();
+            tracer.frames.truncate(depth);
+        }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.unwind(calls.len());
+        }
+        let mut calls = calls.into_iter();
+        if let Some(frame) = calls.next() {
+            let callee_lookup = mem::replace(var_lookup, frame.var_lookup);
+            self.release_lookup(callee_lookup);
+        }
+        for frame in calls {
+            self.release_lookup(frame.var_lookup);
+        }
+        result
+    }
+
+    /// Evaluates the bytecode of a block, along with the functions it calls.  Calls are pushed onto `calls`, and
+    /// popped again as they return.
+    fn eval_calls<'a>(
+        &mut self,
+        bc: &'a BytecodeEngine,
+        definition_id: Option<DefinitionId>,
+        bytecode: &'a [Bytecode],
+        var_lookup: &mut HashMap<usize, usize>,
+        calls: &mut Vec<Caller<'a>>,
+    ) -> Result<Value> {
+        if self.halted.is_none() {
+            self.backtrace.clear();
+        }
+
+        let mut current_fn = definition_id;
+        let mut bytecode = bytecode;
         let mut idx = 0;
-        while idx < bytecode_len {
+        while idx < bytecode.len() {
             let code = &bytecode[idx];
+
+            if self.halted.is_some() {
+                break;
+            }
+            if !self.record_step(idx, code, var_lookup) {
+                self.halted = Some(Halt::StepLimit);
+                break;
+            }
+            if !self.debug_pause(bc, current_fn, idx, code, var_lookup, calls.len()) {
+                self.halted = Some(Halt::Stopped);
+                break;
+            }
+            if let Some(ref mut fuel) = self.fuel {
+                if *fuel == 0 {
+                    self.halted = Some(Halt::OutOfFuel);
+                    break;
+                }
+                *fuel -= 1;
+            }
+            if !self.check_interrupts() {
+                break;
+            }
+            if !self.check_stack_depth() {
+                break;
+            }
+            // Objects from earlier instructions that can no longer be reached don't count towards the heap's limit,
+            // so they're collected before an allocation could exceed it
+            let at_heap_limit = match self.limits.max_heap_objects {
+                Some(max) => self.heap.live() >= max && self.heap.allocated_since_collection(),
+                None => false,
+            };
+            if self.heap.needs_collection() || at_heap_limit {
+                self.collect_garbage();
+            }
+            self.instructions += 1;
+            if let Some(ref mut observer) = self.observer {
+                let frame = Frame {
+                    definition_id: current_fn,
+                    offset: idx,
+                    depth: calls.len(),
+                    locals: var_lookup,
+                    stack: &self.value_stack,
+                };
+                observer(&frame, code);
+            }
+            if let Some(ref mut profiler) = self.profiler {
+                profiler.count_instruction(current_fn, code.name());
+            }
+            if let Some(ref mut coverage) = self.coverage {
+                coverage.count_instruction(current_fn, idx);
+            }
+
+            // When replaying, the values that came from outside the engine come from the recording instead
+            let external = replay::nondeterministic(code);
+            if let (Some(instruction), Some(replayer)) = (external, self.replayer.as_mut()) {
+                let value = match replayer.next(instruction) {
+                    Ok(value) => value,
+                    Err(message) => fail!(self, "{}", message),
+                };
+                if let Bytecode::RandomBelow | Bytecode::Arg = code {
+                    self.value_stack.pop();
+                }
+                self.value_stack.push(Value::U64(value));
+                idx += 1;
+                continue;
+            }
+
+            if let Some(builtin) = bc.config().disallowed_builtin(code) {
+                fail!(
+                    self,
+                    "{} is not allowed by this engine's configuration",
+                    builtin
+                );
+            }
+
+            // Superinstructions push the operands of the instruction they end with, then run as that instruction
+            let code = match code {
+                Bytecode::AddVarConst(var_id, val) => {
+                    self.push_var_and_int(var_lookup[var_id], *val);
+                    &Bytecode::Add
+                }
+                Bytecode::SubVarConst(var_id, val) => {
+                    self.push_var_and_int(var_lookup[var_id], *val);
+                    &Bytecode::Sub
+                }
+                Bytecode::LtVarConst(var_id, val) => {
+                    self.push_var_and_int(var_lookup[var_id], *val);
+                    &Bytecode::Lt
+                }
+                Bytecode::AssignVar(var_id) => {
+                    let pos: usize = var_lookup[var_id];
+                    self.value_stack.push(Value::Reference(Place::Stack(pos)));
+                    &Bytecode::Assign
+                }
+                code => code,
+            };
+
             match code {
-                Bytecode::ReturnVoid => {
-                    return Value::Void;
+                Bytecode::ReturnVoid | Bytecode::ReturnLastStackValue => {
+                    let result = match code {
+                        Bytecode::ReturnVoid => Value::Void,
+                        _ => match self.value_stack.pop() {
+                            Some(result) => result,
+                            None => fail!(self, "Internal error: returning missing value"),
+                        },
+                    };
+                    let caller = match calls.pop() {
+                        Some(caller) => caller,
+                        None => return Ok(result),
+                    };
+
+                    if let Some(ref mut tracer) = self.tracer {
+                        tracer.frames.pop();
+                    }
+                    if let Some(ref mut profiler) = self.profiler {
+                        profiler.count_return();
+                    }
+                    self.pop_frame(caller.base, &result);
+                    self.value_stack.push(result);
+
+                    current_fn = caller.definition_id;
+                    bytecode = caller.bytecode;
+                    idx = caller.idx;
+                    let callee_lookup = mem::replace(var_lookup, caller.var_lookup);
+                    self.release_lookup(callee_lookup);
+                    continue;
                 }
-                Bytecode::ReturnLastStackValue => match self.value_stack.pop() {
-                    Some(s) => return s,
-                    _ => return Value::Error,
-                },
                 Bytecode::As(type_id) => match self.value_stack.pop() {
-                    Some(Value::UnknownInt(val)) => match *type_id {
-                        builtin_type::I32 => {
-                            self.value_stack.push(Value::I32(val as i32));
-                        }
-                        builtin_type::I64 => {
-                            self.value_stack.push(Value::I64(val as i64));
-                        }
-                        builtin_type::U32 => {
-                            self.value_stack.push(Value::U32(val as u32));
-                        }
-                        builtin_type::U64 => {
-                            self.value_stack.push(Value::U64(val as u64));
-                        }
-                        _ => {
-                            unimplemented!("Trying to convert {{unknown int}} to non-integer type");
+                    Some(Value::UnknownInt(val)) => match unknown_int_as(val, *type_id) {
+                        Some(value) => self.value_stack.push(value),
+                        None => {
+                            fail!(
+                                self,
+                                "Trying to convert {{unknown int}} to non-integer type"
+                            );
                         }
                     },
+                    // Widening coercions
+                    Some(Value::U32(val)) if *type_id == builtin_type::U64 => {
+                        self.value_stack.push(Value::U64(u64::from(val)))
+                    }
+                    Some(Value::I32(val)) if *type_id == builtin_type::I64 => {
+                        self.value_stack.push(Value::I64(i64::from(val)))
+                    }
                     Some(x) => self.value_stack.push(x),
-                    None => unimplemented!("Can not do a type conversion of missing value"),
+                    None => fail!(self, "Can not do a type conversion of missing value"),
                 },
                 Bytecode::Neg => match self.value_stack.pop() {
                     Some(Value::I64(val)) => {
-                        self.value_stack.push(Value::I64(-val));
+                        push_int!(
+                            self,
+                            Value::I64,
+                            val.checked_neg(),
+                            val.wrapping_neg(),
+                            "negate"
+                        );
                     }
                     Some(Value::I32(val)) => {
-                        self.value_stack.push(Value::I32(-val));
+                        push_int!(
+                            self,
+                            Value::I32,
+                            val.checked_neg(),
+                            val.wrapping_neg(),
+                            "negate"
+                        );
                     }
                     Some(Value::UnknownInt(val)) => {
-                        self.value_stack.push(Value::UnknownInt(-val));
-                    }
-                    x => unimplemented!("Can't negate values of {:?}", x),
-                },
-                Bytecode::Add => match (self.value_stack.pop(), self.value_stack.pop()) {
-                    (Some(Value::U64(rhs)), Some(Value::U64(lhs))) => {
-                        self.value_stack.push(Value::U64(lhs + rhs));
-                    }
-                    (Some(Value::U32(rhs)), Some(Value::U32(lhs))) => {
-                        self.value_stack.push(Value::U32(lhs + rhs));
-                    }
-                    (Some(Value::I64(rhs)), Some(Value::I64(lhs))) => {
-                        self.value_stack.push(Value::I64(lhs + rhs));
-                    }
-                    (Some(Value::I32(rhs)), Some(Value::I32(lhs))) => {
-                        self.value_stack.push(Value::I32(lhs + rhs));
-                    }
-                    (Some(Value::UnknownInt(rhs)), Some(Value::UnknownInt(lhs))) => {
-                        self.value_stack.push(Value::UnknownInt(lhs + rhs));
-                    }
-                    (x, y) => unimplemented!("Can't add values of {:?} and {:?}", x, y),
-                },
-                Bytecode::Sub => match (self.value_stack.pop(), self.value_stack.pop()) {
-                    (Some(Value::U64(rhs)), Some(Value::U64(lhs))) => {
-                        self.value_stack.push(Value::U64(lhs - rhs));
-                    }
-                    (Some(Value::U32(rhs)), Some(Value::U32(lhs))) => {
-                        self.value_stack.push(Value::U32(lhs - rhs));
-                    }
-                    (Some(Value::I64(rhs)), Some(Value::I64(lhs))) => {
-                        self.value_stack.push(Value::I64(lhs - rhs));
+                        push_int!(
+                            self,
+                            Value::UnknownInt,
+                            val.checked_neg(),
+                            val.wrapping_neg(),
+                            "negate"
+                        );
                     }
-                    (Some(Value::I32(rhs)), Some(Value::I32(lhs))) => {
-                        self.value_stack.push(Value::I32(lhs - rhs));
-                    }
-                    (Some(Value::UnknownInt(rhs)), Some(Value::UnknownInt(lhs))) => {
-                        self.value_stack.push(Value::UnknownInt(lhs - rhs));
-                    }
-                    (x, y) => unimplemented!("Can't add values of {:?} and {:?}", x, y),
+                    x => fail!(self, "Can't negate values of {:?}", x),
                 },
-                Bytecode::Mul => match (self.value_stack.pop(), self.value_stack.pop()) {
-                    (Some(Value::U64(rhs)), Some(Value::U64(lhs))) => {
-                        self.value_stack.push(Value::U64(lhs * rhs));
-                    }
-                    (Some(Value::U32(rhs)), Some(Value::U32(lhs))) => {
-                        self.value_stack.push(Value::U32(lhs * rhs));
-                    }
-                    (Some(Value::I64(rhs)), Some(Value::I64(lhs))) => {
-                        self.value_stack.push(Value::I64(lhs * rhs));
-                    }
-                    (Some(Value::I32(rhs)), Some(Value::I32(lhs))) => {
-                        self.value_stack.push(Value::I32(lhs * rhs));
-                    }
-                    (Some(Value::UnknownInt(rhs)), Some(Value::UnknownInt(lhs))) => {
-                        self.value_stack.push(Value::UnknownInt(lhs * rhs));
-                    }
-                    (x, y) => unimplemented!("Can't add values of {:?} and {:?}", x, y),
-                },
-                Bytecode::Div => match (self.value_stack.pop(), self.value_stack.pop()) {
-                    (Some(Value::U64(rhs)), Some(Value::U64(lhs))) => {
-                        self.value_stack.push(Value::U64(lhs / rhs));
-                    }
-                    (Some(Value::U32(rhs)), Some(Value::U32(lhs))) => {
-                        self.value_stack.push(Value::U32(lhs / rhs));
-                    }
-                    (Some(Value::I64(rhs)), Some(Value::I64(lhs))) => {
-                        self.value_stack.push(Value::I64(lhs / rhs));
-                    }
-                    (Some(Value::I32(rhs)), Some(Value::I32(lhs))) => {
-                        self.value_stack.push(Value::I32(lhs / rhs));
-                    }
-                    (Some(Value::UnknownInt(rhs)), Some(Value::UnknownInt(lhs))) => {
-                        self.value_stack.push(Value::UnknownInt(lhs / rhs));
-                    }
-                    (x, y) => unimplemented!("Can't add values of {:?} and {:?}", x, y),
-                },
-                Bytecode::Lt => match (self.value_stack.pop(), self.value_stack.pop()) {
+                Bytecode::Add => int_binop!(self, checked_add, wrapping_add, "add"),
+                Bytecode::Sub => int_binop!(self, checked_sub, wrapping_sub, "subtract"),
+                Bytecode::Mul => int_binop!(self, checked_mul, wrapping_mul, "multiply"),
+                Bytecode::Div if is_zero(self.value_stack.last()) => {
+                    fail!(self, "attempt to divide by zero")
+                }
+                Bytecode::Div => int_binop!(self, checked_div, wrapping_div, "divide"),
+                Bytecode::Lt => match self.pop_operands() {
                     (Some(Value::U64(rhs)), Some(Value::U64(lhs))) => {
                         self.value_stack.push(Value::Bool(lhs < rhs));
                     }
@@ -231,33 +1162,53 @@ impl EvalEngine {
                     (Some(Value::UnknownInt(rhs)), Some(Value::UnknownInt(lhs))) => {
                         self.value_stack.push(Value::Bool(lhs < rhs));
                     }
-                    (x, y) => unimplemented!("Can't add values of {:?} and {:?}", x, y),
+                    (x, y) => fail!(self, "Can't compare values of {:?} and {:?}", x, y),
+                },
+                Bytecode::Borrow(_) => {
+                    // The lvalue on the stack is already a reference to its slot
+                }
+                Bytecode::Deref => match self.value_stack.pop() {
+                    Some(Value::Reference(place)) => match self.read_place(place).cloned() {
+                        Some(value) => self.value_stack.push(value),
+                        None => fail!(self, "Dangling reference to {:?}", place),
+                    },
+                    x => fail!(self, "Can't dereference value of {:?}", x),
+                },
+                Bytecode::LValueDeref => match self.value_stack.pop() {
+                    Some(Value::Reference(place)) => match self.read_place(place).cloned() {
+                        Some(Value::Reference(target)) => {
+                            self.value_stack.push(Value::Reference(target))
+                        }
+                        x => fail!(self, "Can't dereference value of {:?}", x),
+                    },
+                    _ => fail!(self, "Dereference of unknown lvalue"),
                 },
                 Bytecode::Dot(field) => match self.value_stack.pop() {
-                    Some(Value::Object(obj)) => {
-                        if obj.contains_key(field) {
-                            self.value_stack.push(self.value_stack[obj[field]].clone())
-                        } else {
-                            unimplemented!("Can not find field {} in object {:#?}", field, obj);
+                    Some(Value::Object(handle)) => match self.field_index(handle, field) {
+                        Some(idx) => {
+                            let value = self.heap.fields(handle)[idx].1.clone();
+                            self.value_stack.push(value)
                         }
-                    }
+                        None => fail!(self, "Can not find field {} in object {:?}", field, handle),
+                    },
                     _ => {
-                        unimplemented!("Dot access on value that isn't an object");
+                        fail!(self, "Dot access on value that isn't an object");
                     }
                 },
                 Bytecode::LValueDot(field) => match self.value_stack.pop() {
-                    Some(Value::Reference(slot)) => match self.value_stack[slot] {
-                        Value::Object(ref obj) => {
-                            if obj.contains_key(field) {
-                                self.value_stack.push(Value::Reference(obj[field]))
-                            } else {
-
+                    Some(Value::Reference(place)) => match self.read_place(place).cloned() {
+                        Some(Value::Object(handle)) => match self.field_index(handle, field) {
+                            Some(idx) => self
+                                .value_stack
+                                .push(Value::Reference(Place::Field(handle, idx))),
+                            None => {
+                                fail!(self, "Can not find field {} in object {:?}", field, handle)
                             }
-                        }
-                        _ => unimplemented!("Field access of non-object"),
+                        },
+                        _ => fail!(self, "Field access of non-object"),
                     },
                     _ => {
-                        unimplemented!("Field access into unknown value");
+                        fail!(self, "Field access into unknown value");
                     }
                 },
                 Bytecode::PushU64(val) => {
@@ -272,9 +1223,11 @@ impl EvalEngine {
                 Bytecode::PushI32(val) => {
                     self.value_stack.push(Value::I32(*val));
                 }
-                Bytecode::PushUnknownInt(val) => {
-                    self.value_stack.push(Value::UnknownInt(*val));
-                }
+                // Literals whose type was never pinned down are i32s, as they are in Rust
+                Bytecode::PushUnknownInt(val) => match i32::try_from(*val) {
+                    Ok(val) => self.value_stack.push(Value::UnknownInt(val)),
+                    Err(_) => fail!(self, "Literal out of range for i32"),
+                },
                 Bytecode::PushBool(val) => {
                     self.value_stack.push(Value::Bool(*val));
                 }
@@ -282,7 +1235,7 @@ impl EvalEngine {
                     if val.is_null() {
                         self.value_stack.push(Value::RawPtr(ptr::null()));
                     } else {
-                        unimplemented!("Unsupported pointer type")
+                        fail!(self, "Unsupported pointer type")
                     }
                 }
                 Bytecode::If(offset, _) => match self.value_stack.pop() {
@@ -292,7 +1245,7 @@ impl EvalEngine {
                             continue;
                         }
                     }
-                    _ => unimplemented!("Expected boolean condition for if"),
+                    _ => fail!(self, "Expected boolean condition for if"),
                 },
                 Bytecode::Else(offset, _) => {
                     idx += offset;
@@ -307,13 +1260,18 @@ impl EvalEngine {
                             continue;
                         }
                     }
-                    _ => unimplemented!("Expected boolean condition for if"),
+                    _ => fail!(self, "Expected boolean condition for while"),
                 },
                 Bytecode::EndWhile(offset) => {
                     idx -= offset;
                     continue;
                 }
                 Bytecode::VarDecl(var_id) => {
+                    if let Some(Value::Object(_)) = self.value_stack.last() {
+                        let value = self.value_stack.pop().unwrap();
+                        let value = self.copy_value(value);
+                        self.value_stack.push(value);
+                    }
                     var_lookup.insert(*var_id, self.value_stack.len() - 1);
                 }
                 Bytecode::VarDeclUninit(var_id) => {
@@ -325,83 +1283,625 @@ impl EvalEngine {
                     let pos: usize = var_lookup[var_id];
                     self.value_stack.push(self.value_stack[pos].clone());
                 }
+                Bytecode::Move(var_id) => {
+                    // Values which aren't Copy are moved out of their variable rather than cloned.  Remember the
+                    // move so the value isn't dropped here too.
+                    let pos: usize = var_lookup[var_id];
+                    self.moved_places.insert(Place::Stack(pos));
+                    let value = mem::replace(&mut self.value_stack[pos], Value::Void);
+                    self.value_stack.push(value);
+                }
+                Bytecode::DropVars(vars) => {
+                    for (var_id, ty) in vars {
+                        if let Some(slot) = var_lookup.get(var_id).cloned() {
+                            if self.drop_value(bc, *ty, Place::Stack(slot)).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    if self.halted.is_some() {
+                        break;
+                    }
+                }
+                Bytecode::AddVarConst(_, _)
+                | Bytecode::SubVarConst(_, _)
+                | Bytecode::LtVarConst(_, _)
+                | Bytecode::AssignVar(_) => {
+                    fail!(self, "Internal error: superinstruction was not expanded")
+                }
                 Bytecode::LValueVar(var_id) => {
                     let pos: usize = var_lookup[var_id];
-                    self.value_stack.push(Value::Reference(pos));
+                    self.value_stack.push(Value::Reference(Place::Stack(pos)));
                 }
                 Bytecode::Assign => match (self.value_stack.pop(), self.value_stack.pop()) {
-                    (Some(Value::Reference(slot)), Some(rhs)) => {
-                        self.value_stack[slot] = rhs;
+                    (Some(Value::Reference(place)), Some(rhs)) => {
+                        let value = self.copy_value(rhs);
+                        if !self.write_place(place, value) {
+                            fail!(self, "Dangling reference to {:?}", place);
+                        }
+                        self.moved_places.remove(&place);
                     }
-                    _ => unimplemented!("Assignment missing right-hand side value"),
+                    _ => fail!(self, "Assignment missing right-hand side value"),
                 },
                 Bytecode::Call(definition_id) => {
+                    #[cfg(feature = "jit")]
+                    {
+                        if self.is_jit_compiled(*definition_id) {
+                            match self.call_jit_compiled(*definition_id, calls.len()) {
+                                JitCall::Returned => {
+                                    idx += 1;
+                                    continue;
+                                }
+                                JitCall::Halted => break,
+                                JitCall::TooDeep => {}
+                            }
+                        }
+                    }
                     if let Definition::Fun(ref target_fun) = bc.definitions[*definition_id] {
                         if let Some(ref ex_name) = target_fun.extern_name {
-                            let result = self.extern_fns[ex_name](&mut self.value_stack);
+                            if !bc.config().allow_extern_fns {
+                                fail!(
+                                    self,
+                                    "Calling extern functions is not allowed by this engine's configuration"
+                                );
+                            }
+                            let result = match self.extern_fns.get(ex_name) {
+                                Some(extern_fn) => extern_fn(&mut self.value_stack),
+                                None => {
+                                    fail!(self, "Extern function {} is not registered", ex_name)
+                                }
+                            };
                             self.value_stack.push(result);
                         } else {
-                            let result = self.eval_fn_bytecode(bc, target_fun);
-                            self.value_stack.push(result);
+                            if !self.check_call_depth(calls.len() + 1) {
+                                break;
+                            }
+                            let base = self.value_stack.len() - target_fun.params.len();
+                            let callee_lookup = self.enter_fn(bc, *definition_id, target_fun);
+                            calls.push(Caller {
+                                definition_id: current_fn,
+                                bytecode,
+                                idx: idx + 1,
+                                var_lookup: mem::replace(var_lookup, callee_lookup),
+                                base,
+                            });
+                            current_fn = Some(*definition_id);
+                            bytecode = &target_fun.bytecode;
+                            idx = 0;
+                            continue;
                         }
                     } else if let Definition::Struct(ref st) = bc.definitions[*definition_id] {
-                        let mut hash = HashMap::new();
-                        let mut offset = 1;
-                        for field in st.fields.iter().rev() {
-                            hash.insert(field.0.clone(), self.value_stack.len() - offset);
-                            offset += 1;
+                        let base = self.value_stack.len() - st.fields.len();
+                        let values = self.value_stack.split_off(base);
+                        let mut fields = vec![];
+                        for ((field, _), value) in st.fields.iter().zip(values) {
+                            let value = self.copy_value(value);
+                            fields.push((self.interner.intern(field), value));
                         }
-                        self.value_stack.push(Value::Object(hash))
+                        let object = Value::Object(self.alloc_object(fields));
+                        if !self.check_limits(&object) {
+                            break;
+                        }
+                        self.value_stack.push(object)
                     } else if let Definition::InstantiatedFun(orig_id, _) =
                         bc.definitions[*definition_id]
                     {
                         if let Definition::Fun(ref target_fun) = bc.definitions[orig_id] {
-                            let result = self.eval_fn_bytecode(bc, target_fun);
-                            self.value_stack.push(result);
+                            if !self.check_call_depth(calls.len() + 1) {
+                                break;
+                            }
+                            let base = self.value_stack.len() - target_fun.params.len();
+                            let callee_lookup = self.enter_fn(bc, *definition_id, target_fun);
+                            calls.push(Caller {
+                                definition_id: current_fn,
+                                bytecode,
+                                idx: idx + 1,
+                                var_lookup: mem::replace(var_lookup, callee_lookup),
+                                base,
+                            });
+                            current_fn = Some(*definition_id);
+                            bytecode = &target_fun.bytecode;
+                            idx = 0;
+                            continue;
                         }
                     } else {
-                        unimplemented!("Eval of unprocessed function");
+                        fail!(self, "Eval of unprocessed function");
                     }
                 }
-                Bytecode::DebugPrint(_) => match self.value_stack.pop() {
-                    Some(s) => match self.debug_capture {
-                        Some(ref mut debug_log) => {
-                            debug_log.push_str(&format!("DEBUG: {:?}\n", s));
+                Bytecode::CallNative(native_id) => {
+                    let native = &bc.natives[*native_id];
+                    let base = self.value_stack.len() - native.param_tys.len();
+                    for (arg, ty) in self.value_stack[base..].iter_mut().zip(&native.param_tys) {
+                        if let Value::UnknownInt(val) = *arg {
+                            if let Some(value) = unknown_int_as(val, *ty) {
+                                *arg = value;
+                            }
                         }
-                        None => {
-                            println!("DEBUG: {:?}", s);
+                    }
+                    let result = (native.fun)(&self.value_stack[base..]);
+                    self.value_stack.truncate(base);
+                    let result = match result {
+                        Ok(result) => result,
+                        Err(message) => fail!(self, "{}", message),
+                    };
+                    if !self.check_limits(&result) {
+                        break;
+                    }
+                    self.value_stack.push(result);
+                }
+                Bytecode::DebugPrint(_) => match self.value_stack.pop() {
+                    Some(s) => {
+                        let s = self.debug_string(&s);
+                        match self.debug_capture {
+                            Some(ref mut debug_log) => {
+                                debug_log.push_str(&format!("DEBUG: {}\n", s));
+                            }
+                            None => match self.output {
+                                Some(ref mut output) => {
+                                    if let Err(err) = writeln!(output, "DEBUG: {}", s) {
+                                        fail!(self, "Could not write output: {}", err);
+                                    }
+                                }
+                                None => println!("DEBUG: {}", s),
+                            },
                         }
-                    },
-                    _ => unimplemented!("Internal error: debug printing missing value"),
+                    }
+                    _ => fail!(self, "Internal error: debug printing missing value"),
+                },
+                Bytecode::ReadInput => {
+                    let mut line = String::new();
+                    let read = match self.input {
+                        Some(ref mut input) => input.read_line(&mut line),
+                        None => io::stdin().read_line(&mut line),
+                    };
+                    match read {
+                        Ok(0) => fail!(self, "There is no more input to read"),
+                        Ok(_) => match line.trim().parse() {
+                            Ok(value) => self.value_stack.push(Value::U64(value)),
+                            Err(_) => fail!(self, "Expected a u64 but read {:?}", line.trim()),
+                        },
+                        Err(err) => fail!(self, "Could not read input: {}", err),
+                    }
+                }
+                Bytecode::ReadClock => {
+                    let now = match self.clock {
+                        Some(ref mut clock) => clock(),
+                        None => match SystemTime::now().duration_since(UNIX_EPOCH) {
+                            Ok(since_epoch) => since_epoch.as_millis() as u64,
+                            Err(_) => fail!(self, "The system clock is set before the Unix epoch"),
+                        },
+                    };
+                    self.value_stack.push(Value::U64(now));
+                }
+                Bytecode::RandomBelow => {
+                    let bound = match self.value_stack.pop() {
+                        Some(Value::U64(bound)) => bound,
+                        Some(Value::UnknownInt(bound)) if bound >= 0 => bound as u64,
+                        x => fail!(self, "Can't use {:?} as the bound of a random number", x),
+                    };
+                    if bound == 0 {
+                        fail!(self, "random_below needs a bound greater than 0");
+                    }
+                    let seed = bc.config().random_seed;
+                    let rng = self
+                        .rng
+                        .get_or_insert_with(|| Rng::new(seed.unwrap_or_else(clock_seed)));
+                    let value = rng.below(bound);
+                    self.value_stack.push(Value::U64(value));
+                }
+                Bytecode::ArgCount => {
+                    let count = self.args.len() as u64;
+                    self.value_stack.push(Value::U64(count));
+                }
+                Bytecode::Arg => {
+                    let index = match self.value_stack.pop() {
+                        Some(Value::U64(index)) => index,
+                        Some(Value::UnknownInt(index)) if index >= 0 => index as u64,
+                        x => fail!(self, "Can't use {:?} as the index of an argument", x),
+                    };
+                    let arg = match self.args.get(index as usize) {
+                        Some(arg) => arg.clone(),
+                        None => fail!(self, "There is no argument {}", index),
+                    };
+                    match arg.parse() {
+                        Ok(value) => self.value_stack.push(Value::U64(value)),
+                        Err(_) => fail!(
+                            self,
+                            "Expected a u64 but read {:?} from argument {}",
+                            arg,
+                            index
+                        ),
+                    }
+                }
+                Bytecode::Env(name) => {
+                    let value = match self.env {
+                        Some(ref env) => env.get(name).cloned(),
+                        None => ::std::env::var(name).ok(),
+                    };
+                    match value {
+                        Some(value) => match value.parse() {
+                            Ok(parsed) => self.value_stack.push(Value::U64(parsed)),
+                            Err(_) => {
+                                fail!(self, "Expected a u64 but read {:?} from {}", value, name)
+                            }
+                        },
+                        None => fail!(self, "The environment variable {} is not set", name),
+                    }
+                }
+                Bytecode::ReadFile(path) => {
+                    let full_path = match bc.config().file_root {
+                        Some(ref root) => root.join(path),
+                        None => fail!(self, "There is no file root to read {} from", path),
+                    };
+                    let contents = match fs::read_to_string(full_path) {
+                        Ok(contents) => contents,
+                        Err(err) => fail!(self, "Could not read {}: {}", path, err),
+                    };
+                    match contents.trim().parse() {
+                        Ok(value) => self.value_stack.push(Value::U64(value)),
+                        Err(_) => fail!(
+                            self,
+                            "Expected a u64 but read {:?} from {}",
+                            contents.trim(),
+                            path
+                        ),
+                    }
+                }
+                Bytecode::WriteFile(path) => {
+                    let value = match self.value_stack.pop() {
+                        Some(Value::U64(value)) => value,
+                        Some(Value::UnknownInt(value)) if value >= 0 => value as u64,
+                        x => fail!(self, "Can't write {:?} to a file", x),
+                    };
+                    let full_path = match bc.config().file_root {
+                        Some(ref root) => root.join(path),
+                        None => fail!(self, "There is no file root to write {} in", path),
+                    };
+                    if let Err(err) = fs::write(full_path, format!("{}\n", value)) {
+                        fail!(self, "Could not write {}: {}", path, err);
+                    }
+                }
+                Bytecode::Panic(message) => fail!(self, "panicked: {}", message),
+                Bytecode::Exit => match self.value_stack.pop() {
+                    Some(Value::I32(code)) | Some(Value::UnknownInt(code)) => {
+                        self.halted = Some(Halt::Exit(code));
+                        break;
+                    }
+                    x => fail!(self, "Can't exit with {:?}", x),
                 },
             }
 
+            if let (Some(instruction), Some(recording)) = (external, self.recording.as_mut()) {
+                if let Some(Value::U64(value)) = self.value_stack.last() {
+                    recording.inputs.push(RecordedInput {
+                        instruction,
+                        value: *value,
+                    });
+                }
+            }
+
             idx += 1;
         }
 
-        Value::Void
+        if let Some(halt) = &self.halted {
+            // Callers are waiting on the Call instruction just before where they'll continue from
+            self.backtrace.push(backtrace_frame(bc, current_fn, idx));
+            for caller in calls.iter().rev() {
+                let frame = backtrace_frame(bc, caller.definition_id, caller.idx - 1);
+                self.backtrace.push(frame);
+            }
+            return Err(PeachError::Runtime(RuntimeError {
+                message: halt.to_string(),
+                peach_backtrace: self.backtrace.clone(),
+            }));
+        }
+
+        Ok(Value::Void)
     }
 
-    fn eval_fn_bytecode(&mut self, bc: &BytecodeEngine, fun: &Fun) -> Value {
-        let mut var_lookup: HashMap<usize, usize> = HashMap::new();
+    /// Evaluates a function whose arguments are on the top of the value stack
+    fn eval_fn_bytecode(
+        &mut self,
+        bc: &BytecodeEngine,
+        definition_id: DefinitionId,
+        fun: &Fun,
+    ) -> Result<Value> {
+        let mut var_lookup = self.enter_fn(bc, definition_id, fun);
+        let result = self.eval_block_in(bc, Some(definition_id), &fun.bytecode, &mut var_lookup);
+        self.release_lookup(var_lookup);
+
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.frames.pop();
+        }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.count_return();
+        }
+
+        result
+    }
+
+    /// Sets up the variables for a call of the function, whose arguments are on the top of the value stack, and
+    /// starts tracing it
+    fn enter_fn(
+        &mut self,
+        bc: &BytecodeEngine,
+        definition_id: DefinitionId,
+        fun: &Fun,
+    ) -> HashMap<usize, usize> {
+        let mut var_lookup = self.lookup_pool.pop().unwrap_or_default();
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.count_call(definition_id);
+        }
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.count_call(definition_id);
+        }
 
         let mut param_offset = fun.params.len();
         for param in &fun.params {
             var_lookup.insert(param.var_id, self.value_stack.len() - param_offset);
             param_offset -= 1;
         }
+        for param in &fun.params {
+            let slot = var_lookup[&param.var_id];
+            if let Value::Object(_) = self.value_stack[slot] {
+                let value = self.value_stack[slot].clone();
+                self.value_stack[slot] = self.copy_value(value);
+            }
+        }
+
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.frames.push(TraceFrame {
+                function: bc
+                    .definition_name(definition_id)
+                    .unwrap_or_else(|| format!("fun_{}", definition_id)),
+                var_names: fun.vars.iter().map(|v| v.ident.clone()).collect(),
+            });
+        }
 
-        self.eval_block_bytecode(bc, &fun.bytecode, &mut var_lookup)
+        var_lookup
+    }
+
+    /// Keeps the variable map of a call which has returned, for a later call to reuse
+    fn release_lookup(&mut self, mut var_lookup: HashMap<usize, usize>) {
+        if self.lookup_pool.len() < LOOKUP_POOL_SIZE {
+            var_lookup.clear();
+            self.lookup_pool.push(var_lookup);
+        }
     }
 
     /// Begin evaluating the bytecode starting at the given function name.  Optionally, capture the debug output for later use.
-    pub fn eval_program(&mut self, bc: &BytecodeEngine, starting_fn_name: &str) -> Value {
+    /// If evaluation is stopped early, the error is a `PeachError::Runtime` and `halted` has the reason.
+    pub fn eval_program(&mut self, bc: &BytecodeEngine, starting_fn_name: &str) -> Result<Value> {
         // begin evaluating with the first function
         // We assume scope 0 is the file root scope of the starting file, where will find the main
+        self.halted = None;
+        self.rng = None;
+
+        let fun = bc.get_fn(starting_fn_name, 0)?;
+        let definition_id = match bc.get_defn(starting_fn_name, 0) {
+            Some((definition_id, _)) => definition_id,
+            None => bail!("Function {} could not be found", starting_fn_name),
+        };
+
+        let base = self.value_stack.len();
+        let result = self.eval_fn_bytecode(bc, definition_id, fun);
+        self.unwind_if_halted(base);
+        result
+    }
+
+    /// Evaluates the program like `eval_program`, and gives the status the process running it should exit with: the
+    /// one given to `exit`, the integer the starting function returns, or 0 if it returns nothing.  The starting
+    /// function has to return nothing or an integer that fits in an `i32`.  If the program stops with an error, it's
+    /// returned as it is by `eval_program`.
+    ///
+    /// Eg)
+    /// ```text
+    /// let status = ee.eval_program_exit_code(&bc, "main")?;
+    /// std::process::exit(status);
+    /// ```
+    pub fn eval_program_exit_code(
+        &mut self,
+        bc: &BytecodeEngine,
+        starting_fn_name: &str,
+    ) -> Result<i32> {
+        let return_ty = bc.get_fn(starting_fn_name, 0)?.return_ty;
+        if return_ty != builtin_type::VOID && !is_integer(return_ty) {
+            bail!(
+                "Function {} has to return nothing or an integer to give an exit status, not {}",
+                starting_fn_name,
+                bc.ty(return_ty)
+            );
+        }
+
+        let value = match self.eval_program(bc, starting_fn_name) {
+            Ok(value) => value,
+            Err(e) => match self.halted {
+                Some(Halt::Exit(code)) => return Ok(code),
+                _ => return Err(e),
+            },
+        };
+        let code = match value {
+            Value::U64(x) => i32::try_from(x).ok(),
+            Value::U32(x) => i32::try_from(x).ok(),
+            Value::I64(x) => i32::try_from(x).ok(),
+            Value::I32(x) | Value::UnknownInt(x) => Some(x),
+            _ => Some(0),
+        };
+        match code {
+            Some(code) => Ok(code),
+            None => bail!(
+                "Function {} returned {}, which is too large for an exit status",
+                starting_fn_name,
+                value
+            ),
+        }
+    }
+
+    /// Calls a processed function, like those whose ids are returned by `BytecodeEngine::process_fn`, with the given
+    /// arguments and returns its result.  If evaluation is stopped early, the error is a `PeachError::Runtime` and
+    /// `halted` has the reason.
+    pub fn call_fn(
+        &mut self,
+        bc: &BytecodeEngine,
+        definition_id: DefinitionId,
+        args: &[Value],
+    ) -> Result<Value> {
+        self.halted = None;
+
+        let fun = match bc.definitions.get(definition_id) {
+            Some(Definition::Fun(fun)) if !fun.is_generic() => fun,
+            Some(Definition::InstantiatedFun(orig_id, _)) => match bc.definitions[*orig_id] {
+                Definition::Fun(ref fun) => fun,
+                _ => bail!("Definition {} is not a processed function", definition_id),
+            },
+            Some(Definition::Fun(_)) => {
+                bail!("Generic functions can only be called through one of their instantiations")
+            }
+            _ => bail!("Definition {} is not a processed function", definition_id),
+        };
+
+        if args.len() != fun.params.len() {
+            bail!(
+                "Function takes {} arguments but {} were given",
+                fun.params.len(),
+                args.len()
+            );
+        }
+        for (arg, param) in args.iter().zip(&fun.params) {
+            if !value_has_type(arg, param.ty) {
+                bail!(
+                    "Argument {} should be a {} but is {:?}",
+                    param.name,
+                    bc.ty(param.ty),
+                    arg
+                );
+            }
+        }
+
+        // Calls go through the same instruction the program would use, so they're dispatched the same way
+        let call = match fun.native_id {
+            Some(native_id) => Bytecode::CallNative(native_id),
+            None => Bytecode::Call(definition_id),
+        };
+        let base = self.value_stack.len();
+        self.value_stack.extend(args.iter().cloned());
+        let result = self.eval_block_bytecode(
+            bc,
+            &vec![call, Bytecode::ReturnLastStackValue],
+            &mut HashMap::new(),
+        );
+        self.unwind_if_halted(base);
+        result
+    }
+
+    /// Converts a single expression in the engine's root scope and evaluates it, giving its value and its type.
+    /// Nothing needs to be loaded first, though the expression can call the functions of files that have been.  It's
+    /// converted like a line entered at a repl, so integer literals whose type isn't pinned down have the type
+    /// `{integer}`.  If evaluation is stopped early, the error is a `PeachError::Runtime` and `halted` has the reason.
+    ///
+    /// Eg)
+    /// ```text
+    /// let mut bc = BytecodeEngine::with_prelude(&Prelude::standard())?;
+    /// let mut ee = EvalEngine::new();
+    /// let (value, ty) = ee.eval_expr_str(&mut bc, "1 + 2 * 3")?;
+    /// println!("{}: {}", value, bc.ty(ty));
+    /// ```
+    pub fn eval_expr_str(
+        &mut self,
+        bc: &mut BytecodeEngine,
+        expr: &str,
+    ) -> Result<(Value, DefinitionId)> {
+        let mut bytecode = vec![];
+        let ty = bc.process_raw_expr_str(expr, &mut bytecode, &mut VarStack::new())?;
+        if ty != builtin_type::VOID {
+            bytecode.push(Bytecode::ReturnLastStackValue);
+        }
+
+        self.halted = None;
+        self.rng = None;
+        let base = self.value_stack.len();
+        let result = self.eval_block_bytecode(bc, &bytecode, &mut HashMap::new());
+        self.unwind_if_halted(base);
+        Ok((result?, ty))
+    }
+
+    /// Removes what an evaluation that was halted part way through left on the stack above `base`, so the engine
+    /// can be used again
+    fn unwind_if_halted(&mut self, base: usize) {
+        if self.halted.is_some() {
+            self.truncate_stack(base);
+        }
+    }
+
+    /// Evaluates the program like `eval_program`, recording the instruction, value stack, and locals before each step.
+    /// Evaluation stops once `max_steps` instructions have been recorded, so this is safe to use on programs that may not terminate.
+    pub fn trace_program(
+        &mut self,
+        bc: &BytecodeEngine,
+        starting_fn_name: &str,
+        max_steps: usize,
+    ) -> Trace {
+        self.tracer = Some(Tracer {
+            max_steps,
+            steps: vec![],
+            frames: vec![],
+        });
+
+        // How the program ended shows in the trace, so the result isn't needed
+        let _ = self.eval_program(bc, starting_fn_name);
+
+        let tracer = self.tracer.take().unwrap();
+        Trace {
+            steps: tracer.steps,
+            truncated: self.halted == Some(Halt::StepLimit),
+        }
+    }
+
+    /// Evaluates the program like `eval_program`, counting the instructions evaluated for each opcode, function, and
+    /// call stack, and how often each function is called.  How the program ended is left in `halted`.
+    pub fn profile_program(&mut self, bc: &BytecodeEngine, starting_fn_name: &str) -> Profile {
+        self.profiler = Some(Profiler::default());
+
+        // The profile has what ran before any error, so the result isn't needed
+        let _ = self.eval_program(bc, starting_fn_name);
+
+        self.profiler.take().unwrap().into_profile(bc)
+    }
+
+    /// Evaluates the program like `eval_program`, counting how many times each line of it ran.  How the program
+    /// ended is left in `halted`.
+    pub fn cover_program(&mut self, bc: &BytecodeEngine, starting_fn_name: &str) -> Coverage {
+        self.coverage = Some(CoverageRecorder::default());
+
+        // The coverage has what ran before any error, so the result isn't needed
+        let _ = self.eval_program(bc, starting_fn_name);
+
+        self.coverage.take().unwrap().into_coverage(bc)
+    }
+
+    /// Evaluates the program like `eval_program`, recording each value it reads from outside the engine, so that the
+    /// run can be reproduced with `replay_program`.  How the program ended is left in `halted`.
+    pub fn record_program(&mut self, bc: &BytecodeEngine, starting_fn_name: &str) -> Recording {
+        self.recording = Some(Recording::default());
+
+        // A recording of a run that failed is still useful for reproducing the failure, so the result isn't needed
+        let _ = self.eval_program(bc, starting_fn_name);
 
-        let fun = bc.get_fn(starting_fn_name, 0);
+        self.recording.take().unwrap()
+    }
 
-        self.eval_fn_bytecode(bc, &fun)
+    /// Evaluates the program like `eval_program`, but the values it would read from outside the engine are taken from
+    /// the recording instead.  Evaluation halts if the program reads something the recording doesn't have next.
+    pub fn replay_program(
+        &mut self,
+        bc: &BytecodeEngine,
+        starting_fn_name: &str,
+        recording: &Recording,
+    ) -> Result<Value> {
+        self.replayer = Some(Replayer::new(recording));
+        let result = self.eval_program(bc, starting_fn_name);
+        self.replayer = None;
+        result
     }
 
     pub fn register_extern_fn_0<Ret: Any>(
@@ -572,3 +2072,99 @@ impl EvalEngine {
         self.extern_fns.insert(name.to_string(), fun);
     }
 }
+
+/// The function with the given id, or that it's an instantiation of
+fn fun_of(bc: &BytecodeEngine, definition_id: DefinitionId) -> Option<&Fun> {
+    let fun_id = match bc.definitions[definition_id] {
+        Definition::InstantiatedFun(orig_id, _) => orig_id,
+        _ => definition_id,
+    };
+    match bc.definitions[fun_id] {
+        Definition::Fun(ref fun) => Some(fun),
+        _ => None,
+    }
+}
+
+/// Describes the instruction at `offset` in the given function, or in a block that isn't part of a function
+fn backtrace_frame(
+    bc: &BytecodeEngine,
+    definition_id: Option<DefinitionId>,
+    offset: usize,
+) -> BacktraceFrame {
+    let definition_id = match definition_id {
+        Some(definition_id) => definition_id,
+        None => {
+            return BacktraceFrame {
+                function: "{block}".to_string(),
+                offset,
+                location: None,
+            }
+        }
+    };
+    let location = fun_of(bc, definition_id).and_then(|fun| fun.location_of(offset));
+
+    BacktraceFrame {
+        function: bc
+            .definition_name(definition_id)
+            .unwrap_or_else(|| format!("fun_{}", definition_id)),
+        offset,
+        location,
+    }
+}
+
+/// A seed for runs that weren't given one, which differs from run to run
+fn clock_seed() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_nanos() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Whether the value is an integer zero
+fn is_zero(value: Option<&Value>) -> bool {
+    match value {
+        Some(Value::U64(x)) => *x == 0,
+        Some(Value::U32(x)) => *x == 0,
+        Some(Value::I64(x)) => *x == 0,
+        Some(Value::I32(x)) => *x == 0,
+        Some(Value::UnknownInt(x)) => *x == 0,
+        _ => false,
+    }
+}
+
+/// Gives an integer whose type was never pinned down the given integer type.  Returns `None` if `ty` isn't an
+/// integer type.
+fn unknown_int_as(val: i32, ty: DefinitionId) -> Option<Value> {
+    match ty {
+        builtin_type::U64 => Some(Value::U64(val as u64)),
+        builtin_type::U32 => Some(Value::U32(val as u32)),
+        builtin_type::I64 => Some(Value::I64(val as i64)),
+        builtin_type::I32 => Some(Value::I32(val)),
+        _ => None,
+    }
+}
+
+/// The type of a value, if it's an integer of a known type
+fn int_type(value: &Value) -> Option<DefinitionId> {
+    match value {
+        Value::U64(_) => Some(builtin_type::U64),
+        Value::U32(_) => Some(builtin_type::U32),
+        Value::I64(_) => Some(builtin_type::I64),
+        Value::I32(_) => Some(builtin_type::I32),
+        _ => None,
+    }
+}
+
+/// Checks that a value given by the host can be used where the type `ty` is expected.  Only the builtin types can be
+/// told apart from their values; values of other types are trusted.
+fn value_has_type(value: &Value, ty: DefinitionId) -> bool {
+    match ty {
+        builtin_type::U64 => matches!(value, Value::U64(_) | Value::UnknownInt(_)),
+        builtin_type::U32 => matches!(value, Value::U32(_) | Value::UnknownInt(_)),
+        builtin_type::I64 => matches!(value, Value::I64(_) | Value::UnknownInt(_)),
+        builtin_type::I32 => matches!(value, Value::I32(_) | Value::UnknownInt(_)),
+        builtin_type::BOOL => matches!(value, Value::Bool(_)),
+        builtin_type::VOID_PTR => matches!(value, Value::RawPtr(_)),
+        _ => true,
+    }
+}
@@ -0,0 +1,109 @@
+//! Golden files: checked-in text that a test's output has to match, like the `snapshot_fn` of a function, so that a
+//! change in how code is lowered shows up as a failing test with the difference.
+//!
+//! A golden file is made, or updated after an intended change, by running the tests with `PEACH_BLESS=1`, which
+//! writes each file with the output instead of checking it:
+//!
+//! ```ignore
+//! let mut bc = BytecodeEngine::new();
+//! bc.load_file("while01.rs")?;
+//! let snapshot = bc.snapshot_fn("main")?;
+//! if let Err(mismatch) = check_golden(&snapshot, Path::new("snapshots/while01.main.txt")) {
+//!     panic!("{}", mismatch);
+//! }
+//! ```
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The environment variable that makes `check_golden` write golden files rather than check them
+pub const BLESS_VAR: &str = "PEACH_BLESS";
+
+/// Output that doesn't match its golden file
+#[derive(Clone, Debug, PartialEq)]
+pub struct GoldenMismatch {
+    pub path: PathBuf,
+    /// What the golden file holds, or `None` if there isn't one
+    pub expected: Option<String>,
+    pub actual: String,
+}
+
+impl fmt::Display for GoldenMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let expected = match self.expected {
+            Some(ref expected) => expected,
+            None => {
+                return write!(
+                    f,
+                    "{} doesn't exist.  Run with {}=1 to write it with:\n{}",
+                    self.path.display(),
+                    BLESS_VAR,
+                    self.actual
+                )
+            }
+        };
+
+        // The first line that differs, to show where to look
+        let mut expected_lines = expected.lines();
+        let mut actual_lines = self.actual.lines();
+        let mut line = 1;
+        loop {
+            match (expected_lines.next(), actual_lines.next()) {
+                (Some(lhs), Some(rhs)) if lhs == rhs => line += 1,
+                (lhs, rhs) => {
+                    writeln!(
+                        f,
+                        "{} doesn't match at line {}.  Run with {}=1 to update it if the change is intended.",
+                        self.path.display(),
+                        line,
+                        BLESS_VAR
+                    )?;
+                    writeln!(f, "expected: {}", lhs.unwrap_or("<end of file>"))?;
+                    writeln!(f, "actual:   {}", rhs.unwrap_or("<end of file>"))?;
+                    break;
+                }
+            }
+        }
+        write!(f, "--- expected\n{}+++ actual\n{}", expected, self.actual)
+    }
+}
+
+/// Checks the output against the golden file at `path`, or writes the file with it if `PEACH_BLESS` is set.  Line
+/// endings aren't compared, so a file checked out with CRLF endings still matches.
+pub fn check_golden(actual: &str, path: &Path) -> Result<(), GoldenMismatch> {
+    let bless = env::var_os(BLESS_VAR).is_some_and(|value| !value.is_empty() && value != "0");
+    compare_golden(actual, path, bless)
+}
+
+pub(crate) fn compare_golden(actual: &str, path: &Path, bless: bool) -> Result<(), GoldenMismatch> {
+    let actual = actual.replace("\r\n", "\n");
+    if bless {
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, &actual));
+        return match written {
+            Ok(()) => Ok(()),
+            // The file can't be updated, so it's reported as it is
+            Err(_) => Err(GoldenMismatch {
+                path: path.to_path_buf(),
+                expected: fs::read_to_string(path).ok(),
+                actual,
+            }),
+        };
+    }
+
+    let expected = fs::read_to_string(path)
+        .ok()
+        .map(|expected| expected.replace("\r\n", "\n"));
+    if expected.as_ref() == Some(&actual) {
+        Ok(())
+    } else {
+        Err(GoldenMismatch {
+            path: path.to_path_buf(),
+            expected,
+            actual,
+        })
+    }
+}
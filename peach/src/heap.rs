@@ -0,0 +1,152 @@
+//! The heap that struct values live in.  Values refer to objects on the heap by handle, so copying a value around
+//! the stack doesn't copy its fields.  Objects are freed by a simple mark and sweep collection, which keeps every
+//! object that can be reached from the value stack.
+use eval::Value;
+use intern::Symbol;
+
+/// How many objects may be live before the first collection
+const INITIAL_COLLECTION_THRESHOLD: usize = 1024;
+
+/// Refers to an object on the heap.  Handles are only valid while something on the value stack can reach the object,
+/// as the object is freed, and its handle reused, once nothing can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Handle(pub(crate) usize);
+
+/// Where a value is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Place {
+    /// A slot of the value stack
+    Stack(usize),
+    /// The field of an object, by its index in the struct's declaration
+    Field(Handle, usize),
+}
+
+/// The objects, each a list of (field name, value) in declaration order
+pub(crate) struct Heap {
+    objects: Vec<Option<Vec<(Symbol, Value)>>>,
+    /// Indices in `objects` which have been freed and can be reused
+    free: Vec<usize>,
+    live: usize,
+    /// How many objects have been allocated altogether, including those since freed
+    allocations: u64,
+    /// The number of live objects at which the next collection happens
+    next_collection: usize,
+    /// The value of `allocations` at the last collection
+    allocations_at_collection: u64,
+}
+
+impl Heap {
+    pub(crate) fn new() -> Heap {
+        Heap {
+            objects: vec![],
+            free: vec![],
+            live: 0,
+            allocations: 0,
+            next_collection: INITIAL_COLLECTION_THRESHOLD,
+            allocations_at_collection: 0,
+        }
+    }
+
+    pub(crate) fn alloc(&mut self, fields: Vec<(Symbol, Value)>) -> Handle {
+        self.live += 1;
+        self.allocations += 1;
+        match self.free.pop() {
+            Some(idx) => {
+                self.objects[idx] = Some(fields);
+                Handle(idx)
+            }
+            None => {
+                self.objects.push(Some(fields));
+                Handle(self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// The fields of the object, which has none if it's been freed
+    pub(crate) fn fields(&self, handle: Handle) -> &[(Symbol, Value)] {
+        match self.objects.get(handle.0) {
+            Some(Some(fields)) => fields,
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn field_index(&self, handle: Handle, name: Symbol) -> Option<usize> {
+        self.fields(handle)
+            .iter()
+            .position(|(field, _)| *field == name)
+    }
+
+    pub(crate) fn field(&self, handle: Handle, idx: usize) -> Option<&Value> {
+        self.fields(handle).get(idx).map(|(_, value)| value)
+    }
+
+    pub(crate) fn field_mut(&mut self, handle: Handle, idx: usize) -> Option<&mut Value> {
+        match self.objects.get_mut(handle.0) {
+            Some(Some(fields)) => fields.get_mut(idx).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn contains(&self, handle: Handle) -> bool {
+        match self.objects.get(handle.0) {
+            Some(object) => object.is_some(),
+            None => false,
+        }
+    }
+
+    /// The number of objects that haven't been freed
+    pub(crate) fn live(&self) -> usize {
+        self.live
+    }
+
+    pub(crate) fn allocations(&self) -> u64 {
+        self.allocations
+    }
+
+    pub(crate) fn needs_collection(&self) -> bool {
+        self.live >= self.next_collection
+    }
+
+    /// Whether objects have been allocated since the last collection, so another could free something
+    pub(crate) fn allocated_since_collection(&self) -> bool {
+        self.allocations != self.allocations_at_collection
+    }
+
+    /// Frees the objects that can't be reached from the roots, and returns how many were freed
+    pub(crate) fn collect(&mut self, roots: &[Value]) -> usize {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<Handle> = roots.iter().filter_map(handle_in).collect();
+        while let Some(handle) = pending.pop() {
+            if marked[handle.0] {
+                continue;
+            }
+            marked[handle.0] = true;
+            pending.extend(
+                self.fields(handle)
+                    .iter()
+                    .filter_map(|(_, value)| handle_in(value)),
+            );
+        }
+
+        let mut freed = 0;
+        for (idx, object) in self.objects.iter_mut().enumerate() {
+            if object.is_some() && !marked[idx] {
+                *object = None;
+                self.free.push(idx);
+                freed += 1;
+            }
+        }
+        self.live -= freed;
+        self.next_collection = INITIAL_COLLECTION_THRESHOLD.max(self.live * 2);
+        self.allocations_at_collection = self.allocations;
+        freed
+    }
+}
+
+/// The object the value refers to, if it refers to one
+fn handle_in(value: &Value) -> Option<Handle> {
+    match value {
+        Value::Object(handle) | Value::Reference(Place::Field(handle, _)) => Some(*handle),
+        _ => None,
+    }
+}
@@ -0,0 +1,873 @@
+//! Images: the processed definitions and scopes of an engine, saved in a compact binary format so a program can be
+//! processed ahead of time and shipped without its source.
+//!
+//! An image starts with a header: the magic bytes `PEACHIMG`, then the version of the image format, the version of
+//! the bytecode, and the flags of the features the image uses, each as four little-endian bytes.  The header is
+//! written the same way in every version, so an image that can't be loaded is refused with why, rather than being
+//! misread.
+//!
+//! After the header, numbers are written as LEB128 varints, with signed ones zigzag encoded first.  Strings and lists
+//! are written with their length first.  Maps are written in the order of their keys, so the same engine always gives
+//! the same image.
+//!
+//! Definitions that are still syntax, like functions that were never processed, traits, and macros, can't be saved.
+//! They're written as omitted, with only their kind, so the ids of the definitions after them are kept.  Loading an
+//! image gives an engine that can be evaluated without the source, but nothing new can be converted in it that needs
+//! an omitted definition.
+use bytecode::{
+    Bytecode, BytecodeEngine, Definition, DefinitionId, Fun, Impl, Import, Mod, Param, Scope,
+    Struct, VarDecl, OPCODE_NAMES,
+};
+use capabilities::BYTECODE_VERSION;
+use error::{Location, PeachError, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::Hash;
+
+/// The bytes every image starts with
+const IMAGE_MAGIC: &[u8] = b"PEACHIMG";
+
+/// The version of the format written by `BytecodeEngine::to_image`
+pub const IMAGE_FORMAT_VERSION: u32 = 2;
+
+/// The features an image can use, as the bits of the flags in its header.  An image with a flag this peach doesn't
+/// know is refused, as whatever the flag means isn't understood.
+mod feature {
+    /// Some functions are `extern` functions, which the host has to provide
+    pub const EXTERN_FNS: u32 = 1;
+    /// The program was compiled for tests, so its `#[test]` functions are listed
+    pub const TESTS: u32 = 1 << 1;
+
+    pub const ALL: u32 = EXTERN_FNS | TESTS;
+}
+
+/// The tag each kind of definition is written with
+mod tag {
+    pub const BUILTIN: u8 = 0;
+    pub const TYPE_VARIABLE: u8 = 1;
+    pub const REFERENCE: u8 = 2;
+    pub const FUN: u8 = 3;
+    pub const INSTANTIATED_FUN: u8 = 4;
+    pub const STRUCT: u8 = 5;
+    pub const MOD: u8 = 6;
+    pub const IMPL: u8 = 7;
+    pub const OMITTED: u8 = 8;
+}
+
+/// Whether a definition is written to images as omitted, as `write_definition` writes it
+pub(crate) fn omitted_from_images(definition: &Definition) -> bool {
+    match definition {
+        Definition::Fun(fun) => fun.native_id.is_some(),
+        Definition::Builtin
+        | Definition::TypeVariable
+        | Definition::Reference(..)
+        | Definition::InstantiatedFun(..)
+        | Definition::Struct(_)
+        | Definition::Mod(_)
+        | Definition::Impl(_) => false,
+        _ => true,
+    }
+}
+
+/// The kinds an omitted definition can have, as given by `Definition::kind`
+const OMITTED_KINDS: &[&str] = &["fn", "mod", "struct", "impl", "trait", "macro", "type"];
+
+/// The bytes of an image as it's being written
+#[derive(Default)]
+pub(crate) struct ImageWriter {
+    pub(crate) bytes: Vec<u8>,
+}
+
+impl ImageWriter {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    /// Writes the number as four bytes, as the header does
+    fn fixed_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn u64(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.u8(byte);
+                return;
+            }
+            self.u8(byte | 0x80);
+        }
+    }
+
+    pub(crate) fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.u64(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    /// Written like an `i64`, so the two are read the same way while the value fits in one
+    fn i128(&mut self, value: i128) {
+        let mut value = ((value << 1) ^ (value >> 127)) as u128;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.u8(byte);
+                return;
+            }
+            self.u8(byte | 0x80);
+        }
+    }
+
+    pub(crate) fn str(&mut self, value: &str) {
+        self.usize(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn ids(&mut self, ids: &[DefinitionId]) {
+        self.usize(ids.len());
+        for id in ids {
+            self.usize(*id);
+        }
+    }
+
+    pub(crate) fn option<T>(&mut self, value: Option<T>, write: impl FnOnce(&mut ImageWriter, T)) {
+        match value {
+            Some(value) => {
+                self.bool(true);
+                write(self, value);
+            }
+            None => self.bool(false),
+        }
+    }
+
+    pub(crate) fn location(&mut self, location: &Location) {
+        self.str(&location.file);
+        self.usize(location.line);
+        self.usize(location.column);
+    }
+
+    /// Writes the entries of a map, sorted by key
+    fn map<K: Ord, V>(
+        &mut self,
+        map: &HashMap<K, V>,
+        mut write: impl FnMut(&mut ImageWriter, &K, &V),
+    ) {
+        let mut entries: Vec<(&K, &V)> = map.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        self.usize(entries.len());
+        for (key, value) in entries {
+            write(self, key, value);
+        }
+    }
+}
+
+/// The bytes of an image as it's being read.  Everything read is checked, so a damaged image gives an error rather
+/// than an engine that can't be trusted.
+pub(crate) struct ImageReader<'a> {
+    bytes: &'a [u8],
+    pub(crate) pos: usize,
+    /// How many definitions the image has, once they've been counted, which the ids in it have to be below
+    definition_count: usize,
+}
+
+impl<'a> ImageReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> ImageReader<'a> {
+        ImageReader {
+            bytes,
+            pos: 0,
+            definition_count: 0,
+        }
+    }
+
+    fn error(&self, problem: &str) -> PeachError {
+        PeachError::Parse(format!(
+            "Not a valid image: {} at byte {}",
+            problem, self.pos
+        ))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        match self.bytes.get(self.pos) {
+            Some(byte) => {
+                self.pos += 1;
+                Ok(*byte)
+            }
+            None => Err(self.error("it ends early")),
+        }
+    }
+
+    fn fixed_u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        for byte in bytes.iter_mut() {
+            *byte = self.u8()?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(self.error("expected a bool")),
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift >= 64 || (shift == 63 && byte > 1) {
+                return Err(self.error("a number is too large"));
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        Ok(self.u64()? as usize)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let value = self.u64()?;
+        if value > u64::from(u32::MAX) {
+            return Err(self.error("a u32 is too large"));
+        }
+        Ok(value as u32)
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        let value = self.u64()?;
+        Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+    }
+
+    fn i128(&mut self) -> Result<i128> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift >= 128 || (shift == 126 && byte > 3) {
+                return Err(self.error("a number is too large"));
+            }
+            value |= u128::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(((value >> 1) as i128) ^ -((value & 1) as i128));
+            }
+            shift += 7;
+        }
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        let value = self.i64()?;
+        if value < i64::from(i32::MIN) || value > i64::from(i32::MAX) {
+            return Err(self.error("an i32 is too large"));
+        }
+        Ok(value as i32)
+    }
+
+    pub(crate) fn str(&mut self) -> Result<String> {
+        let len = self.usize()?;
+        if len > self.bytes.len() - self.pos {
+            return Err(self.error("it ends early"));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(value) => {
+                self.pos += len;
+                Ok(value)
+            }
+            Err(_) => Err(self.error("a string is not UTF-8")),
+        }
+    }
+
+    /// Reads a definition id, which has to be one of the image's definitions
+    fn id(&mut self) -> Result<DefinitionId> {
+        let id = self.usize()?;
+        if id >= self.definition_count {
+            return Err(self.error(&format!("definition {} doesn't exist", id)));
+        }
+        Ok(id)
+    }
+
+    fn ids(&mut self) -> Result<Vec<DefinitionId>> {
+        self.list(ImageReader::id)
+    }
+
+    pub(crate) fn option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<Option<T>> {
+        if self.bool()? {
+            Ok(Some(read(self)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub(crate) fn list<T>(
+        &mut self,
+        mut read: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<Vec<T>> {
+        let len = self.usize()?;
+        let mut list = vec![];
+        for _ in 0..len {
+            list.push(read(self)?);
+        }
+        Ok(list)
+    }
+
+    fn map<K: Eq + Hash, V>(
+        &mut self,
+        mut read: impl FnMut(&mut Self) -> Result<(K, V)>,
+    ) -> Result<HashMap<K, V>> {
+        Ok(self.list(|r| read(r))?.into_iter().collect())
+    }
+
+    pub(crate) fn location(&mut self) -> Result<Location> {
+        Ok(Location {
+            file: self.str()?,
+            line: self.usize()?,
+            column: self.usize()?,
+        })
+    }
+}
+
+impl BytecodeEngine {
+    /// Loads an engine from an image written by `save_image`.  See `from_image`.
+    pub fn load_image(path: &str) -> Result<BytecodeEngine> {
+        match fs::read(path) {
+            Ok(image) => BytecodeEngine::from_image(&image),
+            Err(e) => Err(PeachError::Io(format!("{}: {}", path, e))),
+        }
+    }
+
+    /// Makes an engine from an image, with the definitions, scopes, and settings it was saved with.  Nothing is
+    /// parsed, so its functions can be evaluated straight away.  The bytecode is verified first, as it didn't come
+    /// from conversion.  The engine starts with the default policy for builtins rather than the one the image was
+    /// saved with, and evaluation holds the program to whatever policy is set with `set_config` after loading, so an
+    /// image can't give itself access the host didn't.  The file builtins need a file root set the same way.
+    /// Natives aren't part of an image, so they have to be registered again.  Images saved with a different version
+    /// of the format or the bytecode, or with features this peach doesn't know, are refused.
+    ///
+    /// Eg)
+    /// ```text
+    /// let bc = BytecodeEngine::load_image("bin.img")?;
+    /// let mut ee = EvalEngine::new();
+    /// ee.eval_program(&bc, "main")?;
+    /// ```
+    pub fn from_image(image: &[u8]) -> Result<BytecodeEngine> {
+        let mut r = ImageReader::new(image);
+        BytecodeEngine::read_image_header(&mut r)?;
+        let mut bc = BytecodeEngine::new();
+
+        bc.config.random_seed = r.option(ImageReader::u64)?;
+        bc.config.widening_coercions = r.bool()?;
+        bc.config.tests = r.bool()?;
+
+        r.definition_count = r.usize()?;
+        bc.definitions = vec![];
+        for _ in 0..r.definition_count {
+            let definition = BytecodeEngine::read_definition(&mut r)?;
+            bc.definitions.push(definition);
+        }
+
+        bc.scopes = r.list(|r| {
+            let mut scope = Scope::new(r.option(ImageReader::usize)?, r.bool()?);
+            scope.definitions = r.map(|r| Ok((r.str()?, r.id()?)))?;
+            scope.private = r
+                .list(ImageReader::str)?
+                .into_iter()
+                .collect::<HashSet<_>>();
+            scope.imported = r.map(|r| {
+                Ok((
+                    r.str()?,
+                    Import {
+                        location: r.option(ImageReader::location)?,
+                        glob: r.bool()?,
+                        used: r.bool()?,
+                    },
+                ))
+            })?;
+            Ok(scope)
+        })?;
+
+        bc.prelude = r.map(|r| Ok((r.str()?, r.id()?)))?;
+        bc.crates = r.map(|r| Ok((r.str()?, r.id()?)))?;
+        bc.reference_tys = r.map(|r| Ok(((r.id()?, r.bool()?), r.id()?)))?;
+        bc.impls = r.list(|r| Ok((r.id()?, r.usize()?)))?;
+        bc.drop_fns = r.map(|r| Ok((r.id()?, r.id()?)))?;
+        bc.struct_instances = r.map(|r| Ok(((r.id()?, r.ids()?), r.id()?)))?;
+        bc.fn_instances = r.map(|r| Ok(((r.id()?, r.ids()?), r.id()?)))?;
+        bc.type_var_bounds = r.map(|r| Ok((r.id()?, r.list(ImageReader::str)?)))?;
+        bc.tests = r.ids()?;
+
+        if r.pos != image.len() {
+            return Err(r.error("it continues past its end"));
+        }
+        bc.check_image_scopes()
+            .map_err(|scope_id| r.error(&format!("scope {} doesn't exist", scope_id)))?;
+
+        bc.verify()?;
+        Ok(bc)
+    }
+
+    fn read_image_header(r: &mut ImageReader) -> Result<()> {
+        if !r.bytes.starts_with(IMAGE_MAGIC) {
+            return Err(PeachError::Parse(
+                "Not a peach image: it doesn't start with \"PEACHIMG\"".to_string(),
+            ));
+        }
+        r.pos = IMAGE_MAGIC.len();
+
+        let version = r.fixed_u32()?;
+        if version != IMAGE_FORMAT_VERSION {
+            return Err(PeachError::Parse(format!(
+                "The image is in version {} of the image format, but this peach only loads version {}",
+                version, IMAGE_FORMAT_VERSION
+            )));
+        }
+        let bytecode_version = r.fixed_u32()?;
+        if bytecode_version != BYTECODE_VERSION {
+            return Err(PeachError::Parse(format!(
+                "The image has version {} of the bytecode, but this peach runs version {}, so it has to be saved again",
+                bytecode_version, BYTECODE_VERSION
+            )));
+        }
+        let features = r.fixed_u32()?;
+        if features & !feature::ALL != 0 {
+            return Err(PeachError::Parse(format!(
+                "The image uses features this peach doesn't know (flags {:#x})",
+                features & !feature::ALL
+            )));
+        }
+        Ok(())
+    }
+
+    /// The flags of the features the image of the engine uses
+    fn image_features(&self) -> u32 {
+        let mut features = 0;
+        let has_extern_fns = self.definitions.iter().any(|definition| match definition {
+            Definition::Fun(fun) => fun.extern_name.is_some(),
+            _ => false,
+        });
+        if has_extern_fns {
+            features |= feature::EXTERN_FNS;
+        }
+        if self.config.tests {
+            features |= feature::TESTS;
+        }
+        features
+    }
+
+    /// Checks that the scopes the image refers to are all there, returning the first one that isn't
+    fn check_image_scopes(&self) -> ::std::result::Result<(), usize> {
+        let mut scope_ids: Vec<usize> = self
+            .scopes
+            .iter()
+            .filter_map(|scope| scope.parent)
+            .collect();
+        scope_ids.extend(self.impls.iter().map(|(_, scope_id)| *scope_id));
+        for definition in &self.definitions {
+            match definition {
+                Definition::Mod(m) => scope_ids.push(m.scope_id),
+                Definition::Impl(imp) => scope_ids.push(imp.scope_id),
+                _ => {}
+            }
+        }
+        match scope_ids
+            .into_iter()
+            .find(|scope_id| *scope_id >= self.scopes.len())
+        {
+            Some(scope_id) => Err(scope_id),
+            None if self.scopes.is_empty() => Err(0),
+            None => Ok(()),
+        }
+    }
+
+    fn read_definition(r: &mut ImageReader) -> Result<Definition> {
+        Ok(match r.u8()? {
+            tag::BUILTIN => Definition::Builtin,
+            tag::TYPE_VARIABLE => Definition::TypeVariable,
+            tag::REFERENCE => Definition::Reference(r.id()?, r.bool()?),
+            tag::FUN => Definition::Fun(BytecodeEngine::read_fun(r)?),
+            tag::INSTANTIATED_FUN => {
+                Definition::InstantiatedFun(r.id()?, r.list(|r| Ok((r.id()?, r.id()?)))?)
+            }
+            tag::STRUCT => Definition::Struct(Struct {
+                fields: r.list(|r| Ok((r.str()?, r.id()?)))?,
+                copy: r.bool()?,
+                instance_of: r.option(|r| Ok((r.id()?, r.ids()?)))?,
+                location: r.option(ImageReader::location)?,
+            }),
+            tag::MOD => Definition::Mod(Mod::new(r.usize()?)),
+            tag::IMPL => Definition::Impl(Impl {
+                self_ty: r.id()?,
+                trait_name: r.option(ImageReader::str)?,
+                scope_id: r.usize()?,
+            }),
+            tag::OMITTED => {
+                let kind = r.str()?;
+                match OMITTED_KINDS.iter().find(|known| **known == kind) {
+                    Some(kind) => Definition::Omitted(kind),
+                    None => return Err(r.error(&format!("{} is not a kind of definition", kind))),
+                }
+            }
+            _ => return Err(r.error("expected a definition")),
+        })
+    }
+
+    fn read_fun(r: &mut ImageReader) -> Result<Fun> {
+        let ty_params = r.ids()?;
+        let params = r.list(|r| Ok(Param::new(r.str()?, r.usize()?, r.id()?)))?;
+        let return_ty = r.id()?;
+        let vars = r.list(|r| {
+            Ok(VarDecl {
+                ident: r.str()?,
+                ty: r.id()?,
+            })
+        })?;
+        let bytecode = r.list(BytecodeEngine::read_bytecode)?;
+        Ok(Fun {
+            ty_params,
+            params,
+            return_ty,
+            vars,
+            bytecode,
+            extern_name: r.option(ImageReader::str)?,
+            native_id: None,
+            location: r.option(ImageReader::location)?,
+            stmt_locations: r.list(|r| Ok((r.usize()?..r.usize()?, r.location()?)))?,
+        })
+    }
+
+    fn read_bytecode(r: &mut ImageReader) -> Result<Bytecode> {
+        let opcode = r.u8()?;
+        let name = match OPCODE_NAMES.get(opcode as usize) {
+            Some(name) => *name,
+            None => return Err(r.error(&format!("{} is not an opcode", opcode))),
+        };
+        Ok(match name {
+            "ReturnLastStackValue" => Bytecode::ReturnLastStackValue,
+            "ReturnVoid" => Bytecode::ReturnVoid,
+            "PushU64" => Bytecode::PushU64(r.u64()?),
+            "PushU32" => Bytecode::PushU32(r.u32()?),
+            "PushI64" => Bytecode::PushI64(r.i64()?),
+            "PushI32" => Bytecode::PushI32(r.i32()?),
+            "PushUnknownInt" => Bytecode::PushUnknownInt(r.i128()?),
+            "PushBool" => Bytecode::PushBool(r.bool()?),
+            "As" => Bytecode::As(r.id()?),
+            "Add" => Bytecode::Add,
+            "Sub" => Bytecode::Sub,
+            "Mul" => Bytecode::Mul,
+            "Div" => Bytecode::Div,
+            "Lt" => Bytecode::Lt,
+            "Neg" => Bytecode::Neg,
+            "Borrow" => Bytecode::Borrow(r.bool()?),
+            "Deref" => Bytecode::Deref,
+            "Dot" => Bytecode::Dot(r.str()?),
+            "VarDecl" => Bytecode::VarDecl(r.usize()?),
+            "VarDeclUninit" => Bytecode::VarDeclUninit(r.usize()?),
+            "Var" => Bytecode::Var(r.usize()?),
+            "Move" => Bytecode::Move(r.usize()?),
+            "DropVars" => Bytecode::DropVars(r.list(|r| Ok((r.usize()?, r.id()?)))?),
+            "Assign" => Bytecode::Assign,
+            "Call" => Bytecode::Call(r.id()?),
+            "If" => Bytecode::If(r.usize()?, r.id()?),
+            "Else" => Bytecode::Else(r.usize()?, r.id()?),
+            "EndIf" => Bytecode::EndIf(r.id()?),
+            "BeginWhile" => Bytecode::BeginWhile,
+            "WhileCond" => Bytecode::WhileCond(r.usize()?),
+            "EndWhile" => Bytecode::EndWhile(r.usize()?),
+            "DebugPrint" => Bytecode::DebugPrint(r.id()?),
+            "ReadInput" => Bytecode::ReadInput,
+            "ReadClock" => Bytecode::ReadClock,
+            "RandomBelow" => Bytecode::RandomBelow,
+            "ArgCount" => Bytecode::ArgCount,
+            "Arg" => Bytecode::Arg,
+            "Env" => Bytecode::Env(r.str()?),
+            "ReadFile" => Bytecode::ReadFile(r.str()?),
+            "WriteFile" => Bytecode::WriteFile(r.str()?),
+            "Panic" => Bytecode::Panic(r.str()?),
+            "Exit" => Bytecode::Exit,
+            "LValueVar" => Bytecode::LValueVar(r.usize()?),
+            "LValueDot" => Bytecode::LValueDot(r.str()?),
+            "LValueDeref" => Bytecode::LValueDeref,
+            "AddVarConst" => Bytecode::AddVarConst(r.usize()?, r.i32()?),
+            "SubVarConst" => Bytecode::SubVarConst(r.usize()?, r.i32()?),
+            "LtVarConst" => Bytecode::LtVarConst(r.usize()?, r.i32()?),
+            "AssignVar" => Bytecode::AssignVar(r.usize()?),
+            // Raw pointers and natives are refused when saving
+            _ => return Err(r.error(&format!("{} can't be loaded", name))),
+        })
+    }
+
+    /// Writes the image of the engine to a file.  See `to_image`.
+    pub fn save_image(&self, path: &str) -> Result<()> {
+        let image = self.to_image()?;
+        fs::write(path, image).map_err(|e| PeachError::Io(format!("{}: {}", path, e)))
+    }
+
+    /// The image of the engine: its definitions, scopes, and the other state evaluation needs, like the prelude, the
+    /// `Drop` implementations, and the settings of the configuration.  Which builtins are allowed is left out, as
+    /// that's up to whoever runs the image.  Functions that call natives can't be saved, as natives are Rust closures
+    /// the host registers.
+    pub fn to_image(&self) -> Result<Vec<u8>> {
+        let mut w = ImageWriter::default();
+
+        w.bytes.extend_from_slice(IMAGE_MAGIC);
+        w.fixed_u32(IMAGE_FORMAT_VERSION);
+        w.fixed_u32(BYTECODE_VERSION);
+        w.fixed_u32(self.image_features());
+
+        w.option(self.config.random_seed, ImageWriter::u64);
+        w.bool(self.config.widening_coercions);
+        w.bool(self.config.tests);
+
+        w.usize(self.definitions.len());
+        for (definition_id, definition) in self.definitions.iter().enumerate() {
+            self.write_definition(&mut w, definition_id, definition)?;
+        }
+
+        w.usize(self.scopes.len());
+        for scope in &self.scopes {
+            w.option(scope.parent, ImageWriter::usize);
+            w.bool(scope.is_mod);
+            w.map(&scope.definitions, |w, name, id| {
+                w.str(name);
+                w.usize(*id);
+            });
+            let mut private: Vec<&String> = scope.private.iter().collect();
+            private.sort();
+            w.usize(private.len());
+            for name in private {
+                w.str(name);
+            }
+            w.map(&scope.imported, |w, name, import| {
+                w.str(name);
+                w.option(import.location.as_ref(), ImageWriter::location);
+                w.bool(import.glob);
+                w.bool(import.used);
+            });
+        }
+
+        let write_named = |w: &mut ImageWriter, name: &String, id: &DefinitionId| {
+            w.str(name);
+            w.usize(*id);
+        };
+        w.map(&self.prelude, write_named);
+        w.map(&self.crates, write_named);
+        w.map(&self.reference_tys, |w, (ty, mutable), id| {
+            w.usize(*ty);
+            w.bool(*mutable);
+            w.usize(*id);
+        });
+        w.usize(self.impls.len());
+        for (impl_id, scope_id) in &self.impls {
+            w.usize(*impl_id);
+            w.usize(*scope_id);
+        }
+        w.map(&self.drop_fns, |w, ty, drop_fn| {
+            w.usize(*ty);
+            w.usize(*drop_fn);
+        });
+        let write_instance = |w: &mut ImageWriter,
+                              (generic_id, type_args): &(DefinitionId, Vec<DefinitionId>),
+                              id: &DefinitionId| {
+            w.usize(*generic_id);
+            w.ids(type_args);
+            w.usize(*id);
+        };
+        w.map(&self.struct_instances, write_instance);
+        w.map(&self.fn_instances, write_instance);
+        w.map(&self.type_var_bounds, |w, ty, bounds| {
+            w.usize(*ty);
+            w.usize(bounds.len());
+            for bound in bounds {
+                w.str(bound);
+            }
+        });
+        w.ids(&self.tests);
+
+        Ok(w.bytes)
+    }
+
+    fn write_definition(
+        &self,
+        w: &mut ImageWriter,
+        definition_id: DefinitionId,
+        definition: &Definition,
+    ) -> Result<()> {
+        match definition {
+            Definition::Builtin => w.u8(tag::BUILTIN),
+            Definition::TypeVariable => w.u8(tag::TYPE_VARIABLE),
+            Definition::Reference(ty, mutable) => {
+                w.u8(tag::REFERENCE);
+                w.usize(*ty);
+                w.bool(*mutable);
+            }
+            // Natives are saved like the functions that were never processed, and calls to them are refused
+            Definition::Fun(fun) if fun.native_id.is_none() => {
+                w.u8(tag::FUN);
+                self.write_fun(w, definition_id, fun)?;
+            }
+            Definition::InstantiatedFun(generic_id, substitutions) => {
+                w.u8(tag::INSTANTIATED_FUN);
+                w.usize(*generic_id);
+                w.usize(substitutions.len());
+                for (ty_param, ty) in substitutions {
+                    w.usize(*ty_param);
+                    w.usize(*ty);
+                }
+            }
+            Definition::Struct(st) => {
+                w.u8(tag::STRUCT);
+                w.usize(st.fields.len());
+                for (name, ty) in &st.fields {
+                    w.str(name);
+                    w.usize(*ty);
+                }
+                w.bool(st.copy);
+                w.option(st.instance_of.as_ref(), |w, (generic_id, type_args)| {
+                    w.usize(*generic_id);
+                    w.ids(type_args);
+                });
+                w.option(st.location.as_ref(), ImageWriter::location);
+            }
+            Definition::Mod(m) => {
+                w.u8(tag::MOD);
+                w.usize(m.scope_id);
+            }
+            Definition::Impl(imp) => {
+                w.u8(tag::IMPL);
+                w.usize(imp.self_ty);
+                w.option(imp.trait_name.as_ref(), |w, name| w.str(name));
+                w.usize(imp.scope_id);
+            }
+            _ => {
+                w.u8(tag::OMITTED);
+                w.str(definition.kind());
+            }
+        }
+        Ok(())
+    }
+
+    fn write_fun(&self, w: &mut ImageWriter, definition_id: DefinitionId, fun: &Fun) -> Result<()> {
+        w.ids(&fun.ty_params);
+        w.usize(fun.params.len());
+        for param in &fun.params {
+            w.str(&param.name);
+            w.usize(param.var_id);
+            w.usize(param.ty);
+        }
+        w.usize(fun.return_ty);
+        w.usize(fun.vars.len());
+        for var in &fun.vars {
+            w.str(&var.ident);
+            w.usize(var.ty);
+        }
+
+        w.usize(fun.bytecode.len());
+        for code in &fun.bytecode {
+            let opcode = OPCODE_NAMES
+                .iter()
+                .position(|name| *name == code.name())
+                .expect("every instruction has an opcode");
+            w.u8(opcode as u8);
+            match code {
+                Bytecode::PushU64(value) => w.u64(*value),
+                Bytecode::PushU32(value) => w.u64(u64::from(*value)),
+                Bytecode::PushI64(value) => w.i64(*value),
+                Bytecode::PushI32(value) => w.i64(i64::from(*value)),
+                Bytecode::PushUnknownInt(value) => w.i128(*value),
+                Bytecode::PushBool(value) | Bytecode::Borrow(value) => w.bool(*value),
+                Bytecode::PushRawPtr(_) | Bytecode::CallNative(_) => bail!(
+                    "Function {} uses {}, which can't be saved in an image",
+                    self.image_fn_name(definition_id),
+                    match code {
+                        Bytecode::PushRawPtr(_) => "a raw pointer",
+                        _ => "a native function",
+                    }
+                ),
+                Bytecode::As(id)
+                | Bytecode::Call(id)
+                | Bytecode::EndIf(id)
+                | Bytecode::DebugPrint(id) => w.usize(*id),
+                Bytecode::VarDecl(var_id)
+                | Bytecode::VarDeclUninit(var_id)
+                | Bytecode::Var(var_id)
+                | Bytecode::Move(var_id)
+                | Bytecode::LValueVar(var_id)
+                | Bytecode::AssignVar(var_id) => w.usize(*var_id),
+                Bytecode::WhileCond(offset) | Bytecode::EndWhile(offset) => w.usize(*offset),
+                Bytecode::If(offset, ty) | Bytecode::Else(offset, ty) => {
+                    w.usize(*offset);
+                    w.usize(*ty);
+                }
+                Bytecode::DropVars(vars) => {
+                    w.usize(vars.len());
+                    for (var_id, ty) in vars {
+                        w.usize(*var_id);
+                        w.usize(*ty);
+                    }
+                }
+                Bytecode::Dot(name)
+                | Bytecode::LValueDot(name)
+                | Bytecode::Env(name)
+                | Bytecode::ReadFile(name)
+                | Bytecode::WriteFile(name)
+                | Bytecode::Panic(name) => w.str(name),
+                Bytecode::AddVarConst(var_id, value)
+                | Bytecode::SubVarConst(var_id, value)
+                | Bytecode::LtVarConst(var_id, value) => {
+                    w.usize(*var_id);
+                    w.i64(i64::from(*value));
+                }
+                Bytecode::ReturnLastStackValue
+                | Bytecode::ReturnVoid
+                | Bytecode::Add
+                | Bytecode::Sub
+                | Bytecode::Mul
+                | Bytecode::Div
+                | Bytecode::Lt
+                | Bytecode::Neg
+                | Bytecode::Deref
+                | Bytecode::Assign
+                | Bytecode::BeginWhile
+                | Bytecode::ReadInput
+                | Bytecode::ReadClock
+                | Bytecode::RandomBelow
+                | Bytecode::ArgCount
+                | Bytecode::Arg
+                | Bytecode::Exit
+                | Bytecode::LValueDeref => {}
+            }
+        }
+
+        w.option(fun.extern_name.as_ref(), |w, name| w.str(name));
+        w.option(fun.location.as_ref(), ImageWriter::location);
+        w.usize(fun.stmt_locations.len());
+        for (range, location) in &fun.stmt_locations {
+            w.usize(range.start);
+            w.usize(range.end);
+            w.location(location);
+        }
+        Ok(())
+    }
+
+    fn image_fn_name(&self, definition_id: DefinitionId) -> String {
+        self.qualified_name(definition_id)
+            .unwrap_or_else(|| format!("{{fn {}}}", definition_id))
+    }
+}
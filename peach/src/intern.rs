@@ -0,0 +1,40 @@
+//! Interning for the strings the evaluator uses at runtime, like the names of fields.  Each distinct string is kept
+//! once and referred to by a `Symbol`, so storing a name doesn't allocate and comparing two is comparing integers.
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct Symbol(u32);
+
+pub(crate) struct Interner {
+    symbols: HashMap<String, Symbol>,
+    /// The string of each symbol, indexed by the symbol
+    names: Vec<String>,
+}
+
+impl Interner {
+    pub(crate) fn new() -> Interner {
+        Interner {
+            symbols: HashMap::new(),
+            names: vec![],
+        }
+    }
+
+    pub(crate) fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(symbol) = self.symbols.get(name) {
+            return *symbol;
+        }
+        let symbol = Symbol(self.names.len() as u32);
+        self.names.push(name.to_string());
+        self.symbols.insert(name.to_string(), symbol);
+        symbol
+    }
+
+    /// The symbol for the string, if it's been interned.  A string which hasn't been can't match any symbol.
+    pub(crate) fn get(&self, name: &str) -> Option<Symbol> {
+        self.symbols.get(name).cloned()
+    }
+
+    pub(crate) fn name(&self, symbol: Symbol) -> &str {
+        &self.names[symbol.0 as usize]
+    }
+}
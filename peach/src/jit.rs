@@ -0,0 +1,1052 @@
+//! Compiles functions to native code with cranelift, so that `eval_engine_jit` can run them in place of evaluating
+//! them.  Only built with the `jit` feature.
+//!
+//! Functions are compiled when they only work with integers and bools, and only call functions that are compiled
+//! too.  The others are evaluated as usual, and their calls into compiled functions run the native code.
+use bytecode::{builtin_type, Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use cranelift_codegen::entity::EntityRef;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, FuncRef, Function, InstBuilder, MemFlags, Signature, TrapCode, Type,
+    UserFuncName,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use error::Result;
+use eval::{EvalEngine, Halt, LimitError, OverflowPolicy, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem;
+
+/// The most calls compiled code may nest when the engine has no lower call depth limit.  Compiled calls use the
+/// native stack, so deeper recursion is evaluated instead.
+const JIT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// Why compiled code stopped, as it's stored in `Status::error`
+const ADD_OVERFLOW: u32 = 1;
+const SUBTRACT_OVERFLOW: u32 = 2;
+const MULTIPLY_OVERFLOW: u32 = 3;
+const DIVIDE_BY_ZERO: u32 = 4;
+const DIVIDE_OVERFLOW: u32 = 5;
+const NEGATE_OVERFLOW: u32 = 6;
+const CALL_DEPTH: u32 = 7;
+
+/// Shared by the host and the compiled code it calls.  When compiled code can't go on, it sets `error` and returns,
+/// and each compiled caller sees that and returns too.
+#[repr(C)]
+struct Status {
+    error: u32,
+    /// How many compiled calls are in progress
+    depth: u32,
+    max_depth: u32,
+}
+
+const STATUS_ERROR: i32 = 0;
+const STATUS_DEPTH: i32 = 4;
+const STATUS_MAX_DEPTH: i32 = 8;
+
+/// Every compiled function is called by the host through a trampoline of this type.  It reads the function's
+/// arguments from an array, and returns its result widened to 64 bits.
+type Trampoline = extern "C" fn(*const u64, *mut Status) -> u64;
+
+/// The substitutions for the type parameters of a generic function
+type Instantiations<'a> = Option<&'a [(DefinitionId, DefinitionId)]>;
+
+/// The types of a function's parameters, and of its result
+type FnTypes = (Vec<DefinitionId>, DefinitionId);
+
+/// A compiled function, and the types of the values it takes and returns
+struct CompiledFn {
+    trampoline: Trampoline,
+    param_tys: Vec<DefinitionId>,
+    return_ty: DefinitionId,
+}
+
+/// What happened when the evaluator called a compiled function
+pub(crate) enum JitCall {
+    /// The function's result was pushed
+    Returned,
+    /// The function stopped with an error, which `halted` has
+    Halted,
+    /// Its calls nested too deeply for the native stack.  Compiled functions only compute a result, so the call is
+    /// evaluated instead, and its arguments are left on the stack for that.
+    TooDeep,
+}
+
+/// The functions of a program that were compiled to native code
+pub(crate) struct JitProgram {
+    /// Owns the memory the compiled code is in
+    module: Option<JITModule>,
+    fns: HashMap<DefinitionId, CompiledFn>,
+}
+
+impl Drop for JitProgram {
+    fn drop(&mut self) {
+        // Nothing refers to the compiled code once the program is dropped
+        if let Some(module) = self.module.take() {
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// The cranelift type of a value of type `ty`, and whether it's signed.  Integers whose type was never settled are
+/// signed 32-bit, as they are in the evaluator.
+fn scalar_type(ty: DefinitionId) -> Option<(Type, bool)> {
+    match ty {
+        builtin_type::U64 => Some((types::I64, false)),
+        builtin_type::U32 => Some((types::I32, false)),
+        builtin_type::I64 => Some((types::I64, true)),
+        builtin_type::I32 | builtin_type::UNKNOWN_INT => Some((types::I32, true)),
+        builtin_type::BOOL => Some((types::I8, false)),
+        _ => None,
+    }
+}
+
+fn is_int(ty: DefinitionId) -> bool {
+    ty != builtin_type::BOOL && scalar_type(ty).is_some()
+}
+
+/// The function a definition calls, and the instantiation of it if it's generic, when it's one that could be
+/// compiled
+fn callable_fn(
+    bc: &BytecodeEngine,
+    definition_id: DefinitionId,
+) -> Option<(&Fun, Instantiations<'_>)> {
+    match bc.definitions.get(definition_id)? {
+        Definition::Fun(fun) if !fun.is_generic() => Some((fun, None)),
+        Definition::InstantiatedFun(orig_id, instantiations) => match bc.definitions[*orig_id] {
+            Definition::Fun(ref fun) => Some((fun, Some(&instantiations[..]))),
+            _ => None,
+        },
+        _ => None,
+    }
+    .filter(|(fun, _)| fun.extern_name.is_none() && fun.native_id.is_none())
+}
+
+/// The types of a function's parameters and result, with any type parameters substituted
+fn fn_types(bc: &BytecodeEngine, fun: &Fun, instantiations: Instantiations) -> FnTypes {
+    let resolve = |ty| match instantiations {
+        Some(instantiations) => bc.find_substituted_type(ty, instantiations),
+        None => ty,
+    };
+    (
+        fun.params.iter().map(|param| resolve(param.ty)).collect(),
+        resolve(fun.return_ty),
+    )
+}
+
+/// The signature compiled functions are called with: their parameters, then the status
+fn fn_signature(
+    module: &JITModule,
+    param_tys: &[DefinitionId],
+    return_ty: DefinitionId,
+) -> Option<Signature> {
+    let mut sig = module.make_signature();
+    for ty in param_tys {
+        sig.params.push(AbiParam::new(scalar_type(*ty)?.0));
+    }
+    sig.params
+        .push(AbiParam::new(module.target_config().pointer_type()));
+    if return_ty != builtin_type::VOID {
+        sig.returns.push(AbiParam::new(scalar_type(return_ty)?.0));
+    }
+    Some(sig)
+}
+
+/// A value on the expression stack, while a function is being compiled
+enum Operand {
+    Value(cranelift_codegen::ir::Value, DefinitionId),
+    /// The variable an assignment is to
+    Var(usize),
+    /// What calls of functions without results leave
+    Void,
+}
+
+/// The blocks of an if being compiled
+struct IfBlocks {
+    else_block: Block,
+    merge_block: Block,
+    has_else: bool,
+}
+
+/// Compiles the bytecode of one function.  Returns `None` from any instruction that can't be compiled.
+struct FnTranslation<'a, 'b> {
+    bc: &'a BytecodeEngine,
+    fun: &'a Fun,
+    instantiations: Instantiations<'a>,
+    overflow: OverflowPolicy,
+    builder: FunctionBuilder<'b>,
+    func_refs: &'a HashMap<DefinitionId, FuncRef>,
+    status: cranelift_codegen::ir::Value,
+    /// The number of compiled calls in progress when the function was called, which is restored when it returns
+    depth: cranelift_codegen::ir::Value,
+    /// Returns from the function once compiled code has stopped with an error
+    bail_block: Block,
+    stack: Vec<Operand>,
+}
+
+impl<'a, 'b> FnTranslation<'a, 'b> {
+    fn resolve(&self, ty: DefinitionId) -> DefinitionId {
+        match self.instantiations {
+            Some(instantiations) => self.bc.find_substituted_type(ty, instantiations),
+            None => ty,
+        }
+    }
+
+    fn var_ty(&self, var_id: usize) -> DefinitionId {
+        self.resolve(self.fun.vars[var_id].ty)
+    }
+
+    fn pop(&mut self) -> Option<(cranelift_codegen::ir::Value, DefinitionId)> {
+        match self.stack.pop()? {
+            Operand::Value(value, ty) => Some((value, ty)),
+            _ => None,
+        }
+    }
+
+    /// Converts a value to the given type.  Only integers whose type wasn't settled are converted, as they are in the
+    /// evaluator.
+    fn convert(
+        &mut self,
+        value: cranelift_codegen::ir::Value,
+        from: DefinitionId,
+        to: DefinitionId,
+    ) -> Option<cranelift_codegen::ir::Value> {
+        if from == to {
+            return Some(value);
+        }
+        match (from, to) {
+            (builtin_type::U32, builtin_type::U64) => {
+                return Some(self.builder.ins().uextend(types::I64, value))
+            }
+            (builtin_type::I32, builtin_type::I64) => {
+                return Some(self.builder.ins().sextend(types::I64, value))
+            }
+            _ => {}
+        }
+        if from != builtin_type::UNKNOWN_INT || !is_int(to) {
+            return None;
+        }
+        match scalar_type(to)?.0 {
+            types::I32 => Some(value),
+            ty => Some(self.builder.ins().sextend(ty, value)),
+        }
+    }
+
+    /// Pops the operands of an arithmetic or comparison instruction.  An integer whose type wasn't settled takes the
+    /// type of the other operand.
+    fn pop_operands(
+        &mut self,
+    ) -> Option<(
+        cranelift_codegen::ir::Value,
+        cranelift_codegen::ir::Value,
+        DefinitionId,
+    )> {
+        let (rhs, rhs_ty) = self.pop()?;
+        let (lhs, lhs_ty) = self.pop()?;
+        let ty = if lhs_ty == builtin_type::UNKNOWN_INT {
+            rhs_ty
+        } else {
+            lhs_ty
+        };
+        if !is_int(ty) {
+            return None;
+        }
+        let lhs = self.convert(lhs, lhs_ty, ty)?;
+        let rhs = self.convert(rhs, rhs_ty, ty)?;
+        Some((lhs, rhs, ty))
+    }
+
+    /// Stops with the given error if `cond` is true
+    fn fail_if(&mut self, cond: cranelift_codegen::ir::Value, error: u32) {
+        let fail_block = self.builder.create_block();
+        let continue_block = self.builder.create_block();
+        self.builder.set_cold_block(fail_block);
+        self.builder
+            .ins()
+            .brif(cond, fail_block, &[], continue_block, &[]);
+
+        self.builder.switch_to_block(fail_block);
+        self.builder.seal_block(fail_block);
+        let error = self.builder.ins().iconst(types::I32, i64::from(error));
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), error, self.status, STATUS_ERROR);
+        self.builder.ins().jump(self.bail_block, &[]);
+
+        self.builder.switch_to_block(continue_block);
+        self.builder.seal_block(continue_block);
+    }
+
+    /// Adds, subtracts, or multiplies the top two values, which overflow as the engine's policy says
+    fn arithmetic(&mut self, code: &Bytecode) -> Option<()> {
+        let (lhs, rhs, ty) = self.pop_operands()?;
+        let signed = scalar_type(ty)?.1;
+        let result = match self.overflow {
+            OverflowPolicy::Wrap => match code {
+                Bytecode::Add => self.builder.ins().iadd(lhs, rhs),
+                Bytecode::Sub => self.builder.ins().isub(lhs, rhs),
+                _ => self.builder.ins().imul(lhs, rhs),
+            },
+            OverflowPolicy::Error => {
+                let (result, overflowed, error) = match (code, signed) {
+                    (Bytecode::Add, false) => {
+                        let (result, overflowed) = self.builder.ins().uadd_overflow(lhs, rhs);
+                        (result, overflowed, ADD_OVERFLOW)
+                    }
+                    (Bytecode::Add, true) => {
+                        let (result, overflowed) = self.builder.ins().sadd_overflow(lhs, rhs);
+                        (result, overflowed, ADD_OVERFLOW)
+                    }
+                    (Bytecode::Sub, false) => {
+                        let (result, overflowed) = self.builder.ins().usub_overflow(lhs, rhs);
+                        (result, overflowed, SUBTRACT_OVERFLOW)
+                    }
+                    (Bytecode::Sub, true) => {
+                        let (result, overflowed) = self.builder.ins().ssub_overflow(lhs, rhs);
+                        (result, overflowed, SUBTRACT_OVERFLOW)
+                    }
+                    (_, false) => {
+                        let (result, overflowed) = self.builder.ins().umul_overflow(lhs, rhs);
+                        (result, overflowed, MULTIPLY_OVERFLOW)
+                    }
+                    (_, true) => {
+                        let (result, overflowed) = self.builder.ins().smul_overflow(lhs, rhs);
+                        (result, overflowed, MULTIPLY_OVERFLOW)
+                    }
+                };
+                self.fail_if(overflowed, error);
+                result
+            }
+        };
+        self.stack.push(Operand::Value(result, ty));
+        Some(())
+    }
+
+    fn divide(&mut self) -> Option<()> {
+        let (lhs, rhs, ty) = self.pop_operands()?;
+        let (ir_ty, signed) = scalar_type(ty)?;
+
+        let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, rhs, 0);
+        self.fail_if(is_zero, DIVIDE_BY_ZERO);
+        let result = if signed {
+            // The smallest value divided by -1 overflows, which the native instruction traps on
+            let min = if ir_ty == types::I64 {
+                i64::MIN
+            } else {
+                i64::from(i32::MIN)
+            };
+            let is_min = self.builder.ins().icmp_imm(IntCC::Equal, lhs, min);
+            let is_minus_one = self.builder.ins().icmp_imm(IntCC::Equal, rhs, -1);
+            let overflows = self.builder.ins().band(is_min, is_minus_one);
+            match self.overflow {
+                OverflowPolicy::Error => {
+                    self.fail_if(overflows, DIVIDE_OVERFLOW);
+                    self.builder.ins().sdiv(lhs, rhs)
+                }
+                OverflowPolicy::Wrap => {
+                    let one = self.builder.ins().iconst(ir_ty, 1);
+                    let divisor = self.builder.ins().select(overflows, one, rhs);
+                    let quotient = self.builder.ins().sdiv(lhs, divisor);
+                    self.builder.ins().select(overflows, lhs, quotient)
+                }
+            }
+        } else {
+            self.builder.ins().udiv(lhs, rhs)
+        };
+        self.stack.push(Operand::Value(result, ty));
+        Some(())
+    }
+
+    fn negate(&mut self) -> Option<()> {
+        let (value, ty) = self.pop()?;
+        let (ir_ty, signed) = scalar_type(ty)?;
+        // The evaluator only negates signed integers
+        if !signed || ty == builtin_type::BOOL {
+            return None;
+        }
+        if self.overflow == OverflowPolicy::Error {
+            let min = if ir_ty == types::I64 {
+                i64::MIN
+            } else {
+                i64::from(i32::MIN)
+            };
+            let is_min = self.builder.ins().icmp_imm(IntCC::Equal, value, min);
+            self.fail_if(is_min, NEGATE_OVERFLOW);
+        }
+        let result = self.builder.ins().ineg(value);
+        self.stack.push(Operand::Value(result, ty));
+        Some(())
+    }
+
+    /// Pushes the variable and constant that a superinstruction stands for
+    fn push_var_and_int(&mut self, var_id: usize, val: i32) {
+        let ty = self.var_ty(var_id);
+        let value = self.builder.use_var(Variable::new(var_id));
+        self.stack.push(Operand::Value(value, ty));
+        let val = self.builder.ins().iconst(types::I32, i64::from(val));
+        self.stack
+            .push(Operand::Value(val, builtin_type::UNKNOWN_INT));
+    }
+
+    fn assign(&mut self, var_id: usize) -> Option<()> {
+        let (value, ty) = self.pop()?;
+        let var_ty = self.var_ty(var_id);
+        let value = self.convert(value, ty, var_ty)?;
+        self.builder.def_var(Variable::new(var_id), value);
+        Some(())
+    }
+
+    fn ret(&mut self, values: &[cranelift_codegen::ir::Value]) {
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), self.depth, self.status, STATUS_DEPTH);
+        self.builder.ins().return_(values);
+
+        // Anything after the return is never reached
+        let block = self.builder.create_block();
+        self.builder.switch_to_block(block);
+        self.builder.seal_block(block);
+    }
+
+    fn call(&mut self, definition_id: DefinitionId) -> Option<()> {
+        let func_ref = *self.func_refs.get(&definition_id)?;
+        let (fun, instantiations) = callable_fn(self.bc, definition_id)?;
+        let (param_tys, return_ty) = fn_types(self.bc, fun, instantiations);
+
+        let mut args = vec![];
+        for param_ty in param_tys.iter().rev() {
+            let (value, ty) = self.pop()?;
+            args.push(self.convert(value, ty, *param_ty)?);
+        }
+        args.reverse();
+        args.push(self.status);
+
+        let call = self.builder.ins().call(func_ref, &args);
+        let result = self.builder.inst_results(call).first().cloned();
+
+        // Stop here too if the callee stopped with an error
+        let error =
+            self.builder
+                .ins()
+                .load(types::I32, MemFlags::trusted(), self.status, STATUS_ERROR);
+        let continue_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(error, self.bail_block, &[], continue_block, &[]);
+        self.builder.switch_to_block(continue_block);
+        self.builder.seal_block(continue_block);
+
+        match result {
+            Some(result) => self.stack.push(Operand::Value(result, return_ty)),
+            None => self.stack.push(Operand::Void),
+        }
+        Some(())
+    }
+
+    fn translate(&mut self) -> Option<()> {
+        let fun = self.fun;
+        let mut ifs: Vec<IfBlocks> = vec![];
+        let mut whiles: Vec<(Block, Block)> = vec![];
+
+        for code in &fun.bytecode {
+            match code {
+                Bytecode::ReturnVoid => {
+                    if self.resolve(fun.return_ty) == builtin_type::VOID {
+                        self.ret(&[]);
+                    } else {
+                        // Only follows the function's own return, so it's never reached
+                        self.builder.ins().trap(TrapCode::unwrap_user(1));
+                        let block = self.builder.create_block();
+                        self.builder.switch_to_block(block);
+                        self.builder.seal_block(block);
+                    }
+                }
+                Bytecode::ReturnLastStackValue => {
+                    let (value, ty) = self.pop()?;
+                    let return_ty = self.resolve(fun.return_ty);
+                    let value = self.convert(value, ty, return_ty)?;
+                    self.ret(&[value]);
+                }
+                Bytecode::As(ty) => {
+                    let (value, from) = self.pop()?;
+                    let to = self.resolve(*ty);
+                    let value = self.convert(value, from, to)?;
+                    self.stack.push(Operand::Value(value, to));
+                }
+                Bytecode::PushU64(val) => {
+                    let value = self.builder.ins().iconst(types::I64, *val as i64);
+                    self.stack.push(Operand::Value(value, builtin_type::U64));
+                }
+                Bytecode::PushU32(val) => {
+                    let value = self.builder.ins().iconst(types::I32, i64::from(*val));
+                    self.stack.push(Operand::Value(value, builtin_type::U32));
+                }
+                Bytecode::PushI64(val) => {
+                    let value = self.builder.ins().iconst(types::I64, *val);
+                    self.stack.push(Operand::Value(value, builtin_type::I64));
+                }
+                Bytecode::PushI32(val) => {
+                    let value = self.builder.ins().iconst(types::I32, i64::from(*val));
+                    self.stack.push(Operand::Value(value, builtin_type::I32));
+                }
+                Bytecode::PushUnknownInt(val) => {
+                    let val = i32::try_from(*val).ok()?;
+                    let value = self.builder.ins().iconst(types::I32, i64::from(val));
+                    self.stack
+                        .push(Operand::Value(value, builtin_type::UNKNOWN_INT));
+                }
+                Bytecode::PushBool(val) => {
+                    let value = self.builder.ins().iconst(types::I8, i64::from(*val));
+                    self.stack.push(Operand::Value(value, builtin_type::BOOL));
+                }
+                Bytecode::Neg => self.negate()?,
+                Bytecode::Add | Bytecode::Sub | Bytecode::Mul => self.arithmetic(code)?,
+                Bytecode::AddVarConst(var_id, val) => {
+                    self.push_var_and_int(*var_id, *val);
+                    self.arithmetic(&Bytecode::Add)?;
+                }
+                Bytecode::SubVarConst(var_id, val) => {
+                    self.push_var_and_int(*var_id, *val);
+                    self.arithmetic(&Bytecode::Sub)?;
+                }
+                Bytecode::Div => self.divide()?,
+                Bytecode::Lt | Bytecode::LtVarConst(_, _) => {
+                    if let Bytecode::LtVarConst(var_id, val) = code {
+                        self.push_var_and_int(*var_id, *val);
+                    }
+                    let (lhs, rhs, ty) = self.pop_operands()?;
+                    let cc = if scalar_type(ty)?.1 {
+                        IntCC::SignedLessThan
+                    } else {
+                        IntCC::UnsignedLessThan
+                    };
+                    let result = self.builder.ins().icmp(cc, lhs, rhs);
+                    self.stack.push(Operand::Value(result, builtin_type::BOOL));
+                }
+                Bytecode::VarDecl(var_id) => self.assign(*var_id)?,
+                // Variables start out as zero, which is never read before they're given a value
+                Bytecode::VarDeclUninit(_) => {}
+                Bytecode::Var(var_id) | Bytecode::Move(var_id) => {
+                    let value = self.builder.use_var(Variable::new(*var_id));
+                    let ty = self.var_ty(*var_id);
+                    self.stack.push(Operand::Value(value, ty));
+                }
+                // Integers and bools have nothing to drop
+                Bytecode::DropVars(_) => {}
+                Bytecode::LValueVar(var_id) => self.stack.push(Operand::Var(*var_id)),
+                Bytecode::Assign => match self.stack.pop()? {
+                    Operand::Var(var_id) => self.assign(var_id)?,
+                    _ => return None,
+                },
+                Bytecode::AssignVar(var_id) => self.assign(*var_id)?,
+                Bytecode::Call(definition_id) => self.call(*definition_id)?,
+                Bytecode::If(_, ty) => {
+                    let (cond, _) = self.pop()?;
+                    let then_block = self.builder.create_block();
+                    let else_block = self.builder.create_block();
+                    let merge_block = self.builder.create_block();
+                    let ty = self.resolve(*ty);
+                    if ty != builtin_type::VOID {
+                        self.builder
+                            .append_block_param(merge_block, scalar_type(ty)?.0);
+                    }
+                    self.builder
+                        .ins()
+                        .brif(cond, then_block, &[], else_block, &[]);
+                    self.builder.switch_to_block(then_block);
+                    self.builder.seal_block(then_block);
+                    ifs.push(IfBlocks {
+                        else_block,
+                        merge_block,
+                        has_else: false,
+                    });
+                }
+                Bytecode::Else(_, ty) => {
+                    let blocks = ifs.last_mut()?;
+                    blocks.has_else = true;
+                    let (else_block, merge_block) = (blocks.else_block, blocks.merge_block);
+                    self.jump_with_result(merge_block, *ty)?;
+                    self.builder.switch_to_block(else_block);
+                    self.builder.seal_block(else_block);
+                }
+                Bytecode::EndIf(ty) => {
+                    let blocks = ifs.pop()?;
+                    if blocks.has_else {
+                        self.jump_with_result(blocks.merge_block, *ty)?;
+                        self.builder.switch_to_block(blocks.merge_block);
+                        self.builder.seal_block(blocks.merge_block);
+                        if let Some(result) = self.builder.block_params(blocks.merge_block).first()
+                        {
+                            let result = *result;
+                            let ty = self.resolve(*ty);
+                            self.stack.push(Operand::Value(result, ty));
+                        }
+                    } else {
+                        // Without an else, the false branch goes straight to the end
+                        self.builder.ins().jump(blocks.else_block, &[]);
+                        self.builder.switch_to_block(blocks.else_block);
+                        self.builder.seal_block(blocks.else_block);
+                    }
+                }
+                Bytecode::BeginWhile => {
+                    let header_block = self.builder.create_block();
+                    let exit_block = self.builder.create_block();
+                    self.builder.ins().jump(header_block, &[]);
+                    self.builder.switch_to_block(header_block);
+                    whiles.push((header_block, exit_block));
+                }
+                Bytecode::WhileCond(_) => {
+                    let (cond, _) = self.pop()?;
+                    let exit_block = whiles.last()?.1;
+                    let body_block = self.builder.create_block();
+                    self.builder
+                        .ins()
+                        .brif(cond, body_block, &[], exit_block, &[]);
+                    self.builder.switch_to_block(body_block);
+                    self.builder.seal_block(body_block);
+                }
+                Bytecode::EndWhile(_) => {
+                    let (header_block, exit_block) = whiles.pop()?;
+                    self.builder.ins().jump(header_block, &[]);
+                    self.builder.seal_block(header_block);
+                    self.builder.switch_to_block(exit_block);
+                    self.builder.seal_block(exit_block);
+                }
+                // References, structs, and anything that reaches outside the engine are left to the evaluator
+                _ => return None,
+            }
+        }
+
+        // Every function ends in a return, so the block after it is never reached
+        self.builder.ins().trap(TrapCode::unwrap_user(1));
+        Some(())
+    }
+
+    /// Ends a branch of an if by jumping to where the branches meet, passing the branch's result if it has one
+    fn jump_with_result(&mut self, merge_block: Block, ty: DefinitionId) -> Option<()> {
+        let ty = self.resolve(ty);
+        if ty == builtin_type::VOID {
+            self.builder.ins().jump(merge_block, &[]);
+        } else {
+            let (value, from) = self.pop()?;
+            let value = self.convert(value, from, ty)?;
+            self.builder.ins().jump(merge_block, &[value]);
+        }
+        Some(())
+    }
+}
+
+/// Compiles a function's bytecode into `func`.  Returns `None` if it does something that can't be compiled, or calls a
+/// function that isn't in `fn_ids`.
+fn translate_fn(
+    module: &mut JITModule,
+    bc: &BytecodeEngine,
+    fun: &Fun,
+    instantiations: Instantiations,
+    overflow: OverflowPolicy,
+    fn_ids: &HashMap<DefinitionId, FuncId>,
+    func: &mut Function,
+) -> Option<()> {
+    let mut func_refs = HashMap::new();
+    for code in &fun.bytecode {
+        if let Bytecode::Call(definition_id) = code {
+            let func_id = *fn_ids.get(definition_id)?;
+            func_refs.insert(*definition_id, module.declare_func_in_func(func_id, func));
+        }
+    }
+    let (_, return_ty) = fn_types(bc, fun, instantiations);
+
+    // Translation can stop part way through, so each function is built with its own context
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(func, &mut builder_context);
+    let entry_block = builder.create_block();
+    builder.append_block_params_for_function_params(entry_block);
+    builder.switch_to_block(entry_block);
+    builder.seal_block(entry_block);
+
+    for (var_id, var) in fun.vars.iter().enumerate() {
+        let ty = match instantiations {
+            Some(instantiations) => bc.find_substituted_type(var.ty, instantiations),
+            None => var.ty,
+        };
+        let ir_ty = scalar_type(ty)?.0;
+        builder.declare_var(Variable::new(var_id), ir_ty);
+        let zero = builder.ins().iconst(ir_ty, 0);
+        builder.def_var(Variable::new(var_id), zero);
+    }
+    let params = builder.block_params(entry_block).to_vec();
+    for (param, value) in fun.params.iter().zip(&params) {
+        builder.def_var(Variable::new(param.var_id), *value);
+    }
+    let status = *params.last()?;
+
+    let depth = builder
+        .ins()
+        .load(types::I32, MemFlags::trusted(), status, STATUS_DEPTH);
+    let max_depth = builder
+        .ins()
+        .load(types::I32, MemFlags::trusted(), status, STATUS_MAX_DEPTH);
+    let new_depth = builder.ins().iadd_imm(depth, 1);
+    builder
+        .ins()
+        .store(MemFlags::trusted(), new_depth, status, STATUS_DEPTH);
+    let too_deep = builder
+        .ins()
+        .icmp(IntCC::UnsignedGreaterThan, new_depth, max_depth);
+
+    let bail_block = builder.create_block();
+    builder.set_cold_block(bail_block);
+    let mut translation = FnTranslation {
+        bc,
+        fun,
+        instantiations,
+        overflow,
+        builder,
+        func_refs: &func_refs,
+        status,
+        depth,
+        bail_block,
+        stack: vec![],
+    };
+    translation.fail_if(too_deep, CALL_DEPTH);
+    translation.translate()?;
+
+    // What's returned once an error is set is never used
+    let mut builder = translation.builder;
+    builder.switch_to_block(bail_block);
+    if return_ty == builtin_type::VOID {
+        builder.ins().return_(&[]);
+    } else {
+        let zero = builder.ins().iconst(scalar_type(return_ty)?.0, 0);
+        builder.ins().return_(&[zero]);
+    }
+    builder.seal_all_blocks();
+    builder.finalize();
+    Some(())
+}
+
+/// Defines the trampoline the host calls a compiled function through
+fn define_trampoline(
+    module: &mut JITModule,
+    definition_id: DefinitionId,
+    func_id: FuncId,
+    compiled: &(Vec<DefinitionId>, DefinitionId),
+    ctx: &mut Context,
+    builder_context: &mut FunctionBuilderContext,
+) -> Option<FuncId> {
+    let (ref param_tys, return_ty) = *compiled;
+    let pointer_type = module.target_config().pointer_type();
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(pointer_type));
+    sig.params.push(AbiParam::new(pointer_type));
+    sig.returns.push(AbiParam::new(types::I64));
+    let trampoline_id = module
+        .declare_function(
+            &format!("trampoline_{}", definition_id),
+            Linkage::Local,
+            &sig,
+        )
+        .ok()?;
+
+    ctx.func = Function::with_name_signature(UserFuncName::user(1, definition_id as u32), sig);
+    let callee = module.declare_func_in_func(func_id, &mut ctx.func);
+    let mut builder = FunctionBuilder::new(&mut ctx.func, builder_context);
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+    builder.seal_block(block);
+    let (args_ptr, status) = (
+        builder.block_params(block)[0],
+        builder.block_params(block)[1],
+    );
+
+    let mut args = vec![];
+    for (idx, ty) in param_tys.iter().enumerate() {
+        let arg = builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), args_ptr, (idx * 8) as i32);
+        let ir_ty = scalar_type(*ty)?.0;
+        if ir_ty == types::I64 {
+            args.push(arg);
+        } else {
+            args.push(builder.ins().ireduce(ir_ty, arg));
+        }
+    }
+    args.push(status);
+    let call = builder.ins().call(callee, &args);
+
+    let result = match builder.inst_results(call).first().cloned() {
+        Some(result) => match scalar_type(return_ty)? {
+            (types::I64, _) => result,
+            (_, true) => builder.ins().sextend(types::I64, result),
+            (_, false) => builder.ins().uextend(types::I64, result),
+        },
+        None => builder.ins().iconst(types::I64, 0),
+    };
+    builder.ins().return_(&[result]);
+    builder.finalize();
+
+    module.define_function(trampoline_id, ctx).ok()?;
+    module.clear_context(ctx);
+    Some(trampoline_id)
+}
+
+/// Defines the functions that can be compiled in the module, along with their trampolines.  Returns the
+/// trampolines, and the types of the functions' parameters and results.
+fn define_fns(
+    module: &mut JITModule,
+    bc: &BytecodeEngine,
+    overflow: OverflowPolicy,
+) -> Option<HashMap<DefinitionId, (FuncId, FnTypes)>> {
+    let mut fn_ids = HashMap::new();
+    let mut fn_tys = HashMap::new();
+    let mut sigs = HashMap::new();
+    for definition_id in 0..bc.definitions.len() {
+        if let Some((fun, instantiations)) = callable_fn(bc, definition_id) {
+            let (param_tys, return_ty) = fn_types(bc, fun, instantiations);
+            if let Some(sig) = fn_signature(module, &param_tys, return_ty) {
+                let func_id = module
+                    .declare_function(&format!("fun_{}", definition_id), Linkage::Local, &sig)
+                    .ok()?;
+                fn_ids.insert(definition_id, func_id);
+                fn_tys.insert(definition_id, (param_tys, return_ty));
+                sigs.insert(definition_id, sig);
+            }
+        }
+    }
+
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut translated = HashMap::new();
+    let mut callees = HashMap::new();
+    for (definition_id, sig) in sigs {
+        let (fun, instantiations) = callable_fn(bc, definition_id)?;
+        let mut func =
+            Function::with_name_signature(UserFuncName::user(0, definition_id as u32), sig);
+        if translate_fn(
+            module,
+            bc,
+            fun,
+            instantiations,
+            overflow,
+            &fn_ids,
+            &mut func,
+        )
+        .is_some()
+        {
+            translated.insert(definition_id, func);
+            let called: Vec<DefinitionId> = fun
+                .bytecode
+                .iter()
+                .filter_map(|code| match code {
+                    Bytecode::Call(callee) => Some(*callee),
+                    _ => None,
+                })
+                .collect();
+            callees.insert(definition_id, called);
+        }
+    }
+
+    // Functions that call a function which couldn't be compiled are left to the evaluator too
+    loop {
+        let uncompiled: Vec<DefinitionId> = translated
+            .keys()
+            .filter(|definition_id| {
+                callees[*definition_id]
+                    .iter()
+                    .any(|callee| !translated.contains_key(callee))
+            })
+            .cloned()
+            .collect();
+        if uncompiled.is_empty() {
+            break;
+        }
+        for definition_id in uncompiled {
+            translated.remove(&definition_id);
+        }
+    }
+
+    let mut ctx = module.make_context();
+    let mut trampolines = HashMap::new();
+    for (definition_id, func) in translated {
+        ctx.func = func;
+        module
+            .define_function(fn_ids[&definition_id], &mut ctx)
+            .ok()?;
+        module.clear_context(&mut ctx);
+
+        let compiled = fn_tys.remove(&definition_id)?;
+        let trampoline_id = define_trampoline(
+            module,
+            definition_id,
+            fn_ids[&definition_id],
+            &compiled,
+            &mut ctx,
+            &mut builder_context,
+        )?;
+        trampolines.insert(definition_id, (trampoline_id, compiled));
+    }
+    Some(trampolines)
+}
+
+impl JitProgram {
+    /// Compiles the functions of the program that can be compiled, for an engine with the given overflow policy.
+    /// Returns `None` if cranelift can't generate code for the host.
+    pub(crate) fn compile(bc: &BytecodeEngine, overflow: OverflowPolicy) -> Option<JitProgram> {
+        let isa_builder = cranelift_native::builder().ok()?;
+        let mut flag_builder = settings::builder();
+        flag_builder.set("opt_level", "speed").ok()?;
+        // The code is put wherever there's memory for it, so it can't rely on being near what it calls
+        flag_builder.set("use_colocated_libcalls", "false").ok()?;
+        flag_builder.set("is_pic", "true").ok()?;
+        let isa = isa_builder
+            .finish(settings::Flags::new(flag_builder))
+            .ok()?;
+        let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        let trampolines = match define_fns(&mut module, bc, overflow) {
+            Some(trampolines) if module.finalize_definitions().is_ok() => trampolines,
+            _ => {
+                unsafe { module.free_memory() };
+                return None;
+            }
+        };
+
+        let mut fns = HashMap::new();
+        for (definition_id, (trampoline_id, (param_tys, return_ty))) in trampolines {
+            let code = module.get_finalized_function(trampoline_id);
+            fns.insert(
+                definition_id,
+                CompiledFn {
+                    trampoline: unsafe { mem::transmute::<*const u8, Trampoline>(code) },
+                    param_tys,
+                    return_ty,
+                },
+            );
+        }
+        Some(JitProgram {
+            module: Some(module),
+            fns,
+        })
+    }
+
+    /// Returns true if the function was compiled
+    pub(crate) fn contains(&self, definition_id: DefinitionId) -> bool {
+        self.fns.contains_key(&definition_id)
+    }
+}
+
+/// The bits a value is passed to compiled code as.  Signed integers are sign extended, so that they can be narrowed
+/// again to any width.
+fn value_bits(value: &Value) -> u64 {
+    match *value {
+        Value::U64(x) => x,
+        Value::U32(x) => u64::from(x),
+        Value::I64(x) => x as u64,
+        Value::I32(x) | Value::UnknownInt(x) => x as i64 as u64,
+        Value::Bool(x) => u64::from(x),
+        _ => 0,
+    }
+}
+
+/// The value of type `ty` that compiled code returned as `bits`
+fn bits_value(bits: u64, ty: DefinitionId) -> Value {
+    match ty {
+        builtin_type::U64 => Value::U64(bits),
+        builtin_type::U32 => Value::U32(bits as u32),
+        builtin_type::I64 => Value::I64(bits as i64),
+        builtin_type::I32 => Value::I32(bits as i32),
+        builtin_type::UNKNOWN_INT => Value::UnknownInt(bits as i32),
+        builtin_type::BOOL => Value::Bool(bits != 0),
+        _ => Value::Void,
+    }
+}
+
+impl EvalEngine {
+    pub(crate) fn is_jit_compiled(&self, definition_id: DefinitionId) -> bool {
+        match self.jit {
+            Some(ref jit) => jit.contains(definition_id),
+            None => false,
+        }
+    }
+
+    /// Calls a compiled function with the arguments on top of the value stack, and pushes its result.  `depth` is
+    /// the number of calls the evaluator has in progress.
+    pub(crate) fn call_jit_compiled(
+        &mut self,
+        definition_id: DefinitionId,
+        depth: usize,
+    ) -> JitCall {
+        let compiled = match self.jit {
+            Some(ref jit) => &jit.fns[&definition_id],
+            None => return JitCall::TooDeep,
+        };
+        let base = self.value_stack.len() - compiled.param_tys.len();
+        let args: Vec<u64> = self.value_stack[base..].iter().map(value_bits).collect();
+
+        // The engine's limit is kept to if it's lower than what the native stack allows
+        let limit = self
+            .limits
+            .max_call_depth
+            .map(|max| max.saturating_sub(depth))
+            .filter(|max| *max <= JIT_MAX_CALL_DEPTH);
+        let mut status = Status {
+            error: 0,
+            depth: 0,
+            max_depth: limit.unwrap_or(JIT_MAX_CALL_DEPTH) as u32,
+        };
+        let result = (compiled.trampoline)(args.as_ptr(), &mut status);
+
+        if status.error == CALL_DEPTH {
+            return match (limit, self.limits.max_call_depth) {
+                (Some(_), Some(max)) => {
+                    let depth = depth + status.depth as usize;
+                    self.halted = Some(Halt::LimitExceeded(LimitError::CallDepth { depth, max }));
+                    JitCall::Halted
+                }
+                _ => JitCall::TooDeep,
+            };
+        }
+        self.value_stack.truncate(base);
+        let message = match status.error {
+            0 => {
+                self.value_stack
+                    .push(bits_value(result, compiled.return_ty));
+                return JitCall::Returned;
+            }
+            ADD_OVERFLOW => "attempt to add with overflow",
+            SUBTRACT_OVERFLOW => "attempt to subtract with overflow",
+            MULTIPLY_OVERFLOW => "attempt to multiply with overflow",
+            DIVIDE_BY_ZERO => "attempt to divide by zero",
+            DIVIDE_OVERFLOW => "attempt to divide with overflow",
+            NEGATE_OVERFLOW => "attempt to negate with overflow",
+            _ => "Internal error: compiled code stopped for an unknown reason",
+        };
+        self.halted = Some(Halt::Error(message.to_string()));
+        JitCall::Halted
+    }
+}
+
+/// Evaluates the program like `EvalEngine::eval_program`, after compiling what it can to native code with cranelift.
+/// Calls of compiled functions run the native code, and everything else is evaluated as usual.
+///
+/// Functions are compiled when they only work with integers and bools, don't print or read anything, and only call
+/// functions that are compiled too.  Their arguments are converted to the types of their parameters, as they are for
+/// native functions, and they return values of their declared type.  Compiled code can't be paused or watched part
+/// way through, so nothing is compiled while the engine has fuel, a deadline, a cancel flag, a debugger, or an
+/// observer.  Calls nested too deeply for the native stack are evaluated instead.
+pub fn eval_engine_jit(
+    ee: &mut EvalEngine,
+    bc: &BytecodeEngine,
+    starting_fn_name: &str,
+) -> Result<Value> {
+    if ee.can_run_compiled() {
+        ee.jit = JitProgram::compile(bc, ee.overflow);
+    }
+    let result = ee.eval_program(bc, starting_fn_name);
+    ee.jit = None;
+    result
+}
@@ -0,0 +1,490 @@
+//! JSON output: the state of an engine for tooling that doesn't link the crate, and small helpers for emitting and
+//! reading JSON without pulling in a serialization dependency
+use bytecode::{Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use error::Location;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// The version of the JSON produced by `BytecodeEngine::to_json`.  It's bumped whenever the shape of the output
+/// changes.
+pub const ENGINE_JSON_FORMAT_VERSION: u32 = 1;
+
+/// Escapes a string and wraps it in quotes so it can be used as a JSON string value
+pub(crate) fn quote(s: &str) -> String {
+    let mut output = String::with_capacity(s.len() + 2);
+    output.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+/// A JSON value that has been read.  Numbers are kept as they were written, so they can be written back unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    /// Reads a JSON value, which has to be all of the text apart from whitespace around it
+    pub(crate) fn parse(text: &str) -> Option<JsonValue> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Some(value),
+            Some(_) => None,
+        }
+    }
+
+    /// The value of the field with the given name, if this is an object that has it
+    pub(crate) fn get(&self, name: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(fields) => fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u64(&self) -> Option<u64> {
+        match self {
+            JsonValue::Number(number) => number.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Writes the value back out as JSON
+    pub(crate) fn to_json(&self) -> String {
+        match self {
+            JsonValue::Null => "null".to_string(),
+            JsonValue::Bool(b) => b.to_string(),
+            JsonValue::Number(number) => number.clone(),
+            JsonValue::String(s) => quote(s),
+            JsonValue::Array(values) => {
+                let values: Vec<String> = values.iter().map(JsonValue::to_json).collect();
+                format!("[{}]", values.join(","))
+            }
+            JsonValue::Object(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(name, value)| format!("{}:{}", quote(name), value.to_json()))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = chars.peek() {
+        chars.next();
+    }
+}
+
+/// Consumes the keyword, whose first letter has already been read
+fn parse_keyword(chars: &mut Peekable<Chars>, rest: &str, value: JsonValue) -> Option<JsonValue> {
+    for expected in rest.chars() {
+        if chars.next() != Some(expected) {
+            return None;
+        }
+    }
+    Some(value)
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
+    skip_whitespace(chars);
+    match chars.next()? {
+        'n' => parse_keyword(chars, "ull", JsonValue::Null),
+        't' => parse_keyword(chars, "rue", JsonValue::Bool(true)),
+        'f' => parse_keyword(chars, "alse", JsonValue::Bool(false)),
+        '"' => parse_string(chars).map(JsonValue::String),
+        '[' => {
+            let mut values = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Some(JsonValue::Array(values));
+            }
+            loop {
+                values.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => {}
+                    ']' => return Some(JsonValue::Array(values)),
+                    _ => return None,
+                }
+            }
+        }
+        '{' => {
+            let mut fields = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Some(JsonValue::Object(fields));
+            }
+            loop {
+                skip_whitespace(chars);
+                if chars.next()? != '"' {
+                    return None;
+                }
+                let name = parse_string(chars)?;
+                skip_whitespace(chars);
+                if chars.next()? != ':' {
+                    return None;
+                }
+                fields.push((name, parse_value(chars)?));
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => {}
+                    '}' => return Some(JsonValue::Object(fields)),
+                    _ => return None,
+                }
+            }
+        }
+        c if c == '-' || c.is_ascii_digit() => {
+            let mut number = c.to_string();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            number.parse::<f64>().ok()?;
+            Some(JsonValue::Number(number))
+        }
+        _ => None,
+    }
+}
+
+/// Reads the rest of a string, whose opening quote has already been read
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                'r' => s.push('\r'),
+                't' => s.push('\t'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    let mut code = parse_hex4(chars)?;
+                    // Characters outside the basic plane are written as a pair of surrogates
+                    if (0xd800..0xdc00).contains(&code) {
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low = parse_hex4(chars)?.checked_sub(0xdc00)?;
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low & 0x3ff);
+                    }
+                    s.push(::std::char::from_u32(code)?);
+                }
+                c => s.push(c),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut Peekable<Chars>) -> Option<u32> {
+    let mut code = 0;
+    for _ in 0..4 {
+        code = code * 16 + chars.next()?.to_digit(16)?;
+    }
+    Some(code)
+}
+
+fn location(location: &Option<Location>) -> String {
+    match location {
+        Some(location) => format!(
+            "{{\"file\":{},\"line\":{},\"column\":{}}}",
+            quote(&location.file),
+            location.line,
+            location.column
+        ),
+        None => "null".to_string(),
+    }
+}
+
+fn option<T>(value: Option<T>, to_json: impl FnOnce(T) -> String) -> String {
+    value.map(to_json).unwrap_or_else(|| "null".to_string())
+}
+
+/// The operands of an instruction, in order.  Ids, offsets and integers are numbers.
+fn operands(code: &Bytecode) -> Vec<String> {
+    match code {
+        Bytecode::PushU64(value) => vec![value.to_string()],
+        Bytecode::PushU32(value) => vec![value.to_string()],
+        Bytecode::PushI64(value) => vec![value.to_string()],
+        Bytecode::PushI32(value) => vec![value.to_string()],
+        Bytecode::PushUnknownInt(value) => vec![value.to_string()],
+        Bytecode::PushBool(value) | Bytecode::Borrow(value) => vec![value.to_string()],
+        // The address only means something in the process that converted the program
+        Bytecode::PushRawPtr(_) => vec!["null".to_string()],
+        Bytecode::As(id)
+        | Bytecode::Call(id)
+        | Bytecode::CallNative(id)
+        | Bytecode::EndIf(id)
+        | Bytecode::DebugPrint(id)
+        | Bytecode::VarDecl(id)
+        | Bytecode::VarDeclUninit(id)
+        | Bytecode::Var(id)
+        | Bytecode::Move(id)
+        | Bytecode::LValueVar(id)
+        | Bytecode::AssignVar(id)
+        | Bytecode::WhileCond(id)
+        | Bytecode::EndWhile(id) => vec![id.to_string()],
+        Bytecode::If(offset, ty) | Bytecode::Else(offset, ty) => {
+            vec![offset.to_string(), ty.to_string()]
+        }
+        Bytecode::DropVars(vars) => {
+            let vars: Vec<String> = vars
+                .iter()
+                .map(|(var_id, ty)| format!("[{},{}]", var_id, ty))
+                .collect();
+            vec![format!("[{}]", vars.join(","))]
+        }
+        Bytecode::Dot(name)
+        | Bytecode::LValueDot(name)
+        | Bytecode::Env(name)
+        | Bytecode::ReadFile(name)
+        | Bytecode::WriteFile(name)
+        | Bytecode::Panic(name) => vec![quote(name)],
+        Bytecode::AddVarConst(var_id, value)
+        | Bytecode::SubVarConst(var_id, value)
+        | Bytecode::LtVarConst(var_id, value) => vec![var_id.to_string(), value.to_string()],
+        _ => vec![],
+    }
+}
+
+impl BytecodeEngine {
+    /// Describes the engine's scopes and definitions as JSON, for editors, visualizers, and other tools that don't
+    /// link the crate.  Definitions are referred to by their id, which is their index in `definitions`, and scopes
+    /// by their index in `scopes`.  Types are written as their names, like `&mut Point`, except in the operands of
+    /// instructions, where they're ids.
+    ///
+    /// ```text
+    /// {"version": number, "scopes": [scope], "prelude": [{"name": string, "id": number}], "definitions": [definition]}
+    /// ```
+    ///
+    /// Each scope is `{"id": number, "parent": number or null, "module": bool, "names": [name]}`, where each name is
+    /// `{"name": string, "id": number, "public": bool, "imported": bool}`.  Blocks have scopes of their own, which
+    /// aren't modules.
+    ///
+    /// Each definition has `"id"`, `"kind"` (like `"fn"`, `"struct"`, or `"type"`), `"name"` (its path, or null if it
+    /// isn't named), and `"processed"`, which is false for items nothing processed has needed yet.  Processed ones
+    /// have more, by kind:
+    ///   * functions: `"type_params"` (ids), `"params"` (`[{"name": string, "type": string}]`), `"return_type"`,
+    ///     `"location"`, `"vars"` (`[{"name": string, "type": string}]`, indexed by the variable ids in the bytecode),
+    ///     and `"bytecode"`, as `[{"op": string, "operands": [...]}]`
+    ///   * instances of generic functions: `"instance_of"` (an id) and `"type_args"` (`[{"param": number, "type":
+    ///     string}]`)
+    ///   * structs: `"fields"` (`[{"name": string, "type": string}]`), `"copy"`, `"instance_of"` (the id of the
+    ///     generic struct, or null), and `"location"`
+    ///   * modules: `"scope"`
+    ///   * impls: `"self_type"`, `"trait"` (or null), and `"scope"`
+    ///   * references: `"referenced"` (an id) and `"mutable"`
+    ///
+    /// Locations are `{"file": string, "line": number, "column": number}`, or null if they aren't known.
+    pub fn to_json(&self) -> String {
+        let scopes: Vec<String> = self
+            .scopes
+            .iter()
+            .enumerate()
+            .map(|(scope_id, scope)| {
+                let mut names: Vec<(&String, &DefinitionId)> = scope.definitions.iter().collect();
+                names.sort();
+                let names: Vec<String> = names
+                    .into_iter()
+                    .map(|(name, definition_id)| {
+                        format!(
+                            "{{\"name\":{},\"id\":{},\"public\":{},\"imported\":{}}}",
+                            quote(name),
+                            definition_id,
+                            !scope.is_private(name),
+                            scope.imported.contains_key(name)
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{{\"id\":{},\"parent\":{},\"module\":{},\"names\":[{}]}}",
+                    scope_id,
+                    option(scope.parent, |parent| parent.to_string()),
+                    scope.is_mod,
+                    names.join(",")
+                )
+            })
+            .collect();
+
+        let mut prelude: Vec<(&String, &DefinitionId)> = self.prelude.iter().collect();
+        prelude.sort();
+        let prelude: Vec<String> = prelude
+            .into_iter()
+            .map(|(name, definition_id)| {
+                format!("{{\"name\":{},\"id\":{}}}", quote(name), definition_id)
+            })
+            .collect();
+
+        let definitions: Vec<String> = (0..self.definitions.len())
+            .map(|definition_id| self.definition_json(definition_id))
+            .collect();
+
+        format!(
+            "{{\"version\":{},\"scopes\":[{}],\"prelude\":[{}],\"definitions\":[{}]}}",
+            ENGINE_JSON_FORMAT_VERSION,
+            scopes.join(","),
+            prelude.join(","),
+            definitions.join(",")
+        )
+    }
+
+    fn definition_json(&self, definition_id: DefinitionId) -> String {
+        let definition = &self.definitions[definition_id];
+        let ty = |ty: DefinitionId| quote(&self.ty(ty).to_string());
+        let named_tys = |names: Vec<(&str, DefinitionId)>| {
+            let named: Vec<String> = names
+                .into_iter()
+                .map(|(name, id)| format!("{{\"name\":{},\"type\":{}}}", quote(name), ty(id)))
+                .collect();
+            format!("[{}]", named.join(","))
+        };
+
+        let details = match definition {
+            Definition::Fun(fun) => self.fun_json(fun),
+            Definition::InstantiatedFun(generic_id, type_args) => {
+                let type_args: Vec<String> = type_args
+                    .iter()
+                    .map(|(param, arg)| format!("{{\"param\":{},\"type\":{}}}", param, ty(*arg)))
+                    .collect();
+                format!(
+                    "\"instance_of\":{},\"type_args\":[{}]",
+                    generic_id,
+                    type_args.join(",")
+                )
+            }
+            Definition::Struct(st) => format!(
+                "\"fields\":{},\"copy\":{},\"instance_of\":{},\"location\":{}",
+                named_tys(
+                    st.fields
+                        .iter()
+                        .map(|(name, id)| (name.as_str(), *id))
+                        .collect()
+                ),
+                st.copy,
+                option(st.instance_of.as_ref(), |(generic_id, _)| generic_id
+                    .to_string()),
+                location(&st.location)
+            ),
+            Definition::Mod(m) => format!("\"scope\":{}", m.scope_id),
+            Definition::Impl(imp) => format!(
+                "\"self_type\":{},\"trait\":{},\"scope\":{}",
+                ty(imp.self_ty),
+                option(imp.trait_name.as_ref(), |name| quote(name)),
+                imp.scope_id
+            ),
+            Definition::Reference(referenced, mutable) => {
+                format!("\"referenced\":{},\"mutable\":{}", referenced, mutable)
+            }
+            _ => String::new(),
+        };
+        let processed = !matches!(
+            definition,
+            Definition::LazyFn(_)
+                | Definition::LazyMod(_)
+                | Definition::LazyStruct(_)
+                | Definition::LazyImpl(_)
+                | Definition::LazyTrait(_)
+                | Definition::Omitted(_)
+        );
+
+        // Types that aren't declared by name, like references and instances of generic structs, are named like types
+        let name = match definition {
+            Definition::Builtin | Definition::TypeVariable | Definition::Reference(..) => {
+                Some(self.ty(definition_id).to_string())
+            }
+            Definition::Struct(st) if st.instance_of.is_some() => {
+                Some(self.ty(definition_id).to_string())
+            }
+            _ => self.qualified_name(definition_id),
+        };
+        let mut output = format!(
+            "{{\"id\":{},\"kind\":{},\"name\":{},\"processed\":{}",
+            definition_id,
+            quote(definition.kind()),
+            option(name, |name| quote(&name)),
+            processed
+        );
+        if !details.is_empty() {
+            output.push(',');
+            output += &details;
+        }
+        output.push('}');
+        output
+    }
+
+    fn fun_json(&self, fun: &Fun) -> String {
+        let ty = |ty: DefinitionId| quote(&self.ty(ty).to_string());
+        let ty_params: Vec<String> = fun.ty_params.iter().map(|id| id.to_string()).collect();
+        let params: Vec<String> = fun
+            .params
+            .iter()
+            .map(|param| {
+                format!(
+                    "{{\"name\":{},\"type\":{}}}",
+                    quote(&param.name),
+                    ty(param.ty)
+                )
+            })
+            .collect();
+        let vars: Vec<String> = fun
+            .vars
+            .iter()
+            .map(|var| format!("{{\"name\":{},\"type\":{}}}", quote(&var.ident), ty(var.ty)))
+            .collect();
+        let bytecode: Vec<String> = fun
+            .bytecode
+            .iter()
+            .map(|code| {
+                format!(
+                    "{{\"op\":{},\"operands\":[{}]}}",
+                    quote(code.name()),
+                    operands(code).join(",")
+                )
+            })
+            .collect();
+        format!(
+            "\"type_params\":[{}],\"params\":[{}],\"return_type\":{},\"location\":{},\"vars\":[{}],\"bytecode\":[{}]",
+            ty_params.join(","),
+            params.join(","),
+            ty(fun.return_ty),
+            location(&fun.location),
+            vars.join(","),
+            bytecode.join(",")
+        )
+    }
+}
@@ -9,12 +9,86 @@
 extern crate proc_macro2;
 extern crate syn;
 extern crate time;
+#[cfg(feature = "jit")]
+extern crate cranelift_codegen;
+#[cfg(feature = "jit")]
+extern crate cranelift_frontend;
+#[cfg(feature = "jit")]
+extern crate cranelift_jit;
+#[cfg(feature = "jit")]
+extern crate cranelift_module;
+#[cfg(feature = "jit")]
+extern crate cranelift_native;
 
+#[macro_use]
+mod error;
+mod bench;
+mod build;
 mod bytecode;
+mod cache;
+mod capabilities;
 mod compile;
+mod coverage;
+mod dap;
+mod debugger;
+mod dot;
+mod dump;
 mod eval;
+#[cfg(any(test, feature = "testing"))]
+pub mod golden;
+mod heap;
+mod image;
+mod intern;
+#[cfg(feature = "jit")]
+mod jit;
+mod json;
+mod lsp;
+#[cfg(feature = "llvm")]
+mod llvm;
+mod marshal;
+mod profile;
+mod repl;
+mod replay;
+mod rng;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod test_runner;
 mod tests;
+mod transpile;
 
-pub use bytecode::{builtin_type, Bytecode, BytecodeEngine, Fun, VarStack};
-pub use compile::compile_bytecode;
-pub use eval::{EvalEngine, Value};
+pub use bench::{BenchLength, Benchmark};
+pub use build::{build, build_executable};
+#[cfg(feature = "llvm")]
+pub use build::build_llvm_executable;
+pub use bytecode::{
+    builtin_type, BasicBlock, BlockId, Bytecode, BytecodeEngine, Cfg, ConstantFolding,
+    ConstantPropagation, EngineConfig, Fun, Label, Labeled, LabeledCode, NativeId, Pass, Pipeline,
+    Prelude, Ssa, SsaValue, Ty, UnreachableCode, ValueDef, ValueId, VarStack, VerifyError,
+    VerifyProblem,
+};
+pub use capabilities::{
+    Backend, Capabilities, Format, BYTECODE_VERSION, CAPABILITIES_FORMAT_VERSION,
+};
+pub use compile::{compile_bytecode, emit_c};
+pub use coverage::{Coverage, FileCoverage, FunctionCoverage};
+pub use dap::DebugAdapter;
+pub use debugger::{Breakpoint, DebugCommand, Debugger, Pause};
+pub use error::{BacktraceFrame, Location, PeachError, Result, RuntimeError, Warning};
+pub use eval::{
+    EvalEngine, Frame, Halt, LimitError, Observer, OverflowPolicy, Trace, TraceStep, Value,
+    ValueLimits, TRACE_FORMAT_VERSION,
+};
+pub use heap::{Handle, Place};
+pub use image::IMAGE_FORMAT_VERSION;
+pub use json::ENGINE_JSON_FORMAT_VERSION;
+#[cfg(feature = "jit")]
+pub use jit::eval_engine_jit;
+#[cfg(feature = "llvm")]
+pub use llvm::emit_llvm;
+pub use lsp::LanguageServer;
+pub use marshal::{FromPeach, IntoPeach};
+pub use profile::{FunctionProfile, Profile};
+pub use repl::{is_incomplete, ReplOutput, ReplSession};
+pub use replay::{RecordedInput, Recording, RECORDING_FORMAT_VERSION};
+pub use test_runner::{TestReport, TestResult};
+pub use transpile::emit_rust;
@@ -0,0 +1,982 @@
+//! Lowers bytecode to LLVM IR text, so that LLVM's optimizer can build fast native code from it.  Only built with the
+//! `llvm` feature.
+//!
+//! Every variable gets a stack slot, which LLVM's mem2reg pass turns back into registers.  The builtins are calls of
+//! the helpers in the C runtime, so the IR is built together with `runtime.c`.
+use bytecode::{builtin_type, Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use compile::entry_fn_id;
+use error::Result;
+use rng::Rng;
+
+/// The functions and globals of the C runtime that the IR uses
+const RUNTIME_DECLS: &str = "declare i32 @printf(ptr, ...)
+declare i64 @peach_read_u64()
+declare i64 @peach_now_millis()
+declare i64 @peach_random_below(i64)
+declare i64 @peach_arg_count()
+declare i64 @peach_arg(i64)
+declare i64 @peach_env(ptr)
+declare i64 @peach_read_file_u64(ptr)
+declare void @peach_write_file_u64(ptr, i64)
+declare void @peach_panic(ptr)
+declare void @exit(i32)
+declare void @llvm.trap()
+@peach_argc = external global i32
+@peach_argv = external global ptr
+";
+
+/// A value on the expression stack, or the address of a place when it's an lvalue
+struct Operand {
+    repr: String,
+    ty: DefinitionId,
+    place: bool,
+}
+
+impl Operand {
+    fn value(repr: String, ty: DefinitionId) -> Operand {
+        Operand {
+            repr,
+            ty,
+            place: false,
+        }
+    }
+
+    fn place(repr: String, ty: DefinitionId) -> Operand {
+        Operand {
+            repr,
+            ty,
+            place: true,
+        }
+    }
+}
+
+/// The string constants used by the module's functions
+struct Strings {
+    globals: String,
+    count: usize,
+}
+
+impl Strings {
+    /// Adds a constant holding the text, and returns its name
+    fn add(&mut self, text: &str) -> String {
+        let name = format!("@.str.{}", self.count);
+        self.count += 1;
+        self.globals += &format!("{} = {}\n", name, llvm_string(text));
+        name
+    }
+}
+
+/// A private constant holding the text, with a terminating nul
+fn llvm_string(text: &str) -> String {
+    let mut quoted = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'"' | b'\\' => quoted += &format!("\\{:02X}", byte),
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => quoted += &format!("\\{:02X}", byte),
+        }
+    }
+    format!(
+        "private unnamed_addr constant [{} x i8] c\"{}\\00\"",
+        text.len() + 1,
+        quoted
+    )
+}
+
+/// The width and signedness of an integer type.  Integers whose type was never settled are signed 64-bit, so that
+/// borrowing one as any integer type reads its value.
+fn int_info(ty: DefinitionId) -> Option<(u32, bool)> {
+    match ty {
+        builtin_type::U64 => Some((64, false)),
+        builtin_type::U32 => Some((32, false)),
+        builtin_type::I64 | builtin_type::UNKNOWN_INT => Some((64, true)),
+        builtin_type::I32 => Some((32, true)),
+        builtin_type::BOOL => Some((1, false)),
+        _ => None,
+    }
+}
+
+fn llvm_type(
+    bc: &BytecodeEngine,
+    ty: DefinitionId,
+    instantiations: Option<&Vec<(DefinitionId, DefinitionId)>>,
+) -> String {
+    let ty = resolve(bc, ty, instantiations);
+    match int_info(ty) {
+        Some((bits, _)) => format!("i{}", bits),
+        None => match bc.definitions[ty] {
+            _ if ty == builtin_type::VOID => "void".into(),
+            Definition::Struct(_) => format!("%struct.{}", ty),
+            Definition::Reference(_, _) => "ptr".into(),
+            _ if ty == builtin_type::VOID_PTR => "ptr".into(),
+            _ => unimplemented!("Expected struct during llvm_type"),
+        },
+    }
+}
+
+/// The type that a type mentioned by a generic function stands for in the instantiation being lowered
+fn resolve(
+    bc: &BytecodeEngine,
+    ty: DefinitionId,
+    instantiations: Option<&Vec<(DefinitionId, DefinitionId)>>,
+) -> DefinitionId {
+    match instantiations {
+        Some(instantiations) => bc.find_substituted_type(ty, instantiations),
+        None => ty,
+    }
+}
+
+/// The fields of a struct, as they were declared
+fn struct_fields(bc: &BytecodeEngine, ty: DefinitionId) -> &[(String, DefinitionId)] {
+    match bc.definitions[ty] {
+        Definition::Struct(ref st) => &st.fields,
+        _ => unimplemented!("Expected struct during field access"),
+    }
+}
+
+fn field_index(bc: &BytecodeEngine, ty: DefinitionId, field: &str) -> usize {
+    match struct_fields(bc, ty).iter().position(|x| x.0 == field) {
+        Some(idx) => idx,
+        None => unimplemented!("Field {} not found during lowering", field),
+    }
+}
+
+/// Lowers one function.  Its stack slots are allocated in the entry block, and its instructions in the blocks after.
+struct FnLowering<'a> {
+    bc: &'a BytecodeEngine,
+    fun: &'a Fun,
+    instantiations: Option<&'a Vec<(DefinitionId, DefinitionId)>>,
+    is_main: bool,
+    allocas: String,
+    body: String,
+    stack: Vec<Operand>,
+    next_reg: usize,
+    next_label: usize,
+}
+
+impl<'a> FnLowering<'a> {
+    fn ty(&self, ty: DefinitionId) -> String {
+        llvm_type(self.bc, ty, self.instantiations)
+    }
+
+    fn resolve(&self, ty: DefinitionId) -> DefinitionId {
+        resolve(self.bc, ty, self.instantiations)
+    }
+
+    fn reg(&mut self) -> String {
+        self.next_reg += 1;
+        format!("%r{}", self.next_reg - 1)
+    }
+
+    fn label(&mut self) -> usize {
+        self.next_label += 1;
+        self.next_label - 1
+    }
+
+    fn emit(&mut self, instruction: &str) {
+        self.body += "  ";
+        self.body += instruction;
+        self.body += "\n";
+    }
+
+    fn block(&mut self, label: &str) {
+        self.body += label;
+        self.body += ":\n";
+    }
+
+    /// Assigns an instruction's result to a new register
+    fn emit_reg(&mut self, instruction: &str) -> String {
+        let reg = self.reg();
+        self.emit(&format!("{} = {}", reg, instruction));
+        reg
+    }
+
+    /// Allocates a stack slot in the entry block, so that it isn't allocated again each time a loop runs
+    fn alloca(&mut self, name: &str, ty: &str) {
+        self.allocas += &format!("  {} = alloca {}\n", name, ty);
+    }
+
+    /// Pops a value, loading it if it's a place
+    fn pop_value(&mut self) -> Operand {
+        let operand = self.stack.pop().unwrap();
+        self.load(operand)
+    }
+
+    /// The value of the operand, which is loaded if it's a place
+    fn load(&mut self, operand: Operand) -> Operand {
+        if operand.place {
+            let ty = self.ty(operand.ty);
+            let reg = self.emit_reg(&format!("load {}, ptr {}", ty, operand.repr));
+            Operand::value(reg, operand.ty)
+        } else {
+            operand
+        }
+    }
+
+    /// Converts an integer between types.  Peach lets integers whose type wasn't settled mix with any integer type,
+    /// so they're widened or narrowed to the type they're used as.
+    fn convert(&mut self, repr: &str, from: DefinitionId, to: DefinitionId) -> String {
+        let (from_bits, signed) = match int_info(self.resolve(from)) {
+            Some(info) => info,
+            None => return repr.to_string(),
+        };
+        let to_bits = match int_info(self.resolve(to)) {
+            Some((bits, _)) => bits,
+            None => return repr.to_string(),
+        };
+
+        if from_bits == to_bits {
+            return repr.to_string();
+        }
+        // Constants widen as they are
+        if from_bits > 1 && to_bits > from_bits {
+            if let Ok(val) = repr.parse::<i64>() {
+                if signed || val >= 0 {
+                    return repr.to_string();
+                }
+            }
+        }
+        let op = if to_bits < from_bits {
+            "trunc"
+        } else if signed {
+            "sext"
+        } else {
+            "zext"
+        };
+        self.emit_reg(&format!("{} i{} {} to i{}", op, from_bits, repr, to_bits))
+    }
+
+    /// Pops a value and converts it to the given type
+    fn pop_as(&mut self, ty: DefinitionId) -> String {
+        let operand = self.pop_value();
+        self.convert(&operand.repr, operand.ty, ty)
+    }
+
+    /// Pops the operands of an arithmetic or comparison instruction, converted to the same type
+    fn pop_operands(&mut self) -> (String, String, DefinitionId) {
+        let rhs = self.pop_value();
+        let lhs = self.pop_value();
+        let ty = if self.resolve(lhs.ty) == builtin_type::UNKNOWN_INT {
+            rhs.ty
+        } else {
+            lhs.ty
+        };
+        let lhs = self.convert(&lhs.repr, lhs.ty, ty);
+        let rhs = self.convert(&rhs.repr, rhs.ty, ty);
+        (lhs, rhs, ty)
+    }
+
+    fn arithmetic(&mut self, op: &str) {
+        let (lhs, rhs, ty) = self.pop_operands();
+        let llvm_ty = self.ty(ty);
+        let reg = self.emit_reg(&format!("{} {} {}, {}", op, llvm_ty, lhs, rhs));
+        self.stack.push(Operand::value(reg, ty));
+    }
+
+    fn is_signed(&self, ty: DefinitionId) -> bool {
+        match int_info(self.resolve(ty)) {
+            Some((_, signed)) => signed,
+            None => false,
+        }
+    }
+
+    fn needs_drop(&self, ty: DefinitionId) -> bool {
+        self.bc.needs_drop(self.resolve(ty))
+    }
+
+    /// Drops the value of type `ty` at `place`: first its Drop impl, then that of its fields
+    fn drop_glue(&mut self, ty: DefinitionId, place: &str) {
+        let ty = self.resolve(ty);
+        if let Some(drop_fn) = self.bc.drop_fns.get(&ty) {
+            self.emit(&format!("call void @fun_{}(ptr {})", drop_fn, place));
+        }
+
+        for (idx, (_, field_ty)) in struct_fields(self.bc, ty).iter().enumerate() {
+            if self.bc.needs_drop(*field_ty) {
+                let field = self.emit_reg(&format!(
+                    "getelementptr %struct.{}, ptr {}, i32 0, i32 {}",
+                    ty, place, idx
+                ));
+                self.drop_glue(*field_ty, &field);
+            }
+        }
+    }
+
+    /// Starts a block that nothing jumps to, for the instructions after a return
+    fn dead_block(&mut self) {
+        let label = self.label();
+        self.block(&format!("dead.{}", label));
+    }
+
+    fn call(&mut self, callee: &str, params: &[DefinitionId], return_ty: DefinitionId) {
+        let start = self.stack.len() - params.len();
+        let mut args = vec![];
+        for (operand, param_ty) in self.stack.split_off(start).into_iter().zip(params) {
+            let operand = self.load(operand);
+            let arg = self.convert(&operand.repr, operand.ty, *param_ty);
+            args.push(format!("{} {}", self.ty(*param_ty), arg));
+        }
+
+        let return_llvm_ty = self.ty(return_ty);
+        let instruction = format!("call {} {}({})", return_llvm_ty, callee, args.join(", "));
+        if self.resolve(return_ty) == builtin_type::VOID {
+            self.emit(&instruction);
+            self.stack
+                .push(Operand::value(String::new(), builtin_type::VOID));
+        } else {
+            let reg = self.emit_reg(&instruction);
+            self.stack.push(Operand::value(reg, return_ty));
+        }
+    }
+
+    fn lower(&mut self, strings: &mut Strings) -> Result<()> {
+        let bc = self.bc;
+        let fun = self.fun;
+
+        // Variables whose values need dropping get a drop flag, which is cleared when their value is moved out
+        for (var_id, var) in fun.vars.iter().enumerate() {
+            let ty = self.resolve(var.ty);
+            if ty == builtin_type::VOID || ty == builtin_type::UNKNOWN || ty == builtin_type::ERROR
+            {
+                continue;
+            }
+            let llvm_ty = self.ty(ty);
+            self.alloca(&format!("%v{}", var_id), &llvm_ty);
+            if bc.needs_drop(ty) {
+                self.alloca(&format!("%d{}", var_id), "i1");
+                self.allocas += &format!("  store i1 false, ptr %d{}\n", var_id);
+            }
+        }
+        for param in &fun.params {
+            let llvm_ty = self.ty(param.ty);
+            self.allocas += &format!(
+                "  store {} %p{}, ptr %v{}\n",
+                llvm_ty, param.var_id, param.var_id
+            );
+            if self.needs_drop(param.ty) {
+                self.allocas += &format!("  store i1 true, ptr %d{}\n", param.var_id);
+            }
+        }
+
+        // The labels of the ifs and whiles being lowered, and whether each if has reached its else
+        let mut ifs: Vec<(usize, bool)> = vec![];
+        let mut whiles: Vec<usize> = vec![];
+
+        for (idx, code) in fun.bytecode.iter().enumerate() {
+            match code {
+                Bytecode::ReturnVoid => {
+                    if self.is_main {
+                        self.emit("ret i32 0");
+                    } else if self.resolve(fun.return_ty) == builtin_type::VOID {
+                        self.emit("ret void");
+                    } else {
+                        // Only follows a function's own return, so it's never reached
+                        self.emit("unreachable");
+                    }
+                    self.dead_block();
+                }
+                Bytecode::ReturnLastStackValue => {
+                    // main returns the program's exit status
+                    let return_ty = if self.is_main {
+                        builtin_type::I32
+                    } else {
+                        fun.return_ty
+                    };
+                    let retval = self.pop_as(return_ty);
+                    let llvm_ty = self.ty(return_ty);
+                    self.emit(&format!("ret {} {}", llvm_ty, retval));
+                    self.dead_block();
+                }
+                Bytecode::As(ty) => {
+                    let val = self.pop_value();
+                    if let Definition::Struct(_) = bc.definitions[self.resolve(*ty)] {
+                        self.stack.push(Operand::value(val.repr, *ty));
+                    } else {
+                        let reg = self.convert(&val.repr, val.ty, *ty);
+                        self.stack.push(Operand::value(reg, *ty));
+                    }
+                }
+                Bytecode::PushU64(val) => {
+                    self.stack
+                        .push(Operand::value(val.to_string(), builtin_type::U64));
+                }
+                Bytecode::PushU32(val) => {
+                    self.stack
+                        .push(Operand::value(val.to_string(), builtin_type::U32));
+                }
+                Bytecode::PushI64(val) => {
+                    self.stack
+                        .push(Operand::value(val.to_string(), builtin_type::I64));
+                }
+                Bytecode::PushI32(val) => {
+                    self.stack
+                        .push(Operand::value(val.to_string(), builtin_type::I32));
+                }
+                Bytecode::PushUnknownInt(val) => {
+                    self.stack
+                        .push(Operand::value(val.to_string(), builtin_type::UNKNOWN_INT));
+                }
+                Bytecode::PushRawPtr(p) => {
+                    if p.is_null() {
+                        self.stack
+                            .push(Operand::value("null".into(), builtin_type::VOID_PTR));
+                    } else {
+                        unimplemented!("Unsupported pointer type");
+                    }
+                }
+                Bytecode::PushBool(val) => {
+                    self.stack
+                        .push(Operand::value(val.to_string(), builtin_type::BOOL));
+                }
+                Bytecode::Neg => {
+                    let val = self.pop_value();
+                    let llvm_ty = self.ty(val.ty);
+                    let reg = self.emit_reg(&format!("sub {} 0, {}", llvm_ty, val.repr));
+                    self.stack.push(Operand::value(reg, val.ty));
+                }
+                Bytecode::Add | Bytecode::AddVarConst(_, _) => {
+                    self.push_fused_operands(code);
+                    self.arithmetic("add");
+                }
+                Bytecode::Sub | Bytecode::SubVarConst(_, _) => {
+                    self.push_fused_operands(code);
+                    self.arithmetic("sub");
+                }
+                Bytecode::Mul => self.arithmetic("mul"),
+                Bytecode::Div => {
+                    let signed = {
+                        let lhs = &self.stack[self.stack.len() - 2];
+                        let rhs = &self.stack[self.stack.len() - 1];
+                        let ty = if self.resolve(lhs.ty) == builtin_type::UNKNOWN_INT {
+                            rhs.ty
+                        } else {
+                            lhs.ty
+                        };
+                        self.is_signed(ty)
+                    };
+                    // Dividing by zero is undefined in LLVM, and the optimizer makes use of that, so the program is
+                    // stopped before it divides, as the C backend's programs are
+                    let (lhs, rhs, ty) = self.pop_operands();
+                    let llvm_ty = self.ty(ty);
+                    let label = self.label();
+                    let is_zero = self.emit_reg(&format!("icmp eq {} {}, 0", llvm_ty, rhs));
+                    self.emit(&format!(
+                        "br i1 {}, label %divzero.{}, label %div.{}",
+                        is_zero, label, label
+                    ));
+                    self.block(&format!("divzero.{}", label));
+                    self.emit("call void @llvm.trap()");
+                    self.emit("unreachable");
+                    self.block(&format!("div.{}", label));
+                    let op = if signed { "sdiv" } else { "udiv" };
+                    let reg = self.emit_reg(&format!("{} {} {}, {}", op, llvm_ty, lhs, rhs));
+                    self.stack.push(Operand::value(reg, ty));
+                }
+                Bytecode::Lt | Bytecode::LtVarConst(_, _) => {
+                    self.push_fused_operands(code);
+                    let (lhs, rhs, ty) = self.pop_operands();
+                    let predicate = if self.is_signed(ty) { "slt" } else { "ult" };
+                    let llvm_ty = self.ty(ty);
+                    let reg =
+                        self.emit_reg(&format!("icmp {} {} {}, {}", predicate, llvm_ty, lhs, rhs));
+                    self.stack.push(Operand::value(reg, builtin_type::BOOL));
+                }
+                Bytecode::Borrow(mutable) => {
+                    let operand = self.stack.pop().unwrap();
+                    let ptr = if operand.place {
+                        operand.repr
+                    } else {
+                        // Borrowing a value that isn't in a place, like a literal, borrows a temporary
+                        let temp = format!("%t{}", self.label());
+                        let llvm_ty = self.ty(operand.ty);
+                        self.alloca(&temp, &llvm_ty);
+                        self.emit(&format!("store {} {}, ptr {}", llvm_ty, operand.repr, temp));
+                        temp
+                    };
+                    let ty = match bc.reference_tys.get(&(operand.ty, *mutable)) {
+                        Some(ty) => *ty,
+                        None => unimplemented!("Reference type was never created"),
+                    };
+                    self.stack.push(Operand::value(ptr, ty));
+                }
+                Bytecode::Deref => {
+                    let val = self.pop_value();
+                    let referenced = self.referenced(val.ty);
+                    let llvm_ty = self.ty(referenced);
+                    let reg = self.emit_reg(&format!("load {}, ptr {}", llvm_ty, val.repr));
+                    self.stack.push(Operand::value(reg, referenced));
+                }
+                Bytecode::LValueDeref => {
+                    let val = self.pop_value();
+                    let referenced = self.referenced(val.ty);
+                    self.stack.push(Operand::place(val.repr, referenced));
+                }
+                Bytecode::Dot(field) | Bytecode::LValueDot(field) => {
+                    let operand = self.stack.pop().unwrap();
+                    let struct_id = self.resolve(operand.ty);
+                    let idx = field_index(bc, struct_id, field);
+                    let field_ty = struct_fields(bc, struct_id)[idx].1;
+                    let struct_ty = self.ty(operand.ty);
+                    if operand.place {
+                        let reg = self.emit_reg(&format!(
+                            "getelementptr {}, ptr {}, i32 0, i32 {}",
+                            struct_ty, operand.repr, idx
+                        ));
+                        self.stack.push(Operand::place(reg, field_ty));
+                    } else {
+                        let reg = self.emit_reg(&format!(
+                            "extractvalue {} {}, {}",
+                            struct_ty, operand.repr, idx
+                        ));
+                        self.stack.push(Operand::value(reg, field_ty));
+                    }
+                }
+                Bytecode::VarDecl(var_id) => {
+                    let var_ty = fun.vars[*var_id].ty;
+                    let val = self.pop_as(var_ty);
+                    let llvm_ty = self.ty(var_ty);
+                    self.emit(&format!("store {} {}, ptr %v{}", llvm_ty, val, var_id));
+                    if self.needs_drop(var_ty) {
+                        self.emit(&format!("store i1 true, ptr %d{}", var_id));
+                    }
+                }
+                Bytecode::VarDeclUninit(var_id) => {
+                    if self.needs_drop(fun.vars[*var_id].ty) {
+                        self.emit(&format!("store i1 false, ptr %d{}", var_id));
+                    }
+                }
+                Bytecode::Var(var_id) => {
+                    let var_ty = fun.vars[*var_id].ty;
+                    let llvm_ty = self.ty(var_ty);
+                    let reg = self.emit_reg(&format!("load {}, ptr %v{}", llvm_ty, var_id));
+                    self.stack.push(Operand::value(reg, var_ty));
+                }
+                Bytecode::Move(var_id) => {
+                    let var_ty = fun.vars[*var_id].ty;
+                    let llvm_ty = self.ty(var_ty);
+                    let reg = self.emit_reg(&format!("load {}, ptr %v{}", llvm_ty, var_id));
+                    if self.needs_drop(var_ty) {
+                        self.emit(&format!("store i1 false, ptr %d{}", var_id));
+                    }
+                    self.stack.push(Operand::value(reg, var_ty));
+                }
+                Bytecode::DropVars(vars) => {
+                    for (var_id, ty) in vars {
+                        if !self.needs_drop(*ty) {
+                            continue;
+                        }
+                        let label = self.label();
+                        let flag = self.emit_reg(&format!("load i1, ptr %d{}", var_id));
+                        self.emit(&format!(
+                            "br i1 {}, label %drop.{}, label %dropped.{}",
+                            flag, label, label
+                        ));
+                        self.block(&format!("drop.{}", label));
+                        self.drop_glue(*ty, &format!("%v{}", var_id));
+                        self.emit(&format!("store i1 false, ptr %d{}", var_id));
+                        self.emit(&format!("br label %dropped.{}", label));
+                        self.block(&format!("dropped.{}", label));
+                    }
+                }
+                Bytecode::LValueVar(var_id) => {
+                    self.stack.push(Operand::place(
+                        format!("%v{}", var_id),
+                        fun.vars[*var_id].ty,
+                    ));
+                }
+                Bytecode::Assign | Bytecode::AssignVar(_) => {
+                    if let Bytecode::AssignVar(var_id) = code {
+                        self.stack.push(Operand::place(
+                            format!("%v{}", var_id),
+                            fun.vars[*var_id].ty,
+                        ));
+                    }
+                    let lhs = self.stack.pop().unwrap();
+                    let rhs = self.pop_as(lhs.ty);
+                    let llvm_ty = self.ty(lhs.ty);
+                    self.emit(&format!("store {} {}, ptr {}", llvm_ty, rhs, lhs.repr));
+
+                    // Assigning to a whole variable gives it a value to drop
+                    let var_id = match (code, idx.checked_sub(1).map(|x| &fun.bytecode[x])) {
+                        (Bytecode::AssignVar(var_id), _) => Some(*var_id),
+                        (_, Some(Bytecode::LValueVar(var_id))) => Some(*var_id),
+                        _ => None,
+                    };
+                    if let Some(var_id) = var_id {
+                        if self.needs_drop(fun.vars[var_id].ty) {
+                            self.emit(&format!("store i1 true, ptr %d{}", var_id));
+                        }
+                    }
+                }
+                Bytecode::Call(definition_id) => match bc.definitions[*definition_id] {
+                    Definition::Fun(ref callee) => {
+                        let params: Vec<DefinitionId> =
+                            callee.params.iter().map(|x| x.ty).collect();
+                        let name = match callee.extern_name {
+                            Some(ref ex_name) => format!("@{}", ex_name),
+                            None => format!("@fun_{}", definition_id),
+                        };
+                        self.call(&name, &params, callee.return_ty);
+                    }
+                    Definition::Struct(_) => {
+                        let fields: Vec<DefinitionId> = struct_fields(bc, *definition_id)
+                            .iter()
+                            .map(|x| x.1)
+                            .collect();
+                        let start = self.stack.len() - fields.len();
+                        let operands = self.stack.split_off(start);
+                        let struct_ty = self.ty(*definition_id);
+                        let mut value = "undef".to_string();
+                        for (idx, (operand, field_ty)) in
+                            operands.into_iter().zip(fields).enumerate()
+                        {
+                            let operand = self.load(operand);
+                            let field = self.convert(&operand.repr, operand.ty, field_ty);
+                            let field_llvm_ty = self.ty(field_ty);
+                            value = self.emit_reg(&format!(
+                                "insertvalue {} {}, {} {}, {}",
+                                struct_ty, value, field_llvm_ty, field, idx
+                            ));
+                        }
+                        self.stack.push(Operand::value(value, *definition_id));
+                    }
+                    Definition::InstantiatedFun(orig_id, ref callee_instantiations) => {
+                        if let Definition::Fun(ref callee) = bc.definitions[orig_id] {
+                            let params: Vec<DefinitionId> = callee
+                                .params
+                                .iter()
+                                .map(|x| bc.find_substituted_type(x.ty, callee_instantiations))
+                                .collect();
+                            let return_ty =
+                                bc.find_substituted_type(callee.return_ty, callee_instantiations);
+                            self.call(&format!("@fun_{}", definition_id), &params, return_ty);
+                        }
+                    }
+                    _ => unimplemented!("Attempt to call unprocessed function"),
+                },
+                Bytecode::CallNative(_) => {
+                    bail!("Native functions can only be run by the evaluator");
+                }
+                Bytecode::If(_, ty) => {
+                    let cond = self.pop_value();
+                    let label = self.label();
+                    if *ty != builtin_type::VOID {
+                        let llvm_ty = self.ty(*ty);
+                        self.alloca(&format!("%if{}", label), &llvm_ty);
+                    }
+                    self.emit(&format!(
+                        "br i1 {}, label %then.{}, label %else.{}",
+                        cond.repr, label, label
+                    ));
+                    self.block(&format!("then.{}", label));
+                    ifs.push((label, false));
+                }
+                Bytecode::Else(_, ty) => {
+                    let label = ifs.last().unwrap().0;
+                    if *ty != builtin_type::VOID {
+                        let result = self.pop_as(*ty);
+                        let llvm_ty = self.ty(*ty);
+                        self.emit(&format!("store {} {}, ptr %if{}", llvm_ty, result, label));
+                    }
+                    self.emit(&format!("br label %endif.{}", label));
+                    self.block(&format!("else.{}", label));
+                    ifs.last_mut().unwrap().1 = true;
+                }
+                Bytecode::EndIf(ty) => {
+                    let (label, has_else) = ifs.pop().unwrap();
+                    if *ty != builtin_type::VOID {
+                        let result = self.pop_as(*ty);
+                        let llvm_ty = self.ty(*ty);
+                        self.emit(&format!("store {} {}, ptr %if{}", llvm_ty, result, label));
+                    }
+                    // Without an else, the false branch goes straight to the end
+                    let end = if has_else { "endif" } else { "else" };
+                    self.emit(&format!("br label %{}.{}", end, label));
+                    self.block(&format!("{}.{}", end, label));
+                    if *ty != builtin_type::VOID {
+                        let llvm_ty = self.ty(*ty);
+                        let reg = self.emit_reg(&format!("load {}, ptr %if{}", llvm_ty, label));
+                        self.stack.push(Operand::value(reg, *ty));
+                    }
+                }
+                Bytecode::BeginWhile => {
+                    let label = self.label();
+                    self.emit(&format!("br label %while.{}", label));
+                    self.block(&format!("while.{}", label));
+                    whiles.push(label);
+                }
+                Bytecode::WhileCond(_) => {
+                    let label = *whiles.last().unwrap();
+                    let cond = self.pop_value();
+                    self.emit(&format!(
+                        "br i1 {}, label %body.{}, label %endwhile.{}",
+                        cond.repr, label, label
+                    ));
+                    self.block(&format!("body.{}", label));
+                }
+                Bytecode::EndWhile(_) => {
+                    let label = whiles.pop().unwrap();
+                    self.emit(&format!("br label %while.{}", label));
+                    self.block(&format!("endwhile.{}", label));
+                }
+                Bytecode::DebugPrint(ty) => {
+                    let val = self.pop_value();
+                    let ty = self.resolve(*ty);
+                    match int_info(ty) {
+                        Some((bits, signed)) => {
+                            let arg = if bits == 64 {
+                                val.repr
+                            } else {
+                                let op = if signed { "sext" } else { "zext" };
+                                self.emit_reg(&format!("{} i{} {} to i64", op, bits, val.repr))
+                            };
+                            let format = if signed {
+                                "@.debug.signed"
+                            } else {
+                                "@.debug.unsigned"
+                            };
+                            self.emit(&format!(
+                                "call i32 (ptr, ...) @printf(ptr {}, i64 {})",
+                                format, arg
+                            ));
+                        }
+                        None => {
+                            let text = match ty {
+                                builtin_type::VOID => "DEBUG: <void>\n".to_string(),
+                                builtin_type::UNKNOWN => "DEBUG: <unknown>\n".to_string(),
+                                _ => format!("DEBUG: <custom type:{}>\n", ty),
+                            };
+                            let text = strings.add(&text);
+                            self.emit(&format!("call i32 (ptr, ...) @printf(ptr {})", text));
+                        }
+                    }
+                }
+                Bytecode::ReadInput => {
+                    let reg = self.emit_reg("call i64 @peach_read_u64()");
+                    self.stack.push(Operand::value(reg, builtin_type::U64));
+                }
+                Bytecode::ReadClock => {
+                    let reg = self.emit_reg("call i64 @peach_now_millis()");
+                    self.stack.push(Operand::value(reg, builtin_type::U64));
+                }
+                Bytecode::RandomBelow => {
+                    let bound = self.pop_as(builtin_type::U64);
+                    let reg =
+                        self.emit_reg(&format!("call i64 @peach_random_below(i64 {})", bound));
+                    self.stack.push(Operand::value(reg, builtin_type::U64));
+                }
+                Bytecode::ArgCount => {
+                    let reg = self.emit_reg("call i64 @peach_arg_count()");
+                    self.stack.push(Operand::value(reg, builtin_type::U64));
+                }
+                Bytecode::Arg => {
+                    let index = self.pop_as(builtin_type::U64);
+                    let reg = self.emit_reg(&format!("call i64 @peach_arg(i64 {})", index));
+                    self.stack.push(Operand::value(reg, builtin_type::U64));
+                }
+                Bytecode::Env(name) => {
+                    let name = strings.add(name);
+                    let reg = self.emit_reg(&format!("call i64 @peach_env(ptr {})", name));
+                    self.stack.push(Operand::value(reg, builtin_type::U64));
+                }
+                Bytecode::ReadFile(path) => {
+                    let path = strings.add(path);
+                    let reg =
+                        self.emit_reg(&format!("call i64 @peach_read_file_u64(ptr {})", path));
+                    self.stack.push(Operand::value(reg, builtin_type::U64));
+                }
+                Bytecode::WriteFile(path) => {
+                    let value = self.pop_as(builtin_type::U64);
+                    let path = strings.add(path);
+                    self.emit(&format!(
+                        "call void @peach_write_file_u64(ptr {}, i64 {})",
+                        path, value
+                    ));
+                }
+                Bytecode::Panic(message) => {
+                    let message = strings.add(message);
+                    self.emit(&format!("call void @peach_panic(ptr {})", message));
+                }
+                Bytecode::Exit => {
+                    let code = self.pop_as(builtin_type::I32);
+                    self.emit(&format!("call void @exit(i32 {})", code));
+                }
+            }
+        }
+
+        // Every function ends in a return, so the block after it is never reached
+        self.emit("unreachable");
+        Ok(())
+    }
+
+    /// Pushes the variable and constant that a superinstruction stands for, leaving the arithmetic to be lowered as
+    /// usual
+    fn push_fused_operands(&mut self, code: &Bytecode) {
+        match code {
+            Bytecode::AddVarConst(var_id, val)
+            | Bytecode::SubVarConst(var_id, val)
+            | Bytecode::LtVarConst(var_id, val) => {
+                let var_ty = self.fun.vars[*var_id].ty;
+                let llvm_ty = self.ty(var_ty);
+                let reg = self.emit_reg(&format!("load {}, ptr %v{}", llvm_ty, var_id));
+                self.stack.push(Operand::value(reg, var_ty));
+                self.stack
+                    .push(Operand::value(val.to_string(), builtin_type::UNKNOWN_INT));
+            }
+            _ => {}
+        }
+    }
+
+    /// The type that a reference of type `ty` points to
+    fn referenced(&self, ty: DefinitionId) -> DefinitionId {
+        match self.bc.definitions[self.resolve(ty)] {
+            Definition::Reference(referenced, _) => referenced,
+            _ => unimplemented!("Expected reference during dereference"),
+        }
+    }
+}
+
+fn lower_fn(
+    output: &mut String,
+    strings: &mut Strings,
+    bc: &BytecodeEngine,
+    fn_name: Option<DefinitionId>,
+    fun: &Fun,
+    instantiations: Option<&Vec<(DefinitionId, DefinitionId)>>,
+) -> Result<()> {
+    let mut lowering = FnLowering {
+        bc,
+        fun,
+        instantiations,
+        is_main: fn_name.is_none(),
+        allocas: String::new(),
+        body: String::new(),
+        stack: vec![],
+        next_reg: 0,
+        next_label: 0,
+    };
+    lowering.lower(strings)?;
+
+    match fn_name {
+        // main takes no parameters in peach, but the program's arguments are kept for arg_count() and arg()
+        None => {
+            *output += "define i32 @main(i32 %argc, ptr %argv) {\nentry:\n";
+            *output += "  store i32 %argc, ptr @peach_argc\n";
+            *output += "  store ptr %argv, ptr @peach_argv\n";
+        }
+        Some(definition_id) => {
+            let params: Vec<String> = fun
+                .params
+                .iter()
+                .map(|param| format!("{} %p{}", lowering.ty(param.ty), param.var_id))
+                .collect();
+            *output += &format!(
+                "define {} @fun_{}({}) {{\nentry:\n",
+                lowering.ty(fun.return_ty),
+                definition_id,
+                params.join(", ")
+            );
+        }
+    }
+    *output += &lowering.allocas;
+    *output += "  br label %start\nstart:\n";
+    *output += &lowering.body;
+    *output += "}\n\n";
+    Ok(())
+}
+
+fn llvm_from_bytecode(bc: &BytecodeEngine, starting_fn_id: DefinitionId) -> Result<String> {
+    let mut output = String::new();
+    let mut functions = String::new();
+    let mut strings = Strings {
+        globals: String::new(),
+        count: 0,
+    };
+
+    for definition_id in 0..bc.definitions.len() {
+        match bc.definitions[definition_id] {
+            Definition::Struct(_) if !bc.contains_type_variable(definition_id) => {
+                let fields: Vec<String> = struct_fields(bc, definition_id)
+                    .iter()
+                    .map(|(_, field_ty)| llvm_type(bc, *field_ty, None))
+                    .collect();
+                output += &format!(
+                    "%struct.{} = type {{ {} }}\n",
+                    definition_id,
+                    fields.join(", ")
+                );
+            }
+            // Generic functions are left out, and their instantiations lowered instead
+            Definition::Fun(ref fun) if fun.is_generic() || fun.native_id.is_some() => {}
+            Definition::Fun(ref fun) => match fun.extern_name {
+                Some(ref ex_name) => {
+                    let params: Vec<String> = fun
+                        .params
+                        .iter()
+                        .map(|param| llvm_type(bc, param.ty, None))
+                        .collect();
+                    functions += &format!(
+                        "declare {} @{}({})\n\n",
+                        llvm_type(bc, fun.return_ty, None),
+                        ex_name,
+                        params.join(", ")
+                    );
+                }
+                None => {
+                    let fn_name = if definition_id == starting_fn_id {
+                        None
+                    } else {
+                        Some(definition_id)
+                    };
+                    lower_fn(&mut functions, &mut strings, bc, fn_name, fun, None)?;
+                }
+            },
+            Definition::InstantiatedFun(orig_id, ref instantiations) => {
+                if let Definition::Fun(ref fun) = bc.definitions[orig_id] {
+                    lower_fn(
+                        &mut functions,
+                        &mut strings,
+                        bc,
+                        Some(definition_id),
+                        fun,
+                        Some(instantiations),
+                    )?;
+                }
+            }
+            _ => {}
+        }
+    }
+    output += "\n";
+
+    // The runtime's generator starts from the configured seed, and its files are found under the configured root
+    let rng_state = match bc.config().random_seed {
+        Some(seed) => Rng::new(seed).state(),
+        None => 0,
+    };
+    output += &format!("@peach_rng_state = global i64 {}\n", rng_state);
+    let file_root = match bc.config().file_root {
+        Some(ref root) => root.to_string_lossy().into_owned(),
+        None => String::new(),
+    };
+    let file_root = strings.add(&file_root);
+    output += &format!("@peach_file_root = global ptr {}\n", file_root);
+    output += &format!(
+        "@.debug.signed = {}\n@.debug.unsigned = {}\n",
+        llvm_string("DEBUG: %lld\n"),
+        llvm_string("DEBUG: %llu\n")
+    );
+    output += &strings.globals;
+    output += RUNTIME_DECLS;
+    output += "\n";
+    output += &functions;
+
+    Ok(output)
+}
+
+/// Lowers the program's bytecode to LLVM IR, in which `entry_fn` becomes `main`.  The entry function is found as it
+/// is for `emit_c`.  The IR uses opaque pointers, so it needs LLVM 15 or later, and calls the helpers of the C
+/// runtime, which it has to be linked with.
+pub fn emit_llvm(bc: &BytecodeEngine, entry_fn: &str) -> Result<String> {
+    let starting_fn_id = entry_fn_id(bc, entry_fn)?;
+    llvm_from_bytecode(bc, starting_fn_id)
+}
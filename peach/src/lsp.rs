@@ -0,0 +1,539 @@
+//! A language server, which gives editors that speak the Language Server Protocol peach's diagnostics, go to
+//! definition, and hover types.
+//!
+//! Programs are checked when one of their files is opened or saved: the file or project is loaded from disk, and every
+//! function in it is processed, so the errors and warnings reported are those `check` would find in every function,
+//! not just the ones main uses.  Go to definition and hover use the names recorded while the program was last
+//! checked, so they follow the file as it was last saved.
+use bytecode::{BytecodeEngine, Definition, Prelude};
+use error::{Location, PeachError, Result, Warning};
+use json::{quote, JsonValue};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// The JSON-RPC error codes the server answers with
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+/// The severities of diagnostics
+const ERROR: u32 = 1;
+const WARNING: u32 = 2;
+
+/// Reads a message framed with a `Content-Length` header, as the Language Server Protocol sends them.  Returns `None`
+/// at the end of the input.
+pub(crate) fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(length) = header.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse::<usize>().ok();
+        }
+    }
+
+    let content_length = match content_length {
+        Some(content_length) => content_length,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message without a Content-Length header",
+            ))
+        }
+    };
+    let mut content = vec![0; content_length];
+    input.read_exact(&mut content)?;
+    String::from_utf8(content)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes a message with the `Content-Length` header it's framed with
+pub(crate) fn write_message(output: &mut impl Write, message: &str) -> io::Result<()> {
+    write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        message.len(),
+        message
+    )?;
+    output.flush()
+}
+
+fn response(id: &JsonValue, result: &str) -> String {
+    format!(
+        "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}",
+        id.to_json(),
+        result
+    )
+}
+
+fn error_response(id: &JsonValue, code: i32, message: &str) -> String {
+    format!(
+        "{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":{},\"message\":{}}}}}",
+        id.to_json(),
+        code,
+        quote(message)
+    )
+}
+
+fn notification(method: &str, params: &str) -> String {
+    format!(
+        "{{\"jsonrpc\":\"2.0\",\"method\":{},\"params\":{}}}",
+        quote(method),
+        params
+    )
+}
+
+/// The path of a `file:` URI
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut path = vec![];
+    let mut idx = 0;
+    while idx < encoded.len() {
+        if encoded[idx] == b'%' {
+            let hex = ::std::str::from_utf8(encoded.get(idx + 1..idx + 3)?).ok()?;
+            path.push(u8::from_str_radix(hex, 16).ok()?);
+            idx += 3;
+        } else {
+            path.push(encoded[idx]);
+            idx += 1;
+        }
+    }
+    String::from_utf8(path).ok().map(PathBuf::from)
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            _ => uri += &format!("%{:02X}", byte),
+        }
+    }
+    uri
+}
+
+/// The file or project directory a file is part of.  A file in the `src` directory of a project laid out like a
+/// Cargo package is part of the project, and any other file is a program of its own.
+fn program_path(path: &Path) -> PathBuf {
+    for dir in path.ancestors().skip(1) {
+        if dir.join("Cargo.toml").is_file() && path.starts_with(dir.join("src")) {
+            return dir.to_path_buf();
+        }
+    }
+    path.to_path_buf()
+}
+
+/// A program that has been checked
+struct Program {
+    /// The engine the program was last loaded into, unless it has never loaded
+    engine: Option<BytecodeEngine>,
+    /// The files diagnostics were last published for, so they can be cleared once their problems are fixed
+    published: HashSet<PathBuf>,
+}
+
+/// Serves one editor, which sends it messages with `handle_message`, or over a stream with `serve`
+///
+/// Eg)
+/// ```no_run
+/// extern crate peach;
+/// use peach::LanguageServer;
+/// use std::io;
+///
+/// let stdin = io::stdin();
+/// let status = LanguageServer::new()
+///     .serve(&mut stdin.lock(), &mut io::stdout())
+///     .unwrap();
+/// std::process::exit(status);
+/// ```
+pub struct LanguageServer {
+    /// The programs that have been checked, by their file or project directory
+    programs: HashMap<PathBuf, Program>,
+    shutdown: bool,
+    exited: bool,
+}
+
+impl LanguageServer {
+    pub fn new() -> LanguageServer {
+        LanguageServer {
+            programs: HashMap::new(),
+            shutdown: false,
+            exited: false,
+        }
+    }
+
+    /// Serves the editor over the streams until it sends `exit`, or its input ends.  Returns the status the server
+    /// should exit with, which is 0 only if the editor asked it to shut down first.
+    pub fn serve(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> Result<i32> {
+        let io_error = |e: io::Error| PeachError::Io(format!("language server: {}", e));
+        while !self.exited {
+            let message = match read_message(input).map_err(io_error)? {
+                Some(message) => message,
+                None => break,
+            };
+            for reply in self.handle_message(&message) {
+                write_message(output, &reply).map_err(io_error)?;
+            }
+        }
+        Ok(if self.shutdown { 0 } else { 1 })
+    }
+
+    /// Handles a message from the editor, returning what to send back: the response, if the message was a request,
+    /// along with any notifications, like the diagnostics of a program that was checked
+    pub fn handle_message(&mut self, message: &str) -> Vec<String> {
+        let message = match JsonValue::parse(message) {
+            Some(message) => message,
+            None => {
+                return vec![error_response(
+                    &JsonValue::Null,
+                    PARSE_ERROR,
+                    "The message is not valid JSON",
+                )]
+            }
+        };
+        // Messages without a method are responses to requests, which the server never sends
+        let method = match message.get("method").and_then(JsonValue::as_str) {
+            Some(method) => method,
+            None => return vec![],
+        };
+        let params = message.get("params").unwrap_or(&JsonValue::Null);
+
+        match message.get("id") {
+            Some(id) if self.shutdown => vec![error_response(
+                id,
+                INVALID_REQUEST,
+                "The server has been shut down",
+            )],
+            Some(id) => match self.handle_request(method, params) {
+                Ok(result) => vec![response(id, &result)],
+                Err((code, message)) => vec![error_response(id, code, &message)],
+            },
+            None => self.handle_notification(method, params),
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        method: &str,
+        params: &JsonValue,
+    ) -> ::std::result::Result<String, (i32, String)> {
+        match method {
+            "initialize" => Ok(format!(
+                "{{\"capabilities\":{{\"textDocumentSync\":{{\"openClose\":true,\"change\":0,\"save\":true}},\
+                 \"hoverProvider\":true,\"definitionProvider\":true}},\
+                 \"serverInfo\":{{\"name\":\"peach\",\"version\":{}}}}}",
+                quote(env!("CARGO_PKG_VERSION"))
+            )),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok("null".to_string())
+            }
+            "textDocument/hover" | "textDocument/definition" => {
+                let (path, line, character) = match text_document_position(params) {
+                    Some(position) => position,
+                    None => {
+                        return Err((
+                            INVALID_PARAMS,
+                            format!("{} needs a textDocument and a position", method),
+                        ))
+                    }
+                };
+                let result = if method == "textDocument/hover" {
+                    self.hover(&path, line, character)
+                } else {
+                    self.definition(&path, line, character)
+                };
+                Ok(result.unwrap_or_else(|| "null".to_string()))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+        }
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &JsonValue) -> Vec<String> {
+        match method {
+            "exit" => {
+                self.exited = true;
+                vec![]
+            }
+            "textDocument/didOpen" | "textDocument/didSave" => {
+                match params
+                    .get("textDocument")
+                    .and_then(|document| document.get("uri"))
+                    .and_then(JsonValue::as_str)
+                    .and_then(uri_to_path)
+                {
+                    Some(path) => self.check(&path),
+                    None => vec![],
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Checks the program the file is part of, returning the diagnostics to publish for each of its files
+    fn check(&mut self, path: &Path) -> Vec<String> {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let program_path = program_path(&path);
+        let (engine, errors, warnings) = check_program(&program_path);
+
+        // Problems are reported in the file they're in, and those without a file in the one that was checked
+        let mut diagnostics: HashMap<PathBuf, Vec<String>> = HashMap::new();
+        diagnostics.insert(path.clone(), vec![]);
+        let problems = errors
+            .iter()
+            .map(|e| (ERROR, e.location(), e.message().to_string(), e.to_string()))
+            .chain(warnings.iter().map(|warning| {
+                let message = warning.message.clone();
+                (
+                    WARNING,
+                    warning.location.as_ref(),
+                    message,
+                    warning.to_string(),
+                )
+            }));
+        for (severity, location, message, full_message) in problems {
+            let located = match (location, &engine) {
+                (Some(location), Some(engine)) => file_path(engine, &location.file)
+                    .filter(|file| file.is_file())
+                    .map(|file| (file, lsp_range(engine, location, None), message)),
+                _ => None,
+            };
+            let (file, range, message) = located.unwrap_or_else(|| {
+                let start = "{\"line\":0,\"character\":0}";
+                (
+                    path.clone(),
+                    format!("{{\"start\":{0},\"end\":{0}}}", start),
+                    full_message,
+                )
+            });
+            diagnostics.entry(file).or_default().push(format!(
+                "{{\"range\":{},\"severity\":{},\"source\":\"peach\",\"message\":{}}}",
+                range,
+                severity,
+                quote(&message)
+            ));
+        }
+
+        // Files whose problems have all been fixed have their diagnostics cleared
+        let program = self
+            .programs
+            .entry(program_path)
+            .or_insert_with(|| Program {
+                engine: None,
+                published: HashSet::new(),
+            });
+        for file in program.published.drain() {
+            diagnostics.entry(file).or_default();
+        }
+        program.published = diagnostics
+            .iter()
+            .filter(|(_, file_diagnostics)| !file_diagnostics.is_empty())
+            .map(|(file, _)| file.clone())
+            .collect();
+        // A program that no longer loads keeps what was known about it before
+        if engine.is_some() {
+            program.engine = engine;
+        }
+
+        let mut diagnostics: Vec<(PathBuf, Vec<String>)> = diagnostics.into_iter().collect();
+        diagnostics.sort();
+        diagnostics
+            .into_iter()
+            .map(|(file, file_diagnostics)| {
+                notification(
+                    "textDocument/publishDiagnostics",
+                    &format!(
+                        "{{\"uri\":{},\"diagnostics\":[{}]}}",
+                        quote(&path_to_uri(&file)),
+                        file_diagnostics.join(",")
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    /// The engine of the program the file was last checked in, with the location of the position in the file
+    fn locate(
+        &self,
+        path: &Path,
+        line: usize,
+        character: usize,
+    ) -> Option<(&BytecodeEngine, Location)> {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let engine = self.programs.get(&program_path(&path))?.engine.as_ref()?;
+        let root = engine.project_root.as_ref()?;
+        let file = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .display()
+            .to_string();
+
+        // Positions count characters in UTF-16, and locations count them as chars
+        let text = engine.sources.get(&file)?.lines().nth(line)?;
+        let mut column = 1;
+        let mut units = 0;
+        for c in text.chars() {
+            units += c.len_utf16();
+            if units > character {
+                break;
+            }
+            column += 1;
+        }
+        Some((
+            engine,
+            Location {
+                file,
+                line: line + 1,
+                column,
+            },
+        ))
+    }
+
+    fn hover(&self, path: &Path, line: usize, character: usize) -> Option<String> {
+        let (engine, location) = self.locate(path, line, character)?;
+        let description = engine.describe_at(&location)?;
+        let symbol = engine.symbol_at(&location)?;
+        Some(format!(
+            "{{\"contents\":{{\"kind\":\"markdown\",\"value\":{}}},\"range\":{}}}",
+            quote(&format!("```rust\n{}\n```", description)),
+            lsp_range(engine, &symbol.location, Some(symbol.len))
+        ))
+    }
+
+    fn definition(&self, path: &Path, line: usize, character: usize) -> Option<String> {
+        let (engine, location) = self.locate(path, line, character)?;
+        let definition = engine.definition_at(&location)?;
+        let file = file_path(engine, &definition.file)?;
+        Some(format!(
+            "{{\"uri\":{},\"range\":{}}}",
+            quote(&path_to_uri(&file)),
+            lsp_range(engine, &definition, None)
+        ))
+    }
+}
+
+impl Default for LanguageServer {
+    fn default() -> LanguageServer {
+        LanguageServer::new()
+    }
+}
+
+/// The file and position of `textDocument/hover` and `textDocument/definition` requests
+fn text_document_position(params: &JsonValue) -> Option<(PathBuf, usize, usize)> {
+    let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+    let position = params.get("position")?;
+    Some((
+        uri_to_path(uri)?,
+        position.get("line")?.as_u64()? as usize,
+        position.get("character")?.as_u64()? as usize,
+    ))
+}
+
+/// The path of a file the engine loaded, from the name its locations use
+fn file_path(engine: &BytecodeEngine, file: &str) -> Option<PathBuf> {
+    Some(engine.project_root.as_ref()?.join(file))
+}
+
+/// The range in LSP's terms of the text at the location, which is `len` characters long, or else the word starting
+/// there
+fn lsp_range(engine: &BytecodeEngine, location: &Location, len: Option<usize>) -> String {
+    let line = engine
+        .sources
+        .get(&location.file)
+        .and_then(|source| source.lines().nth(location.line - 1))
+        .unwrap_or("");
+    let before: Vec<char> = line.chars().take(location.column - 1).collect();
+    let after = line.chars().skip(location.column - 1);
+    let len = len.unwrap_or_else(|| {
+        let word = after
+            .clone()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .count();
+        word.max(1)
+    });
+    let start: usize = before.iter().map(|c| c.len_utf16()).sum();
+    let end = start + after.take(len).map(char::len_utf16).sum::<usize>();
+    format!(
+        "{{\"start\":{{\"line\":{0},\"character\":{1}}},\"end\":{{\"line\":{0},\"character\":{2}}}}}",
+        location.line - 1,
+        start,
+        end
+    )
+}
+
+/// Loads the file or project and processes its functions, giving the engine if it loaded, with the errors and
+/// warnings found.  Warnings about unused functions come from what main uses, before the rest are processed.
+fn check_program(path: &Path) -> (Option<BytecodeEngine>, Vec<PeachError>, Vec<Warning>) {
+    let mut bc = match load_program(path) {
+        Ok(bc) => bc,
+        Err(e) => return (None, e.errors().to_vec(), vec![]),
+    };
+
+    let mut errors = vec![];
+    let mut warnings = vec![];
+    if bc.get_defn("main", 0).is_some() {
+        if let Err(e) = bc.process_fn("main", 0) {
+            errors.extend(e.errors().iter().cloned());
+        }
+        warnings = bc.warnings();
+    }
+    let warnings_before = bc.warnings.len();
+
+    // Processing a function can load modules, which have functions of their own
+    let mut tried = HashSet::new();
+    loop {
+        let mut unprocessed = vec![];
+        for (scope_id, scope) in bc.scopes.iter().enumerate() {
+            if bc.crate_root(scope_id) != 0 {
+                continue;
+            }
+            for (name, definition_id) in &scope.definitions {
+                let is_prelude = bc.prelude.values().any(|id| id == definition_id);
+                if let Definition::LazyFn(ref lazy_fn) = bc.definitions[*definition_id] {
+                    // Generic functions are only processed for the types they're called with
+                    let generic = lazy_fn.decl.generics.type_params().next().is_some();
+                    if !is_prelude && !generic && tried.insert(*definition_id) {
+                        unprocessed.push((name.clone(), scope_id));
+                    }
+                }
+            }
+        }
+        if unprocessed.is_empty() {
+            break;
+        }
+        unprocessed.sort();
+        for (name, scope_id) in unprocessed {
+            if let Err(e) = bc.process_fn(&name, scope_id) {
+                errors.extend(e.errors().iter().cloned());
+            }
+        }
+    }
+    warnings.extend(bc.warnings[warnings_before..].iter().cloned());
+
+    // A function with errors is reported again by each function that calls it
+    let errors = errors.into_iter().fold(vec![], |mut unique, error| {
+        if !unique.contains(&error) {
+            unique.push(error);
+        }
+        unique
+    });
+    (Some(bc), errors, warnings)
+}
+
+/// Loads the file or project like the `peach` driver does, with its tests, so they're checked too
+fn load_program(path: &Path) -> Result<BytecodeEngine> {
+    let mut bc = BytecodeEngine::with_prelude(&Prelude::standard())?;
+    bc.config.tests = true;
+    bc.load_path(&path.to_string_lossy())?;
+    Ok(bc)
+}
@@ -0,0 +1,101 @@
+//! Conversions between host types and the values peach programs work with, for embedders passing values in and out
+//! of programs and native functions.
+//!
+//! Only types with a `Value` of their own can be converted.  Peach doesn't have strings, vectors, or tuples yet, so
+//! there are no conversions for them.
+use eval::Value;
+use std::convert::TryFrom;
+use std::os::raw::c_void;
+
+/// A host type that can be given to a peach program
+pub trait IntoPeach {
+    fn into_peach(self) -> Value;
+}
+
+/// A host type that can be read from a peach value.  Returns `None` if the value is of a different type.
+pub trait FromPeach: Sized {
+    fn from_peach(value: &Value) -> Option<Self>;
+}
+
+macro_rules! int_conversions {
+    ($ty:ty, $variant:ident) => {
+        impl IntoPeach for $ty {
+            fn into_peach(self) -> Value {
+                Value::$variant(self)
+            }
+        }
+
+        impl FromPeach for $ty {
+            fn from_peach(value: &Value) -> Option<$ty> {
+                match value {
+                    Value::$variant(x) => Some(*x),
+                    // Integers whose type was never pinned down convert to any type they fit in
+                    Value::UnknownInt(x) => <$ty>::try_from(*x).ok(),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+int_conversions!(u64, U64);
+int_conversions!(u32, U32);
+int_conversions!(i64, I64);
+int_conversions!(i32, I32);
+
+impl IntoPeach for bool {
+    fn into_peach(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromPeach for bool {
+    fn from_peach(value: &Value) -> Option<bool> {
+        match value {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl IntoPeach for () {
+    fn into_peach(self) -> Value {
+        Value::Void
+    }
+}
+
+impl FromPeach for () {
+    fn from_peach(value: &Value) -> Option<()> {
+        match value {
+            Value::Void => Some(()),
+            _ => None,
+        }
+    }
+}
+
+impl IntoPeach for *const c_void {
+    fn into_peach(self) -> Value {
+        Value::RawPtr(self)
+    }
+}
+
+impl FromPeach for *const c_void {
+    fn from_peach(value: &Value) -> Option<*const c_void> {
+        match value {
+            Value::RawPtr(p) => Some(*p),
+            _ => None,
+        }
+    }
+}
+
+impl IntoPeach for Value {
+    fn into_peach(self) -> Value {
+        self
+    }
+}
+
+impl FromPeach for Value {
+    fn from_peach(value: &Value) -> Option<Value> {
+        Some(value.clone())
+    }
+}
@@ -0,0 +1,216 @@
+//! Counts of what ran during an evaluation, for finding where programs and the evaluator spend their time
+use bytecode::{BytecodeEngine, DefinitionId};
+use std::collections::HashMap;
+
+/// How often a function was called, and how many of the instructions evaluated were its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    /// The name of the function, or `{block}` for bytecode that isn't part of a function
+    pub name: String,
+    pub calls: u64,
+    pub instructions: u64,
+}
+
+/// What ran during a profiled evaluation.  The opcodes and functions are sorted with the most instructions first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// The number of instructions evaluated for each opcode that ran
+    pub opcodes: Vec<(&'static str, u64)>,
+    pub functions: Vec<FunctionProfile>,
+    /// The call stacks that instructions ran in, outermost function first, with how many instructions ran in each
+    /// while it was the whole stack.  Sorted by the stack.
+    pub stacks: Vec<(Vec<String>, u64)>,
+}
+
+impl Profile {
+    /// The number of instructions evaluated altogether
+    pub fn total_instructions(&self) -> u64 {
+        self.opcodes.iter().map(|(_, count)| count).sum()
+    }
+
+    /// The counts for the named opcode, or 0 if it never ran
+    pub fn opcode_count(&self, opcode: &str) -> u64 {
+        self.opcodes
+            .iter()
+            .find(|(name, _)| *name == opcode)
+            .map_or(0, |(_, count)| *count)
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionProfile> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+
+    /// A plain text table of the counts
+    pub fn report(&self) -> String {
+        let mut output = format!("{} instructions\n\n", self.total_instructions());
+        output += &format!("{:>12}  opcode\n", "instructions");
+        for (opcode, count) in &self.opcodes {
+            output += &format!("{:>12}  {}\n", count, opcode);
+        }
+        output += &format!("\n{:>12}  {:>8}  function\n", "instructions", "calls");
+        for function in &self.functions {
+            output += &format!(
+                "{:>12}  {:>8}  {}\n",
+                function.instructions, function.calls, function.name
+            );
+        }
+        output
+    }
+
+    /// The call stacks in the collapsed format that flamegraph tools like inferno and `flamegraph.pl` read, with
+    /// instructions standing in for time.  Spaces are left out of the names, as they end the stack.
+    ///
+    /// Eg)
+    /// ```text
+    /// main 9
+    /// main;add 6
+    /// ```
+    pub fn collapsed(&self) -> String {
+        let mut output = String::new();
+        for (stack, instructions) in &self.stacks {
+            let names: Vec<String> = stack.iter().map(|name| name.replace(' ', "")).collect();
+            output += &format!("{} {}\n", names.join(";"), instructions);
+        }
+        output
+    }
+}
+
+/// A call stack, as a node in the tree of the stacks that ran
+struct StackNode {
+    /// The stack without its innermost call.  The root, which is bytecode that isn't part of a function, is its own
+    /// parent.
+    parent: usize,
+    definition_id: Option<DefinitionId>,
+    instructions: u64,
+}
+
+/// Collects the counts while a program is evaluated
+pub(crate) struct Profiler {
+    opcodes: HashMap<&'static str, u64>,
+    /// (calls, instructions) for each function, or for `None` when the bytecode isn't part of a function
+    functions: HashMap<Option<DefinitionId>, (u64, u64)>,
+    /// The stacks that ran, starting with the root
+    stacks: Vec<StackNode>,
+    /// The stack made by calling a function from another stack
+    callees: HashMap<(usize, DefinitionId), usize>,
+    /// The stack of each call in progress, innermost last
+    path: Vec<usize>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler {
+            opcodes: HashMap::new(),
+            functions: HashMap::new(),
+            stacks: vec![StackNode {
+                parent: 0,
+                definition_id: None,
+                instructions: 0,
+            }],
+            callees: HashMap::new(),
+            path: vec![],
+        }
+    }
+}
+
+impl Profiler {
+    pub(crate) fn count_instruction(
+        &mut self,
+        definition_id: Option<DefinitionId>,
+        opcode: &'static str,
+    ) {
+        *self.opcodes.entry(opcode).or_insert(0) += 1;
+        self.functions.entry(definition_id).or_insert((0, 0)).1 += 1;
+        let stack = self.path.last().cloned().unwrap_or(0);
+        self.stacks[stack].instructions += 1;
+    }
+
+    /// Counts a call of the function, which is in progress until `count_return`
+    pub(crate) fn count_call(&mut self, definition_id: DefinitionId) {
+        self.functions
+            .entry(Some(definition_id))
+            .or_insert((0, 0))
+            .0 += 1;
+
+        let caller = self.path.last().cloned().unwrap_or(0);
+        let stack = match self.callees.get(&(caller, definition_id)) {
+            Some(stack) => *stack,
+            None => {
+                self.stacks.push(StackNode {
+                    parent: caller,
+                    definition_id: Some(definition_id),
+                    instructions: 0,
+                });
+                self.callees
+                    .insert((caller, definition_id), self.stacks.len() - 1);
+                self.stacks.len() - 1
+            }
+        };
+        self.path.push(stack);
+    }
+
+    /// Ends the innermost call in progress
+    pub(crate) fn count_return(&mut self) {
+        self.path.pop();
+    }
+
+    /// Ends the given number of innermost calls, which were in progress when evaluation halted
+    pub(crate) fn unwind(&mut self, calls: usize) {
+        let depth = self.path.len().saturating_sub(calls);
+        self.path.truncate(depth);
+    }
+
+    pub(crate) fn into_profile(self, bc: &BytecodeEngine) -> Profile {
+        let mut opcodes: Vec<(&'static str, u64)> = self.opcodes.into_iter().collect();
+        opcodes.sort_by(|lhs, rhs| rhs.1.cmp(&lhs.1).then(lhs.0.cmp(rhs.0)));
+
+        let mut functions: Vec<FunctionProfile> = self
+            .functions
+            .into_iter()
+            .map(|(definition_id, (calls, instructions))| FunctionProfile {
+                name: match definition_id {
+                    Some(definition_id) => bc
+                        .definition_name(definition_id)
+                        .unwrap_or_else(|| format!("fun_{}", definition_id)),
+                    None => "{block}".to_string(),
+                },
+                calls,
+                instructions,
+            })
+            .collect();
+        functions.sort_by(|lhs, rhs| {
+            rhs.instructions
+                .cmp(&lhs.instructions)
+                .then(lhs.name.cmp(&rhs.name))
+        });
+
+        let name = |definition_id: Option<DefinitionId>| match definition_id {
+            Some(definition_id) => bc
+                .definition_name(definition_id)
+                .unwrap_or_else(|| format!("fun_{}", definition_id)),
+            None => "{block}".to_string(),
+        };
+        let nodes = &self.stacks;
+        let mut stacks: Vec<(Vec<String>, u64)> = nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.instructions > 0)
+            .map(|(mut stack, node)| {
+                let mut names = vec![name(node.definition_id)];
+                while stack != 0 && nodes[stack].parent != 0 {
+                    stack = nodes[stack].parent;
+                    names.push(name(nodes[stack].definition_id));
+                }
+                names.reverse();
+                (names, node.instructions)
+            })
+            .collect();
+        stacks.sort();
+
+        Profile {
+            opcodes,
+            functions,
+            stacks,
+        }
+    }
+}
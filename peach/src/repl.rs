@@ -0,0 +1,414 @@
+//! The state a repl keeps between lines, so that each line is converted and evaluated against the variables and items
+//! from the lines before it
+use bytecode::{
+    builtin_type, Bytecode, BytecodeEngine, Definition, DefinitionId, Prelude, VarStack,
+};
+use error::{PeachError, Result};
+use eval::{EvalEngine, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+/// Whether the input stops part way through, so a repl should read more lines before converting it.  It does if a
+/// bracket, string or block comment is still open, or if it ends with an operator or separator that needs something
+/// after it, like `+`, `=`, `->` or `,`.  Input that closes more brackets than it opens is complete, so its error can
+/// be reported.
+pub fn is_incomplete(input: &str) -> bool {
+    let chars: Vec<char> = input.chars().collect();
+    let mut depth = 0;
+    // Where the last character outside of comments and whitespace is
+    let mut last = None;
+    let mut idx = 0;
+    while idx < chars.len() {
+        if let Some((end, is_comment)) = skip_non_code(&chars, idx) {
+            if end > chars.len() {
+                return true;
+            }
+            if !is_comment {
+                last = Some(end - 1);
+            }
+            idx = end;
+            continue;
+        }
+        match chars[idx] {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            c if c.is_whitespace() => {
+                idx += 1;
+                continue;
+            }
+            _ => {}
+        }
+        last = Some(idx);
+        idx += 1;
+    }
+
+    if depth > 0 {
+        return true;
+    }
+    match last {
+        // A type can end with `>`, so only a comparison, `->` or `=>` needs more
+        Some(idx) if chars[idx] == '>' => {
+            idx > 0 && (chars[idx - 1].is_whitespace() || "-=".contains(chars[idx - 1]))
+        }
+        Some(idx) => "+-*/%=<&|^!,.:".contains(chars[idx]),
+        None => false,
+    }
+}
+
+/// If a comment, string or character literal starts at `idx`, where it ends, and whether it's a comment.  The end is
+/// past the end of the input if it isn't closed.
+fn skip_non_code(chars: &[char], idx: usize) -> Option<(usize, bool)> {
+    match (chars[idx], chars.get(idx + 1), chars.get(idx + 2)) {
+        ('/', Some('/'), _) => {
+            let end = (idx..chars.len()).find(|idx| chars[*idx] == '\n');
+            Some((end.unwrap_or(chars.len()), true))
+        }
+        ('/', Some('*'), _) => {
+            // Block comments nest
+            let mut comments = 0;
+            let mut idx = idx;
+            loop {
+                match (chars.get(idx), chars.get(idx + 1)) {
+                    (None, _) => return Some((chars.len() + 1, true)),
+                    (Some('/'), Some('*')) => {
+                        comments += 1;
+                        idx += 2;
+                    }
+                    (Some('*'), Some('/')) => {
+                        comments -= 1;
+                        idx += 2;
+                        if comments == 0 {
+                            return Some((idx, true));
+                        }
+                    }
+                    _ => idx += 1,
+                }
+            }
+        }
+        ('"', _, _) => {
+            let mut idx = idx + 1;
+            loop {
+                match chars.get(idx) {
+                    None => return Some((chars.len() + 1, false)),
+                    Some('\\') => idx += 2,
+                    Some('"') => return Some((idx + 1, false)),
+                    Some(_) => idx += 1,
+                }
+            }
+        }
+        // A quote is a character literal if it's closed after one character or escape, and otherwise a lifetime
+        ('\'', Some('\\'), _) => {
+            let end = (idx + 3..chars.len()).find(|idx| chars[*idx] == '\'');
+            Some((end.map_or(chars.len() + 1, |end| end + 1), false))
+        }
+        ('\'', _, Some('\'')) => Some((idx + 3, false)),
+        _ => None,
+    }
+}
+
+/// The byte offsets of each `_` in the input that's used as a name, rather than being part of one, a lifetime, or in
+/// a string or comment
+fn underscores(input: &str) -> Vec<usize> {
+    let (offsets, chars): (Vec<usize>, Vec<char>) = input.char_indices().unzip();
+    let is_name = |c: char| c.is_alphanumeric() || c == '_';
+    let mut found = vec![];
+    let mut idx = 0;
+    while idx < chars.len() {
+        if let Some((end, _)) = skip_non_code(&chars, idx) {
+            idx = end;
+            continue;
+        }
+        if !is_name(chars[idx]) {
+            idx += 1;
+            continue;
+        }
+        let end = (idx..chars.len())
+            .find(|idx| !is_name(chars[*idx]))
+            .unwrap_or(chars.len());
+        if end == idx + 1 && chars[idx] == '_' && (idx == 0 || chars[idx - 1] != '\'') {
+            found.push(offsets[idx]);
+        }
+        idx = end;
+    }
+    found
+}
+
+/// What evaluating a line gave
+#[derive(Debug, Clone)]
+pub struct ReplOutput {
+    /// The bytecode the line was converted to
+    pub bytecode: Vec<Bytecode>,
+    /// The line's type, if it was an expression
+    pub ty: Option<DefinitionId>,
+    /// The expression's value.  Statements and items don't have one, and neither do expressions of type `()`.
+    pub value: Option<Value>,
+    /// The variable the value is kept in, like `_1`
+    pub result_var: Option<String>,
+}
+
+/// A repl session.  Each line is converted in the engine's root scope, so items it declares can be used by the lines
+/// after it, and variables it declares keep their values on the evaluator's stack, where later lines find them.
+///
+/// A line that can't be converted leaves the session as it was.  If evaluating a line stops part way through, the
+/// variables it declared are dropped, but what it assigned to earlier variables stays.
+///
+/// Each value a line gives is kept in a variable, `_1` for the first, `_2` for the next, and so on, so later lines
+/// can build on it.  `_` is the latest of them.
+///
+/// Eg)
+/// ```text
+/// let mut session = ReplSession::new()?;
+/// session.eval_line("let x = 3u64")?;
+/// let output = session.eval_line("x * 2")?;
+/// assert!(matches!(output.value, Some(Value::U64(6))));
+/// let output = session.eval_line("_ + _1")?;
+/// assert!(matches!(output.value, Some(Value::U64(12))));
+/// ```
+pub struct ReplSession {
+    pub engine: BytecodeEngine,
+    /// Evaluates the lines.  Its settings, like `fuel` and `cancel`, apply to each line.
+    pub eval: EvalEngine,
+    var_stack: VarStack,
+    /// Where each variable's value is on the evaluator's stack
+    var_lookup: HashMap<usize, usize>,
+    /// The impls each loaded file added, so they can be removed when it's loaded again
+    loaded_impls: HashMap<PathBuf, Vec<DefinitionId>>,
+    /// How many values have been kept in result variables
+    results: usize,
+}
+
+impl ReplSession {
+    /// A session with the standard prelude
+    pub fn new() -> Result<ReplSession> {
+        let engine = BytecodeEngine::with_prelude(&Prelude::standard())?;
+        Ok(ReplSession::with_engine(engine))
+    }
+
+    /// A session that converts lines with the given engine, which may already have files loaded into it
+    pub fn with_engine(engine: BytecodeEngine) -> ReplSession {
+        ReplSession {
+            engine,
+            eval: EvalEngine::new(),
+            var_stack: VarStack::new(),
+            var_lookup: HashMap::new(),
+            loaded_impls: HashMap::new(),
+            results: 0,
+        }
+    }
+
+    /// The variables the lines so far have declared
+    pub fn var_stack(&self) -> &VarStack {
+        &self.var_stack
+    }
+
+    /// The value of the variable, once it's been given one
+    pub fn var_value(&self, var_id: usize) -> Option<&Value> {
+        self.var_lookup
+            .get(&var_id)
+            .and_then(|slot| self.eval.value_stack.get(*slot))
+    }
+
+    /// Loads the items in the file into the root scope, as if they'd been entered at the prompt, so its functions can
+    /// be called from the lines after it.  Modules it declares are loaded from the file's directory.
+    ///
+    /// An item replaces the earlier one with the same name, so loading a file again after editing it replaces what it
+    /// defined before.  Its earlier impls are removed for the types its new impls are for, so their methods are
+    /// found in the new ones, while values of a struct it replaced keep the methods they had.  Functions that have
+    /// already been converted keep calling the definitions they were converted with.
+    pub fn load_file(&mut self, path: &str) -> Result<()> {
+        let path = Path::new(path)
+            .canonicalize()
+            .map_err(|e| PeachError::Io(format!("{}: {}", path, e)))?;
+        let (dir, fname) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(fname)) => (dir.to_string_lossy(), fname.to_string_lossy()),
+            _ => return Err(PeachError::Io(format!("{}: not a file", path.display()))),
+        };
+        self.engine.set_project_root(&dir)?;
+
+        let impls_before = self.engine.impls.len();
+        self.engine.load_file(&fname)?;
+        let impls: Vec<DefinitionId> = self.engine.impls[impls_before..]
+            .iter()
+            .map(|(definition_id, _)| *definition_id)
+            .collect();
+        let old_impls = match self.loaded_impls.insert(path, impls.clone()) {
+            Some(old_impls) => old_impls,
+            None => return Ok(()),
+        };
+
+        // An impl that hasn't been processed yet would be for whatever its type's name now refers to, so it's removed
+        // before the new impls are processed to find out which types they're for
+        let definitions = &self.engine.definitions;
+        let is_old_lazy = |definition_id: &DefinitionId| {
+            old_impls.contains(definition_id)
+                && matches!(definitions[*definition_id], Definition::LazyImpl(_))
+        };
+        self.engine
+            .impls
+            .retain(|(definition_id, _)| !is_old_lazy(definition_id));
+        self.engine.process_impls()?;
+
+        let definitions = &self.engine.definitions;
+        let self_ty = |definition_id: &DefinitionId| match definitions[*definition_id] {
+            Definition::Impl(ref imp) => Some(imp.self_ty),
+            _ => None,
+        };
+        let new_tys: Vec<DefinitionId> = impls.iter().filter_map(self_ty).collect();
+        self.engine.impls.retain(|(definition_id, _)| {
+            !(old_impls.contains(definition_id)
+                && self_ty(definition_id).is_some_and(|ty| new_tys.contains(&ty)))
+        });
+        // Which types implement which traits may have changed with them
+        self.engine.trait_solutions.borrow_mut().clear();
+        Ok(())
+    }
+
+    /// Completes the name or path that ends at `pos` in the line.  Gives where the part being completed starts, and the
+    /// names that can finish it: those `BytecodeEngine::complete_path` gives for the root scope, along with the
+    /// session's variables when there's no `::`.  Field names after a `.` aren't completed.
+    pub fn completions(&mut self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| c.is_alphanumeric() || *c == '_' || *c == ':')
+            .last()
+            .map_or(pos, |(idx, _)| idx);
+        let word = &line[start..pos];
+        if line[..start].ends_with('.') {
+            return (pos, vec![]);
+        }
+
+        let mut names = self.engine.complete_path(word, 0);
+        match word.rfind("::") {
+            Some(idx) => (start + idx + 2, names),
+            None => {
+                for (_, var) in self.var_stack.visible_vars() {
+                    if var.ident.starts_with(word) {
+                        names.push(var.ident.clone());
+                    }
+                }
+                names.sort();
+                names.dedup();
+                (start, names)
+            }
+        }
+    }
+
+    /// Converts the expression and gives its type, without evaluating it or changing the session's variables
+    pub fn expr_type(&mut self, expr: &str) -> Result<DefinitionId> {
+        let expr = self.with_latest_result(expr)?;
+        let mut var_stack = self.var_stack.clone();
+        self.engine
+            .process_raw_expr_str(&expr, &mut vec![], &mut var_stack)
+    }
+
+    /// The input with each `_` used as a name replaced by the latest result variable.  `_` can't be parsed as an
+    /// expression, so the variables are only declared with their numbered names.
+    fn with_latest_result(&self, input: &str) -> Result<String> {
+        let underscores = underscores(input);
+        if underscores.is_empty() {
+            return Ok(input.to_string());
+        }
+        if self.results == 0 {
+            bail!("There are no results for _ to refer to yet");
+        }
+
+        let mut output = String::new();
+        let mut copied = 0;
+        for offset in underscores {
+            output += &input[copied..offset];
+            output += &format!("_{}", self.results);
+            copied = offset + 1;
+        }
+        output += &input[copied..];
+        Ok(output)
+    }
+
+    /// Converts and evaluates a line, which can be an expression, a statement, or an item.  A statement can leave off
+    /// its `;`.  If evaluation stops, the error is a `PeachError::Runtime` and `eval.halted` has the reason.
+    pub fn eval_line(&mut self, input: &str) -> Result<ReplOutput> {
+        let input = &self.with_latest_result(input)?;
+
+        // The line is converted against a copy of the variables, so one that can't be converted doesn't add any
+        let mut var_stack = self.var_stack.clone();
+        let mut bytecode = vec![];
+        let ty = match self
+            .engine
+            .process_raw_expr_str(input, &mut bytecode, &mut var_stack)
+        {
+            Ok(ty) => Some(ty),
+            Err(_) => {
+                var_stack = self.var_stack.clone();
+                bytecode.clear();
+                let result = self
+                    .engine
+                    .process_raw_stmt_str(input, &mut bytecode, &mut var_stack);
+                if result.is_err() {
+                    var_stack = self.var_stack.clone();
+                    bytecode.clear();
+                    let input = format!("{};", input);
+                    self.engine
+                        .process_raw_stmt_str(&input, &mut bytecode, &mut var_stack)?;
+                }
+                None
+            }
+        };
+
+        let base = self.eval.value_stack.len();
+        self.eval.halted = None;
+        if let Some(ref cancel) = self.eval.cancel {
+            cancel.store(false, Ordering::SeqCst);
+        }
+        let result = self
+            .eval
+            .eval_block_bytecode(&self.engine, &bytecode, &mut self.var_lookup);
+        if let Err(e) = result {
+            let vars_before = self.var_stack.vars.len();
+            self.var_lookup.retain(|var_id, _| *var_id < vars_before);
+            self.eval.value_stack.truncate(base);
+            return Err(e);
+        }
+        self.var_stack = var_stack;
+
+        // An expression leaves its value on top of the stack.  Anything else the line left there is a temporary, or a
+        // variable from a block that has ended, and isn't needed again.
+        let value = match ty {
+            Some(ty) if ty != builtin_type::VOID && self.eval.value_stack.len() > base => {
+                self.eval.value_stack.pop()
+            }
+            _ => None,
+        };
+        let in_scope = &self.var_stack.var_stack;
+        self.var_lookup
+            .retain(|var_id, _| in_scope.contains(var_id));
+        let vars_end = self
+            .var_lookup
+            .values()
+            .map(|slot| slot + 1)
+            .max()
+            .unwrap_or(0);
+        self.eval.value_stack.truncate(base.max(vars_end));
+
+        // The value is declared as the next result variable, on top of the stack like one the line declared itself
+        let result_var = match (ty, &value) {
+            (Some(ty), Some(value)) => {
+                self.results += 1;
+                let name = format!("_{}", self.results);
+                let var_id = self.var_stack.add_var(name.clone(), ty);
+                self.eval.value_stack.push(value.clone());
+                self.var_lookup
+                    .insert(var_id, self.eval.value_stack.len() - 1);
+                Some(name)
+            }
+            _ => None,
+        };
+
+        Ok(ReplOutput {
+            bytecode,
+            ty,
+            value,
+            result_var,
+        })
+    }
+}
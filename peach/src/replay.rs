@@ -0,0 +1,129 @@
+//! Recording the values a program reads from outside the engine, so a run can be replayed exactly.  Input, the
+//! clock, random numbers, arguments, environment variables, and files are all read by instructions that push a u64, so
+//! a recording is the list of those values in the order they were read.
+use bytecode::Bytecode;
+use error::{PeachError, Result};
+
+/// The version of the log produced by `Recording::to_log`
+pub const RECORDING_FORMAT_VERSION: u32 = 1;
+
+/// The instructions whose values depend on something outside the engine
+const NONDETERMINISTIC: &[&str] = &[
+    "ReadInput",
+    "ReadClock",
+    "RandomBelow",
+    "ArgCount",
+    "Arg",
+    "Env",
+    "ReadFile",
+];
+
+/// A value a program read from outside the engine
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedInput {
+    /// The name of the instruction that read it
+    pub instruction: &'static str,
+    pub value: u64,
+}
+
+/// The values a program read from outside the engine during a run, in the order they were read
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub inputs: Vec<RecordedInput>,
+}
+
+impl Recording {
+    /// Writes the recording as text, one value per line, so it can be attached to a bug report
+    pub fn to_log(&self) -> String {
+        let mut output = format!("peach-recording {}\n", RECORDING_FORMAT_VERSION);
+        for input in &self.inputs {
+            output += &format!("{} {}\n", input.instruction, input.value);
+        }
+        output
+    }
+
+    /// Reads a recording written by `to_log`
+    pub fn from_log(log: &str) -> Result<Recording> {
+        let mut lines = log.lines();
+        match lines.next() {
+            Some(header) if header == format!("peach-recording {}", RECORDING_FORMAT_VERSION) => {}
+            _ => {
+                return Err(PeachError::Parse(format!(
+                    "A replay log has to start with \"peach-recording {}\"",
+                    RECORDING_FORMAT_VERSION
+                )))
+            }
+        }
+
+        let mut inputs = vec![];
+        for (line_num, line) in lines.enumerate() {
+            let mut words = line.split_whitespace();
+            let input = match (words.next(), words.next(), words.next()) {
+                (Some(instruction), Some(value), None) => {
+                    match (nondeterministic_name(instruction), value.parse()) {
+                        (Some(instruction), Ok(value)) => {
+                            Some(RecordedInput { instruction, value })
+                        }
+                        _ => None,
+                    }
+                }
+                (None, _, _) => continue,
+                _ => None,
+            };
+            match input {
+                Some(input) => inputs.push(input),
+                None => {
+                    return Err(PeachError::Parse(format!(
+                        "Line {} of the replay log is not an instruction and a u64: {:?}",
+                        line_num + 2,
+                        line
+                    )))
+                }
+            }
+        }
+
+        Ok(Recording { inputs })
+    }
+}
+
+/// The name of the instruction, if its value depends on something outside the engine
+pub(crate) fn nondeterministic(code: &Bytecode) -> Option<&'static str> {
+    nondeterministic_name(code.name())
+}
+
+fn nondeterministic_name(name: &str) -> Option<&'static str> {
+    NONDETERMINISTIC.iter().find(|x| **x == name).cloned()
+}
+
+/// Feeds the values of a recording back to a run
+pub(crate) struct Replayer {
+    inputs: Vec<RecordedInput>,
+    next: usize,
+}
+
+impl Replayer {
+    pub(crate) fn new(recording: &Recording) -> Replayer {
+        Replayer {
+            inputs: recording.inputs.clone(),
+            next: 0,
+        }
+    }
+
+    /// The recorded value for the instruction, which has to be the next one in the recording
+    pub(crate) fn next(&mut self, instruction: &str) -> ::std::result::Result<u64, String> {
+        match self.inputs.get(self.next) {
+            Some(input) if input.instruction == instruction => {
+                self.next += 1;
+                Ok(input.value)
+            }
+            Some(input) => Err(format!(
+                "The replay log has {} next, but the program ran {}",
+                input.instruction, instruction
+            )),
+            None => Err(format!(
+                "The replay log has no more values, but the program ran {}",
+                instruction
+            )),
+        }
+    }
+}
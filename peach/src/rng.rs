@@ -0,0 +1,35 @@
+//! The random number generator behind the `random_below()` builtin, which is also used to generate test programs.
+//! It's deterministic, so that runs can be replayed from their seed.
+
+/// A small, deterministic xorshift random number generator, so failures can be replayed from their seed
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Mix the seed so that small, similar seeds give unrelated streams.  The state must never be zero.
+        let state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ 0xD1B5_4A32_D192_ED03;
+        Rng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    /// The generator's state, which the C backend starts its own generator from so both give the same numbers
+    pub(crate) fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// Returns a number in `0..bound`
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
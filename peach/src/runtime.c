@@ -0,0 +1,124 @@
+/* The runtime included at the top of every C program peach generates.  Its helpers behave like the evaluator's
+   builtins, including the errors they stop the program with. */
+#include <stdio.h>
+#include <stdbool.h>
+#include <stdlib.h>
+#include <sys/time.h>
+
+/* Defined by the generated program, from the configuration it was converted with */
+extern unsigned long long peach_rng_state;
+extern const char *peach_file_root;
+
+/* Like the evaluator, stop the program if the input isn't a number */
+unsigned long long peach_read_u64() {
+    unsigned long long value;
+    if (scanf("%llu", &value) != 1) {
+        fprintf(stderr, "Expected a u64\n");
+        exit(1);
+    }
+    return value;
+}
+
+unsigned long long peach_now_millis() {
+    struct timeval now;
+    gettimeofday(&now, NULL);
+    return (unsigned long long)now.tv_sec * 1000 + now.tv_usec / 1000;
+}
+
+/* The same generator as the evaluator's, so a seeded program gives the same numbers with either backend.  A state of
+   0 means it's seeded from the clock on first use. */
+unsigned long long peach_random_below(unsigned long long bound) {
+    if (bound == 0) {
+        fprintf(stderr, "random_below needs a bound greater than 0\n");
+        exit(1);
+    }
+    if (peach_rng_state == 0) {
+        peach_rng_state = peach_now_millis() | 1;
+    }
+    peach_rng_state ^= peach_rng_state << 13;
+    peach_rng_state ^= peach_rng_state >> 7;
+    peach_rng_state ^= peach_rng_state << 17;
+    return peach_rng_state % bound;
+}
+
+/* Arguments and environment variables are read as u64s, with the same errors as the evaluator */
+int peach_argc = 0;
+char **peach_argv = NULL;
+
+unsigned long long peach_parse_u64(const char *text, const char *from) {
+    char *end;
+    unsigned long long value = strtoull(text, &end, 10);
+    if (*text < '0' || *text > '9' || *end != 0) {
+        fprintf(stderr, "Expected a u64 but read \"%s\" from %s\n", text, from);
+        exit(1);
+    }
+    return value;
+}
+
+unsigned long long peach_arg_count() {
+    return peach_argc - 1;
+}
+
+unsigned long long peach_arg(unsigned long long index) {
+    char from[32];
+    if (index >= peach_arg_count()) {
+        fprintf(stderr, "There is no argument %llu\n", index);
+        exit(1);
+    }
+    sprintf(from, "argument %llu", index);
+    return peach_parse_u64(peach_argv[index + 1], from);
+}
+
+unsigned long long peach_env(const char *name) {
+    const char *value = getenv(name);
+    if (value == NULL) {
+        fprintf(stderr, "The environment variable %s is not set\n", name);
+        exit(1);
+    }
+    return peach_parse_u64(value, name);
+}
+
+/* Files are found under the root the program was converted with.  An empty root means there wasn't one. */
+FILE *peach_open_file(const char *path, const char *mode) {
+    char full_path[4096];
+    FILE *file;
+    if (*peach_file_root == 0) {
+        fprintf(stderr, "There is no file root to use %s in\n", path);
+        exit(1);
+    }
+    snprintf(full_path, sizeof(full_path), "%s/%s", peach_file_root, path);
+    file = fopen(full_path, mode);
+    if (file == NULL) {
+        fprintf(stderr, "Could not open %s\n", path);
+        exit(1);
+    }
+    return file;
+}
+
+unsigned long long peach_read_file_u64(const char *path) {
+    FILE *file = peach_open_file(path, "r");
+    unsigned long long value;
+    int first;
+    char extra;
+    fscanf(file, " ");
+    first = getc(file);
+    if (first < '0' || first > '9' || ungetc(first, file) == EOF
+        || fscanf(file, "%llu", &value) != 1 || fscanf(file, " %c", &extra) == 1) {
+        fprintf(stderr, "Expected a u64 in %s\n", path);
+        exit(1);
+    }
+    fclose(file);
+    return value;
+}
+
+void peach_write_file_u64(const char *path, unsigned long long value) {
+    FILE *file = peach_open_file(path, "w");
+    fprintf(file, "%llu\n", value);
+    fclose(file);
+}
+
+/* Like the evaluator, a panic stops the program with its message */
+void peach_panic(const char *message) {
+    fprintf(stderr, "panicked: %s\n", message);
+    exit(1);
+}
@@ -0,0 +1,120 @@
+// The runtime included at the top of every Rust program peach generates.  Its helpers behave like the evaluator's
+// builtins and the C runtime's, including the errors they stop the program with.
+use std::io::BufRead;
+use std::sync::atomic::Ordering;
+
+fn peach_fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    ::std::process::exit(1)
+}
+
+// Like scanf, reads the next number and leaves what follows it to be read
+fn peach_read_u64() -> u64 {
+    let stdin = ::std::io::stdin();
+    let mut input = stdin.lock();
+    let mut digits = String::new();
+    loop {
+        let byte = match input.fill_buf() {
+            Ok(buffer) if !buffer.is_empty() => buffer[0],
+            _ => break,
+        };
+        if byte.is_ascii_digit() {
+            digits.push(byte as char);
+        } else if !digits.is_empty() || !byte.is_ascii_whitespace() {
+            break;
+        }
+        input.consume(1);
+    }
+    match digits.parse() {
+        Ok(value) => value,
+        Err(_) => peach_fail("Expected a u64"),
+    }
+}
+
+fn peach_now_millis() -> u64 {
+    let now = ::std::time::SystemTime::now()
+        .duration_since(::std::time::UNIX_EPOCH)
+        .unwrap();
+    now.as_secs() * 1000 + now.subsec_millis() as u64
+}
+
+// The same generator as the evaluator's, so a seeded program gives the same numbers with every backend.  A state of
+// 0 means it's seeded from the clock on first use.
+fn peach_random_below(bound: u64) -> u64 {
+    if bound == 0 {
+        peach_fail("random_below needs a bound greater than 0");
+    }
+    let mut state = PEACH_RNG_STATE.load(Ordering::SeqCst);
+    if state == 0 {
+        state = peach_now_millis() | 1;
+    }
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    PEACH_RNG_STATE.store(state, Ordering::SeqCst);
+    state % bound
+}
+
+// Only digits are read, so unlike Rust's parse, a leading + isn't accepted
+fn peach_digits_u64(text: &str) -> Option<u64> {
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        text.parse().ok()
+    } else {
+        None
+    }
+}
+
+// Arguments and environment variables are read as u64s, with the same errors as the evaluator
+fn peach_parse_u64(text: &str, from: &str) -> u64 {
+    match peach_digits_u64(text) {
+        Some(value) => value,
+        None => peach_fail(&format!(
+            "Expected a u64 but read \"{}\" from {}",
+            text, from
+        )),
+    }
+}
+
+fn peach_arg_count() -> u64 {
+    ::std::env::args().count() as u64 - 1
+}
+
+fn peach_arg(index: u64) -> u64 {
+    if index >= peach_arg_count() {
+        peach_fail(&format!("There is no argument {}", index));
+    }
+    let arg = ::std::env::args().nth(index as usize + 1).unwrap();
+    peach_parse_u64(&arg, &format!("argument {}", index))
+}
+
+fn peach_env(name: &str) -> u64 {
+    match ::std::env::var(name) {
+        Ok(value) => peach_parse_u64(&value, name),
+        Err(_) => peach_fail(&format!("The environment variable {} is not set", name)),
+    }
+}
+
+// Files are found under the root the program was converted with.  An empty root means there wasn't one.
+fn peach_file_path(path: &str) -> String {
+    if PEACH_FILE_ROOT.is_empty() {
+        peach_fail(&format!("There is no file root to use {} in", path));
+    }
+    format!("{}/{}", PEACH_FILE_ROOT, path)
+}
+
+fn peach_read_file_u64(path: &str) -> u64 {
+    let contents = match ::std::fs::read_to_string(peach_file_path(path)) {
+        Ok(contents) => contents,
+        Err(_) => peach_fail(&format!("Could not open {}", path)),
+    };
+    match peach_digits_u64(contents.trim()) {
+        Some(value) => value,
+        None => peach_fail(&format!("Expected a u64 in {}", path)),
+    }
+}
+
+fn peach_write_file_u64(path: &str, value: u64) {
+    if ::std::fs::write(peach_file_path(path), format!("{}\n", value)).is_err() {
+        peach_fail(&format!("Could not open {}", path));
+    }
+}
@@ -0,0 +1,192 @@
+//! Runs a program's `#[test]` functions, like `cargo test` does.  The program has to be loaded with
+//! `EngineConfig::tests` set, so that its tests, and the items behind `#[cfg(test)]`, are included.
+use bytecode::{builtin_type, BytecodeEngine, Definition, DefinitionId};
+use coverage::{Coverage, CoverageRecorder};
+use error::{PeachError, Result};
+use eval::EvalEngine;
+use std::fmt;
+
+/// How a test went
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    /// The test's path from the root of its crate, like `tests::adds`
+    pub name: String,
+    /// Why the test failed, like a panic or a failed assertion, or `None` if it passed
+    pub failure: Option<PeachError>,
+    /// What the test printed
+    pub output: String,
+}
+
+/// The results of running a program's tests, in the order the tests were loaded
+#[derive(Debug, Clone, Default)]
+pub struct TestReport {
+    pub results: Vec<TestResult>,
+}
+
+impl TestReport {
+    /// How many of the tests passed
+    pub fn passed(&self) -> usize {
+        self.results.len() - self.failed()
+    }
+
+    /// How many of the tests failed
+    pub fn failed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| result.failure.is_some())
+            .count()
+    }
+}
+
+/// Lists each test with whether it passed, then what each failed test printed and why it failed, then a summary.
+///
+/// Eg)
+/// ```text
+/// running 2 tests
+/// test adds ... ok
+/// test tests::subtracts ... FAILED
+///
+/// failures:
+///
+/// ---- tests::subtracts ----
+/// runtime error: panicked: assertion failed: 3 - 1 == 1
+///     in tests::subtracts at 5 (lib.rs:9:9)
+///
+/// test result: FAILED. 1 passed; 1 failed
+/// ```
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let plural = if self.results.len() == 1 { "" } else { "s" };
+        writeln!(f, "running {} test{}", self.results.len(), plural)?;
+        for result in &self.results {
+            let status = match result.failure {
+                Some(_) => "FAILED",
+                None => "ok",
+            };
+            writeln!(f, "test {} ... {}", result.name, status)?;
+        }
+
+        if self.failed() > 0 {
+            writeln!(f, "\nfailures:")?;
+            for result in &self.results {
+                if let Some(ref failure) = result.failure {
+                    writeln!(f, "\n---- {} ----", result.name)?;
+                    write!(f, "{}", result.output)?;
+                    writeln!(f, "{}", failure)?;
+                }
+            }
+        }
+
+        let outcome = if self.failed() > 0 { "FAILED" } else { "ok" };
+        writeln!(
+            f,
+            "\ntest result: {}. {} passed; {} failed",
+            outcome,
+            self.passed(),
+            self.failed()
+        )
+    }
+}
+
+impl BytecodeEngine {
+    /// Processes the `#[test]` functions, then evaluates each of them with its own evaluator, capturing what it
+    /// prints.  A test fails if evaluating it stops with an error, like a panic, a failed assertion, or an overflow.
+    /// If any test can't be converted, none are run and the errors are returned, as the program doesn't compile.
+    ///
+    /// Eg)
+    /// ```text
+    /// let mut bc = BytecodeEngine::with_prelude(&Prelude::standard())?;
+    /// bc.set_config(EngineConfig {
+    ///     tests: true,
+    ///     ..EngineConfig::default()
+    /// });
+    /// bc.load_file("lib.rs")?;
+    /// print!("{}", bc.run_tests()?);
+    /// ```
+    pub fn run_tests(&mut self) -> Result<TestReport> {
+        self.run_tests_recording(&mut None)
+    }
+
+    /// Runs the tests like `run_tests`, also counting how many times each line of the program ran across all of them
+    pub fn run_tests_with_coverage(&mut self) -> Result<(TestReport, Coverage)> {
+        let mut coverage = Some(CoverageRecorder::default());
+        let report = self.run_tests_recording(&mut coverage)?;
+        Ok((report, coverage.unwrap().into_coverage(self)))
+    }
+
+    /// Runs the tests, passing the coverage recorder, if there is one, from each test's evaluator to the next
+    fn run_tests_recording(
+        &mut self,
+        coverage: &mut Option<CoverageRecorder>,
+    ) -> Result<TestReport> {
+        self.load_lazy_mods()?;
+
+        let mut errors = vec![];
+        for definition_id in self.tests.clone() {
+            if let Err(e) = self.process_test(definition_id) {
+                errors.push(e);
+            }
+        }
+        if !errors.is_empty() {
+            return Err(PeachError::from_errors(errors));
+        }
+
+        let mut report = TestReport::default();
+        for definition_id in &self.tests {
+            let mut ee = EvalEngine::new();
+            ee.debug_capture = Some(String::new());
+            ee.coverage = coverage.take();
+            let failure = ee.call_fn(self, *definition_id, &[]).err();
+            *coverage = ee.coverage.take();
+            report.results.push(TestResult {
+                name: self.test_name(*definition_id),
+                failure,
+                output: ee.debug_capture.take().unwrap_or_default(),
+            });
+        }
+        Ok(report)
+    }
+
+    /// Loads the items of the modules declared inline, like `mod tests { ... }`, which are otherwise only loaded once
+    /// something in them is used.  Modules found while loading them are appended, so they're loaded too.
+    fn load_lazy_mods(&mut self) -> Result<()> {
+        let mut definition_id = 0;
+        while definition_id < self.definitions.len() {
+            if let Definition::LazyMod(_) = self.definitions[definition_id] {
+                if let Some((scope_id, mod_name)) = self.declared_in(definition_id) {
+                    self.process_mod(&mod_name, scope_id)?;
+                }
+            }
+            definition_id += 1;
+        }
+        Ok(())
+    }
+
+    /// Processes a test, which has to be a function that takes nothing and returns nothing
+    fn process_test(&mut self, definition_id: DefinitionId) -> Result<()> {
+        let (scope_id, fn_name) = match self.declared_in(definition_id) {
+            Some(declared) => declared,
+            None => bail!("Test {} has been replaced", self.test_name(definition_id)),
+        };
+        self.process_fn(&fn_name, scope_id)?;
+
+        match self.definitions[definition_id] {
+            Definition::Fun(ref fun)
+                if fun.ty_params.is_empty()
+                    && fun.params.is_empty()
+                    && fun.return_ty == builtin_type::VOID =>
+            {
+                Ok(())
+            }
+            _ => bail!(
+                "Test {} has to be a function that takes no arguments and returns nothing",
+                self.test_name(definition_id)
+            ),
+        }
+    }
+
+    fn test_name(&self, definition_id: DefinitionId) -> String {
+        self.qualified_name(definition_id)
+            .unwrap_or_else(|| format!("{{fn {}}}", definition_id))
+    }
+}
@@ -0,0 +1,756 @@
+//! Property-based testing support for backends
+//!
+//! This module generates random, well-typed peach programs over the subset of the language that every backend
+//! supports (integer variables, arithmetic, comparisons, `if`, bounded `while` loops and printing), and shrinks
+//! failing programs down to small reproductions.  The typical property is that the interpreter and a compiled backend
+//! print the same values:
+//!
+//! ```ignore
+//! let mut rng = Rng::new(seed);
+//! let program = Program::generate(&mut rng, &GenConfig::default());
+//! if let Err(mismatch) = check_backends_agree(&program, "prop") {
+//!     let small = minimize(program, |p| check_backends_agree(p, "prop").is_err());
+//!     panic!("{}\n{}", mismatch, small.to_source());
+//! }
+//! ```
+//!
+//! Generated programs have no inputs, so each one also carries its expected output, computed by a small reference
+//! interpreter.  Programs whose intermediate values could overflow a 32-bit integer are never produced.
+use bytecode::BytecodeEngine;
+use compile;
+use eval::EvalEngine;
+pub use rng::Rng;
+use std::fmt;
+
+/// The largest magnitude any intermediate value may reach in a generated program
+const MAX_MAGNITUDE: i64 = 1 << 20;
+
+impl Rng {
+    /// Returns true with a probability of `numerator` in `denominator`
+    pub fn chance(&mut self, numerator: u64, denominator: u64) -> bool {
+        self.below(denominator) < numerator
+    }
+}
+
+/// Controls the size and shape of generated programs
+#[derive(Clone, Debug)]
+pub struct GenConfig {
+    /// Maximum number of statements in a single block
+    pub max_stmts: usize,
+    /// Maximum nesting of `if` and `while` blocks
+    pub max_block_depth: usize,
+    /// Maximum nesting of expressions
+    pub max_expr_depth: usize,
+    /// Maximum number of times a generated loop runs
+    pub max_loop_iterations: i64,
+    /// Literals are generated in `0..=max_literal`
+    pub max_literal: i64,
+}
+
+impl Default for GenConfig {
+    fn default() -> GenConfig {
+        GenConfig {
+            max_stmts: 6,
+            max_block_depth: 2,
+            max_expr_depth: 3,
+            max_loop_iterations: 4,
+            max_literal: 20,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Lit(i64),
+    Var(String),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+/// A `lhs < rhs` condition, the only comparison every backend supports
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cond {
+    pub lhs: Expr,
+    pub rhs: Expr,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stmt {
+    Let {
+        name: String,
+        mutable: bool,
+        value: Expr,
+    },
+    Assign {
+        name: String,
+        value: Expr,
+    },
+    Print(Expr),
+    If {
+        cond: Cond,
+        then_block: Vec<Stmt>,
+        else_block: Vec<Stmt>,
+    },
+    /// A `while` loop driven by its own counter, which the body can read but never assigns
+    Loop {
+        counter: String,
+        iterations: i64,
+        body: Vec<Stmt>,
+    },
+}
+
+/// A generated program, which is the body of its `main` function
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub body: Vec<Stmt>,
+}
+
+/// Variables visible while generating, innermost scope last
+struct GenScope {
+    scopes: Vec<Vec<(String, bool)>>,
+    next_var: usize,
+}
+
+impl GenScope {
+    fn fresh_name(&mut self) -> String {
+        self.next_var += 1;
+        format!("v{}", self.next_var - 1)
+    }
+
+    fn visible(&self) -> Vec<&(String, bool)> {
+        self.scopes.iter().flat_map(|scope| scope.iter()).collect()
+    }
+}
+
+impl Program {
+    /// Generates a random program.  Generation is retried until the program stays within the value bounds.
+    pub fn generate(rng: &mut Rng, config: &GenConfig) -> Program {
+        loop {
+            let mut scope = GenScope {
+                scopes: vec![vec![]],
+                next_var: 0,
+            };
+            let mut body = gen_block(rng, config, &mut scope, 0);
+
+            // Make sure every program prints something
+            let last_value = match scope.visible().last() {
+                Some((name, _)) => Expr::Var(name.clone()),
+                None => gen_expr(rng, config, &scope, config.max_expr_depth),
+            };
+            body.push(Stmt::Print(last_value));
+
+            let program = Program { body };
+            if program.expected_output().is_some() {
+                return program;
+            }
+        }
+    }
+
+    /// Renders the program as peach source
+    pub fn to_source(&self) -> String {
+        let mut output = String::from("fn main() {\n");
+        render_block(&mut output, &self.body, 1);
+        output.push_str("}\n");
+        output
+    }
+
+    /// The values the program prints, in order, according to the reference semantics.  Returns None if the
+    /// program uses a variable that isn't in scope or leaves the value bounds.
+    pub fn expected_output(&self) -> Option<Vec<i64>> {
+        let mut scopes = vec![vec![]];
+        let mut output = vec![];
+        run_block(&self.body, &mut scopes, &mut output)?;
+        Some(output)
+    }
+
+    /// Returns smaller variants of this program, each of which is still a valid program
+    pub fn shrink(&self) -> Vec<Program> {
+        shrink_block(&self.body)
+            .into_iter()
+            .map(|body| Program { body })
+            .filter(|program| program.expected_output().is_some())
+            .collect()
+    }
+}
+
+/// Repeatedly shrinks `program` for as long as `still_fails` holds for the smaller program, returning the smallest
+/// failing program found
+pub fn minimize<F: FnMut(&Program) -> bool>(program: Program, mut still_fails: F) -> Program {
+    let mut current = program;
+    'outer: loop {
+        for candidate in current.shrink() {
+            if still_fails(&candidate) {
+                current = candidate;
+                continue 'outer;
+            }
+        }
+        return current;
+    }
+}
+
+/// The outputs of the interpreter and the C backend for a program that they disagree on
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub source: String,
+    pub expected: Vec<i64>,
+    pub eval_output: Vec<String>,
+    pub compiled_output: Vec<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "backends disagree\nexpected: {:?}\neval: {:?}\ncompiled: {:?}\n{}",
+            self.expected, self.eval_output, self.compiled_output, self.source
+        )
+    }
+}
+
+/// Loads the program and converts its `main` to bytecode
+fn load_program(source: &str) -> ::error::Result<BytecodeEngine> {
+    let mut bc = BytecodeEngine::new();
+    bc.load_str("<generated>", source)?;
+    bc.process_fn("main", 0)?;
+    Ok(bc)
+}
+
+/// Runs the program in the interpreter, returning the printed values
+pub fn eval_output(source: &str) -> Vec<String> {
+    let bc = match load_program(source) {
+        Ok(bc) => bc,
+        Err(e) => return vec![format!("<conversion error: {}>", e)],
+    };
+
+    let mut ee = EvalEngine::new();
+    ee.debug_capture = Some(String::new());
+    let result = ee.eval_program(&bc, "main");
+
+    let mut output: Vec<String> = ee
+        .debug_capture
+        .unwrap()
+        .lines()
+        .map(|line| {
+            // Values are printed in their debug form, eg `DEBUG: UnknownInt(5)`
+            let value = line.trim_start_matches("DEBUG: ");
+            match (value.find('('), value.rfind(')')) {
+                (Some(start), Some(end)) if start < end => value[start + 1..end].to_string(),
+                _ => value.to_string(),
+            }
+        })
+        .collect();
+    if let Err(e) = result {
+        output.push(format!("<runtime error: {}>", e.message()));
+    }
+    output
+}
+
+/// Compiles the program with the C backend and runs it, returning the printed values.  `name` is used for the
+/// temporary files, so concurrent callers should use different names.
+pub fn compiled_output(source: &str, name: &str) -> ::std::io::Result<Vec<String>> {
+    use std::process::Command;
+
+    let bc = match load_program(source) {
+        Ok(bc) => bc,
+        Err(e) => return Ok(vec![format!("<conversion error: {}>", e)]),
+    };
+
+    let binary = match compile::compile_bytecode(&bc, name) {
+        Ok(binary) => binary,
+        Err(e) => return Err(::std::io::Error::other(e.to_string())),
+    };
+    let output = Command::new(binary).output()?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim_start_matches("DEBUG: ").to_string())
+        .collect())
+}
+
+/// Checks that the interpreter, the C backend and the reference semantics all agree on what the program prints
+pub fn check_backends_agree(program: &Program, name: &str) -> Result<(), Mismatch> {
+    let source = program.to_source();
+    let expected = program
+        .expected_output()
+        .expect("Generated programs should stay within bounds");
+    let expected_strings: Vec<String> = expected.iter().map(|x| x.to_string()).collect();
+
+    let eval_output = eval_output(&source);
+    let compiled_output = match compiled_output(&source, name) {
+        Ok(output) => output,
+        Err(e) => vec![format!("<compile error: {}>", e)],
+    };
+
+    if eval_output == expected_strings && compiled_output == expected_strings {
+        Ok(())
+    } else {
+        Err(Mismatch {
+            source,
+            expected,
+            eval_output,
+            compiled_output,
+        })
+    }
+}
+
+fn gen_block(rng: &mut Rng, config: &GenConfig, scope: &mut GenScope, depth: usize) -> Vec<Stmt> {
+    let count = 1 + rng.below(config.max_stmts as u64) as usize;
+    (0..count)
+        .map(|_| gen_stmt(rng, config, scope, depth))
+        .collect()
+}
+
+fn gen_nested_block(
+    rng: &mut Rng,
+    config: &GenConfig,
+    scope: &mut GenScope,
+    depth: usize,
+) -> Vec<Stmt> {
+    scope.scopes.push(vec![]);
+    let block = gen_block(rng, config, scope, depth + 1);
+    scope.scopes.pop();
+    block
+}
+
+fn gen_stmt(rng: &mut Rng, config: &GenConfig, scope: &mut GenScope, depth: usize) -> Stmt {
+    let can_nest = depth < config.max_block_depth;
+    let mutable_vars: Vec<String> = scope
+        .visible()
+        .iter()
+        .filter(|(_, mutable)| *mutable)
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    match rng.below(10) {
+        0..=3 => {
+            let value = gen_expr(rng, config, scope, config.max_expr_depth);
+            let name = scope.fresh_name();
+            let mutable = rng.chance(1, 2);
+            scope
+                .scopes
+                .last_mut()
+                .unwrap()
+                .push((name.clone(), mutable));
+            Stmt::Let {
+                name,
+                mutable,
+                value,
+            }
+        }
+        4 | 5 if !mutable_vars.is_empty() => {
+            let name = mutable_vars[rng.below(mutable_vars.len() as u64) as usize].clone();
+            Stmt::Assign {
+                name,
+                value: gen_expr(rng, config, scope, config.max_expr_depth),
+            }
+        }
+        6 if can_nest => {
+            let cond = Cond {
+                lhs: gen_expr(rng, config, scope, config.max_expr_depth),
+                rhs: gen_expr(rng, config, scope, config.max_expr_depth),
+            };
+            let then_block = gen_nested_block(rng, config, scope, depth);
+            let else_block = if rng.chance(1, 2) {
+                gen_nested_block(rng, config, scope, depth)
+            } else {
+                vec![]
+            };
+            Stmt::If {
+                cond,
+                then_block,
+                else_block,
+            }
+        }
+        7 if can_nest => {
+            let counter = scope.fresh_name();
+            let iterations = rng.below(config.max_loop_iterations as u64 + 1) as i64;
+
+            // The counter is visible to the body, but only as a value it can read
+            scope.scopes.push(vec![(counter.clone(), false)]);
+            let body = gen_block(rng, config, scope, depth + 1);
+            scope.scopes.pop();
+
+            Stmt::Loop {
+                counter,
+                iterations,
+                body,
+            }
+        }
+        _ => Stmt::Print(gen_expr(rng, config, scope, config.max_expr_depth)),
+    }
+}
+
+fn gen_expr(rng: &mut Rng, config: &GenConfig, scope: &GenScope, depth: usize) -> Expr {
+    let vars = scope.visible();
+    let leaf = depth == 0 || rng.chance(1, 3);
+
+    if leaf {
+        if !vars.is_empty() && rng.chance(1, 2) {
+            let (ref name, _) = *vars[rng.below(vars.len() as u64) as usize];
+            Expr::Var(name.clone())
+        } else {
+            Expr::Lit(rng.below(config.max_literal as u64 + 1) as i64)
+        }
+    } else {
+        match rng.below(8) {
+            0 => Expr::Neg(Box::new(gen_expr(rng, config, scope, depth - 1))),
+            x => {
+                let op = match x {
+                    1..=3 => BinOp::Add,
+                    4..=6 => BinOp::Sub,
+                    _ => BinOp::Mul,
+                };
+                Expr::Binary(
+                    op,
+                    Box::new(gen_expr(rng, config, scope, depth - 1)),
+                    Box::new(gen_expr(rng, config, scope, depth - 1)),
+                )
+            }
+        }
+    }
+}
+
+fn render_expr(output: &mut String, expr: &Expr) {
+    match expr {
+        Expr::Lit(x) => output.push_str(&x.to_string()),
+        Expr::Var(name) => output.push_str(name),
+        Expr::Neg(inner) => {
+            output.push_str("-(");
+            render_expr(output, inner);
+            output.push(')');
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            output.push('(');
+            render_expr(output, lhs);
+            output.push_str(match op {
+                BinOp::Add => " + ",
+                BinOp::Sub => " - ",
+                BinOp::Mul => " * ",
+            });
+            render_expr(output, rhs);
+            output.push(')');
+        }
+    }
+}
+
+fn render_block(output: &mut String, block: &[Stmt], indent: usize) {
+    for stmt in block {
+        render_stmt(output, stmt, indent);
+    }
+}
+
+fn render_stmt(output: &mut String, stmt: &Stmt, indent: usize) {
+    let pad = "    ".repeat(indent);
+    output.push_str(&pad);
+    match stmt {
+        Stmt::Let {
+            name,
+            mutable,
+            value,
+        } => {
+            output.push_str(if *mutable { "let mut " } else { "let " });
+            output.push_str(name);
+            output.push_str(" = ");
+            render_expr(output, value);
+            output.push_str(";\n");
+        }
+        Stmt::Assign { name, value } => {
+            output.push_str(name);
+            output.push_str(" = ");
+            render_expr(output, value);
+            output.push_str(";\n");
+        }
+        Stmt::Print(value) => {
+            output.push_str("println!(\"{}\", ");
+            render_expr(output, value);
+            output.push_str(");\n");
+        }
+        Stmt::If {
+            cond,
+            then_block,
+            else_block,
+        } => {
+            output.push_str("if ");
+            render_expr(output, &cond.lhs);
+            output.push_str(" < ");
+            render_expr(output, &cond.rhs);
+            output.push_str(" {\n");
+            render_block(output, then_block, indent + 1);
+            output.push_str(&pad);
+            output.push('}');
+            if !else_block.is_empty() {
+                output.push_str(" else {\n");
+                render_block(output, else_block, indent + 1);
+                output.push_str(&pad);
+                output.push('}');
+            }
+            output.push('\n');
+        }
+        Stmt::Loop {
+            counter,
+            iterations,
+            body,
+        } => {
+            output.push_str(&format!("let mut {} = 0;\n", counter));
+            output.push_str(&pad);
+            output.push_str(&format!("while {} < {} {{\n", counter, iterations));
+            render_block(output, body, indent + 1);
+            output.push_str(&pad);
+            output.push_str(&format!("    {} = {} + 1;\n", counter, counter));
+            output.push_str(&pad);
+            output.push_str("}\n");
+        }
+    }
+}
+
+fn in_bounds(x: i64) -> Option<i64> {
+    if x.abs() <= MAX_MAGNITUDE {
+        Some(x)
+    } else {
+        None
+    }
+}
+
+fn lookup(scopes: &[Vec<(String, i64)>], name: &str) -> Option<i64> {
+    scopes
+        .iter()
+        .rev()
+        .flat_map(|scope| scope.iter().rev())
+        .find(|(var, _)| var == name)
+        .map(|(_, value)| *value)
+}
+
+fn run_expr(expr: &Expr, scopes: &[Vec<(String, i64)>]) -> Option<i64> {
+    match expr {
+        Expr::Lit(x) => in_bounds(*x),
+        Expr::Var(name) => lookup(scopes, name),
+        Expr::Neg(inner) => in_bounds(-run_expr(inner, scopes)?),
+        Expr::Binary(op, lhs, rhs) => {
+            let lhs = run_expr(lhs, scopes)?;
+            let rhs = run_expr(rhs, scopes)?;
+            in_bounds(match op {
+                BinOp::Add => lhs + rhs,
+                BinOp::Sub => lhs - rhs,
+                BinOp::Mul => lhs * rhs,
+            })
+        }
+    }
+}
+
+fn run_nested_block(
+    block: &[Stmt],
+    scopes: &mut Vec<Vec<(String, i64)>>,
+    output: &mut Vec<i64>,
+) -> Option<()> {
+    scopes.push(vec![]);
+    let result = run_block(block, scopes, output);
+    scopes.pop();
+    result
+}
+
+fn run_block(
+    block: &[Stmt],
+    scopes: &mut Vec<Vec<(String, i64)>>,
+    output: &mut Vec<i64>,
+) -> Option<()> {
+    for stmt in block {
+        match stmt {
+            Stmt::Let { name, value, .. } => {
+                let value = run_expr(value, scopes)?;
+                scopes.last_mut().unwrap().push((name.clone(), value));
+            }
+            Stmt::Assign { name, value } => {
+                let value = run_expr(value, scopes)?;
+                let slot = scopes
+                    .iter_mut()
+                    .rev()
+                    .flat_map(|scope| scope.iter_mut().rev())
+                    .find(|(var, _)| var == name)?;
+                slot.1 = value;
+            }
+            Stmt::Print(value) => {
+                output.push(run_expr(value, scopes)?);
+            }
+            Stmt::If {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                if run_expr(&cond.lhs, scopes)? < run_expr(&cond.rhs, scopes)? {
+                    run_nested_block(then_block, scopes, output)?;
+                } else {
+                    run_nested_block(else_block, scopes, output)?;
+                }
+            }
+            Stmt::Loop {
+                counter,
+                iterations,
+                body,
+            } => {
+                for i in 0..*iterations {
+                    scopes.push(vec![(counter.clone(), i)]);
+                    let result = run_nested_block(body, scopes, output);
+                    scopes.pop();
+                    result?;
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+fn shrink_expr(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Lit(x) => {
+            let mut candidates = vec![];
+            if *x != 0 {
+                candidates.push(Expr::Lit(0));
+            }
+            if x.abs() > 1 {
+                candidates.push(Expr::Lit(x / 2));
+            }
+            candidates
+        }
+        Expr::Var(_) => vec![Expr::Lit(0)],
+        Expr::Neg(inner) => {
+            let mut candidates = vec![(**inner).clone()];
+            candidates.extend(
+                shrink_expr(inner)
+                    .into_iter()
+                    .map(|x| Expr::Neg(Box::new(x))),
+            );
+            candidates
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            let mut candidates = vec![(**lhs).clone(), (**rhs).clone()];
+            candidates.extend(
+                shrink_expr(lhs)
+                    .into_iter()
+                    .map(|x| Expr::Binary(*op, Box::new(x), rhs.clone())),
+            );
+            candidates.extend(
+                shrink_expr(rhs)
+                    .into_iter()
+                    .map(|x| Expr::Binary(*op, lhs.clone(), Box::new(x))),
+            );
+            candidates
+        }
+    }
+}
+
+/// Returns the replacements for a statement that are smaller than it.  A statement may be replaced by several.
+fn shrink_stmt(stmt: &Stmt) -> Vec<Vec<Stmt>> {
+    match stmt {
+        Stmt::Let {
+            name,
+            mutable,
+            value,
+        } => shrink_expr(value)
+            .into_iter()
+            .map(|value| {
+                vec![Stmt::Let {
+                    name: name.clone(),
+                    mutable: *mutable,
+                    value,
+                }]
+            })
+            .collect(),
+        Stmt::Assign { name, value } => shrink_expr(value)
+            .into_iter()
+            .map(|value| {
+                vec![Stmt::Assign {
+                    name: name.clone(),
+                    value,
+                }]
+            })
+            .collect(),
+        Stmt::Print(value) => shrink_expr(value)
+            .into_iter()
+            .map(|value| vec![Stmt::Print(value)])
+            .collect(),
+        Stmt::If {
+            cond,
+            then_block,
+            else_block,
+        } => {
+            let rebuild = |cond: Cond, then_block: Vec<Stmt>, else_block: Vec<Stmt>| {
+                vec![Stmt::If {
+                    cond,
+                    then_block,
+                    else_block,
+                }]
+            };
+            let mut candidates = vec![then_block.clone(), else_block.clone()];
+            for lhs in shrink_expr(&cond.lhs) {
+                let cond = Cond {
+                    lhs,
+                    rhs: cond.rhs.clone(),
+                };
+                candidates.push(rebuild(cond, then_block.clone(), else_block.clone()));
+            }
+            for rhs in shrink_expr(&cond.rhs) {
+                let cond = Cond {
+                    lhs: cond.lhs.clone(),
+                    rhs,
+                };
+                candidates.push(rebuild(cond, then_block.clone(), else_block.clone()));
+            }
+            for block in shrink_block(then_block) {
+                candidates.push(rebuild(cond.clone(), block, else_block.clone()));
+            }
+            for block in shrink_block(else_block) {
+                candidates.push(rebuild(cond.clone(), then_block.clone(), block));
+            }
+            candidates
+        }
+        Stmt::Loop {
+            counter,
+            iterations,
+            body,
+        } => {
+            let rebuild = |iterations: i64, body: Vec<Stmt>| {
+                vec![Stmt::Loop {
+                    counter: counter.clone(),
+                    iterations,
+                    body,
+                }]
+            };
+            let mut candidates = vec![body.clone()];
+            if *iterations > 0 {
+                candidates.push(rebuild(iterations - 1, body.clone()));
+            }
+            for block in shrink_block(body) {
+                candidates.push(rebuild(*iterations, block));
+            }
+            candidates
+        }
+    }
+}
+
+fn shrink_block(block: &[Stmt]) -> Vec<Vec<Stmt>> {
+    let mut candidates = vec![];
+
+    for idx in 0..block.len() {
+        let mut removed = block.to_vec();
+        removed.remove(idx);
+        candidates.push(removed);
+    }
+
+    for (idx, stmt) in block.iter().enumerate() {
+        for replacement in shrink_stmt(stmt) {
+            let mut replaced = block[..idx].to_vec();
+            replaced.extend(replacement);
+            replaced.extend_from_slice(&block[idx + 1..]);
+            candidates.push(replaced);
+        }
+    }
+
+    candidates
+}
@@ -2,27 +2,36 @@
 mod tests {
     use std::process::Command;
 
-    use bytecode::{builtin_type, BytecodeEngine};
+    use bytecode::{
+        builtin_type, Bytecode, BytecodeEngine, Definition, DefinitionId, EngineConfig, Prelude,
+        VarStack,
+    };
+    use capabilities::BYTECODE_VERSION;
     use compile;
-    use eval::EvalEngine;
+    use error::{Location, PeachError, Result};
+    use eval::{EvalEngine, Value};
+    use marshal::{FromPeach, IntoPeach};
 
-    fn load_to_bc(fname: &str) -> BytecodeEngine {
+    fn load_to_bc(fname: &str) -> Result<BytecodeEngine> {
         let mut bc = BytecodeEngine::new();
 
         // Step 1: Load up the parsed file so that we can lazily convert it
-        bc.set_project_root("test_files");
-        bc.load_file(fname);
+        bc.set_project_root("test_files")?;
+        bc.load_file(fname)?;
 
         // Step 2: Convert to bytecode from the given location
         // We assume the starting function is found in scope 0, the starting scope
-        bc.process_fn("main", 0);
+        bc.process_fn("main", 0)?;
 
-        bc
+        Ok(bc)
     }
 
     fn run_test(fname: &str, eval_expect: &str, compile_expect: &str) {
-        let bc = load_to_bc(fname);
+        let bc = load_to_bc(fname).unwrap();
+        check_output(&bc, fname, eval_expect, compile_expect);
+    }
 
+    fn check_output(bc: &BytecodeEngine, fname: &str, eval_expect: &str, compile_expect: &str) {
         extern "C" {
             fn abs(input: i32) -> i32;
         }
@@ -31,11 +40,11 @@ mod tests {
         let mut ee = EvalEngine::new();
         ee.debug_capture = Some(String::new());
         ee.register_extern_fn_1("abs", abs);
-        ee.eval_program(&bc, "main");
+        ee.eval_program(bc, "main").unwrap();
         assert_eq!(eval_expect, ee.debug_capture.unwrap().trim());
 
         // Compile stage
-        let compile_result = compile::compile_bytecode(&bc, fname);
+        let compile_result = compile::compile_bytecode(bc, fname);
         assert!(compile_result.is_ok());
 
         let cmd = Command::new(compile_result.unwrap())
@@ -47,16 +56,13 @@ mod tests {
     }
 
     fn run_bad_test(fname: &str, expected_error_msg: &str) {
-        use std::panic;
-
-        let result = panic::catch_unwind(|| {
-            load_to_bc(fname);
-        });
-
-        match result {
+        match load_to_bc(fname) {
             Err(e) => {
-                let error_msg = e.downcast_ref::<String>().unwrap();
-                assert!(error_msg.contains(expected_error_msg));
+                assert!(
+                    e.to_string().contains(expected_error_msg),
+                    "Unexpected error: {}",
+                    e
+                );
             }
             _ => panic!("Expected failing test is succeeding"),
         }
@@ -154,22 +160,26 @@ mod tests {
 
     #[test]
     fn test_expr_bad01() {
-        run_bad_test("expr_bad01.rs", "Can't add values of");
+        // Operands that can't be used together are reported at the operator
+        run_bad_test(
+            "expr_bad01.rs",
+            "expr_bad01.rs:2:25: Can't add values of bool and {integer}",
+        );
     }
 
     #[test]
     fn test_fn01() {
-        run_test("fn01.rs", "DEBUG: UnknownInt(6)", "DEBUG: 6");
+        run_test("fn01.rs", "DEBUG: U64(6)", "DEBUG: 6");
     }
 
     #[test]
     fn test_fn02() {
-        run_test("fn02.rs", "DEBUG: UnknownInt(11)", "DEBUG: 11");
+        run_test("fn02.rs", "DEBUG: U64(11)", "DEBUG: 11");
     }
 
     #[test]
     fn test_fn03() {
-        run_test("fn03.rs", "DEBUG: UnknownInt(2)", "DEBUG: 2");
+        run_test("fn03.rs", "DEBUG: U64(2)", "DEBUG: 2");
     }
 
     #[test]
@@ -194,7 +204,7 @@ mod tests {
 
     #[test]
     fn test_fn08() {
-        run_test("fn08.rs", "DEBUG: UnknownInt(8)", "DEBUG: 8");
+        run_test("fn08.rs", "DEBUG: U64(8)", "DEBUG: 8");
     }
 
     #[test]
@@ -204,7 +214,7 @@ mod tests {
 
     #[test]
     fn test_var02() {
-        run_test("var02.rs", "DEBUG: UnknownInt(3)", "DEBUG: 3");
+        run_test("var02.rs", "DEBUG: U64(3)", "DEBUG: 3");
     }
 
     #[test]
@@ -222,6 +232,103 @@ mod tests {
         run_test("infer01.rs", "DEBUG: UnknownInt(3)", "DEBUG: 3");
     }
 
+    #[test]
+    fn test_infer02() {
+        // The literals given to `a` take the type of what it's used with, and the one nothing pins down is left
+        run_test(
+            "infer02.rs",
+            "DEBUG: U32(8)\nDEBUG: U32(12)\nDEBUG: UnknownInt(3)",
+            "DEBUG: 8\nDEBUG: 12\nDEBUG: 3",
+        );
+    }
+
+    #[test]
+    fn test_infer_bad01() {
+        run_bad_test(
+            "infer_bad01.rs",
+            "infer_bad01.rs:8:5: Mismatched types: expected u32, found u64",
+        );
+    }
+
+    #[test]
+    fn test_infer_bad02() {
+        run_bad_test(
+            "infer_bad02.rs",
+            "infer_bad02.rs:4:5: Can't negate value of type u32",
+        );
+    }
+
+    #[test]
+    fn test_infer03() {
+        // Literals keep their whole value until they're given a type, so those too large for an i32 aren't cut down
+        run_test(
+            "infer03.rs",
+            "DEBUG: U64(5000000000)\nDEBUG: I64(3000000000)\nDEBUG: I64(-6000000000)",
+            "DEBUG: 5000000000\nDEBUG: 3000000000\nDEBUG: -6000000000",
+        );
+    }
+
+    #[test]
+    fn test_infer04() {
+        // The minimum of a signed type is only in range once it's negated
+        run_test(
+            "infer04.rs",
+            "DEBUG: I32(-2147483648)\nDEBUG: I64(-9223372036854775808)\nDEBUG: I32(-2147483648)\n\
+             DEBUG: I64(-9223372036854775808)\nDEBUG: I32(-2147483648)",
+            "DEBUG: -2147483648\nDEBUG: -9223372036854775808\nDEBUG: -2147483648\n\
+             DEBUG: -9223372036854775808\nDEBUG: -2147483648",
+        );
+    }
+
+    #[test]
+    fn test_infer_bad03() {
+        run_bad_test(
+            "infer_bad03.rs",
+            "infer_bad03.rs:2:5: Literal out of range for u32",
+        );
+    }
+
+    #[test]
+    fn test_infer_bad04() {
+        // Suffixed literals are checked as they're converted, and those nothing pins down are i32s
+        let mut bc = BytecodeEngine::new();
+        bc.load_str("main.rs", "fn main() { let x = 5000000000u32; }")
+            .unwrap();
+        let err = bc.process_fn("main", 0).unwrap_err();
+        assert_eq!(err.message(), "Literal out of range for u32");
+
+        // Negation only lets in the minimum, not anything past it
+        let mut bc = BytecodeEngine::new();
+        bc.load_str("main.rs", "fn main() { let x: i32 = -2147483649; }")
+            .unwrap();
+        let err = bc.process_fn("main", 0).unwrap_err();
+        assert_eq!(err.message(), "Literal out of range for i32");
+
+        let mut ee = EvalEngine::new();
+        let err = ee
+            .eval_expr_str(&mut bc, "9223372036854775807")
+            .unwrap_err();
+        assert!(err.to_string().contains("Literal out of range for i32"));
+    }
+
+    #[test]
+    fn test_unify01() {
+        // The branches of an if unify, and a variable declared without a value takes the type it's given
+        run_test(
+            "unify01.rs",
+            "DEBUG: U32(10)\nDEBUG: UnknownInt(2)\nDEBUG: U64(4)",
+            "DEBUG: 10\nDEBUG: 2\nDEBUG: 4",
+        );
+    }
+
+    #[test]
+    fn test_unify_bad01() {
+        run_bad_test(
+            "unify_bad01.rs",
+            "unify_bad01.rs:6:9: If and else have incompatible types: expected {integer}, found bool",
+        );
+    }
+
     #[test]
     fn test_if01() {
         run_test("if01.rs", "DEBUG: UnknownInt(3)", "DEBUG: 3");
@@ -284,12 +391,12 @@ mod tests {
 
     #[test]
     fn test_mod07() {
-        run_test("mod07.rs", "DEBUG: UnknownInt(1)", "DEBUG: 1");
+        run_test("mod07.rs", "DEBUG: U64(1)", "DEBUG: 1");
     }
 
     #[test]
     fn test_mod08() {
-        run_test("mod08.rs", "DEBUG: UnknownInt(3)", "DEBUG: 3");
+        run_test("mod08.rs", "DEBUG: U64(3)", "DEBUG: 3");
     }
 
     #[test]
@@ -307,11 +414,46 @@ mod tests {
         run_test("mod11.rs", "DEBUG: UnknownInt(23)", "DEBUG: 23");
     }
 
+    #[test]
+    fn test_mod12() {
+        run_test(
+            "mod12.rs",
+            "DEBUG: U64(18)\nDEBUG: U64(10)\nDEBUG: U64(10)\nDEBUG: U64(2)\nDEBUG: U64(10)\nDEBUG: U64(10)",
+            "DEBUG: 18\nDEBUG: 10\nDEBUG: 10\nDEBUG: 2\nDEBUG: 10\nDEBUG: 10",
+        );
+    }
+
+    #[test]
+    fn test_mod13() {
+        run_test("mod13.rs", "DEBUG: U64(40)", "DEBUG: 40");
+    }
+
     #[test]
     fn test_mod_bad01() {
         run_bad_test("mod_bad01.rs", "Can not find mod");
     }
 
+    #[test]
+    fn test_visibility_bad01() {
+        run_bad_test(
+            "visibility_bad01.rs",
+            "visibility_bad01.rs:19:5: secret is private, so it can only be used inside its module",
+        );
+    }
+
+    #[test]
+    fn test_visibility_bad02() {
+        run_bad_test(
+            "visibility_bad02.rs",
+            "secret is private, so it can only be used inside its module",
+        );
+    }
+
+    #[test]
+    fn test_mod_bad02() {
+        run_bad_test("mod_bad02.rs", "There are too many leading super keywords");
+    }
+
     #[test]
     fn test_scope01() {
         run_test("scope01.rs", "DEBUG: UnknownInt(3)", "DEBUG: 3");
@@ -330,27 +472,136 @@ mod tests {
 
     #[test]
     fn test_struct02() {
-        run_test("struct02.rs", "DEBUG: UnknownInt(3)", "DEBUG: 3");
+        run_test("struct02.rs", "DEBUG: U64(3)", "DEBUG: 3");
     }
 
     #[test]
     fn test_struct03() {
-        run_test("struct03.rs", "DEBUG: UnknownInt(5)", "DEBUG: 5");
+        run_test("struct03.rs", "DEBUG: U64(5)", "DEBUG: 5");
     }
 
     #[test]
     fn test_struct04() {
-        run_test("struct04.rs", "DEBUG: UnknownInt(4)", "DEBUG: 4");
+        run_test("struct04.rs", "DEBUG: U64(4)", "DEBUG: 4");
     }
 
     #[test]
     fn test_struct05() {
-        run_test("struct05.rs", "DEBUG: UnknownInt(4)", "DEBUG: 4");
+        run_test("struct05.rs", "DEBUG: U64(4)", "DEBUG: 4");
     }
 
     #[test]
     fn test_struct06() {
-        run_test("struct06.rs", "DEBUG: UnknownInt(5)", "DEBUG: 5");
+        run_test("struct06.rs", "DEBUG: U64(5)", "DEBUG: 5");
+    }
+
+    #[test]
+    fn test_struct07() {
+        run_test("struct07.rs", "DEBUG: U64(7)", "DEBUG: 7");
+    }
+
+    #[test]
+    fn test_move01() {
+        run_test("move01.rs", "DEBUG: U64(7)", "DEBUG: 7");
+    }
+
+    #[test]
+    fn test_move_bad01() {
+        run_bad_test("move_bad01.rs", "Use of moved value: bar");
+    }
+
+    #[test]
+    fn test_move_bad02() {
+        run_bad_test("move_bad02.rs", "Use of moved value: bar");
+    }
+
+    #[test]
+    fn test_init01() {
+        run_test(
+            "init01.rs",
+            "DEBUG: U64(10)\nDEBUG: UnknownInt(3)",
+            "DEBUG: 10\nDEBUG: 3",
+        );
+    }
+
+    #[test]
+    fn test_init_bad01() {
+        // Only one arm of the if assigns it
+        run_bad_test(
+            "init_bad01.rs",
+            "init_bad01.rs:7:5: Use of possibly-uninitialized variable: x",
+        );
+    }
+
+    #[test]
+    fn test_init_bad02() {
+        // The body of the while may not run
+        run_bad_test(
+            "init_bad02.rs",
+            "init_bad02.rs:8:5: Use of possibly-uninitialized variable: x",
+        );
+    }
+
+    #[test]
+    fn test_ref01() {
+        run_test("ref01.rs", "DEBUG: U64(10)", "DEBUG: 10");
+    }
+
+    #[test]
+    fn test_borrow01() {
+        run_test(
+            "borrow01.rs",
+            "DEBUG: UnknownInt(5)\nDEBUG: UnknownInt(6)",
+            "DEBUG: 5\nDEBUG: 6",
+        );
+    }
+
+    #[test]
+    fn test_borrow_bad01() {
+        run_bad_test("borrow_bad01.rs", "Can not borrow x as mutable");
+    }
+
+    #[test]
+    fn test_borrow_bad02() {
+        run_bad_test("borrow_bad02.rs", "Can not assign to x");
+    }
+
+    #[test]
+    fn test_impl01() {
+        run_test("impl01.rs", "DEBUG: UnknownInt(7)", "DEBUG: 7");
+    }
+
+    #[test]
+    fn test_drop01() {
+        run_test(
+            "drop01.rs",
+            "DEBUG: UnknownInt(10)\nDEBUG: U64(2)\nDEBUG: UnknownInt(30)\nDEBUG: U64(3)\nDEBUG: UnknownInt(20)\nDEBUG: U64(1)",
+            "DEBUG: 10\nDEBUG: 2\nDEBUG: 30\nDEBUG: 3\nDEBUG: 20\nDEBUG: 1",
+        );
+    }
+
+    #[test]
+    fn test_drop02() {
+        run_test(
+            "drop02.rs",
+            "DEBUG: U64(2)\nDEBUG: U64(1)\nDEBUG: U64(6)\nDEBUG: U64(6)\nDEBUG: UnknownInt(9)\nDEBUG: U64(5)\nDEBUG: UnknownInt(8)\nDEBUG: U64(4)\nDEBUG: U64(3)",
+            "DEBUG: 2\nDEBUG: 1\nDEBUG: 6\nDEBUG: 6\nDEBUG: 9\nDEBUG: 5\nDEBUG: 8\nDEBUG: 4\nDEBUG: 3",
+        );
+    }
+
+    #[test]
+    fn test_drop03() {
+        // Values that statements throw away are dropped at the end of the statement
+        run_test(
+            "drop03.rs",
+            "DEBUG: U64(2)\nDEBUG: U64(3)\nDEBUG: U64(4)\nDEBUG: UnknownInt(5)\nDEBUG: U64(1)",
+            "DEBUG: 2\nDEBUG: 3\nDEBUG: 4\nDEBUG: 5\nDEBUG: 1",
+        );
+    }
+
+    #[test]
+    fn test_drop_bad01() {
+        run_bad_test("drop_bad01.rs", "Can not move out of outer");
     }
 
     #[test]
@@ -368,6 +619,33 @@ mod tests {
         run_test("generic02.rs", "DEBUG: UnknownInt(8)", "DEBUG: 8");
     }
 
+    #[test]
+    fn test_generic03() {
+        // Each generic function gets its own instance for the same types, and later calls with them reuse it
+        let bc = load_to_bc("generic03.rs").unwrap();
+        let first = bc.get_defn("first", 0).unwrap().0;
+        let second = bc.get_defn("second", 0).unwrap().0;
+        let instances: Vec<(DefinitionId, Vec<DefinitionId>)> = bc
+            .fn_instances()
+            .into_iter()
+            .map(|(generic_id, ty_args, _)| (generic_id, ty_args))
+            .collect();
+        assert_eq!(
+            instances,
+            vec![
+                (first, vec![builtin_type::U64]),
+                (second, vec![builtin_type::U64]),
+                (first, vec![builtin_type::BOOL]),
+            ]
+        );
+        check_output(
+            &bc,
+            "generic03.rs",
+            "DEBUG: U64(1)\nDEBUG: U64(2)\nDEBUG: Bool(true)\nDEBUG: U64(7)",
+            "DEBUG: 1\nDEBUG: 2\nDEBUG: 1\nDEBUG: 7",
+        );
+    }
+
     #[test]
     fn test_generic_bad01() {
         run_bad_test("generic_bad01.rs", "add values of");
@@ -378,6 +656,30 @@ mod tests {
         run_test("while01.rs", "DEBUG: UnknownInt(10)", "DEBUG: 10");
     }
 
+    #[test]
+    fn test_eval01() {
+        run_test(
+            "eval01.rs",
+            "DEBUG: U64(8)\nDEBUG: I32(-6)\nDEBUG: I32(-4)\nDEBUG: I32(-3)\nDEBUG: U32(3)",
+            "DEBUG: 8\nDEBUG: -6\nDEBUG: -4\nDEBUG: -3\nDEBUG: 3",
+        );
+    }
+
+    #[test]
+    fn test_recursion01() {
+        run_test(
+            "recursion01.rs",
+            "DEBUG: U64(10)\nDEBUG: Bool(false)",
+            "DEBUG: 10\nDEBUG: 0",
+        );
+    }
+
+    #[test]
+    fn test_recursion02() {
+        // Calls don't use the host's stack, so deep recursion doesn't overflow it
+        run_test("recursion02.rs", "DEBUG: U64(100000)", "DEBUG: 100000");
+    }
+
     #[test]
     fn test_ffi01() {
         run_test("ffi01.rs", "DEBUG: I32(1)", "DEBUG: 1");
@@ -397,4 +699,3777 @@ mod tests {
     fn test_pain03() {
         run_test("pain03.rs", "DEBUG: UnknownInt(10000)", "DEBUG: 10000");
     }
+
+    #[test]
+    fn test_trace01() {
+        let bc = load_to_bc("fn08.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+
+        let trace = ee.trace_program(&bc, "main", 1000);
+        assert!(!trace.truncated);
+        assert_eq!(trace.steps[0].function, "main");
+        assert!(trace
+            .steps
+            .iter()
+            .any(|step| step.function == "foo" && step.locals == vec![("y".to_string(), 1)]));
+        assert_eq!("DEBUG: U64(8)", ee.debug_capture.unwrap().trim());
+    }
+
+    #[test]
+    fn test_trace02() {
+        let bc = load_to_bc("while01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+
+        let trace = ee.trace_program(&bc, "main", 5);
+        assert!(trace.truncated);
+        assert_eq!(trace.steps.len(), 5);
+        assert_eq!(ee.debug_capture.unwrap(), "");
+        assert!(trace.to_json().starts_with(
+            "{\"version\":2,\"truncated\":true,\"steps\":[{\"function\":\"main\",\"offset\":0,"
+        ));
+    }
+
+    #[test]
+    fn test_copy01() {
+        run_test(
+            "copy01.rs",
+            "DEBUG: U64(3)\nDEBUG: U64(12)\nDEBUG: U64(9)",
+            "DEBUG: 3\nDEBUG: 12\nDEBUG: 9",
+        );
+    }
+
+    #[test]
+    fn test_copy_bad01() {
+        run_bad_test("copy_bad01.rs", "field name is not Copy");
+    }
+
+    #[test]
+    fn test_copy_bad02() {
+        run_bad_test("copy_bad02.rs", "because it implements Drop");
+    }
+
+    #[test]
+    fn test_copy_bad03() {
+        run_bad_test("copy_bad03.rs", "Use of moved value: a");
+    }
+
+    #[test]
+    fn test_lifetime01() {
+        run_test(
+            "lifetime01.rs",
+            "DEBUG: U64(5)\nDEBUG: U64(6)",
+            "DEBUG: 5\nDEBUG: 6",
+        );
+    }
+
+    #[test]
+    fn test_lifetime02() {
+        run_test("lifetime02.rs", "DEBUG: U64(12)", "DEBUG: 12");
+    }
+
+    #[test]
+    fn test_generic_struct01() {
+        run_test(
+            "generic_struct01.rs",
+            "DEBUG: U64(2)\nDEBUG: U64(7)\nDEBUG: Bool(false)",
+            "DEBUG: 2\nDEBUG: 7\nDEBUG: 0",
+        );
+    }
+
+    #[test]
+    fn test_generic_struct02() {
+        run_test("generic_struct02.rs", "DEBUG: U64(84)", "DEBUG: 84");
+    }
+
+    #[test]
+    fn test_generic_struct_bad01() {
+        run_bad_test(
+            "generic_struct_bad01.rs",
+            "Mismatched types: expected u64, found bool",
+        );
+    }
+
+    #[test]
+    fn test_generic_struct_bad02() {
+        run_bad_test("generic_struct_bad02.rs", "Copy is not satisfied");
+    }
+
+    #[test]
+    fn test_struct_bad01() {
+        run_bad_test(
+            "struct_bad01.rs",
+            "struct_bad01.rs:1:10: Tuple structs aren't supported",
+        );
+        run_bad_test(
+            "struct_bad02.rs",
+            "struct_bad02.rs:1:13: Tuple structs aren't supported",
+        );
+        run_bad_test(
+            "struct_bad03.rs",
+            "struct_bad03.rs:1:13: Tuple structs aren't supported",
+        );
+    }
+
+    #[test]
+    fn test_trait01() {
+        run_test(
+            "trait01.rs",
+            "DEBUG: U64(33)\nDEBUG: U64(49)\nDEBUG: U64(9)",
+            "DEBUG: 33\nDEBUG: 49\nDEBUG: 9",
+        );
+    }
+
+    #[test]
+    fn test_trait02() {
+        run_test("trait02.rs", "DEBUG: U64(11)", "DEBUG: 11");
+    }
+
+    #[test]
+    fn test_trait_solver01() {
+        let mut bc = load_to_bc("copy01.rs").unwrap();
+        let point = bc.get_defn("Point", 0).unwrap().0;
+        let size = bc.get_defn("Size", 0).unwrap().0;
+        assert!(bc.implements(point, "Copy"));
+        assert!(bc.implements(size, "Copy"));
+        assert!(!bc.implements(size, "Drop"));
+
+        // A struct that's only Copy if it's Copy itself isn't, and finding that out doesn't recurse forever
+        if let Definition::Struct(ref mut st) = bc.definitions[point] {
+            st.fields.push(("size".to_string(), size));
+        }
+        if let Definition::Struct(ref mut st) = bc.definitions[size] {
+            st.fields.push(("point".to_string(), point));
+        }
+        bc.trait_solutions.borrow_mut().clear();
+        assert!(!bc.implements(point, "Copy"));
+        assert!(!bc.implements(size, "Copy"));
+    }
+
+    #[test]
+    fn test_ty01() {
+        let bc = load_to_bc("ty01.rs").unwrap();
+        let var_ty = |name| bc.var_ty("main", name, 0).unwrap();
+        assert_eq!(var_ty("p").to_string(), "shapes::Point");
+        assert_eq!(var_ty("r").to_string(), "&mut shapes::Point");
+        assert_eq!(var_ty("flags").to_string(), "Pair<bool>");
+        assert_eq!(var_ty("n").to_string(), "u32");
+
+        assert!(var_ty("r").is_mutable_reference());
+        assert_eq!(var_ty("r").referenced(), Some(var_ty("p")));
+        assert!(var_ty("p").is_struct() && !var_ty("p").is_copy());
+        assert!(var_ty("n").is_integer() && !var_ty("n").is_signed() && var_ty("n").is_copy());
+        assert!(!var_ty("flags").is_copy());
+
+        assert_eq!(
+            bc.var_ty("first", "pair", 0).unwrap().to_string(),
+            "&Pair<T>"
+        );
+        assert_eq!(bc.return_ty("first", 0).unwrap().to_string(), "T");
+        assert!(bc.return_ty("main", 0).unwrap().is_void());
+        assert!(bc.var_ty("main", "q", 0).is_err());
+    }
+
+    #[test]
+    fn test_scope_names01() {
+        let bc = load_to_bc("ty01.rs").unwrap();
+        let names: Vec<(String, &str)> = bc
+            .scope_names(0)
+            .into_iter()
+            .map(|(name, definition_id)| (name, bc.definition(definition_id).kind()))
+            .collect();
+        // A generic function's type parameters are named in the scope it's declared in
+        assert_eq!(
+            names,
+            vec![
+                ("Pair".to_string(), "struct"),
+                ("T".to_string(), "type"),
+                ("first".to_string(), "fn"),
+                ("main".to_string(), "fn"),
+                ("shapes".to_string(), "mod"),
+            ]
+        );
+
+        // A variable that's shadowed can't be named any more
+        let mut bc = BytecodeEngine::new();
+        let mut var_stack = VarStack::new();
+        for stmt in &["let n = 3;", "let m = true;", "let n = 4u32;"] {
+            bc.process_raw_stmt_str(stmt, &mut vec![], &mut var_stack)
+                .unwrap();
+        }
+        let vars: Vec<(usize, String)> = var_stack
+            .visible_vars()
+            .into_iter()
+            .map(|(var_id, var)| (var_id, format!("{}: {}", var.ident, bc.ty(var.ty))))
+            .collect();
+        assert_eq!(
+            vars,
+            vec![(1, "m: bool".to_string()), (2, "n: u32".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_repl_session01() {
+        use eval::Halt;
+        use repl::ReplSession;
+
+        // Variables and items from earlier lines can be used by later ones, and statements can leave off their `;`
+        let mut session = ReplSession::new().unwrap();
+        let output = session.eval_line("let x = 3u64").unwrap();
+        assert!(output.ty.is_none() && output.value.is_none());
+        session
+            .eval_line("fn double(a: u64) -> u64 { a * 2 }")
+            .unwrap();
+        let output = session.eval_line("double(x) + 1").unwrap();
+        assert_eq!(output.ty, Some(builtin_type::U64));
+        assert!(matches!(output.value, Some(Value::U64(7))));
+        assert_eq!(session.expr_type("x < 4").unwrap(), builtin_type::BOOL);
+
+        // A line that can't be converted doesn't declare anything
+        assert!(session.eval_line("let y = nope").is_err());
+        assert_eq!(session.var_stack().find_var("y"), None);
+
+        // Neither does one whose evaluation stops, though what it assigned stays
+        session.eval.fuel = Some(1000);
+        session.eval_line("let mut i = 0u64").unwrap();
+        let stopped = session.eval_line("let j = { while true { i = i + 1; } 1u64 }");
+        assert!(matches!(stopped, Err(PeachError::Runtime(_))));
+        assert_eq!(session.eval.halted, Some(Halt::OutOfFuel));
+        assert_eq!(session.var_stack().find_var("j"), None);
+        let i = session.var_stack().find_var("i").unwrap();
+        assert!(matches!(session.var_value(i), Some(Value::U64(n)) if *n > 0));
+
+        // Temporaries, and the variables of blocks that have ended, don't build up on the stack.  Only the value, kept
+        // as a result variable, does.
+        session.eval.fuel = None;
+        let depth = session.eval.value_stack.len();
+        let output = session.eval_line("{ let q = 2u64; q * x }").unwrap();
+        assert!(matches!(output.value, Some(Value::U64(6))));
+        session.eval_line("double(4);").unwrap();
+        assert_eq!(session.eval.value_stack.len(), depth + 1);
+    }
+
+    #[test]
+    fn test_repl_load01() {
+        use repl::ReplSession;
+        use std::fs;
+
+        let root =
+            ::std::env::temp_dir().join(format!("peach_repl_load01_{}", ::std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("shapes.rs");
+        let path = path.to_str().unwrap();
+        fs::write(
+            path,
+            "struct Square { side: u64 }\n\
+             impl Square { fn area(&self) -> u64 { self.side * self.side } }\n\
+             fn scale() -> u64 { 2 }\n",
+        )
+        .unwrap();
+
+        let mut session = ReplSession::new().unwrap();
+        session.load_file(path).unwrap();
+        session
+            .eval_line("let square = Square { side: 3 }")
+            .unwrap();
+        let area = "square.area() * scale()";
+        let output = session.eval_line(area).unwrap();
+        assert!(matches!(output.value, Some(Value::U64(18))));
+
+        // Loading the file again replaces the items, and the methods, that it defined before
+        fs::write(
+            path,
+            "struct Square { side: u64 }\n\
+             impl Square { fn area(&self) -> u64 { self.side * 4 } }\n\
+             fn scale() -> u64 { 10 }\n",
+        )
+        .unwrap();
+        session.load_file(path).unwrap();
+        let new_square = session.expr_type("Square { side: 3 }").unwrap();
+        session
+            .eval_line("let new_square = Square { side: 3 }")
+            .unwrap();
+        let output = session.eval_line("new_square.area() * scale()").unwrap();
+        assert!(matches!(output.value, Some(Value::U64(120))));
+
+        // A value of the struct it replaced keeps the methods it had
+        assert_ne!(session.expr_type("square").unwrap(), new_square);
+        let output = session.eval_line(area).unwrap();
+        assert!(matches!(output.value, Some(Value::U64(90))));
+
+        let missing = root.join("missing.rs");
+        assert!(session.load_file(missing.to_str().unwrap()).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_repl_results01() {
+        use repl::ReplSession;
+
+        let mut session = ReplSession::new().unwrap();
+        match session.eval_line("_ + 1") {
+            Err(e) => assert_eq!(e.message(), "There are no results for _ to refer to yet"),
+            Ok(_) => panic!("Expected _ to have no result to refer to"),
+        }
+
+        // Each value is kept in the next numbered variable, and `_` is the latest
+        let output = session.eval_line("2u64 + 3").unwrap();
+        assert_eq!(output.result_var, Some("_1".to_string()));
+        session.eval_line("let x = 10u64").unwrap();
+        let output = session.eval_line("_1 * x").unwrap();
+        assert_eq!(output.result_var, Some("_2".to_string()));
+        assert!(matches!(output.value, Some(Value::U64(50))));
+        let output = session.eval_line("_ + _1").unwrap();
+        assert!(matches!(output.value, Some(Value::U64(55))));
+        assert_eq!(session.expr_type("_ < 1").unwrap(), builtin_type::BOOL);
+
+        // Statements don't give a value, and a `_` that isn't a name is left alone
+        let output = session.eval_line("let y_ = _").unwrap();
+        assert!(output.result_var.is_none());
+        let output = session.eval_line("y_ + 1_000 /* _ */").unwrap();
+        assert!(matches!(output.value, Some(Value::U64(1055))));
+        assert_eq!(output.result_var, Some("_4".to_string()));
+    }
+
+    #[test]
+    fn test_completions01() {
+        use repl::ReplSession;
+
+        let mut session = ReplSession::new().unwrap();
+        session
+            .eval_line(
+                "mod shapes {
+                    pub struct Point { pub x: u64 }
+                    impl Point { pub fn new() -> Point { Point { x: 1 } } }
+                    fn hidden() {}
+                    pub fn area() -> u64 { 3 }
+                }",
+            )
+            .unwrap();
+        session.eval_line("let counter = 1").unwrap();
+        let mut complete = |line: &str| {
+            let (start, names) = session.completions(line, line.len());
+            (start, names.join(" "))
+        };
+
+        // Names from the scope, the prelude, and the session's variables
+        assert_eq!(complete("let y = co"), (8, "counter".to_string()));
+        assert_eq!(complete("m"), (0, "max min".to_string()));
+
+        // Items of a module that can be used from outside it, and a type's associated functions
+        assert_eq!(complete("shapes::"), (8, "Point area".to_string()));
+        assert_eq!(complete("shapes::Point::n"), (15, "new".to_string()));
+        assert_eq!(complete("crate::sh"), (7, "shapes".to_string()));
+
+        // Fields and paths that lead nowhere aren't completed
+        assert_eq!(complete("counter.x"), (9, "".to_string()));
+        assert_eq!(complete("nope::a"), (6, "".to_string()));
+    }
+
+    #[test]
+    fn test_is_incomplete01() {
+        use repl::is_incomplete;
+
+        for input in &[
+            "fn add(a: u64, b: u64) -> u64 {",
+            "foo(1,",
+            "let x = [1, 2",
+            "let x = 1 +",
+            "let x =",
+            "fn next(x: u64) ->",
+            "x >",
+            "x.",
+            "let s = \"a (",
+            "/* a { */ /* b",
+            "let c = '{'; {",
+        ] {
+            assert!(is_incomplete(input), "{}", input);
+        }
+
+        for input in &[
+            "",
+            "let x = 1 + 2",
+            "fn add(a: u64, b: u64) -> u64 { a + b }",
+            "let x = (1 + // )\n 2)",
+            "let p: Pair<Pair<u64>>",
+            "let s = \"a ( \\\" {\"",
+            "/* a /* { */ */ 1",
+            "let c = '('",
+            "let c = '\\''",
+            "fn first<'a>(x: &'a u64) -> &'a u64 { x }",
+            "x)",
+        ] {
+            assert!(!is_incomplete(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_trait_bad01() {
+        run_bad_test(
+            "trait_bad01.rs",
+            "Missing method sides in impl of Shape for Circle",
+        );
+    }
+
+    #[test]
+    fn test_trait_bad02() {
+        run_bad_test(
+            "trait_bad02.rs",
+            "Method radius is not a member of trait Shape",
+        );
+    }
+
+    #[test]
+    fn test_trait_bad03() {
+        run_bad_test("trait_bad03.rs", "Shape is not satisfied");
+    }
+
+    #[test]
+    fn test_trait_bad04() {
+        run_bad_test("trait_bad04.rs", "have a method named size: Shape, Box");
+    }
+
+    #[test]
+    fn test_macro01() {
+        run_test(
+            "macro01.rs",
+            "DEBUG: UnknownInt(10)\nDEBUG: UnknownInt(16)\nDEBUG: UnknownInt(11)",
+            "DEBUG: 10\nDEBUG: 16\nDEBUG: 11",
+        );
+    }
+
+    #[test]
+    fn test_macro_bad01() {
+        run_bad_test(
+            "macro_bad01.rs",
+            "Recursion limit reached while expanding forever!",
+        );
+    }
+
+    #[test]
+    fn test_attr01() {
+        run_test("attr01.rs", "DEBUG: U64(16)", "DEBUG: 16");
+
+        let bc = load_to_bc("attr01.rs").unwrap();
+        let skipped = bc.skipped_attributes();
+        for name in &[
+            "allow",
+            "derive(Debug)",
+            "derive(PartialEq)",
+            "inline",
+            "must_use",
+        ] {
+            assert!(
+                skipped.contains(&name.to_string()),
+                "{} was not skipped",
+                name
+            );
+        }
+        assert!(!skipped.contains(&"derive(Copy)".to_string()));
+        assert!(!skipped.contains(&"doc".to_string()));
+    }
+
+    #[test]
+    fn test_tests01() {
+        // Tests aren't loaded unless compiling for them
+        let mut bc = load_to_bc("tests01.rs").unwrap();
+        assert_eq!(bc.run_tests().unwrap().results.len(), 0);
+
+        let config = EngineConfig {
+            tests: true,
+            ..EngineConfig::default()
+        };
+        let mut bc = load_with_config("tests01.rs", config).unwrap();
+        let report = bc.run_tests().unwrap();
+        let names: Vec<&str> = report
+            .results
+            .iter()
+            .map(|result| result.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["doubles", "tests::doubles_odd", "tests::stops"]);
+        assert_eq!((report.passed(), report.failed()), (1, 2));
+
+        assert!(report.results[0].failure.is_none());
+        let failure = report.results[1].failure.as_ref().unwrap();
+        assert_eq!(
+            failure.message(),
+            "panicked: assertion failed: double(3) == expected()"
+        );
+        assert_eq!(report.results[1].output, "DEBUG: U64(6)\n");
+        let failure = report.results[2].failure.as_ref().unwrap();
+        assert_eq!(failure.message(), "panicked: not yet");
+
+        let summary = report.to_string();
+        assert!(summary.starts_with("running 3 tests\ntest doubles ... ok\n"));
+        assert!(summary.contains("test tests::stops ... FAILED\n"));
+        assert!(summary.contains("---- tests::doubles_odd ----\nDEBUG: U64(6)\n"));
+        assert!(summary.ends_with("test result: FAILED. 1 passed; 2 failed\n"));
+    }
+
+    #[test]
+    fn test_tests_bad01() {
+        let config = EngineConfig {
+            tests: true,
+            ..EngineConfig::default()
+        };
+        let mut bc = load_with_config("tests_bad01.rs", config).unwrap();
+        match bc.run_tests() {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Test takes_input has to be a function that takes no arguments and returns nothing"
+            ),
+            Ok(report) => panic!("Expected the tests not to run, got {}", report),
+        }
+    }
+
+    #[test]
+    fn test_project01() {
+        let mut bc = BytecodeEngine::new();
+        bc.load_project("test_files/project01").unwrap();
+        bc.process_fn("main", 0).unwrap();
+        check_output(&bc, "project01", "DEBUG: U64(42)", "DEBUG: 42");
+    }
+
+    #[test]
+    fn test_project02() {
+        // A library has no main, but its functions can still be processed
+        let mut bc = BytecodeEngine::new();
+        bc.load_project("test_files/project02").unwrap();
+        bc.process_fn("answer", 0).unwrap();
+
+        let mut bc = BytecodeEngine::new();
+        match bc.load_project("test_files/project01/src") {
+            Err(PeachError::Io(_)) => {}
+            x => panic!("Expected an io error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_project03() {
+        let mut bc = BytecodeEngine::new();
+        bc.load_project("test_files/project03").unwrap();
+        bc.process_fn("main", 0).unwrap();
+        check_output(
+            &bc,
+            "project03",
+            "DEBUG: U64(12)\nDEBUG: U64(9)",
+            "DEBUG: 12\nDEBUG: 9",
+        );
+
+        let mut bc = BytecodeEngine::new();
+        let err = bc.load_project("test_files/project04").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "src/main.rs:1:5: File for module dup found at both src/dup.rs and src/dup/mod.rs"
+        );
+    }
+
+    #[test]
+    fn test_project05() {
+        let mut bc = BytecodeEngine::new();
+        bc.load_project("test_files/project05").unwrap();
+        bc.process_fn("main", 0).unwrap();
+        assert!(bc.skipped_attributes().is_empty());
+        check_output(
+            &bc,
+            "project05",
+            "DEBUG: U64(5)\nDEBUG: U64(7)",
+            "DEBUG: 5\nDEBUG: 7",
+        );
+    }
+
+    fn load_with_crate(fname: &str, crate_name: &str) -> Result<BytecodeEngine> {
+        let mut bc = BytecodeEngine::new();
+        bc.set_project_root("test_files")?;
+        bc.add_crate(crate_name, "test_files/geometry")?;
+        bc.load_file(fname)?;
+        bc.process_fn("main", 0)?;
+        Ok(bc)
+    }
+
+    #[test]
+    fn test_crate01() {
+        let bc = load_with_crate("crate01.rs", "geometry").unwrap();
+        check_output(
+            &bc,
+            "crate01.rs",
+            "DEBUG: U64(18)\nDEBUG: U64(8)",
+            "DEBUG: 18\nDEBUG: 8",
+        );
+        assert!(bc.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_crate02() {
+        // Crates can be used without extern crate, under the name they were added with
+        let bc = load_with_crate("crate02.rs", "geom").unwrap();
+        check_output(&bc, "crate02.rs", "DEBUG: U64(10)", "DEBUG: 10");
+    }
+
+    #[test]
+    fn test_crate_bad01() {
+        let err = load_with_crate("crate_bad01.rs", "geometry").err().unwrap();
+        assert_eq!(
+            err.message(),
+            "helper is private, so it can only be used inside its module"
+        );
+
+        let err = load_to_bc("crate_bad01.rs").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "crate_bad01.rs:1:14: Can not find crate geometry"
+        );
+    }
+
+    fn load_with_prelude(fname: &str) -> Result<BytecodeEngine> {
+        let mut prelude = Prelude::standard();
+        prelude.add_source("host.rs", "pub fn answer() -> u64 { 42 }");
+
+        let mut bc = BytecodeEngine::with_prelude(&prelude)?;
+        bc.set_project_root("test_files")?;
+        bc.load_file(fname)?;
+        bc.process_fn("main", 0)?;
+        Ok(bc)
+    }
+
+    #[test]
+    fn test_prelude01() {
+        let bc = load_with_prelude("prelude01.rs").unwrap();
+        check_output(
+            &bc,
+            "prelude01.rs",
+            "DEBUG: UnknownInt(8)\nDEBUG: U64(4)\nDEBUG: U64(42)",
+            "DEBUG: 8\nDEBUG: 4\nDEBUG: 42",
+        );
+        assert!(bc.warnings().is_empty());
+
+        // Without the prelude, there's no max
+        assert!(load_to_bc("prelude01.rs").is_err());
+    }
+
+    #[test]
+    fn test_prelude02() {
+        let bc = load_with_prelude("prelude02.rs").unwrap();
+        check_output(&bc, "prelude02.rs", "DEBUG: U64(11)", "DEBUG: 11");
+    }
+
+    fn load_with_natives(fname: &str) -> Result<BytecodeEngine> {
+        let mut bc = BytecodeEngine::new();
+        bc.register_native(
+            "triple",
+            &[builtin_type::U64],
+            builtin_type::U64,
+            |args| match args[0] {
+                Value::U64(x) => Ok(Value::U64(x * 3)),
+                _ => Err("triple takes a u64".to_string()),
+            },
+        );
+        bc.register_native(
+            "scale",
+            &[builtin_type::U64],
+            builtin_type::U64,
+            |args| match args[0] {
+                Value::U64(x) => Ok(Value::U64(x * 10)),
+                _ => Err("scale takes a u64".to_string()),
+            },
+        );
+        bc.set_project_root("test_files")?;
+        bc.load_file(fname)?;
+        bc.process_fn("main", 0)?;
+        Ok(bc)
+    }
+
+    #[test]
+    fn test_native01() {
+        let bc = load_with_natives("native01.rs").unwrap();
+
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.unwrap().trim(),
+            "DEBUG: U64(21)\nDEBUG: U64(15)\nDEBUG: U64(60)"
+        );
+
+        // Only the evaluator can call them, so the C backend reports the first it meets
+        let err = compile::compile_bytecode(&bc, "native01.rs").unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Native function triple can't be compiled to C"
+        );
+    }
+
+    #[test]
+    fn test_native_bad01() {
+        match load_with_natives("native_bad01.rs") {
+            Err(e) => assert_eq!(e.message(), "Mismatched types: expected u64, found bool"),
+            Ok(_) => panic!("Expected native_bad01.rs to fail"),
+        }
+    }
+
+    #[test]
+    fn test_native02() {
+        let mut bc = BytecodeEngine::new();
+        bc.register_native(
+            "is_even",
+            &[builtin_type::U64],
+            builtin_type::BOOL,
+            |args| match u64::from_peach(&args[0]) {
+                Some(x) => Ok((x % 2 == 0).into_peach()),
+                None => Err("is_even takes a u64".to_string()),
+            },
+        );
+        bc.register_native("half", &[builtin_type::U64], builtin_type::U64, |args| {
+            match u64::from_peach(&args[0]) {
+                Some(x) => Ok((x / 2).into_peach()),
+                None => Err("half takes a u64".to_string()),
+            }
+        });
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("native02.rs").unwrap();
+        bc.process_fn("main", 0).unwrap();
+
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.unwrap().trim(),
+            "DEBUG: U64(6)\nDEBUG: Bool(false)"
+        );
+    }
+
+    fn load_with_config(fname: &str, config: EngineConfig) -> Result<BytecodeEngine> {
+        let mut bc = BytecodeEngine::new();
+        bc.set_config(config);
+        bc.set_project_root("test_files")?;
+        bc.load_file(fname)?;
+        bc.process_fn("main", 0)?;
+        Ok(bc)
+    }
+
+    #[test]
+    fn test_config01() {
+        let config = EngineConfig {
+            allow_extern_fns: false,
+            ..EngineConfig::default()
+        };
+        match load_with_config("ffi01.rs", config) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Calling extern functions is not allowed by this engine's configuration"
+            ),
+            Ok(_) => panic!("Expected ffi01.rs to fail"),
+        }
+
+        match load_with_config("while01.rs", EngineConfig::sandboxed()) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "println! is not allowed by this engine's configuration"
+            ),
+            Ok(_) => panic!("Expected while01.rs to fail"),
+        }
+
+        // Natives are up to the host, so they're allowed in a sandbox
+        let mut bc = BytecodeEngine::new();
+        bc.set_config(EngineConfig::sandboxed());
+        bc.register_native("answer", &[], builtin_type::U64, |_| Ok(Value::U64(42)));
+        bc.load_str("main.rs", "fn main() -> u64 { answer() }")
+            .unwrap();
+        bc.process_fn("main", 0).unwrap();
+    }
+
+    #[test]
+    fn test_widen01() {
+        // Without the setting, a u32 can't be used as a u64
+        let err = load_to_bc("widen01.rs").err().unwrap();
+        let errors: Vec<String> = err.errors().iter().map(|x| x.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "widen01.rs:26:15: Mismatched types: expected u64, found u32",
+                "widen01.rs:27:24: Mismatched types: expected u64, found u32",
+                "widen01.rs:30:27: Mismatched types: expected u64, found u32",
+                "widen01.rs:16:5: Mismatched types: expected u64, found u32",
+                "widen01.rs:20:12: Mismatched types: expected i64, found i32",
+            ]
+        );
+
+        let config = EngineConfig {
+            widening_coercions: true,
+            ..EngineConfig::default()
+        };
+        let bc = load_with_config("widen01.rs", config).unwrap();
+        check_output(
+            &bc,
+            "widen01.rs",
+            "DEBUG: U64(14)\nDEBUG: U64(14)\nDEBUG: I64(-3)\nDEBUG: U64(7)",
+            "DEBUG: 14\nDEBUG: 14\nDEBUG: -3\nDEBUG: 7",
+        );
+
+        // Compiled functions widen too
+        #[cfg(feature = "jit")]
+        {
+            use eval::OverflowPolicy;
+            use jit::JitProgram;
+            let jit = JitProgram::compile(&bc, OverflowPolicy::Error).unwrap();
+            for fname in &["widen", "offset"] {
+                let (definition_id, _) = bc.get_defn(fname, 0).unwrap();
+                assert!(jit.contains(definition_id), "{}", fname);
+            }
+        }
+    }
+
+    #[test]
+    fn test_call_fn01() {
+        let mut bc = BytecodeEngine::new();
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("call_fn01.rs").unwrap();
+        let add = bc.process_fn("add", 0).unwrap();
+        let manhattan = bc.process_fn("manhattan", 0).unwrap();
+        let is_less = bc.process_fn("is_less", 0).unwrap();
+
+        let mut ee = EvalEngine::new();
+        let result = ee
+            .call_fn(&bc, add, &[2u64.into_peach(), 3u64.into_peach()])
+            .unwrap();
+        assert_eq!(u64::from_peach(&result), Some(5));
+
+        let result = ee
+            .call_fn(&bc, manhattan, &[4u64.into_peach(), 6u64.into_peach()])
+            .unwrap();
+        assert_eq!(u64::from_peach(&result), Some(10));
+
+        let result = ee
+            .call_fn(&bc, is_less, &[(-1i32).into_peach(), 1i32.into_peach()])
+            .unwrap();
+        assert_eq!(bool::from_peach(&result), Some(true));
+        assert!(ee.value_stack.is_empty());
+
+        match ee.call_fn(&bc, add, &[2u64.into_peach()]) {
+            Err(e) => assert_eq!(e.message(), "Function takes 2 arguments but 1 were given"),
+            Ok(_) => panic!("Expected the call to fail"),
+        }
+        match ee.call_fn(&bc, add, &[true.into_peach(), 3u64.into_peach()]) {
+            Err(e) => assert_eq!(e.message(), "Argument a should be a u64 but is Bool(true)"),
+            Ok(_) => panic!("Expected the call to fail"),
+        }
+        assert!(ee.call_fn(&bc, builtin_type::U64, &[]).is_err());
+    }
+
+    #[test]
+    fn test_eval_expr_str01() {
+        let mut bc = BytecodeEngine::with_prelude(&Prelude::standard()).unwrap();
+        let mut ee = EvalEngine::new();
+        let mut eval = |bc: &mut BytecodeEngine, expr: &str| {
+            let (value, ty) = ee.eval_expr_str(bc, expr).unwrap();
+            format!("{}: {}", value, bc.ty(ty))
+        };
+        assert_eq!(eval(&mut bc, "1 + 2 * 3"), "7: {integer}");
+        assert_eq!(eval(&mut bc, "max(3u64, 4) < 4"), "false: bool");
+
+        // Functions of loaded files can be called
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("call_fn01.rs").unwrap();
+        assert_eq!(eval(&mut bc, "add(2, manhattan(4, 6))"), "12: u64");
+
+        match ee.eval_expr_str(&mut bc, "add(1, true)") {
+            Err(e) => assert_eq!(e.message(), "Mismatched types: expected u64, found bool"),
+            Ok(_) => panic!("Expected add(1, true) to not typecheck"),
+        }
+        match ee.eval_expr_str(&mut bc, "0u64 - 1") {
+            Err(PeachError::Runtime(_)) => assert!(ee.value_stack.is_empty()),
+            Err(e) => panic!("Expected a runtime error, got {}", e),
+            Ok(_) => panic!("Expected 0u64 - 1 to overflow"),
+        }
+    }
+
+    #[test]
+    fn test_call_fn02() {
+        let mut bc = BytecodeEngine::new();
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("recursion01.rs").unwrap();
+        let countdown = bc.process_fn("countdown", 0).unwrap();
+        let is_even = bc.process_fn("is_even", 0).unwrap();
+
+        // Calls reuse the variables of earlier calls, including those of a call that was halted part way down
+        let mut ee = EvalEngine::new();
+        for n in &[50u64, 10, 200] {
+            let result = ee.call_fn(&bc, countdown, &[n.into_peach()]).unwrap();
+            assert_eq!(u64::from_peach(&result), Some(*n));
+        }
+        ee.fuel = Some(100);
+        assert!(ee.call_fn(&bc, countdown, &[100u64.into_peach()]).is_err());
+        ee.fuel = None;
+        let result = ee.call_fn(&bc, is_even, &[7u64.into_peach()]).unwrap();
+        assert_eq!(bool::from_peach(&result), Some(false));
+        let result = ee.call_fn(&bc, countdown, &[3u64.into_peach()]).unwrap();
+        assert_eq!(u64::from_peach(&result), Some(3));
+        assert!(ee.value_stack.is_empty());
+    }
+
+    #[test]
+    fn test_emit_c01() {
+        let mut bc = BytecodeEngine::new();
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("emit01.rs").unwrap();
+        let start = bc.process_fn("start", 0).unwrap();
+        bc.process_fn("double", 0).unwrap();
+
+        // The entry function becomes C's main, and the program's main is left out as it wasn't converted
+        let c = compile::emit_c(&bc, "start").unwrap();
+        assert!(c.contains("int main(int argc, char **argv) {"));
+        assert!(!c.contains(&format!("fun_{}(", start)));
+        let double = bc.get_defn("double", 0).unwrap().0;
+        assert!(c.contains(&format!("unsigned long long fun_{}(", double)));
+
+        for (entry_fn, message) in &[
+            ("double", "The entry function double can't take parameters"),
+            ("missing", "Function missing could not be found"),
+        ] {
+            match compile::emit_c(&bc, entry_fn) {
+                Err(e) => assert_eq!(e.message(), *message),
+                Ok(_) => panic!("Expected emitting C from {} to fail", entry_fn),
+            }
+        }
+    }
+
+    #[test]
+    fn test_build01() {
+        use build;
+        use std::env;
+
+        let output = env::temp_dir().join("peach_build01");
+        let path = build::build("test_files/while01.rs", output.to_str().unwrap()).unwrap();
+        assert_eq!(path, output);
+        let cmd = Command::new(&path).output().unwrap();
+        assert_eq!(String::from_utf8(cmd.stdout).unwrap().trim(), "DEBUG: 10");
+
+        match build::build("test_files/missing.rs", output.to_str().unwrap()) {
+            Err(PeachError::Io(_)) => {}
+            x => panic!("Expected an io error, got {:?}", x),
+        }
+
+        // Problems running the compiler are reported, rather than leaving a missing executable
+        let bc = load_to_bc("while01.rs").unwrap();
+        env::set_var("CC", "peach-no-such-compiler");
+        let missing = build::build_executable(&bc, "main", &output);
+        env::set_var("CC", "false");
+        let failing = build::build_executable(&bc, "main", &output);
+        env::remove_var("CC");
+        match missing {
+            Err(PeachError::Io(message)) => {
+                assert!(message.starts_with("Could not run the C compiler peach-no-such-compiler"))
+            }
+            x => panic!("Expected an io error, got {:?}", x),
+        }
+        match failing {
+            Err(PeachError::Build(message)) => assert!(message.starts_with("false failed")),
+            x => panic!("Expected a build error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_emit_rust01() {
+        use std::env;
+        use std::fs;
+        use transpile;
+
+        // Programs translated back to Rust and built by rustc print what the C backend's builds do
+        for (fname, expect) in &[
+            ("while01.rs", "DEBUG: 10"),
+            ("drop02.rs", "DEBUG: 2\nDEBUG: 1\nDEBUG: 6\nDEBUG: 6\nDEBUG: 9\nDEBUG: 5\nDEBUG: 8\nDEBUG: 4\nDEBUG: 3"),
+            ("drop03.rs", "DEBUG: 2\nDEBUG: 3\nDEBUG: 4\nDEBUG: 5\nDEBUG: 1"),
+            ("lifetime01.rs", "DEBUG: 5\nDEBUG: 6"),
+            ("generic_struct02.rs", "DEBUG: 84"),
+            ("ffi01.rs", "DEBUG: 1"),
+            ("exit01.rs", "DEBUG: 1"),
+            ("exit02.rs", "DEBUG: 1"),
+        ] {
+            let bc = load_to_bc(fname).unwrap();
+            let source = transpile::emit_rust(&bc, "main").unwrap();
+
+            let path = env::temp_dir().join(format!("peach_rust_{}", fname));
+            let output = path.with_extension("");
+            fs::write(&path, source).unwrap();
+            let rustc = Command::new("rustc")
+                .arg("-o")
+                .arg(&output)
+                .arg(&path)
+                .output()
+                .unwrap();
+            assert!(
+                rustc.status.success(),
+                "rustc failed on {}: {}",
+                fname,
+                String::from_utf8_lossy(&rustc.stderr)
+            );
+
+            let cmd = Command::new(&output).output().unwrap();
+            assert_eq!(String::from_utf8(cmd.stdout).unwrap().trim(), *expect);
+            let status = match *fname {
+                "exit01.rs" => 3,
+                "exit02.rs" => 42,
+                _ => 0,
+            };
+            assert_eq!(cmd.status.code(), Some(status), "{}", fname);
+        }
+
+        let mut bc = load_to_bc("emit01.rs").unwrap();
+        bc.process_fn("double", 0).unwrap();
+        match transpile::emit_rust(&bc, "double") {
+            Err(e) => assert_eq!(
+                e.message(),
+                "The entry function double can't take parameters"
+            ),
+            Ok(_) => panic!("Expected emitting Rust from double to fail"),
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn test_jit01() {
+        use eval::{Halt, LimitError, OverflowPolicy};
+        use jit::{eval_engine_jit, JitProgram};
+
+        // Functions that only work with integers are compiled, and main, which prints, is evaluated
+        let bc = load_to_bc("jit01.rs").unwrap();
+        let jit = JitProgram::compile(&bc, OverflowPolicy::Error).unwrap();
+        for (fname, compiled) in &[("fib", true), ("sum_to", true), ("main", false)] {
+            let (definition_id, _) = bc.get_defn(fname, 0).unwrap();
+            assert_eq!(jit.contains(definition_id), *compiled, "{}", fname);
+        }
+
+        // Compiled functions return values of their declared types
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        eval_engine_jit(&mut ee, &bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.take().unwrap().trim(),
+            "DEBUG: U64(6765)\nDEBUG: I64(5050)"
+        );
+        assert!(ee.jit.is_none());
+
+        // Errors in compiled code stop the program as they do in the evaluator
+        let bc = load_to_bc("div_zero01.rs").unwrap();
+        ee.debug_capture = Some(String::new());
+        match eval_engine_jit(&mut ee, &bc, "main") {
+            Err(e) => assert_eq!(e.message(), "attempt to divide by zero"),
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        assert_eq!(ee.debug_capture.take().unwrap().trim(), "DEBUG: U64(5)");
+        assert!(ee.value_stack.is_empty());
+
+        // Compiled calls count towards the call depth limit
+        let bc = load_to_bc("jit01.rs").unwrap();
+        ee.limits.max_call_depth = Some(10);
+        assert!(eval_engine_jit(&mut ee, &bc, "main").is_err());
+        assert_eq!(
+            ee.halted,
+            Some(Halt::LimitExceeded(LimitError::CallDepth { depth: 11, max: 10 }))
+        );
+        assert!(ee.eval_program(&bc, "main").is_err());
+        assert_eq!(
+            ee.halted,
+            Some(Halt::LimitExceeded(LimitError::CallDepth { depth: 11, max: 10 }))
+        );
+        ee.limits.max_call_depth = None;
+
+        // Recursion deeper than the native stack allows is evaluated instead
+        let deep = load_to_bc("recursion02.rs").unwrap();
+        ee.debug_capture = Some(String::new());
+        eval_engine_jit(&mut ee, &deep, "main").unwrap();
+        assert_eq!(ee.debug_capture.take().unwrap().trim(), "DEBUG: U64(100000)");
+
+        // Compiled code can't use fuel, so with fuel everything is evaluated
+        ee.debug_capture = Some(String::new());
+        ee.fuel = Some(1_000_000);
+        eval_engine_jit(&mut ee, &bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.take().unwrap().trim(),
+            "DEBUG: U64(6765)\nDEBUG: I64(5050)"
+        );
+    }
+
+    #[cfg(feature = "llvm")]
+    #[test]
+    fn test_llvm01() {
+        use build;
+        use std::env;
+
+        // Programs lowered to LLVM IR and optimized print what the C backend's builds do
+        let output = env::temp_dir().join("peach_llvm01");
+        for (fname, expect) in &[
+            ("while01.rs", "DEBUG: 10"),
+            ("drop02.rs", "DEBUG: 2\nDEBUG: 1\nDEBUG: 6\nDEBUG: 6\nDEBUG: 9\nDEBUG: 5\nDEBUG: 8\nDEBUG: 4\nDEBUG: 3"),
+            ("lifetime01.rs", "DEBUG: 5\nDEBUG: 6"),
+            ("generic_struct02.rs", "DEBUG: 84"),
+            ("ffi01.rs", "DEBUG: 1"),
+            ("recursion01.rs", "DEBUG: 10\nDEBUG: 0"),
+        ] {
+            let bc = load_to_bc(fname).unwrap();
+            build::build_llvm_executable(&bc, "main", &output).unwrap();
+            let cmd = Command::new(&output).output().unwrap();
+            assert_eq!(String::from_utf8(cmd.stdout).unwrap().trim(), *expect);
+        }
+
+        // The runtime's generator starts from the same seed as the evaluator's
+        let config = EngineConfig {
+            random_seed: Some(7),
+            ..EngineConfig::default()
+        };
+        let bc = load_with_config("random01.rs", config).unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        let evaluated: Vec<String> = ee
+            .debug_capture
+            .unwrap()
+            .lines()
+            .map(|line| {
+                line.trim_start_matches("DEBUG: U64(")
+                    .trim_end_matches(')')
+                    .to_string()
+            })
+            .collect();
+        build::build_llvm_executable(&bc, "main", &output).unwrap();
+        let cmd = Command::new(&output).output().unwrap();
+        let built: Vec<String> = String::from_utf8(cmd.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| line.trim_start_matches("DEBUG: ").to_string())
+            .collect();
+        assert_eq!(built, evaluated);
+
+        let bc = load_to_bc("while01.rs").unwrap();
+        let clang = env::var("CLANG");
+        env::set_var("CLANG", "peach-no-such-compiler");
+        let missing = build::build_llvm_executable(&bc, "main", &output);
+        match clang {
+            Ok(clang) => env::set_var("CLANG", clang),
+            Err(_) => env::remove_var("CLANG"),
+        }
+        match missing {
+            Err(PeachError::Io(message)) => {
+                assert!(message.starts_with("Could not run the C compiler peach-no-such-compiler"))
+            }
+            x => panic!("Expected an io error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_runtime_error01() {
+        let mut bc = BytecodeEngine::new();
+        bc.register_native(
+            "expect_nonzero",
+            &[builtin_type::U64],
+            builtin_type::U64,
+            |args| match args[0] {
+                Value::U64(0) => Err("expected a nonzero value".to_string()),
+                ref x => Ok(x.clone()),
+            },
+        );
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("runtime_error01.rs").unwrap();
+        bc.process_fn("main", 0).unwrap();
+
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        let err = match ee.eval_program(&bc, "main") {
+            Err(PeachError::Runtime(err)) => err,
+            x => panic!("Expected a runtime error, got {:?}", x),
+        };
+        assert_eq!(err.message, "expected a nonzero value");
+        assert_eq!(ee.debug_capture.unwrap().trim(), "DEBUG: U64(4)");
+
+        // The calls that were in progress, innermost first, with where each was in the source
+        let calls: Vec<(&str, usize)> = err
+            .peach_backtrace
+            .iter()
+            .map(|frame| {
+                (
+                    frame.function.as_str(),
+                    frame.location.as_ref().unwrap().line,
+                )
+            })
+            .collect();
+        assert_eq!(calls, vec![("check", 2), ("outer", 6), ("main", 11)]);
+        for frame in &err.peach_backtrace {
+            assert!(frame.offset > 0);
+        }
+        assert!(ee.value_stack.is_empty());
+    }
+
+    #[test]
+    fn test_marshal01() {
+        assert_eq!(u64::from_peach(&42u64.into_peach()), Some(42));
+        assert_eq!(i32::from_peach(&(-7i32).into_peach()), Some(-7));
+        assert_eq!(bool::from_peach(&true.into_peach()), Some(true));
+        assert_eq!(<()>::from_peach(&().into_peach()), Some(()));
+
+        // Untyped integers convert to any type they fit in
+        assert_eq!(u32::from_peach(&Value::UnknownInt(5)), Some(5));
+        assert_eq!(u64::from_peach(&Value::UnknownInt(-5)), None);
+
+        assert_eq!(u64::from_peach(&Value::Bool(true)), None);
+        assert_eq!(bool::from_peach(&Value::U64(1)), None);
+        assert_eq!(u64::from_peach(&Value::U32(1)), None);
+    }
+
+    #[test]
+    fn test_error01() {
+        let mut bc = BytecodeEngine::new();
+        bc.set_project_root("test_files").unwrap();
+        match bc.load_file("no_such_file.rs") {
+            Err(PeachError::Io(_)) => {}
+            x => panic!("Expected an io error, got {:?}", x),
+        }
+
+        let mut bc = BytecodeEngine::new();
+        match bc.load_str("main.rs", "fn main( {") {
+            Err(PeachError::Parse(_)) => {}
+            x => panic!("Expected a parse error, got {:?}", x),
+        }
+
+        let mut bc = BytecodeEngine::new();
+        bc.load_str("main.rs", "fn main() { let x: u64 = true; }")
+            .unwrap();
+        match bc.process_fn("missing", 0) {
+            Err(PeachError::Conversion(ref msg, _)) if msg == "Can not find function missing" => {}
+            x => panic!("Expected a conversion error, got {:?}", x),
+        }
+        assert!(bc.get_fn("main", 0).is_err());
+        assert!(bc.process_fn("main", 0).is_err());
+    }
+
+    #[test]
+    fn test_load_str01() {
+        let mut bc = BytecodeEngine::new();
+        bc.load_str(
+            "playground.rs",
+            "fn main() {\n    let x = 3;\n    println!(\"{}\", x + true);\n}\n",
+        )
+        .unwrap();
+        let err = bc.process_fn("main", 0).err().unwrap();
+        assert!(err.to_string().starts_with("playground.rs:3:22: "));
+
+        let mut bc = BytecodeEngine::new();
+        match bc.load_str("playground.rs", "fn main( {") {
+            Err(PeachError::Parse(ref msg)) if msg.starts_with("playground.rs: ") => {}
+            x => panic!("Expected a parse error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_error_location01() {
+        for (fname, line, column) in &[
+            ("expr_bad01.rs", 2, 25),
+            ("move_bad01.rs", 13, 5),
+            ("borrow_bad02.rs", 4, 5),
+            ("copy_bad01.rs", 6, 8),
+            ("macro_bad01.rs", 8, 5),
+        ] {
+            let err = load_to_bc(fname).err().unwrap();
+            assert_eq!(
+                err.location(),
+                Some(&Location {
+                    file: fname.to_string(),
+                    line: *line,
+                    column: *column,
+                })
+            );
+            assert!(err
+                .to_string()
+                .starts_with(&format!("{}:{}:{}: ", fname, line, column)));
+        }
+    }
+
+    #[test]
+    fn test_errors01() {
+        let err = load_to_bc("errors01.rs").err().unwrap();
+        let locations: Vec<(usize, usize)> = err
+            .errors()
+            .iter()
+            .map(|x| {
+                let location = x.location().unwrap();
+                (location.line, location.column)
+            })
+            .collect();
+        // The use of `a`, whose declaration has an error, isn't reported again
+        assert_eq!(locations, vec![(15, 18), (18, 5), (6, 19)]);
+        assert_eq!(err.to_string().lines().count(), 3);
+
+        // consume is converted for both of its calls, but its error is only reported once
+        let err = load_to_bc("errors02.rs").err().unwrap();
+        assert_eq!(err.errors().len(), 1);
+
+        // Neither are the reads and writes through `x`, whose value has an error
+        let err = load_to_bc("errors03.rs").err().unwrap();
+        let errors: Vec<String> = err.errors().iter().map(|x| x.to_string()).collect();
+        assert_eq!(errors, vec!["errors03.rs:3:5: Could not find variable: m"]);
+    }
+
+    #[test]
+    fn test_warnings01() {
+        let bc = load_to_bc("warnings01.rs").unwrap();
+        let warnings: Vec<(String, usize)> = bc
+            .warnings()
+            .iter()
+            .map(|x| (x.message.clone(), x.location.as_ref().unwrap().line))
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                ("unused variable: unused".to_string(), 18),
+                ("function is never used: unused_fn".to_string(), 15),
+                ("function is never used: unused_method".to_string(), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_imports01() {
+        // Glob imports and `pub use` aren't reported
+        let bc = load_to_bc("imports01.rs").unwrap();
+        let warnings: Vec<(String, usize, usize)> = bc
+            .warnings()
+            .iter()
+            .map(|x| {
+                let location = x.location.as_ref().unwrap();
+                (x.message.clone(), location.line, location.column)
+            })
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                ("unused import: diagonal".to_string(), 25, 13),
+                ("unused import: outline".to_string(), 24, 26),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unreachable01() {
+        use bytecode::{Bytecode, Pipeline, UnreachableCode};
+
+        // Only the first statement of a run that can't be reached is reported
+        let bc = load_to_bc("unreachable01.rs").unwrap();
+        let warnings: Vec<(String, usize)> = bc
+            .warnings()
+            .iter()
+            .map(|x| (x.message.clone(), x.location.as_ref().unwrap().line))
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                ("unreachable statement".to_string(), 11),
+                ("unreachable statement".to_string(), 19),
+                ("unreachable statement".to_string(), 23),
+            ]
+        );
+        check_output(
+            &bc,
+            "unreachable01.rs",
+            "DEBUG: U64(3)\nDEBUG: U64(0)\nDEBUG: U64(0)",
+            "DEBUG: 3\nDEBUG: 0\nDEBUG: 0",
+        );
+
+        // The pass removes them, and what can be reached runs the same
+        let mut bc = BytecodeEngine::new();
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("unreachable01.rs").unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline.add(UnreachableCode);
+        bc.set_pipeline(pipeline);
+        bc.process_fn("main", 0).unwrap();
+        let pick = &bc.get_fn("pick", 0).unwrap().bytecode;
+        assert!(!pick.iter().any(|x| matches!(x, Bytecode::DebugPrint(_))));
+        let first = &bc.get_fn("first", 0).unwrap().bytecode;
+        assert!(!first.iter().any(|x| matches!(x, Bytecode::Add)));
+        check_output(
+            &bc,
+            "unreachable01.rs",
+            "DEBUG: U64(3)\nDEBUG: U64(0)\nDEBUG: U64(0)",
+            "DEBUG: 3\nDEBUG: 0\nDEBUG: 0",
+        );
+    }
+
+    #[test]
+    fn test_capabilities01() {
+        let capabilities = BytecodeEngine::capabilities();
+        assert!(capabilities.supports("structs"));
+        assert!(!capabilities.supports("closures"));
+        assert!(capabilities.has_backend("eval"));
+        assert!(capabilities.has_backend("c"));
+        assert!(capabilities.has_backend("rust"));
+        assert_eq!(capabilities.has_backend("llvm"), cfg!(feature = "llvm"));
+        assert_eq!(capabilities.has_backend("jit"), cfg!(feature = "jit"));
+        assert_eq!(capabilities.format_version("trace-json"), Some(2));
+
+        // Every instruction the bytecode engine produces should be reported
+        for fname in &[
+            "drop02.rs",
+            "ref01.rs",
+            "while01.rs",
+            "if03.rs",
+            "generic01.rs",
+        ] {
+            let bc = load_to_bc(fname).unwrap();
+            for definition in &bc.definitions {
+                if let Definition::Fun(ref fun) = definition {
+                    for code in &fun.bytecode {
+                        assert!(capabilities.opcodes.contains(&code.name()));
+                    }
+                }
+            }
+        }
+
+        let json = capabilities.to_json();
+        assert!(json.starts_with("{\"format_version\":1,\"version\":\"0.1.0\","));
+        assert!(json.contains("{\"name\":\"trace-json\",\"version\":2}"));
+    }
+
+    #[test]
+    fn test_limits01() {
+        use eval::{Halt, LimitError};
+
+        let bc = load_to_bc("struct07.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.limits.max_aggregate_depth = Some(1);
+
+        match ee.eval_program(&bc, "main") {
+            Err(_) => {}
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        assert_eq!(
+            ee.halted,
+            Some(Halt::LimitExceeded(LimitError::AggregateDepth {
+                depth: 2,
+                max: 1
+            }))
+        );
+        assert_eq!(ee.debug_capture.unwrap(), "");
+    }
+
+    #[test]
+    fn test_limits02() {
+        use eval::{Halt, LimitError};
+
+        let bc = load_to_bc("limits01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.limits.max_stack_depth = Some(50);
+
+        match ee.eval_program(&bc, "main") {
+            Err(_) => {}
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        assert_eq!(
+            ee.halted,
+            Some(Halt::LimitExceeded(LimitError::StackDepth {
+                depth: 51,
+                max: 50
+            }))
+        );
+        assert_eq!(ee.debug_capture.unwrap(), "");
+    }
+
+    #[test]
+    fn test_limits03() {
+        use eval::{Halt, LimitError};
+
+        let bc = load_to_bc("recursion02.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.limits.max_call_depth = Some(100);
+
+        match ee.eval_program(&bc, "main") {
+            Err(_) => {}
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        assert_eq!(
+            ee.halted,
+            Some(Halt::LimitExceeded(LimitError::CallDepth {
+                depth: 101,
+                max: 100
+            }))
+        );
+        assert!(ee.value_stack.is_empty());
+    }
+
+    #[test]
+    fn test_limits04() {
+        use eval::{Halt, LimitError};
+
+        // Each call of step leaves its objects behind, which are collected rather than counted towards the limit
+        let bc = load_to_bc("heap01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.limits.max_heap_objects = Some(10);
+        ee.eval_program(&bc, "main").unwrap();
+        assert!(ee.heap_allocations() > 10);
+
+        // Every call of grow keeps its node until it returns
+        let bc = load_to_bc("heap02.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.limits.max_heap_objects = Some(100);
+
+        match ee.eval_program(&bc, "main") {
+            Err(_) => {}
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        assert_eq!(
+            ee.halted,
+            Some(Halt::LimitExceeded(LimitError::HeapObjects {
+                objects: 101,
+                max: 100
+            }))
+        );
+        assert_eq!(ee.debug_capture.unwrap(), "");
+
+        ee.limits.max_heap_objects = None;
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(ee.debug_capture.unwrap(), "DEBUG: U64(500500)\n");
+    }
+
+    #[test]
+    fn test_div_zero01() {
+        let bc = load_to_bc("div_zero01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(e.message(), "attempt to divide by zero"),
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        assert_eq!(ee.debug_capture.unwrap().trim(), "DEBUG: U64(5)");
+        assert!(ee.value_stack.is_empty());
+    }
+
+    #[test]
+    fn test_assert01() {
+        let bc = load_to_bc("assert01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(e.message(), "panicked: x is too big"),
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        assert_eq!(ee.debug_capture.unwrap().trim(), "DEBUG: U64(5)");
+
+        // Compiled programs stop with the same message
+        let output = Command::new(compile::compile_bytecode(&bc, "assert01.rs").unwrap())
+            .output()
+            .expect("failed to execute test");
+        assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "DEBUG: 5");
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap().trim(),
+            "panicked: x is too big"
+        );
+        assert!(!output.status.success());
+    }
+
+    #[test]
+    fn test_overflow01() {
+        use eval::OverflowPolicy;
+
+        let bc = load_to_bc("overflow01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(e.message(), "attempt to subtract with overflow"),
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        assert_eq!(ee.debug_capture.take().unwrap().trim(), "DEBUG: U32(2)");
+
+        ee.overflow = OverflowPolicy::Wrap;
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.unwrap().trim(),
+            "DEBUG: U32(2)\nDEBUG: U32(4294967294)"
+        );
+    }
+
+    #[test]
+    fn test_output01() {
+        use std::cell::RefCell;
+        use std::io::{self, Write};
+        use std::rc::Rc;
+
+        #[derive(Clone, Default)]
+        struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for SharedBuffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let bc = load_to_bc("while01.rs").unwrap();
+        let buffer = SharedBuffer::default();
+        let mut ee = EvalEngine::new();
+        ee.output = Some(Box::new(buffer.clone()));
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "DEBUG: UnknownInt(10)\n"
+        );
+
+        // Failing to write stops the program
+        struct Closed;
+
+        impl Write for Closed {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        ee.output = Some(Box::new(Closed));
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(e.message(), "Could not write output: closed"),
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_clock01() {
+        let bc = load_to_bc("clock01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        let mut now = 750;
+        ee.clock = Some(Box::new(move || {
+            now += 250;
+            now
+        }));
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.take().unwrap().trim(),
+            "DEBUG: U64(1000)\nDEBUG: U64(250)"
+        );
+
+        // Without a clock, the system clock is used by both backends
+        ee.clock = None;
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        let output = ee.debug_capture.unwrap();
+        let millis: Vec<u64> = output
+            .lines()
+            .map(|line| {
+                line.trim_start_matches("DEBUG: U64(")
+                    .trim_end_matches(')')
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+        assert!(millis[0] > 1_500_000_000_000);
+        assert!(millis[1] < 1000);
+
+        let binary = compile::compile_bytecode(&bc, "clock01.rs").unwrap();
+        let output = Command::new(binary).output().unwrap();
+        let output = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(
+            lines[1]
+                .trim_start_matches("DEBUG: ")
+                .parse::<u64>()
+                .unwrap()
+                < 1000
+        );
+
+        match load_with_config("clock01.rs", EngineConfig::sandboxed()) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "now_millis is not allowed by this engine's configuration"
+            ),
+            Ok(_) => panic!("Expected clock01.rs to fail"),
+        }
+    }
+
+    #[test]
+    fn test_random01() {
+        let config = EngineConfig {
+            random_seed: Some(7),
+            ..EngineConfig::default()
+        };
+        let bc = load_with_config("random01.rs", config).unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        let first = ee.debug_capture.take().unwrap();
+
+        // Each run starts again from the seed
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(ee.debug_capture.take().unwrap(), first);
+
+        let numbers: Vec<u64> = first
+            .lines()
+            .map(|line| {
+                line.trim_start_matches("DEBUG: U64(")
+                    .trim_end_matches(')')
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(numbers.len(), 5);
+        assert!(numbers.iter().all(|x| *x < 100));
+
+        // The compiled program gives the same numbers
+        let binary = compile::compile_bytecode(&bc, "random01.rs").unwrap();
+        let output = Command::new(binary).output().unwrap();
+        let compiled: Vec<u64> = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| line.trim_start_matches("DEBUG: ").parse().unwrap())
+            .collect();
+        assert_eq!(compiled, numbers);
+
+        let bc = load_to_bc("random02.rs").unwrap();
+        match EvalEngine::new().eval_program(&bc, "main") {
+            Err(e) => assert_eq!(e.message(), "random_below needs a bound greater than 0"),
+            Ok(_) => panic!("Expected random02.rs to fail"),
+        }
+    }
+
+    #[test]
+    fn test_args01() {
+        use std::collections::HashMap;
+
+        let bc = load_to_bc("args01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.args = vec!["2".into(), "3".into()];
+        let mut env = HashMap::new();
+        env.insert("PEACH_ARGS01_START".to_string(), "10".to_string());
+        ee.env = Some(env);
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.take().unwrap().trim(),
+            "DEBUG: U64(2)\nDEBUG: U64(15)"
+        );
+
+        ee.args = vec!["2".into(), "three".into()];
+        ee.debug_capture = Some(String::new());
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Expected a u64 but read \"three\" from argument 1"
+            ),
+            Ok(_) => panic!("Expected a bad argument to fail"),
+        }
+
+        ee.env = Some(HashMap::new());
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(
+                e.message(),
+                "The environment variable PEACH_ARGS01_START is not set"
+            ),
+            Ok(_) => panic!("Expected a missing environment variable to fail"),
+        }
+
+        let binary = compile::compile_bytecode(&bc, "args01.rs").unwrap();
+        let output = Command::new(binary)
+            .args(["2", "3"])
+            .env("PEACH_ARGS01_START", "10")
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap().trim(),
+            "DEBUG: 2\nDEBUG: 15"
+        );
+
+        match load_with_config("args01.rs", EngineConfig::sandboxed()) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "arg_count is not allowed by this engine's configuration"
+            ),
+            Ok(_) => panic!("Expected args01.rs to fail"),
+        }
+    }
+
+    #[test]
+    fn test_exit01() {
+        use eval::Halt;
+
+        // exit stops the program, which exits with the status it's given
+        let bc = load_to_bc("exit01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        assert_eq!(ee.eval_program_exit_code(&bc, "main").unwrap(), 3);
+        assert_eq!(ee.debug_capture.take().unwrap().trim(), "DEBUG: U64(1)");
+        assert_eq!(ee.halted, Some(Halt::Exit(3)));
+        assert!(ee.value_stack.is_empty());
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(e.message(), "the program exited with status 3"),
+            Ok(_) => panic!("Expected eval_program to stop at the exit"),
+        }
+
+        let binary = compile::compile_bytecode(&bc, "exit01.rs").unwrap();
+        let output = Command::new(binary).output().unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "DEBUG: 1");
+
+        // Built programs exit with what main returns, or 0 if it returns nothing
+        for (fname, status) in &[("exit02.rs", 42), ("while01.rs", 0)] {
+            let bc = load_to_bc(fname).unwrap();
+            let binary = compile::compile_bytecode(&bc, fname).unwrap();
+            let output = Command::new(binary).output().unwrap();
+            assert_eq!(output.status.code(), Some(*status), "{}", fname);
+        }
+        let bc = load_to_bc("exit02.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        assert_eq!(ee.eval_program_exit_code(&bc, "main").unwrap(), 42);
+
+        // Otherwise the status is what main returns
+        let exit_code = |src: &str| {
+            let mut bc = BytecodeEngine::new();
+            bc.load_str("main.rs", src).unwrap();
+            bc.process_fn("main", 0).unwrap();
+            EvalEngine::new()
+                .eval_program_exit_code(&bc, "main")
+                .map_err(|e| e.message().to_string())
+        };
+        assert_eq!(exit_code("fn main() {}"), Ok(0));
+        assert_eq!(exit_code("fn main() -> u64 { 40 + 2 }"), Ok(42));
+        assert_eq!(exit_code("fn main() -> i32 { 0 - 1 }"), Ok(-1));
+        assert_eq!(
+            exit_code("fn main() -> u64 { 5000000000u64 }"),
+            Err(
+                "Function main returned 5000000000, which is too large for an exit status"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            exit_code("fn main() -> bool { true }"),
+            Err("Function main has to return nothing or an integer to give an exit status, not bool".to_string())
+        );
+    }
+
+    #[test]
+    fn test_file01() {
+        use std::fs;
+
+        let root = ::std::env::temp_dir().join(format!("peach_file01_{}", ::std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("counter.txt"), "41\n").unwrap();
+        let config = EngineConfig {
+            file_root: Some(root.clone()),
+            ..EngineConfig::default()
+        };
+
+        let bc = load_with_config("file01.rs", config.clone()).unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(ee.debug_capture.take().unwrap().trim(), "DEBUG: U64(41)");
+        assert_eq!(
+            fs::read_to_string(root.join("counter.txt")).unwrap(),
+            "42\n"
+        );
+
+        let binary = compile::compile_bytecode(&bc, "file01.rs").unwrap();
+        let output = Command::new(binary).output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap().trim(),
+            "DEBUG: 42"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("counter.txt")).unwrap(),
+            "43\n"
+        );
+
+        fs::write(root.join("counter.txt"), "many\n").unwrap();
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Expected a u64 but read \"many\" from counter.txt"
+            ),
+            Ok(_) => panic!("Expected reading a file without a number to fail"),
+        }
+        fs::remove_dir_all(&root).unwrap();
+
+        match load_with_config("file02.rs", config) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "\"../counter.txt\" is not a path inside the file root"
+            ),
+            Ok(_) => panic!("Expected file02.rs to fail"),
+        }
+        match load_with_config("file01.rs", EngineConfig::default()) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "read_file_u64 is not allowed by this engine's configuration"
+            ),
+            Ok(_) => panic!("Expected file01.rs to fail without a file root"),
+        }
+    }
+
+    #[test]
+    fn test_debugger01() {
+        use debugger::{Breakpoint, DebugCommand, Debugger};
+        use eval::Halt;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let bc = load_to_bc("debug01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+
+        // Line breakpoints pause once each time the line is reached, with the current frame's variables
+        let pauses = Rc::new(RefCell::new(vec![]));
+        let recorded = pauses.clone();
+        let mut debugger = Debugger::new(move |pause| {
+            recorded.borrow_mut().push((
+                pause.function.clone(),
+                pause.location.as_ref().map(|location| location.line),
+                pause.depth,
+                pause.locals.clone(),
+            ));
+            DebugCommand::Continue
+        });
+        debugger.break_at_line("debug01.rs", 3);
+        ee.debugger = Some(debugger);
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(ee.debug_capture.take().unwrap().trim(), "DEBUG: U64(3)");
+        assert_eq!(
+            format!("{:?}", pauses.borrow()),
+            "[(\"add\", Some(3), 1, [(\"x\", U64(1)), (\"y\", U64(2)), (\"sum\", U64(3))])]"
+        );
+
+        // Stepping pauses before each instruction, until the handler stops evaluation
+        let offsets = Rc::new(RefCell::new(vec![]));
+        let recorded = offsets.clone();
+        let mut debugger = Debugger::new(move |pause| {
+            let mut offsets = recorded.borrow_mut();
+            offsets.push((pause.function.clone(), pause.offset));
+            if offsets.len() < 3 {
+                DebugCommand::Step
+            } else {
+                DebugCommand::Stop
+            }
+        });
+        debugger.break_at_offset("add", 0);
+        assert_eq!(
+            debugger.breakpoints(),
+            &[Breakpoint::Offset {
+                function: "add".to_string(),
+                offset: 0,
+            }][..]
+        );
+        ee.debugger = Some(debugger);
+        ee.debug_capture = Some(String::new());
+        let stack_len = ee.value_stack.len();
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(e.message(), "evaluation was stopped by the debugger"),
+            Ok(_) => panic!("Expected the debugger to stop evaluation"),
+        }
+        assert_eq!(ee.halted, Some(Halt::Stopped));
+        assert_eq!(ee.debug_capture.take().unwrap(), "");
+        assert_eq!(
+            *offsets.borrow(),
+            vec![
+                ("add".to_string(), 0),
+                ("add".to_string(), 1),
+                ("add".to_string(), 2),
+            ]
+        );
+        assert_eq!(ee.value_stack.len(), stack_len);
+    }
+
+    #[test]
+    fn test_dap01() {
+        use lsp::{read_message, write_message};
+        use std::cell::RefCell;
+        use std::io::{self, Cursor, Write};
+        use std::path::Path;
+        use std::rc::Rc;
+        use DebugAdapter;
+
+        #[derive(Clone, Default)]
+        struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for SharedBuffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let path = Path::new("test_files/debug01.rs").canonicalize().unwrap();
+        let path = path.display();
+        let requests = vec![
+            ("initialize", "{\"adapterID\":\"peach\"}".to_string()),
+            ("launch", format!("{{\"program\":\"{}\"}}", path)),
+            (
+                "setBreakpoints",
+                format!(
+                    "{{\"source\":{{\"path\":\"{}\"}},\"breakpoints\":[{{\"line\":3}}]}}",
+                    path
+                ),
+            ),
+            ("configurationDone", "{}".to_string()),
+            ("stackTrace", "{\"threadId\":1}".to_string()),
+            ("variables", "{\"variablesReference\":2}".to_string()),
+            ("stepOut", "{\"threadId\":1}".to_string()),
+            ("next", "{\"threadId\":1}".to_string()),
+            ("stackTrace", "{\"threadId\":1}".to_string()),
+            ("variables", "{\"variablesReference\":1}".to_string()),
+            ("continue", "{\"threadId\":1}".to_string()),
+            ("disconnect", "{}".to_string()),
+        ];
+        let mut input = vec![];
+        for (seq, (command, arguments)) in requests.into_iter().enumerate() {
+            let request = format!(
+                "{{\"seq\":{},\"type\":\"request\",\"command\":\"{}\",\"arguments\":{}}}",
+                seq + 1,
+                command,
+                arguments
+            );
+            write_message(&mut input, &request).unwrap();
+        }
+
+        let output = SharedBuffer::default();
+        DebugAdapter::new(Cursor::new(input), output.clone())
+            .serve()
+            .unwrap();
+        let mut output = Cursor::new(output.0.borrow().clone());
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        let frame = |id: usize, name: &str, line: usize| {
+            format!(
+                "{{\"id\":{},\"name\":\"{}\",\"line\":{},\"column\":5,\"source\":{{\"name\":\"debug01.rs\",\"path\":\"{}\"}}}}",
+                id, name, line, path
+            )
+        };
+        let body = |message: &String| message[message.find("\"body\":").unwrap() + 7..].to_string();
+
+        // The program runs once it's configured, and stops at the breakpoint in add, called from main
+        assert_eq!(
+            messages[1],
+            "{\"seq\":2,\"type\":\"event\",\"event\":\"initialized\",\"body\":{}}"
+        );
+        assert!(messages[5].contains("\"event\":\"stopped\",\"body\":{\"reason\":\"breakpoint\""));
+        assert_eq!(
+            body(&messages[6]),
+            format!(
+                "{{\"stackFrames\":[{},{}],\"totalFrames\":2}}}}",
+                frame(1, "add", 3),
+                frame(0, "main", 8)
+            )
+        );
+        assert_eq!(
+            body(&messages[7]),
+            "{\"variables\":[{\"name\":\"x\",\"value\":\"1\",\"variablesReference\":0},\
+             {\"name\":\"y\",\"value\":\"2\",\"variablesReference\":0},\
+             {\"name\":\"sum\",\"value\":\"3\",\"variablesReference\":0}]}}"
+        );
+
+        // Stepping out returns to the call in main, and stepping over it goes on to the next line
+        assert!(messages[9].contains("\"reason\":\"step\""));
+        assert!(messages[11].contains("\"reason\":\"step\""));
+        assert_eq!(
+            body(&messages[12]),
+            format!(
+                "{{\"stackFrames\":[{}],\"totalFrames\":1}}}}",
+                frame(0, "main", 9)
+            )
+        );
+        assert!(body(&messages[13]).contains("{\"name\":\"b\",\"value\":\"3\""));
+
+        // What the program prints is sent as output, then its exit
+        assert_eq!(
+            messages[messages.len() - 3..],
+            [
+                "{\"seq\":19,\"type\":\"event\",\"event\":\"exited\",\"body\":{\"exitCode\":0}}",
+                "{\"seq\":20,\"type\":\"event\",\"event\":\"terminated\",\"body\":{}}",
+                "{\"seq\":21,\"type\":\"response\",\"request_seq\":12,\"command\":\"disconnect\",\"success\":true,\"body\":{}}",
+            ]
+        );
+        let printed: String = messages
+            .iter()
+            .filter(|message| message.contains("\"category\":\"stdout\""))
+            .map(|message| {
+                let output = &message[message.find("\"output\":\"").unwrap() + 10..];
+                output[..output.find('"').unwrap()].to_string()
+            })
+            .collect();
+        assert_eq!(printed, "DEBUG: U64(3)\\n");
+    }
+
+    #[test]
+    fn test_observer01() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let bc = load_to_bc("debug01.rs").unwrap();
+        let seen = Rc::new(RefCell::new(vec![]));
+        let recorded = seen.clone();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.observer = Some(Box::new(move |frame, code| {
+            recorded.borrow_mut().push((
+                frame.definition_id,
+                frame.offset,
+                frame.depth,
+                frame.locals.len(),
+                code.name(),
+            ));
+        }));
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(ee.debug_capture.unwrap().trim(), "DEBUG: U64(3)");
+
+        // The observer sees the same instructions as a trace does
+        let trace = EvalEngine::new().trace_program(&bc, "main", 1000);
+        let seen = seen.borrow();
+        assert_eq!(seen.len(), trace.steps.len());
+        for (seen, step) in seen.iter().zip(&trace.steps) {
+            let (definition_id, offset, depth, num_locals, name) = *seen;
+            assert_eq!(
+                bc.definition_name(definition_id.unwrap()).unwrap(),
+                step.function
+            );
+            assert_eq!(offset, step.offset);
+            assert_eq!(depth, if step.function == "add" { 1 } else { 0 });
+            assert_eq!(num_locals, step.locals.len());
+            assert_eq!(name, step.instruction.name());
+        }
+    }
+
+    #[test]
+    fn test_profile01() {
+        let bc = load_to_bc("debug01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        let profile = ee.profile_program(&bc, "main");
+        assert_eq!(ee.halted, None);
+        assert_eq!(ee.debug_capture.unwrap().trim(), "DEBUG: U64(3)");
+
+        // The counts match the instructions a trace records
+        let trace = EvalEngine::new().trace_program(&bc, "main", 1000);
+        assert_eq!(profile.total_instructions(), trace.steps.len() as u64);
+        for name in &["main", "add"] {
+            let function = profile.function(name).unwrap();
+            assert_eq!(function.calls, 1);
+            let steps = trace.steps.iter().filter(|x| x.function == *name).count();
+            assert_eq!(function.instructions, steps as u64);
+        }
+        assert_eq!(profile.opcode_count("Call"), 1);
+        assert_eq!(profile.opcode_count("Lt"), 0);
+        assert!(profile
+            .opcodes
+            .windows(2)
+            .all(|pair| pair[0].1 >= pair[1].1));
+        assert!(profile
+            .report()
+            .contains(&format!("{} instructions", profile.total_instructions())));
+    }
+
+    #[test]
+    fn test_flamegraph01() {
+        let bc = load_to_bc("debug01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        let profile = ee.profile_program(&bc, "main");
+        let add = profile.function("add").unwrap().instructions;
+        let main = profile.function("main").unwrap().instructions;
+        assert_eq!(
+            profile.stacks,
+            vec![
+                (vec!["main".to_string()], main),
+                (vec!["main".to_string(), "add".to_string()], add),
+            ]
+        );
+        assert_eq!(
+            profile.collapsed(),
+            format!("main {}\nmain;add {}\n", main, add)
+        );
+
+        // Each depth of a recursive call is a stack of its own, and together the stacks count every instruction
+        let bc = load_to_bc("jit01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        let profile = ee.profile_program(&bc, "main");
+        let fib_stacks: Vec<&(Vec<String>, u64)> = profile
+            .stacks
+            .iter()
+            .filter(|(stack, _)| stack.len() > 1 && stack[1] == "fib")
+            .collect();
+        assert_eq!(profile.stacks.len(), fib_stacks.len() + 2);
+        assert_eq!(fib_stacks.last().unwrap().0.len(), 21);
+        let fib: u64 = fib_stacks.iter().map(|(_, count)| count).sum();
+        assert_eq!(fib, profile.function("fib").unwrap().instructions);
+        let total: u64 = profile.stacks.iter().map(|(_, count)| count).sum();
+        assert_eq!(total, profile.total_instructions());
+    }
+
+    #[test]
+    fn test_coverage01() {
+        let bc = load_to_bc("coverage01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        let coverage = ee.cover_program(&bc, "main");
+        assert_eq!(ee.halted, None);
+        assert_eq!(ee.debug_capture.unwrap().trim(), "DEBUG: U64(3)");
+
+        // Lines count how often they ran, and the branch that was never taken is marked in the annotated source
+        let file = coverage.file("coverage01.rs").unwrap();
+        assert_eq!(
+            (file.hits(2), file.hits(5), file.hits(16), file.hits(19)),
+            (Some(3), Some(0), Some(4), None)
+        );
+        assert_eq!((file.lines_hit(), file.lines.len()), (10, 12));
+        let annotated = [
+            "coverage01.rs: 10 of 12 lines ran",
+            "        3 | fn classify(x: u64) -> u64 {",
+            "        3 |     if x < 10 {",
+            "        3 |         1",
+            "          |     } else {",
+            "    ##### |         2",
+            "          |     }",
+        ];
+        assert!(coverage.annotate().starts_with(&annotated.join("\n")));
+
+        // Functions nothing used were never converted, and are reported as never called
+        let lcov = coverage.lcov();
+        assert!(lcov.starts_with("TN:\nSF:/"));
+        assert!(lcov.contains(
+            "/coverage01.rs\nFN:1,classify\nFN:9,unused\nFN:13,main\nFNDA:3,classify\nFNDA:0,unused\nFNDA:1,main\n\
+             FNF:3\nFNH:2\nDA:1,3\n"
+        ));
+        assert!(lcov.contains("DA:9,0\n"));
+        assert!(lcov.ends_with("LF:12\nLH:10\nend_of_record\n"));
+
+        // The tests' coverage adds up what each of them ran
+        let config = EngineConfig {
+            tests: true,
+            ..EngineConfig::default()
+        };
+        let mut bc = load_with_config("tests01.rs", config).unwrap();
+        let (report, coverage) = bc.run_tests_with_coverage().unwrap();
+        assert_eq!((report.passed(), report.failed()), (1, 2));
+        let file = coverage.file("tests01.rs").unwrap();
+        let calls: Vec<(&str, u64)> = file
+            .functions
+            .iter()
+            .map(|function| (function.name.as_str(), function.calls))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("double", 4),
+                ("main", 0),
+                ("doubles", 1),
+                ("tests::expected", 1),
+                ("tests::doubles_odd", 1),
+                ("tests::stops", 1),
+            ]
+        );
+        assert_eq!(
+            (file.hits(2), file.hits(6), file.hits(31)),
+            (Some(4), Some(0), Some(1))
+        );
+    }
+
+    #[test]
+    fn test_bench01() {
+        use bench::BenchLength;
+        use std::time::Duration;
+
+        let bc = load_to_bc("bench01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        let profile = ee.profile_program(&bc, "work");
+        let allocations = ee.heap_allocations();
+        assert_eq!(allocations, 20);
+
+        // The counts are totals over the iterations, which each count the same as a single run
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        let benchmark = ee
+            .bench_fn(&bc, "work", BenchLength::Iterations(3))
+            .unwrap();
+        assert_eq!(benchmark.iterations, 3);
+        assert_eq!(benchmark.instructions, 3 * profile.total_instructions());
+        assert_eq!(benchmark.allocations, 3 * allocations);
+        assert_eq!(
+            benchmark.instructions_per_iteration(),
+            profile.total_instructions()
+        );
+        assert_eq!(ee.debug_capture.unwrap().lines().count(), 30);
+        assert!(benchmark
+            .to_string()
+            .starts_with("bench work: 3 iterations in "));
+
+        // A function always runs at least once, however short the duration
+        let benchmark = EvalEngine::new()
+            .bench_fn(&bc, "main", BenchLength::Duration(Duration::from_secs(0)))
+            .unwrap();
+        assert_eq!(benchmark.iterations, 1);
+
+        let mut ee = EvalEngine::new();
+        match ee.bench_fn(&bc, "add", BenchLength::Iterations(3)) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Function add takes arguments, so it can't be benchmarked"
+            ),
+            Ok(benchmark) => panic!("Expected benchmarking to fail, got {:?}", benchmark),
+        }
+        assert!(ee
+            .bench_fn(&bc, "work", BenchLength::Iterations(0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_fuse01() {
+        // Fused programs give the same output from both backends, while dispatching fewer instructions
+        let mut bc = load_to_bc("while01.rs").unwrap();
+        let unfused = EvalEngine::new().profile_program(&bc, "main");
+        bc.fuse_instructions();
+        check_output(&bc, "while01.rs", "DEBUG: UnknownInt(10)", "DEBUG: 10");
+        let fused = EvalEngine::new().profile_program(&bc, "main");
+        assert!(fused.total_instructions() < unfused.total_instructions());
+        assert_eq!(fused.opcode_count("LtVarConst"), 9);
+        assert_eq!(fused.opcode_count("AddVarConst"), 8);
+        assert_eq!(fused.opcode_count("AssignVar"), 8);
+        assert_eq!(fused.opcode_count("Add"), 0);
+
+        // Fusing again changes nothing
+        let len = bc.get_fn("main", 0).unwrap().bytecode.len();
+        bc.fuse_instructions();
+        assert_eq!(bc.get_fn("main", 0).unwrap().bytecode.len(), len);
+
+        // Jumps over and into fused code still land in the right place
+        let mut bc = load_to_bc("recursion01.rs").unwrap();
+        bc.fuse_instructions();
+        check_output(
+            &bc,
+            "recursion01.rs",
+            "DEBUG: U64(10)\nDEBUG: Bool(false)",
+            "DEBUG: 10\nDEBUG: 0",
+        );
+    }
+
+    #[test]
+    fn test_cfg01() {
+        use bytecode::{Bytecode, Cfg};
+
+        // An if/else splits into the condition, both branches, and the block they join in
+        let bc = load_to_bc("recursion01.rs").unwrap();
+        let cfg = Cfg::new(bc.get_fn("countdown", 0).unwrap());
+        let successors: Vec<Vec<usize>> = cfg.blocks.iter().map(|x| x.successors.clone()).collect();
+        assert_eq!(successors, vec![vec![1, 2], vec![3], vec![3], vec![]]);
+        assert_eq!(
+            cfg.predecessors(),
+            vec![vec![], vec![0], vec![0], vec![1, 2]]
+        );
+        assert_eq!(cfg.reverse_postorder(), vec![0, 2, 1, 3]);
+
+        // A while loop splits into the condition, which the body jumps back to, and the code after it
+        let bc = load_to_bc("while01.rs").unwrap();
+        let cfg = Cfg::new(bc.get_fn("main", 0).unwrap());
+        let starts: Vec<usize> = cfg.blocks.iter().map(|x| x.start).collect();
+        assert_eq!(starts, vec![0, 2, 7, 13]);
+        assert_eq!(cfg.predecessors()[1], vec![0, 2]);
+        assert_eq!(cfg.reverse_postorder(), vec![0, 1, 3, 2]);
+
+        // Laying the blocks out again gives back the bytecode they were built from, fused or not
+        for fname in &["recursion01.rs", "while01.rs", "if05.rs", "drop02.rs"] {
+            let mut bc = load_to_bc(fname).unwrap();
+            for _ in 0..2 {
+                for definition in &bc.definitions {
+                    if let Definition::Fun(ref fun) = definition {
+                        assert_eq!(
+                            format!("{:?}", Cfg::new(fun).linearize()),
+                            format!("{:?}", fun.bytecode),
+                            "{}",
+                            fname
+                        );
+                    }
+                }
+                bc.fuse_instructions();
+            }
+        }
+
+        // Jumps still land in the right place after instructions are added to the blocks.  BeginWhile does nothing
+        // when it's evaluated.
+        let mut bc = load_to_bc("recursion01.rs").unwrap();
+        for definition in &mut bc.definitions {
+            if let Definition::Fun(ref mut fun) = definition {
+                let mut cfg = Cfg::new(fun);
+                for block in &mut cfg.blocks {
+                    block.code.insert(0, Bytecode::BeginWhile);
+                }
+                fun.bytecode = cfg.linearize();
+            }
+        }
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.unwrap().trim(),
+            "DEBUG: U64(10)\nDEBUG: Bool(false)"
+        );
+    }
+
+    #[test]
+    fn test_labels01() {
+        use bytecode::{Bytecode, Labeled, LabeledCode};
+
+        // Labeling a function and finishing it again gives back the same bytecode and statements
+        for fname in &["recursion01.rs", "while01.rs", "if05.rs", "drop02.rs"] {
+            let mut bc = load_to_bc(fname).unwrap();
+            for definition in &mut bc.definitions {
+                if let Definition::Fun(ref mut fun) = definition {
+                    let bytecode = format!("{:?}", fun.bytecode);
+                    let stmt_locations = fun.stmt_locations.clone();
+                    Labeled::from_fun(fun).apply(fun);
+                    assert_eq!(format!("{:?}", fun.bytecode), bytecode, "{}", fname);
+                    assert_eq!(fun.stmt_locations, stmt_locations);
+                }
+            }
+        }
+
+        // Instructions added before every label move the jumps and statements after them.  BeginWhile does nothing
+        // when it's evaluated.
+        let mut bc = load_to_bc("while01.rs").unwrap();
+        for definition in &mut bc.definitions {
+            if let Definition::Fun(ref mut fun) = definition {
+                let mut labeled = Labeled::from_fun(fun);
+                labeled.code = labeled
+                    .code
+                    .into_iter()
+                    .flat_map(|code| match code {
+                        LabeledCode::Place(_) => {
+                            vec![LabeledCode::Code(Bytecode::BeginWhile), code]
+                        }
+                        code => vec![code],
+                    })
+                    .collect();
+                labeled.apply(fun);
+            }
+        }
+        let main = bc.get_fn("main", 0).unwrap();
+        let print = main
+            .bytecode
+            .iter()
+            .position(|x| matches!(x, Bytecode::DebugPrint(_)))
+            .unwrap();
+        assert_eq!(main.location_of(print).unwrap().line, 8);
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(ee.debug_capture.unwrap().trim(), "DEBUG: UnknownInt(10)");
+
+        // Labels can be placed before or after the jumps to them
+        let mut labeled = Labeled::new();
+        let top = labeled.new_label();
+        let done = labeled.new_label();
+        labeled.place(top);
+        labeled.push(Bytecode::BeginWhile);
+        labeled.push(Bytecode::PushBool(false));
+        labeled.jump(Bytecode::WhileCond(0), done);
+        labeled.jump(Bytecode::EndWhile(0), top);
+        labeled.place(done);
+        labeled.push(Bytecode::ReturnVoid);
+        let (bytecode, positions) = labeled.finish();
+        assert_eq!(
+            format!("{:?}", bytecode),
+            "[BeginWhile, PushBool(false), WhileCond(1), EndWhile(3), ReturnVoid]"
+        );
+        assert_eq!(positions, vec![Some(0), Some(4)]);
+    }
+
+    #[test]
+    fn test_typed_listing01() {
+        // Each instruction shows the stack's types before and after it, and an if's arms both start from its stack
+        let bc = load_to_bc("if05.rs").unwrap();
+        let listing = bc.typed_listing(bc.get_fn("main", 0).unwrap());
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 14);
+        assert_eq!(lines[0], "   0: PushUnknownInt(3)  [] -> [{integer}]");
+        assert_eq!(
+            lines[4],
+            "   4: Lt                 [{integer}, {integer}] -> [bool]"
+        );
+        assert_eq!(lines[7], "   7: Else(2, 1)         [{integer}] -> []");
+        assert_eq!(
+            lines[9],
+            "   9: EndIf(1)           [{integer}] -> [{integer}]"
+        );
+
+        // Every processed function is listed under its qualified name
+        let bc = load_to_bc("impl01.rs").unwrap();
+        let listings = bc.typed_listings();
+        assert!(listings.contains("fn main:\n   0: Call("));
+        assert!(listings.contains("fn Foo::bar:\n   0: PushUnknownInt(7)  [] -> [{integer}]\n"));
+    }
+
+    #[test]
+    fn test_disassemble01() {
+        // Jumps are drawn as arrows, with nested ones inside, and variables that share a name are told apart
+        let bc = load_to_bc("disassemble01.rs").unwrap();
+        let disassembly = bc.disassemble("main").unwrap();
+        let lines: Vec<&str> = disassembly.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert_eq!(lines[1], "            1: VarDecl x#0");
+        assert_eq!(lines[2], "+------>    2: BeginWhile");
+        assert_eq!(lines[6], "| +-----    6: WhileCond 29");
+        assert_eq!(lines[10], "| |   +-   10: If 16");
+        assert_eq!(lines[12], "| |   |    12: Call double");
+        assert_eq!(lines[15], "| | +-|-   15: Else 22");
+        assert_eq!(lines[16], "| | | +>   16: Var x#0");
+        assert_eq!(lines[19], "| | |      19: VarDecl x#1");
+        assert_eq!(lines[21], "| | |      21: DebugPrint u64");
+        assert_eq!(lines[22], "| | +-->   22: EndIf");
+        assert_eq!(lines[28], "+-|-----   28: EndWhile 2");
+        assert_eq!(lines[29], "  +---->   29: Var x#0");
+        assert_eq!(lines[31], "           31: ReturnVoid");
+
+        // Without jumps there's nothing to the left of the indices
+        let bc = load_to_bc("fn01.rs").unwrap();
+        assert_eq!(
+            bc.disassemble("main").unwrap(),
+            "   0: Call bar\n   1: DebugPrint u64\n   2: ReturnVoid\n"
+        );
+        assert!(bc.disassemble("baz").is_err());
+    }
+
+    #[test]
+    fn test_snapshot01() {
+        use golden::check_golden;
+        use std::path::Path;
+
+        // Each function's lowering is locked in by its golden file, which running with PEACH_BLESS=1 writes
+        let snapshots = vec![
+            ("debug01.rs", "add"),
+            ("disassemble01.rs", "main"),
+            ("fn01.rs", "main"),
+            ("while01.rs", "main"),
+        ];
+        let mut mismatches = vec![];
+        for (fname, fn_name) in snapshots {
+            let mut bc = load_to_bc(fname).unwrap();
+            let snapshot = bc.snapshot_fn(fn_name).unwrap();
+            let golden = format!(
+                "test_files/snapshots/{}.{}.txt",
+                fname.trim_end_matches(".rs"),
+                fn_name
+            );
+            if let Err(mismatch) = check_golden(&snapshot, Path::new(&golden)) {
+                mismatches.push(mismatch.to_string());
+            }
+        }
+        assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+    }
+
+    #[test]
+    fn test_golden01() {
+        use golden::{compare_golden, GoldenMismatch};
+        use std::env;
+        use std::fs;
+
+        let dir = env::temp_dir().join("peach_golden01");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("add.txt");
+
+        // A golden file that doesn't exist yet is a mismatch, until it's blessed
+        let snapshot = load_to_bc("debug01.rs")
+            .unwrap()
+            .snapshot_fn("add")
+            .unwrap();
+        assert!(snapshot.starts_with("fn add(x: u64, y: u64) -> u64\n   0: "));
+        let missing = compare_golden(&snapshot, &path, false).unwrap_err();
+        assert_eq!(missing.expected, None);
+        assert!(missing.to_string().contains("PEACH_BLESS=1 to write it"));
+        compare_golden(&snapshot, &path, true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), snapshot);
+        compare_golden(&snapshot, &path, false).unwrap();
+
+        // Line endings aren't compared, but everything else is, and the first line that differs is pointed out
+        fs::write(&path, snapshot.replace('\n', "\r\n")).unwrap();
+        compare_golden(&snapshot, &path, false).unwrap();
+        let changed = snapshot.replace("Add", "Sub");
+        let mismatch = compare_golden(&changed, &path, false).unwrap_err();
+        assert_eq!(
+            mismatch,
+            GoldenMismatch {
+                path: path.clone(),
+                expected: Some(snapshot.clone()),
+                actual: changed.clone(),
+            }
+        );
+        let message = mismatch.to_string();
+        assert!(message.contains("add.txt doesn't match at line 4."));
+        assert!(message.contains("\nexpected:    2: Add\nactual:      2: Sub\n"));
+    }
+
+    #[test]
+    fn test_image01() {
+        // Loading the same program gives the same image, whatever order the engine's maps are in
+        let bc = load_to_bc("image01.rs").unwrap();
+        let image = bc.to_image().unwrap();
+        assert_eq!(image, load_to_bc("image01.rs").unwrap().to_image().unwrap());
+
+        let path = ::std::env::temp_dir().join(format!("peach_image01_{}", ::std::process::id()));
+        bc.save_image(path.to_str().unwrap()).unwrap();
+        assert_eq!(::std::fs::read(&path).unwrap(), image);
+        ::std::fs::remove_file(&path).unwrap();
+
+        // Natives are closures in the host, so programs that call them can't be saved
+        match load_with_natives("native01.rs").unwrap().to_image() {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Function main uses a native function, which can't be saved in an image"
+            ),
+            Ok(_) => panic!("Expected native01.rs to not be saved"),
+        }
+    }
+
+    #[test]
+    fn test_load_image01() {
+        // A loaded image runs like the program it was saved from, and saves to the same image again
+        let image = load_to_bc("image01.rs").unwrap().to_image().unwrap();
+        let mut bc = BytecodeEngine::from_image(&image).unwrap();
+        check_output(
+            &bc,
+            "image01.rs",
+            "DEBUG: U64(1)\nDEBUG: UnknownInt(2)\nDEBUG: U64(3)",
+            "DEBUG: 1\nDEBUG: 2\nDEBUG: 3",
+        );
+        assert_eq!(bc.to_image().unwrap(), image);
+
+        // Functions that weren't processed aren't in the image
+        match bc.process_fn("never_called", 0) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "The fn never_called was left out of the image it was loaded from"
+            ),
+            Ok(_) => panic!("Expected never_called to be left out"),
+        }
+
+        match BytecodeEngine::from_image(&image[..image.len() - 1]) {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                format!(
+                    "parse error: Not a valid image: it ends early at byte {}",
+                    image.len() - 1
+                )
+            ),
+            Ok(_) => panic!("Expected a truncated image to not load"),
+        }
+
+        // Bytecode from an image is verified before it can be run
+        let mut bc = load_to_bc("image01.rs").unwrap();
+        let (main_id, _) = bc.get_defn("main", 0).unwrap();
+        if let Definition::Fun(ref mut fun) = bc.definitions[main_id] {
+            for code in fun.bytecode.iter_mut() {
+                if let Bytecode::EndWhile(ref mut offset) = code {
+                    *offset += 100;
+                }
+            }
+        }
+        match BytecodeEngine::from_image(&bc.to_image().unwrap()) {
+            Err(PeachError::Verify(e)) => assert_eq!(e.function, "main"),
+            Err(e) => panic!("Expected a verify error, got {}", e),
+            Ok(_) => panic!("Expected the image to not verify"),
+        }
+    }
+
+    #[test]
+    fn test_load_image02() {
+        use bytecode::VerifyProblem;
+
+        // An image doesn't bring the policy it was saved with, so it can't reach files the host didn't give it
+        let config = EngineConfig {
+            file_root: Some(::std::env::temp_dir()),
+            ..EngineConfig::default()
+        };
+        let image = load_with_config("file01.rs", config)
+            .unwrap()
+            .to_image()
+            .unwrap();
+        let bc = BytecodeEngine::from_image(&image).unwrap();
+        assert_eq!(bc.config().file_root, None);
+        match EvalEngine::new().eval_program(&bc, "main") {
+            Err(e) => assert_eq!(
+                e.message(),
+                "There is no file root to read counter.txt from"
+            ),
+            Ok(_) => panic!("Expected the image to have no file root"),
+        }
+
+        // A path that conversion would refuse is refused in an image too
+        let mut hostile = image.clone();
+        let at = hostile
+            .windows(11)
+            .position(|bytes| bytes == b"counter.txt")
+            .unwrap();
+        hostile[at..at + 11].copy_from_slice(b"/etc/passwd");
+        match BytecodeEngine::from_image(&hostile) {
+            Err(PeachError::Verify(e)) => assert_eq!(
+                e.problem,
+                VerifyProblem::PathOutsideRoot("/etc/passwd".to_string())
+            ),
+            Err(e) => panic!("Expected a verify error, got {}", e),
+            Ok(_) => panic!("Expected the hostile image to not load"),
+        }
+
+        // Builtins the policy set after loading doesn't allow stop the program when they're reached
+        let image = load_to_bc("args01.rs").unwrap().to_image().unwrap();
+        let mut bc = BytecodeEngine::from_image(&image).unwrap();
+        bc.set_config(EngineConfig {
+            allow_env: false,
+            ..EngineConfig::default()
+        });
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(
+                e.message(),
+                "env is not allowed by this engine's configuration"
+            ),
+            Ok(_) => panic!("Expected env to not be allowed"),
+        }
+        assert_eq!(ee.debug_capture.take().unwrap().trim(), "DEBUG: U64(0)");
+    }
+
+    #[test]
+    fn test_image_header01() {
+        let image = load_to_bc("image01.rs").unwrap().to_image().unwrap();
+        assert!(image.starts_with(b"PEACHIMG"));
+        assert_eq!(
+            BytecodeEngine::capabilities().format_version("image"),
+            Some(2)
+        );
+        let load_changed = |offset: usize, bytes: &[u8]| {
+            let mut changed = image.clone();
+            changed[offset..offset + bytes.len()].copy_from_slice(bytes);
+            match BytecodeEngine::from_image(&changed) {
+                Err(e) => e.message().to_string(),
+                Ok(_) => panic!("Expected the changed image to not load"),
+            }
+        };
+
+        assert_eq!(
+            load_changed(0, b"PEACHJPG"),
+            "Not a peach image: it doesn't start with \"PEACHIMG\""
+        );
+        assert_eq!(
+            load_changed(8, &[1, 0, 0, 0]),
+            "The image is in version 1 of the image format, but this peach only loads version 2"
+        );
+        assert_eq!(
+            load_changed(12, &[200, 0, 0, 0]),
+            format!(
+                "The image has version 200 of the bytecode, but this peach runs version {}, so it has to be saved again",
+                BYTECODE_VERSION
+            )
+        );
+        assert_eq!(
+            load_changed(16, &[0, 1, 0, 0]),
+            "The image uses features this peach doesn't know (flags 0x100)"
+        );
+
+        // Programs compiled for tests say so in the header
+        let mut bc = load_with_config(
+            "tests01.rs",
+            EngineConfig {
+                tests: true,
+                ..EngineConfig::default()
+            },
+        )
+        .unwrap();
+        bc.run_tests().unwrap();
+        let image = bc.to_image().unwrap();
+        assert_eq!(image[16], 2);
+        assert_eq!(BytecodeEngine::from_image(&image).unwrap().tests.len(), 3);
+    }
+
+    #[test]
+    fn test_cache01() {
+        use std::fs;
+
+        let dir = ::std::env::temp_dir().join(format!("peach_cache01_{}", ::std::process::id()));
+        let src = fs::read_to_string("test_files/warnings01.rs").unwrap();
+        let process_cached = |src: &str| {
+            let mut bc = BytecodeEngine::new();
+            bc.set_cache_dir(dir.to_str().unwrap());
+            bc.load_str("warnings01.rs", src).unwrap();
+            bc.process_fn("main", 0).unwrap();
+            bc
+        };
+        let unused_warning = |bc: &BytecodeEngine| bc.warnings()[0].message.clone();
+
+        // The first run processes main and saves it
+        let bc = process_cached(&src);
+        let entries: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            bc.warnings(),
+            load_to_bc("warnings01.rs").unwrap().warnings()
+        );
+        let image = bc.to_image().unwrap();
+
+        // Later runs load it, warnings and all, so a changed warning in the entry shows that it was used
+        let mut entry = fs::read(&entries[0]).unwrap();
+        let at = entry
+            .windows(6)
+            .position(|bytes| bytes == b"unused")
+            .unwrap();
+        entry[at..at + 6].copy_from_slice(b"UNUSED");
+        fs::write(&entries[0], &entry).unwrap();
+        let bc = process_cached(&src);
+        assert_eq!(unused_warning(&bc), "UNUSED variable: unused");
+        assert_eq!(bc.to_image().unwrap(), image);
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(ee.debug_capture.take().unwrap().trim(), "DEBUG: U64(5)");
+
+        // An entry that can't be read is processed again, and replaced
+        fs::write(&entries[0], b"not an entry").unwrap();
+        let bc = process_cached(&src);
+        assert_eq!(unused_warning(&bc), "unused variable: unused");
+        assert_eq!(
+            BytecodeEngine::from_image(&bc.to_image().unwrap())
+                .unwrap()
+                .definitions
+                .len(),
+            bc.definitions.len()
+        );
+        assert_ne!(fs::read(&entries[0]).unwrap(), b"not an entry");
+
+        // Changing the source gives a new entry, which replaces the one it supersedes
+        process_cached(&src.replace("x: 5", "x: 6"));
+        let new_entries: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(new_entries.len(), 1);
+        assert_ne!(new_entries[0], entries[0]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache02() {
+        use std::fs;
+
+        // Two projects whose files have the same names share a cache directory, and keep their own entries
+        let dir = ::std::env::temp_dir().join(format!("peach_cache02_{}", ::std::process::id()));
+        let cache_dir = dir.join("cache");
+        let src = fs::read_to_string("test_files/warnings01.rs").unwrap();
+        let process_cached = |project: &str| {
+            let root = dir.join(project);
+            fs::create_dir_all(&root).unwrap();
+            let mut bc = BytecodeEngine::new();
+            bc.set_project_root(root.to_str().unwrap()).unwrap();
+            bc.set_cache_dir(cache_dir.to_str().unwrap());
+            bc.load_str("main.rs", &src).unwrap();
+            bc.process_fn("main", 0).unwrap();
+        };
+        let entries = || {
+            let mut entries: Vec<_> = fs::read_dir(&cache_dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect();
+            entries.sort();
+            entries
+        };
+
+        process_cached("one");
+        let first = entries();
+        process_cached("two");
+        assert_eq!(entries().len(), 2);
+        assert!(entries().contains(&first[0]));
+
+        // Running either again finds its entry rather than replacing the other's
+        process_cached("one");
+        process_cached("two");
+        assert_eq!(entries().len(), 2);
+        assert!(entries().contains(&first[0]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_engine_json01() {
+        let bc = load_to_bc("fn01.rs").unwrap();
+        let json = bc.to_json();
+        assert!(json.starts_with(
+            "{\"version\":1,\"scopes\":[{\"id\":0,\"parent\":null,\"module\":true,\"names\":[\
+             {\"name\":\"bar\",\"id\":10,\"public\":false,\"imported\":false},\
+             {\"name\":\"main\",\"id\":11,\"public\":false,\"imported\":false}]}"
+        ));
+        assert!(json.contains("{\"id\":3,\"kind\":\"type\",\"name\":\"u64\",\"processed\":true}"));
+        assert!(json.ends_with(
+            "{\"id\":11,\"kind\":\"fn\",\"name\":\"main\",\"processed\":true,\"type_params\":[],\"params\":[],\
+             \"return_type\":\"()\",\"location\":{\"file\":\"fn01.rs\",\"line\":5,\"column\":1},\"vars\":[],\
+             \"bytecode\":[{\"op\":\"Call\",\"operands\":[10]},{\"op\":\"DebugPrint\",\"operands\":[3]},\
+             {\"op\":\"ReturnVoid\",\"operands\":[]}]}]}"
+        ));
+
+        // Instances of generic structs are named like types, and functions nothing needed aren't processed
+        let json = load_to_bc("image01.rs").unwrap().to_json();
+        assert!(json.contains(
+            "\"kind\":\"struct\",\"name\":\"Pair<u64>\",\"processed\":true,\
+             \"fields\":[{\"name\":\"a\",\"type\":\"u64\"},{\"name\":\"b\",\"type\":\"u64\"}]"
+        ));
+        assert!(json.contains("\"kind\":\"fn\",\"name\":\"never_called\",\"processed\":false}"));
+    }
+
+    #[test]
+    fn test_lsp01() {
+        use std::env;
+        use std::fs;
+        use std::io::Cursor;
+        use LanguageServer;
+
+        let dir = env::temp_dir().join("peach_lsp01");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lsp01.rs");
+        fs::copy("test_files/lsp01.rs", &path).unwrap();
+        let uri = format!("file://{}", path.canonicalize().unwrap().display());
+        let document = format!("{{\"textDocument\":{{\"uri\":\"{}\"}}}}", uri);
+        let at = |line: usize, character: usize| {
+            format!(
+                "{{\"textDocument\":{{\"uri\":\"{}\"}},\"position\":{{\"line\":{},\"character\":{}}}}}",
+                uri, line, character
+            )
+        };
+        let request = |id: usize, method: &str, params: &str| {
+            format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":\"{}\",\"params\":{}}}",
+                id, method, params
+            )
+        };
+        let notification = |method: &str, params: &str| {
+            format!(
+                "{{\"jsonrpc\":\"2.0\",\"method\":\"{}\",\"params\":{}}}",
+                method, params
+            )
+        };
+
+        let mut server = LanguageServer::new();
+        let replies = server.handle_message(&request(1, "initialize", "{}"));
+        assert!(replies[0].contains("\"hoverProvider\":true,\"definitionProvider\":true"));
+
+        // Opening the file checks every function in it, not just those main uses
+        let replies = server.handle_message(&notification("textDocument/didOpen", &document));
+        assert_eq!(
+            replies,
+            vec![format!(
+                "{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{{\"uri\":\"{}\",\
+                 \"diagnostics\":[{{\"range\":{{\"start\":{{\"line\":11,\"character\":4}},\"end\":{{\"line\":11,\"character\":8}}}},\
+                 \"severity\":1,\"source\":\"peach\",\"message\":\"Mismatched types: expected u64, found bool\"}},\
+                 {{\"range\":{{\"start\":{{\"line\":9,\"character\":0}},\"end\":{{\"line\":9,\"character\":2}}}},\
+                 \"severity\":2,\"source\":\"peach\",\"message\":\"function is never used: broken\"}}]}}}}",
+                uri
+            )]
+        );
+
+        // Hovering shows the type of what's named, and its definition is where it's declared
+        let replies = server.handle_message(&request(2, "textDocument/hover", &at(16, 17)));
+        assert_eq!(
+            replies,
+            vec![
+                "{\"jsonrpc\":\"2.0\",\"id\":2,\"result\":{\"contents\":{\"kind\":\"markdown\",\
+                 \"value\":\"```rust\\nfn add(a: u64, b: u64) -> u64\\n```\"},\
+                 \"range\":{\"start\":{\"line\":16,\"character\":16},\"end\":{\"line\":16,\"character\":19}}}}"
+            ]
+        );
+        let replies = server.handle_message(&request(3, "textDocument/definition", &at(16, 17)));
+        assert_eq!(
+            replies,
+            vec![format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":3,\"result\":{{\"uri\":\"{}\",\
+                 \"range\":{{\"start\":{{\"line\":5,\"character\":0}},\"end\":{{\"line\":5,\"character\":2}}}}}}}}",
+                uri
+            )]
+        );
+        // Variables named inside println! are found too
+        let replies = server.handle_message(&request(4, "textDocument/hover", &at(17, 20)));
+        assert!(replies[0].contains("\"value\":\"```rust\\ntotal: u64\\n```\""));
+        let replies = server.handle_message(&request(5, "textDocument/definition", &at(17, 20)));
+        assert!(replies[0].contains(
+            "\"range\":{\"start\":{\"line\":16,\"character\":8},\"end\":{\"line\":16,\"character\":13}}"
+        ));
+        let replies = server.handle_message(&request(6, "textDocument/hover", &at(15, 13)));
+        assert!(replies[0].contains("struct Point { x: u64, y: u64 }"));
+        let replies = server.handle_message(&request(7, "textDocument/hover", &at(14, 0)));
+        assert_eq!(
+            replies,
+            vec!["{\"jsonrpc\":\"2.0\",\"id\":7,\"result\":null}"]
+        );
+
+        // Once the problems are fixed and the file is saved, its diagnostics are cleared
+        let fixed = fs::read_to_string(&path)
+            .unwrap()
+            .replace("let flag = true;\n    flag", "add(1, 2)")
+            .replace(
+                "println!(\"{}\", total);",
+                "println!(\"{}\", total + broken());",
+            );
+        fs::write(&path, fixed).unwrap();
+        let replies = server.handle_message(&notification("textDocument/didSave", &document));
+        assert_eq!(
+            replies,
+            vec![format!(
+                "{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{{\"uri\":\"{}\",\
+                 \"diagnostics\":[]}}}}",
+                uri
+            )]
+        );
+
+        // Served over a stream, messages are framed with their length, and the server exits cleanly once shut down
+        let mut input = vec![];
+        for message in &[
+            request(1, "textDocument/rename", "{}"),
+            request(2, "shutdown", "null"),
+            notification("exit", "null"),
+        ] {
+            input.extend(format!("Content-Length: {}\r\n\r\n{}", message.len(), message).bytes());
+        }
+        let mut output = vec![];
+        let status = LanguageServer::new()
+            .serve(&mut Cursor::new(input), &mut output)
+            .unwrap();
+        assert_eq!(status, 0);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Content-Length: 95\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":1,\"error\":{\"code\":-32601,\
+             \"message\":\"Unknown method textDocument/rename\"}}\
+             Content-Length: 38\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":2,\"result\":null}"
+        );
+    }
+
+    #[test]
+    fn test_dump_lowering01() {
+        // Each statement is followed by its instructions, with those of the statements nested in it indented
+        let mut bc = load_to_bc("while01.rs").unwrap();
+        let warnings = bc.warnings();
+        let processed = bc.typed_listings();
+        assert_eq!(
+            bc.dump_lowering("main").unwrap(),
+            "// while01.rs:2:5  let mut x = 2;
+   0: PushUnknownInt(2)
+   1: VarDecl(0)
+// while01.rs:4:5  while x < 10 {
+   2: BeginWhile
+   3: Var(0)
+   4: PushUnknownInt(10)
+   5: Lt
+   6: WhileCond(6)
+   // while01.rs:5:9  x = x + 1;
+      7: Var(0)
+      8: PushUnknownInt(1)
+      9: Add
+     10: LValueVar(0)
+     11: Assign
+  12: EndWhile(10)
+// while01.rs:8:5  println!(\"{}\", x)
+  13: Var(0)
+  14: DebugPrint(1)
+  15: ReturnVoid
+"
+        );
+        // Dumping leaves the processed function, and the warnings, as they were
+        assert_eq!(bc.typed_listings(), processed);
+        assert_eq!(bc.warnings(), warnings);
+
+        // Functions can be dumped before they're processed
+        let mut bc = BytecodeEngine::new();
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("fn01.rs").unwrap();
+        let lowering = bc.dump_lowering("bar").unwrap();
+        assert!(lowering.starts_with("// fn01.rs:2:5  6\n"));
+        let ast = bc.dump_ast("bar").unwrap();
+        assert!(ast.starts_with("LazyFn {\n    decl: FnDecl {"));
+        assert!(ast.contains("lit: 6,"));
+        match bc.dump_ast("baz") {
+            Err(e) => assert_eq!(e.message(), "Function baz could not be found"),
+            Ok(ast) => panic!("Expected no function to dump, got {}", ast),
+        }
+    }
+
+    #[test]
+    fn test_verify01() {
+        use bytecode::{Bytecode, VerifyProblem};
+
+        // Bytecode from conversion verifies, fused or not
+        let mut bc = load_to_bc("if05.rs").unwrap();
+        bc.verify().unwrap();
+        bc.fuse_instructions();
+        bc.verify().unwrap();
+
+        let code = load_to_bc("if05.rs")
+            .unwrap()
+            .get_fn("main", 0)
+            .unwrap()
+            .bytecode
+            .clone();
+        let position = |name: &str| code.iter().position(|x| x.name() == name).unwrap();
+        let (if_at, else_at, end_if_at) = (position("If"), position("Else"), position("EndIf"));
+        let if_ty = match code[end_if_at] {
+            Bytecode::EndIf(ty) => ty,
+            _ => unreachable!(),
+        };
+        let y = match code[end_if_at + 1] {
+            Bytecode::VarDecl(var_id) => var_id,
+            _ => unreachable!(),
+        };
+
+        // Each change to the bytecode breaks it in a different way, which is found where it's broken
+        let broken = |change: &dyn Fn(&mut Vec<Bytecode>)| {
+            let mut bc = load_to_bc("if05.rs").unwrap();
+            let main = bc.get_defn("main", 0).unwrap().0;
+            if let Definition::Fun(ref mut fun) = bc.definitions[main] {
+                change(&mut fun.bytecode);
+            }
+            match bc.verify() {
+                Err(PeachError::Verify(error)) => {
+                    assert_eq!(error.function, "main");
+                    (error.offset, error.problem)
+                }
+                result => panic!("Expected bytecode not to verify, got {:?}", result),
+            }
+        };
+        assert_eq!(
+            broken(&|bytecode| bytecode[else_at] = Bytecode::Else(100, if_ty)),
+            (else_at, VerifyProblem::JumpOutOfBounds)
+        );
+        assert_eq!(
+            broken(&|bytecode| bytecode[else_at] = Bytecode::Else(2, builtin_type::BOOL)),
+            (
+                else_at,
+                VerifyProblem::MismatchedTypes {
+                    expected: if_ty,
+                    found: builtin_type::BOOL
+                }
+            )
+        );
+        assert_eq!(
+            broken(&|bytecode| {
+                bytecode.remove(end_if_at);
+            }),
+            (if_at, VerifyProblem::Unbalanced)
+        );
+        assert_eq!(
+            broken(&|bytecode| bytecode[if_at + 1] = Bytecode::DropVars(vec![])),
+            (
+                else_at,
+                VerifyProblem::StackMismatch {
+                    expected: 1,
+                    found: 0
+                }
+            )
+        );
+        assert_eq!(
+            broken(&|bytecode| bytecode.insert(0, Bytecode::Add)),
+            (
+                0,
+                VerifyProblem::StackUnderflow {
+                    needed: 2,
+                    depth: 0
+                }
+            )
+        );
+        assert_eq!(
+            broken(&|bytecode| bytecode.insert(0, Bytecode::Var(99))),
+            (0, VerifyProblem::UnknownVar(99))
+        );
+        assert_eq!(
+            broken(&|bytecode| bytecode.insert(0, Bytecode::Var(y))),
+            (0, VerifyProblem::UndeclaredVar(y))
+        );
+    }
+
+    #[test]
+    fn test_ssa01() {
+        use bytecode::{Bytecode, Cfg, ConstantPropagation, Pipeline, Ssa, ValueDef};
+
+        // Where the branches of an if join, the variable assigned in one of them is read through a phi
+        let mut bc = load_to_bc("ssa01.rs").unwrap();
+        let fun = bc.get_fn("pick", 0).unwrap();
+        let cfg = Cfg::new(fun);
+        let ssa = Ssa::new(fun, &cfg);
+        let x = ssa.read(2, 2, 1).unwrap();
+        assert_eq!(ssa.phis[2], vec![x]);
+        match ssa.values[x].def {
+            ValueDef::Phi(2, ref args) => {
+                let defs: Vec<&ValueDef> = args.iter().map(|x| &ssa.values[*x].def).collect();
+                assert_eq!(defs, vec![&ValueDef::Store(0, 1), &ValueDef::Store(1, 2)]);
+            }
+            ref def => panic!("Expected a phi, got {:?}", def),
+        }
+        let y = ssa.read(2, 3, 2).unwrap();
+        assert_eq!(ssa.values[y].def, ValueDef::Store(2, 1));
+        assert_eq!(ssa.reads_of(y), vec![(2, 3)]);
+        assert_eq!(ssa.values[ssa.read(0, 2, 0).unwrap()].def, ValueDef::Param);
+
+        // The loop condition reads the value from before the loop or from the end of its body, and borrowed
+        // variables are left out
+        let fun = bc.get_fn("main", 0).unwrap();
+        let cfg = Cfg::new(fun);
+        let ssa = Ssa::new(fun, &cfg);
+        let i = ssa.read(1, 1, 0).unwrap();
+        assert_eq!(ssa.phis[1], vec![i]);
+        assert_eq!(ssa.reads_of(i), vec![(1, 1), (2, 0), (3, 6)]);
+        assert!(!ssa.is_promoted(1));
+        assert!(ssa.is_promoted(2));
+
+        // A loop at the start of a function gets an empty entry block, so the parameter flows into the loop's phi
+        let fun = bc.get_fn("shrink", 0).unwrap();
+        let cfg = Cfg::new(fun);
+        assert!(cfg.blocks[0].code.is_empty());
+        assert_eq!(cfg.blocks[0].successors, vec![1]);
+        assert_eq!(
+            format!("{:?}", cfg.linearize()),
+            format!("{:?}", fun.bytecode)
+        );
+        let ssa = Ssa::new(fun, &cfg);
+        let n = ssa.read(1, 2, 0).unwrap();
+        match ssa.values[n].def {
+            ValueDef::Phi(1, ref args) => {
+                assert_eq!(ssa.values[args[0]].def, ValueDef::Param);
+                assert_eq!(ssa.values[args[1]].def, ValueDef::Store(2, 4));
+            }
+            ref def => panic!("Expected a phi, got {:?}", def),
+        }
+
+        // Only reads that can see just one literal are replaced, and the program prints the same
+        let mut pipeline = Pipeline::new();
+        pipeline.add(ConstantPropagation);
+        let (definition_id, _) = bc.get_defn("pick", 0).unwrap();
+        if let Definition::Fun(ref mut fun) = bc.definitions[definition_id] {
+            let stmt_locations = fun.stmt_locations.clone();
+            assert_eq!(pipeline.run(fun), vec!["constant-propagation"]);
+            assert_eq!(fun.stmt_locations, stmt_locations);
+            assert!(pipeline.run(fun).is_empty());
+        }
+        bc.run_pipeline(&pipeline);
+        let code = &bc.get_fn("pick", 0).unwrap().bytecode;
+        assert_eq!(
+            format!("{:?}", &code[code.len() - 4..]),
+            format!(
+                "{:?}",
+                vec![
+                    Bytecode::Var(1),
+                    Bytecode::PushU64(7),
+                    Bytecode::Add,
+                    Bytecode::ReturnLastStackValue
+                ]
+            )
+        );
+        check_output(
+            &bc,
+            "ssa01.rs",
+            "DEBUG: U64(9)\nDEBUG: U64(8)\nDEBUG: UnknownInt(3)\nDEBUG: U64(5)\nDEBUG: UnknownInt(6)",
+            "DEBUG: 9\nDEBUG: 8\nDEBUG: 3\nDEBUG: 5\nDEBUG: 6",
+        );
+    }
+
+    #[test]
+    fn test_fold01() {
+        use bytecode::{Bytecode, ConstantFolding, ConstantPropagation, Pipeline};
+
+        fn load_folded(fname: &str, pipeline: Pipeline) -> BytecodeEngine {
+            let mut bc = BytecodeEngine::new();
+            bc.set_project_root("test_files").unwrap();
+            bc.load_file(fname).unwrap();
+            bc.set_pipeline(pipeline);
+            bc.process_fn("main", 0).unwrap();
+            bc
+        }
+
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&load_to_bc("fold01.rs").unwrap(), "main")
+            .unwrap();
+        let unfolded = ee.debug_capture.unwrap();
+
+        // Arithmetic on literals becomes a single push, and ifs with literal conditions lose the arm that can't run
+        let mut pipeline = Pipeline::new();
+        pipeline.add(ConstantFolding);
+        let bc = load_folded("fold01.rs", pipeline);
+        let main = &bc.get_fn("main", 0).unwrap().bytecode;
+        assert!(!main.iter().any(|x| matches!(
+            x,
+            Bytecode::Add | Bytecode::Sub | Bytecode::Mul | Bytecode::Neg | Bytecode::Else(..)
+        )));
+        let ifs = main
+            .iter()
+            .filter(|x| matches!(x, Bytecode::If(..)))
+            .count();
+        assert_eq!(ifs, 1);
+        let answer = &bc.get_fn("answer", 0).unwrap().bytecode;
+        assert!(matches!(answer[0], Bytecode::PushU64(42)));
+        check_output(
+            &bc,
+            "fold01.rs",
+            unfolded.trim(),
+            "DEBUG: 14\nDEBUG: 9\nDEBUG: 6\nDEBUG: 7\nDEBUG: 42\nDEBUG: 3",
+        );
+
+        // Propagating constants first lets the condition that reads a variable be folded too
+        let mut pipeline = Pipeline::new();
+        pipeline.add(ConstantPropagation);
+        pipeline.add(ConstantFolding);
+        let bc = load_folded("fold01.rs", pipeline);
+        let main = &bc.get_fn("main", 0).unwrap().bytecode;
+        assert!(!main
+            .iter()
+            .any(|x| matches!(x, Bytecode::If(..) | Bytecode::EndIf(_))));
+        check_output(
+            &bc,
+            "fold01.rs",
+            unfolded.trim(),
+            "DEBUG: 14\nDEBUG: 9\nDEBUG: 6\nDEBUG: 7\nDEBUG: 42\nDEBUG: 3",
+        );
+
+        // Operations that would fail are left to fail when they're evaluated, in the statement they're part of
+        for (source, message) in &[
+            ("1 / 0", "attempt to divide by zero"),
+            ("2147483647 + 1", "attempt to add with overflow"),
+        ] {
+            let mut bc = BytecodeEngine::new();
+            let mut pipeline = Pipeline::new();
+            pipeline.add(ConstantFolding);
+            bc.set_pipeline(pipeline);
+            bc.load_str(
+                "fold.rs",
+                &format!(
+                    "fn main() {{\n    let x: i32 = -1;\n    println!(\"{{}}\", {});\n}}\n",
+                    source
+                ),
+            )
+            .unwrap();
+            bc.process_fn("main", 0).unwrap();
+            match EvalEngine::new().eval_program(&bc, "main") {
+                Err(PeachError::Runtime(e)) => {
+                    assert_eq!(e.message, *message);
+                    let location = e.peach_backtrace[0].location.as_ref().unwrap();
+                    assert_eq!(location.line, 3);
+                }
+                x => panic!("Expected {} to fail, got {:?}", source, x),
+            }
+        }
+    }
+
+    #[test]
+    fn test_callgraph01() {
+        // Functions are named by the module or type they're in, and the one nothing calls is still lazy.  The call
+        // that constructs a struct and the instance of the generic function are left out.
+        let bc = load_to_bc("callgraph01.rs").unwrap();
+        let dot = bc.call_graph_dot();
+        assert!(dot.starts_with("digraph calls {\n"));
+        for line in &[
+            "[label=\"shapes::area\"];",
+            "[label=\"shapes::scale\"];",
+            "[label=\"shapes::unused\", style=dashed];",
+            "[label=\"Counter::new\"];",
+            "[label=\"id\"];",
+            "[label=\"main\"];",
+        ] {
+            assert!(dot.contains(line), "{} is missing from {}", line, dot);
+        }
+
+        // Edges go between the ids of the functions' nodes
+        let node = |name: &str| {
+            let label = format!("[label=\"{}\"", name);
+            let line = dot.lines().find(|x| x.contains(&label)).unwrap();
+            line.trim().split(' ').next().unwrap().to_string()
+        };
+        let edges: Vec<&str> = dot.lines().filter(|x| x.contains(" -> ")).collect();
+        assert_eq!(
+            edges,
+            vec![
+                format!("    {} -> {};", node("shapes::area"), node("shapes::scale")),
+                format!("    {} -> {};", node("main"), node("Counter::new")),
+                format!("    {} -> {};", node("main"), node("shapes::area")),
+                format!("    {} -> {};", node("main"), node("id")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cfg_dot01() {
+        // The loop's condition is a block of its own, which the body jumps back to
+        let bc = load_to_bc("while01.rs").unwrap();
+        assert_eq!(
+            bc.get_fn("main", 0).unwrap().to_dot(),
+            r#"digraph cfg {
+    node [shape=box, fontname=monospace];
+    b0 [label="0: PushUnknownInt(2)\l1: VarDecl(0)\l"];
+    b1 [label="2: BeginWhile\l3: Var(0)\l4: PushUnknownInt(10)\l5: Lt\l6: WhileCond(6)\l"];
+    b2 [label="7: Var(0)\l8: PushUnknownInt(1)\l9: Add\l10: LValueVar(0)\l11: Assign\l12: EndWhile(10)\l"];
+    b3 [label="13: Var(0)\l14: DebugPrint(1)\l15: ReturnVoid\l"];
+    b0 -> b1;
+    b1 -> b2 [label=true];
+    b1 -> b3 [label=false];
+    b2 -> b1;
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_typecheck01() {
+        let mut bc = BytecodeEngine::new();
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("typecheck01.rs").unwrap();
+
+        // Every error in the function is reported, with statements in inner blocks found before the ones around them.
+        // The use of `a`, whose declaration has an error, isn't reported again.
+        let err = bc.check_fn("main", 0).err().unwrap();
+        let errors: Vec<(String, usize, usize)> = err
+            .errors()
+            .iter()
+            .map(|x| {
+                let location = x.location().unwrap();
+                (x.message().to_string(), location.line, location.column)
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("Can't add values of bool and bool".to_string(), 9, 18),
+                ("Can't multiply values of u64 and bool".to_string(), 13, 19),
+                ("While condition needs to be boolean".to_string(), 12, 5),
+            ]
+        );
+
+        // Checking doesn't keep the bytecode, so the function is still lazy
+        let (definition_id, _) = bc.get_defn("main", 0).unwrap();
+        assert!(matches!(
+            bc.definitions[definition_id],
+            Definition::LazyFn(_)
+        ));
+
+        let err = bc.check_fn("unsigned", 0).err().unwrap();
+        let messages: Vec<&str> = err.errors().iter().map(|x| x.message()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Can't negate value of type u32",
+                "If condition needs to be boolean"
+            ]
+        );
+        assert!(bc.check_fn("missing", 0).is_err());
+
+        // Functions without errors check cleanly
+        let mut bc = BytecodeEngine::new();
+        bc.set_project_root("test_files").unwrap();
+        bc.load_file("fold01.rs").unwrap();
+        bc.check_fn("main", 0).unwrap();
+    }
+
+    #[test]
+    fn test_mismatch01() {
+        // Each mismatch is placed at the expression with the wrong type
+        let err = load_to_bc("mismatch01.rs").err().unwrap();
+        let errors: Vec<String> = err.errors().iter().map(|x| x.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "mismatch01.rs:20:17: Mismatched types: expected u64, found bool",
+                "mismatch01.rs:13:16: Mismatched types: expected u64, found bool",
+                "mismatch01.rs:21:20: Mismatched types: expected u64, found bool",
+                "mismatch01.rs:23:9: Mismatched types: expected u32, found bool",
+                "mismatch01.rs:24:34: If and else have incompatible types: expected {integer}, found bool",
+                "mismatch01.rs:25:22: Mismatched types: expected u64, found Counter",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_return01() {
+        // A function doesn't need a tail expression when every way through it returns
+        run_test(
+            "return01.rs",
+            "DEBUG: U64(1)\nDEBUG: U64(8)",
+            "DEBUG: 1\nDEBUG: 8",
+        );
+    }
+
+    #[test]
+    fn test_return_bad01() {
+        let err = load_to_bc("return_bad01.rs").err().unwrap();
+        let errors: Vec<String> = err.errors().iter().map(|x| x.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "return_bad01.rs:1:24: Function may finish without returning a value of type bool",
+                "return_bad01.rs:8:5: Mismatched types: expected u64, found bool",
+                "return_bad01.rs:12:5: Mismatched types: expected (), found u64",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_heap01() {
+        let bc = load_to_bc("heap01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.take().unwrap(),
+            "DEBUG: U64(12502500)\nDEBUG: Object({\"inner\": Object({\"x\": U64(2)}), \"y\": U64(3)})\n"
+        );
+
+        // Each call of step allocates several objects, which are collected once it returns.  Only main's last
+        // struct and the struct inside it are still on the stack.
+        assert!(ee.heap_objects() < 5000);
+        ee.collect_garbage();
+        assert_eq!(ee.heap_objects(), 2);
+    }
+
+    #[test]
+    fn test_intern01() {
+        use intern::Interner;
+
+        let mut interner = Interner::new();
+        assert_eq!(interner.get("x"), None);
+        let x = interner.intern("x");
+        let y = interner.intern("y");
+        assert_ne!(x, y);
+        assert_eq!(interner.intern("x"), x);
+        assert_eq!(interner.get("y"), Some(y));
+        assert_eq!(interner.name(x), "x");
+    }
+
+    #[test]
+    fn test_replay01() {
+        use replay::Recording;
+        use std::io::Cursor;
+
+        let bc = load_to_bc("replay01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.input = Some(Box::new(Cursor::new("5\n")));
+        ee.clock = Some(Box::new(|| 1234));
+        ee.args = vec!["17".into()];
+        let recording = ee.record_program(&bc, "main");
+        assert_eq!(ee.halted, None);
+        let output = ee.debug_capture.unwrap();
+
+        let log = recording.to_log();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines[0], "peach-recording 1");
+        assert_eq!(lines[1], "ReadInput 5");
+        assert!(lines[2].starts_with("RandomBelow "));
+        assert_eq!(&lines[3..], &["ReadClock 1234", "ArgCount 1", "Arg 17"]);
+
+        // Nothing is read from outside the engine when replaying, so the run is the same without any input
+        let recording = Recording::from_log(&log).unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.input = Some(Box::new(Cursor::new("")));
+        ee.replay_program(&bc, "main", &recording).unwrap();
+        assert_eq!(ee.debug_capture.unwrap(), output);
+
+        // A recording from a different run doesn't fit
+        let mut shuffled = recording.clone();
+        shuffled.inputs.swap(0, 1);
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        match ee.replay_program(&bc, "main", &shuffled) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "The replay log has RandomBelow next, but the program ran ReadInput"
+            ),
+            Ok(_) => panic!("Expected a shuffled recording to fail"),
+        }
+
+        match Recording::from_log("peach-recording 1\nReadClock soon\n") {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Line 2 of the replay log is not an instruction and a u64: \"ReadClock soon\""
+            ),
+            Ok(_) => panic!("Expected a bad replay log to fail"),
+        }
+    }
+
+    #[test]
+    fn test_input01() {
+        use std::io::{Cursor, Write};
+        use std::process::Stdio;
+
+        let bc = load_to_bc("input01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.input = Some(Box::new(Cursor::new("10\n50\n42\n")));
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(
+            ee.debug_capture.take().unwrap().trim(),
+            "DEBUG: UnknownInt(1)\nDEBUG: UnknownInt(2)\nDEBUG: U64(3)"
+        );
+
+        ee.input = Some(Box::new(Cursor::new("10\nfifty\n")));
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(e.message(), "Expected a u64 but read \"fifty\""),
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        ee.input = Some(Box::new(Cursor::new("10\n")));
+        match ee.eval_program(&bc, "main") {
+            Err(e) => assert_eq!(e.message(), "There is no more input to read"),
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+
+        let binary = compile::compile_bytecode(&bc, "input01.rs").unwrap();
+        let mut child = Command::new(binary)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"10\n50\n42\n")
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap().trim(),
+            "DEBUG: 1\nDEBUG: 2\nDEBUG: 3"
+        );
+
+        match load_with_config("input01.rs", EngineConfig::sandboxed()) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "read_u64 is not allowed by this engine's configuration"
+            ),
+            Ok(_) => panic!("Expected input01.rs to fail"),
+        }
+    }
+
+    #[test]
+    fn test_fuel01() {
+        use eval::Halt;
+
+        let bc = load_to_bc("fuel01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+        ee.fuel = Some(1000);
+
+        match ee.eval_program(&bc, "main") {
+            Err(_) => {}
+            Ok(x) => panic!("Expected evaluation to stop, got {:?}", x),
+        }
+        assert_eq!(ee.halted, Some(Halt::OutOfFuel));
+        assert_eq!(ee.fuel, Some(0));
+        assert_eq!(
+            ee.debug_capture.take().unwrap().trim(),
+            "DEBUG: UnknownInt(1)"
+        );
+
+        // Programs that finish leave the fuel they didn't need
+        let bc = load_to_bc("while01.rs").unwrap();
+        ee.debug_capture = Some(String::new());
+        ee.fuel = Some(1000);
+        ee.eval_program(&bc, "main").unwrap();
+        assert_eq!(ee.halted, None);
+        assert!(ee.fuel.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_interrupt01() {
+        use eval::Halt;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+
+        let bc = load_to_bc("fuel01.rs").unwrap();
+        let mut ee = EvalEngine::new();
+        ee.debug_capture = Some(String::new());
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        ee.cancel = Some(cancel.clone());
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel.store(true, Ordering::Relaxed);
+        });
+        assert!(ee.eval_program(&bc, "main").is_err());
+        canceller.join().unwrap();
+        assert_eq!(ee.halted, Some(Halt::Cancelled));
+        assert!(ee.value_stack.is_empty());
+
+        ee.cancel = None;
+        ee.set_timeout(Duration::from_millis(50));
+        assert!(ee.eval_program(&bc, "main").is_err());
+        assert_eq!(ee.halted, Some(Halt::TimedOut));
+        assert!(ee.value_stack.is_empty());
+
+        // The engine can still run programs after being interrupted
+        ee.deadline = None;
+        ee.debug_capture = Some(String::new());
+        ee.eval_program(&load_to_bc("while01.rs").unwrap(), "main")
+            .unwrap();
+        assert_eq!(ee.halted, None);
+        assert_eq!(ee.debug_capture.unwrap().trim(), "DEBUG: UnknownInt(10)");
+    }
+
+    #[test]
+    fn test_backends_agree_on_generated_programs() {
+        use testing::{check_backends_agree, minimize, GenConfig, Program, Rng};
+
+        let config = GenConfig::default();
+        for seed in 0..20 {
+            let mut rng = Rng::new(seed);
+            let program = Program::generate(&mut rng, &config);
+            let name = format!("prop{}", seed);
+
+            if let Err(mismatch) = check_backends_agree(&program, &name) {
+                let small = minimize(program, |p| check_backends_agree(p, &name).is_err());
+                panic!(
+                    "seed {}: {}
+minimized:
+{}",
+                    seed,
+                    mismatch,
+                    small.to_source()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_shrink_generated_program() {
+        use testing::{minimize, GenConfig, Program, Rng, Stmt};
+
+        let mut rng = Rng::new(42);
+        let program = Program::generate(&mut rng, &GenConfig::default());
+        let small = minimize(program, |p| !p.expected_output().unwrap().is_empty());
+
+        assert_eq!(small.body, vec![Stmt::Print(::testing::Expr::Lit(0))]);
+        assert_eq!(
+            small.to_source(),
+            "fn main() {\n    println!(\"{}\", 0);\n}\n"
+        );
+    }
 }
@@ -0,0 +1,663 @@
+//! Translates processed bytecode back into Rust source.  Compiling the result with rustc checks that converting to
+//! bytecode kept the program's meaning, and reading it shows what the conversion made of each function.
+//!
+//! Like the C backend, functions are named after their definitions, and variables after their ids.  Every reference
+//! in a signature is given the one lifetime `'a`, which rustc may find stricter than the lifetimes the program was
+//! written with.
+use bytecode::{builtin_type, Bytecode, BytecodeEngine, Definition, DefinitionId, Fun};
+use compile::entry_fn_id;
+use error::Result;
+use rng::Rng;
+use std::collections::HashSet;
+
+/// The helpers that builtins are translated to calls of, which go at the top of each Rust program
+const RUNTIME: &str = include_str!("runtime.rs");
+
+/// The lints that translated code trips, as it keeps the shape of the bytecode rather than of idiomatic Rust.
+/// Overflow and division by zero are left to fail when the program runs, as they do in the evaluator.
+const ALLOWED_LINTS: &str = "#![allow(arithmetic_overflow, dead_code, non_camel_case_types, path_statements, \
+                             unconditional_panic, unreachable_code, unused_assignments, unused_must_use, \
+                             unused_mut, unused_parens, unused_unsafe, unused_variables)]\n";
+
+struct RustFile {
+    output_src: String,
+    expression_stack: Vec<String>,
+    indent: usize,
+    next_temp_id: usize,
+}
+
+impl RustFile {
+    fn new() -> RustFile {
+        RustFile {
+            output_src: String::new(),
+            expression_stack: vec![],
+            indent: 0,
+            next_temp_id: 0,
+        }
+    }
+
+    /// Adds a line, indented by the blocks it's inside of
+    fn line(&mut self, line: &str) {
+        if line.starts_with('}') {
+            self.indent -= 1;
+        }
+        for _ in 0..self.indent {
+            self.output_src += "    ";
+        }
+        self.output_src += line;
+        self.output_src += "\n";
+        if line.ends_with('{') {
+            self.indent += 1;
+        }
+    }
+
+    fn delay_expr(&mut self, expr: String) {
+        self.expression_stack.push(expr);
+    }
+
+    fn pop_expr(&mut self) -> String {
+        self.expression_stack.pop().unwrap()
+    }
+
+    fn new_temp(&mut self) -> String {
+        self.next_temp_id += 1;
+        format!("t{}", self.next_temp_id - 1)
+    }
+
+    /// Evaluates the expressions waiting to be used into temporaries, so that statements can run before they're used
+    /// without changing the order things happen in
+    fn spill(&mut self) {
+        for idx in 0..self.expression_stack.len() {
+            let temp = self.new_temp();
+            let expr = ::std::mem::replace(&mut self.expression_stack[idx], temp.clone());
+            self.line(&format!("let {} = {};", temp, expr));
+        }
+    }
+
+    /// Pops the arguments of a call, in the order they're passed
+    fn pop_args(&mut self, count: usize) -> String {
+        let start = self.expression_stack.len() - count;
+        self.expression_stack.split_off(start).join(", ")
+    }
+
+    /// Adds a statement.  Expressions whose values were never used are evaluated first, for their side effects.
+    fn stmt(&mut self, stmt: &str) {
+        let unused: Vec<String> = self.expression_stack.drain(0..).collect();
+        for expr in unused {
+            self.line(&format!("{};", expr));
+        }
+        self.line(stmt);
+    }
+}
+
+/// Returns true if the type is, or contains, a reference.  Structs like this need a lifetime parameter.
+fn holds_references(bc: &BytecodeEngine, ty: DefinitionId) -> bool {
+    match bc.definitions[ty] {
+        Definition::Reference(_, _) => true,
+        Definition::Struct(ref st) => st
+            .fields
+            .iter()
+            .any(|(_, field_ty)| holds_references(bc, *field_ty)),
+        _ => false,
+    }
+}
+
+/// The Rust spelling of a type.  In signatures and struct definitions, references are given the lifetime `'a`.
+/// Elsewhere, rustc is left to infer their lifetimes, and the types of integers that peach didn't settle.  Peach lets
+/// those mix with any integer type, which rustc only allows while it's still inferring them.
+fn rust_type(
+    bc: &BytecodeEngine,
+    definition_id: DefinitionId,
+    instantiations: Option<&Vec<(DefinitionId, DefinitionId)>>,
+    in_signature: bool,
+) -> String {
+    match definition_id {
+        builtin_type::UNKNOWN => "_".into(),
+        builtin_type::U64 => "u64".into(),
+        builtin_type::U32 => "u32".into(),
+        builtin_type::I64 => "i64".into(),
+        builtin_type::I32 => "i32".into(),
+        // Like rustc, integers whose type was never settled are i32s
+        builtin_type::UNKNOWN_INT if in_signature => "i32".into(),
+        builtin_type::UNKNOWN_INT => "_".into(),
+        builtin_type::VOID_PTR => "*mut ::std::os::raw::c_void".into(),
+        builtin_type::VOID => "()".into(),
+        builtin_type::BOOL => "bool".into(),
+        ty => match bc.definitions[ty] {
+            Definition::Struct(ref st) => {
+                // Generic functions use the instance for their instantiation's type arguments
+                let ty = match (&st.instance_of, instantiations) {
+                    (Some(_), Some(instantiations)) if bc.contains_type_variable(ty) => {
+                        bc.find_substituted_type(ty, instantiations)
+                    }
+                    _ => ty,
+                };
+                match (holds_references(bc, ty), in_signature) {
+                    (false, _) => format!("struct_{}", ty),
+                    (true, true) => format!("struct_{}<'a>", ty),
+                    (true, false) => format!("struct_{}<'_>", ty),
+                }
+            }
+            Definition::Reference(referenced, mutable) => format!(
+                "&{}{}{}",
+                if in_signature { "'a " } else { "" },
+                if mutable { "mut " } else { "" },
+                rust_type(bc, referenced, instantiations, in_signature)
+            ),
+            Definition::TypeVariable => {
+                match instantiations.and_then(|x| x.iter().find(|x| x.0 == ty)) {
+                    Some(x) => rust_type(bc, x.1, instantiations, in_signature),
+                    None => {
+                        unimplemented!("Can't find appropriate substitution during instantiation")
+                    }
+                }
+            }
+            _ => unimplemented!("Expected struct during rust_type"),
+        },
+    }
+}
+
+/// The name, generic parameters, parameters, and return type of a function, for its definition
+fn rust_signature(
+    bc: &BytecodeEngine,
+    fn_name: &str,
+    fun: &Fun,
+    instantiations: Option<&Vec<(DefinitionId, DefinitionId)>>,
+) -> String {
+    let params: Vec<String> = fun
+        .params
+        .iter()
+        .map(|param| {
+            format!(
+                "mut v{}: {}",
+                param.var_id,
+                rust_type(bc, param.ty, instantiations, true)
+            )
+        })
+        .collect();
+    let mut signature = format!("({})", params.join(", "));
+    if fun.return_ty != builtin_type::VOID {
+        signature += &format!(" -> {}", rust_type(bc, fun.return_ty, instantiations, true));
+    }
+
+    if signature.contains("'a") {
+        format!("fn {}<'a>{}", fn_name, signature)
+    } else {
+        format!("fn {}{}", fn_name, signature)
+    }
+}
+
+fn emit_fn(
+    file: &mut RustFile,
+    bc: &BytecodeEngine,
+    fn_name: &str,
+    fun: &Fun,
+    instantiations: Option<&Vec<(DefinitionId, DefinitionId)>>,
+) -> Result<()> {
+    let mut temp_stack = vec![];
+    // The variables whose values may have been moved out, which rustc won't let be dropped by hand
+    let mut moved = HashSet::new();
+
+    file.line(&format!(
+        "{} {{",
+        rust_signature(bc, fn_name, fun, instantiations)
+    ));
+
+    for (idx, code) in fun.bytecode.iter().enumerate() {
+        match code {
+            // What follows a return outside of any block can't run, and may not even typecheck
+            Bytecode::ReturnVoid => {
+                file.stmt("return;");
+                if file.indent == 1 {
+                    break;
+                }
+            }
+            Bytecode::ReturnLastStackValue => {
+                let retval = file.pop_expr();
+                file.stmt(&format!("return {};", retval));
+                if file.indent == 1 {
+                    break;
+                }
+            }
+            Bytecode::As(ty) => {
+                let val = file.pop_expr();
+                if let Definition::Struct(_) = bc.definitions[*ty] {
+                    file.delay_expr(val);
+                } else {
+                    file.delay_expr(format!(
+                        "(({}) as {})",
+                        val,
+                        rust_type(bc, *ty, instantiations, false)
+                    ));
+                }
+            }
+            Bytecode::PushU64(val) => {
+                file.delay_expr(format!("{}u64", val));
+            }
+            Bytecode::PushU32(val) => {
+                file.delay_expr(format!("{}u32", val));
+            }
+            Bytecode::PushI64(val) => {
+                file.delay_expr(format!("({}i64)", val));
+            }
+            Bytecode::PushI32(val) => {
+                file.delay_expr(format!("({}i32)", val));
+            }
+            Bytecode::PushUnknownInt(val) => {
+                // Left unsuffixed, so rustc infers the same type that peach did
+                file.delay_expr(format!("({})", val));
+            }
+            Bytecode::PushRawPtr(p) => {
+                if p.is_null() {
+                    file.delay_expr("::std::ptr::null_mut()".to_string());
+                } else {
+                    unimplemented!("Unsupported pointer type");
+                }
+            }
+            Bytecode::PushBool(val) => {
+                file.delay_expr(val.to_string());
+            }
+            Bytecode::Neg => {
+                let val = file.pop_expr();
+                file.delay_expr(format!("(-{})", val));
+            }
+            Bytecode::Add => {
+                let rhs = file.pop_expr();
+                let lhs = file.pop_expr();
+                file.delay_expr(format!("({} + {})", lhs, rhs));
+            }
+            Bytecode::Sub => {
+                let rhs = file.pop_expr();
+                let lhs = file.pop_expr();
+                file.delay_expr(format!("({} - {})", lhs, rhs));
+            }
+            Bytecode::Mul => {
+                let rhs = file.pop_expr();
+                let lhs = file.pop_expr();
+                file.delay_expr(format!("({} * {})", lhs, rhs));
+            }
+            Bytecode::Div => {
+                let rhs = file.pop_expr();
+                let lhs = file.pop_expr();
+                file.delay_expr(format!("({} / {})", lhs, rhs));
+            }
+            Bytecode::Lt => {
+                let rhs = file.pop_expr();
+                let lhs = file.pop_expr();
+                file.delay_expr(format!("({} < {})", lhs, rhs));
+            }
+            Bytecode::Borrow(mutable) => {
+                let lhs = file.pop_expr();
+                if *mutable {
+                    file.delay_expr(format!("(&mut {})", lhs));
+                } else {
+                    file.delay_expr(format!("(&{})", lhs));
+                }
+            }
+            Bytecode::Deref | Bytecode::LValueDeref => {
+                let val = file.pop_expr();
+                file.delay_expr(format!("(*{})", val));
+            }
+            Bytecode::Dot(field) | Bytecode::LValueDot(field) => {
+                let lhs = file.pop_expr();
+                file.delay_expr(format!("{}.{}", lhs, field));
+            }
+            Bytecode::VarDecl(var_id) => {
+                let rhs = file.pop_expr();
+                moved.remove(var_id);
+                file.stmt(&format!(
+                    "let mut v{}: {} = {};",
+                    var_id,
+                    rust_type(bc, fun.vars[*var_id].ty, instantiations, false),
+                    rhs
+                ));
+            }
+            Bytecode::VarDeclUninit(var_id) => {
+                file.stmt(&format!(
+                    "let mut v{}: {};",
+                    var_id,
+                    rust_type(bc, fun.vars[*var_id].ty, instantiations, false)
+                ));
+            }
+            Bytecode::Var(var_id) | Bytecode::LValueVar(var_id) => {
+                file.delay_expr(format!("v{}", var_id));
+            }
+            Bytecode::Move(var_id) => {
+                moved.insert(*var_id);
+                file.delay_expr(format!("v{}", var_id));
+            }
+            Bytecode::DropVars(vars) => {
+                // Rust blocks aren't rebuilt, so values are dropped by hand where their scope ends.  Those that may
+                // have been moved are left for rustc to drop at the end of the function.
+                let dropped: Vec<_> = vars
+                    .iter()
+                    .filter(|(var_id, ty)| bc.needs_drop(*ty) && !moved.contains(var_id))
+                    .collect();
+                if !dropped.is_empty() {
+                    file.spill();
+                    for (var_id, _) in dropped {
+                        file.line(&format!("drop(v{});", var_id));
+                    }
+                }
+            }
+            Bytecode::Assign => {
+                let lhs = file.pop_expr();
+                let rhs = file.pop_expr();
+                file.stmt(&format!("{} = {};", lhs, rhs));
+
+                // Assigning to a whole variable gives it a value to drop again
+                if let Some(Bytecode::LValueVar(var_id)) = fun.bytecode.get(idx - 1) {
+                    moved.remove(var_id);
+                }
+            }
+            Bytecode::AddVarConst(var_id, val) => {
+                file.delay_expr(format!("(v{} + ({}))", var_id, val));
+            }
+            Bytecode::SubVarConst(var_id, val) => {
+                file.delay_expr(format!("(v{} - ({}))", var_id, val));
+            }
+            Bytecode::LtVarConst(var_id, val) => {
+                file.delay_expr(format!("(v{} < ({}))", var_id, val));
+            }
+            Bytecode::AssignVar(var_id) => {
+                let rhs = file.pop_expr();
+                file.stmt(&format!("v{} = {};", var_id, rhs));
+                moved.remove(var_id);
+            }
+            Bytecode::Call(definition_id) => match bc.definitions[*definition_id] {
+                Definition::Fun(ref fun) => {
+                    let args = file.pop_args(fun.params.len());
+                    match fun.extern_name {
+                        Some(ref ex_name) => {
+                            file.delay_expr(format!("unsafe {{ {}({}) }}", ex_name, args))
+                        }
+                        None => file.delay_expr(format!("fun_{}({})", definition_id, args)),
+                    }
+                }
+                Definition::Struct(ref st) => {
+                    let struct_id = match instantiations {
+                        Some(instantiations) => {
+                            bc.find_substituted_type(*definition_id, instantiations)
+                        }
+                        None => *definition_id,
+                    };
+                    let start = file.expression_stack.len() - st.fields.len();
+                    let values = file.expression_stack.split_off(start);
+                    let fields: Vec<String> = st
+                        .fields
+                        .iter()
+                        .zip(values)
+                        .map(|((field, _), value)| format!("{}: {}", field, value))
+                        .collect();
+                    file.delay_expr(format!("struct_{} {{ {} }}", struct_id, fields.join(", ")));
+                }
+                Definition::InstantiatedFun(orig_id, _) => {
+                    if let Definition::Fun(ref fun) = bc.definitions[orig_id] {
+                        let args = file.pop_args(fun.params.len());
+                        file.delay_expr(format!("fun_{}({})", definition_id, args));
+                    }
+                }
+                _ => unimplemented!("Attempt to call unprocessed function"),
+            },
+            Bytecode::CallNative(_) => {
+                bail!("Native functions can only be run by the evaluator");
+            }
+            Bytecode::If(_, ty) => {
+                let cond = file.pop_expr();
+                file.spill();
+                if *ty != builtin_type::VOID {
+                    let temp = file.new_temp();
+                    file.line(&format!(
+                        "let {}: {};",
+                        temp,
+                        rust_type(bc, *ty, instantiations, false)
+                    ));
+                    temp_stack.push(temp);
+                }
+                file.line(&format!("if {} {{", cond));
+            }
+            Bytecode::Else(_, ty) => {
+                if *ty != builtin_type::VOID {
+                    let result = file.pop_expr();
+                    file.stmt(&format!("{} = {};", temp_stack.last().unwrap(), result));
+                }
+                file.stmt("} else {");
+            }
+            Bytecode::EndIf(ty) => {
+                if *ty != builtin_type::VOID {
+                    let result = file.pop_expr();
+                    let temp = temp_stack.pop().unwrap();
+                    file.stmt(&format!("{} = {};", temp, result));
+                    file.line("}");
+                    file.delay_expr(temp);
+                } else {
+                    file.stmt("}");
+                }
+            }
+            Bytecode::BeginWhile => {
+                file.stmt("loop {");
+            }
+            Bytecode::WhileCond(_) => {
+                let cond = file.pop_expr();
+                file.stmt(&format!("if !{} {{", cond));
+                file.line("break;");
+                file.line("}");
+            }
+            Bytecode::EndWhile(_) => {
+                file.stmt("}");
+            }
+            Bytecode::DebugPrint(ty) => {
+                // Printed the way the C backend prints them, with bools as 0 or 1
+                let val = file.pop_expr();
+                match *ty {
+                    builtin_type::VOID | builtin_type::UNKNOWN => {
+                        file.stmt(&format!("{};", val));
+                        file.line(&format!(
+                            "println!(\"DEBUG: {}\");",
+                            if *ty == builtin_type::VOID {
+                                "<void>"
+                            } else {
+                                "<unknown>"
+                            }
+                        ));
+                    }
+                    builtin_type::BOOL => {
+                        file.stmt(&format!("println!(\"DEBUG: {{}}\", ({}) as u8);", val));
+                    }
+                    builtin_type::U32
+                    | builtin_type::U64
+                    | builtin_type::I32
+                    | builtin_type::I64
+                    | builtin_type::UNKNOWN_INT => {
+                        file.stmt(&format!("println!(\"DEBUG: {{}}\", {});", val));
+                    }
+                    _ => {
+                        file.stmt(&format!("{};", val));
+                        file.line(&format!("println!(\"DEBUG: <custom type:{}>\");", ty));
+                    }
+                }
+            }
+            Bytecode::ReadInput => {
+                file.delay_expr("peach_read_u64()".into());
+            }
+            Bytecode::ReadClock => {
+                file.delay_expr("peach_now_millis()".into());
+            }
+            Bytecode::RandomBelow => {
+                let bound = file.pop_expr();
+                file.delay_expr(format!("peach_random_below({})", bound));
+            }
+            Bytecode::ArgCount => {
+                file.delay_expr("peach_arg_count()".into());
+            }
+            Bytecode::Arg => {
+                let index = file.pop_expr();
+                file.delay_expr(format!("peach_arg({})", index));
+            }
+            Bytecode::Env(name) => {
+                file.delay_expr(format!("peach_env({:?})", name));
+            }
+            Bytecode::ReadFile(path) => {
+                file.delay_expr(format!("peach_read_file_u64({:?})", path));
+            }
+            Bytecode::WriteFile(path) => {
+                let value = file.pop_expr();
+                file.stmt(&format!("peach_write_file_u64({:?}, {});", path, value));
+            }
+            Bytecode::Panic(message) => {
+                let message = format!("panicked: {}", message);
+                file.stmt(&format!("peach_fail({:?});", message));
+            }
+            Bytecode::Exit => {
+                let code = file.pop_expr();
+                file.stmt(&format!("std::process::exit({});", code));
+            }
+        }
+    }
+
+    file.stmt("}");
+    file.line("");
+    Ok(())
+}
+
+fn emit_struct(file: &mut RustFile, bc: &BytecodeEngine, definition_id: DefinitionId) {
+    let st = match bc.definitions[definition_id] {
+        Definition::Struct(ref st) => st,
+        _ => return,
+    };
+
+    if st.copy {
+        file.line("#[derive(Clone, Copy)]");
+    }
+    file.line(&format!(
+        "struct {} {{",
+        rust_type(bc, definition_id, None, true)
+    ));
+    for (field, field_ty) in &st.fields {
+        file.line(&format!(
+            "{}: {},",
+            field,
+            rust_type(bc, *field_ty, None, true)
+        ));
+    }
+    file.line("}");
+    file.line("");
+
+    // The Drop impl is translated like any other function, so the impl only has to call it
+    if let Some(drop_fn) = bc.drop_fns.get(&definition_id) {
+        let impl_generics = if holds_references(bc, definition_id) {
+            "<'a>"
+        } else {
+            ""
+        };
+        file.line(&format!(
+            "impl{} Drop for {} {{",
+            impl_generics,
+            rust_type(bc, definition_id, None, true)
+        ));
+        file.line("fn drop(&mut self) {");
+        file.line(&format!("fun_{}(self);", drop_fn));
+        file.line("}");
+        file.line("}");
+        file.line("");
+    }
+}
+
+/// Declares the extern functions the program calls, in one `extern` block
+fn emit_externs(file: &mut RustFile, bc: &BytecodeEngine) {
+    let externs: Vec<(&String, &Fun)> = bc
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fun(ref fun) => fun.extern_name.as_ref().map(|name| (name, fun)),
+            _ => None,
+        })
+        .collect();
+    if externs.is_empty() {
+        return;
+    }
+
+    file.line("extern \"C\" {");
+    for (name, fun) in externs {
+        let params: Vec<String> = fun
+            .params
+            .iter()
+            .map(|param| format!("{}: {}", param.name, rust_type(bc, param.ty, None, true)))
+            .collect();
+        let mut line = format!("fn {}({})", name, params.join(", "));
+        if fun.return_ty != builtin_type::VOID {
+            line += &format!(" -> {}", rust_type(bc, fun.return_ty, None, true));
+        }
+        file.line(&(line + ";"));
+    }
+    file.line("}");
+    file.line("");
+}
+
+fn emit_rust_from_bytecode(bc: &BytecodeEngine, starting_fn_id: DefinitionId) -> Result<String> {
+    let mut file = RustFile::new();
+
+    file.output_src += ALLOWED_LINTS;
+    file.output_src += "\n";
+    file.output_src += RUNTIME;
+    file.line("");
+
+    // The runtime's generator starts from the configured seed, and its files are found under the configured root
+    let rng_state = match bc.config().random_seed {
+        Some(seed) => Rng::new(seed).state(),
+        None => 0,
+    };
+    file.line(&format!(
+        "static PEACH_RNG_STATE: ::std::sync::atomic::AtomicU64 = ::std::sync::atomic::AtomicU64::new({});",
+        rng_state
+    ));
+    let file_root = match bc.config().file_root {
+        Some(ref root) => root.to_string_lossy().into_owned(),
+        None => String::new(),
+    };
+    file.line(&format!("const PEACH_FILE_ROOT: &str = {:?};", file_root));
+    file.line("");
+
+    emit_externs(&mut file, bc);
+
+    for definition_id in 0..bc.definitions.len() {
+        match bc.definitions[definition_id] {
+            // Generic functions and structs are left out, and their instances translated instead
+            Definition::Fun(ref fun) if fun.is_generic() => {}
+            Definition::Fun(ref fun) if fun.extern_name.is_none() && fun.native_id.is_none() => {
+                if definition_id == starting_fn_id && fun.return_ty != builtin_type::VOID {
+                    // Rust's main can't return an integer, so the program exits with it instead
+                    emit_fn(&mut file, bc, "peach_main", fun, None)?;
+                    file.line("fn main() {");
+                    file.line("::std::process::exit(peach_main() as i32);");
+                    file.line("}");
+                } else if definition_id == starting_fn_id {
+                    emit_fn(&mut file, bc, "main", fun, None)?;
+                } else {
+                    let fn_name = format!("fun_{}", definition_id);
+                    emit_fn(&mut file, bc, &fn_name, fun, None)?;
+                }
+            }
+            Definition::Struct(_) if !bc.contains_type_variable(definition_id) => {
+                emit_struct(&mut file, bc, definition_id);
+            }
+            Definition::InstantiatedFun(orig_id, ref instantiations) => {
+                if let Definition::Fun(ref fun) = bc.definitions[orig_id] {
+                    let fn_name = format!("fun_{}", definition_id);
+                    emit_fn(&mut file, bc, &fn_name, fun, Some(instantiations))?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(file.output_src)
+}
+
+/// Translates the program's bytecode back to Rust, in which `entry_fn` becomes `main`.  The entry function is found
+/// as it is for `emit_c`.  The Rust only needs the standard library, so rustc can build it on its own.  Programs that
+/// call native functions can't be translated, as those only exist in the process that registered them.
+pub fn emit_rust(bc: &BytecodeEngine, entry_fn: &str) -> Result<String> {
+    let starting_fn_id = entry_fn_id(bc, entry_fn)?;
+    emit_rust_from_bytecode(bc, starting_fn_id)
+}
@@ -0,0 +1,11 @@
+fn main() {
+    let count = arg_count();
+    println!("{}", count);
+    let mut total = env("PEACH_ARGS01_START");
+    let mut i = 0;
+    while i < count {
+        total = total + arg(i);
+        i = i + 1;
+    }
+    println!("{}", total);
+}
@@ -0,0 +1,13 @@
+fn check(x: u64) -> u64 {
+    assert!(x < 10, "x is too big");
+    x
+}
+
+fn main() {
+    let x = check(5);
+    assert!(x + 1 < 7);
+    assert_eq!(x * 2, 10);
+    assert_ne!(x, 4);
+    println!("{}", x);
+    println!("{}", check(12));
+}
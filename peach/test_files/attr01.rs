@@ -0,0 +1,45 @@
+#![allow(dead_code)]
+
+/// A point
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Point {
+    /// The x coordinate
+    x: u64,
+    #[allow(unused)]
+    y: u64,
+}
+
+impl Point {
+    /// Sums the coordinates
+    #[inline]
+    fn sum(&self) -> u64 {
+        self.x + self.y
+    }
+
+    #[test]
+    fn check_sum() {
+        assert_eq!(Point { x: 1, y: 2 }.sum(), 3);
+    }
+}
+
+#[inline(always)]
+#[must_use]
+fn double(x: u64) -> u64 {
+    x * 2
+}
+
+#[cfg(not(test))]
+fn offset() -> u64 {
+    10
+}
+
+#[cfg(test)]
+mod tests;
+
+fn main() {
+    #[allow(unused_variables)]
+    let p = Point { x: 1, y: 2 };
+    #[cfg(test)]
+    let p = Point { x: 100, y: 200 };
+    println!("{}", double(p.sum()) + offset());
+}
@@ -0,0 +1,22 @@
+struct Point {
+    x: u64,
+    y: u64,
+}
+
+fn work() {
+    let mut i = 0;
+    while i < 10 {
+        let p = Point { x: i, y: 1 };
+        println!("{}", p.x + p.y);
+        i = i + 1;
+    }
+}
+
+fn add(x: u64, y: u64) -> u64 {
+    x + y
+}
+
+fn main() {
+    work();
+    println!("{}", add(1, 2));
+}
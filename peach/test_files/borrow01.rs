@@ -0,0 +1,9 @@
+fn main() {
+    let mut x = 5;
+    let r = &x;
+    println!("{}", *r);
+
+    let m = &mut x;
+    *m = *m + 1;
+    println!("{}", x);
+}
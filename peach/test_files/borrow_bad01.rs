@@ -0,0 +1,8 @@
+fn main() {
+    let mut x = 5;
+    let r = &x;
+    let m = &mut x;
+    *m = 6;
+
+    println!("{}", *r);
+}
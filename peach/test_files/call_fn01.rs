@@ -0,0 +1,17 @@
+struct Point {
+    x: u64,
+    y: u64,
+}
+
+fn add(a: u64, b: u64) -> u64 {
+    a + b
+}
+
+fn manhattan(x: u64, y: u64) -> u64 {
+    let p = Point { x, y };
+    add(p.x, p.y)
+}
+
+fn is_less(a: i32, b: i32) -> bool {
+    a < b
+}
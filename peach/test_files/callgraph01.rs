@@ -0,0 +1,34 @@
+mod shapes {
+    pub fn area(w: u64, h: u64) -> u64 {
+        scale(w) * h
+    }
+
+    fn scale(x: u64) -> u64 {
+        x
+    }
+
+    pub fn unused() -> u64 {
+        0
+    }
+}
+
+struct Counter {
+    count: u64,
+}
+
+impl Counter {
+    fn new() -> Counter {
+        Counter { count: 1 }
+    }
+}
+
+use shapes::area;
+
+fn id<T>(x: T) -> T {
+    x
+}
+
+fn main() {
+    let c = Counter::new();
+    println!("{}", id(area(2, 3)) + c.count);
+}
@@ -0,0 +1,5 @@
+fn main() {
+    let start = now_millis();
+    println!("{}", start);
+    println!("{}", elapsed_millis(start));
+}
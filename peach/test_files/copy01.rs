@@ -0,0 +1,26 @@
+#[derive(Clone, Copy)]
+struct Point {
+    x: u64,
+    y: u64,
+}
+
+struct Size {
+    width: u64,
+}
+
+impl Copy for Size {}
+
+fn sum(p: Point) -> u64 {
+    p.x + p.y
+}
+
+fn main() {
+    let a = Point { x: 1, y: 2 };
+    let mut b = a;
+    b.x = 10;
+    let s = Size { width: 4 };
+    let t = s;
+    println!("{}", sum(a));
+    println!("{}", sum(b));
+    println!("{}", a.x + s.width + t.width);
+}
@@ -0,0 +1,13 @@
+struct Name {
+    id: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Person {
+    name: Name,
+}
+
+fn main() {
+    let p = Person { name: Name { id: 1 } };
+    let q = p;
+}
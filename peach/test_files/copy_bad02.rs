@@ -0,0 +1,14 @@
+#[derive(Clone, Copy)]
+struct Handle {
+    id: u64,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        println!("{}", self.id);
+    }
+}
+
+fn main() {
+    let h = Handle { id: 1 };
+}
@@ -0,0 +1,9 @@
+struct Point {
+    x: u64,
+}
+
+fn main() {
+    let a = Point { x: 1 };
+    let b = a;
+    println!("{}", a.x);
+}
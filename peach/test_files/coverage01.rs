@@ -0,0 +1,21 @@
+fn classify(x: u64) -> u64 {
+    if x < 10 {
+        1
+    } else {
+        2
+    }
+}
+
+fn unused() -> u64 {
+    3
+}
+
+fn main() {
+    let mut total = 0;
+    let mut i = 0;
+    while i < 3 {
+        total = total + classify(i);
+        i = i + 1;
+    }
+    println!("{}", total);
+}
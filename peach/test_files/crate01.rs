@@ -0,0 +1,8 @@
+extern crate geometry;
+
+use geometry::shapes::area;
+
+fn main() {
+    println!("{}", area(3));
+    println!("{}", geometry::double(4));
+}
@@ -0,0 +1,5 @@
+extern crate geometry;
+
+fn main() {
+    println!("{}", geometry::helper(5));
+}
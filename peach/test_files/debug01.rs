@@ -0,0 +1,10 @@
+fn add(x: u64, y: u64) -> u64 {
+    let sum = x + y;
+    sum
+}
+
+fn main() {
+    let a: u64 = 1;
+    let b = add(a, 2);
+    println!("{}", b);
+}
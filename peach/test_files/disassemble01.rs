@@ -0,0 +1,17 @@
+fn double(x: u64) -> u64 {
+    x * 2
+}
+
+fn main() {
+    let mut x = 2;
+    while x < 10 {
+        if x < 5 {
+            x = double(x);
+        } else {
+            let x = x + 1;
+            println!("{}", x);
+        }
+        x = x + 3;
+    }
+    println!("{}", x);
+}
@@ -0,0 +1,8 @@
+fn divide(x: u64, y: u64) -> u64 {
+    x / y
+}
+
+fn main() {
+    println!("{}", divide(10, 2));
+    println!("{}", divide(1, 0));
+}
@@ -0,0 +1,24 @@
+struct Noisy {
+    id: u64,
+}
+
+impl Drop for Noisy {
+    fn drop(&mut self) {
+        println!("{}", self.id);
+    }
+}
+
+fn consume(noisy: Noisy) {
+    println!("{}", 30);
+}
+
+fn main() {
+    let a = Noisy { id: 1 };
+    {
+        let b = Noisy { id: 2 };
+        println!("{}", 10);
+    }
+    let c = Noisy { id: 3 };
+    consume(c);
+    println!("{}", 20);
+}
@@ -0,0 +1,48 @@
+struct Inner {
+    id: u64,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        println!("{}", self.id);
+    }
+}
+
+struct Outer {
+    inner: Inner,
+    tag: u64,
+}
+
+impl Drop for Outer {
+    fn drop(&mut self) {
+        println!("{}", self.tag);
+    }
+}
+
+fn consume(inner: Inner) {
+    println!("{}", 9);
+}
+
+fn main() {
+    let mut outer = Outer {
+        inner: Inner { id: 1 },
+        tag: 2,
+    };
+    outer = Outer {
+        inner: Inner { id: 3 },
+        tag: 4,
+    };
+
+    let mut i = 0;
+    while i < 2 {
+        let temp = Inner { id: 6 };
+        i = i + 1;
+    }
+
+    let x = Inner { id: 5 };
+    if i < 5 {
+        consume(x);
+    }
+
+    println!("{}", 8);
+}
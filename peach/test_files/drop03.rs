@@ -0,0 +1,22 @@
+struct Inner {
+    id: u64,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        println!("{}", self.id);
+    }
+}
+
+fn make(id: u64) -> Inner {
+    Inner { id: id }
+}
+
+fn main() {
+    let kept = make(1);
+    make(2);
+    Inner { id: 3 };
+    let moved = make(4);
+    moved;
+    println!("{}", 5);
+}
@@ -0,0 +1,20 @@
+struct Inner {
+    id: u64,
+}
+
+struct Outer {
+    inner: Inner,
+}
+
+impl Drop for Outer {
+    fn drop(&mut self) {
+        println!("{}", 1);
+    }
+}
+
+fn main() {
+    let outer = Outer {
+        inner: Inner { id: 2 },
+    };
+    let inner = outer.inner;
+}
@@ -0,0 +1,11 @@
+fn double(x: u64) -> u64 {
+    x * 2
+}
+
+fn start() {
+    println!("{}", double(21));
+}
+
+fn main() {
+    println!("{}", 0);
+}
@@ -0,0 +1,20 @@
+struct Bar {
+    x: u64,
+}
+
+fn helper(flag: bool) -> u64 {
+    let y: bool = 3;
+    if flag {
+        5
+    } else {
+        6
+    }
+}
+
+fn main() {
+    let a = true + 3;
+    let b = a + 1;
+    let bar = Bar { x: 2 };
+    println!("{}", bar.y);
+    println!("{}", helper(false) + b);
+}
@@ -0,0 +1,14 @@
+struct Bar {
+    x: u64,
+}
+
+fn consume(bar: Bar) -> u64 {
+    let total = bar.x;
+    let again = bar;
+    again.x + bar.x + total
+}
+
+fn main() {
+    println!("{}", consume(Bar { x: 1 }));
+    println!("{}", consume(Bar { x: 2 }));
+}
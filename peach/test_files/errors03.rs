@@ -0,0 +1,7 @@
+fn main() {
+    let mut n: u64 = 1;
+    let x = &mut m;
+    let y = *x + 1;
+    *x = 2;
+    println!("{}", y);
+}
@@ -0,0 +1,15 @@
+fn main() {
+    let x: u64 = 7;
+    println!("{}", x + 1);
+
+    let y: i32 = -3;
+    println!("{}", 2 * y);
+    println!("{}", y - 1);
+    println!("{}", 9 / y);
+
+    let mut count: u32 = 0;
+    while count < 3 {
+        count = count + 1;
+    }
+    println!("{}", count);
+}
@@ -0,0 +1,12 @@
+fn check(value: u64) {
+    if value < 3 {
+        println!("{}", value);
+        exit(3);
+    }
+}
+
+fn main() {
+    check(5);
+    check(1);
+    println!("{}", 9);
+}
@@ -0,0 +1,8 @@
+fn status(base: u64) -> u64 {
+    base + 2
+}
+
+fn main() -> u64 {
+    println!("{}", 1);
+    status(40)
+}
@@ -0,0 +1,5 @@
+fn main() {
+    let count = read_file_u64("counter.txt");
+    println!("{}", count);
+    write_file_u64("counter.txt", count + 1);
+}
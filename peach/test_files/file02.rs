@@ -0,0 +1,3 @@
+fn main() {
+    println!("{}", read_file_u64("../counter.txt"));
+}
@@ -0,0 +1,27 @@
+fn answer() -> u64 {
+    let x: u64 = 6 * 7;
+    x
+}
+
+fn main() {
+    let a = 2 + 3 * 4;
+    println!("{}", a);
+
+    if 1 < 2 {
+        println!("{}", 10 - 1);
+    } else {
+        println!("{}", 0);
+    }
+    if 3 < 2 {
+        println!("{}", 1);
+    }
+    let b = if 5 < 4 { 1 } else { 2 * 3 };
+    println!("{}", b);
+    println!("{}", -(3 - 10));
+    println!("{}", answer());
+
+    let limit = 3;
+    if limit < 5 {
+        println!("{}", limit);
+    }
+}
@@ -0,0 +1,13 @@
+fn spin() -> u64 {
+    let mut count = 0;
+    while true {
+        count = count + 1;
+    }
+    count
+}
+
+fn main() {
+    println!("{}", 1);
+    spin();
+    println!("{}", 2);
+}
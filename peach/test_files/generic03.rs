@@ -0,0 +1,18 @@
+fn first<T>(x: T, y: T) -> T {
+    x
+}
+
+fn second<T>(x: T, y: T) -> T {
+    y
+}
+
+fn sum(a: u64, b: u64) -> u64 {
+    first(a, b) + second(a, b)
+}
+
+fn main() {
+    println!("{}", first(1u64, 2u64));
+    println!("{}", second(1u64, 2u64));
+    println!("{}", first(true, false));
+    println!("{}", sum(3, 4));
+}
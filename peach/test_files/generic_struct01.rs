@@ -0,0 +1,34 @@
+struct Pair<T> {
+    a: T,
+    b: T,
+}
+
+struct Labeled<T> {
+    label: u64,
+    value: T,
+}
+
+fn first<T>(pair: Pair<T>) -> T {
+    pair.a
+}
+
+fn swap<T: Copy>(pair: Pair<T>) -> Pair<T> {
+    Pair {
+        a: pair.b,
+        b: pair.a,
+    }
+}
+
+fn main() {
+    let p: Pair<u64> = Pair { a: 1, b: 2 };
+    let q = swap(p);
+    println!("{}", first(q));
+
+    let flags = Pair { a: true, b: false };
+    let l = Labeled {
+        label: 7,
+        value: flags,
+    };
+    println!("{}", l.label);
+    println!("{}", l.value.b);
+}
@@ -0,0 +1,22 @@
+#[derive(Clone, Copy)]
+struct Pair<T> {
+    a: T,
+    b: T,
+}
+
+fn dup<T>(x: T) -> Pair<T>
+where
+    T: Copy,
+{
+    Pair { a: x, b: x }
+}
+
+fn sum(pair: &Pair<u64>) -> u64 {
+    pair.a + pair.b
+}
+
+fn main() {
+    let p = dup(21u64);
+    let q = p;
+    println!("{}", sum(&p) + sum(&q));
+}
@@ -0,0 +1,8 @@
+struct Pair<T> {
+    a: T,
+    b: T,
+}
+
+fn main() {
+    let p = Pair { a: 1u64, b: true };
+}
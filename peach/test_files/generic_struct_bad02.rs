@@ -0,0 +1,14 @@
+struct Name {
+    id: u64,
+}
+
+fn dup<T>(x: T) -> T
+where
+    T: Copy,
+{
+    x
+}
+
+fn main() {
+    let n = dup(Name { id: 1 });
+}
@@ -0,0 +1,9 @@
+pub mod shapes;
+
+pub fn double(x: u64) -> u64 {
+    crate::helper(x) * 2
+}
+
+fn helper(x: u64) -> u64 {
+    x
+}
@@ -0,0 +1,3 @@
+pub fn area(x: u64) -> u64 {
+    x * super::double(x)
+}
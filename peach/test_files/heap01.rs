@@ -0,0 +1,33 @@
+struct Inner {
+    x: u64,
+}
+
+struct Outer {
+    inner: Inner,
+    y: u64,
+}
+
+fn step(i: u64) -> u64 {
+    let mut outer = Outer {
+        inner: Inner { x: i },
+        y: 1,
+    };
+    outer.inner.x = outer.inner.x + outer.y;
+    outer.inner.x
+}
+
+fn main() {
+    let mut total = 0;
+    let mut i = 0;
+    while i < 5000 {
+        total = total + step(i);
+        i = i + 1;
+    }
+    println!("{}", total);
+
+    let last = Outer {
+        inner: Inner { x: 2 },
+        y: 3,
+    };
+    println!("{}", last);
+}
@@ -0,0 +1,16 @@
+struct Node {
+    depth: u64,
+}
+
+fn grow(n: u64) -> u64 {
+    let node = Node { depth: n };
+    if n < 1 {
+        node.depth
+    } else {
+        grow(n - 1) + node.depth
+    }
+}
+
+fn main() {
+    println!("{}", grow(1000));
+}
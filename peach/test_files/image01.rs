@@ -0,0 +1,33 @@
+struct Pair<T> {
+    a: T,
+    b: T,
+}
+
+struct Noisy {
+    id: u64,
+}
+
+impl Drop for Noisy {
+    fn drop(&mut self) {
+        println!("{}", self.id);
+    }
+}
+
+fn first<T>(pair: Pair<T>) -> T {
+    pair.a
+}
+
+fn never_called() -> u64 {
+    5
+}
+
+fn main() {
+    let p: Pair<u64> = Pair { a: 1, b: 2 };
+    println!("{}", first(p));
+    let _n = Noisy { id: 3 };
+    let mut i = 0;
+    while i < 2 {
+        i = i + 1;
+    }
+    println!("{}", i);
+}
@@ -0,0 +1,31 @@
+mod shapes {
+    pub struct Square {
+        pub side: u64,
+    }
+
+    pub fn area(square: &Square) -> u64 {
+        square.side * square.side
+    }
+
+    pub fn perimeter(square: &Square) -> u64 {
+        square.side * 4
+    }
+
+    pub fn diagonal() -> u64 {
+        0
+    }
+}
+
+mod reexports {
+    pub use shapes::diagonal;
+}
+
+use shapes::{area, Square};
+use shapes::perimeter as outline;
+use shapes::diagonal;
+use shapes::*;
+
+fn main() {
+    let square = Square { side: 3 };
+    println!("{}", area(&square));
+}
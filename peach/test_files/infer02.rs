@@ -0,0 +1,13 @@
+fn takes_u32(x: u32) -> u32 {
+    x + 1
+}
+
+fn main() {
+    let a = 7;
+    let b: u32 = 5;
+    let c = a + b;
+    println!("{}", takes_u32(a));
+    println!("{}", c);
+    let d = 3;
+    println!("{}", d);
+}
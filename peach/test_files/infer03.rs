@@ -0,0 +1,9 @@
+fn main() {
+    let x: u64 = 5000000000;
+    let y: i64 = 3000000000;
+    let z = y - 9000000000;
+
+    println!("{}", x);
+    println!("{}", y);
+    println!("{}", z);
+}
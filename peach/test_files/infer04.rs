@@ -0,0 +1,13 @@
+fn main() {
+    let a: i32 = -2147483648;
+    let b: i64 = -9223372036854775808;
+    let c = -2147483648i32;
+    let d = -9223372036854775808i64;
+    let e = -2147483648;
+
+    println!("{}", a);
+    println!("{}", b);
+    println!("{}", c);
+    println!("{}", d);
+    println!("{}", e);
+}
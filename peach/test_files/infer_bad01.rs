@@ -0,0 +1,9 @@
+fn takes_u32(x: u32) {}
+
+fn takes_u64(x: u64) {}
+
+fn main() {
+    let a = 7;
+    takes_u32(a);
+    takes_u64(a);
+}
@@ -0,0 +1,6 @@
+fn takes_u32(x: u32) {}
+
+fn main() {
+    let n = -1;
+    takes_u32(n);
+}
@@ -0,0 +1,5 @@
+fn main() {
+    let x: u32 = 5000000000;
+
+    println!("{}", x);
+}
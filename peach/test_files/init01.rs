@@ -0,0 +1,16 @@
+fn main() {
+    let total: u64;
+    let mut i = 0;
+    if i < 1 {
+        total = 10;
+    } else {
+        total = 20;
+    }
+    let last;
+    while i < 3 {
+        i = i + 1;
+    }
+    last = i;
+    println!("{}", total);
+    println!("{}", last);
+}
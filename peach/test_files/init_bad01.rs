@@ -0,0 +1,8 @@
+fn main() {
+    let x: u64;
+    let flag = true;
+    if flag {
+        x = 1;
+    }
+    println!("{}", x);
+}
@@ -0,0 +1,9 @@
+fn main() {
+    let mut i = 0;
+    let x;
+    while i < 3 {
+        x = i;
+        i = i + 1;
+    }
+    println!("{}", x);
+}
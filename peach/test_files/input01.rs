@@ -0,0 +1,24 @@
+fn is_wrong(guess: u64, secret: u64) -> bool {
+    if guess < secret {
+        println!("{}", 1);
+        true
+    } else {
+        if secret < guess {
+            println!("{}", 2);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn main() {
+    let secret: u64 = 42;
+    let mut tries: u64 = 1;
+    let mut guess = read_u64();
+    while is_wrong(guess, secret) {
+        tries = tries + 1;
+        guess = read_u64();
+    }
+    println!("{}", tries);
+}
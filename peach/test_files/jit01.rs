@@ -0,0 +1,22 @@
+fn fib(n: u64) -> u64 {
+    if n < 2 {
+        n
+    } else {
+        fib(n - 1) + fib(n - 2)
+    }
+}
+
+fn sum_to(n: i64) -> i64 {
+    let mut total = 0;
+    let mut i = 0;
+    while i < n {
+        i = i + 1;
+        total = total + i;
+    }
+    total
+}
+
+fn main() {
+    println!("{}", fib(20));
+    println!("{}", sum_to(100));
+}
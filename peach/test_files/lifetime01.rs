@@ -0,0 +1,29 @@
+struct Holder<'a> {
+    value: &'a u64,
+}
+
+impl<'a> Holder<'a> {
+    fn get(&self) -> &'a u64 {
+        self.value
+    }
+}
+
+fn longest<'a, 'b: 'a>(x: &'a u64, y: &'b u64) -> &'a u64 {
+    if *x < *y {
+        y
+    } else {
+        x
+    }
+}
+
+fn make<'a>(v: &'a u64) -> Holder<'a> {
+    Holder { value: v }
+}
+
+fn main() {
+    let a = 5;
+    let b = 6;
+    let h = make(&a);
+    println!("{}", *Holder::get(&h));
+    println!("{}", *longest(&a, &b));
+}
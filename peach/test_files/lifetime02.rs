@@ -0,0 +1,25 @@
+struct Counter {
+    count: u64,
+}
+
+impl Counter {
+    fn bump<'a>(&'a mut self) -> &'a mut u64 {
+        self.count = self.count + 1;
+        &mut self.count
+    }
+}
+
+fn pick<'a, 'b>(x: &'a u64, y: &'b u64) -> &'a u64
+where
+    'b: 'a,
+{
+    x
+}
+
+fn main() {
+    let mut c = Counter { count: 1 };
+    let r = Counter::bump(&mut c);
+    *r = *r + 10;
+    let limit: &'static u64 = &c.count;
+    println!("{}", *pick::<'_, '_>(limit, &c.count));
+}
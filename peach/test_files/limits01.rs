@@ -0,0 +1,14 @@
+#[derive(Clone, Copy)]
+struct Point {
+    x: u64,
+    y: u64,
+}
+
+fn main() {
+    let mut count = 0;
+    while count < 1000 {
+        let p = Point { x: count, y: count };
+        count = p.x + 1;
+    }
+    println!("{}", count);
+}
@@ -0,0 +1,19 @@
+struct Point {
+    x: u64,
+    y: u64,
+}
+
+fn add(a: u64, b: u64) -> u64 {
+    a + b
+}
+
+fn broken() -> u64 {
+    let flag = true;
+    flag
+}
+
+fn main() {
+    let p = Point { x: 1, y: 2 };
+    let total = add(p.x, p.y);
+    println!("{}", total);
+}
@@ -0,0 +1,39 @@
+macro_rules! square {
+    ($x:expr) => {
+        $x * $x
+    };
+}
+
+macro_rules! pick {
+    (first $a:expr, $b:expr) => {
+        $a
+    };
+    (second $a:expr, $b:expr) => {
+        $b
+    };
+}
+
+macro_rules! make_getter {
+    ($name:ident, $value:literal) => {
+        fn $name() -> u64 {
+            $value
+        }
+    };
+}
+
+macro_rules! bump {
+    ($var:ident) => {
+        $var = $var + 1;
+    };
+}
+
+make_getter!(seven, 7);
+
+fn main() {
+    let mut x = square!(2 + 1);
+    bump!(x);
+    println!("{}", x);
+    println!("{}", pick!(second seven(), square!(4)));
+    bump! { x }
+    println!("{}", pick!(first x, 0));
+}
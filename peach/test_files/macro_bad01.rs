@@ -0,0 +1,9 @@
+macro_rules! forever {
+    ($x:expr) => {
+        forever!($x)
+    };
+}
+
+fn main() {
+    println!("{}", forever!(1));
+}
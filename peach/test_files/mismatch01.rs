@@ -0,0 +1,26 @@
+struct Counter {
+    count: u64,
+}
+
+impl Counter {
+    fn add(&mut self, amount: u64) {
+        self.count = self.count + amount;
+    }
+}
+
+fn double(x: u64) -> u64 {
+    if x < 10 {
+        return true;
+    }
+    x + x
+}
+
+fn main() {
+    let mut counter = Counter { count: 0 };
+    counter.add(false);
+    let y = double(true);
+    let mut z: u32 = 1;
+    z = false;
+    let w = if true { 1 } else { false };
+    let count: u64 = counter;
+}
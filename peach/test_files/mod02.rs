@@ -1,5 +1,5 @@
 mod foo {
-    mod bar {
+    pub mod bar {
         pub fn baz() {
             println!("{}", 2);
         }
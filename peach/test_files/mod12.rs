@@ -0,0 +1,36 @@
+mod shapes {
+    pub fn two() -> u64 {
+        2
+    }
+
+    pub mod square {
+        use super::two;
+
+        pub fn area(x: u64) -> u64 {
+            x * x
+        }
+
+        pub fn doubled_area(x: u64) -> u64 {
+            two() * self::area(x)
+        }
+    }
+
+    pub mod report {
+        pub fn print(x: u64) {
+            println!("{}", super::square::doubled_area(x));
+            println!("{}", crate::top());
+            println!("{}", super::super::top());
+        }
+    }
+}
+
+use self::shapes::report;
+
+fn top() -> u64 {
+    10
+}
+
+fn main() {
+    report::print(3);
+    crate::shapes::report::print(1);
+}
@@ -0,0 +1,9 @@
+mod mod_companion02;
+
+fn base() -> u64 {
+    20
+}
+
+fn main() {
+    println!("{}", mod_companion02::foo());
+}
@@ -0,0 +1,5 @@
+fn foo() {}
+
+fn main() {
+    super::foo();
+}
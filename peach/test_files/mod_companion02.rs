@@ -0,0 +1,3 @@
+pub fn foo() -> u64 {
+    super::base() + crate::base()
+}
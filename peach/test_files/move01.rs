@@ -0,0 +1,15 @@
+struct Bar {
+    x: u64,
+}
+
+fn consume(bar: Bar) -> u64 {
+    bar.x
+}
+
+fn main() {
+    let mut bar = Bar { x: 3 };
+    let total = consume(bar);
+    bar = Bar { x: total + 4 };
+
+    println!("{}", bar.x);
+}
@@ -0,0 +1,14 @@
+struct Bar {
+    x: u64,
+}
+
+fn consume(bar: Bar) -> u64 {
+    bar.x
+}
+
+fn main() {
+    let bar = Bar { x: 3 };
+    let total = consume(bar);
+
+    println!("{}", bar.x + total);
+}
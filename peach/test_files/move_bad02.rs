@@ -0,0 +1,17 @@
+struct Bar {
+    x: u64,
+}
+
+fn consume(bar: Bar) -> u64 {
+    bar.x
+}
+
+fn main() {
+    let bar = Bar { x: 3 };
+    let mut i = 0;
+
+    while i < 2 {
+        consume(bar);
+        i = i + 1;
+    }
+}
@@ -0,0 +1,12 @@
+mod geometry {
+    pub fn area(width: u64, height: u64) -> u64 {
+        scale(width * height)
+    }
+}
+
+fn main() {
+    let x: u64 = 7;
+    println!("{}", triple(x));
+    println!("{}", triple(5));
+    println!("{}", geometry::area(2, 3));
+}
@@ -0,0 +1,7 @@
+fn main() {
+    let n: u64 = 12;
+    if is_even(n) {
+        println!("{}", half(n));
+    }
+    println!("{}", is_even(7));
+}
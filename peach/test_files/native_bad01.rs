@@ -0,0 +1,4 @@
+fn main() {
+    let flag = true;
+    println!("{}", triple(flag));
+}
@@ -0,0 +1,6 @@
+fn main() {
+    let x: u32 = 3;
+    let y: u32 = 5;
+    println!("{}", y - x);
+    println!("{}", x - y);
+}
@@ -0,0 +1,11 @@
+mod inner {
+    pub fn smallest(a: u64, b: u64) -> u64 {
+        min(a, b)
+    }
+}
+
+fn main() {
+    println!("{}", max(3, 8));
+    println!("{}", inner::smallest(4, 6));
+    println!("{}", answer());
+}
@@ -0,0 +1,8 @@
+// Definitions in the program shadow the ones in the prelude
+fn max(a: u64, b: u64) -> u64 {
+    a + b
+}
+
+fn main() {
+    println!("{}", max(3, 8));
+}
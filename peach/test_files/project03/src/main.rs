@@ -0,0 +1,10 @@
+mod shapes;
+
+mod util {
+    pub mod math;
+}
+
+fn main() {
+    println!("{}", shapes::circle::area(2));
+    println!("{}", util::math::square(3));
+}
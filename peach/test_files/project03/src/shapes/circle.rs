@@ -0,0 +1,3 @@
+pub fn area(r: u64) -> u64 {
+    3 * r * r
+}
@@ -0,0 +1,3 @@
+pub fn square(x: u64) -> u64 {
+    x * x
+}
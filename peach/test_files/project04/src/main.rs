@@ -0,0 +1,3 @@
+mod dup;
+
+fn main() {}
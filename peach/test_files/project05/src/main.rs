@@ -0,0 +1,12 @@
+#[path = "other/renamed.rs"]
+mod helpers;
+
+mod outer {
+    #[path = "deep.rs"]
+    pub mod inner;
+}
+
+fn main() {
+    println!("{}", helpers::inner_value());
+    println!("{}", outer::inner::deep_value());
+}
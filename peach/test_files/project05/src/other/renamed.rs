@@ -0,0 +1,5 @@
+mod sibling;
+
+pub fn inner_value() -> u64 {
+    sibling::value() + 1
+}
@@ -0,0 +1,3 @@
+pub fn deep_value() -> u64 {
+    7
+}
@@ -0,0 +1,7 @@
+fn main() {
+    let mut i = 0;
+    while i < 5 {
+        println!("{}", random_below(100));
+        i = i + 1;
+    }
+}
@@ -0,0 +1,3 @@
+fn main() {
+    println!("{}", random_below(0));
+}
@@ -0,0 +1,28 @@
+fn countdown(n: u64) -> u64 {
+    if n < 1 {
+        n
+    } else {
+        countdown(n - 1) + 1
+    }
+}
+
+fn is_even(n: u64) -> bool {
+    if n < 1 {
+        true
+    } else {
+        is_odd(n - 1)
+    }
+}
+
+fn is_odd(n: u64) -> bool {
+    if n < 1 {
+        false
+    } else {
+        is_even(n - 1)
+    }
+}
+
+fn main() {
+    println!("{}", countdown(10));
+    println!("{}", is_even(7));
+}
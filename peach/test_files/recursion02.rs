@@ -0,0 +1,11 @@
+fn countdown(n: u64) -> u64 {
+    if n < 1 {
+        n
+    } else {
+        countdown(n - 1) + 1
+    }
+}
+
+fn main() {
+    println!("{}", countdown(100000));
+}
@@ -0,0 +1,21 @@
+struct Counter {
+    count: u64,
+}
+
+fn bump(counter: &mut Counter) {
+    counter.count = counter.count + 1;
+}
+
+fn get(counter: &Counter) -> u64 {
+    counter.count
+}
+
+fn main() {
+    let mut counter = Counter { count: 6 };
+    bump(&mut counter);
+    bump(&mut counter);
+    let x = 2;
+    let y = &x;
+
+    println!("{}", get(&counter) + *y);
+}
@@ -0,0 +1,8 @@
+fn main() {
+    let guess = read_u64();
+    let secret = random_below(1000);
+    println!("{}", guess + secret);
+    println!("{}", now_millis());
+    println!("{}", arg_count());
+    println!("{}", arg(0));
+}
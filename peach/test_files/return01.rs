@@ -0,0 +1,23 @@
+fn sign(x: u64) -> u64 {
+    if x < 1 {
+        return 0;
+    } else {
+        return 1;
+    }
+}
+
+fn first_over(limit: u64) -> u64 {
+    let mut i = 0;
+    while i < 100 {
+        if limit < i * i {
+            return i;
+        }
+        i = i + 1;
+    }
+    i
+}
+
+fn main() {
+    println!("{}", sign(0) + sign(5));
+    println!("{}", first_over(50));
+}
@@ -0,0 +1,19 @@
+fn positive(x: u64) -> bool {
+    if 0 < x {
+        return true;
+    }
+}
+
+fn half(x: u64) -> u64 {
+    x < 2
+}
+
+fn show(x: u64) {
+    x
+}
+
+fn main() {
+    positive(1);
+    half(4);
+    show(2);
+}
@@ -0,0 +1,12 @@
+fn check(x: u64) -> u64 {
+    expect_nonzero(x)
+}
+
+fn outer(x: u64) -> u64 {
+    check(x) + 1
+}
+
+fn main() {
+    println!("{}", outer(3));
+    println!("{}", outer(0));
+}
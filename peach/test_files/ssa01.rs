@@ -0,0 +1,31 @@
+fn pick(flag: bool) -> u64 {
+    let mut x = 1;
+    if flag {
+        x = 2;
+    }
+    let y = 7;
+    x + y
+}
+
+fn shrink(mut n: u64) -> u64 {
+    while 10 < n {
+        n = n - 10;
+    }
+    n
+}
+
+fn main() {
+    let mut i = 0;
+    while i < 3 {
+        i = i + 1;
+    }
+    println!("{}", pick(true));
+    println!("{}", pick(false));
+    println!("{}", i);
+    println!("{}", shrink(35));
+
+    let mut z = 5;
+    let r = &mut z;
+    *r = *r + 1;
+    println!("{}", z);
+}
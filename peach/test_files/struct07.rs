@@ -0,0 +1,13 @@
+struct Inner {
+    x: u64,
+}
+
+struct Outer {
+    inner: Inner,
+}
+
+fn main() {
+    let outer = Outer { inner: Inner { x: 7 } };
+
+    println!("{}", outer.inner.x);
+}
@@ -0,0 +1,5 @@
+struct P(u32);
+
+fn main() {
+    let p: P;
+}
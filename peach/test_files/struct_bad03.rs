@@ -0,0 +1,5 @@
+struct W<T>(T);
+
+fn main() {
+    let w = W { x: 5 };
+}
@@ -0,0 +1,39 @@
+fn double(x: u64) -> u64 {
+    x * 2
+}
+
+fn main() {
+    println!("{}", double(21));
+}
+
+#[test]
+fn doubles() {
+    assert_eq!(double(2), 4);
+    assert_ne!(double(2), 5);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expected() -> u64 {
+        7
+    }
+
+    #[test]
+    fn doubles_odd() {
+        println!("{}", double(3));
+        assert_eq!(double(3), expected());
+    }
+
+    #[test]
+    fn stops() {
+        panic!("not yet");
+    }
+
+    #[test]
+    #[cfg(not(test))]
+    fn left_out() {
+        panic!();
+    }
+}
@@ -0,0 +1,6 @@
+fn main() {}
+
+#[test]
+fn takes_input(x: u64) {
+    assert!(x < 10);
+}
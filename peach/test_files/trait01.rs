@@ -0,0 +1,43 @@
+trait Shape {
+    fn sides(&self) -> u64;
+
+    fn corners(&self) -> u64 {
+        self.sides()
+    }
+
+    fn describe(&self) -> u64 {
+        self.sides() * 10 + self.corners()
+    }
+}
+
+struct Triangle {
+    size: u64,
+}
+
+struct Square {
+    size: u64,
+}
+
+impl Shape for Triangle {
+    fn sides(&self) -> u64 {
+        3
+    }
+}
+
+impl Shape for Square {
+    fn sides(&self) -> u64 {
+        4
+    }
+
+    fn corners(&self) -> u64 {
+        self.size
+    }
+}
+
+fn main() {
+    let t = Triangle { size: 1 };
+    let s = Square { size: 9 };
+    println!("{}", t.describe());
+    println!("{}", s.describe());
+    println!("{}", Square::corners(&s));
+}
@@ -0,0 +1,32 @@
+trait Shape {
+    fn sides(&self) -> u64;
+}
+
+trait Named {
+    fn id(&self) -> u64;
+}
+
+struct Square {
+    size: u64,
+}
+
+impl Shape for Square {
+    fn sides(&self) -> u64 {
+        4
+    }
+}
+
+impl Named for Square {
+    fn id(&self) -> u64 {
+        self.size
+    }
+}
+
+fn keep<T: Shape + Named>(x: T) -> T {
+    x
+}
+
+fn main() {
+    let s = keep(Square { size: 7 });
+    println!("{}", s.sides() + s.id());
+}
@@ -0,0 +1,22 @@
+trait Shape {
+    fn sides(&self) -> u64;
+
+    fn corners(&self) -> u64 {
+        self.sides()
+    }
+}
+
+struct Circle {
+    radius: u64,
+}
+
+impl Shape for Circle {
+    fn corners(&self) -> u64 {
+        0
+    }
+}
+
+fn main() {
+    let c = Circle { radius: 1 };
+    println!("{}", c.corners());
+}
@@ -0,0 +1,22 @@
+trait Shape {
+    fn sides(&self) -> u64;
+}
+
+struct Circle {
+    radius: u64,
+}
+
+impl Shape for Circle {
+    fn sides(&self) -> u64 {
+        0
+    }
+
+    fn radius(&self) -> u64 {
+        self.radius
+    }
+}
+
+fn main() {
+    let c = Circle { radius: 1 };
+    println!("{}", c.sides());
+}
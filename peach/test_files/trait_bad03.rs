@@ -0,0 +1,16 @@
+trait Shape {
+    fn sides(&self) -> u64;
+}
+
+struct Circle {
+    radius: u64,
+}
+
+fn keep<T: Shape>(x: T) -> T {
+    x
+}
+
+fn main() {
+    let c = keep(Circle { radius: 1 });
+    println!("{}", c.radius);
+}
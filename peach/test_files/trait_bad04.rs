@@ -0,0 +1,28 @@
+trait Shape {
+    fn size(&self) -> u64;
+}
+
+trait Box {
+    fn size(&self) -> u64;
+}
+
+struct Square {
+    side: u64,
+}
+
+impl Shape for Square {
+    fn size(&self) -> u64 {
+        self.side
+    }
+}
+
+impl Box for Square {
+    fn size(&self) -> u64 {
+        self.side * self.side
+    }
+}
+
+fn main() {
+    let s = Square { side: 3 };
+    println!("{}", s.size());
+}
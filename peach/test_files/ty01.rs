@@ -0,0 +1,27 @@
+mod shapes {
+    pub struct Point {
+        pub x: i64,
+        pub y: i64,
+    }
+}
+
+struct Pair<T> {
+    a: T,
+    b: T,
+}
+
+fn first<T: Copy>(pair: &Pair<T>) -> T {
+    pair.a
+}
+
+fn main() {
+    let mut p = shapes::Point { x: 1, y: 2 };
+    let r = &mut p;
+    r.y = 5;
+    let flags = Pair { a: true, b: false };
+    let n = 3;
+    let n = n + 4u32;
+    println!("{}", p.y);
+    println!("{}", first(&flags));
+    println!("{}", n);
+}
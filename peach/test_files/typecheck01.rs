@@ -0,0 +1,16 @@
+fn unsigned(x: u32) -> u32 {
+    if x {
+        let y = -x;
+    }
+    x
+}
+
+fn main() {
+    let a = true + false;
+    let b: u64 = 3;
+    let c = true < false;
+    while b {
+        let d = b * c;
+    }
+    let e = a + 1;
+}
@@ -0,0 +1,17 @@
+fn pick(flag: bool, value: u32) -> u32 {
+    let x = if flag { value } else { 10 };
+    x
+}
+
+fn main() {
+    let y;
+    if pick(true, 1) < 5 {
+        y = 2;
+    } else {
+        y = 3;
+    }
+    let z = if y < 3 { 4 } else { 5u64 };
+    println!("{}", pick(false, 1));
+    println!("{}", y);
+    println!("{}", z);
+}
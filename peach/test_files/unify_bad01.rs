@@ -0,0 +1,8 @@
+fn main() {
+    let flag = true;
+    let a = if flag {
+        1
+    } else {
+        flag
+    };
+}
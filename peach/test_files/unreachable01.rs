@@ -0,0 +1,30 @@
+struct Counter {
+    count: u64,
+}
+
+fn pick(flag: bool) -> u64 {
+    if flag {
+        return 1;
+    } else {
+        return 2;
+    }
+    println!("{}", 3);
+    println!("{}", 4);
+}
+
+fn first(limit: u64) -> u64 {
+    let mut i = 0;
+    while i < limit {
+        return i;
+        i = i + 1;
+    }
+    let counter = Counter { count: limit };
+    return counter.count;
+    counter.count + 1
+}
+
+fn main() {
+    println!("{}", pick(true) + pick(false));
+    println!("{}", first(0));
+    println!("{}", first(5));
+}
@@ -0,0 +1,20 @@
+mod foo {
+    fn secret() -> u64 {
+        3
+    }
+
+    pub fn open() -> u64 {
+        secret()
+    }
+
+    pub mod inner {
+        pub fn peek() -> u64 {
+            super::secret()
+        }
+    }
+}
+
+fn main() {
+    println!("{}", foo::open() + foo::inner::peek());
+    println!("{}", foo::secret());
+}
@@ -0,0 +1,11 @@
+mod foo {
+    fn secret() -> u64 {
+        3
+    }
+}
+
+use foo::secret;
+
+fn main() {
+    println!("{}", secret());
+}
@@ -0,0 +1,22 @@
+struct Bar {
+    x: u64,
+}
+
+impl Bar {
+    fn get(&self) -> u64 {
+        self.x
+    }
+
+    fn unused_method(&self) -> u64 {
+        0
+    }
+}
+
+fn unused_fn() {}
+
+fn main() {
+    let unused = 3;
+    let _ignored = 4;
+    let bar = Bar { x: 5 };
+    println!("{}", bar.get());
+}
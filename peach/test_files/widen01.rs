@@ -0,0 +1,34 @@
+struct Total {
+    sum: u64,
+}
+
+impl Total {
+    fn add(&mut self, amount: u64) {
+        self.sum = self.sum + amount;
+    }
+}
+
+fn double(x: u64) -> u64 {
+    x + x
+}
+
+fn widen(x: u32) -> u64 {
+    x
+}
+
+fn offset(x: i32) -> i64 {
+    return x;
+}
+
+fn main() {
+    let small = 7u32;
+    let mut total = Total { sum: 0 };
+    total.add(small);
+    let mut big: u64 = small;
+    big = big + 1;
+    big = small;
+    println!("{}", double(small));
+    println!("{}", widen(small) + big);
+    println!("{}", offset(-3i32));
+    println!("{}", total.sum);
+}
@@ -1,164 +1,661 @@
 //! Peach - a lightweight Rust *thing*
 //!
-//! Peach has three modes:
-//!   * "build" - builds given project to a binary (uses the system C compiler)
+//! Peach has these modes:
+//!   * "bench" - runs a function of the project over and over, then reports how long it took, how many instructions it
+//!     evaluated, and how many objects it allocated
+//!   * "build" - builds given project to a binary (uses the system C compiler, or the one `CC` names)
+//!   * "check" - converts and typechecks the project's code that main uses, without running it.  With
+//!     `--emit=ast` or `--emit=lowering`, it prints the syntax tree of main, or the bytecode each of its statements
+//!     is converted to, instead.  A function can be named after the flag to show it rather than main.  With
+//!     `--emit=json`, it prints the engine's scopes and definitions as JSON, for other tools.
+//!   * "emit-rust" - prints the project's bytecode translated back to Rust source
+//!   * "emit-llvm" - prints the project's bytecode lowered to LLVM IR (only with the `llvm` feature)
+//!   * "emit-types" - prints the project's bytecode, with the types on the stack before and after each instruction
+//!   * "image" - converts the project to bytecode and saves it as an image, so it can be shipped without its source
 //!   * "run" - converts the project to bytecode, which it runs immediately
-//!   * "repl" - creates a repl to interact with the code directly
+//!   * "run-image" - runs an image saved by "image", without the project's source
+//!   * "profile" - runs the project like "run", then reports how often each instruction and function ran.
+//!     `--collapsed=<file>` also writes the call stacks it ran, in the collapsed format flamegraph tools read.
+//!   * "coverage" - runs the project like "run", then prints its source with how many times each line ran.
+//!     `--lcov=<file>` also writes the coverage as an lcov tracefile, which "test" can write for its tests too.
+//!   * "record" - runs the project like "run", and writes what it read from outside to a log
+//!   * "replay" - runs the project again, feeding it what was read in a "record" log
+//!   * "test" - runs the project's `#[test]` functions, then reports which of them passed
+//!   * "repl" - creates a repl to interact with the code directly.  Lines can be edited, earlier ones are recalled
+//!     with the arrow keys, and Ctrl-C stops a line that's being evaluated.  `:load` loads a file's items into it,
+//!     and loads them again once the file has changed.
+//!   * "-e" - evaluates a single expression, like `peach -e "1 + 2 * 3"`, and prints its value with its type
+//!   * "lsp" - serves an editor over stdin and stdout as a language server, with diagnostics when files are opened or
+//!     saved, go to definition, and hover types
+//!   * "dap" - serves an editor over stdin and stdout as a debug adapter, which runs the program it launches with
+//!     breakpoints, stepping, and the variables of each call
+//!
+//! Errors and warnings are printed to stderr.  Peach exits with 1 if the project has errors, if running it stops
+//! with one, or if any of its tests fail, and with 2 if it isn't given a command it knows.  When "run" or
+//! "run-image" finishes, peach exits with the program's own status: the integer its main returns, or the code it
+//! passes to `exit`.
+//!
+//! If `PEACH_CACHE_DIR` is set, the functions of the project are cached in that directory once they're converted, and
+//! loaded from it on later runs while the project's files are unchanged.
 
+extern crate ctrlc;
 extern crate peach;
+extern crate rustyline;
 extern crate syn;
 
-use peach::{compile_bytecode, Bytecode, BytecodeEngine, EvalEngine, VarStack};
+use peach::{
+    build_executable, builtin_type, emit_rust, is_incomplete, BenchLength, BytecodeEngine,
+    DebugAdapter, EngineConfig, EvalEngine, LanguageServer, PeachError, Prelude, Recording,
+    ReplSession,
+};
+#[cfg(feature = "llvm")]
+use peach::emit_llvm;
 
-use std::collections::HashMap;
-use std::path::Path;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::cell::RefCell;
+use std::env;
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-/// Run a peach repl on the commandline.
-pub fn repl() {
-    use std::io::{stdin, stdout, Write};
+/// The commands that need a filename or project directory
+const FILE_COMMANDS: &[&str] = &[
+    "bench",
+    "build",
+    "check",
+    "emit-rust",
+    "emit-llvm",
+    "emit-types",
+    "image",
+    "run",
+    "run-image",
+    "profile",
+    "coverage",
+    "record",
+    "replay",
+    "test",
+];
 
-    let mut bc = BytecodeEngine::new();
-    let mut ee = EvalEngine::new();
-    let mut var_stack = VarStack::new();
-    let mut var_lookup: HashMap<usize, usize> = HashMap::new();
-    let mut show_type = false;
-    let mut show_bytecode = false;
+/// How long a benchmark runs for when the command doesn't say
+const DEFAULT_BENCH_LENGTH: BenchLength = BenchLength::Duration(Duration::from_secs(3));
 
-    println!("peach repl (:h for help, :q to quit)");
-    loop {
-        let mut bytecode: Vec<Bytecode> = vec![];
-        let mut input = String::new();
+/// Reads how long a benchmark runs for, which is either a number of iterations, like `100`, or a number of seconds,
+/// like `2.5s`
+fn parse_bench_length(text: &str) -> Option<BenchLength> {
+    if let Some(seconds) = text.strip_suffix('s') {
+        return match seconds.parse::<f64>() {
+            Ok(seconds) if seconds > 0.0 => {
+                Some(BenchLength::Duration(Duration::from_secs_f64(seconds)))
+            }
+            _ => None,
+        };
+    }
+    match text.parse::<u64>() {
+        Ok(iterations) if iterations > 0 => Some(BenchLength::Iterations(iterations)),
+        _ => None,
+    }
+}
 
-        print!("> ");
-        let _ = stdout().flush();
-        stdin().read_line(&mut input).expect("Could not read input");
-        input = input.trim().to_string();
+/// How many instructions each line entered in the repl may run, so that a loop which never ends doesn't hang it
+const REPL_FUEL: usize = 10_000_000;
 
-        if input == ":quit" || input == ":q" {
-            break;
-        }
+/// Prints the type of the expression, without evaluating it
+fn show_expr_type(session: &mut ReplSession, expr: &str) {
+    match session.expr_type(expr) {
+        Ok(type_id) => println!("{}: {}", expr, session.engine.ty(type_id)),
+        Err(e) => println!("Error: {}", e),
+    }
+}
 
-        if input == ":type" || input == ":t" {
-            show_type ^= true;
-            println!("show type: {}", show_type);
-            continue;
-        }
+/// Prints the function's bytecode with the types on the stack, converting the function first if it hasn't been
+fn show_fn_bytecode(bc: &mut BytecodeEngine, fn_name: &str) {
+    match bc.process_fn(fn_name, 0) {
+        Ok(_) => match bc.get_fn(fn_name, 0) {
+            Ok(fun) => print!("{}", bc.typed_listing(fun)),
+            Err(e) => println!("Error: {}", e),
+        },
+        Err(e) => println!("Error: {}", e),
+    }
+}
 
-        if input == ":bytecode" || input == ":b" {
-            show_bytecode ^= true;
-            println!("show bytecode: {}", show_bytecode);
-            continue;
+/// Prints the variables the session can name, with their types and, once they've been given them, their values
+fn show_vars(session: &ReplSession) {
+    for (var_id, var) in session.var_stack().visible_vars() {
+        let ty = session.engine.ty(var.ty);
+        match session.var_value(var_id) {
+            Some(value) => println!("{}: {} = {}", var.ident, ty, value),
+            None => println!("{}: {}", var.ident, ty),
         }
+    }
+}
 
-        if input == ":stack" || input == ":s" {
-            println!("{:?}", ee.value_stack);
-            continue;
-        }
+/// Completes names at the repl's prompt from the session
+struct ReplHelper {
+    session: Rc<RefCell<ReplSession>>,
+}
 
-        if input == ":help" || input == ":h" {
-            println!(":h(elp) - print this help message");
-            println!(":t(ype) - print the result type");
-            println!(":b(ytecode) - print the bytecode");
-            println!(":s(tack) - print the contents of the stack");
-            println!(":q(uit) - quit repl");
-            continue;
-        }
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.session.borrow_mut().completions(line, pos))
+    }
+}
 
-        if let Ok(type_id) = bc.process_raw_expr_str(&input, &mut bytecode, &mut var_stack) {
-            if show_type {
-                println!("type: {}", bc.printable_name(type_id));
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+type ReplEditor = Editor<ReplHelper, DefaultHistory>;
+
+/// Where the repl keeps the lines entered in earlier sessions, if there's a home directory to keep them in
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(|home| Path::new(&home).join(".peach_history"))
+}
+
+/// Reads a line of repl input, along with the lines that continue it while it's incomplete.  Gives `None` once there's
+/// no more input.
+fn read_input(editor: &mut ReplEditor) -> Option<String> {
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !input.is_empty() {
+                    input.push('\n');
+                }
+                input.push_str(&line);
+                if !is_incomplete(&input) {
+                    return Some(input.trim().to_string());
+                }
             }
-            if show_bytecode {
-                println!("bytecode: {:?}", bytecode);
+            // Like a shell, Ctrl-C throws away what's been typed
+            Err(ReadlineError::Interrupted) => input.clear(),
+            // What's been typed is still converted, so that the reason it's incomplete is reported
+            Err(ReadlineError::Eof) if !input.is_empty() => return Some(input.trim().to_string()),
+            Err(ReadlineError::Eof) => return None,
+            Err(e) => {
+                eprintln!("Could not read input: {}", e);
+                return None;
             }
-            ee.eval_block_bytecode(&bc, &bytecode, &mut var_lookup);
+        }
+    }
+}
 
-            // This funny little trick should, in theory, let us pop off temporaries without popping off our variables
-            let last = if ee.value_stack.len() > var_lookup.len() {
-                ee.value_stack.pop().unwrap()
-            } else {
-                ee.value_stack.last().unwrap().clone()
-            };
+/// Run a peach repl on the commandline.
+pub fn repl() {
+    let session = ReplSession::new().expect("Could not load the prelude");
+    let session = Rc::new(RefCell::new(session));
 
-            println!("{}", last);
-        } else {
-            match bc.process_raw_stmt_str(&input, &mut bytecode, &mut var_stack) {
-                Ok(_) => {
-                    if show_bytecode {
-                        println!("bytecode: {:?}", bytecode);
-                    }
-                    ee.eval_block_bytecode(&bc, &bytecode, &mut var_lookup);
-                }
-                Err(_) => {
-                    input.push(';');
-
-                    match bc.process_raw_stmt_str(&input, &mut bytecode, &mut var_stack) {
-                        Ok(_) => {
-                            if show_bytecode {
-                                println!("bytecode: {:?}", bytecode);
-                            }
-                            ee.eval_block_bytecode(&bc, &bytecode, &mut var_lookup);
-                        }
-                        Err(e) => {
-                            println!("Error: {}", e);
-                        }
+    let mut editor = ReplEditor::new().unwrap_or_else(|e| fail(e));
+    editor.set_helper(Some(ReplHelper {
+        session: session.clone(),
+    }));
+    let history = history_path();
+    if let Some(ref history) = history {
+        // There's no history the first time the repl is run
+        let _ = editor.load_history(history);
+    }
+
+    // The editor reads Ctrl-C itself while it's waiting for a line, so the handler only sees it during evaluation
+    let cancel = Arc::new(AtomicBool::new(false));
+    let handler_cancel = cancel.clone();
+    match ctrlc::set_handler(move || handler_cancel.store(true, Ordering::SeqCst)) {
+        Ok(()) => session.borrow_mut().eval.cancel = Some(cancel),
+        Err(e) => eprintln!("Ctrl-C won't stop evaluation: {}", e),
+    }
+    let mut show_type = false;
+    let mut show_bytecode = false;
+
+    println!("peach repl (:h for help, :q to quit)");
+    while let Some(input) = read_input(&mut editor) {
+        if input.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(input.as_str());
+        let mut session = session.borrow_mut();
+
+        // Commands ask about the session rather than evaluating anything
+        if input.starts_with(':') {
+            let (command, arg) = match input.find(char::is_whitespace) {
+                Some(idx) => (&input[..idx], input[idx..].trim()),
+                None => (&input[..], ""),
+            };
+            match (command, arg) {
+                (":quit", _) | (":q", _) => break,
+                (":type", "") | (":t", "") => {
+                    show_type ^= true;
+                    println!("show type: {}", show_type);
+                }
+                (":type", expr) | (":t", expr) => show_expr_type(&mut session, expr),
+                (":bytecode", "") | (":b", "") => {
+                    show_bytecode ^= true;
+                    println!("show bytecode: {}", show_bytecode);
+                }
+                (":bytecode", fn_name) | (":b", fn_name) => {
+                    show_fn_bytecode(&mut session.engine, fn_name)
+                }
+                (":vars", _) | (":v", _) => show_vars(&session),
+                (":defs", _) | (":d", _) => {
+                    for (name, definition_id) in session.engine.scope_names(0) {
+                        let kind = session.engine.definition(definition_id).kind();
+                        println!("{} {}", kind, name);
                     }
                 }
+                (":stack", _) | (":s", _) => println!("{:?}", session.eval.value_stack),
+                (":load", "") | (":l", "") => println!("Usage: :load <path>"),
+                (":load", path) | (":l", path) => match session.load_file(path) {
+                    Ok(()) => println!("Loaded {}", path),
+                    Err(e) => println!("Error: {}", e),
+                },
+                (":help", _) | (":h", _) => {
+                    println!(":h(elp) - print this help message");
+                    println!(":t(ype) <expr> - print the expression's type without evaluating it");
+                    println!(":t(ype) - print the type of each result");
+                    println!(":b(ytecode) <fn> - print the function's bytecode, with the types on the stack");
+                    println!(":b(ytecode) - print the bytecode of each line");
+                    println!(":v(ars) - print the variables, with their types and values");
+                    println!(":d(efs) - print the functions, structs and other items that have been defined");
+                    println!(":s(tack) - print the contents of the stack");
+                    println!(":l(oad) <path> - load the file's items, or reload them");
+                    println!(":q(uit) - quit repl");
+                }
+                _ => println!("Unknown command: {} (:h for help)", command),
             }
+            continue;
+        }
+
+        session.eval.fuel = Some(REPL_FUEL);
+        match session.eval_line(&input) {
+            Ok(output) => {
+                if let (true, Some(ty)) = (show_type, output.ty) {
+                    println!("type: {}", session.engine.ty(ty));
+                }
+                if show_bytecode {
+                    println!("bytecode: {:?}", output.bytecode);
+                }
+                if let (Some(value), Some(var)) = (output.value, output.result_var) {
+                    println!("{} = {}", var, value);
+                }
+            }
+            Err(e @ PeachError::Runtime(_)) => println!("Evaluation stopped: {}", e),
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+
+    if let Some(ref history) = history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("Could not save the history to {}: {}", history.display(), e);
         }
     }
 }
 
-fn process(fname: &str, start_fn: &str) -> BytecodeEngine {
-    let mut bc = BytecodeEngine::new();
+/// Loads the file or project, with its tests and `#[cfg(test)]` items if `tests` is set
+fn load(fname: &str, tests: bool) -> Result<BytecodeEngine, PeachError> {
+    let mut bc = BytecodeEngine::with_prelude(&Prelude::standard())?;
+    bc.set_config(EngineConfig {
+        tests,
+        ..EngineConfig::default()
+    });
+
+    // Step 1: Load up the parsed file so that we can lazily convert it.  A directory is a project laid out like a
+    // Cargo package.
+    bc.load_path(fname)?;
 
-    // Step 1: Load up the parsed file so that we can lazily convert it
-    //TODO: FIXME: we should probably take &str or Path
-    let path = Path::new(fname).canonicalize().unwrap();
-    bc.set_project_root(path.parent().unwrap().to_str().unwrap());
-    bc.load_file(path.file_name().unwrap().to_str().unwrap());
+    Ok(bc)
+}
+
+fn process(fname: &str, start_fn: &str) -> Result<BytecodeEngine, PeachError> {
+    let mut bc = load(fname, false)?;
+    if let Some(cache_dir) = env::var_os("PEACH_CACHE_DIR") {
+        bc.set_cache_dir(&cache_dir.to_string_lossy());
+    }
 
     // Step 2: Convert to bytecode from the given location
     // We assume the starting function is found in scope 0, the starting scope
-    bc.process_fn(start_fn, 0);
+    bc.process_fn(start_fn, 0)?;
     //println!("{:#?}", bc.processed_fns);
 
-    bc
+    for warning in bc.warnings() {
+        eprintln!("{}", warning);
+    }
+
+    Ok(bc)
 }
 
-fn main() {
-    use std::env;
+/// Evaluates the program's main, then exits with the status it gives, either by returning an integer or by calling
+/// `exit`
+fn run_main(ee: &mut EvalEngine, bc: &BytecodeEngine) {
+    match ee.eval_program_exit_code(bc, "main") {
+        Ok(0) => {}
+        Ok(code) => exit(code),
+        Err(e) => fail(e),
+    }
+}
+
+/// Takes an option like `--lcov=<file>` from the start of the arguments, giving the file to write a report to
+fn take_file_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+    let path = args.first()?.strip_prefix(option)?.to_string();
+    args.remove(0);
+    Some(path)
+}
 
+fn write_report(path: &str, report: &str) {
+    if let Err(e) = std::fs::write(path, report) {
+        fail(format!("Could not write {}: {}", path, e));
+    }
+}
+
+/// Prints the error and exits with a failure status
+fn fail<E: Display>(e: E) -> ! {
+    eprintln!("Error: {}", e);
+    exit(1)
+}
+
+fn usage() {
+    eprintln!("Usage:");
+    eprintln!("   bench <filename or project directory> <function> [iterations or seconds, like 100 or 2.5s]");
+    eprintln!("   build <filename or project directory> [executable]");
+    eprintln!("   check <filename or project directory> [--emit=ast|--emit=lowering [function]|--emit=json]");
+    eprintln!("   emit-rust <filename or project directory>");
+    #[cfg(feature = "llvm")]
+    eprintln!("   emit-llvm <filename or project directory>");
+    eprintln!("   emit-types <filename or project directory>");
+    eprintln!("   image <filename or project directory> [image file]");
+    eprintln!("   run <filename or project directory> [arguments...]");
+    eprintln!("   run-image <image file> [arguments...]");
+    eprintln!("   profile <filename or project directory> [--collapsed=<file>] [arguments...]");
+    eprintln!("   coverage <filename or project directory> [--lcov=<file>] [arguments...]");
+    eprintln!("   record <filename or project directory> <log file> [arguments...]");
+    eprintln!("   replay <filename or project directory> <log file>");
+    eprintln!("   test <filename or project directory> [--lcov=<file>]");
+    eprintln!("   repl");
+    eprintln!("   -e <expression>");
+    eprintln!("   lsp");
+    eprintln!("   dap");
+    eprintln!("   capabilities");
+}
+
+fn main() {
     let mut args = env::args();
     let _ = args.next(); // executable name
 
     match (args.next(), args.next()) {
         (Some(ref cmd), Some(ref fname)) if cmd == "build" => {
-            let bc = process(&fname, "main");
-            let compile_result = compile_bytecode(&bc, &fname);
-            match compile_result {
-                Ok(msg) => println!("\nCompile succeeded: {}", msg),
-                Err(e) => println!("\nCompile failed: {}", e),
+            let bc = process(fname, "main").unwrap_or_else(|e| fail(e));
+            // Unless it's given, the executable goes in the current directory, named after the file or project
+            let output = match args.next() {
+                Some(output) => output,
+                None => match Path::new(fname).file_stem() {
+                    Some(stem) => stem.to_string_lossy().into_owned(),
+                    None => "main".to_string(),
+                },
+            };
+            match build_executable(&bc, "main", Path::new(&output)) {
+                Ok(path) => println!("Build succeeded: {}", path.display()),
+                Err(e) => {
+                    eprintln!("Build failed: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "bench" => {
+            let (fn_name, length) = match (args.next(), args.next()) {
+                (Some(fn_name), None) => (fn_name, DEFAULT_BENCH_LENGTH),
+                (Some(fn_name), Some(length)) => match parse_bench_length(&length) {
+                    Some(length) => (fn_name, length),
+                    None => {
+                        eprintln!("Expected a number of iterations or seconds, not {}", length);
+                        exit(2);
+                    }
+                },
+                (None, _) => {
+                    eprintln!("Usage: bench <filename or project directory> <function> [iterations or seconds]");
+                    exit(2);
+                }
+            };
+            let bc = process(fname, &fn_name).unwrap_or_else(|e| fail(e));
+            let mut ee = EvalEngine::new();
+            // What the function prints would only get in the way of the report
+            ee.output = Some(Box::new(std::io::sink()));
+
+            let benchmark = ee
+                .bench_fn(&bc, &fn_name, length)
+                .unwrap_or_else(|e| fail(e));
+            print!("{}", benchmark);
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "check" => {
+            let emit = args.next();
+            if let Some(ref emit) = emit {
+                if emit != "--emit=ast" && emit != "--emit=lowering" && emit != "--emit=json" {
+                    eprintln!(
+                        "Expected --emit=ast, --emit=lowering, or --emit=json, not {}",
+                        emit
+                    );
+                    exit(2);
+                }
             }
+            let fn_name = args.next().unwrap_or_else(|| "main".to_string());
+
+            let mut bc = process(fname, &fn_name).unwrap_or_else(|e| fail(e));
+            let dump = match emit.as_deref() {
+                Some("--emit=ast") => bc.dump_ast(&fn_name),
+                Some("--emit=json") => Ok(format!("{}\n", bc.to_json())),
+                Some(_) => bc.dump_lowering(&fn_name),
+                None => {
+                    println!("Check succeeded: {}", fname);
+                    return;
+                }
+            };
+            print!("{}", dump.unwrap_or_else(|e| fail(e)));
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "emit-rust" => {
+            let result = process(fname, "main").and_then(|bc| emit_rust(&bc, "main"));
+            print!("{}", result.unwrap_or_else(|e| fail(e)));
+        }
+        #[cfg(feature = "llvm")]
+        (Some(ref cmd), Some(ref fname)) if cmd == "emit-llvm" => {
+            let result = process(fname, "main").and_then(|bc| emit_llvm(&bc, "main"));
+            print!("{}", result.unwrap_or_else(|e| fail(e)));
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "emit-types" => {
+            let bc = process(fname, "main").unwrap_or_else(|e| fail(e));
+            print!("{}", bc.typed_listings());
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "image" => {
+            let bc = process(fname, "main").unwrap_or_else(|e| fail(e));
+            // Like an executable, the image goes in the current directory unless it's given
+            let output = match args.next() {
+                Some(output) => output,
+                None => match Path::new(fname).file_stem() {
+                    Some(stem) => format!("{}.img", stem.to_string_lossy()),
+                    None => "main.img".to_string(),
+                },
+            };
+            bc.save_image(&output).unwrap_or_else(|e| fail(e));
+            println!("Image saved: {}", output);
         }
         (Some(ref cmd), Some(ref fname)) if cmd == "run" => {
-            let bc = process(&fname, "main");
+            let bc = process(fname, "main").unwrap_or_else(|e| fail(e));
+            let mut ee = EvalEngine::new();
+            // Anything after the file name is passed on to the program
+            ee.args = args.collect();
+
+            println!("Eval result:");
+            run_main(&mut ee, &bc);
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "run-image" => {
+            let bc = BytecodeEngine::load_image(fname).unwrap_or_else(|e| fail(e));
+            let mut ee = EvalEngine::new();
+            ee.args = args.collect();
+
+            println!("Eval result:");
+            run_main(&mut ee, &bc);
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "profile" => {
+            let mut args: Vec<String> = args.collect();
+            let collapsed = take_file_option(&mut args, "--collapsed=");
+            let bc = process(fname, "main").unwrap_or_else(|e| fail(e));
+            let mut ee = EvalEngine::new();
+            ee.args = args;
+
+            let profile = ee.profile_program(&bc, "main");
+            if let Some(ref halted) = ee.halted {
+                eprintln!("Error: {}", halted);
+            }
+            println!("\n{}", profile.report());
+            if let Some(ref path) = collapsed {
+                write_report(path, &profile.collapsed());
+            }
+            if ee.halted.is_some() {
+                exit(1);
+            }
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "coverage" => {
+            let mut args: Vec<String> = args.collect();
+            let lcov = take_file_option(&mut args, "--lcov=");
+            let bc = process(fname, "main").unwrap_or_else(|e| fail(e));
+            let mut ee = EvalEngine::new();
+            ee.args = args;
+
+            let coverage = ee.cover_program(&bc, "main");
+            if let Some(ref halted) = ee.halted {
+                eprintln!("Error: {}", halted);
+            }
+            print!("\n{}", coverage.annotate());
+            if let Some(ref path) = lcov {
+                write_report(path, &coverage.lcov());
+            }
+            if ee.halted.is_some() {
+                exit(1);
+            }
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "record" || cmd == "replay" => {
+            let log_fname = match args.next() {
+                Some(log_fname) => log_fname,
+                None => {
+                    eprintln!("Usage: {} <filename or project directory> <log file>", cmd);
+                    exit(2);
+                }
+            };
+            let bc = process(fname, "main").unwrap_or_else(|e| fail(e));
             let mut ee = EvalEngine::new();
 
             println!("Eval result:");
-            ee.eval_program(&bc, "main");
+            if cmd == "record" {
+                ee.args = args.collect();
+                let recording = ee.record_program(&bc, "main");
+                // The log is written even if the program stopped with an error, so that it can be replayed
+                if let Err(e) = std::fs::write(&log_fname, recording.to_log()) {
+                    fail(format!("Could not write {}: {}", log_fname, e));
+                }
+                if let Some(ref halted) = ee.halted {
+                    fail(halted);
+                }
+            } else {
+                let recording = match std::fs::read_to_string(&log_fname) {
+                    Ok(log) => Recording::from_log(&log),
+                    Err(e) => Err(PeachError::Io(format!("{}: {}", log_fname, e))),
+                };
+                let result =
+                    recording.and_then(|recording| ee.replay_program(&bc, "main", &recording));
+                if let Err(e) = result {
+                    fail(e);
+                }
+            }
+        }
+        (Some(ref cmd), Some(ref fname)) if cmd == "test" => {
+            let mut options: Vec<String> = args.collect();
+            let lcov = take_file_option(&mut options, "--lcov=");
+            let mut bc = load(fname, true).unwrap_or_else(|e| fail(e));
+            let report = match lcov {
+                Some(ref path) => {
+                    let (report, coverage) =
+                        bc.run_tests_with_coverage().unwrap_or_else(|e| fail(e));
+                    write_report(path, &coverage.lcov());
+                    report
+                }
+                None => bc.run_tests().unwrap_or_else(|e| fail(e)),
+            };
+            for warning in bc.warnings() {
+                eprintln!("{}", warning);
+            }
+
+            print!("{}", report);
+            if report.failed() > 0 {
+                exit(1);
+            }
+        }
+        (Some(ref cmd), Some(ref expr)) if cmd == "-e" => {
+            let mut bc =
+                BytecodeEngine::with_prelude(&Prelude::standard()).unwrap_or_else(|e| fail(e));
+            let mut ee = EvalEngine::new();
+            let (value, ty) = ee.eval_expr_str(&mut bc, expr).unwrap_or_else(|e| fail(e));
+            // What the expression printed is all there is to show for one of type ()
+            if ty != builtin_type::VOID {
+                println!("{}: {}", value, bc.ty(ty));
+            }
+        }
+        (Some(ref cmd), None) if cmd == "-e" => {
+            eprintln!("-e needs an expression to evaluate");
+            usage();
+            exit(2);
         }
         (Some(ref cmd), _) if cmd == "repl" => {
             repl();
         }
+        (Some(ref cmd), _) if cmd == "lsp" => {
+            let stdin = io::stdin();
+            let status = LanguageServer::new()
+                .serve(&mut stdin.lock(), &mut io::stdout())
+                .unwrap_or_else(|e| fail(e));
+            exit(status);
+        }
+        (Some(ref cmd), _) if cmd == "dap" => {
+            DebugAdapter::new(io::BufReader::new(io::stdin()), io::stdout())
+                .serve()
+                .unwrap_or_else(|e| fail(e));
+        }
+        (Some(ref cmd), _) if cmd == "capabilities" => {
+            println!("{}", BytecodeEngine::capabilities().to_json());
+        }
+        (Some(ref cmd), None) if FILE_COMMANDS.contains(&cmd.as_str()) => {
+            eprintln!("The {} command needs a filename or project directory", cmd);
+            usage();
+            exit(2);
+        }
         (Some(ref cmd), _) => {
-            println!("Unknown command: {}", cmd);
+            eprintln!("Unknown command: {}", cmd);
+            usage();
+            exit(2);
         }
         (None, _) => {
-            println!("Usage:");
-            println!("   build <filename>");
-            println!("   run <filename>");
-            println!("   repl");
+            usage();
+            exit(2);
         }
     }
 }